@@ -0,0 +1,297 @@
+//! Operator actions on a running relay
+use crate::compression::gzip;
+use crate::config::{Listener, Settings};
+use crate::error::{Error, Result};
+use crate::nip98;
+use crate::relay_keys::RelayKeys;
+use hyper::{Body, Client, Method, Request};
+use nostr::key::FromPkStr;
+use nostr::Keys;
+use serde::{Deserialize, Serialize};
+
+/// Path of the admin route for operator notices
+pub const BROADCAST_NOTICE_PATH: &str = "/admin/broadcast-notice";
+
+/// Path of the admin route listing the top IPs by open connections
+pub const CONNECTIONS_PATH: &str = "/admin/connections";
+
+/// Path of the admin route deleting the events matching a filter
+pub const DELETE_EVENTS_PATH: &str = "/admin/delete-events";
+
+/// Path of the admin route importing gzip-compressed NDJSON events
+pub const IMPORT_PATH: &str = "/admin/import";
+
+/// Path of the admin route listing, releasing and banning quarantined
+/// pubkeys
+pub const QUARANTINE_PATH: &str = "/admin/quarantine";
+
+/// Path of the admin route for read-only (maintenance) mode
+pub const READ_ONLY_PATH: &str = "/admin/read-only";
+
+/// Path of the admin route counting stored events by source
+pub const SOURCES_PATH: &str = "/admin/sources";
+
+/// Path of the admin route listing the authors writing the most events
+pub const STATS_PATH: &str = "/admin/stats";
+
+/// Path of the admin route reporting the source of the pubkey whitelist
+pub const WHITELIST_PATH: &str = "/admin/whitelist";
+
+/// First bytes of a gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Body of a broadcast notice request.  A plain text body is also
+/// accepted, as a signed notice for every client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastRequest {
+    pub message: String,
+    /// Also send authenticated clients an event signed by the relay
+    #[serde(default = "signed_by_default")]
+    pub signed: bool,
+    /// `all`, `admitted` or `unadmitted` clients
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Only clients authenticated as one of these pubkeys
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+}
+
+fn signed_by_default() -> bool {
+    true
+}
+
+/// Base URL for reaching a listener from the relay host
+fn local_url(listener: &Listener) -> String {
+    let scheme = if listener.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = match listener.address.trim() {
+        "0.0.0.0" | "" => "127.0.0.1".to_owned(),
+        "::" => "[::1]".to_owned(),
+        a if a.contains(':') => format!("[{a}]"),
+        a => a.to_owned(),
+    };
+    format!("{scheme}://{host}:{}", listener.port)
+}
+
+/// Base URL of the first listener serving admin routes
+#[must_use]
+pub fn admin_url(settings: &Settings) -> Option<String> {
+    settings
+        .network
+        .listeners()
+        .iter()
+        .find(|l| l.admin_api)
+        .map(local_url)
+}
+
+/// Send a request to an admin route of a running relay, authorized
+/// (NIP-98) with the relay keys from the config.  Returns the relay's
+/// response.
+fn admin_request(
+    settings: &Settings,
+    url: Option<&str>,
+    method: Method,
+    path: &str,
+    body: impl Into<Body>,
+) -> Result<String> {
+    let relay_keys = RelayKeys::load(&settings.info)?
+        .ok_or_else(|| Error::CustomError("relay keys are not configured".to_owned()))?;
+    let base = url
+        .map(|u| u.trim_end_matches('/').to_owned())
+        .or_else(|| admin_url(settings))
+        .ok_or_else(|| Error::CustomError("no listener serves admin routes".to_owned()))?;
+    let url = format!("{base}{path}");
+    let auth = nip98::sign_auth_header(relay_keys.keys(), &url, method.as_str())?;
+    let request = Request::builder()
+        .method(method)
+        .uri(&url)
+        .header("Authorization", auth)
+        .body(body.into())?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder().build::<_, Body>(https);
+        let response = client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(Error::CustomError(format!(
+                "relay returned {status}: {body}"
+            )))
+        }
+    })
+}
+
+/// Ask a running relay to send a notice (optionally signed with its
+/// keys) to the connected clients the request targets.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the notice.
+pub fn run_broadcast_notice(
+    settings: &Settings,
+    notice: &BroadcastRequest,
+    url: Option<&str>,
+) -> Result<String> {
+    let body = serde_json::to_string(notice)?;
+    admin_request(settings, url, Method::POST, BROADCAST_NOTICE_PATH, body)
+}
+
+/// Turn read-only (maintenance) mode of a running relay on or off.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the request.
+pub fn run_read_only(settings: &Settings, active: bool, url: Option<&str>) -> Result<String> {
+    let body = serde_json::json!({ "read_only": active }).to_string();
+    admin_request(settings, url, Method::POST, READ_ONLY_PATH, body)
+}
+
+/// List the addresses with the most open connections to a running
+/// relay, with the client software of each connection (unless
+/// `logging.client_identity` is off).
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the request.
+pub fn run_connections(settings: &Settings, limit: usize, url: Option<&str>) -> Result<String> {
+    let path = format!("{CONNECTIONS_PATH}?limit={limit}");
+    admin_request(settings, url, Method::GET, &path, String::new())
+}
+
+/// Report where the pubkeys allowed to publish on a running relay come
+/// from (membership list, contact list, config, or none), and how many
+/// there are.  For signed lists, the id of the event in force is
+/// included.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the request.
+pub fn run_whitelist(settings: &Settings, url: Option<&str>) -> Result<String> {
+    admin_request(settings, url, Method::GET, WHITELIST_PATH, String::new())
+}
+
+/// Count the events stored by a running relay by where they came from
+/// (published locally, mirrored or ingested).
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the request.
+pub fn run_sources(settings: &Settings, url: Option<&str>) -> Result<String> {
+    admin_request(settings, url, Method::GET, SOURCES_PATH, String::new())
+}
+
+/// List the pubkeys quarantined or banned by a running relay.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay keys are not configured, or the
+/// relay could not be reached or refused the request.
+pub fn run_quarantine_list(settings: &Settings, url: Option<&str>) -> Result<String> {
+    admin_request(settings, url, Method::GET, QUARANTINE_PATH, String::new())
+}
+
+/// Release (`release`) or ban (`ban`) a pubkey, given as hex or npub,
+/// on a running relay.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey is invalid, the relay keys are not
+/// configured, or the relay could not be reached or refused the
+/// request.
+pub fn run_quarantine_action(
+    settings: &Settings,
+    pubkey: &str,
+    action: &str,
+    url: Option<&str>,
+) -> Result<String> {
+    let pubkey = Keys::from_pk_str(pubkey)?.public_key().to_string();
+    let body = serde_json::json!({ "pubkey": pubkey, "action": action }).to_string();
+    admin_request(settings, url, Method::POST, QUARANTINE_PATH, body)
+}
+
+/// Delete the events matching a filter (JSON, as in a `REQ`) from a
+/// running relay.  Unless `confirm` is set, the matching events are
+/// only counted.
+///
+/// # Errors
+///
+/// Will return `Err` if the filter is not a JSON object, the relay keys
+/// are not configured, or the relay could not be reached or refused
+/// the request.
+pub fn run_delete_events(
+    settings: &Settings,
+    filter: &str,
+    confirm: bool,
+    url: Option<&str>,
+) -> Result<String> {
+    let filter: serde_json::Value = serde_json::from_str(filter)?;
+    if !filter.is_object() {
+        return Err(Error::CustomError(
+            "filter must be a JSON object".to_owned(),
+        ));
+    }
+    let body = serde_json::json!({ "filter": filter, "dry_run": !confirm }).to_string();
+    admin_request(settings, url, Method::POST, DELETE_EVENTS_PATH, body)
+}
+
+/// Import the events of an NDJSON file, one event per line, into a
+/// running relay.  The file is compressed with gzip, unless it already
+/// is (like the segments of the event archive).  Returns the result for
+/// each line.
+///
+/// # Errors
+///
+/// Will return `Err` if the file could not be read, the relay keys are
+/// not configured, or the relay could not be reached or refused the
+/// upload.
+pub fn run_import(settings: &Settings, file: &str, url: Option<&str>) -> Result<String> {
+    let contents = std::fs::read(file)?;
+    let body = if contents.starts_with(&GZIP_MAGIC) {
+        contents
+    } else {
+        gzip(&contents)?
+    };
+    admin_request(settings, url, Method::POST, IMPORT_PATH, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_url_uses_admin_listener() {
+        let mut settings = Settings::default();
+        assert_eq!(
+            admin_url(&settings).as_deref(),
+            Some("http://127.0.0.1:8080")
+        );
+        let mut public = settings.network.listeners().remove(0);
+        public.admin_api = false;
+        let mut internal = public.clone();
+        internal.address = "::".to_owned();
+        internal.port = 9090;
+        internal.admin_api = true;
+        settings.network.listener = vec![public, internal];
+        assert_eq!(admin_url(&settings).as_deref(), Some("http://[::1]:9090"));
+        settings.network.listener.pop();
+        assert!(admin_url(&settings).is_none());
+    }
+}
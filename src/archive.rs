@@ -0,0 +1,467 @@
+//! Append-only archive of accepted events
+//!
+//! Events the relay accepts (other than ephemeral ones) are batched,
+//! and appended to gzip-compressed NDJSON segments in
+//! `archive.directory`, one event per line.  Each batch is a complete
+//! gzip member, so a segment cut short by a crash is still readable.
+//! A segment is written as `<name>.partial`, and renamed once it
+//! reaches `segment_max_mb` or `segment_max_secs`; only then is it
+//! listed in `manifest.ndjson` (with its size, event count, sha256 and
+//! the range of `created_at` times).  Finished segments never change,
+//! so the directory can be synced to object storage as is, and a
+//! segment restored with `zcat <segment> | bulkloader`.
+use crate::compression::gzip;
+use crate::config::Archive;
+use crate::error::Result;
+use crate::event::{BroadcastEvent, Event};
+use crate::utils::unix_time;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use flate2::read::MultiGzDecoder;
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+/// Finished segments, one JSON object per line
+pub const MANIFEST: &str = "manifest.ndjson";
+
+/// Suffix of the segment being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Events held before a batch is written early
+const MAX_BATCH: usize = 1000;
+
+/// A finished segment, as listed in the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub events: u64,
+    pub bytes: u64,
+    pub sha256: String,
+    /// Oldest and newest `created_at` of the segment's events
+    pub oldest: u64,
+    pub newest: u64,
+    /// Unix time the segment was finished
+    pub closed_at: u64,
+}
+
+/// The segment being written
+struct Segment {
+    name: String,
+    file: File,
+    opened: Instant,
+    bytes: u64,
+    events: u64,
+    oldest: u64,
+    newest: u64,
+    hash: sha256::HashEngine,
+}
+
+/// Writes accepted events to the archive directory
+pub struct EventArchive {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    batch_interval: Duration,
+    segment: Option<Segment>,
+    dropped: IntCounter,
+}
+
+impl EventArchive {
+    /// Open the archive directory, creating it if needed.  Segments
+    /// left unfinished by a previous run are finished first.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the directory could not be created, or an
+    /// unfinished segment could not be read.
+    pub fn open(settings: &Archive, dropped: IntCounter) -> Result<EventArchive> {
+        let dir = PathBuf::from(&settings.directory);
+        std::fs::create_dir_all(&dir)?;
+        info!("archiving accepted events to {:?}", dir);
+        let archive = EventArchive {
+            dir,
+            max_bytes: settings.segment_max_mb * 1024 * 1024,
+            max_age: Duration::from_secs(settings.segment_max_secs),
+            batch_interval: Duration::from_secs(settings.batch_secs),
+            segment: None,
+            dropped,
+        };
+        archive.recover()?;
+        Ok(archive)
+    }
+
+    /// Finish any partial segments, counting the events that can still
+    /// be read from them.
+    fn recover(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(PARTIAL_SUFFIX))
+            else {
+                continue;
+            };
+            finish_partial(&self.dir, name)?;
+        }
+        Ok(())
+    }
+
+    /// A name for a new segment, after the time it was started
+    fn new_segment_name(&self) -> String {
+        let now = unix_time();
+        let mut name = format!("events-{now}.ndjson.gz");
+        let mut n = 1;
+        while self.exists(&name) {
+            name = format!("events-{now}-{n}.ndjson.gz");
+            n += 1;
+        }
+        name
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        let partial = format!("{name}{PARTIAL_SUFFIX}");
+        self.dir.join(name).exists() || self.dir.join(partial).exists()
+    }
+
+    fn partial_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}{PARTIAL_SUFFIX}"))
+    }
+
+    /// Append a batch of events, as one gzip member, to the current
+    /// segment (starting one if needed).  The segment is finished once
+    /// it is full.
+    async fn write_batch(&mut self, events: &[Event]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut lines = vec![];
+        for event in events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+        let member = gzip(&lines)?;
+        if self.segment.is_none() {
+            let name = self.new_segment_name();
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.partial_path(&name))
+                .await?;
+            self.segment = Some(Segment {
+                name,
+                file,
+                opened: Instant::now(),
+                bytes: 0,
+                events: 0,
+                oldest: u64::MAX,
+                newest: 0,
+                hash: sha256::Hash::engine(),
+            });
+        }
+        if let Some(segment) = &mut self.segment {
+            segment.file.write_all(&member).await?;
+            segment.file.flush().await?;
+            segment.hash.input(&member);
+            segment.bytes += member.len() as u64;
+            segment.events += events.len() as u64;
+            for event in events {
+                segment.oldest = segment.oldest.min(event.created_at);
+                segment.newest = segment.newest.max(event.created_at);
+            }
+            if segment.bytes >= self.max_bytes {
+                self.finish_segment().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename the current segment to its final name, and list it in
+    /// the manifest.
+    async fn finish_segment(&mut self) -> Result<()> {
+        let Some(segment) = self.segment.take() else {
+            return Ok(());
+        };
+        segment.file.sync_all().await?;
+        let path = self.partial_path(&segment.name);
+        fs::rename(&path, self.dir.join(&segment.name)).await?;
+        let entry = ManifestEntry {
+            file: segment.name.clone(),
+            events: segment.events,
+            bytes: segment.bytes,
+            sha256: sha256::Hash::from_engine(segment.hash).to_string(),
+            oldest: segment.oldest,
+            newest: segment.newest,
+            closed_at: unix_time(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let mut manifest = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(MANIFEST))
+            .await?;
+        manifest.write_all(&line).await?;
+        manifest.sync_all().await?;
+        info!(
+            "finished archive segment {} ({} events)",
+            segment.name, segment.events
+        );
+        Ok(())
+    }
+
+    /// Write a batch, counting its events as dropped if that fails.
+    async fn write_or_drop(&mut self, batch: &mut Vec<Event>) {
+        if let Err(e) = self.write_batch(batch).await {
+            warn!("could not write to the event archive: {:?}", e);
+            self.dropped.inc_by(batch.len() as u64);
+            // start a new segment, rather than append to a damaged
+            // one.  Part of the batch may have been written, so the
+            // segment is counted and hashed again from the file.
+            if let Some(segment) = self.segment.take() {
+                let dir = self.dir.clone();
+                let finished =
+                    tokio::task::spawn_blocking(move || finish_partial(&dir, &segment.name)).await;
+                match finished {
+                    Ok(Err(e)) => warn!("could not finish archive segment: {:?}", e),
+                    Err(e) => warn!("could not finish archive segment: {:?}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        }
+        batch.clear();
+    }
+
+    /// Archive accepted events until shutdown, then finish the current
+    /// segment.
+    pub async fn run(
+        mut self,
+        mut events: broadcast::Receiver<BroadcastEvent>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) {
+        let mut interval = tokio::time::interval(self.batch_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut batch: Vec<Event> = vec![];
+        loop {
+            tokio::select! {
+                received = events.recv() => match received {
+                    Ok(bcast) if !bcast.event.is_ephemeral() => {
+                        batch.push(bcast.event);
+                        if batch.len() >= MAX_BATCH {
+                            self.write_or_drop(&mut batch).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("event archive fell behind, and missed {} events", n);
+                        self.dropped.inc_by(n);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = interval.tick() => {
+                    self.write_or_drop(&mut batch).await;
+                    let expired = self
+                        .segment
+                        .as_ref()
+                        .map_or(false, |s| s.opened.elapsed() >= self.max_age);
+                    if expired {
+                        if let Err(e) = self.finish_segment().await {
+                            warn!("could not finish archive segment: {:?}", e);
+                        }
+                    }
+                }
+                _ = shutdown.recv() => break,
+            }
+        }
+        self.write_or_drop(&mut batch).await;
+        if let Err(e) = self.finish_segment().await {
+            warn!("could not finish archive segment: {:?}", e);
+        }
+    }
+}
+
+/// Finish a partial segment from what can be read from its file,
+/// counting and hashing its events again.
+fn finish_partial(dir: &Path, name: &str) -> Result<()> {
+    let path = dir.join(format!("{name}{PARTIAL_SUFFIX}"));
+    let contents = std::fs::read(&path)?;
+    let (mut events, mut oldest, mut newest) = (0, u64::MAX, 0);
+    for line in BufReader::new(MultiGzDecoder::new(contents.as_slice())).lines() {
+        let Ok(line) = line else {
+            warn!("archive segment {} ends with a truncated batch", name);
+            break;
+        };
+        if let Ok(event) = serde_json::from_str::<Event>(&line) {
+            events += 1;
+            oldest = oldest.min(event.created_at);
+            newest = newest.max(event.created_at);
+        }
+    }
+    let entry = ManifestEntry {
+        file: name.to_owned(),
+        events,
+        bytes: contents.len() as u64,
+        sha256: sha256::Hash::hash(&contents).to_string(),
+        oldest: oldest.min(newest),
+        newest,
+        closed_at: unix_time(),
+    };
+    std::fs::rename(&path, dir.join(name))?;
+    append_manifest(dir, &entry)?;
+    info!("finished archive segment {} ({} events)", name, events);
+    Ok(())
+}
+
+fn append_manifest(dir: &Path, entry: &ManifestEntry) -> Result<()> {
+    use std::io::Write;
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut manifest = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(MANIFEST))?;
+    manifest.write_all(&line)?;
+    manifest.sync_all()?;
+    Ok(())
+}
+
+/// Finished segments listed in an archive's manifest.
+///
+/// # Errors
+///
+/// Will return `Err` if the manifest could not be read or parsed.
+pub fn read_manifest(dir: &Path) -> Result<Vec<ManifestEntry>> {
+    let contents = match std::fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = vec![];
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        entries.push(serde_json::from_str(line)?);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn settings(dir: &Path) -> Archive {
+        let mut archive = crate::config::Settings::default().archive;
+        archive.enabled = true;
+        archive.directory = dir.to_string_lossy().into_owned();
+        archive
+    }
+
+    fn event(created_at: u64) -> Event {
+        let mut event: Event = serde_json::from_str(
+            r#"{"id":"0000","pubkey":"0000","created_at":0,"kind":1,"tags":[],"content":"archived","sig":"0000"}"#,
+        )
+        .unwrap();
+        event.created_at = created_at;
+        event
+    }
+
+    fn read_segment(path: &Path) -> Result<Vec<Event>> {
+        let mut text = String::new();
+        MultiGzDecoder::new(std::fs::File::open(path)?).read_to_string(&mut text)?;
+        let mut events = vec![];
+        for line in text.lines() {
+            events.push(serde_json::from_str(line)?);
+        }
+        Ok(events)
+    }
+
+    #[tokio::test]
+    async fn segments_are_rotated_and_listed() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("archive-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let mut archive = EventArchive::open(&settings(&dir), dropped.clone())?;
+        archive.write_batch(&[event(10), event(30)]).await?;
+        archive.write_batch(&[event(20)]).await?;
+        // nothing is listed until the segment is finished
+        assert!(read_manifest(&dir)?.is_empty());
+        let name = archive.segment.as_ref().unwrap().name.clone();
+        assert_eq!(read_segment(&archive.partial_path(&name))?.len(), 3);
+        archive.finish_segment().await?;
+        let manifest = read_manifest(&dir)?;
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].file, name);
+        assert_eq!(manifest[0].events, 3);
+        assert_eq!((manifest[0].oldest, manifest[0].newest), (10, 30));
+        let contents = std::fs::read(dir.join(&name))?;
+        assert_eq!(manifest[0].bytes, contents.len() as u64);
+        assert_eq!(
+            manifest[0].sha256,
+            sha256::Hash::hash(&contents).to_string()
+        );
+        let events = read_segment(&dir.join(&name))?;
+        let times: Vec<u64> = events.iter().map(|e| e.created_at).collect();
+        assert_eq!(times, vec![10, 30, 20]);
+        // a full segment is finished as soon as it is written
+        archive.max_bytes = 1;
+        archive.write_batch(&[event(40)]).await?;
+        assert!(archive.segment.is_none());
+        assert_eq!(read_manifest(&dir)?.len(), 2);
+        // a segment left partial is finished when the archive is opened
+        archive.max_bytes = u64::MAX;
+        archive.write_batch(&[event(50), event(60)]).await?;
+        let name = archive.segment.as_ref().unwrap().name.clone();
+        drop(archive);
+        EventArchive::open(&settings(&dir), dropped)?;
+        let manifest = read_manifest(&dir)?;
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest[2].file, name);
+        assert_eq!(manifest[2].events, 2);
+        assert_eq!((manifest[2].oldest, manifest[2].newest), (50, 60));
+        assert!(!archive_has_partials(&dir)?);
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn damaged_segment_is_hashed_from_its_file() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("archive-damaged-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let mut archive = EventArchive::open(&settings(&dir), dropped)?;
+        archive.write_batch(&[event(10)]).await?;
+        let name = archive.segment.take().unwrap().name;
+        // a failed write left half of the next batch behind
+        let member = gzip(b"{\"half\":")?;
+        let mut partial = std::fs::OpenOptions::new()
+            .append(true)
+            .open(archive.partial_path(&name))?;
+        std::io::Write::write_all(&mut partial, &member[..member.len() / 2])?;
+        drop(partial);
+        finish_partial(&dir, &name)?;
+        let manifest = read_manifest(&dir)?;
+        let contents = std::fs::read(dir.join(&name))?;
+        assert_eq!(manifest[0].events, 1);
+        assert_eq!(manifest[0].bytes, contents.len() as u64);
+        assert_eq!(
+            manifest[0].sha256,
+            sha256::Hash::hash(&contents).to_string()
+        );
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    fn archive_has_partials(dir: &Path) -> Result<bool> {
+        for entry in std::fs::read_dir(dir)? {
+            if entry?.path().to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
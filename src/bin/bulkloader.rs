@@ -1,8 +1,11 @@
 use nostr_rs_relay::config;
 use nostr_rs_relay::error::{Error, Result};
-use nostr_rs_relay::event::{single_char_tagname, Event};
+use nostr_rs_relay::event::{
+    is_indexable_tag_value, normalize_tag_value, single_char_tagname, Event,
+};
 use nostr_rs_relay::repo::sqlite::{build_pool, PooledConnection};
 use nostr_rs_relay::repo::sqlite_migration::{curr_db_version, DB_VERSION};
+use nostr_rs_relay::source::EventSource;
 use nostr_rs_relay::utils::is_lower_hex;
 use rusqlite::params;
 use rusqlite::{OpenFlags, Transaction};
@@ -15,10 +18,19 @@ use tracing::info;
 /// Bulk load JSONL data from STDIN to the database specified in config.toml (or ./nostr.db as a default).
 /// The database must already exist, this will not create a new one.
 /// Tested against schema v13.
+/// Events are recorded as ingested from the URL given as the first
+/// argument (`ingest:stdin` without one).
 
 pub fn main() -> Result<()> {
     let _trace_sub = tracing_subscriber::fmt::try_init();
     println!("Nostr-rs-relay Bulk Loader");
+    let source = EventSource::Ingest(
+        std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| "stdin".to_owned()),
+    )
+    .to_string();
+    info!("recording events as from {}", source);
     // check for a database file, or create one.
     let settings = config::Settings::new(&None)?;
     if !Path::new(&settings.database.data_directory).is_dir() {
@@ -47,6 +59,8 @@ pub fn main() -> Result<()> {
     }
     // this channel will contain parsed events ready to be inserted
     let (event_tx, event_rx) = mpsc::sync_channel(100_000);
+    let max_tag_bytes = settings.limits.max_tag_value_bytes;
+    let max_event_tags = settings.limits.max_event_tags;
     // Thread for reading events
     let _stdin_reader_handler = thread::spawn(move || {
         let stdin = io::stdin();
@@ -55,7 +69,9 @@ pub fn main() -> Result<()> {
                 // try to parse a nostr event
                 let eres: Result<Event, serde_json::Error> = serde_json::from_str(&line);
                 if let Ok(mut e) = eres {
-                    if let Ok(()) = e.validate() {
+                    if let Err(err) = e.validate_tag_limits(max_tag_bytes, max_event_tags) {
+                        info!("rejecting event: {}", err);
+                    } else if let Ok(()) = e.validate() {
                         e.build_index();
                         //debug!("Event: {:?}", e);
                         event_tx.send(Some(e)).ok();
@@ -90,7 +106,12 @@ pub fn main() -> Result<()> {
                     events_read += 1;
                     // ignore ephemeral events
                     if !(e.kind >= 20000 && e.kind < 30000) {
-                        match write_event(&tx, e) {
+                        match write_event(
+                            &tx,
+                            e,
+                            &source,
+                            settings.limits.max_indexed_tag_value_bytes,
+                        ) {
                             Ok(c) => {
                                 new_events += c;
                             }
@@ -126,15 +147,20 @@ pub fn main() -> Result<()> {
 
 /// Write an event and update the tag table.
 /// Assumes the event has its index built.
-fn write_event(tx: &Transaction, e: Event) -> Result<usize> {
+fn write_event(
+    tx: &Transaction,
+    e: Event,
+    source: &str,
+    max_tag_bytes: Option<usize>,
+) -> Result<usize> {
     let id_blob = hex::decode(&e.id).ok();
     let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
     let delegator_blob: Option<Vec<u8>> = e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
     let event_str = serde_json::to_string(&e).ok();
     // ignore if the event hash is a duplicate.
     let ins_count = tx.execute(
-	"INSERT OR IGNORE INTO event (event_hash, created_at, kind, author, delegated_by, content, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%s','now'), FALSE);",
-	params![id_blob, e.created_at, e.kind, pubkey_blob, delegator_blob, event_str]
+	"INSERT OR IGNORE INTO event (event_hash, created_at, kind, author, delegated_by, content, first_seen, hidden, source) VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%s','now'), FALSE, ?7);",
+	params![id_blob, e.created_at, e.kind, pubkey_blob, delegator_blob, event_str, source]
     )?;
     if ins_count == 0 {
         return Ok(0);
@@ -148,8 +174,13 @@ fn write_event(tx: &Transaction, e: Event) -> Result<usize> {
         if tagnamechar_opt.is_none() {
             continue;
         }
-        // safe because len was > 1
-        let tagval = t.get(1).unwrap();
+        // safe because len was > 1; ids and pubkeys are indexed in lowercase
+        let tagval = normalize_tag_value(tagname, t.get(1).unwrap());
+        let tagval = tagval.as_ref();
+        // skip values too long to index
+        if !is_indexable_tag_value(tagname, tagval, max_tag_bytes) {
+            continue;
+        }
         // insert as BLOB if we can restore it losslessly.
         // this means it needs to be even length and lowercase.
         if (tagval.len() % 2 == 0) && is_lower_hex(tagval) {
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(about = "A nostr relay written in Rust", author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
@@ -17,4 +17,341 @@ pub struct CLIArgs {
         required = false
     )]
     pub config: Option<String>,
+    #[arg(
+        long,
+        help = "Check the integrity of the event store before starting",
+        required = false
+    )]
+    pub verify_on_start: bool,
+    #[arg(
+        long,
+        help = "Partition a new PostgreSQL event store by month (see database.partitioned)",
+        required = false
+    )]
+    pub partitioned: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the integrity of the event store, and exit
+    Verify(VerifyArgs),
+    /// Report events missing from the tag index (read-only), and exit
+    CheckTags,
+    /// Report stored events whose id does not match their canonical form (read-only), and exit
+    CheckCanonical,
+    /// Reclaim unused space, report table and index sizes, and exit
+    Compact(CompactArgs),
+    /// Rebuild one of the relay's indexes, report its size before and after, and exit
+    Reindex(ReindexArgs),
+    /// Convert the PostgreSQL event and tag tables to monthly partitions, and exit
+    PartitionEvents,
+    /// Restore a deleted event that has not yet been purged, and exit
+    Undelete(UndeleteArgs),
+    /// Print the balance ledger for an account, and exit
+    Ledger(LedgerArgs),
+    /// Export everything stored about a pubkey to a zip of JSONL files, and exit
+    UserExport(UserExportArgs),
+    /// Erase everything stored about a pubkey, print a signed receipt, and exit
+    UserErase(UserEraseArgs),
+    /// Report accounts whose balance differs from their ledger, and exit
+    VerifyLedger,
+    /// Query the daily write statistics, and exit
+    Stats(StatsArgs),
+    /// Mark a paid invoice as refunded and debit the account, and exit
+    RefundInvoice(RefundInvoiceArgs),
+    /// Send a notice, signed by the relay, to the clients of the running relay
+    #[command(alias = "broadcast")]
+    BroadcastNotice(BroadcastNoticeArgs),
+    /// Turn read-only (maintenance) mode of the running relay on or off
+    ReadOnly(ReadOnlyArgs),
+    /// List the addresses with the most connections to the running relay
+    Connections(ConnectionsArgs),
+    /// Count, or with --confirm delete, the events of the running relay matching a filter
+    DeleteEvents(DeleteEventsArgs),
+    /// Import the events of an NDJSON file (optionally gzipped) into the running relay
+    Import(ImportArgs),
+    /// Show where the running relay's pubkey whitelist comes from, and its size
+    Whitelist(WhitelistArgs),
+    /// List, release or ban the pubkeys quarantined by the running relay
+    Quarantine(QuarantineArgs),
+    /// Count the running relay's stored events by where they came from
+    Sources(SourcesArgs),
+    /// Generate seeded, signed synthetic events for benchmarks, and exit
+    GenEvents(GenEventsArgs),
+}
+
+#[derive(Args)]
+pub struct QuarantineArgs {
+    #[command(subcommand)]
+    pub command: QuarantineCommand,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum QuarantineCommand {
+    /// List the quarantined and banned pubkeys
+    List,
+    /// Make the events of a pubkey visible to everyone
+    Release(QuarantinePubkeyArgs),
+    /// Withhold the events of a pubkey, and reject new ones
+    Ban(QuarantinePubkeyArgs),
+}
+
+#[derive(Args)]
+pub struct QuarantinePubkeyArgs {
+    #[arg(help = "Public key (hex or npub)")]
+    pub pubkey: String,
+}
+
+#[derive(Args)]
+pub struct WhitelistArgs {
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SourcesArgs {
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct DeleteEventsArgs {
+    #[arg(
+        help = "Filter selecting the events, as JSON (e.g. '{\"authors\":[\"<hex>\"],\"kinds\":[1]}')"
+    )]
+    pub filter: String,
+    #[arg(
+        long,
+        help = "Delete the matching events; without this, they are only counted"
+    )]
+    pub confirm: bool,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    #[arg(help = "File of events, one per line (plain or gzip-compressed NDJSON)")]
+    pub file: String,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ConnectionsArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = 20,
+        help = "Number of addresses to list"
+    )]
+    pub limit: usize,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ReadOnlyArgs {
+    #[arg(value_parser = ["on", "off"], help = "Reject new events (on), or accept them again (off)")]
+    pub state: String,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct BroadcastNoticeArgs {
+    #[arg(help = "Notice to send")]
+    pub message: String,
+    #[arg(
+        long,
+        value_parser = ["all", "admitted", "unadmitted"],
+        help = "Only send to clients authenticated as admitted pubkeys, or to the others"
+    )]
+    pub target: Option<String>,
+    #[arg(
+        long = "pubkey",
+        help = "Only send to clients authenticated as this pubkey (repeatable)"
+    )]
+    pub pubkeys: Vec<String>,
+    #[arg(
+        long,
+        help = "Send a plain NOTICE, without an event signed by the relay"
+    )]
+    pub unsigned: bool,
+    #[arg(
+        long,
+        help = "Base URL of the relay's admin listener (defaults to the first admin listener in the config)"
+    )]
+    pub url: Option<String>,
+}
+
+#[derive(Args)]
+pub struct LedgerArgs {
+    #[arg(help = "Account public key (hex or npub)")]
+    pub pubkey: String,
+    #[arg(short, long, help = "Only show the <limit> most recent entries")]
+    pub limit: Option<u64>,
+}
+
+#[derive(Args)]
+pub struct UserExportArgs {
+    #[arg(help = "Public key (hex or npub)")]
+    pub pubkey: String,
+    #[arg(
+        short,
+        long,
+        help = "Write the export to <file> (defaults to <pubkey>.zip)"
+    )]
+    pub output: Option<String>,
+    #[arg(long, help = "Export even if the pubkey has an unpaid invoice")]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct UserEraseArgs {
+    #[arg(help = "Public key (hex or npub)")]
+    pub pubkey: String,
+    #[arg(long, help = "Erase even if the pubkey has an unpaid invoice")]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct StatsArgs {
+    #[command(subcommand)]
+    pub command: StatsCommand,
+}
+
+#[derive(Subcommand)]
+pub enum StatsCommand {
+    /// List the authors that wrote the most events on a day
+    Top(StatsTopArgs),
+}
+
+#[derive(Args)]
+pub struct StatsTopArgs {
+    #[arg(long, help = "Day to report, as YYYY-MM-DD (defaults to today, UTC)")]
+    pub day: Option<String>,
+    #[arg(short, long, help = "Only count events of this kind")]
+    pub kind: Option<u64>,
+    #[arg(short, long, default_value_t = 20, help = "Number of authors to list")]
+    pub limit: u64,
+}
+
+#[derive(Args)]
+pub struct CompactArgs {
+    #[arg(
+        long,
+        help = "Use VACUUM FULL on PostgreSQL, which returns space to the OS but locks each table while it is rewritten"
+    )]
+    pub full: bool,
+}
+
+#[derive(Args)]
+pub struct ReindexArgs {
+    #[arg(help = "Name of the index to rebuild")]
+    pub index: String,
+    #[arg(
+        long,
+        help = "Use a plain REINDEX on PostgreSQL, which is faster but blocks writes to the table"
+    )]
+    pub blocking: bool,
+}
+
+#[derive(Args)]
+pub struct UndeleteArgs {
+    #[arg(help = "Id of the deleted event (hex)")]
+    pub event_id: String,
+}
+
+#[derive(Args)]
+pub struct RefundInvoiceArgs {
+    #[arg(help = "Payment hash of the invoice")]
+    pub payment_hash: String,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[arg(
+        short,
+        long,
+        help = "Write offending event ids to <file>",
+        required = false
+    )]
+    pub output: Option<String>,
+    #[arg(long, help = "Rebuild tag rows that do not match their event")]
+    pub repair_tags: bool,
+    #[arg(long, help = "Hide events that fail id or signature checks")]
+    pub hide_invalid: bool,
+}
+
+#[derive(Args)]
+pub struct GenEventsArgs {
+    #[arg(
+        short = 'n',
+        long,
+        default_value_t = 100_000,
+        help = "Number of events"
+    )]
+    pub count: u64,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Seed for the RNG; the same seed and options give the same events"
+    )]
+    pub seed: u64,
+    #[arg(
+        long,
+        default_value = crate::synthetic::DEFAULT_KINDS,
+        help = "Kinds to generate, with relative weights (<kind>:<weight>,...)"
+    )]
+    pub kinds: String,
+    #[arg(long, default_value_t = 1000, help = "Number of distinct authors")]
+    pub pubkeys: usize,
+    #[arg(long, default_value_t = 4, help = "Most tags (e, p and t) per event")]
+    pub max_tags: usize,
+    #[arg(long, default_value_t = 280, help = "Most content bytes per event")]
+    pub max_content: usize,
+    #[arg(long, default_value_t = 1_700_000_000, help = "Newest created_at")]
+    pub until: u64,
+    #[arg(
+        long,
+        default_value_t = 30 * 86400,
+        help = "Spread created_at over this many seconds before --until"
+    )]
+    pub spread_secs: u64,
+    #[arg(
+        short,
+        long,
+        required_unless_present = "database",
+        conflicts_with = "database",
+        help = "Write events as JSONL to <file> (- for stdout)"
+    )]
+    pub output: Option<String>,
+    #[arg(long, help = "Write events into the configured database, in batches")]
+    pub database: bool,
 }
@@ -13,6 +13,10 @@ pub struct CloseCmd {
     id: String,
 }
 
+/// Subscription identifier that closes all of a connection's
+/// subscriptions, when `options.close_all` is enabled.
+pub const CLOSE_ALL: &str = "*";
+
 /// Identifier of the subscription to be closed.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct Close {
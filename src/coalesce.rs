@@ -0,0 +1,268 @@
+//! Write coalescing for websocket connections
+//!
+//! Tungstenite flushes the underlying stream after every frame, which
+//! results in one write syscall per event sent.  When many events are
+//! broadcast in a burst, this stream buffers writes and suppresses
+//! flushes while a batch is held open, so several frames can go out in
+//! a single write.  The connection loop releases the hold once its
+//! flush deadline passes.
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, BufWriter, ReadBuf};
+
+/// Size of the write buffer used while batching
+const BATCH_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Stream wrapper that can hold back flushes
+pub struct CoalescingStream<S> {
+    inner: BufWriter<S>,
+    hold: Arc<AtomicBool>,
+}
+
+impl<S: AsyncRead + AsyncWrite> CoalescingStream<S> {
+    /// Wrap a stream.  If batching is disabled, writes are passed
+    /// straight through to the inner stream.
+    pub fn new(stream: S, batching: bool) -> Self {
+        let capacity = if batching { BATCH_BUFFER_BYTES } else { 0 };
+        CoalescingStream {
+            inner: BufWriter::with_capacity(capacity, stream),
+            hold: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Handle for holding back flushes.  While set to `true`, flushes
+    /// only reach the inner stream when the buffer fills.
+    pub fn flush_hold(&self) -> Arc<AtomicBool> {
+        self.hold.clone()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for CoalescingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for CoalescingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.hold.load(Ordering::Relaxed) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    /// Writer that counts write calls, standing in for a socket
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        bytes: Vec<u8>,
+    }
+
+    impl AsyncRead for CountingWriter {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Socket that counts the writes made to it
+    struct CountedSocket {
+        inner: TcpStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountedSocket {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountedSocket {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if written.is_ready() {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            written
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Deliver a burst of `events` to each of `subscribers` websocket
+    /// connections over loopback TCP, as the connection loop does.
+    /// Returns the writes made to the sockets, and the time until every
+    /// subscriber has read the whole burst.
+    async fn deliver(subscribers: usize, events: usize, batching: bool) -> (usize, Duration) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writes = Arc::new(AtomicUsize::new(0));
+        let mut streams = vec![];
+        let mut readers = vec![];
+        for _ in 0..subscribers {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (socket, _) = listener.accept().await.unwrap();
+            readers.push(tokio::spawn(async move {
+                let mut received = vec![];
+                client.read_to_end(&mut received).await.unwrap();
+                received.len()
+            }));
+            let socket = CountedSocket {
+                inner: socket,
+                writes: writes.clone(),
+            };
+            let stream = CoalescingStream::new(socket, batching);
+            let hold = stream.flush_hold();
+            let ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+            streams.push((ws, hold));
+        }
+        let start = Instant::now();
+        for i in 0..events {
+            let msg = format!(r#"["EVENT","sub",{{"id":"{i:064x}","kind":1,"content":"gm"}}]"#);
+            for (ws, hold) in &mut streams {
+                let msg = Message::Text(msg.clone());
+                if batching {
+                    hold.store(true, Ordering::Relaxed);
+                    ws.feed(msg).await.unwrap();
+                } else {
+                    ws.send(msg).await.unwrap();
+                }
+            }
+        }
+        // the flush deadline passes, and the connections close
+        for (mut ws, hold) in streams {
+            hold.store(false, Ordering::Relaxed);
+            ws.flush().await.unwrap();
+            ws.get_mut().shutdown().await.unwrap();
+        }
+        for reader in readers {
+            assert!(reader.await.unwrap() > 0);
+        }
+        (writes.load(Ordering::Relaxed), start.elapsed())
+    }
+
+    /// Write `n` frames, flushing after each as tungstenite does.
+    async fn write_frames(stream: &mut CoalescingStream<CountingWriter>, n: usize) {
+        for i in 0..n {
+            stream
+                .write_all(format!("[\"EVENT\",\"sub\",{i}]").as_bytes())
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn passthrough_without_batching() {
+        let mut stream = CoalescingStream::new(CountingWriter::default(), false);
+        write_frames(&mut stream, 100).await;
+        assert_eq!(stream.inner.get_ref().writes, 100);
+    }
+
+    #[tokio::test]
+    async fn held_flushes_coalesce_writes() {
+        let mut stream = CoalescingStream::new(CountingWriter::default(), true);
+        let hold = stream.flush_hold();
+        hold.store(true, Ordering::Relaxed);
+        write_frames(&mut stream, 100).await;
+        // nothing reaches the socket until the hold is released
+        assert_eq!(stream.inner.get_ref().writes, 0);
+        hold.store(false, Ordering::Relaxed);
+        stream.flush().await.unwrap();
+        assert_eq!(stream.inner.get_ref().writes, 1);
+        // ordering is preserved
+        let out = String::from_utf8(stream.inner.get_ref().bytes.clone()).unwrap();
+        assert!(out.starts_with("[\"EVENT\",\"sub\",0]"));
+        assert!(out.ends_with("[\"EVENT\",\"sub\",99]"));
+    }
+
+    #[tokio::test]
+    async fn released_hold_flushes_immediately() {
+        let mut stream = CoalescingStream::new(CountingWriter::default(), true);
+        write_frames(&mut stream, 10).await;
+        assert_eq!(stream.inner.get_ref().writes, 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batching_saves_writes_to_many_subscribers() {
+        const SUBSCRIBERS: usize = 200;
+        const EVENTS: usize = 100;
+        let (unbatched, unbatched_time) = deliver(SUBSCRIBERS, EVENTS, false).await;
+        let (batched, batched_time) = deliver(SUBSCRIBERS, EVENTS, true).await;
+        // timings are reported with --nocapture
+        println!(
+            "{EVENTS} events to {SUBSCRIBERS} subscribers: \
+             unbatched {unbatched} writes in {unbatched_time:?}, \
+             batched {batched} writes in {batched_time:?}"
+        );
+        assert!(unbatched >= SUBSCRIBERS * EVENTS);
+        // the burst fits the batch buffer, so reaches each socket in
+        // one write (or two, when the socket takes only part of it)
+        assert!(batched <= 2 * SUBSCRIBERS);
+    }
+}
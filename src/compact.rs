@@ -0,0 +1,271 @@
+//! Reclaiming space in the event store
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::postgres::{self, PostgresPool};
+use crate::repo::sqlite::{self, build_pool};
+use rusqlite::OpenFlags;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Size of a single table or index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelationSize {
+    /// Table or index name
+    pub name: String,
+    /// Is this an index?
+    pub is_index: bool,
+    /// Rows in a table (an estimate, for PostgreSQL)
+    pub rows: Option<u64>,
+    /// Dead rows waiting to be vacuumed (PostgreSQL only)
+    pub dead_rows: Option<u64>,
+    /// Space used on disk
+    pub bytes: u64,
+}
+
+/// Size of the database, and each table and index in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatabaseSize {
+    /// Total space used on disk, including free pages
+    pub total_bytes: u64,
+    /// Tables and indexes, by name
+    pub relations: Vec<RelationSize>,
+}
+
+/// Database sizes before and after compacting.
+#[derive(Debug, Clone)]
+pub struct CompactReport {
+    pub before: DatabaseSize,
+    pub after: DatabaseSize,
+    pub elapsed: Duration,
+}
+
+fn opt(v: Option<u64>) -> String {
+    v.map_or_else(|| "-".to_owned(), |v| v.to_string())
+}
+
+impl fmt::Display for CompactReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name\tkind\trows\tdead rows\tbytes")?;
+        for b in &self.before.relations {
+            let a = self.after.relations.iter().find(|a| a.name == b.name);
+            writeln!(
+                f,
+                "{}\t{}\t{} -> {}\t{} -> {}\t{} -> {}",
+                b.name,
+                if b.is_index { "index" } else { "table" },
+                opt(b.rows),
+                opt(a.and_then(|a| a.rows)),
+                opt(b.dead_rows),
+                opt(a.and_then(|a| a.dead_rows)),
+                b.bytes,
+                opt(a.map(|a| a.bytes)),
+            )?;
+        }
+        write!(
+            f,
+            "total: {} -> {} bytes (reclaimed {}) in {:?}",
+            self.before.total_bytes,
+            self.after.total_bytes,
+            self.before
+                .total_bytes
+                .saturating_sub(self.after.total_bytes),
+            self.elapsed
+        )
+    }
+}
+
+/// Reclaim space in the configured database, reporting sizes before
+/// and after.
+///
+/// SQLite is rebuilt with `VACUUM`, which blocks writers while it
+/// runs.  PostgreSQL runs a plain `VACUUM`, which does not, unless
+/// `full` is set, in which case `VACUUM FULL` rewrites every table
+/// under an exclusive lock.  Both update planner statistics.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be opened, or the
+/// maintenance commands fail.
+pub fn run_compact(settings: &Settings, full: bool) -> Result<CompactReport> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "compact",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_WRITE,
+                1,
+                1,
+                false,
+            );
+            let conn = pool.get()?;
+            let before = sqlite::database_size(&conn)?;
+            let start = Instant::now();
+            sqlite::compact(&conn)?;
+            let elapsed = start.elapsed();
+            info!("sqlite compacted in {:?}", elapsed);
+            let after = sqlite::database_size(&conn)?;
+            Ok(CompactReport {
+                before,
+                after,
+                elapsed,
+            })
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = postgres::pool_options(settings)
+                    .max_connections(1)
+                    .connect(&settings.database.connection)
+                    .await?;
+                let before = postgres::database_size(&pool).await?;
+                let start = Instant::now();
+                postgres::compact(&pool, full).await?;
+                let elapsed = start.elapsed();
+                info!("postgres vacuumed (full: {}) in {:?}", full, elapsed);
+                let after = postgres::database_size(&pool).await?;
+                Ok(CompactReport {
+                    before,
+                    after,
+                    elapsed,
+                })
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+/// Size of an index before and after it was rebuilt.
+#[derive(Debug, Clone)]
+pub struct ReindexReport {
+    pub index: String,
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+    /// Was the index rebuilt without blocking writes?
+    pub concurrently: bool,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ReindexReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} bytes in {:?}{}",
+            self.index,
+            self.before_bytes,
+            self.after_bytes,
+            self.elapsed,
+            if self.concurrently {
+                " (concurrently)"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// Size of one of the relay's own indexes.  Only indexes the relay
+/// created (in its schema, for PostgreSQL) may be rebuilt, so the
+/// name is looked up among them.
+fn index_bytes(size: &DatabaseSize, index: &str) -> Result<u64> {
+    let indexes = size.relations.iter().filter(|r| r.is_index);
+    if let Some(rel) = indexes.clone().find(|r| r.name == index) {
+        return Ok(rel.bytes);
+    }
+    let names: Vec<&str> = indexes.map(|r| r.name.as_str()).collect();
+    Err(Error::CustomError(format!(
+        "{index} is not one of the relay's indexes: {}",
+        names.join(", ")
+    )))
+}
+
+/// Rebuild one of the relay's indexes, reporting its size before and
+/// after.
+///
+/// SQLite drops the index and creates it again, which blocks writers
+/// while it runs.  PostgreSQL runs `REINDEX CONCURRENTLY`, which does
+/// not, unless `blocking` is set, in which case a plain `REINDEX` is
+/// faster but blocks writes to the table.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be opened, `index` is
+/// not one of the relay's indexes, or the rebuild fails.
+pub fn run_reindex(settings: &Settings, index: &str, blocking: bool) -> Result<ReindexReport> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "reindex",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_WRITE,
+                1,
+                1,
+                false,
+            );
+            let mut conn = pool.get()?;
+            let before_bytes = index_bytes(&sqlite::database_size(&conn)?, index)?;
+            let start = Instant::now();
+            sqlite::recreate_index(&mut conn, index)?;
+            let elapsed = start.elapsed();
+            info!("sqlite index {} recreated in {:?}", index, elapsed);
+            let after_bytes = index_bytes(&sqlite::database_size(&conn)?, index)?;
+            Ok(ReindexReport {
+                index: index.to_owned(),
+                before_bytes,
+                after_bytes,
+                concurrently: false,
+                elapsed,
+            })
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = postgres::pool_options(settings)
+                    .max_connections(1)
+                    .connect(&settings.database.connection)
+                    .await?;
+                let before_bytes = index_bytes(&postgres::database_size(&pool).await?, index)?;
+                let start = Instant::now();
+                postgres::reindex(&pool, index, !blocking).await?;
+                let elapsed = start.elapsed();
+                info!(
+                    "postgres index {} rebuilt (concurrently: {}) in {:?}",
+                    index, !blocking, elapsed
+                );
+                let after_bytes = index_bytes(&postgres::database_size(&pool).await?, index)?;
+                Ok(ReindexReport {
+                    index: index.to_owned(),
+                    before_bytes,
+                    after_bytes,
+                    concurrently: !blocking,
+                    elapsed,
+                })
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_relay_indexes_are_rebuilt() {
+        let rel = |name: &str, is_index| RelationSize {
+            name: name.to_owned(),
+            is_index,
+            rows: None,
+            dead_rows: None,
+            bytes: 4096,
+        };
+        let size = DatabaseSize {
+            total_bytes: 8192,
+            relations: vec![rel("event", false), rel("event_hash_index", true)],
+        };
+        assert_eq!(index_bytes(&size, "event_hash_index").unwrap(), 4096);
+        let err = index_bytes(&size, "event").unwrap_err().to_string();
+        assert!(err.contains("event_hash_index"));
+        assert!(index_bytes(&size, "pg_class_oid_index").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Compression of HTTP responses (NIP-11 and admin routes)
+//!
+//! Websocket compression (permessage-deflate) is not implemented: the
+//! websocket library rejects compressed frames, so the extension is
+//! declined when offered.
+use crate::server::NostrMetrics;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY,
+};
+use hyper::body::to_bytes;
+use hyper::{Body, Response};
+use std::io::Write;
+use tracing::warn;
+
+/// Bodies smaller than this are sent uncompressed, since gzip would
+/// save little (or even add bytes).
+pub const MIN_COMPRESS_BYTES: usize = 256;
+
+/// Does an `Accept-Encoding` header allow gzip?  A coding (or `*`) with
+/// `q=0` is refused.
+#[must_use]
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q <= 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Compress bytes with gzip.
+///
+/// # Errors
+///
+/// Will return `Err` if compression failed.
+pub fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len() / 2), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Compress a response body with gzip, unless it is already encoded or
+/// too small to be worth it.  Sizes before and after are counted in
+/// metrics.
+pub async fn gzip_response(response: Response<Body>, metrics: &NostrMetrics) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.append(VARY, ACCEPT_ENCODING.into());
+    if parts.headers.contains_key(CONTENT_ENCODING) {
+        return Response::from_parts(parts, body);
+    }
+    let bytes = match to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("could not read response body to compress: {:?}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    if bytes.len() < MIN_COMPRESS_BYTES {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    match gzip(&bytes) {
+        Ok(compressed) => {
+            let sizes = &metrics.http_compression_bytes;
+            sizes
+                .with_label_values(&["uncompressed"])
+                .inc_by(bytes.len() as u64);
+            sizes
+                .with_label_values(&["compressed"])
+                .inc_by(compressed.len() as u64);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            warn!("could not compress response: {:?}", e);
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn gzip_is_negotiated() {
+        assert!(accepts_gzip(&accept("gzip, deflate, br")));
+        assert!(accepts_gzip(&accept("br;q=1.0, GZIP;q=0.5")));
+        assert!(accepts_gzip(&accept("*")));
+        assert!(!accepts_gzip(&accept("gzip;q=0")));
+        assert!(!accepts_gzip(&accept("deflate, br")));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_compressed() {
+        let (_, metrics) = crate::server::create_metrics();
+        let json = serde_json::json!({ "supported_nips": vec![1; 200] }).to_string();
+        let response = gzip_response(Response::new(Body::from(json.clone())), &metrics).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert!(body.len() < json.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, json);
+        let sizes = &metrics.http_compression_bytes;
+        assert_eq!(
+            sizes.with_label_values(&["uncompressed"]).get(),
+            json.len() as u64
+        );
+        assert_eq!(
+            sizes.with_label_values(&["compressed"]).get(),
+            body.len() as u64
+        );
+        // small bodies are left alone
+        let response = gzip_response(Response::new(Body::from("ok")), &metrics).await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -1,9 +1,38 @@
 //! Configuration file and settings management
 use crate::payment::Processor;
-use config::{Config, ConfigError, File};
+use crate::source::EventSource;
+use crate::utils::is_http_url;
+use config::{Config, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::time::Duration;
 
+/// Secret key material.  The value is redacted from `Debug` output,
+/// so settings can be logged without leaking it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret(s)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Info {
@@ -14,6 +43,27 @@ pub struct Info {
     pub contact: Option<String>,
     pub favicon: Option<String>,
     pub relay_icon: Option<String>,
+    pub relay_icon_file: Option<String>, // image served by the relay at /icon, and advertised instead of relay_icon
+    #[serde(default)]
+    pub expose_first_seen: bool, // let authorized pubkeys query and receive when events were first seen
+    #[serde(default)]
+    pub first_seen_pubkeys: Vec<String>, // pubkeys, besides the admin pubkey, that may see first-seen times
+    pub relay_secret_key: Option<Secret>, // hex or nsec secret key used to sign relay-authored events
+    pub relay_secret_key_file: Option<String>, // file containing the relay secret key, instead of relay_secret_key
+    #[serde(default)]
+    pub language_tags: Vec<String>, // languages of the relay's content (IETF tags), advertised in NIP-11
+}
+
+impl Info {
+    /// May a client authenticated (NIP-42) as this pubkey query by,
+    /// and receive, the time events were first seen by the relay?
+    #[must_use]
+    pub fn can_see_first_seen(&self, auth_pubkey: Option<&String>) -> bool {
+        self.expose_first_seen
+            && auth_pubkey.map_or(false, |pk| {
+                self.pubkey.as_ref() == Some(pk) || self.first_seen_pubkeys.contains(pk)
+            })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +76,21 @@ pub struct Database {
     pub max_conn: u32,
     pub connection: String,
     pub connection_write: Option<String>,
+    pub postgres_schema: Option<String>, // create and use tables in this schema, instead of the default search_path
+    pub busy_timeout_ms: u64, // how long SQLite waits on a locked database before failing with SQLITE_BUSY
+    pub write_attempts: u32, // attempts for event writes that fail from locking or transaction conflicts
+    pub explain_slow_queries_ms: Option<u64>, // postgres: EXPLAIN a sample of queries slower than this, warning of sequential scans
+    pub explain_sample_rate: f64,             // fraction of slow queries to EXPLAIN
+    #[serde(default)]
+    pub deferred_tag_indexing: bool, // if true, index tags of new events in the background, after they are stored
+    pub tag_index_delay_ms: u64, // longest wait before deferred tags are indexed
+    #[serde(default)]
+    pub shards: Vec<String>, // split events by id over these databases (postgres connection strings, or sqlite data directories)
+    #[serde(default)]
+    pub partitioned: bool, // postgres: partition events and tags by month of created_at
+    #[serde(default)]
+    pub block_until_migrated: bool, // don't listen until migrations are done, rather than serving the startup state
+    pub query_timeout_ms: Option<u64>, // stop stored queries running longer than this, keeping the events already sent
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +98,8 @@ pub struct Database {
 pub struct Grpc {
     pub event_admission_server: Option<String>,
     pub restricts_write: bool,
+    #[serde(default)]
+    pub async_admission: bool, // store events as pending, and admit them once the admission server decides
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,12 +109,127 @@ pub struct Network {
     pub address: String,
     pub remote_ip_header: Option<String>, // retrieve client IP from this HTTP header if present
     pub ping_interval_seconds: u32,
+    pub broadcast_flush_ms: Option<u64>, // if set, coalesce broadcast events into batched writes, flushed within this many milliseconds
+    #[serde(default)]
+    pub http_compression: bool, // gzip NIP-11 and admin responses for clients that accept it
+    pub accept_backlog: u32, // connections the OS queues on each listener before they are accepted
+    pub tcp_nodelay: Option<bool>, // disable Nagle's algorithm on accepted connections; defaults to on for TLS listeners, off otherwise
+    pub tcp_reuse_address: bool, // set SO_REUSEADDR, so a restarted relay can bind while old connections linger
+    pub tcp_keepalive_secs: Option<u64>, // send TCP keepalives after a connection is idle this long
+    pub tcp_keepalive_interval_secs: Option<u64>, // wait between unanswered keepalives
+    pub tcp_keepalive_retries: Option<u32>, // unanswered keepalives before the connection is dropped
+    #[serde(default)]
+    pub listener: Vec<Listener>, // additional listeners; if any are set, they replace address/port
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Is this a schema name that needs no quoting in PostgreSQL?
+fn is_schema_name(name: &str) -> bool {
+    name.len() <= 63
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// An address to accept connections on, with its own policy overlay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Listener {
+    pub name: Option<String>, // used in logs; defaults to the bind address
+    pub address: String,
+    pub port: u16,
+    pub tls_cert: Option<String>, // PEM certificate chain; serve TLS if set along with tls_key
+    pub tls_key: Option<String>,  // PEM private key
+    #[serde(default)]
+    pub auth_required: bool, // clients must authenticate (NIP-42) before publishing or subscribing
+    pub enforce_payment: Option<bool>, // require payment for publishing; defaults to pay_to_relay.enabled
+    #[serde(default = "default_true")]
+    pub admin_api: bool, // serve admin routes (metrics)
+}
+
+/// Policy applied to connections accepted on a listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerPolicy {
+    pub name: String,
+    pub auth_required: bool,
+    pub enforce_payment: bool,
+    pub admin_api: bool,
+}
+
+impl Listener {
+    #[must_use]
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.address.trim(), self.port)
+    }
+
+    /// Resolve the policy overlay against the global settings.
+    #[must_use]
+    pub fn policy(&self, settings: &Settings) -> ListenerPolicy {
+        ListenerPolicy {
+            name: self.name.clone().unwrap_or_else(|| self.bind_addr()),
+            auth_required: self.auth_required,
+            enforce_payment: self
+                .enforce_payment
+                .unwrap_or(settings.pay_to_relay.enabled),
+            admin_api: self.admin_api,
+        }
+    }
+}
+
+impl Network {
+    /// Listeners to bind.  Without any `[[network.listener]]`
+    /// entries, this is the single `address`/`port`.
+    #[must_use]
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listener.is_empty() {
+            return self.listener.clone();
+        }
+        vec![Listener {
+            name: None,
+            address: self.address.clone(),
+            port: self.port,
+            tls_cert: None,
+            tls_key: None,
+            auth_required: false,
+            enforce_payment: None,
+            admin_api: true,
+        }]
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Options {
     pub reject_future_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the future
+    #[serde(default)]
+    pub shadow_enforcement: bool, // if true, log (but accept) events that policy rules would reject
+    #[serde(default)]
+    pub read_only: bool, // start in read-only (maintenance) mode, rejecting all events
+    pub read_only_message: String,            // reason sent to clients while read-only
+    #[serde(default)]
+    pub verbose_notices: bool, // if true, explain to publishers when accepted events are not delivered to everyone
+    #[serde(default)]
+    pub resume_subscriptions: bool, // if true, fill in `since` when an authenticated client repeats a REQ
+    pub resume_watermark_days: u64, // forget resume points not updated for this many days
+    pub req_progress_secs: Option<u64>, // report progress on subscriptions still sending stored events after this long
+    #[serde(default)]
+    pub req_progress_default: bool, // if true, report progress to clients that did not ask with `_progress`
+    pub req_progress_format: String, // "notice", or "progress" for draft PROGRESS messages
+    #[serde(default)]
+    pub close_all: bool, // if true, ["CLOSE", "*"] closes every subscription of the connection
+    #[serde(default)]
+    pub send_newer_version: bool, // if true, send publishers of outdated replaceable events the stored version
+    #[serde(default)]
+    pub monotonic_created_at: bool, // if true, reject events older than the author's newest stored event
+    #[serde(default)]
+    pub exclude_kinds: bool, // if true, filters may leave out kinds with the non-standard `_excludeKinds`
+    pub unknown_messages: UnknownMessages, // how to answer message types the relay does not support
+    pub debounce_replaceable_ms: Option<u64>, // broadcast only the newest version of replaceable events updated within this long
+    pub debounce_kinds: Option<Vec<u64>>,     // replaceable kinds to debounce (all if not set)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,8 +238,46 @@ pub struct Retention {
     // TODO: implement
     pub max_events: Option<usize>,                // max events
     pub max_bytes: Option<usize>,                 // max size
-    pub persist_days: Option<usize>,              // oldest message
+    pub persist_days: Option<usize>,              // oldest message (implemented)
     pub whitelist_addresses: Option<Vec<String>>, // whitelisted addresses (never delete)
+    #[serde(default)]
+    pub purge_delay_hours: u64, // keep deleted events recoverable for this long (0 deletes immediately)
+    #[serde(default)]
+    pub source_retention: Vec<SourceRetention>, // shorter retention for mirrored or ingested events
+    pub erasure_tombstone_days: u64, // refuse the older events of an erased pubkey for this long (0 to accept them, the default)
+}
+
+/// Days to keep the events of a source (`mirror`, `ingest`, or one
+/// relay's `mirror:<url>`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceRetention {
+    pub source: String,
+    pub days: usize,
+}
+
+/// Events (of matching sources, if set) to delete once older than
+/// `days`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub source: Option<String>,
+    pub days: usize,
+}
+
+impl Retention {
+    /// Retention rules in force: `persist_days` for all events, and
+    /// `source_retention` for the events of each source.
+    #[must_use]
+    pub fn rules(&self) -> Vec<RetentionRule> {
+        let all = self
+            .persist_days
+            .map(|days| RetentionRule { source: None, days });
+        all.into_iter()
+            .chain(self.source_retention.iter().map(|r| RetentionRule {
+                source: Some(r.source.clone()),
+                days: r.days,
+            }))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -68,12 +288,39 @@ pub struct Limits {
     pub db_conns_per_client: Option<u32>, // How many concurrent database queries (not subscriptions) may a client have?
     pub max_blocking_threads: usize,
     pub max_event_bytes: Option<usize>, // Maximum size of an EVENT message
+    #[serde(default)]
+    pub max_event_bytes_by_kind: HashMap<String, usize>, // Maximum size of an EVENT message, for specific kinds
     pub max_ws_message_bytes: Option<usize>,
     pub max_ws_frame_bytes: Option<usize>,
     pub broadcast_buffer: usize, // events to buffer for subscribers (prevents slow readers from consuming memory)
+    pub live_events_before_eose: usize, // live events held per subscription until EOSE; beyond this, they are queried again
+    pub max_events_before_eose: Option<u64>, // stored events sent per subscription before EOSE is sent early
     pub event_persist_buffer: usize, // events to buffer for database commits (block senders if database writes are too slow)
     pub event_kind_blacklist: Option<Vec<u64>>,
     pub event_kind_allowlist: Option<Vec<u64>>,
+    pub max_indexed_tag_value_bytes: Option<usize>, // Tag values longer than this are stored, but not indexed
+    pub max_tag_value_bytes: Option<usize>, // Reject events with a tag value longer than this
+    pub max_filter_values: Option<usize>, // Reject REQs with more values than this in any filter array
+    pub max_filter_ids: Option<usize>,    // Allow this many ids in filters with no other conditions
+    pub max_event_tags: Option<usize>,    // Reject events with more tags than this
+    pub default_limit: Option<u64>,       // Limit of filters without one (except those with ids)
+    pub max_limit: Option<u64>,           // Reduce larger filter limits to this
+    #[serde(default)]
+    pub strict_tags: bool, // Reject events with empty, unnamed, or exactly repeated tags
+    #[serde(default)]
+    pub strict_hex: bool, // Reject events with ids or pubkeys in uppercase hex in e and p tags
+    pub default_since_window_secs: Option<u64>, // Start filters without since, until or ids this long ago
+    pub max_stored_events: Option<u64>, // Evict the oldest events (by first_seen) beyond this many
+    pub events_per_min_per_ip: Option<u32>, // Limit events published from one IP address
+    pub events_per_min_per_pubkey: Option<u32>, // Limit events published by one author
+    pub rate_limit_store: String,       // Where rate limits are counted ("memory" or "redis")
+    pub redis_url: Option<String>,      // Redis server, for rate_limit_store = "redis"
+    pub redis_key_prefix: String,       // Prefix for keys in Redis
+    pub max_connections_per_ip: Option<u32>, // Refuse websocket upgrades beyond this many connections from one IP
+    #[serde(default)]
+    pub connection_limit_exempt_ips: Vec<String>, // IPs (such as trusted proxies) exempt from max_connections_per_ip
+    pub broadcast_notices_per_sec: u32, // Clients sent an operator notice per second, spreading it over time
+    pub auth_attempts_per_min: Option<u32>, // Limit NIP-42 AUTH attempts from one connection, and from one IP address
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -82,6 +329,46 @@ pub struct Authorization {
     pub pubkey_whitelist: Option<Vec<String>>, // If present, only allow these pubkeys to publish events
     pub nip42_auth: bool,                      // if true enables NIP-42 authentication
     pub nip42_dms: bool, // if true send DMs only to their authenticated recipients
+    pub restricted_read_kinds: Vec<u64>, // kinds only readable by their author or recipient, when nip42_dms is set
+    pub membership_admin: Option<String>, // if present, kind 30000 lists signed by this pubkey replace the whitelist
+    pub membership_list: String,          // "d" tag of the admin's membership list
+    #[serde(default)]
+    pub whitelist_from_contact_list: Option<String>, // if present, pubkeys followed (kind 3) by this pubkey are added to the whitelist
+    #[serde(default)]
+    pub contact_list_relay: Option<String>, // relay to fetch that contact list from, in addition to local storage
+    pub contact_list_refresh_secs: u64, // time between contact list refreshes
+    pub allowed_nip05_domains: Option<Vec<String>>, // if present, only authors verified under these NIP-05 domains may publish
+    pub auth_challenge_ttl_secs: u64, // NIP-42 challenges are replaced after this long
+    pub auth_max_skew_secs: u64,      // AUTH events must be created this close to the current time
+    pub auth_failure_delay_ms: u64,   // delay answering a failed AUTH, doubling with each failure
+    pub auth_max_failures: u32,       // close connections after this many failed AUTH attempts
+}
+
+impl Limits {
+    /// Maximum size of an EVENT message with this kind, if limited.
+    #[must_use]
+    pub fn max_event_bytes_for(&self, kind: u64) -> Option<usize> {
+        self.max_event_bytes_by_kind
+            .get(&kind.to_string())
+            .copied()
+            .or(self.max_event_bytes)
+            .filter(|max| *max > 0)
+    }
+
+    /// How far back filters with no time bounds reach, in seconds, if
+    /// they are limited.
+    #[must_use]
+    pub fn default_since_window(&self) -> Option<u64> {
+        self.default_since_window_secs.filter(|window| *window > 0)
+    }
+}
+
+impl Authorization {
+    /// Is this kind only readable by its author and recipient?
+    #[must_use]
+    pub fn is_restricted_read_kind(&self, kind: u64) -> bool {
+        self.restricted_read_kinds.contains(&kind)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -90,6 +377,7 @@ pub struct PayToRelay {
     pub enabled: bool,
     pub admission_cost: u64, // Cost to have pubkey whitelisted
     pub cost_per_event: u64, // Cost author to pay per event
+    pub free_events: u64,    // Events each account may publish before paying cost_per_event
     pub node_url: String,
     pub api_secret: String,
     pub terms_message: String,
@@ -97,6 +385,12 @@ pub struct PayToRelay {
     pub direct_message: bool, // Send direct message to user with invoice and terms
     pub secret_key: Option<String>,
     pub processor: Processor,
+    pub payment_url: Option<String>, // Explicit NIP-11 payments URL (defaults to relay_url + "join")
+    pub join_page_title: Option<String>, // Heading shown on the join page
+    pub join_page_logo: Option<String>, // URL of an image shown on the join page
+    pub join_page_html: Option<String>, // Custom HTML inserted into the join page
+    pub min_paid_ratio: Option<f64>, // Warn when fewer than this fraction of new invoices are paid
+    pub paid_ratio_window_secs: u64, // Window over which min_paid_ratio is measured
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -105,6 +399,14 @@ pub struct Diagnostics {
     pub tracing: bool, // enables tokio console-subscriber
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownMessages {
+    Ignore, // drop the message silently
+    Notice, // answer with a NOTICE, and keep the connection open
+    Close,  // close the connection
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum VerifiedUsersMode {
@@ -167,11 +469,108 @@ impl VerifiedUsers {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct StatusEvents {
+    pub enabled: bool,      // periodically publish a relay-signed status event
+    pub interval_secs: u64, // time between status events
+    pub kind: u64,          // kind of the status event (parameterized replaceable by default)
+    #[serde(default)]
+    pub relays: Vec<String>, // other relays (ws:// or wss://) that also receive the status event
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct StatusPage {
+    #[serde(default)]
+    pub enabled: bool, // serve a JSON summary of the relay (uptime, event counts, clients)
+    pub path: String,      // path of the summary
+    pub refresh_secs: u64, // time between recounting stored events
+    #[serde(default)]
+    pub require_auth: bool, // require NIP-98 authorization from the relay key
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Archive {
+    #[serde(default)]
+    pub enabled: bool, // append accepted events to gzipped NDJSON segments
+    pub directory: String,     // where segments and their manifest are written
+    pub segment_max_mb: u64,   // size at which a segment is finished
+    pub segment_max_secs: u64, // age at which a segment is finished
+    pub batch_secs: u64,       // time between writing batches of events
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Stats {
+    #[serde(default)]
+    pub enabled: bool, // count events written per author, kind and day
+    pub retention_days: u64, // days of counts to keep
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Quarantine {
+    #[serde(default)]
+    pub enabled: bool, // withhold the events of pubkeys new to the relay from other clients
+    pub probation_hours: u64, // hours before a new pubkey is released
+    pub release_after_events: Option<u64>, // release a new pubkey sooner, once it has written this many events
+    pub exempt_kinds: Vec<u64>,            // kinds that are never withheld
+    #[serde(default)]
+    pub reviewer_pubkeys: Vec<String>, // pubkeys, besides the admin pubkey, that may see withheld events
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct GeoIp {
+    pub database: Option<String>, // CSV of IP ranges and country codes; requires the `geoip` feature
+    #[serde(default)]
+    pub allowed_countries: Vec<String>, // if set, only accept connections from these countries
+    #[serde(default)]
+    pub blocked_countries: Vec<String>, // refuse connections from these countries
+    #[serde(default)]
+    pub fail_closed: bool, // if true, refuse clients whose country can not be determined
+}
+
+impl GeoIp {
+    /// Are connections filtered by country?
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.database.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Import {
+    #[serde(default)]
+    pub enabled: bool, // accept gzip-compressed NDJSON events on the admin route /admin/import
+    pub max_upload_mb: u64,       // largest (compressed) upload accepted
+    pub max_decompressed_mb: u64, // stop reading an upload after this much decompressed data
+    pub batch_size: usize,        // events handed to the database writer at a time
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct LanguageDetection {
+    #[serde(default)]
+    pub enabled: bool, // advertise the languages detected in recent notes; requires the `language-detection` feature
+    pub sample_size: u64,     // newest text notes sampled
+    pub refresh_secs: u64,    // time between samples
+    pub min_share: f64,       // share of sampled notes a language needs to be advertised
+    pub max_languages: usize, // most languages advertised
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Logging {
     pub folder_path: Option<String>,
     pub file_prefix: Option<String>,
+    pub client_identity: bool, // record client User-Agents and `client` tags, for tracing abuse
+    pub rejection_log: Option<String>, // file recording every rejected event, for abuse analysis
+    pub rejection_log_per_minute: u32, // rejections recorded per minute before dropping (0 for no limit)
+    pub rejection_log_max_mb: u64, // size at which the rejection log is rotated (0 to never rotate)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -189,6 +588,14 @@ pub struct Settings {
     pub retention: Retention,
     pub options: Options,
     pub logging: Logging,
+    pub status_events: StatusEvents,
+    pub status_page: StatusPage,
+    pub archive: Archive,
+    pub stats: Stats,
+    pub quarantine: Quarantine,
+    pub geoip: GeoIp,
+    pub import: Import,
+    pub language_detection: LanguageDetection,
 }
 
 impl Settings {
@@ -205,7 +612,8 @@ impl Settings {
                 } else {
                     eprintln!("Error reading config file ({:?})", e);
                     eprintln!("WARNING: Default configuration settings will be used");
-                    Ok(default_settings)
+                    // environment overrides still apply to the defaults
+                    Self::new_from_sources(&default_settings, None, Self::env_source())
                 }
             }
             ok => ok,
@@ -221,13 +629,31 @@ impl Settings {
             Some(value) => value,
             None => &default_config_file_name,
         };
-        let builder = Config::builder();
-        let config: Config = builder
-            // use defaults
-            .add_source(Config::try_from(default)?)
+        Self::new_from_sources(default, Some(config), Self::env_source())
+    }
+
+    /// Environment variables that override the config file.  Keys are
+    /// prefixed with `NOSTR__`, and sections are separated by `__`,
+    /// so `NOSTR__DATABASE__MAX_CONN=20` sets `database.max_conn`.
+    fn env_source() -> Environment {
+        Environment::with_prefix("NOSTR")
+            .separator("__")
+            .try_parsing(true)
+    }
+
+    fn new_from_sources(
+        default: &Settings,
+        config_file_name: Option<&str>,
+        env: Environment,
+    ) -> Result<Self, ConfigError> {
+        // use defaults
+        let mut builder = Config::builder().add_source(Config::try_from(default)?);
+        if let Some(config) = config_file_name {
             // override with file contents
-            .add_source(File::with_name(config))
-            .build()?;
+            builder = builder.add_source(File::with_name(config));
+        }
+        // environment takes precedence over the file
+        let config: Config = builder.add_source(env).build()?;
         let mut settings: Settings = config.try_deserialize()?;
         // ensure connection pool size is logical
         assert!(
@@ -236,6 +662,76 @@ impl Settings {
             settings.database.min_conn,
             settings.database.max_conn
         );
+        assert!(
+            (1..=10).contains(&settings.database.write_attempts),
+            "Database write_attempts must be between 1 and 10"
+        );
+        assert!(
+            (0.0..=1.0).contains(&settings.database.explain_sample_rate),
+            "Database explain_sample_rate must be between 0 and 1"
+        );
+        assert!(
+            settings.database.tag_index_delay_ms > 0,
+            "Database tag_index_delay_ms must be at least 1"
+        );
+        assert!(
+            settings.database.shards.len() != 1,
+            "Database shards must list at least two databases"
+        );
+        assert!(
+            settings.limits.broadcast_notices_per_sec > 0,
+            "broadcast_notices_per_sec must be at least 1"
+        );
+        assert!(
+            settings.authorization.auth_challenge_ttl_secs > 0,
+            "auth_challenge_ttl_secs must be at least 1"
+        );
+        assert!(
+            settings.authorization.auth_max_failures > 0,
+            "auth_max_failures must be at least 1"
+        );
+        assert!(
+            matches!(
+                settings.options.req_progress_format.as_str(),
+                "notice" | "progress"
+            ),
+            "req_progress_format must be \"notice\" or \"progress\""
+        );
+        // messages larger than max_ws_message_bytes never arrive
+        let ws_max = settings.limits.max_ws_message_bytes.filter(|max| *max > 0);
+        for (kind, max) in &settings.limits.max_event_bytes_by_kind {
+            assert!(
+                kind.parse::<u64>().is_ok(),
+                "max_event_bytes_by_kind keys must be event kinds (found {kind:?})"
+            );
+            assert!(
+                *max > 0 && ws_max.map_or(true, |ws_max| *max <= ws_max),
+                "max_event_bytes_by_kind for kind {kind} ({max}) must be between 1 and max_ws_message_bytes"
+            );
+        }
+        if let Some(schema) = &settings.database.postgres_schema {
+            assert!(
+                is_schema_name(schema),
+                "Database postgres_schema must be a lowercase identifier (letters, digits and underscores)"
+            );
+        }
+        // rate limits are counted locally or in redis
+        assert!(
+            matches!(
+                settings.limits.rate_limit_store.as_str(),
+                "memory" | "redis"
+            ),
+            "limits.rate_limit_store ({}) must be \"memory\" or \"redis\"",
+            settings.limits.rate_limit_store
+        );
+        assert!(
+            settings.limits.rate_limit_store != "redis" || settings.limits.redis_url.is_some(),
+            "limits.rate_limit_store is \"redis\", but limits.redis_url is not set"
+        );
+        assert!(
+            settings.options.resume_watermark_days >= 1,
+            "options.resume_watermark_days must be at least 1"
+        );
         // ensure durations parse
         assert!(
             settings.verified_users.is_valid(),
@@ -244,9 +740,36 @@ impl Settings {
         // initialize durations for verified users
         settings.verified_users.init();
 
+        if settings.geoip.is_enabled() {
+            assert!(
+                settings.geoip.allowed_countries.is_empty()
+                    || settings.geoip.blocked_countries.is_empty(),
+                "geoip.allowed_countries and geoip.blocked_countries can not both be set"
+            );
+            for code in settings
+                .geoip
+                .allowed_countries
+                .iter_mut()
+                .chain(settings.geoip.blocked_countries.iter_mut())
+            {
+                assert!(
+                    code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()),
+                    "geoip country codes must be two letters (found {code:?})"
+                );
+                code.make_ascii_uppercase();
+            }
+        }
+
         // Validate pay to relay settings
         if settings.pay_to_relay.enabled {
             assert_ne!(settings.pay_to_relay.api_secret, "");
+            if let Some(ratio) = settings.pay_to_relay.min_paid_ratio {
+                assert!(
+                    (0.0..=1.0).contains(&ratio),
+                    "pay_to_relay.min_paid_ratio must be between 0 and 1"
+                );
+            }
+            assert!(settings.pay_to_relay.paid_ratio_window_secs > 0);
             // Should check that url is valid
             assert_ne!(settings.pay_to_relay.node_url, "");
             assert_ne!(settings.pay_to_relay.terms_message, "");
@@ -259,6 +782,124 @@ impl Settings {
                 assert!(settings.pay_to_relay.secret_key.is_some());
             }
         }
+        for rule in &settings.retention.source_retention {
+            assert!(
+                ["local", "mirror", "ingest"].contains(&rule.source.as_str())
+                    || rule.source.parse::<EventSource>().is_ok(),
+                "retention.source_retention: unknown source {:?}",
+                rule.source
+            );
+            assert!(
+                rule.days > 0,
+                "retention.source_retention days must be positive"
+            );
+        }
+        // membership lists are matched against hex pubkeys
+        if let Some(admin) = &settings.authorization.membership_admin {
+            assert!(
+                admin.len() == 64 && admin.chars().all(|c| c.is_ascii_hexdigit()),
+                "authorization.membership_admin ({admin}) must be a hex pubkey"
+            );
+        }
+        // contact lists are matched against hex pubkeys
+        if let Some(owner) = &settings.authorization.whitelist_from_contact_list {
+            assert!(
+                owner.len() == 64 && owner.chars().all(|c| c.is_ascii_hexdigit()),
+                "authorization.whitelist_from_contact_list ({owner}) must be a hex pubkey"
+            );
+            assert!(
+                settings.authorization.contact_list_refresh_secs > 0,
+                "authorization.contact_list_refresh_secs must be positive"
+            );
+        }
+        // the domain allowlist relies on the NIP-05 verifier
+        if settings.authorization.allowed_nip05_domains.is_some() {
+            assert!(
+                settings.verified_users.is_active(),
+                "authorization.allowed_nip05_domains requires verified_users.mode to be enabled or passive"
+            );
+        }
+        // listeners must be usable on their own
+        for l in &settings.network.listener {
+            assert!(
+                l.tls_cert.is_some() == l.tls_key.is_some(),
+                "listener {} needs both tls_cert and tls_key",
+                l.bind_addr()
+            );
+            assert!(
+                l.enforce_payment != Some(true) || settings.pay_to_relay.enabled,
+                "listener {} enforces payment, but pay_to_relay is disabled",
+                l.bind_addr()
+            );
+            assert!(
+                !l.auth_required || settings.info.relay_url.is_some(),
+                "listener {} requires authentication, which needs info.relay_url",
+                l.bind_addr()
+            );
+        }
+        assert!(
+            settings.info.relay_secret_key.is_none()
+                || settings.info.relay_secret_key_file.is_none(),
+            "only one of info.relay_secret_key and info.relay_secret_key_file may be set"
+        );
+        if settings.status_events.enabled {
+            assert!(
+                settings.status_events.interval_secs > 0,
+                "status_events.interval_secs must be positive"
+            );
+        }
+        if settings.status_page.enabled {
+            assert!(
+                settings.status_page.refresh_secs > 0,
+                "status_page.refresh_secs must be positive"
+            );
+            assert!(
+                settings.status_page.path.starts_with('/'),
+                "status_page.path must start with /"
+            );
+        }
+        if settings.archive.enabled {
+            assert!(
+                settings.archive.segment_max_mb > 0
+                    && settings.archive.segment_max_secs > 0
+                    && settings.archive.batch_secs > 0,
+                "archive segment_max_mb, segment_max_secs and batch_secs must be positive"
+            );
+        }
+        if settings.language_detection.enabled {
+            assert!(
+                settings.language_detection.refresh_secs > 0
+                    && settings.language_detection.sample_size > 0,
+                "language_detection.refresh_secs and sample_size must be positive"
+            );
+            assert!(
+                (0.0..=1.0).contains(&settings.language_detection.min_share),
+                "language_detection.min_share must be between 0 and 1"
+            );
+        }
+        if settings.stats.enabled {
+            assert!(
+                settings.stats.retention_days > 0,
+                "stats.retention_days must be positive"
+            );
+        }
+        if settings.quarantine.enabled {
+            assert!(
+                settings.quarantine.probation_hours > 0,
+                "quarantine.probation_hours must be positive"
+            );
+            assert!(
+                settings.quarantine.release_after_events != Some(0),
+                "quarantine.release_after_events must be positive"
+            );
+        }
+        // ensure an explicit payment URL is usable
+        if let Some(payment_url) = &settings.pay_to_relay.payment_url {
+            assert!(
+                is_http_url(payment_url),
+                "pay_to_relay.payment_url ({payment_url}) is not a valid http(s) URL"
+            );
+        }
 
         Ok(settings)
     }
@@ -275,6 +916,12 @@ impl Default for Settings {
                 contact: None,
                 favicon: None,
                 relay_icon: None,
+                relay_icon_file: None,
+                expose_first_seen: false,
+                first_seen_pubkeys: vec![],
+                relay_secret_key: None,
+                relay_secret_key_file: None,
+                language_tags: vec![],
             },
             diagnostics: Diagnostics { tracing: false },
             database: Database {
@@ -285,39 +932,95 @@ impl Default for Settings {
                 max_conn: 8,
                 connection: "".to_owned(),
                 connection_write: None,
+                postgres_schema: None,
+                busy_timeout_ms: 5000,
+                write_attempts: 4,
+                explain_slow_queries_ms: None,
+                explain_sample_rate: 0.01,
+                deferred_tag_indexing: false,
+                tag_index_delay_ms: 250,
+                shards: vec![],
+                partitioned: false,
+                block_until_migrated: false,
+                query_timeout_ms: None,
             },
             grpc: Grpc {
                 event_admission_server: None,
                 restricts_write: false,
+                async_admission: false,
             },
             network: Network {
                 port: 8080,
                 ping_interval_seconds: 300,
                 address: "0.0.0.0".to_owned(),
                 remote_ip_header: None,
+                broadcast_flush_ms: None,
+                http_compression: false,
+                accept_backlog: 1024,
+                tcp_nodelay: None,
+                tcp_reuse_address: true,
+                tcp_keepalive_secs: None,
+                tcp_keepalive_interval_secs: None,
+                tcp_keepalive_retries: None,
+                listener: vec![],
             },
             limits: Limits {
                 messages_per_sec: None,
                 subscriptions_per_min: None,
                 db_conns_per_client: None,
                 max_blocking_threads: 16,
-                max_event_bytes: Some(2 << 17),      // 128K
+                max_event_bytes: Some(2 << 17), // 128K
+                max_event_bytes_by_kind: HashMap::new(),
                 max_ws_message_bytes: Some(2 << 17), // 128K
                 max_ws_frame_bytes: Some(2 << 17),   // 128K
                 broadcast_buffer: 16384,
+                live_events_before_eose: 1000,
+                max_events_before_eose: None,
                 event_persist_buffer: 4096,
                 event_kind_blacklist: None,
                 event_kind_allowlist: None,
+                max_indexed_tag_value_bytes: None,
+                max_tag_value_bytes: None,
+                max_event_tags: None,
+                default_limit: None,
+                max_limit: None,
+                strict_tags: false,
+                strict_hex: false,
+                default_since_window_secs: None,
+                max_stored_events: None,
+                max_filter_values: Some(10_000),
+                max_filter_ids: Some(20_000),
+                events_per_min_per_ip: None,
+                events_per_min_per_pubkey: None,
+                rate_limit_store: "memory".to_owned(),
+                redis_url: None,
+                redis_key_prefix: "nostr-rs-relay".to_owned(),
+                max_connections_per_ip: None,
+                connection_limit_exempt_ips: vec![],
+                broadcast_notices_per_sec: 500,
+                auth_attempts_per_min: Some(20),
             },
             authorization: Authorization {
-                pubkey_whitelist: None, // Allow any address to publish
-                nip42_auth: false,      // Disable NIP-42 authentication
-                nip42_dms: false,       // Send DMs to everybody
+                pubkey_whitelist: None,                   // Allow any address to publish
+                nip42_auth: false,                        // Disable NIP-42 authentication
+                nip42_dms: false,                         // Send DMs to everybody
+                restricted_read_kinds: vec![4, 44, 1059], // DMs and gift wraps
+                membership_admin: None,
+                membership_list: "relay-members".to_owned(),
+                whitelist_from_contact_list: None,
+                contact_list_relay: None,
+                contact_list_refresh_secs: 3600,
+                allowed_nip05_domains: None,
+                auth_challenge_ttl_secs: 600,
+                auth_max_skew_secs: 120,
+                auth_failure_delay_ms: 250,
+                auth_max_failures: 5,
             },
             pay_to_relay: PayToRelay {
                 enabled: false,
                 admission_cost: 4200,
                 cost_per_event: 0,
+                free_events: 0,
                 terms_message: "".to_string(),
                 node_url: "".to_string(),
                 api_secret: "".to_string(),
@@ -325,6 +1028,12 @@ impl Default for Settings {
                 direct_message: false,
                 secret_key: None,
                 processor: Processor::LNBits,
+                payment_url: None,
+                join_page_title: None,
+                join_page_logo: None,
+                join_page_html: None,
+                min_paid_ratio: None,
+                paid_ratio_window_secs: 86400,
             },
             verified_users: VerifiedUsers {
                 mode: VerifiedUsersMode::Disabled,
@@ -341,14 +1050,286 @@ impl Default for Settings {
                 max_bytes: None,           // max size
                 persist_days: None,        // oldest message
                 whitelist_addresses: None, // whitelisted addresses (never delete)
+                purge_delay_hours: 0,
+                source_retention: vec![],
+                erasure_tombstone_days: 0,
             },
             options: Options {
                 reject_future_seconds: None, // Reject events in the future if defined
+                shadow_enforcement: false,
+                read_only: false,
+                read_only_message: "relay in maintenance mode".to_owned(),
+                verbose_notices: false,
+                resume_subscriptions: false,
+                resume_watermark_days: 7,
+                req_progress_secs: Some(10),
+                req_progress_default: false,
+                req_progress_format: "notice".to_owned(),
+                close_all: false,
+                send_newer_version: false,
+                monotonic_created_at: false,
+                exclude_kinds: false,
+                unknown_messages: UnknownMessages::Notice,
+                debounce_replaceable_ms: None,
+                debounce_kinds: None,
             },
             logging: Logging {
                 folder_path: None,
                 file_prefix: None,
+                client_identity: true,
+                rejection_log: None,
+                rejection_log_per_minute: 600,
+                rejection_log_max_mb: 100,
+            },
+            status_events: StatusEvents {
+                enabled: false,
+                interval_secs: 3600,
+                kind: 30078,
+                relays: vec![],
+            },
+            status_page: StatusPage {
+                enabled: false,
+                path: "/stats".to_owned(),
+                refresh_secs: 60,
+                require_auth: false,
+            },
+            archive: Archive {
+                enabled: false,
+                directory: "./archive".to_owned(),
+                segment_max_mb: 64,
+                segment_max_secs: 3600,
+                batch_secs: 5,
+            },
+            stats: Stats {
+                enabled: false,
+                retention_days: 30,
+            },
+            quarantine: Quarantine {
+                enabled: false,
+                probation_hours: 24,
+                release_after_events: None,
+                exempt_kinds: vec![0, 3],
+                reviewer_pubkeys: vec![],
+            },
+            geoip: GeoIp {
+                database: None,
+                allowed_countries: vec![],
+                blocked_countries: vec![],
+                fail_closed: false,
+            },
+            import: Import {
+                enabled: false,
+                max_upload_mb: 100,
+                max_decompressed_mb: 1024,
+                batch_size: 500,
+            },
+            language_detection: LanguageDetection {
+                enabled: false,
+                sample_size: 1000,
+                refresh_secs: 3600,
+                min_share: 0.1,
+                max_languages: 5,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn settings_from_env(vars: &[(&str, &str)]) -> Result<Settings, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect();
+        #[allow(deprecated)]
+        let env = Settings::env_source().source(Some(vars));
+        Settings::new_from_sources(&Settings::default(), None, env)
+    }
+
+    #[test]
+    fn postgres_schema_names() {
+        assert!(is_schema_name("nostr"));
+        assert!(is_schema_name("relay_2"));
+        assert!(!is_schema_name(""));
+        assert!(!is_schema_name("2relay"));
+        assert!(!is_schema_name("Nostr"));
+        assert!(!is_schema_name("nostr; drop table event"));
+    }
+
+    #[test]
+    fn env_overrides_defaults() {
+        let settings = settings_from_env(&[
+            ("NOSTR__DATABASE__MAX_CONN", "20"),
+            ("NOSTR__INFO__NAME", "env relay"),
+            ("NOSTR__LIMITS__MESSAGES_PER_SEC", "5"),
+            ("NOSTR__PAY_TO_RELAY__ADMISSION_COST", "1000"),
+            ("OTHER__DATABASE__MAX_CONN", "1"),
+        ])
+        .unwrap();
+        assert_eq!(settings.database.max_conn, 20);
+        assert_eq!(settings.info.name, Some("env relay".to_owned()));
+        assert_eq!(settings.limits.messages_per_sec, Some(5));
+        assert_eq!(settings.pay_to_relay.admission_cost, 1000);
+    }
+
+    #[test]
+    fn shadow_enforcement_is_opt_in() {
+        assert!(!settings_from_env(&[]).unwrap().options.shadow_enforcement);
+        let settings =
+            settings_from_env(&[("NOSTR__OPTIONS__SHADOW_ENFORCEMENT", "true")]).unwrap();
+        assert!(settings.options.shadow_enforcement);
+    }
+
+    #[test]
+    fn single_listener_by_default() {
+        let settings = settings_from_env(&[("NOSTR__NETWORK__PORT", "7777")]).unwrap();
+        let listeners = settings.network.listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].bind_addr(), "0.0.0.0:7777");
+        let policy = listeners[0].policy(&settings);
+        assert!(!policy.auth_required);
+        assert!(!policy.enforce_payment);
+        assert!(policy.admin_api);
+    }
+
+    #[test]
+    fn listener_policy_overlays() {
+        let path = std::env::temp_dir().join(format!("listeners-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[pay_to_relay]
+enabled = true
+api_secret = "secret"
+node_url = "https://lnbits.example.com"
+terms_message = "terms"
+
+[[network.listener]]
+name = "public"
+address = "0.0.0.0"
+port = 8080
+admin_api = false
+
+[[network.listener]]
+address = "127.0.0.1"
+port = 9090
+enforce_payment = false
+"#,
+        )
+        .unwrap();
+        let settings = Settings::new_from_sources(
+            &Settings::default(),
+            path.to_str(),
+            Environment::with_prefix("NOSTR_TEST_UNUSED"),
+        );
+        std::fs::remove_file(&path).ok();
+        let settings = settings.unwrap();
+        let policies: Vec<ListenerPolicy> = settings
+            .network
+            .listeners()
+            .iter()
+            .map(|l| l.policy(&settings))
+            .collect();
+        assert_eq!(
+            policies,
+            vec![
+                ListenerPolicy {
+                    name: "public".to_owned(),
+                    auth_required: false,
+                    enforce_payment: true,
+                    admin_api: false,
+                },
+                ListenerPolicy {
+                    name: "127.0.0.1:9090".to_owned(),
+                    auth_required: false,
+                    enforce_payment: false,
+                    admin_api: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn relay_secret_key_is_redacted() {
+        let secret = "ab".repeat(32);
+        let settings =
+            settings_from_env(&[("NOSTR__INFO__RELAY_SECRET_KEY", secret.as_str())]).unwrap();
+        let key = settings.info.relay_secret_key.as_ref().unwrap();
+        assert_eq!(key.expose(), secret);
+        assert!(!format!("{settings:?}").contains(&secret));
+    }
+
+    #[test]
+    fn first_seen_access() {
+        let mut info = Settings::default().info;
+        let admin = "aa".repeat(32);
+        let indexer = "bb".repeat(32);
+        info.pubkey = Some(admin.clone());
+        info.first_seen_pubkeys = vec![indexer.clone()];
+        assert!(!info.can_see_first_seen(Some(&admin)));
+        info.expose_first_seen = true;
+        assert!(info.can_see_first_seen(Some(&admin)));
+        assert!(info.can_see_first_seen(Some(&indexer)));
+        assert!(!info.can_see_first_seen(Some(&"cc".repeat(32))));
+        assert!(!info.can_see_first_seen(None));
+    }
+
+    #[test]
+    fn redis_store_from_env() {
+        let settings = settings_from_env(&[
+            ("NOSTR__LIMITS__RATE_LIMIT_STORE", "redis"),
+            ("NOSTR__LIMITS__REDIS_URL", "redis://cache.internal:6379/"),
+            ("NOSTR__LIMITS__EVENTS_PER_MIN_PER_IP", "60"),
+        ])
+        .unwrap();
+        assert_eq!(settings.limits.rate_limit_store, "redis");
+        assert_eq!(settings.limits.events_per_min_per_ip, Some(60));
+        assert_eq!(settings.limits.redis_key_prefix, "nostr-rs-relay");
+    }
+
+    #[test]
+    #[should_panic(expected = "redis_url")]
+    fn redis_store_needs_url() {
+        settings_from_env(&[("NOSTR__LIMITS__RATE_LIMIT_STORE", "redis")]).ok();
+    }
+
+    #[test]
+    fn max_event_bytes_per_kind() {
+        let settings = settings_from_env(&[
+            ("NOSTR__LIMITS__MAX_EVENT_BYTES", "1000"),
+            ("NOSTR__LIMITS__MAX_EVENT_BYTES_BY_KIND__30023", "100000"),
+            ("NOSTR__LIMITS__MAX_EVENT_BYTES_BY_KIND__7", "500"),
+        ])
+        .unwrap();
+        assert_eq!(settings.limits.max_event_bytes_for(30023), Some(100_000));
+        assert_eq!(settings.limits.max_event_bytes_for(7), Some(500));
+        assert_eq!(settings.limits.max_event_bytes_for(1), Some(1000));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_ws_message_bytes")]
+    fn max_event_bytes_per_kind_within_message_limit() {
+        settings_from_env(&[("NOSTR__LIMITS__MAX_EVENT_BYTES_BY_KIND__30023", "1000000")]).ok();
+    }
+
+    #[test]
+    #[should_panic(expected = "explain_sample_rate")]
+    fn explain_sample_rate_is_a_fraction() {
+        settings_from_env(&[("NOSTR__DATABASE__EXPLAIN_SAMPLE_RATE", "5")]).ok();
+    }
+
+    #[test]
+    #[should_panic(expected = "write_attempts")]
+    fn write_attempts_are_bounded() {
+        settings_from_env(&[("NOSTR__DATABASE__WRITE_ATTEMPTS", "40")]).ok();
+    }
+
+    #[test]
+    fn malformed_env_value() {
+        let err = settings_from_env(&[("NOSTR__DATABASE__MAX_CONN", "lots")]).unwrap_err();
+        assert!(err.to_string().contains("max_conn"), "{err}");
+    }
+}
@@ -1,11 +1,11 @@
 //! Client connection state
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use tracing::{debug, trace};
 use uuid::Uuid;
 
 use crate::close::Close;
-use crate::conn::Nip42AuthState::{AuthPubkey, Challenge, NoAuth};
 use crate::error::Error;
 use crate::error::Result;
 use crate::event::Event;
@@ -15,15 +15,12 @@ use crate::utils::{host_str, unix_time};
 /// A subscription identifier has a maximum length
 const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
 
-/// NIP-42 authentication state
-pub enum Nip42AuthState {
-    /// The client is not authenticated yet
-    NoAuth,
-    /// The AUTH challenge sent
-    Challenge(String),
-    /// The client is authenticated
-    AuthPubkey(String),
-}
+/// Default lifetime of a NIP-42 challenge
+const DEFAULT_CHALLENGE_TTL: Duration = Duration::from_secs(600);
+
+/// Default allowed difference between an AUTH event's `created_at`
+/// and the current time, in seconds
+const DEFAULT_AUTH_MAX_SKEW: u64 = 600;
 
 /// State for a client connection
 pub struct ClientConn {
@@ -35,8 +32,18 @@ pub struct ClientConn {
     subscriptions: HashMap<String, Subscription>,
     /// Per-connection maximum concurrent subscriptions
     max_subs: usize,
-    /// NIP-42 AUTH
-    auth: Nip42AuthState,
+    /// NIP-42 challenge outstanding, and when it was issued
+    auth_challenge: Option<(String, Instant)>,
+    /// NIP-42 authenticated pubkey
+    auth_pubkey: Option<String>,
+    /// AUTH attempts that failed, other than for an expired challenge
+    auth_failures: u32,
+    /// Challenges are replaced after this long
+    challenge_ttl: Duration,
+    /// AUTH events must be created within this many seconds of now
+    auth_max_skew: u64,
+    /// Generation assigned to the most recent subscription
+    generation: u64,
 }
 
 impl Default for ClientConn {
@@ -55,7 +62,12 @@ impl ClientConn {
             client_id,
             subscriptions: HashMap::new(),
             max_subs: 32,
-            auth: NoAuth,
+            auth_challenge: None,
+            auth_pubkey: None,
+            auth_failures: 0,
+            challenge_ttl: DEFAULT_CHALLENGE_TTL,
+            auth_max_skew: DEFAULT_AUTH_MAX_SKEW,
+            generation: 0,
         }
     }
 
@@ -82,28 +94,73 @@ impl ClientConn {
         &self.client_ip_addr
     }
 
+    /// Set how long challenges last, and how far an AUTH event's
+    /// `created_at` may be from the current time.
+    pub fn set_auth_window(&mut self, challenge_ttl: Duration, max_skew_secs: u64) {
+        self.challenge_ttl = challenge_ttl;
+        self.auth_max_skew = max_skew_secs;
+    }
+
     #[must_use]
     pub fn auth_pubkey(&self) -> Option<&String> {
-        match &self.auth {
-            AuthPubkey(pubkey) => Some(pubkey),
-            _ => None,
-        }
+        self.auth_pubkey.as_ref()
     }
 
     #[must_use]
     pub fn auth_challenge(&self) -> Option<&String> {
-        match &self.auth {
-            Challenge(pubkey) => Some(pubkey),
-            _ => None,
+        self.auth_challenge.as_ref().map(|(challenge, _)| challenge)
+    }
+
+    /// Number of AUTH attempts on this connection that failed, not
+    /// counting answers to an expired challenge
+    #[must_use]
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures
+    }
+
+    /// When the outstanding challenge expires, and should be replaced
+    #[must_use]
+    pub fn auth_challenge_expiry(&self) -> Option<Instant> {
+        self.auth_challenge
+            .as_ref()
+            .map(|(_, issued)| *issued + self.challenge_ttl)
+    }
+
+    /// Determine if this client may read an event.
+    ///
+    /// Events with a restricted kind are only readable by their
+    /// author, or the recipient in the first `p` tag, once
+    /// authenticated.
+    #[must_use]
+    pub fn can_read_event(&self, event: &Event, restricted_kinds: &[u64]) -> bool {
+        if !restricted_kinds.contains(&event.kind) {
+            return true;
+        }
+        match (self.auth_pubkey(), event.tag_values_by_name("p").first()) {
+            (Some(auth_pubkey), Some(recipient_pubkey)) => {
+                recipient_pubkey == auth_pubkey || &event.pubkey == auth_pubkey
+            }
+            (_, _) => false,
         }
     }
 
-    /// Add a new subscription for this connection.
+    /// Determine if a query result belongs to the current subscription
+    /// with this id.  Results from a replaced or closed subscription
+    /// must not be sent.
+    #[must_use]
+    pub fn is_current(&self, sub_id: &str, generation: u64) -> bool {
+        self.subscriptions
+            .get(sub_id)
+            .map_or(false, |s| s.generation == generation)
+    }
+
+    /// Add a new subscription for this connection, returning the
+    /// generation assigned to it.
     /// # Errors
     ///
     /// Will return `Err` if the client has too many subscriptions, or
     /// if the provided name is excessively long.
-    pub fn subscribe(&mut self, s: Subscription) -> Result<()> {
+    pub fn subscribe(&mut self, mut s: Subscription) -> Result<u64> {
         let k = s.get_id();
         let sub_id_len = k.len();
         // prevent arbitrarily long subscription identifiers from
@@ -115,30 +172,27 @@ impl ClientConn {
             );
             return Err(Error::SubIdMaxLengthError);
         }
+        // check if there is room for another subscription.
+        if !self.subscriptions.contains_key(&k) && self.subscriptions.len() >= self.max_subs {
+            return Err(Error::SubMaxExceededError);
+        }
+        self.generation += 1;
+        s.generation = self.generation;
         // check if an existing subscription exists, and replace if so
-        if self.subscriptions.contains_key(&k) {
-            self.subscriptions.remove(&k);
-            self.subscriptions.insert(k, s.clone());
+        if self.subscriptions.insert(k, s).is_some() {
             trace!(
-                "replaced existing subscription (cid: {}, sub: {:?})",
+                "replaced existing subscription (cid: {}, generation: {})",
+                self.get_client_prefix(),
+                self.generation
+            );
+        } else {
+            trace!(
+                "registered new subscription, currently have {} active subs (cid: {})",
+                self.subscriptions.len(),
                 self.get_client_prefix(),
-                s.get_id()
             );
-            return Ok(());
-        }
-
-        // check if there is room for another subscription.
-        if self.subscriptions.len() >= self.max_subs {
-            return Err(Error::SubMaxExceededError);
         }
-        // add subscription
-        self.subscriptions.insert(k, s);
-        trace!(
-            "registered new subscription, currently have {} active subs (cid: {})",
-            self.subscriptions.len(),
-            self.get_client_prefix(),
-        );
-        Ok(())
+        Ok(self.generation)
     }
 
     /// Remove the subscription for this connection.
@@ -152,78 +206,82 @@ impl ClientConn {
         );
     }
 
+    /// Replace the outstanding challenge with a new one.
     pub fn generate_auth_challenge(&mut self) {
-        self.auth = Challenge(Uuid::new_v4().to_string());
+        self.auth_challenge = Some((Uuid::new_v4().to_string(), Instant::now()));
     }
 
+    /// Authenticate with a signed AUTH event.  A client that is already
+    /// authenticated may authenticate again, as the same or another
+    /// pubkey; the new pubkey replaces the old one.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event does not answer the outstanding
+    /// challenge.  The challenge is replaced, so it can not be tried
+    /// again, and the failure is counted, unless the challenge had
+    /// expired.
     pub fn authenticate(&mut self, event: &Event, relay_url: &str) -> Result<()> {
-        match &self.auth {
-            Challenge(_) => (),
-            AuthPubkey(_) => {
-                // already authenticated
-                return Ok(());
+        let result = self.check_auth_event(event, relay_url);
+        match result {
+            Ok(()) => {
+                self.auth_pubkey = Some(event.pubkey.clone());
+                trace!(
+                    "authenticated pubkey {} (cid: {})",
+                    event.pubkey.chars().take(8).collect::<String>(),
+                    self.get_client_prefix()
+                );
             }
-            NoAuth => {
-                // unexpected AUTH request
-                return Err(Error::AuthFailure);
+            Err(ref e) => {
+                if !matches!(e, Error::AuthChallengeExpired) {
+                    self.auth_failures += 1;
+                }
+                if self.auth_challenge.is_some() {
+                    self.generate_auth_challenge();
+                }
             }
         }
-        match event.validate() {
-            Ok(_) => {
-                if event.kind != 22242 {
-                    return Err(Error::AuthFailure);
-                }
+        result
+    }
 
-                let curr_time = unix_time();
-                let past_cutoff = curr_time - 600; // 10 minutes
-                let future_cutoff = curr_time + 600; // 10 minutes
-                if event.created_at < past_cutoff || event.created_at > future_cutoff {
-                    return Err(Error::AuthFailure);
-                }
+    fn check_auth_event(&self, event: &Event, relay_url: &str) -> Result<()> {
+        let Some((sent_challenge, issued)) = &self.auth_challenge else {
+            // unexpected AUTH request
+            return Err(Error::AuthFailure);
+        };
+        if issued.elapsed() >= self.challenge_ttl {
+            return Err(Error::AuthChallengeExpired);
+        }
+        if event.validate().is_err() || event.kind != 22242 {
+            return Err(Error::AuthFailure);
+        }
 
-                let mut challenge: Option<&str> = None;
-                let mut relay: Option<&str> = None;
+        let curr_time = unix_time();
+        let past_cutoff = curr_time.saturating_sub(self.auth_max_skew);
+        let future_cutoff = curr_time + self.auth_max_skew;
+        if event.created_at < past_cutoff || event.created_at > future_cutoff {
+            return Err(Error::AuthFailure);
+        }
 
-                for tag in &event.tags {
-                    if tag.len() == 2 && tag.get(0) == Some(&"challenge".into()) {
-                        challenge = tag.get(1).map(|x| x.as_str());
-                    }
-                    if tag.len() == 2 && tag.get(0) == Some(&"relay".into()) {
-                        relay = tag.get(1).map(|x| x.as_str());
-                    }
-                }
+        let mut challenge: Option<&str> = None;
+        let mut relay: Option<&str> = None;
 
-                match (challenge, &self.auth) {
-                    (Some(received_challenge), Challenge(sent_challenge)) => {
-                        if received_challenge != sent_challenge {
-                            return Err(Error::AuthFailure);
-                        }
-                    }
-                    (_, _) => {
-                        return Err(Error::AuthFailure);
-                    }
-                }
+        for tag in &event.tags {
+            if tag.len() == 2 && tag.get(0) == Some(&"challenge".into()) {
+                challenge = tag.get(1).map(|x| x.as_str());
+            }
+            if tag.len() == 2 && tag.get(0) == Some(&"relay".into()) {
+                relay = tag.get(1).map(|x| x.as_str());
+            }
+        }
 
-                match (relay.and_then(host_str), host_str(relay_url)) {
-                    (Some(received_relay), Some(our_relay)) => {
-                        if received_relay != our_relay {
-                            return Err(Error::AuthFailure);
-                        }
-                    }
-                    (_, _) => {
-                        return Err(Error::AuthFailure);
-                    }
-                }
+        if challenge != Some(sent_challenge.as_str()) {
+            return Err(Error::AuthFailure);
+        }
 
-                self.auth = AuthPubkey(event.pubkey.clone());
-                trace!(
-                    "authenticated pubkey {} (cid: {})",
-                    event.pubkey.chars().take(8).collect::<String>(),
-                    self.get_client_prefix()
-                );
-                Ok(())
-            }
-            Err(_) => Err(Error::AuthFailure),
+        match (relay.and_then(host_str), host_str(relay_url)) {
+            (Some(received_relay), Some(our_relay)) if received_relay == our_relay => Ok(()),
+            (_, _) => Err(Error::AuthFailure),
         }
     }
 }
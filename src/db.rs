@@ -1,21 +1,30 @@
 //! Event persistence and querying
 use crate::config::Settings;
-use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::debounce::BroadcastDebouncer;
+use crate::error::{Error, RejectReason, Result};
+use crate::event::{BroadcastEvent, Event};
+use crate::membership::Membership;
 use crate::nauthz;
+use crate::nip05::is_domain_listed;
 use crate::notice::Notice;
-use crate::payment::PaymentMessage;
-use crate::repo::postgres::{PostgresPool, PostgresRepo};
+use crate::payment::{LedgerReason, PaymentMessage};
+use crate::pending::{can_admit_later, pending_validator, PendingEvent, PENDING_QUEUE_SIZE};
+use crate::quarantine::{PubkeyQuarantine, PubkeyStatus};
+use crate::read_policy::{delivery_note, ReadPolicy};
+use crate::repo::postgres::{
+    pool_options, retry_startup, PostgresPool, PostgresRepo, STARTUP_ATTEMPTS, STARTUP_RETRY_DELAY,
+};
+use crate::repo::sharded::ShardedRepo;
 use crate::repo::sqlite::SqliteRepo;
-use crate::repo::NostrRepo;
+use crate::repo::{NostrRepo, WriteResult};
 use crate::server::NostrMetrics;
+use crate::stats::WriteStats;
 use governor::clock::Clock;
 use governor::{Quota, RateLimiter};
 use log::LevelFilter;
 use nostr::key::FromPkStr;
 use nostr::key::Keys;
 use r2d2;
-use sqlx::pool::PoolOptions;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::ConnectOptions;
 use std::sync::Arc;
@@ -34,6 +43,7 @@ pub struct SubmittedEvent {
     pub origin: Option<String>,
     pub user_agent: Option<String>,
     pub auth_pubkey: Option<Vec<u8>>,
+    pub enforce_payment: bool, // pay-to-relay applies on the listener the event arrived on
 }
 
 /// Database file
@@ -44,6 +54,32 @@ pub const DB_FILE: &str = "nostr.db";
 ///
 /// Will panic if the pool could not be created.
 pub async fn build_repo(settings: &Settings, metrics: NostrMetrics) -> Arc<dyn NostrRepo> {
+    if settings.database.shards.is_empty() {
+        return build_engine_repo(settings, metrics).await;
+    }
+    let mut shards = vec![];
+    for shard in &settings.database.shards {
+        shards.push(build_engine_repo(&shard_settings(settings, shard), metrics.clone()).await);
+    }
+    info!("Sharding events over {} databases", shards.len());
+    Arc::new(ShardedRepo::new(shards))
+}
+
+/// Settings for one shard: its postgres connection string, or its
+/// sqlite data directory.
+fn shard_settings(settings: &Settings, shard: &str) -> Settings {
+    let mut shard_settings = settings.clone();
+    shard_settings.database.shards = vec![];
+    if settings.database.engine == "postgres" {
+        shard_settings.database.connection = shard.to_owned();
+        shard_settings.database.connection_write = None;
+    } else {
+        shard_settings.database.data_directory = shard.to_owned();
+    }
+    shard_settings
+}
+
+async fn build_engine_repo(settings: &Settings, metrics: NostrMetrics) -> Arc<dyn NostrRepo> {
     match settings.database.engine.as_str() {
         "sqlite" => Arc::new(build_sqlite_pool(settings, metrics).await),
         "postgres" => Arc::new(build_postgres_pool(settings, metrics).await),
@@ -62,50 +98,84 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
     let mut options: PgConnectOptions = settings.database.connection.as_str().parse().unwrap();
     options.log_statements(LevelFilter::Debug);
     options.log_slow_statements(LevelFilter::Warn, Duration::from_secs(60));
-
-    let pool: PostgresPool = PoolOptions::new()
-        .max_connections(settings.database.max_conn)
-        .min_connections(settings.database.min_conn)
-        .idle_timeout(Duration::from_secs(60))
-        .connect_with(options)
-        .await
-        .unwrap();
+    let pool = connect_postgres(settings, options).await;
 
     let write_pool: PostgresPool = match &settings.database.connection_write {
         Some(cfg_write) => {
             let mut options_write: PgConnectOptions = cfg_write.as_str().parse().unwrap();
             options_write.log_statements(LevelFilter::Debug);
             options_write.log_slow_statements(LevelFilter::Warn, Duration::from_secs(60));
-
-            PoolOptions::new()
-                .max_connections(settings.database.max_conn)
-                .min_connections(settings.database.min_conn)
-                .idle_timeout(Duration::from_secs(60))
-                .connect_with(options_write)
-                .await
-                .unwrap()
+            connect_postgres(settings, options_write).await
         }
         None => pool.clone(),
     };
 
-    let repo = PostgresRepo::new(pool, write_pool, metrics);
+    let repo = PostgresRepo::new(pool, write_pool, metrics, settings);
 
     // Panic on migration failure
-    let version = repo.migrate_up().await.unwrap();
+    let version = repo
+        .migrate_up()
+        .await
+        .unwrap_or_else(|e| panic!("could not migrate postgres database: {e}"));
     info!("Postgres migration completed, at v{}", version);
     // startup scheduled tasks
     repo.start().await.ok();
     repo
 }
 
+/// Open a connection pool, waiting for the database if it can not be
+/// reached yet.
+async fn connect_postgres(settings: &Settings, options: PgConnectOptions) -> PostgresPool {
+    retry_startup(
+        "connect to postgres",
+        STARTUP_ATTEMPTS,
+        STARTUP_RETRY_DELAY,
+        || async {
+            let pool = pool_options(settings)
+                .max_connections(settings.database.max_conn)
+                .min_connections(settings.database.min_conn)
+                .idle_timeout(Duration::from_secs(60))
+                .connect_with(options.clone())
+                .await?;
+            Ok(pool)
+        },
+    )
+    .await
+    .unwrap_or_else(|e| panic!("could not connect to postgres: {e:?}"))
+}
+
+/// Decide whether to enforce a rule that an event has failed.  In
+/// shadow mode, the would-be rejection is logged and counted, and the
+/// event is accepted.  Returns true if the event should be rejected.
+pub fn enforce(
+    shadow: bool,
+    metrics: &NostrMetrics,
+    rule: &str,
+    event_id: &str,
+    reason: &str,
+) -> bool {
+    if shadow {
+        let id_prefix: String = event_id.chars().take(8).collect();
+        info!("shadow mode: accepting event {id_prefix:?} that would be rejected (rule: {rule}, reason: {reason})");
+        metrics.shadow_rejections.with_label_values(&[rule]).inc();
+    }
+    !shadow
+}
+
 /// Spawn a database writer that persists events to the `SQLite` store.
+#[allow(clippy::too_many_arguments)]
 pub async fn db_writer(
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
-    bcast_tx: tokio::sync::broadcast::Sender<Event>,
+    bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     metadata_tx: tokio::sync::broadcast::Sender<Event>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    membership: Membership,
+    write_stats: Option<WriteStats>,
+    quarantine: PubkeyQuarantine,
+    metrics: NostrMetrics,
+    read_policy: Arc<dyn ReadPolicy>,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<()> {
     // are we performing NIP-05 checking?
@@ -114,13 +184,30 @@ pub async fn db_writer(
     let nip05_enabled = settings.verified_users.is_enabled();
 
     let pay_to_relay_enabled = settings.pay_to_relay.enabled;
+    let shadow = settings.options.shadow_enforcement;
+    if shadow {
+        info!("shadow enforcement enabled; policy rejections will be logged, not enforced");
+    }
     let cost_per_event = settings.pay_to_relay.cost_per_event;
+    // events each account publishes before paying for them
+    let free_events = settings.pay_to_relay.free_events;
+    // explain to publishers why accepted events may not reach everyone
+    let verbose = settings.options.verbose_notices;
+    // send publishers of outdated replaceable events the stored version
+    let send_newer_version = settings.options.send_newer_version;
+    // reject events older than the author's newest stored event
+    let monotonic = settings.options.monotonic_created_at;
+    // refuse the old events of erased pubkeys
+    let tombstones = settings.retention.erasure_tombstone_days > 0;
+    // rapid updates of replaceable events may be broadcast once
+    let debouncer = BroadcastDebouncer::new(&settings, bcast_tx.clone());
     debug!("Pay to relay: {}", pay_to_relay_enabled);
 
     //upgrade_db(&mut pool.get()?)?;
 
-    // Make a copy of the whitelist
-    let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    // a signed membership list, once received, replaces the
+    // whitelist; a contact list adds to it.
+    let whitelisted = |pubkey: &String| -> Option<bool> { membership.whitelisted(pubkey) };
 
     // get rate limit settings
     let rps_setting = settings.limits.messages_per_sec;
@@ -141,6 +228,24 @@ pub async fn db_writer(
     } else {
         None
     };
+    // events stored before the admission server decides on them are
+    // handed to a separate validator.
+    let pending_tx = match grpc_client {
+        Some(ref c) if settings.grpc.async_admission => {
+            info!("admitting events asynchronously");
+            let (pending_tx, pending_rx) = tokio::sync::mpsc::channel(PENDING_QUEUE_SIZE);
+            pending_validator(
+                repo.clone(),
+                c.clone(),
+                pending_rx,
+                bcast_tx.clone(),
+                metrics.clone(),
+                shadow,
+            );
+            Some(pending_tx)
+        }
+        _ => None,
+    };
 
     //let gprc_client = settings.grpc.event_admission_server.map(|s| {
     //        event_admitter_connect(&s);
@@ -163,18 +268,30 @@ pub async fn db_writer(
         let subm_event = next_event.unwrap();
         let event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
+        let enforce_payment = subm_event.enforce_payment;
 
         // Check that event kind isn't blacklisted
         let kinds_blacklist = &settings.limits.event_kind_blacklist.clone();
         if let Some(event_kind_blacklist) = kinds_blacklist {
-            if event_kind_blacklist.contains(&event.kind) {
+            if event_kind_blacklist.contains(&event.kind)
+                && enforce(
+                    shadow,
+                    &metrics,
+                    "kind_blacklist",
+                    &event.id,
+                    "blacklisted kind",
+                )
+            {
                 debug!(
                     "rejecting event: {}, blacklisted kind: {}",
                     &event.get_event_id_prefix(),
                     &event.kind
                 );
                 notice_tx
-                    .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
+                    .try_send(Notice::rejected(
+                        event.id,
+                        RejectReason::Blocked("event kind is blocked by relay".to_owned()),
+                    ))
                     .ok();
                 continue;
             }
@@ -183,14 +300,25 @@ pub async fn db_writer(
         // Check that event kind isn't allowlisted
         let kinds_allowlist = &settings.limits.event_kind_allowlist.clone();
         if let Some(event_kind_allowlist) = kinds_allowlist {
-            if !event_kind_allowlist.contains(&event.kind) {
+            if !event_kind_allowlist.contains(&event.kind)
+                && enforce(
+                    shadow,
+                    &metrics,
+                    "kind_allowlist",
+                    &event.id,
+                    "kind not allowed",
+                )
+            {
                 debug!(
                     "rejecting event: {}, allowlist kind: {}",
                     &event.get_event_id_prefix(),
                     &event.kind
                 );
                 notice_tx
-                    .try_send(Notice::blocked(event.id, "event kind is blocked by relay"))
+                    .try_send(Notice::rejected(
+                        event.id,
+                        RejectReason::Blocked("event kind is blocked by relay".to_owned()),
+                    ))
                     .ok();
                 continue;
             }
@@ -203,28 +331,37 @@ pub async fn db_writer(
         let mut user_balance: Option<u64> = None;
         if !pay_to_relay_enabled {
             // check if this event is authorized.
-            if let Some(allowed_addrs) = whitelist {
-                // TODO: incorporate delegated pubkeys
-                // if the event address is not in allowed_addrs.
-                if !allowed_addrs.contains(&event.pubkey) {
+            // TODO: incorporate delegated pubkeys
+            // the admin may always update the membership list.
+            if !membership.is_membership_event(&event) {
+                // if the event address is not allowed.
+                if whitelisted(&event.pubkey) == Some(false)
+                    && enforce(
+                        shadow,
+                        &metrics,
+                        "whitelist",
+                        &event.id,
+                        "unauthorized author",
+                    )
+                {
                     debug!(
                         "rejecting event: {}, unauthorized author",
                         event.get_event_id_prefix()
                     );
                     notice_tx
-                        .try_send(Notice::blocked(
+                        .try_send(Notice::rejected(
                             event.id,
-                            "pubkey is not allowed to publish to this relay",
+                            RejectReason::Blocked(
+                                "pubkey is not allowed to publish to this relay".to_owned(),
+                            ),
                         ))
                         .ok();
                     continue;
                 }
             }
-        } else {
+        } else if enforce_payment {
             // If the user is on whitelist there is no need to check if the user is admitted or has balance to post
-            if whitelist.is_none()
-                || (whitelist.is_some() && !whitelist.as_ref().unwrap().contains(&event.pubkey))
-            {
+            if whitelisted(&event.pubkey) != Some(true) {
                 let key = Keys::from_pk_str(&event.pubkey).unwrap();
                 match repo.get_account_balance(&key).await {
                     Ok((user_admitted, balance)) => {
@@ -238,27 +375,40 @@ pub async fn db_writer(
                                 .send(PaymentMessage::CheckAccount(event.pubkey))
                                 .ok();
                             notice_tx
-                                .try_send(Notice::blocked(event.id, "User is not admitted"))
+                                .try_send(Notice::rejected(
+                                    event.id,
+                                    RejectReason::Blocked("user is not admitted".to_owned()),
+                                ))
                                 .ok();
                             continue;
                         }
 
-                        // Checks that user has enough balance to post
+                        // Checks that user has enough balance to post,
+                        // unless free events remain
                         // TODO: this should send an invoice to user to top up
-                        if balance < cost_per_event {
+                        let free_event = balance < cost_per_event
+                            && free_events > 0
+                            && match repo.published_event_count(&key).await {
+                                Ok(published) => published < free_events,
+                                Err(e) => {
+                                    warn!("could not count published events: {:?}", e);
+                                    false
+                                }
+                            };
+                        if balance < cost_per_event && !free_event {
                             debug!("user: {}, does not have a balance", &event.pubkey,);
                             notice_tx
-                                .try_send(Notice::blocked(event.id, "Insufficient balance"))
+                                .try_send(Notice::rejected(
+                                    event.id,
+                                    RejectReason::Blocked("insufficient balance".to_owned()),
+                                ))
                                 .ok();
                             continue;
                         }
                         user_balance = Some(balance);
                         debug!("User balance: {:?}", user_balance);
                     }
-                    Err(
-                        Error::SqlError(rusqlite::Error::QueryReturnedNoRows)
-                        | Error::SqlxError(sqlx::Error::RowNotFound),
-                    ) => {
+                    Err(Error::NotFound) => {
                         // User does not exist
                         info!("Unregistered user");
                         if settings.pay_to_relay.sign_ups && settings.pay_to_relay.direct_message {
@@ -266,14 +416,24 @@ pub async fn db_writer(
                                 .send(PaymentMessage::NewAccount(event.pubkey))
                                 .ok();
                         }
-                        let msg = "Pubkey not registered";
-                        notice_tx.try_send(Notice::error(event.id, msg)).ok();
+                        let msg = "pubkey not registered";
+                        notice_tx
+                            .try_send(Notice::rejected(
+                                event.id,
+                                RejectReason::Blocked(msg.to_owned()),
+                            ))
+                            .ok();
                         continue;
                     }
                     Err(err) => {
                         warn!("Error checking admission status: {:?}", err);
                         let msg = "relay experienced an error checking your admission status";
-                        notice_tx.try_send(Notice::error(event.id, msg)).ok();
+                        notice_tx
+                            .try_send(Notice::rejected(
+                                event.id,
+                                RejectReason::Error(msg.to_owned()),
+                            ))
+                            .ok();
                         // Other error
                         continue;
                     }
@@ -281,6 +441,45 @@ pub async fn db_writer(
             }
         }
 
+        // replaceable events are checked against the version they
+        // replace when written; ephemeral events are not stored.
+        if monotonic
+            && !event.is_replaceable()
+            && !event.is_param_replaceable()
+            && !event.is_ephemeral()
+        {
+            match repo.latest_created_at(&event.pubkey).await {
+                Ok(Some(latest))
+                    if event.created_at < latest
+                        && enforce(
+                            shadow,
+                            &metrics,
+                            "monotonic",
+                            &event.id,
+                            "created_at not monotonic",
+                        ) =>
+                {
+                    debug!(
+                        "rejecting event: {}, created_at {} is older than the author's newest event ({})",
+                        event.get_event_id_prefix(),
+                        event.created_at,
+                        latest
+                    );
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::Invalid("created_at not monotonic".to_owned()),
+                        ))
+                        .ok();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("checking the author's newest event failed: {:?}", e);
+                }
+            }
+        }
+
         // send any metadata events to the NIP-05 verifier
         if nip05_active && event.is_kind_metadata() {
             // we are sending this prior to even deciding if we
@@ -307,37 +506,43 @@ pub async fn db_writer(
                             uv.name.to_string(),
                             event.get_author_prefix()
                         );
-                    } else {
+                    } else if enforce(shadow, &metrics, "nip05", &event.id, "verification invalid")
+                    {
                         info!(
                             "rejecting event, author ({:?} / {:?}) verification invalid (expired/wrong domain)",
                             uv.name.to_string(),
                             event.get_author_prefix()
                         );
                         notice_tx
-                            .try_send(Notice::blocked(
+                            .try_send(Notice::rejected(
                                 event.id,
-                                "NIP-05 verification is no longer valid (expired/wrong domain)",
+                                RejectReason::Blocked(
+                                    "NIP-05 verification is no longer valid (expired/wrong domain)"
+                                        .to_owned(),
+                                ),
                             ))
                             .ok();
                         continue;
                     }
                 }
-                Err(
-                    Error::SqlError(rusqlite::Error::QueryReturnedNoRows)
-                    | Error::SqlxError(sqlx::Error::RowNotFound),
-                ) => {
+                Err(Error::NotFound)
+                    if enforce(shadow, &metrics, "nip05", &event.id, "not verified") =>
+                {
                     debug!(
                         "no verification records found for pubkey: {:?}",
                         event.get_author_prefix()
                     );
                     notice_tx
-                        .try_send(Notice::blocked(
+                        .try_send(Notice::rejected(
                             event.id,
-                            "NIP-05 verification needed to publish events",
+                            RejectReason::Blocked(
+                                "NIP-05 verification needed to publish events".to_owned(),
+                            ),
                         ))
                         .ok();
                     continue;
                 }
+                Err(Error::NotFound) => {}
                 Err(e) => {
                     warn!("checking nip05 verification status failed: {:?}", e);
                     continue;
@@ -345,27 +550,100 @@ pub async fn db_writer(
             }
         }
 
+        // only authors verified under an allowed domain may publish
+        if let Some(domains) = &settings.authorization.allowed_nip05_domains {
+            let verified = matches!(
+                &validation,
+                Some(Ok(uv)) if uv.is_valid(&settings.verified_users)
+                    && is_domain_listed(&uv.name.domain, domains)
+            );
+            if !verified
+                && whitelisted(&event.pubkey) != Some(true)
+                && !membership.is_membership_event(&event)
+                && enforce(
+                    shadow,
+                    &metrics,
+                    "nip05_domain",
+                    &event.id,
+                    "author not verified under an allowed domain",
+                )
+            {
+                debug!(
+                    "rejecting event: {}, author not verified under an allowed domain",
+                    event.get_event_id_prefix()
+                );
+                notice_tx
+                    .try_send(Notice::rejected(
+                        event.id,
+                        RejectReason::Restricted(
+                            "publishing requires NIP-05 verification under an allowed domain"
+                                .to_owned(),
+                        ),
+                    ))
+                    .ok();
+                continue;
+            }
+        }
+
         // nip05 address
         let nip05_address: Option<crate::nip05::Nip05Name> =
             validation.and_then(|x| x.ok().map(|y| y.name));
 
+        // events that can wait for the admission decision are
+        // stored as pending, and left to the validator.
+        let admit_later = pending_tx.is_some()
+            && can_admit_later(&event)
+            && !membership.is_membership_event(&event);
+
+        // a full queue of pending events sheds load: the event is
+        // refused before it is stored, rather than queued without
+        // bound for the admission server.
+        let pending_permit = match pending_tx.as_ref().filter(|_| admit_later) {
+            Some(pending_tx) => match pending_tx.try_reserve() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    debug!(
+                        "rejecting event: {}, pending queue is full",
+                        event.get_event_id_prefix()
+                    );
+                    metrics.pending_events.with_label_values(&["shed"]).inc();
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::RateLimited("relay is busy, try again later".to_owned()),
+                        ))
+                        .ok();
+                    continue;
+                }
+            },
+            None => None,
+        };
+
         // GRPC check
-        if let Some(ref mut c) = grpc_client {
+        if let Some(c) = grpc_client.as_mut().filter(|_| !admit_later) {
             trace!("checking if grpc permits");
             let grpc_start = Instant::now();
             let decision_res = c
                 .admit_event(
                     &event,
                     &subm_event.source_ip,
-                    subm_event.origin,
-                    subm_event.user_agent,
-                    nip05_address,
-                    subm_event.auth_pubkey,
+                    subm_event.origin.clone(),
+                    subm_event.user_agent.clone(),
+                    nip05_address.clone(),
+                    subm_event.auth_pubkey.clone(),
                 )
                 .await;
             match decision_res {
                 Ok(decision) => {
-                    if !decision.permitted() {
+                    if !decision.permitted()
+                        && enforce(
+                            shadow,
+                            &metrics,
+                            "grpc",
+                            &event.id,
+                            &decision.message().unwrap_or_default(),
+                        )
+                    {
                         // GPRC returned a decision to reject this event
                         info!(
                             "GRPC rejected event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
@@ -376,9 +654,9 @@ pub async fn db_writer(
                             subm_event.source_ip
                         );
                         notice_tx
-                            .try_send(Notice::blocked(
+                            .try_send(Notice::rejected(
                                 event.id,
-                                &decision.message().unwrap_or_default(),
+                                RejectReason::Blocked(decision.message().unwrap_or_default()),
                             ))
                             .ok();
                         continue;
@@ -390,10 +668,73 @@ pub async fn db_writer(
             }
         }
 
+        // events from before their author was erased are not
+        // published again.
+        if tombstones {
+            match repo.erased_at(&event.pubkey).await {
+                Ok(Some(erased_at))
+                    if event.created_at <= erased_at
+                        && enforce(shadow, &metrics, "erased", &event.id, "erased pubkey") =>
+                {
+                    debug!(
+                        "rejecting event: {}, author was erased",
+                        event.get_event_id_prefix()
+                    );
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::Blocked(
+                                "events of this pubkey from before it was erased are not accepted"
+                                    .to_owned(),
+                            ),
+                        ))
+                        .ok();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("could not check erased pubkeys: {:?}", e),
+            }
+        }
+
+        // authors new to the relay are quarantined, and banned
+        // authors may not publish.  Stored events of quarantined
+        // authors count towards their release.
+        let mut quarantined = false;
+        if quarantine.is_enabled() {
+            match quarantine.admit(repo.as_ref(), &event.pubkey).await {
+                Ok(PubkeyStatus::Banned)
+                    if enforce(shadow, &metrics, "banned", &event.id, "banned pubkey") =>
+                {
+                    debug!(
+                        "rejecting event: {}, banned author",
+                        event.get_event_id_prefix()
+                    );
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::Blocked("pubkey is banned from this relay".to_owned()),
+                        ))
+                        .ok();
+                    continue;
+                }
+                Ok(status) => {
+                    quarantined = status == PubkeyStatus::Quarantined && !event.is_ephemeral();
+                }
+                Err(e) => warn!("could not check quarantine status: {:?}", e),
+            }
+        }
+
         // TODO: cache recent list of authors to remove a DB call.
         let start = Instant::now();
+        // the id is moved into the OK notice, but is needed for the ledger
+        let event_id = event.id.clone();
+        // ephemeral events are not stored, so are not counted
+        let event_bytes = write_stats
+            .as_ref()
+            .filter(|_| !event.is_ephemeral())
+            .map(|_| serde_json::to_vec(&event).map_or(0, |v| v.len()));
         if event.is_ephemeral() {
-            bcast_tx.send(event.clone()).ok();
+            bcast_tx.send(event.clone().into()).ok();
             debug!(
                 "published ephemeral event: {:?} from: {:?} in: {:?}",
                 event.get_event_id_prefix(),
@@ -403,13 +744,66 @@ pub async fn db_writer(
             event_write = true;
 
             // send OK message
+            let note = delivery_note(&event, read_policy.as_ref()).filter(|_| verbose);
             notice_tx.try_send(Notice::saved(event.id)).ok();
+            if let Some(note) = note {
+                notice_tx.try_send(Notice::message(note)).ok();
+            }
         } else {
-            match repo.write_event(&event).await {
-                Ok(updated) => {
+            let written = if admit_later {
+                repo.write_pending_event(&event)
+                    .await
+                    .map(WriteResult::Added)
+            } else {
+                repo.write_event(&event).await
+            };
+            match written {
+                Ok(WriteResult::Superseded(newer)) => {
+                    trace!("ignoring superseded replaceable event");
+                    // sent first, so clients that stop listening once
+                    // they have the OK still see it.
+                    let auth_pubkey = subm_event.auth_pubkey.as_ref().map(hex::encode);
+                    if send_newer_version && read_policy.can_read(&newer, auth_pubkey.as_deref()) {
+                        notice_tx.try_send(Notice::newer_version(*newer)).ok();
+                    }
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::Superseded("have newer version".to_owned()),
+                        ))
+                        .ok();
+                }
+                Ok(WriteResult::Added(updated)) => {
                     if updated == 0 {
                         trace!("ignoring duplicate or deleted event");
-                        notice_tx.try_send(Notice::duplicate(event.id)).ok();
+                        notice_tx
+                            .try_send(Notice::rejected(
+                                event.id,
+                                RejectReason::Duplicate("already have this event".to_owned()),
+                            ))
+                            .ok();
+                    } else if let Some(permit) = pending_permit {
+                        debug!(
+                            "stored pending event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
+                            event.get_event_id_prefix(),
+                            event.kind,
+                            event.get_author_prefix(),
+                            start.elapsed(),
+                            subm_event.source_ip,
+                        );
+                        // pending events are charged and rate limited
+                        // when stored, whatever the decision.
+                        event_write = true;
+                        metrics.pending_events.with_label_values(&["stored"]).inc();
+                        notice_tx.try_send(Notice::pending(event.id.clone())).ok();
+                        permit.send(PendingEvent {
+                            event: event.clone(),
+                            source_ip: subm_event.source_ip,
+                            origin: subm_event.origin,
+                            user_agent: subm_event.user_agent,
+                            nip05: nip05_address,
+                            auth_pubkey: subm_event.auth_pubkey,
+                        });
                     } else {
                         info!(
                             "persisted event: {:?} (kind: {}) from: {:?} in: {:?} (IP: {:?})",
@@ -420,30 +814,67 @@ pub async fn db_writer(
                             subm_event.source_ip,
                         );
                         event_write = true;
+                        // apply membership changes to subsequent events
+                        membership.update(&event);
                         // send this out to all clients
-                        bcast_tx.send(event.clone()).ok();
+                        debouncer.send(event.clone());
+                        let note = quarantine
+                            .delivery_note(&event)
+                            .or_else(|| delivery_note(&event, read_policy.as_ref()))
+                            .filter(|_| verbose);
                         notice_tx.try_send(Notice::saved(event.id)).ok();
+                        if let Some(note) = note {
+                            notice_tx.try_send(Notice::message(note)).ok();
+                        }
                     }
                 }
                 Err(err) => {
                     warn!("event insert failed: {:?}", err);
                     let msg = "relay experienced an error trying to publish the latest event";
-                    notice_tx.try_send(Notice::error(event.id, msg)).ok();
+                    notice_tx
+                        .try_send(Notice::rejected(
+                            event.id,
+                            RejectReason::Error(msg.to_owned()),
+                        ))
+                        .ok();
                 }
             }
         }
 
         // use rate limit, if defined, and if an event was actually written.
         if event_write {
+            // counted in memory; a failed flush never delays the writer
+            if let (Some(stats), Some(bytes)) = (write_stats.as_ref(), event_bytes) {
+                stats.record(&event.pubkey, event.kind, bytes);
+            }
+            if quarantined {
+                if let Err(e) = quarantine.count_event(repo.as_ref(), &event.pubkey).await {
+                    warn!("could not count quarantined event: {:?}", e);
+                }
+            }
             // If pay to relay is diabaled or the cost per event is 0
             // No need to update user balance
             if pay_to_relay_enabled && cost_per_event > 0 {
                 // If the user balance is some, user was not on whitelist
-                // Their balance should be reduced by the cost per event
+                // Their balance should be reduced by the cost per event,
+                // once their free events are used up
                 if let Some(_balance) = user_balance {
                     let pubkey = Keys::from_pk_str(&event.pubkey)?;
-                    repo.update_account_balance(&pubkey, false, cost_per_event)
+                    let published = repo.count_published_event(&pubkey).await?;
+                    if published > free_events {
+                        repo.update_account_balance(
+                            &pubkey,
+                            false,
+                            cost_per_event,
+                            LedgerReason::Publication,
+                            Some(&event_id),
+                        )
                         .await?;
+                        metrics
+                            .payment_funnel
+                            .with_label_values(&["publication_debit"])
+                            .inc();
+                    }
                 }
             }
             if let Some(ref lim) = lim_opt {
@@ -478,4 +909,11 @@ pub struct QueryResult {
     pub sub_id: String,
     /// Serialized event
     pub event: String,
+    /// When the relay first saw the event, if the subscription asked
+    pub first_seen: Option<u64>,
+    /// Generation of the subscription that requested the query
+    pub generation: u64,
+    /// Set on the `EOSE` of a query stopped at its deadline, or
+    /// aborted, whose results may be incomplete
+    pub timed_out: bool,
 }
@@ -0,0 +1,154 @@
+//! Debouncing broadcasts of replaceable events
+//!
+//! Clients that update a profile (kind 0) or relay list (kind 10002)
+//! several times in quick succession would otherwise have every
+//! intermediate version broadcast to subscribers.  With
+//! `options.debounce_replaceable_ms` set, the first version of an
+//! address (pubkey, kind and `d` tag) starts a window; versions
+//! arriving within it replace the one held, and only the newest is
+//! broadcast when the window ends.  Every version is still stored as
+//! usual, and the window never restarts, so an update is delayed by
+//! at most one window.
+use crate::config::Settings;
+use crate::event::{BroadcastEvent, Event};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Sender;
+
+/// Events are debounced per author, kind and `d` tag
+type Address = (String, u64, Option<String>);
+
+/// Sends events to subscribers, holding back replaceable events of
+/// the configured kinds until their debounce window ends.
+#[derive(Clone)]
+pub struct BroadcastDebouncer {
+    bcast_tx: Sender<BroadcastEvent>,
+    window: Option<Duration>,
+    /// Kinds debounced; all replaceable kinds if `None`
+    kinds: Option<Vec<u64>>,
+    pending: Arc<Mutex<HashMap<Address, BroadcastEvent>>>,
+}
+
+/// Does `a` replace `b`?  The newer event wins, or for equal
+/// timestamps the one with the lowest id.
+fn replaces(a: &Event, b: &Event) -> bool {
+    a.created_at > b.created_at || (a.created_at == b.created_at && a.id < b.id)
+}
+
+impl BroadcastDebouncer {
+    #[must_use]
+    pub fn new(settings: &Settings, bcast_tx: Sender<BroadcastEvent>) -> Self {
+        BroadcastDebouncer {
+            bcast_tx,
+            window: settings
+                .options
+                .debounce_replaceable_ms
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            kinds: settings.options.debounce_kinds.clone(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn debounced(&self, event: &Event) -> bool {
+        (event.is_replaceable() || event.is_param_replaceable())
+            && self
+                .kinds
+                .as_ref()
+                .map_or(true, |k| k.contains(&event.kind))
+    }
+
+    /// Broadcast an event, now or when its debounce window ends.
+    pub fn send(&self, event: Event) {
+        let window = match self.window {
+            Some(window) if self.debounced(&event) => window,
+            _ => {
+                self.bcast_tx.send(event.into()).ok();
+                return;
+            }
+        };
+        let address = (event.pubkey.clone(), event.kind, event.distinct_param());
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(held) = pending.get_mut(&address) {
+            // a window is already open; the flush sends the newest
+            if replaces(&event, &held.event) {
+                *held = event.into();
+            }
+            return;
+        }
+        pending.insert(address.clone(), event.into());
+        drop(pending);
+        let debouncer = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            debouncer.flush(&address);
+        });
+    }
+
+    fn flush(&self, address: &Address) {
+        let held = self.pending.lock().unwrap().remove(address);
+        if let Some(mut held) = held {
+            // lag is measured from when the event is actually sent
+            held.broadcast_at = Instant::now();
+            self.bcast_tx.send(held).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    fn event(kind: u64, created_at: u64, id: &str) -> Event {
+        Event {
+            id: id.to_owned(),
+            pubkey: "a".repeat(64),
+            delegated_by: None,
+            created_at,
+            kind,
+            tags: vec![],
+            content: String::new(),
+            sig: String::new(),
+            tagidx: None,
+        }
+    }
+
+    fn debouncer(ms: u64) -> (BroadcastDebouncer, broadcast::Receiver<BroadcastEvent>) {
+        let mut settings = Settings::default();
+        settings.options.debounce_replaceable_ms = Some(ms);
+        let (tx, rx) = broadcast::channel(16);
+        (BroadcastDebouncer::new(&settings, tx), rx)
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_version_is_broadcast() {
+        let (debouncer, mut rx) = debouncer(50);
+        debouncer.send(event(0, 10, "first"));
+        debouncer.send(event(0, 12, "third"));
+        // arriving late does not make an older version win
+        debouncer.send(event(0, 11, "second"));
+        debouncer.send(event(1, 10, "note"));
+        // other kinds are sent at once
+        assert_eq!(rx.recv().await.unwrap().event.id, "note");
+        assert!(rx.try_recv().is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(rx.try_recv().unwrap().event.id, "third");
+        assert!(rx.try_recv().is_err());
+        // a new window opens for the next update
+        debouncer.send(event(0, 13, "fourth"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(rx.try_recv().unwrap().event.id, "fourth");
+    }
+
+    #[tokio::test]
+    async fn disabled_by_default() {
+        let (tx, mut rx) = broadcast::channel(16);
+        let debouncer = BroadcastDebouncer::new(&Settings::default(), tx);
+        debouncer.send(event(0, 10, "first"));
+        debouncer.send(event(0, 11, "second"));
+        assert_eq!(rx.try_recv().unwrap().event.id, "first");
+        assert_eq!(rx.try_recv().unwrap().event.id, "second");
+    }
+}
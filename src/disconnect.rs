@@ -0,0 +1,233 @@
+//! Ending websocket connections with a close handshake
+//!
+//! Clients that see a connection simply drop tend to report a network
+//! error and reconnect at once.  A close frame with a status code and
+//! reason tells them why, and whether retrying is worthwhile.
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::error::CapacityError;
+use tungstenite::error::Error as WsError;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// How long to wait for the client to acknowledge a close frame
+const CLOSE_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why the relay is ending a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The relay is shutting down
+    Shutdown,
+    /// The relay is starting up, and not yet serving clients
+    Starting,
+    /// The client sent nothing (not even a pong) for too long
+    Idle,
+    /// A message or frame was larger than the relay accepts
+    MessageTooBig,
+    /// The client's address already has too many connections
+    TooManyConnections,
+    /// Connections are not accepted from the client's country
+    Geoblocked,
+    /// The client broke the websocket protocol
+    Protocol,
+    /// The client sent a message type the relay does not support
+    UnsupportedMessage,
+    /// The client failed to authenticate too many times
+    AuthFailures,
+    /// The client authenticated as a banned pubkey
+    Banned,
+    /// The relay fell too far behind sending events to the client
+    Overloaded,
+}
+
+impl CloseReason {
+    /// Status code sent in the close frame
+    #[must_use]
+    pub fn code(self) -> CloseCode {
+        match self {
+            CloseReason::Shutdown | CloseReason::Starting | CloseReason::Overloaded => {
+                CloseCode::Again
+            }
+            CloseReason::Idle => CloseCode::Away,
+            CloseReason::MessageTooBig => CloseCode::Size,
+            CloseReason::TooManyConnections
+            | CloseReason::Geoblocked
+            | CloseReason::AuthFailures
+            | CloseReason::Banned => CloseCode::Policy,
+            CloseReason::Protocol => CloseCode::Protocol,
+            CloseReason::UnsupportedMessage => CloseCode::Unsupported,
+        }
+    }
+
+    /// Short explanation sent in the close frame
+    #[must_use]
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::Shutdown => "relay is shutting down, try again later",
+            CloseReason::Starting => "relay is starting up, try again later",
+            CloseReason::Idle => "connection idle for too long",
+            CloseReason::MessageTooBig => "message too large",
+            CloseReason::TooManyConnections => "too many connections from this address",
+            CloseReason::Geoblocked => "connections from your region are not accepted",
+            CloseReason::Protocol => "websocket protocol error",
+            CloseReason::UnsupportedMessage => "unsupported message type",
+            CloseReason::AuthFailures => "too many failed authentication attempts",
+            CloseReason::Banned => "pubkey is banned from this relay",
+            CloseReason::Overloaded => "relay is overloaded, try again later",
+        }
+    }
+
+    /// The reason to close a connection after a read error, if any.
+    /// I/O errors and closed connections can not be answered.
+    #[must_use]
+    pub fn for_error(e: &WsError) -> Option<CloseReason> {
+        match e {
+            WsError::Capacity(CapacityError::MessageTooLong { .. }) => {
+                Some(CloseReason::MessageTooBig)
+            }
+            WsError::Io(_) | WsError::AlreadyClosed | WsError::ConnectionClosed => None,
+            _ => Some(CloseReason::Protocol),
+        }
+    }
+
+    fn frame(self) -> CloseFrame<'static> {
+        CloseFrame {
+            code: self.code(),
+            reason: self.reason().into(),
+        }
+    }
+}
+
+/// Send a close frame, and wait briefly for the client to acknowledge
+/// it.  Messages the client sends in the meantime are discarded.
+pub async fn close_connection<S>(ws_stream: &mut WebSocketStream<S>, reason: CloseReason)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if ws_stream
+        .send(Message::Close(Some(reason.frame())))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let acknowledged = async {
+        while let Some(Ok(msg)) = ws_stream.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    };
+    tokio::time::timeout(CLOSE_ACK_TIMEOUT, acknowledged)
+        .await
+        .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+    use tungstenite::protocol::{Role, WebSocketConfig};
+
+    async fn ws_pair(
+        max_message_size: Option<usize>,
+    ) -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig {
+            max_message_size,
+            ..Default::default()
+        };
+        let server = WebSocketStream::from_raw_socket(server_io, Role::Server, Some(config)).await;
+        let client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+        (server, client)
+    }
+
+    /// Close the server side, and return the frame the client received.
+    async fn closed_with(
+        mut server: WebSocketStream<DuplexStream>,
+        mut client: WebSocketStream<DuplexStream>,
+        reason: CloseReason,
+    ) -> CloseFrame<'static> {
+        let server_task = tokio::spawn(async move { close_connection(&mut server, reason).await });
+        let frame = match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => frame.into_owned(),
+            other => panic!("expected a close frame, got {other:?}"),
+        };
+        // reading the close frame queued the acknowledgement
+        client.flush().await.ok();
+        tokio::time::timeout(Duration::from_millis(500), server_task)
+            .await
+            .expect("the close was acknowledged")
+            .unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn shutdown_asks_clients_to_retry_later() {
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::Shutdown).await;
+        assert_eq!(frame.code, CloseCode::Again);
+        assert_eq!(u16::from(frame.code), 1013);
+        assert_eq!(frame.reason, "relay is shutting down, try again later");
+    }
+
+    #[tokio::test]
+    async fn startup_asks_clients_to_retry_later() {
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::Starting).await;
+        assert_eq!(u16::from(frame.code), 1013);
+        assert_eq!(frame.reason, "relay is starting up, try again later");
+    }
+
+    #[tokio::test]
+    async fn connection_limit_is_a_policy_violation() {
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::TooManyConnections).await;
+        assert_eq!(u16::from(frame.code), 1008);
+        assert_eq!(frame.reason, "too many connections from this address");
+    }
+
+    #[tokio::test]
+    async fn overload_asks_clients_to_retry_later() {
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::Overloaded).await;
+        assert_eq!(u16::from(frame.code), 1013);
+        assert_eq!(frame.reason, "relay is overloaded, try again later");
+    }
+
+    #[tokio::test]
+    async fn bans_and_auth_failures_are_policy_violations() {
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::Banned).await;
+        assert_eq!(u16::from(frame.code), 1008);
+        assert_eq!(frame.reason, "pubkey is banned from this relay");
+        let (server, client) = ws_pair(None).await;
+        let frame = closed_with(server, client, CloseReason::AuthFailures).await;
+        assert_eq!(u16::from(frame.code), 1008);
+        assert_eq!(frame.reason, "too many failed authentication attempts");
+    }
+
+    #[tokio::test]
+    async fn oversized_message_is_refused() {
+        let (mut server, mut client) = ws_pair(Some(1024)).await;
+        client.send(Message::text("x".repeat(4096))).await.unwrap();
+        let err = server.next().await.unwrap().unwrap_err();
+        let reason = CloseReason::for_error(&err).unwrap();
+        assert_eq!(reason, CloseReason::MessageTooBig);
+        let frame = closed_with(server, client, reason).await;
+        assert_eq!(u16::from(frame.code), 1009);
+        assert_eq!(frame.reason, "message too large");
+    }
+
+    #[test]
+    fn unanswerable_errors() {
+        assert_eq!(CloseReason::for_error(&WsError::ConnectionClosed), None);
+        assert_eq!(
+            CloseReason::for_error(&WsError::Utf8),
+            Some(CloseReason::Protocol)
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! Live events for subscriptions still sending stored events
+//!
+//! Events broadcast while a subscription's stored events are being
+//! queried are held until EOSE, then sent in the order they arrived.
+//! Events the query already returned are not sent twice.  If too many
+//! arrive, they are dropped, and the subscription is queried again
+//! after EOSE for events first seen since its query started.  A
+//! subscription may also be capped at a number of stored events, after
+//! which EOSE is sent early and the rest of its query is ignored.
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug)]
+struct Backlog<T> {
+    /// Server time when the stored events query started
+    started: u64,
+    /// Ids of events sent to the subscription
+    sent: HashSet<String>,
+    /// Live events waiting for EOSE, in arrival order
+    held: Vec<(String, T)>,
+    /// Were live events dropped because too many were held?
+    overflowed: bool,
+    /// Is the query for dropped events running?
+    catching_up: bool,
+    /// Stored events sent by the subscription's query
+    stored_count: u64,
+    /// Did the subscription reach its quota of stored events?
+    capped: bool,
+    /// Was EOSE sent early, because of the quota?
+    ended: bool,
+}
+
+/// What to do when a subscription's stored events query ends
+#[derive(Debug, PartialEq, Eq)]
+pub enum EndOfStored<T> {
+    /// Send EOSE, then these live events
+    Flush(Vec<T>),
+    /// Send EOSE, then query for events first seen since this time
+    CatchUp(u64),
+    /// The catch-up query ended; nothing is sent
+    CaughtUp,
+}
+
+/// Per-connection record of subscriptions waiting for EOSE
+#[derive(Debug)]
+pub struct StoredPhase<T> {
+    /// Most live events held for one subscription
+    capacity: usize,
+    /// Most stored events sent to one subscription before EOSE
+    quota: Option<u64>,
+    subs: HashMap<String, Backlog<T>>,
+}
+
+/// Just the id of a serialized event
+#[derive(Deserialize)]
+struct EventId {
+    id: String,
+}
+
+impl<T> StoredPhase<T> {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        StoredPhase {
+            capacity,
+            quota: None,
+            subs: HashMap::new(),
+        }
+    }
+
+    /// Send EOSE early once a subscription has sent this many stored
+    /// events.
+    #[must_use]
+    pub fn with_quota(mut self, quota: Option<u64>) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Start holding live events for a subscription whose stored
+    /// events are being queried, replacing any earlier one with the
+    /// same id.
+    pub fn start(&mut self, sub_id: &str, now: u64) {
+        self.subs.insert(
+            sub_id.to_owned(),
+            Backlog {
+                started: now,
+                sent: HashSet::new(),
+                held: vec![],
+                overflowed: false,
+                catching_up: false,
+                stored_count: 0,
+                capped: false,
+                ended: false,
+            },
+        );
+    }
+
+    /// Stop tracking a subscription, when it is closed or replaced by
+    /// one without a stored events query.
+    pub fn finish(&mut self, sub_id: &str) {
+        self.subs.remove(sub_id);
+    }
+
+    /// Should this stored event be sent?  Events sent before (as a
+    /// live event, or by an earlier query) are not.
+    pub fn stored(&mut self, sub_id: &str, event: &str) -> bool {
+        let Some(b) = self.subs.get_mut(sub_id) else {
+            return true;
+        };
+        if b.ended {
+            // the rest of a query that reached its quota
+            return false;
+        }
+        let Ok(EventId { id }) = serde_json::from_str(event) else {
+            return true;
+        };
+        // the query found an event that is also waiting
+        b.held.retain(|(held, _)| held != &id);
+        let send = b.sent.insert(id);
+        if send && !b.catching_up {
+            b.stored_count += 1;
+        }
+        send
+    }
+
+    /// Has the subscription just reached its quota of stored events?
+    /// If so, the caller should abandon its query and end it at once
+    /// with [`end_of_stored`](Self::end_of_stored).
+    pub fn reached_quota(&mut self, sub_id: &str) -> bool {
+        let (Some(quota), Some(b)) = (self.quota, self.subs.get_mut(sub_id)) else {
+            return false;
+        };
+        if b.capped || b.catching_up || b.stored_count < quota {
+            return false;
+        }
+        b.capped = true;
+        true
+    }
+
+    /// A live event matched the subscription.  It is returned if it
+    /// should be sent now.
+    pub fn live(&mut self, sub_id: &str, id: &str, item: T) -> Option<T> {
+        let Some(b) = self.subs.get_mut(sub_id) else {
+            return Some(item);
+        };
+        if b.sent.contains(id) || b.held.iter().any(|(held, _)| held == id) {
+            return None;
+        }
+        if b.ended {
+            // EOSE was sent early.  The abandoned query may never
+            // end, so live events are not remembered.
+            return Some(item);
+        }
+        if b.catching_up {
+            // EOSE was sent, but the catch-up query may return it
+            b.sent.insert(id.to_owned());
+            return Some(item);
+        }
+        if b.overflowed {
+            return None;
+        }
+        if b.held.len() >= self.capacity {
+            // the catch-up query will find these
+            b.held.clear();
+            b.overflowed = true;
+            return None;
+        }
+        b.held.push((id.to_owned(), item));
+        None
+    }
+
+    /// A query for the subscription ended.
+    pub fn end_of_stored(&mut self, sub_id: &str) -> EndOfStored<T> {
+        let Some(b) = self.subs.get_mut(sub_id) else {
+            return EndOfStored::Flush(vec![]);
+        };
+        if b.catching_up || b.ended {
+            // for a capped subscription, the abandoned query finished
+            // before it noticed
+            self.subs.remove(sub_id);
+            return EndOfStored::CaughtUp;
+        }
+        if b.capped {
+            // the rest of the query is ignored until it ends.  Live
+            // events dropped because too many were held are not
+            // queried for; the client is asked to narrow its filters.
+            b.ended = true;
+            return EndOfStored::Flush(b.held.drain(..).map(|(_, item)| item).collect());
+        }
+        if b.overflowed {
+            b.catching_up = true;
+            return EndOfStored::CatchUp(b.started);
+        }
+        let held = self.subs.remove(sub_id).map(|b| b.held).unwrap_or_default();
+        EndOfStored::Flush(held.into_iter().map(|(_, item)| item).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: &str) -> String {
+        format!(r#"{{"id":"{id}","kind":1}}"#)
+    }
+
+    #[test]
+    fn untracked_subscriptions_send_live_events() {
+        let mut phase = StoredPhase::new(10);
+        assert_eq!(phase.live("sub", "a", 1), Some(1));
+        assert!(phase.stored("sub", &event("a")));
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::Flush(vec![]));
+    }
+
+    #[test]
+    fn live_events_follow_eose_once() {
+        let mut phase = StoredPhase::new(10);
+        phase.start("sub", 100);
+        assert!(phase.stored("sub", &event("a")));
+        // already sent as a stored event
+        assert_eq!(phase.live("sub", "a", 1), None);
+        assert_eq!(phase.live("sub", "c", 3), None);
+        assert_eq!(phase.live("sub", "b", 2), None);
+        assert_eq!(phase.live("sub", "b", 2), None);
+        // found by the query after it was held
+        assert!(phase.stored("sub", &event("b")));
+        assert!(!phase.stored("sub", &event("a")));
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::Flush(vec![3]));
+        // after EOSE, live events are sent straight away
+        assert_eq!(phase.live("sub", "d", 4), Some(4));
+    }
+
+    #[test]
+    fn overflow_catches_up_after_eose() {
+        let mut phase = StoredPhase::new(2);
+        phase.start("sub", 100);
+        assert!(phase.stored("sub", &event("a")));
+        for (i, id) in ["b", "c", "d"].into_iter().enumerate() {
+            assert_eq!(phase.live("sub", id, i), None);
+        }
+        assert_eq!(phase.live("sub", "e", 9), None);
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::CatchUp(100));
+        // live events during the catch-up are sent, once
+        assert_eq!(phase.live("sub", "f", 5), Some(5));
+        assert_eq!(phase.live("sub", "f", 5), None);
+        assert!(!phase.stored("sub", &event("a")));
+        assert!(phase.stored("sub", &event("b")));
+        assert!(!phase.stored("sub", &event("f")));
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::CaughtUp);
+        assert_eq!(phase.live("sub", "g", 6), Some(6));
+    }
+
+    #[test]
+    fn quota_ends_stored_events_early() {
+        let mut phase = StoredPhase::new(10).with_quota(Some(2));
+        phase.start("sub", 100);
+        assert!(phase.stored("sub", &event("a")));
+        assert!(!phase.reached_quota("sub"));
+        assert_eq!(phase.live("sub", "x", 9), None);
+        // a duplicate does not count
+        assert!(!phase.stored("sub", &event("a")));
+        assert!(!phase.reached_quota("sub"));
+        assert!(phase.stored("sub", &event("b")));
+        assert!(phase.reached_quota("sub"));
+        assert!(!phase.reached_quota("sub"));
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::Flush(vec![9]));
+        // the rest of the query is not sent, but live events are
+        assert!(!phase.stored("sub", &event("c")));
+        assert_eq!(phase.live("sub", "y", 10), Some(10));
+        assert_eq!(phase.live("sub", "a", 11), None);
+        // nor is the query's own EOSE, if it comes
+        assert_eq!(phase.end_of_stored("sub"), EndOfStored::CaughtUp);
+        // without a quota, nothing is capped
+        let mut phase = StoredPhase::<u32>::new(10);
+        phase.start("sub", 100);
+        assert!(phase.stored("sub", &event("a")));
+        assert!(!phase.reached_quota("sub"));
+    }
+
+    /// Send a query's results slowly, ending with `EOSE`.
+    fn slow_query(results: Vec<String>, query_tx: tokio::sync::mpsc::Sender<String>) {
+        tokio::spawn(async move {
+            for id in results {
+                query_tx.send(id).await.ok();
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            query_tx.send("EOSE".to_owned()).await.ok();
+        });
+    }
+
+    /// A client that receives a slow stored events query while events
+    /// are published, handling both like the connection loop does.  A
+    /// catch-up query finds every published event.  Returns everything
+    /// sent, with `EOSE` marking the end of stored events.
+    async fn interleave(
+        capacity: usize,
+        stored: Vec<String>,
+        published: Vec<String>,
+    ) -> Vec<String> {
+        use tokio::sync::{broadcast, mpsc};
+        let (query_tx, mut query_rx) = mpsc::channel::<String>(4);
+        let (bcast_tx, mut bcast_rx) = broadcast::channel::<String>(1024);
+        slow_query(stored, query_tx.clone());
+        let to_publish = published.clone();
+        let publisher = tokio::spawn(async move {
+            for id in to_publish {
+                bcast_tx.send(id).ok();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut phase = StoredPhase::new(capacity);
+        phase.start("sub", 100);
+        let mut sent = vec![];
+        let mut publishing = true;
+        loop {
+            tokio::select! {
+                Some(id) = query_rx.recv() => {
+                    if id != "EOSE" {
+                        if phase.stored("sub", &event(&id)) {
+                            sent.push(id);
+                        }
+                        continue;
+                    }
+                    match phase.end_of_stored("sub") {
+                        EndOfStored::Flush(live) => {
+                            sent.push(id);
+                            sent.extend(live);
+                        }
+                        EndOfStored::CatchUp(since) => {
+                            assert_eq!(since, 100);
+                            sent.push(id);
+                            slow_query(published.clone(), query_tx.clone());
+                        }
+                        EndOfStored::CaughtUp => {}
+                    }
+                },
+                msg = bcast_rx.recv(), if publishing => {
+                    match msg {
+                        Ok(id) => {
+                            if let Some(id) = phase.live("sub", &id.clone(), id) {
+                                sent.push(id);
+                            }
+                        }
+                        Err(_) => publishing = false,
+                    }
+                },
+                else => break,
+            }
+            if !publishing && phase.subs.is_empty() {
+                break;
+            }
+        }
+        publisher.await.unwrap();
+        sent
+    }
+
+    fn ids(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{prefix}{i}")).collect()
+    }
+
+    /// Assert the client got every stored event, one EOSE, then every
+    /// other published event exactly once.
+    fn assert_delivered(sent: &[String], stored: &[String], published: &[String]) {
+        assert_eq!(sent.iter().filter(|id| *id == "EOSE").count(), 1);
+        let eose = sent.iter().position(|id| id == "EOSE").unwrap();
+        assert_eq!(sent[..eose], stored[..]);
+        let mut after: Vec<&String> = sent[eose + 1..].iter().collect();
+        after.sort();
+        let mut expected: Vec<&String> =
+            published.iter().filter(|id| !stored.contains(id)).collect();
+        expected.sort();
+        assert_eq!(after, expected);
+    }
+
+    #[tokio::test]
+    async fn concurrent_events_follow_stored_events() {
+        let stored = ids("h", 40);
+        // the last stored events were published during the query too
+        let mut published = stored[30..].to_vec();
+        published.extend(ids("l", 30));
+        let sent = interleave(1000, stored.clone(), published.clone()).await;
+        assert_delivered(&sent, &stored, &published);
+        // held events are sent in the order they arrived
+        let eose = sent.iter().position(|id| id == "EOSE").unwrap();
+        assert_eq!(sent[eose + 1..], ids("l", 30)[..]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_overflow_is_queried_once() {
+        let stored = ids("h", 40);
+        let mut published = stored[30..].to_vec();
+        published.extend(ids("l", 30));
+        let sent = interleave(5, stored.clone(), published.clone()).await;
+        assert_delivered(&sent, &stored, &published);
+    }
+}
@@ -1,4 +1,5 @@
 //! Error handling
+use std::fmt;
 use std::result;
 use thiserror::Error;
 use tungstenite::error::Error as WsError;
@@ -21,7 +22,7 @@ pub enum Error {
     CloseParseFailed,
     #[error("Event invalid signature")]
     EventInvalidSignature,
-    #[error("Event invalid id")]
+    #[error("id does not match")]
     EventInvalidId,
     #[error("Event malformed pubkey")]
     EventMalformedPubkey,
@@ -29,6 +30,14 @@ pub enum Error {
     EventCouldNotCanonicalize,
     #[error("Event too large")]
     EventMaxLengthError(usize),
+    #[error("too many tags (max_event_tags: {0})")]
+    EventMaxTagsError(usize),
+    #[error("tag value too large (max_tag_value_bytes: {0})")]
+    EventMaxTagValueError(usize),
+    #[error("malformed tags")]
+    EventMalformedTags,
+    #[error("malformed {0}: expected {1}")]
+    EventMalformedField(&'static str, &'static str),
     #[error("Subscription identifier max length exceeded")]
     SubIdMaxLengthError,
     #[error("Maximum concurrent subscription count reached")]
@@ -36,24 +45,34 @@ pub enum Error {
     // this should be used if the JSON is invalid
     #[error("JSON parsing failed")]
     JsonParseFailed(serde_json::Error),
+    #[error("malformed JSON at position {0} ({1})")]
+    MalformedJson(usize, String),
     #[error("WebSocket proto error")]
     WebsocketError(WsError),
     #[error("Command unknown")]
     CommandUnknownError,
+    #[error("unsupported message type")]
+    UnsupportedMessageType(String),
     #[error("SQL error")]
     SqlError(rusqlite::Error),
     #[error("Config error")]
     ConfigError(config::ConfigError),
     #[error("Data directory does not exist")]
     DatabaseDirError,
+    #[error("stopped while starting up")]
+    StoppedWhileStarting,
     #[error("Database Connection Pool Error")]
     DatabasePoolError(r2d2::Error),
     #[error("SQL error")]
     SqlxError(sqlx::Error),
     #[error("Database Connection Pool Error")]
     SqlxDatabasePoolError(sqlx::Error),
+    #[error("migration {0} failed: {}", database_detail(.1))]
+    MigrationError(i64, Box<Error>),
     #[error("Custom Error : {0}")]
     CustomError(String),
+    #[error("record not found")]
+    NotFound,
     #[error("Task join error")]
     JoinError,
     #[error("Hyper Client error")]
@@ -70,6 +89,8 @@ pub enum Error {
     TonicError(tonic::Status),
     #[error("Invalid AUTH message")]
     AuthFailure,
+    #[error("AUTH challenge expired")]
+    AuthChallengeExpired,
     #[error("I/O Error")]
     IoError(std::io::Error),
     #[error("Event builder error")]
@@ -82,10 +103,21 @@ pub enum Error {
     URLParseError(url::ParseError),
     #[error("HTTP error")]
     HTTPError(http::Error),
+    #[error("Redis error")]
+    RedisError(redis::RedisError),
     #[error("Unknown/Undocumented")]
     UnknownError,
 }
 
+/// Describe an error with the database's own message, when it has one
+fn database_detail(e: &Error) -> String {
+    match e {
+        Error::SqlxError(e) | Error::SqlxDatabasePoolError(e) => e.to_string(),
+        Error::SqlError(e) => e.to_string(),
+        e => e.to_string(),
+    }
+}
+
 //impl From<Box<dyn std::error::Error>> for Error {
 //    fn from(e: Box<dyn std::error::Error>) -> Self {
 //        Error::CustomError("error".to_owned())
@@ -137,6 +169,34 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+/// Explain why a client message is not valid JSON: the byte position
+/// (counting from 1) where parsing failed, and what was expected there.
+/// Returns `None` if the message is valid JSON, but not a valid
+/// message.
+///
+/// Syntax errors only describe the expected token, never the input,
+/// so the error is safe to send back to the client.
+#[must_use]
+pub fn malformed_json(msg: &str, e: &serde_json::Error) -> Option<Error> {
+    if !(e.is_syntax() || e.is_eof()) {
+        return None;
+    }
+    // serde_json reports a line, and a byte column within it
+    let preceding: usize = msg
+        .split('\n')
+        .take(e.line().saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum();
+    let description = e.to_string();
+    let expected = description
+        .rsplit_once(" at line ")
+        .map_or(description.as_str(), |(expected, _)| expected);
+    Some(Error::MalformedJson(
+        preceding + e.column(),
+        expected.to_owned(),
+    ))
+}
+
 impl From<WsError> for Error {
     /// Wrap Websocket error
     fn from(r: WsError) -> Self {
@@ -190,3 +250,164 @@ impl From<http::Error> for Error {
         Error::HTTPError(r)
     }
 }
+
+impl From<redis::RedisError> for Error {
+    /// Wrap Redis error
+    fn from(r: redis::RedisError) -> Self {
+        Error::RedisError(r)
+    }
+}
+
+/// Why a client's event, subscription or message was rejected.  Sent
+/// to clients in `OK`, `CLOSED` and `NOTICE` messages, prefixed with
+/// a machine-readable reason (NIP-01).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The event or message is malformed, or breaks a relay limit
+    Invalid(String),
+    /// The author or kind is not permitted
+    Blocked(String),
+    /// The client is sending too quickly
+    RateLimited(String),
+    /// The client must authenticate (NIP-42) first
+    AuthRequired(String),
+    /// The authenticated client is not permitted
+    Restricted(String),
+    /// The event was already stored (reported as accepted)
+    Duplicate(String),
+    /// A newer version of the replaceable event is stored (reported as
+    /// rejected, with the `duplicate` prefix)
+    Superseded(String),
+    /// The relay failed, through no fault of the client
+    Error(String),
+}
+
+impl RejectReason {
+    /// Machine-readable prefix for the reason
+    #[must_use]
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Invalid(_) => "invalid",
+            Self::Blocked(_) => "blocked",
+            Self::RateLimited(_) => "rate-limited",
+            Self::AuthRequired(_) => "auth-required",
+            Self::Restricted(_) => "restricted",
+            Self::Duplicate(_) | Self::Superseded(_) => "duplicate",
+            Self::Error(_) => "error",
+        }
+    }
+
+    /// Human-readable explanation, without the prefix
+    #[must_use]
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Invalid(m)
+            | Self::Blocked(m)
+            | Self::RateLimited(m)
+            | Self::AuthRequired(m)
+            | Self::Restricted(m)
+            | Self::Duplicate(m)
+            | Self::Superseded(m)
+            | Self::Error(m) => m,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    /// The reason as sent to clients, `<prefix>: <message>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.prefix(), self.message())
+    }
+}
+
+impl From<&Error> for RejectReason {
+    /// Explain an error to the client.  Problems with the client's
+    /// input are described; internal failures are not.
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::ProtoParseError
+            | Error::EventParseFailed
+            | Error::CloseParseFailed
+            | Error::EventInvalidSignature
+            | Error::EventInvalidId
+            | Error::EventMalformedPubkey
+            | Error::EventCouldNotCanonicalize
+            | Error::EventMaxLengthError(_)
+            | Error::EventMaxTagsError(_)
+            | Error::EventMaxTagValueError(_)
+            | Error::EventMalformedTags
+            | Error::EventMalformedField(..)
+            | Error::SubIdMaxLengthError
+            | Error::JsonParseFailed(_)
+            | Error::MalformedJson(..)
+            | Error::CommandUnknownError
+            | Error::UnsupportedMessageType(_)
+            | Error::DelegationParseError
+            | Error::AuthFailure
+            | Error::AuthChallengeExpired => RejectReason::Invalid(e.to_string()),
+            Error::SubMaxExceededError => RejectReason::Blocked(e.to_string()),
+            _ => RejectReason::Error("relay experienced an internal error".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_reason_is_prefixed() {
+        let r = RejectReason::RateLimited("too many events, slow down".to_owned());
+        assert_eq!(r.prefix(), "rate-limited");
+        assert_eq!(r.to_string(), "rate-limited: too many events, slow down");
+    }
+
+    #[test]
+    fn superseded_is_a_rejected_duplicate() {
+        use crate::notice::EventResultStatus;
+        let r = RejectReason::Superseded("have newer version".to_owned());
+        assert_eq!(r.to_string(), "duplicate: have newer version");
+        assert!(!EventResultStatus::from(&r).to_bool());
+        let duplicate = RejectReason::Duplicate("already have this event".to_owned());
+        assert!(EventResultStatus::from(&duplicate).to_bool());
+    }
+
+    #[test]
+    fn malformed_json_has_a_position() {
+        let explain = |msg: &str| {
+            let e = serde_json::from_str::<serde_json::Value>(msg).unwrap_err();
+            malformed_json(msg, &e).map(|e| RejectReason::from(&e).to_string())
+        };
+        assert_eq!(
+            explain(r#"["REQ", "sub" {}]"#).unwrap(),
+            "invalid: malformed JSON at position 15 (expected `,` or `]`)"
+        );
+        assert_eq!(
+            explain("[\"REQ\",\n\"sub\",").unwrap(),
+            "invalid: malformed JSON at position 14 (EOF while parsing a value)"
+        );
+        // valid JSON is not malformed, whatever it contains
+        let e = serde_json::from_str::<Vec<u64>>(r#"["REQ"]"#).unwrap_err();
+        assert!(malformed_json(r#"["REQ"]"#, &e).is_none());
+    }
+
+    #[test]
+    fn reject_reason_from_error() {
+        assert_eq!(
+            RejectReason::from(&Error::EventInvalidId).to_string(),
+            "invalid: id does not match"
+        );
+        assert_eq!(
+            RejectReason::from(&Error::EventMaxTagsError(10)).to_string(),
+            "invalid: too many tags (max_event_tags: 10)"
+        );
+        assert!(matches!(
+            RejectReason::from(&Error::SubMaxExceededError),
+            RejectReason::Blocked(_)
+        ));
+        // internal details are not sent to clients
+        let r = RejectReason::from(&Error::CustomError("db path /var/lib".to_owned()));
+        assert!(matches!(r, RejectReason::Error(_)));
+        assert!(!r.message().contains("/var/lib"));
+    }
+}
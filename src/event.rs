@@ -2,22 +2,26 @@
 use crate::delegation::validate_delegation;
 use crate::error::Error::{
     CommandUnknownError, EventCouldNotCanonicalize, EventInvalidId, EventInvalidSignature,
-    EventMalformedPubkey,
+    EventMalformedField, EventMalformedPubkey, EventMalformedTags, EventMaxTagValueError,
+    EventMaxTagsError,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::event::EventWrapper::WrappedAuth;
 use crate::event::EventWrapper::WrappedEvent;
 use crate::nip05;
-use crate::utils::unix_time;
+use crate::utils::{is_hex, is_lower_hex, unix_time};
 use bitcoin_hashes::{sha256, Hash};
 use lazy_static::lazy_static;
 use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::Value;
-use serde_json::Number;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt::Write;
+use std::ops::Deref;
 use std::str::FromStr;
+use std::time::Instant;
 use tracing::{debug, info};
 
 lazy_static! {
@@ -25,6 +29,33 @@ lazy_static! {
     pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
 }
 
+/// Event published to live subscribers, stamped with the time it
+/// entered the broadcast channel so delivery lag can be measured.
+#[derive(Debug, Clone)]
+pub struct BroadcastEvent {
+    pub event: Event,
+    pub broadcast_at: Instant,
+    /// Unix time the event was received (approximately `first_seen`)
+    pub first_seen: u64,
+}
+
+impl From<Event> for BroadcastEvent {
+    fn from(event: Event) -> Self {
+        BroadcastEvent {
+            event,
+            broadcast_at: Instant::now(),
+            first_seen: unix_time(),
+        }
+    }
+}
+
+impl Deref for BroadcastEvent {
+    type Target = Event;
+    fn deref(&self) -> &Event {
+        &self.event
+    }
+}
+
 /// Event command in network format.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct EventCmd {
@@ -37,6 +68,11 @@ impl EventCmd {
     pub fn event_id(&self) -> &str {
         &self.event.id
     }
+
+    #[must_use]
+    pub fn kind(&self) -> u64 {
+        self.event.kind
+    }
 }
 
 /// Parsed nostr event.
@@ -90,6 +126,88 @@ pub fn single_char_tagname(tagname: &str) -> Option<char> {
     }
 }
 
+/// Determine if a tag value should be added to the tag index.
+///
+/// Values longer than `max_bytes` are not indexed, with the exception
+/// of `d` tags, which are required for parameterized replaceable
+/// events.
+#[must_use]
+pub fn is_indexable_tag_value(tagname: &str, tagval: &str, max_bytes: Option<usize>) -> bool {
+    tagname == "d" || max_bytes.map_or(true, |max| tagval.len() <= max)
+}
+
+/// Tags whose values are event ids (`e`) or pubkeys (`p`)
+const HEX_VALUE_TAGS: [&str; 2] = ["e", "p"];
+
+/// Is a tag value an id or pubkey in hex, of either case?
+fn is_hex_id_value(tagname: &str, tagval: &str) -> bool {
+    HEX_VALUE_TAGS.contains(&tagname) && tagval.len() == 64 && is_hex(tagval)
+}
+
+/// A tag value as it is indexed and searched.  Ids and pubkeys in `e`
+/// and `p` tags are lowercased, so they match regardless of the case
+/// a client used.  The signed tags themselves are never changed.
+#[must_use]
+pub fn normalize_tag_value<'a>(tagname: &str, tagval: &'a str) -> Cow<'a, str> {
+    if is_hex_id_value(tagname, tagval) && !is_lower_hex(tagval) {
+        Cow::Owned(tagval.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(tagval)
+    }
+}
+
+/// Largest kind accepted from clients.
+pub const MAX_KIND: u64 = 65535;
+
+/// Hex-encoded event fields, with their exact length in characters.
+const HEX_FIELDS: [(&str, usize); 3] = [("id", 64), ("pubkey", 64), ("sig", 128)];
+
+/// Check that a hex-encoded field is exactly `len` lowercase hex
+/// characters.
+fn check_hex_field(field: &'static str, value: &str, len: usize) -> Result<()> {
+    if value.len() == len && is_lower_hex(value) {
+        Ok(())
+    } else if len == 64 {
+        Err(EventMalformedField(field, "64 lowercase hex characters"))
+    } else {
+        Err(EventMalformedField(field, "128 lowercase hex characters"))
+    }
+}
+
+fn check_kind(kind: Option<u64>) -> Result<()> {
+    match kind {
+        Some(k) if k <= MAX_KIND => Ok(()),
+        _ => Err(EventMalformedField("kind", "an integer from 0 to 65535")),
+    }
+}
+
+/// Explain why an `EVENT` or `AUTH` message could not be parsed, if
+/// the cause is a malformed event field.
+///
+/// The error names the field only; the offending value is never
+/// included, so it is safe to log and to send back to the client.
+#[must_use]
+pub fn malformed_event_field(msg: &str) -> Option<Error> {
+    let value: Value = serde_json::from_str(msg).ok()?;
+    let event = match value.as_array()?.as_slice() {
+        [cmd, event] if cmd == "EVENT" || cmd == "AUTH" => event.as_object()?,
+        _ => return None,
+    };
+    for (field, len) in HEX_FIELDS {
+        let hex = event.get(field).and_then(Value::as_str).unwrap_or_default();
+        if let Err(e) = check_hex_field(field, hex, len) {
+            return Some(e);
+        }
+    }
+    if let Err(e) = check_kind(event.get("kind").and_then(Value::as_u64)) {
+        return Some(e);
+    }
+    if event.get("created_at").and_then(Value::as_u64).is_none() {
+        return Some(EventMalformedField("created_at", "a non-negative integer"));
+    }
+    None
+}
+
 pub enum EventWrapper {
     WrappedEvent(Event),
     WrappedAuth(Event),
@@ -288,7 +406,7 @@ impl Event {
             idx.entry(tagnamechar).or_insert_with(HashSet::new);
             // get the tag vec and insert entry
             let idx_tag_vec = idx.get_mut(&tagnamechar).expect("could not get tag vector");
-            idx_tag_vec.insert(tagval.clone());
+            idx_tag_vec.insert(normalize_tag_value(tagname, tagval).into_owned());
         }
         // save the tag structure
         self.tagidx = Some(idx);
@@ -332,25 +450,84 @@ impl Event {
         true
     }
 
-    /// Check if this event has a valid signature.
+    /// Check the event tags against the configured size limits.
+    pub fn validate_tag_limits(
+        &self,
+        max_tag_value_bytes: Option<usize>,
+        max_event_tags: Option<usize>,
+    ) -> Result<()> {
+        if let Some(max_tags) = max_event_tags {
+            if self.tags.len() > max_tags {
+                return Err(EventMaxTagsError(max_tags));
+            }
+        }
+        if let Some(max_bytes) = max_tag_value_bytes {
+            // the tag name is not considered a value
+            let too_large = self
+                .tags
+                .iter()
+                .any(|t| t.iter().skip(1).any(|v| v.len() > max_bytes));
+            if too_large {
+                return Err(EventMaxTagValueError(max_bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every tag has a non-empty name, and that no tag is
+    /// repeated exactly.  Tags with no values (such as `["-"]`) are
+    /// allowed.
+    pub fn validate_tag_structure(&self) -> Result<()> {
+        let mut seen = HashSet::with_capacity(self.tags.len());
+        for tag in &self.tags {
+            let named = tag.first().map_or(false, |name| !name.is_empty());
+            if !named || !seen.insert(tag) {
+                return Err(EventMalformedTags);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that ids and pubkeys in `e` and `p` tags are not written
+    /// in uppercase hex.
+    pub fn validate_hex_tags(&self) -> Result<()> {
+        for tag in self.tags.iter().filter(|t| t.len() > 1) {
+            if is_hex_id_value(&tag[0], &tag[1]) && !is_lower_hex(&tag[1]) {
+                let field = if tag[0] == "e" { "e tag" } else { "p tag" };
+                return Err(EventMalformedField(field, "64 lowercase hex characters"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the id, pubkey and signature are lowercase hex of
+    /// the right length, and that the kind is in range.
+    pub fn validate_fields(&self) -> Result<()> {
+        check_hex_field("id", &self.id, 64)?;
+        check_hex_field("pubkey", &self.pubkey, 64)?;
+        check_hex_field("sig", &self.sig, 128)?;
+        check_kind(Some(self.kind))
+    }
+
+    /// Check if this event has a valid id and signature.
+    ///
+    /// Malformed fields are reported (by [`Event::validate_fields`])
+    /// before any hashing or signature verification is attempted.
+    ///
+    /// The id is recomputed from the canonical NIP-01 serialization of
+    /// the event fields, never from the JSON the client sent, so field
+    /// ordering or escaping choices in the original message have no
+    /// effect.
     pub fn validate(&self) -> Result<()> {
-        // TODO: return a Result with a reason for invalid events
+        self.validate_fields()?;
         // validation is performed by:
-        // * parsing JSON string into event fields
         // * create an array:
         // ** [0, pubkey-hex-string, created-at-num, kind-num, tags-array-of-arrays, content-string]
         // * serialize with no spaces/newlines
-        let c_opt = self.to_canonical();
-        if c_opt.is_none() {
-            debug!("could not canonicalize");
-            return Err(EventCouldNotCanonicalize);
-        }
-        let c = c_opt.unwrap();
         // * compute the sha256sum.
-        let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
-        let hex_digest = format!("{digest:x}");
+        let digest = self.canonical_digest().ok_or(EventCouldNotCanonicalize)?;
         // * ensure the id matches the computed sha256sum.
-        if self.id != hex_digest {
+        if self.id != format!("{digest:x}") {
             debug!("event id does not match digest");
             return Err(EventInvalidId);
         }
@@ -370,41 +547,50 @@ impl Event {
         }
     }
 
+    /// Does the event id match the hash of its canonical form?
+    #[must_use]
+    pub fn has_canonical_id(&self) -> bool {
+        self.canonical_digest()
+            .map_or(false, |digest| self.id == format!("{digest:x}"))
+    }
+
+    /// Hash of the canonical representation, which the id must match.
+    pub(crate) fn canonical_digest(&self) -> Option<sha256::Hash> {
+        let c = self.to_canonical()?;
+        Some(sha256::Hash::hash(c.as_bytes()))
+    }
+
     /// Convert event to canonical representation for signing.
+    ///
+    /// This is written out by hand, rather than through a JSON
+    /// serializer, so that the escaping rules are exactly those of
+    /// NIP-01 regardless of serializer features.
     pub fn to_canonical(&self) -> Option<String> {
-        // create a JsonValue for each event element
-        let mut c: Vec<Value> = vec![];
-        // id must be set to 0
-        let id = Number::from(0_u64);
-        c.push(serde_json::Value::Number(id));
-        // public key
-        c.push(Value::String(self.pubkey.clone()));
-        // creation time
-        let created_at = Number::from(self.created_at);
-        c.push(serde_json::Value::Number(created_at));
-        // kind
-        let kind = Number::from(self.kind);
-        c.push(serde_json::Value::Number(kind));
-        // tags
-        c.push(self.tags_to_canonical());
-        // content
-        c.push(Value::String(self.content.clone()));
-        serde_json::to_string(&Value::Array(c)).ok()
-    }
-
-    /// Convert tags to a canonical form for signing.
-    fn tags_to_canonical(&self) -> Value {
-        let mut tags = Vec::<Value>::new();
-        // iterate over self tags,
-        for t in &self.tags {
-            // each tag is a vec of strings
-            let mut a = Vec::<Value>::new();
-            for v in t.iter() {
-                a.push(serde_json::Value::String(v.clone()));
+        let mut c = String::with_capacity(self.content.len() + 160);
+        // id must be set to 0, followed by the public key
+        c.push_str("[0,");
+        push_canonical_str(&mut c, &self.pubkey);
+        // creation time and kind
+        write!(c, ",{},{},[", self.created_at, self.kind).ok()?;
+        // tags, each an array of strings
+        for (i, t) in self.tags.iter().enumerate() {
+            if i > 0 {
+                c.push(',');
             }
-            tags.push(serde_json::Value::Array(a));
+            c.push('[');
+            for (j, v) in t.iter().enumerate() {
+                if j > 0 {
+                    c.push(',');
+                }
+                push_canonical_str(&mut c, v);
+            }
+            c.push(']');
         }
-        serde_json::Value::Array(tags)
+        c.push_str("],");
+        // content
+        push_canonical_str(&mut c, &self.content);
+        c.push(']');
+        Some(c)
     }
 
     /// Determine if the given tag and value set intersect with tags in this event.
@@ -440,6 +626,33 @@ impl From<nostr::Event> for Event {
     }
 }
 
+/// Append a JSON string to `out`, escaped as NIP-01 requires.
+///
+/// Quotes, backslashes, and the control characters with short escapes
+/// (`\n`, `\"`, `\\`, `\r`, `\t`, `\b`, `\f`) use them; any other
+/// control character is written as a lowercase `\u00XX`.  Everything
+/// else, including non-ASCII and non-BMP characters, is written as-is
+/// in UTF-8.
+fn push_canonical_str(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if c < '\u{20}' => {
+                write!(out, "\\u{:04x}", c as u32).ok();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -460,6 +673,176 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn tag_value_index_limit() {
+        // without a limit, everything is indexed
+        assert!(is_indexable_tag_value("r", &"a".repeat(4096), None));
+        // values at the limit are indexed, longer ones are not
+        assert!(is_indexable_tag_value("r", "abcd", Some(4)));
+        assert!(!is_indexable_tag_value("r", "abcde", Some(4)));
+        // d tags are always indexed
+        assert!(is_indexable_tag_value("d", "abcde", Some(4)));
+    }
+
+    #[test]
+    fn tag_limits() {
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec!["r".to_owned(), "a".repeat(10)],
+            vec!["t".to_owned(), "nostr".to_owned()],
+        ];
+        // no limits configured
+        assert!(event.validate_tag_limits(None, None).is_ok());
+        // limits at the boundary are accepted
+        assert!(event.validate_tag_limits(Some(10), Some(2)).is_ok());
+        // exceeding either limit is rejected, naming the limit
+        let err = event.validate_tag_limits(Some(9), None).unwrap_err();
+        assert!(format!("{err}").contains("max_tag_value_bytes"));
+        let err = event.validate_tag_limits(None, Some(1)).unwrap_err();
+        assert!(format!("{err}").contains("max_event_tags"));
+    }
+
+    #[test]
+    fn strict_tag_structure() {
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec!["-".to_owned()],
+            vec!["t".to_owned(), "nostr".to_owned()],
+            vec!["t".to_owned(), "rust".to_owned()],
+        ];
+        assert!(event.validate_tag_structure().is_ok());
+        // an empty tag
+        event.tags.push(vec![]);
+        let err = event.validate_tag_structure().unwrap_err();
+        assert_eq!(format!("{err}"), "malformed tags");
+        // a tag with an empty name
+        event.tags.pop();
+        event.tags.push(vec!["".to_owned(), "value".to_owned()]);
+        assert!(event.validate_tag_structure().is_err());
+        // an exact duplicate
+        event.tags.pop();
+        event.tags.push(vec!["t".to_owned(), "rust".to_owned()]);
+        assert!(event.validate_tag_structure().is_err());
+        // a tag name that is not a string never parses
+        let json = r#"{"id":"a6b6c6d6e6f6","pubkey":"abcdef","created_at":1,"kind":1,"tags":[[1,"x"]],"content":"","sig":"abcdef"}"#;
+        assert!(serde_json::from_str::<Event>(json).is_err());
+    }
+
+    #[test]
+    fn mixed_case_hex_tags() {
+        let lower = "ab".repeat(32);
+        let mixed = "aB".repeat(32);
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec!["e".to_owned(), mixed.clone()],
+            vec!["p".to_owned(), lower.clone()],
+            vec!["t".to_owned(), mixed.clone()],
+        ];
+        // ids and pubkeys are indexed in lowercase, other tags as is
+        event.build_index();
+        assert!(event.generic_tag_val_intersect('e', &HashSet::from([lower.clone()])));
+        assert!(!event.generic_tag_val_intersect('e', &HashSet::from([mixed.clone()])));
+        assert!(event.generic_tag_val_intersect('t', &HashSet::from([mixed.clone()])));
+        assert_eq!(normalize_tag_value("p", &mixed), lower);
+        assert_eq!(normalize_tag_value("t", &mixed), mixed);
+        // and rejected when strict
+        let err = event.validate_hex_tags().unwrap_err();
+        assert_eq!(
+            format!("{err}"),
+            "malformed e tag: expected 64 lowercase hex characters"
+        );
+        event.tags[0][1] = lower;
+        assert!(event.validate_hex_tags().is_ok());
+    }
+
+    fn well_formed_event() -> Event {
+        let mut event = Event::simple_event();
+        event.id = "a".repeat(64);
+        event.pubkey = "b".repeat(64);
+        event.sig = "c".repeat(128);
+        event.kind = 1;
+        event
+    }
+
+    #[test]
+    fn malformed_fields_are_named() {
+        assert!(well_formed_event().validate_fields().is_ok());
+        type Corrupt = fn(&mut Event);
+        let cases: Vec<(&str, Corrupt)> = vec![
+            ("id", |e| e.id = "a".repeat(63)),
+            ("id", |e| e.id = "a".repeat(65)),
+            ("id", |e| e.id = "A".repeat(64)),
+            ("id", |e| e.id = "g".repeat(64)),
+            ("id", |e| e.id = String::new()),
+            ("pubkey", |e| e.pubkey = "b".repeat(63)),
+            ("pubkey", |e| e.pubkey = "B".repeat(64)),
+            ("pubkey", |e| e.pubkey = format!("0x{}", "b".repeat(62))),
+            ("pubkey", |e| e.pubkey = format!("{}\u{e9}", "b".repeat(62))),
+            ("sig", |e| e.sig = "c".repeat(127)),
+            ("sig", |e| e.sig = "c".repeat(64)),
+            ("sig", |e| e.sig = "C".repeat(128)),
+            ("sig", |e| e.sig = format!("{} ", "c".repeat(127))),
+            ("kind", |e| e.kind = MAX_KIND + 1),
+            ("kind", |e| e.kind = u64::MAX),
+        ];
+        for (field, corrupt) in cases {
+            let mut event = well_formed_event();
+            corrupt(&mut event);
+            let err = event.validate_fields().unwrap_err();
+            assert!(
+                matches!(err, EventMalformedField(f, _) if f == field),
+                "expected {field}, got {err}"
+            );
+            // validation stops before checking the signature
+            assert!(matches!(event.validate(), Err(EventMalformedField(..))));
+        }
+        let mut event = well_formed_event();
+        event.kind = MAX_KIND;
+        assert!(event.validate_fields().is_ok());
+    }
+
+    #[test]
+    fn unparseable_event_fields_are_named() {
+        let event = |id: &str, kind: &str, created_at: &str| {
+            format!(
+                r#"["EVENT",{{"id":"{id}","pubkey":"{}","created_at":{created_at},"kind":{kind},"tags":[],"content":"","sig":"{}"}}]"#,
+                "b".repeat(64),
+                "c".repeat(128)
+            )
+        };
+        let id = "a".repeat(64);
+        let field = |msg: String| match malformed_event_field(&msg) {
+            Some(EventMalformedField(f, _)) => Some(f),
+            _ => None,
+        };
+        assert_eq!(field(event(&id, "1", "1")), None);
+        assert_eq!(field(event(&id, "-1", "1")), Some("kind"));
+        assert_eq!(field(event(&id, "1.5", "1")), Some("kind"));
+        assert_eq!(field(event(&id, "\"1\"", "1")), Some("kind"));
+        assert_eq!(field(event(&id, "65536", "1")), Some("kind"));
+        assert_eq!(field(event(&id, "1", "-1")), Some("created_at"));
+        assert_eq!(field(event(&id, "1", "1.5")), Some("created_at"));
+        assert_eq!(field(event(&id, "1", "null")), Some("created_at"));
+        assert_eq!(field(event(&id, "1", "1e30")), Some("created_at"));
+        assert_eq!(field(event("abc", "-1", "-1")), Some("id"));
+        assert_eq!(
+            field(event(&id, "1", "1").replacen("EVENT", "AUTH", 1)),
+            None
+        );
+        // a missing field is reported the same way as a malformed one
+        let no_sig = event(&id, "1", "1").replace(&format!(r#","sig":"{}""#, "c".repeat(128)), "");
+        assert_eq!(field(no_sig), Some("sig"));
+        // not an event at all
+        assert_eq!(field(r#"["REQ","sub",{}]"#.to_owned()), None);
+        assert_eq!(field("not json".to_owned()), None);
+        // the offending value is never echoed back
+        let err = malformed_event_field(&event("<script>", "1", "1")).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "malformed id: expected 64 lowercase hex characters"
+        );
+    }
+
     #[test]
     fn empty_event_tag_match() {
         let event = Event::simple_event();
@@ -608,6 +991,104 @@ mod tests {
         assert_eq!(c, expected);
     }
 
+    /// Content and tags that are easy to escape incorrectly, with the
+    /// event id produced for each by `JSON.stringify` (node) and
+    /// Python's `json.dumps(.., ensure_ascii=False, separators=(',', ':'))`.
+    fn canonical_vectors() -> Vec<(&'static str, Vec<Vec<String>>, &'static str)> {
+        let tag = |t: &[&str]| t.iter().map(|s| (*s).to_owned()).collect::<Vec<String>>();
+        vec![
+            (
+                "line one\nline two",
+                vec![],
+                "44c84d11684b96eb315150c45de6002c690704fe4a5fb9e995fb2d58390e8d98",
+            ),
+            (
+                "back\\slash and \"quotes\"",
+                vec![tag(&["t", "a\\b"])],
+                "c3571b1960af93ea7c53d54f0cb8461aec1b161fce46abbe9650ff8a19000cb5",
+            ),
+            (
+                "non-BMP: \u{1F389} \u{1D11E}, BMP: \u{e9} \u{f1} \u{4e2d}",
+                vec![tag(&["e", &"b".repeat(64), "wss://relay.example.com/"])],
+                "a6fa248ccb7b9e477f49b1b48ae96cd6d86c81a4a5598eeff6b1bfc916d93b75",
+            ),
+            (
+                "control: \u{0}\u{1}\u{1f}\u{7f}",
+                vec![],
+                "d10ee10697c67276c3ce563ea81b00d68bd64705c192ac64acb71575d0ad09f0",
+            ),
+            (
+                "short escapes: \t\r\u{8}\u{c}, slash / and </script>",
+                vec![],
+                "032b871581bf1bd1a3f68fe66461939e6e36c7edb36f323ee3e16f0518d23ac8",
+            ),
+            (
+                "separators: \u{2028}\u{2029}",
+                vec![tag(&["p", &"c".repeat(64), "", "mention"])],
+                "2f28a3b692b3d43fb3b325e2a2557c2e37c61e8c1c8f27b5cd6aaaa72781443a",
+            ),
+        ]
+    }
+
+    #[test]
+    fn event_canonical_vectors() {
+        for (content, tags, id) in canonical_vectors() {
+            let e = Event {
+                pubkey: "a".repeat(64),
+                created_at: 1_700_000_000,
+                kind: 1,
+                tags,
+                content: content.to_owned(),
+                ..Event::simple_event()
+            };
+            let c = e.to_canonical().unwrap();
+            let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
+            assert_eq!(format!("{digest:x}"), id, "canonical form: {c}");
+        }
+        // escaping is visible in the canonical form itself
+        let e = Event {
+            content: "a\u{0}\u{1f}\"\\\n\u{1F389}".to_owned(),
+            ..Event::simple_event()
+        };
+        assert_eq!(
+            e.to_canonical().unwrap(),
+            "[0,\"0\",0,0,[],\"a\\u0000\\u001f\\\"\\\\\\n\u{1F389}\"]"
+        );
+    }
+
+    #[test]
+    fn signed_tricky_events_validate() {
+        use nostr::event::TagKind;
+        // events signed by the nostr crate must verify here
+        let keys = nostr::Keys::generate();
+        for (content, tags, _) in canonical_vectors() {
+            // generic tags, so placeholder values are not parsed as keys
+            let tags: Vec<nostr::Tag> = tags
+                .into_iter()
+                .map(|t| nostr::Tag::Generic(TagKind::Custom(t[0].clone()), t[1..].to_vec()))
+                .collect();
+            let event: Event = nostr::EventBuilder::new_text_note(content, &tags)
+                .to_event(&keys)
+                .unwrap()
+                .into();
+            assert!(event.validate().is_ok(), "content: {content:?}");
+        }
+    }
+
+    #[test]
+    fn mismatched_id_rejected() {
+        let keys = nostr::Keys::generate();
+        let mut event: Event = nostr::EventBuilder::new_text_note("hello\nworld", &[])
+            .to_event(&keys)
+            .unwrap()
+            .into();
+        // the same text with the newline escaped one level too many
+        event.content = "hello\\nworld".to_owned();
+        let err = event.validate().unwrap_err();
+        assert!(matches!(err, EventInvalidId));
+        assert_eq!(err.to_string(), "id does not match");
+    }
+
     #[test]
     fn ephemeral_event() {
         let mut event = Event::simple_event();
@@ -0,0 +1,252 @@
+//! Refusing connections by country
+//!
+//! Operators that must not serve some countries can list the countries
+//! to accept (or refuse) in the `[geoip]` section.  The client's
+//! country is looked up in a CSV database of IP ranges when its
+//! websocket connection is opened.  Filtering is only available in
+//! relays built with the `geoip` feature; otherwise, configuring it is
+//! an error.
+use crate::config::GeoIp;
+use crate::error::{Error, Result};
+use std::sync::Arc;
+
+#[cfg(feature = "geoip")]
+pub use enabled::GeoFilter;
+
+/// Country filter for relays built without the `geoip` feature, which
+/// can never be constructed.
+#[cfg(not(feature = "geoip"))]
+#[derive(Debug)]
+pub enum GeoFilter {}
+
+#[cfg(not(feature = "geoip"))]
+impl GeoFilter {
+    /// Build the filter described by the `[geoip]` settings, if any.
+    pub fn from_settings(settings: &GeoIp) -> Result<Option<Arc<GeoFilter>>> {
+        if settings.is_enabled() {
+            return Err(Error::CustomError(
+                "geoip.database is set, but the relay was built without the geoip feature".into(),
+            ));
+        }
+        Ok(None)
+    }
+
+    /// May a client at `ip` connect?
+    #[must_use]
+    pub fn allows(&self, _ip: &str) -> bool {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "geoip")]
+mod enabled {
+    use super::{Arc, Error, GeoIp, Result};
+    use crate::utils::forwarded_client_ip;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use std::net::IpAddr;
+    use tracing::{info, warn};
+
+    /// IP address ranges (IPv4 as IPv4-mapped IPv6) with their country
+    #[derive(Debug, Default)]
+    struct CountryRanges {
+        /// Sorted by start, non-overlapping: (start, end, country)
+        ranges: Vec<(u128, u128, [u8; 2])>,
+    }
+
+    /// An address, or a range bound given as an integer
+    fn parse_bound(field: &str, v4: Option<bool>) -> Option<(u128, bool)> {
+        let field = field.trim().trim_matches('"');
+        if let Ok(ip) = field.parse::<IpAddr>() {
+            return Some((to_u128(ip), ip.is_ipv4()));
+        }
+        let n = field.parse::<u128>().ok()?;
+        // integer ranges that fit in 32 bits are IPv4, unless the
+        // other end of the range is IPv6
+        if v4 != Some(false) && n <= u128::from(u32::MAX) {
+            Some((0xffff << 32 | n, true))
+        } else {
+            Some((n, false))
+        }
+    }
+
+    fn to_u128(ip: IpAddr) -> u128 {
+        match ip {
+            IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+            IpAddr::V6(v6) => u128::from(v6),
+        }
+    }
+
+    impl CountryRanges {
+        /// Read `start,end,country` lines; others (such as headers)
+        /// are skipped.
+        fn from_csv(reader: impl BufRead) -> Result<Self> {
+            let mut ranges = vec![];
+            let mut skipped = 0;
+            for line in reader.lines() {
+                let line = line?;
+                let mut fields = line.split(',');
+                let parsed = (|| {
+                    let (start, v4) = parse_bound(fields.next()?, None)?;
+                    let (end, _) = parse_bound(fields.next()?, Some(v4))?;
+                    let country = fields.next()?.trim().trim_matches('"').to_ascii_uppercase();
+                    let code: [u8; 2] = country.as_bytes().try_into().ok()?;
+                    (start <= end && code.iter().all(u8::is_ascii_alphabetic))
+                        .then_some((start, end, code))
+                })();
+                match parsed {
+                    Some(range) => ranges.push(range),
+                    None if line.trim().is_empty() => {}
+                    None => skipped += 1,
+                }
+            }
+            if ranges.is_empty() {
+                return Err(Error::CustomError("geoip database has no IP ranges".into()));
+            }
+            if skipped > 0 {
+                warn!("skipped {} unreadable lines in geoip database", skipped);
+            }
+            ranges.sort_unstable();
+            Ok(CountryRanges { ranges })
+        }
+
+        /// The country of an address, if it is in a listed range
+        fn country(&self, ip: IpAddr) -> Option<[u8; 2]> {
+            let ip = to_u128(ip);
+            let after = self.ranges.partition_point(|(start, _, _)| *start <= ip);
+            let (_, end, country) = self.ranges.get(after.checked_sub(1)?)?;
+            (ip <= *end).then_some(*country)
+        }
+    }
+
+    /// Which countries may connect
+    #[derive(Debug)]
+    enum Rule {
+        Allow(HashSet<String>),
+        Block(HashSet<String>),
+    }
+
+    /// Decides, by country, which clients may connect
+    #[derive(Debug)]
+    pub struct GeoFilter {
+        ranges: CountryRanges,
+        rule: Rule,
+        fail_closed: bool,
+    }
+
+    impl GeoFilter {
+        /// Build the filter described by the `[geoip]` settings, if
+        /// any, reading its database.
+        pub fn from_settings(settings: &GeoIp) -> Result<Option<Arc<GeoFilter>>> {
+            let Some(path) = &settings.database else {
+                return Ok(None);
+            };
+            let ranges = CountryRanges::from_csv(BufReader::new(File::open(path)?))?;
+            info!(
+                "loaded {} IP ranges from geoip database {:?}",
+                ranges.ranges.len(),
+                path
+            );
+            Ok(Some(Arc::new(Self::new(ranges, settings))))
+        }
+
+        fn new(ranges: CountryRanges, settings: &GeoIp) -> Self {
+            let codes = |list: &[String]| list.iter().map(|c| c.to_ascii_uppercase()).collect();
+            let rule = if settings.allowed_countries.is_empty() {
+                Rule::Block(codes(&settings.blocked_countries))
+            } else {
+                Rule::Allow(codes(&settings.allowed_countries))
+            };
+            GeoFilter {
+                ranges,
+                rule,
+                fail_closed: settings.fail_closed,
+            }
+        }
+
+        /// May a client at `ip` connect?
+        #[must_use]
+        pub fn allows(&self, ip: &str) -> bool {
+            let country = forwarded_client_ip(ip)
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .and_then(|ip| self.ranges.country(ip))
+                .and_then(|c| String::from_utf8(c.to_vec()).ok());
+            match (&self.rule, country) {
+                (_, None) => !self.fail_closed,
+                (Rule::Allow(allowed), Some(c)) => allowed.contains(&c),
+                (Rule::Block(blocked), Some(c)) => !blocked.contains(&c),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const DB: &str = r#"ip_start,ip_end,country
+1.0.0.0,1.0.0.255,AU
+"16777472","16778239","cn"
+8.8.8.0,8.8.8.255,US
+2001:db8::,2001:db8::ffff,DE
+"#;
+
+        fn filter(allowed: &[&str], blocked: &[&str], fail_closed: bool) -> GeoFilter {
+            let settings = GeoIp {
+                database: Some("test".into()),
+                allowed_countries: allowed.iter().map(|c| (*c).to_owned()).collect(),
+                blocked_countries: blocked.iter().map(|c| (*c).to_owned()).collect(),
+                fail_closed,
+            };
+            GeoFilter::new(CountryRanges::from_csv(DB.as_bytes()).unwrap(), &settings)
+        }
+
+        #[test]
+        fn looks_up_ipv4_and_ipv6_ranges() {
+            let ranges = CountryRanges::from_csv(DB.as_bytes()).unwrap();
+            assert_eq!(ranges.ranges.len(), 4);
+            let country = |ip: &str| ranges.country(ip.parse().unwrap());
+            assert_eq!(country("1.0.0.0"), Some(*b"AU"));
+            assert_eq!(country("1.0.0.255"), Some(*b"AU"));
+            // integer ranges, quoted, with a lowercase country
+            assert_eq!(country("1.0.1.7"), Some(*b"CN"));
+            assert_eq!(country("::ffff:8.8.8.8"), Some(*b"US"));
+            assert_eq!(country("2001:db8::1"), Some(*b"DE"));
+            assert_eq!(country("1.0.4.0"), None);
+            assert_eq!(country("0.0.0.1"), None);
+            assert_eq!(country("2001:db8::1:0"), None);
+        }
+
+        #[test]
+        fn empty_database_is_an_error() {
+            assert!(CountryRanges::from_csv("ip_start,ip_end,country\n".as_bytes()).is_err());
+        }
+
+        #[test]
+        fn allow_and_block_lists() {
+            let allow = filter(&["au", "DE"], &[], false);
+            assert!(allow.allows("1.0.0.1"));
+            assert!(allow.allows("2001:db8::2"));
+            assert!(!allow.allows("8.8.8.8"));
+            let block = filter(&[], &["US"], false);
+            assert!(block.allows("1.0.0.1"));
+            assert!(!block.allows("8.8.8.8"));
+        }
+
+        #[test]
+        fn forwarded_chains_use_the_last_hop() {
+            let block = filter(&[], &["US"], false);
+            assert!(!block.allows("1.0.0.1, 8.8.8.8"));
+            assert!(block.allows("8.8.8.8, 1.0.0.1"));
+        }
+
+        #[test]
+        fn unknown_countries_follow_fail_closed() {
+            for ip in ["9.9.9.9", "not an ip"] {
+                assert!(filter(&[], &["US"], false).allows(ip));
+                assert!(!filter(&[], &["US"], true).allows(ip));
+                assert!(filter(&["US"], &[], false).allows(ip));
+            }
+        }
+    }
+}
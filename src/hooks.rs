@@ -0,0 +1,75 @@
+//! Lifecycle hooks for applications embedding the relay
+use crate::config::Settings;
+use crate::db;
+use crate::event::BroadcastEvent;
+use crate::maintenance::ReadOnlyMode;
+use crate::membership::Membership;
+use crate::payment::{self, PaymentProcessor};
+use crate::read_policy::{ParticipantReadPolicy, ReadPolicy};
+use crate::relay_keys::RelayKeys;
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+
+/// Shared relay state made available to lifecycle hooks.
+#[derive(Clone)]
+pub struct AppState {
+    /// Relay configuration
+    pub settings: Settings,
+    /// Event repository (migrations have already been applied)
+    pub repo: Arc<dyn NostrRepo>,
+    /// Channel for broadcasting events to connected clients
+    pub broadcast: Sender<BroadcastEvent>,
+    /// Prometheus metrics
+    pub metrics: NostrMetrics,
+    /// Membership list in force, if managed by signed events
+    pub membership: Membership,
+    /// Relay keys, if configured
+    pub relay_keys: Option<RelayKeys>,
+    /// Read-only (maintenance) mode, which can be toggled at runtime
+    pub read_only: ReadOnlyMode,
+}
+
+/// Callbacks invoked at points in the relay lifecycle.
+///
+/// All methods default to doing nothing, so implementors only need to
+/// provide the hooks they care about.
+#[async_trait]
+pub trait LifecycleHooks: Send + Sync {
+    /// Storage backend for events, accounts and verification records.
+    /// By default, the database configured in `database.engine` (and
+    /// `database.shards`).  Embedders can return their own
+    /// [`NostrRepo`], already started and migrated, which should pass
+    /// the checks in `repo::conformance` (see the `test-support`
+    /// feature).
+    async fn repo(&self, settings: &Settings, metrics: NostrMetrics) -> Arc<dyn NostrRepo> {
+        db::build_repo(settings, metrics).await
+    }
+
+    /// Called after the database is ready, and before the relay
+    /// begins accepting connections.
+    async fn before_listen(&self, _state: &AppState) {}
+
+    /// Called during graceful shutdown, once the relay has stopped
+    /// accepting connections.
+    async fn on_shutdown(&self, _state: &AppState) {}
+
+    /// Policy deciding which events clients may read.  By default,
+    /// configured kinds are only readable by their participants.
+    fn read_policy(&self, settings: &Settings) -> Arc<dyn ReadPolicy> {
+        Arc::new(ParticipantReadPolicy::from_settings(settings))
+    }
+
+    /// Processor creating and checking pay-to-relay invoices.  By
+    /// default, the one configured in `pay_to_relay.processor`.
+    fn payment_processor(&self, settings: &Settings) -> Arc<dyn PaymentProcessor> {
+        payment::configured_processor(settings)
+    }
+}
+
+/// Hooks that do nothing; used by [`crate::server::start_server`].
+pub struct NoopHooks;
+
+impl LifecycleHooks for NoopHooks {}
@@ -0,0 +1,141 @@
+//! The relay icon (NIP-11 `icon`), served by the relay itself
+//!
+//! A configured image file is read once at startup, and served from
+//! [`ICON_PATH`] with caching headers, so directory sites fetching the
+//! icon do not need an external host.
+use crate::error::Result;
+use bitcoin_hashes::{sha256, Hash};
+use http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::body::Bytes;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use std::path::Path;
+
+/// Path the relay icon is served from
+pub const ICON_PATH: &str = "/icon";
+
+/// How long clients may cache the icon, in seconds (1 day)
+pub const ICON_MAX_AGE: u64 = 86400;
+
+/// Content type of an image, from its file extension
+#[must_use]
+pub fn content_type(path: &str) -> &'static str {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("avif") => "image/avif",
+        _ => "application/octet-stream",
+    }
+}
+
+/// URL of the icon served by a relay, given its websocket URL
+#[must_use]
+pub fn icon_url(relay_url: &str) -> String {
+    format!(
+        "{}{ICON_PATH}",
+        relay_url.trim_end_matches('/').replacen("ws", "http", 1)
+    )
+}
+
+/// An icon image held in memory
+#[derive(Debug, Clone)]
+pub struct RelayIcon {
+    bytes: Bytes,
+    content_type: &'static str,
+    etag: String,
+}
+
+impl RelayIcon {
+    #[must_use]
+    pub fn new(bytes: Vec<u8>, content_type: &'static str) -> Self {
+        let digest = sha256::Hash::hash(&bytes).to_string();
+        RelayIcon {
+            bytes: Bytes::from(bytes),
+            content_type,
+            etag: format!("\"{}\"", &digest[..16]),
+        }
+    }
+
+    /// Read an icon file, typed by its extension.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file could not be read.
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(RelayIcon::new(std::fs::read(path)?, content_type(path)))
+    }
+
+    /// Serve the icon, or `304 Not Modified` if the client's cached
+    /// copy is current.
+    #[must_use]
+    pub fn response(&self, request_headers: &HeaderMap) -> Response<Body> {
+        let cached = request_headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|tag| tag.trim() == self.etag || tag.trim() == "*");
+        let builder = Response::builder()
+            .header(ETAG, &self.etag)
+            .header(CACHE_CONTROL, format!("public, max-age={ICON_MAX_AGE}"));
+        if cached {
+            builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap()
+        } else {
+            builder
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, self.content_type)
+                .body(Body::from(self.bytes.clone()))
+                .unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_from_extension() {
+        assert_eq!(content_type("icon.png"), "image/png");
+        assert_eq!(content_type("/srv/relay/Logo.JPEG"), "image/jpeg");
+        assert_eq!(content_type("icon.svg"), "image/svg+xml");
+        assert_eq!(content_type("icon"), "application/octet-stream");
+    }
+
+    #[test]
+    fn icon_url_from_relay_url() {
+        assert_eq!(
+            icon_url("wss://relay.example.com/"),
+            "https://relay.example.com/icon"
+        );
+        assert_eq!(
+            icon_url("ws://localhost:8080"),
+            "http://localhost:8080/icon"
+        );
+    }
+
+    #[test]
+    fn cached_icons_are_not_resent() {
+        let icon = RelayIcon::new(vec![1, 2, 3], "image/png");
+        let response = icon.response(&HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[CACHE_CONTROL], "public, max-age=86400");
+        let etag = response.headers()[ETAG].clone();
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, etag);
+        assert_eq!(icon.response(&headers).status(), StatusCode::NOT_MODIFIED);
+        headers.insert(IF_NONE_MATCH, "\"stale\"".parse().unwrap());
+        assert_eq!(icon.response(&headers).status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,168 @@
+//! Client software identification (User-Agent, and NIP-89 `client`
+//! tags), for tracing abuse back to buggy clients
+use crate::event::Event;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Longest client name or User-Agent kept, in bytes
+const MAX_IDENTITY_BYTES: usize = 256;
+
+/// What a connection has said about the software it runs
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClientIdentity {
+    pub ip: String,
+    pub user_agent: Option<String>,
+    /// Name from the most recent `client` tag on a published event
+    pub client: Option<String>,
+}
+
+/// Identities of the open connections to this relay.  Clones share
+/// state.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIdentities {
+    open: Arc<Mutex<HashMap<u64, ClientIdentity>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ClientIdentities {
+    /// Record a new connection.  Returns a guard that keeps it listed
+    /// until dropped.
+    #[must_use]
+    pub fn register(&self, ip: &str, user_agent: Option<&str>) -> IdentityGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let identity = ClientIdentity {
+            ip: ip.to_owned(),
+            user_agent: user_agent.map(truncate),
+            client: None,
+        };
+        self.open.lock().unwrap().insert(id, identity);
+        IdentityGuard {
+            identities: self.clone(),
+            id,
+        }
+    }
+
+    /// Identities of the open connections from `ip`
+    #[must_use]
+    pub fn from_ip(&self, ip: &str) -> Vec<ClientIdentity> {
+        let mut found: Vec<ClientIdentity> = self
+            .open
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|c| c.ip == ip)
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| (&a.user_agent, &a.client).cmp(&(&b.user_agent, &b.client)));
+        found
+    }
+}
+
+/// An open connection, listed until this is dropped
+#[derive(Debug)]
+pub struct IdentityGuard {
+    identities: ClientIdentities,
+    id: u64,
+}
+
+impl IdentityGuard {
+    /// Note the `client` tag of an event published on this connection.
+    /// Returns the client name, if it differs from the last one seen.
+    pub fn observe(&self, event: &Event) -> Option<String> {
+        let name = client_tag(event)?;
+        let mut open = self.identities.open.lock().unwrap();
+        let identity = open.get_mut(&self.id)?;
+        if identity.client.as_deref() == Some(name.as_str()) {
+            return None;
+        }
+        identity.client = Some(name.clone());
+        Some(name)
+    }
+}
+
+impl Drop for IdentityGuard {
+    fn drop(&mut self) {
+        self.identities.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Client name from a NIP-89 `["client", <name>, ...]` tag
+fn client_tag(event: &Event) -> Option<String> {
+    event
+        .tag_values_by_name("client")
+        .into_iter()
+        .find(|name| !name.trim().is_empty())
+        .map(|name| truncate(name.trim()))
+}
+
+/// Bound the size of client-supplied strings kept in memory
+fn truncate(s: &str) -> String {
+    let mut end = s.len().min(MAX_IDENTITY_BYTES);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_with_tags(tags: Vec<Vec<String>>) -> Event {
+        let mut event = Event::simple_event();
+        event.tags = tags;
+        event
+    }
+
+    fn client(name: &str) -> Vec<String> {
+        vec!["client".to_owned(), name.to_owned()]
+    }
+
+    #[test]
+    fn connections_listed_until_dropped() {
+        let identities = ClientIdentities::default();
+        let first = identities.register("1.2.3.4", Some("agent/1"));
+        let second = identities.register("1.2.3.4", None);
+        let _other = identities.register("5.6.7.8", Some("agent/2"));
+        assert_eq!(identities.from_ip("1.2.3.4").len(), 2);
+        drop(first);
+        assert_eq!(
+            identities.from_ip("1.2.3.4"),
+            vec![ClientIdentity {
+                ip: "1.2.3.4".to_owned(),
+                ..ClientIdentity::default()
+            }]
+        );
+        drop(second);
+        assert!(identities.from_ip("1.2.3.4").is_empty());
+        assert_eq!(identities.from_ip("5.6.7.8").len(), 1);
+    }
+
+    #[test]
+    fn client_tags_are_recorded() {
+        let identities = ClientIdentities::default();
+        let guard = identities.register("1.2.3.4", Some("agent/1"));
+        assert_eq!(guard.observe(&event_with_tags(vec![])), None);
+        let tagged = event_with_tags(vec![client("buggy")]);
+        assert_eq!(guard.observe(&tagged).as_deref(), Some("buggy"));
+        // only changes are reported
+        assert_eq!(guard.observe(&tagged), None);
+        assert_eq!(
+            identities.from_ip("1.2.3.4")[0].client.as_deref(),
+            Some("buggy")
+        );
+        let renamed = event_with_tags(vec![client(" "), client("fixed")]);
+        assert_eq!(guard.observe(&renamed).as_deref(), Some("fixed"));
+    }
+
+    #[test]
+    fn long_identities_are_truncated() {
+        let identities = ClientIdentities::default();
+        let long = "é".repeat(MAX_IDENTITY_BYTES);
+        let _guard = identities.register("1.2.3.4", Some(&long));
+        let kept = identities.from_ip("1.2.3.4")[0].user_agent.clone().unwrap();
+        assert_eq!(kept.len(), MAX_IDENTITY_BYTES);
+        assert!(long.starts_with(&kept));
+    }
+}
@@ -0,0 +1,470 @@
+//! Importing batches of events over HTTP
+//!
+//! With `import.enabled`, a POST to `/admin/import` (with NIP-98
+//! authorization from the relay key) may carry gzip-compressed NDJSON,
+//! one event per line, such as a segment of the event archive.  The
+//! upload is decompressed as it arrives, so it is never held in
+//! memory.  Each event is checked like one published by a client, and
+//! handed to the database writer in batches of `import.batch_size`, so
+//! the same policies apply (whitelists, blocked kinds, limits and so
+//! on).  The response has the result for every line.
+//!
+//! Imported events reach the event admission server (gRPC) with the
+//! origin `import:http`, in place of a client's origin header.
+//!
+//! An upload larger than `import.max_upload_mb`, or decompressing to
+//! more than `import.max_decompressed_mb`, is cut short; events from
+//! the lines before the limit are still imported.
+use crate::config::Settings;
+use crate::db::SubmittedEvent;
+use crate::error::{RejectReason, Result};
+use crate::event::Event;
+use crate::notice::Notice;
+use flate2::read::MultiGzDecoder;
+use futures::StreamExt;
+use hyper::body::Bytes;
+use hyper::Body;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use tokio::sync::mpsc;
+
+/// Longest line accepted when websocket messages are not limited;
+/// tungstenite's default maximum message size.
+const DEFAULT_MAX_LINE_BYTES: usize = 64 << 20;
+
+/// Chunks of the upload, and lines, buffered between the network and
+/// the decompressor
+const IMPORT_BUFFER: usize = 64;
+
+/// Origin given for imported events, so they can be told apart from
+/// events published by clients
+pub const IMPORT_ORIGIN: &str = "import:http";
+
+/// Error passed to the decompressor once the upload is too large
+#[derive(Debug)]
+struct UploadTooLarge(u64);
+
+impl fmt::Display for UploadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upload is larger than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for UploadTooLarge {}
+
+/// Result for one line of an upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineResult {
+    /// Line number, from 1
+    pub line: u64,
+    /// Event id, if the line could be parsed
+    pub id: Option<String>,
+    pub ok: bool,
+    pub message: String,
+}
+
+/// Why an upload was not read to the end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportStop {
+    /// A size limit was reached
+    TooLarge(String),
+    /// The upload could not be read or decompressed
+    Unreadable(String),
+}
+
+/// What happened to the events of an upload
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Why the upload was cut short, if it was
+    pub error: Option<String>,
+    pub results: Vec<LineResult>,
+    #[serde(skip)]
+    pub stopped: Option<ImportStop>,
+}
+
+impl ImportSummary {
+    fn record(&mut self, line: u64, id: Option<String>, ok: bool, message: String) {
+        if ok {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+        self.results.push(LineResult {
+            line,
+            id,
+            ok,
+            message,
+        });
+    }
+
+    fn reject(&mut self, line: u64, id: Option<String>, reason: &RejectReason) {
+        self.record(line, id, false, reason.to_string());
+    }
+
+    fn stop(&mut self, stop: ImportStop) {
+        let (ImportStop::TooLarge(msg) | ImportStop::Unreadable(msg)) = &stop;
+        self.error = Some(msg.clone());
+        self.stopped = Some(stop);
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} accepted, {} rejected", self.accepted, self.rejected)?;
+        if let Some(error) = &self.error {
+            write!(f, " (stopped: {error})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of the decompressed upload, or why reading stopped
+#[derive(Debug, PartialEq, Eq)]
+enum Upload {
+    Line(Vec<u8>),
+    LineTooLong,
+    Stopped(ImportStop),
+}
+
+/// Reads the chunks of an upload, as they arrive, for the (blocking)
+/// decompressor
+struct ChunkReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Skip the rest of a line that is too long to keep.
+fn skip_line<R: BufRead>(input: &mut R) -> io::Result<()> {
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            input.consume(end + 1);
+            return Ok(());
+        }
+        let len = buf.len();
+        input.consume(len);
+    }
+}
+
+/// Decompress an upload, sending each line (without its newline) to
+/// `tx`.  Lines longer than `max_line` are not kept, and reading stops
+/// after `max_bytes` of decompressed data.
+fn read_lines<R: Read>(input: R, max_bytes: u64, max_line: usize, tx: &mpsc::Sender<Upload>) {
+    let mut input = BufReader::new(MultiGzDecoder::new(input)).take(max_bytes + 1);
+    loop {
+        let mut line = vec![];
+        let read = (&mut input)
+            .take(max_line as u64 + 1)
+            .read_until(b'\n', &mut line);
+        let upload = match read {
+            Ok(0) => return,
+            Ok(_) if input.limit() == 0 => Upload::Stopped(ImportStop::TooLarge(format!(
+                "decompressed upload is larger than {max_bytes} bytes"
+            ))),
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                if line.len() > max_line {
+                    match skip_line(&mut input) {
+                        Ok(()) => Upload::LineTooLong,
+                        Err(e) => Upload::Stopped(unreadable(&e)),
+                    }
+                } else {
+                    Upload::Line(line)
+                }
+            }
+            Err(e) => Upload::Stopped(unreadable(&e)),
+        };
+        let stopped = matches!(upload, Upload::Stopped(_));
+        if tx.blocking_send(upload).is_err() || stopped {
+            return;
+        }
+    }
+}
+
+fn unreadable(e: &io::Error) -> ImportStop {
+    match e.get_ref() {
+        Some(inner) if inner.is::<UploadTooLarge>() => ImportStop::TooLarge(inner.to_string()),
+        _ => ImportStop::Unreadable(format!("could not decompress upload: {e}")),
+    }
+}
+
+/// Check an imported event the way events published by clients are
+/// checked, returning it ready to be written.
+fn check_event(
+    settings: &Settings,
+    line: &[u8],
+) -> std::result::Result<Event, (Option<String>, RejectReason)> {
+    let mut event: Event = serde_json::from_slice(line).map_err(|e| {
+        (
+            None,
+            RejectReason::Invalid(format!("could not parse event: {e}")),
+        )
+    })?;
+    let reject = |reason| Err((Some(event.id.clone()), reason));
+    if let Some(max) = settings.limits.max_event_bytes_for(event.kind) {
+        if line.len() > max {
+            return reject(RejectReason::Invalid(format!(
+                "event too large ({} bytes; the limit for kind {} is {max})",
+                line.len(),
+                event.kind
+            )));
+        }
+    }
+    let checked = event
+        .validate()
+        .and_then(|()| {
+            event.validate_tag_limits(
+                settings.limits.max_tag_value_bytes,
+                settings.limits.max_event_tags,
+            )
+        })
+        .and_then(|()| {
+            if settings.limits.strict_tags {
+                event.validate_tag_structure()?;
+            }
+            if settings.limits.strict_hex {
+                event.validate_hex_tags()?;
+            }
+            Ok(())
+        });
+    if let Err(e) = checked {
+        return reject(RejectReason::from(&e));
+    }
+    if event.is_expired() {
+        return reject(RejectReason::Invalid("event has already expired".into()));
+    }
+    // restricted kinds (gift wraps) have intentionally randomized timestamps
+    if let Some(fut_sec) = settings.options.reject_future_seconds {
+        if !settings.authorization.is_restricted_read_kind(event.kind)
+            && !event.is_valid_timestamp(Some(fut_sec))
+        {
+            return reject(RejectReason::Invalid(format!(
+                "The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay."
+            )));
+        }
+    }
+    event.build_index();
+    event.update_delegation();
+    Ok(event)
+}
+
+/// Hand a batch of events to the database writer, and record the
+/// result for each.
+async fn submit_batch(
+    batch: Vec<(u64, Event)>,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    source_ip: &str,
+    summary: &mut ImportSummary,
+) {
+    // the writer sends at most two notices for an event (its result,
+    // and a newer version or a delivery note)
+    let (notice_tx, mut notice_rx) = mpsc::channel(batch.len() * 2 + 1);
+    let mut lines: HashMap<String, Vec<u64>> = HashMap::new();
+    for (line, event) in batch {
+        let id = event.id.clone();
+        let submitted = SubmittedEvent {
+            event,
+            notice_tx: notice_tx.clone(),
+            source_ip: source_ip.to_owned(),
+            origin: Some(IMPORT_ORIGIN.to_owned()),
+            user_agent: None,
+            auth_pubkey: None,
+            // imports are authorized by the relay key, not paid for
+            enforce_payment: false,
+        };
+        if event_tx.send(submitted).await.is_ok() {
+            lines.entry(id).or_default().push(line);
+        } else {
+            let reason = RejectReason::Error("the database writer is not running".into());
+            summary.reject(line, Some(id), &reason);
+        }
+    }
+    // the writer drops its copy of the sender with each event, so the
+    // channel closes once the whole batch is processed
+    drop(notice_tx);
+    while let Some(notice) = notice_rx.recv().await {
+        if let Notice::EventResult(result) = notice {
+            let line = lines.get_mut(&result.id).and_then(|l| l.pop());
+            if let Some(line) = line {
+                let ok = result.status.to_bool();
+                summary.record(line, Some(result.id), ok, result.msg);
+            }
+        }
+    }
+    // events the writer gave up on without a result
+    let reason = RejectReason::Error("the event could not be checked".into());
+    for (id, event_lines) in lines {
+        for line in event_lines {
+            summary.reject(line, Some(id.clone()), &reason);
+        }
+    }
+}
+
+/// Import the events of a gzip-compressed NDJSON upload.
+///
+/// # Errors
+///
+/// Will return `Err` if the decompressor failed.  Problems with the
+/// upload itself are reported in the summary.
+pub async fn import_events(
+    body: Body,
+    settings: &Settings,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    source_ip: &str,
+) -> Result<ImportSummary> {
+    let mb = 1024 * 1024;
+    let max_upload = settings.import.max_upload_mb * mb;
+    let max_bytes = settings.import.max_decompressed_mb * mb;
+    let max_line = settings
+        .limits
+        .max_ws_message_bytes
+        .filter(|max| *max > 0)
+        .unwrap_or(DEFAULT_MAX_LINE_BYTES);
+    let batch_size = settings.import.batch_size.max(1);
+
+    let (chunk_tx, chunk_rx) = mpsc::channel(IMPORT_BUFFER);
+    let (line_tx, mut line_rx) = mpsc::channel(IMPORT_BUFFER);
+    let reader = tokio::task::spawn_blocking(move || {
+        let input = ChunkReader {
+            rx: chunk_rx,
+            chunk: Bytes::new(),
+        };
+        read_lines(input, max_bytes, max_line, &line_tx);
+    });
+    let feeder = tokio::spawn(async move {
+        let mut body = body;
+        let mut received = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e));
+            received += chunk.as_ref().map_or(0, |c| c.len() as u64);
+            let chunk = if received > max_upload {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    UploadTooLarge(max_upload),
+                ))
+            } else {
+                chunk
+            };
+            let failed = chunk.is_err();
+            if chunk_tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    let mut summary = ImportSummary::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut line_number = 0;
+    while let Some(upload) = line_rx.recv().await {
+        line_number += 1;
+        match upload {
+            Upload::Line(line) if line.iter().all(u8::is_ascii_whitespace) => {}
+            Upload::Line(line) => match check_event(settings, &line) {
+                Ok(event) => batch.push((line_number, event)),
+                Err((id, reason)) => summary.reject(line_number, id, &reason),
+            },
+            Upload::LineTooLong => {
+                let reason = RejectReason::Invalid(format!("line is longer than {max_line} bytes"));
+                summary.reject(line_number, None, &reason);
+            }
+            Upload::Stopped(stop) => summary.stop(stop),
+        }
+        if batch.len() >= batch_size {
+            submit_batch(
+                std::mem::take(&mut batch),
+                event_tx,
+                source_ip,
+                &mut summary,
+            )
+            .await;
+        }
+    }
+    submit_batch(batch, event_tx, source_ip, &mut summary).await;
+    feeder.abort();
+    reader.await?;
+    summary.results.sort_by_key(|r| r.line);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::gzip;
+
+    fn lines(upload: &[u8], max_bytes: u64, max_line: usize) -> Vec<Upload> {
+        let (tx, mut rx) = mpsc::channel(16);
+        read_lines(upload, max_bytes, max_line, &tx);
+        drop(tx);
+        let mut lines = vec![];
+        while let Ok(line) = rx.try_recv() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn upload_is_split_into_lines() -> io::Result<()> {
+        // members written separately, as in archive segments
+        let mut upload = gzip(b"first\nmuch too long\n")?;
+        upload.extend(gzip(b"last")?);
+        assert_eq!(
+            lines(&upload, 1000, 8),
+            vec![
+                Upload::Line(b"first".to_vec()),
+                Upload::LineTooLong,
+                Upload::Line(b"last".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decompressed_size_is_limited() -> io::Result<()> {
+        let upload = gzip(&b"0123456789\n".repeat(100))?;
+        let read = lines(&upload, 25, 100);
+        assert_eq!(read.len(), 3);
+        assert!(matches!(read[2], Upload::Stopped(ImportStop::TooLarge(_))));
+        let read = lines(b"not gzip", 25, 100);
+        assert!(matches!(
+            read[0],
+            Upload::Stopped(ImportStop::Unreadable(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn imported_events_are_checked() {
+        let settings = Settings::default();
+        let (id, reason) = check_event(&settings, b"{\"id\": 1}").unwrap_err();
+        assert!(id.is_none());
+        assert!(matches!(reason, RejectReason::Invalid(_)));
+        let mut event = Event::simple_event();
+        event.id = "a".repeat(64);
+        let line = serde_json::to_vec(&event).unwrap();
+        let (id, _) = check_event(&settings, &line).unwrap_err();
+        assert_eq!(id, Some(event.id));
+    }
+}
@@ -1,7 +1,10 @@
 //! Relay metadata using NIP-11
 /// Relay Info
 use crate::config::Settings;
+use crate::icon::icon_url;
+use crate::supported::capabilities;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 pub const UNIT: &str = "msats";
@@ -16,6 +19,28 @@ pub struct Limitation {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     restricted_writes: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_event_tags: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tag_value_bytes: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_limit: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_limit: Option<u64>,
+
+    /// Not in NIP-11: filters with no `since`, `until` or `ids` only
+    /// match events created in this many seconds before the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_since_window: Option<u64>,
+
+    /// Not in NIP-11: the relay is starting up (such as migrating its
+    /// database), and refuses connections for now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,6 +57,8 @@ pub struct Fees {
 pub struct Fee {
     amount: u64,
     unit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,10 +84,57 @@ pub struct RelayInfo {
     pub version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub limitation: Option<Limitation>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        rename = "payments_url",
+        alias = "payment_url",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub payment_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fees: Option<Fees>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_tags: Option<Vec<String>>,
+    /// Relay-specific protocol extensions that are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<BTreeMap<String, bool>>,
+}
+
+impl RelayInfo {
+    /// Report writes as restricted while the relay is read-only.
+    #[must_use]
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        if read_only {
+            if let Some(limitation) = self.limitation.as_mut() {
+                limitation.restricted_writes = Some(true);
+            }
+        }
+        self
+    }
+
+    /// Add languages detected in stored content, after those set by
+    /// the operator.
+    #[must_use]
+    pub fn with_languages(mut self, detected: &[String]) -> Self {
+        let mut tags = self.language_tags.take().unwrap_or_default();
+        for language in detected {
+            if !tags.iter().any(|t| t.eq_ignore_ascii_case(language)) {
+                tags.push(language.clone());
+            }
+        }
+        self.language_tags = (!tags.is_empty()).then_some(tags);
+        self
+    }
+
+    /// Report that the relay is starting up, and not yet accepting
+    /// connections or events.
+    #[must_use]
+    pub fn with_starting(mut self) -> Self {
+        if let Some(limitation) = self.limitation.as_mut() {
+            limitation.restricted_writes = Some(true);
+            limitation.starting = Some(true);
+        }
+        self
+    }
 }
 
 /// Convert an Info configuration into public Relay Info
@@ -73,6 +147,8 @@ impl From<Settings> for RelayInfo {
             supported_nips.sort();
         }
 
+        let capabilities = capabilities(&c);
+
         let i = c.info;
         let p = c.pay_to_relay;
 
@@ -82,8 +158,19 @@ impl From<Settings> for RelayInfo {
                 p.enabled
                     || c.verified_users.is_enabled()
                     || c.authorization.pubkey_whitelist.is_some()
+                    || c.authorization.membership_admin.is_some()
+                    || c.authorization.whitelist_from_contact_list.is_some()
                     || c.grpc.restricts_write,
             ),
+            max_event_tags: c.limits.max_event_tags,
+            max_tag_value_bytes: c.limits.max_tag_value_bytes,
+            max_limit: c.limits.max_limit,
+            default_limit: c
+                .limits
+                .default_limit
+                .map(|default| c.limits.max_limit.map_or(default, |max| default.min(max))),
+            default_since_window: c.limits.default_since_window(),
+            starting: None,
         };
 
         let (payment_url, fees) = if p.enabled {
@@ -91,6 +178,7 @@ impl From<Settings> for RelayInfo {
                 Some(vec![Fee {
                     amount: p.admission_cost * 1000,
                     unit: UNIT.to_string(),
+                    description: None,
                 }])
             } else {
                 None
@@ -100,6 +188,8 @@ impl From<Settings> for RelayInfo {
                 Some(vec![Fee {
                     amount: p.cost_per_event * 1000,
                     unit: UNIT.to_string(),
+                    description: (p.free_events > 0)
+                        .then(|| format!("per event, after the first {} events", p.free_events)),
                 }])
             } else {
                 None
@@ -110,19 +200,24 @@ impl From<Settings> for RelayInfo {
                 publication: post_fee,
             };
 
-            let payment_url = if p.enabled && i.relay_url.is_some() {
-                Some(format!(
-                    "{}join",
-                    i.relay_url.clone().unwrap().replace("ws", "http")
-                ))
-            } else {
-                None
-            };
+            // prefer an explicitly configured URL, falling back to
+            // the join page served by this relay.
+            let payment_url = p.payment_url.clone().or_else(|| {
+                i.relay_url
+                    .as_ref()
+                    .map(|url| format!("{}join", url.replace("ws", "http")))
+            });
             (payment_url, Some(fees))
         } else {
             (None, None)
         };
 
+        // prefer the icon served by this relay, when it has one
+        let icon = match (&i.relay_icon_file, &i.relay_url) {
+            (Some(_), Some(url)) => Some(icon_url(url)),
+            _ => i.relay_icon,
+        };
+
         RelayInfo {
             id: i.relay_url,
             name: i.name,
@@ -135,7 +230,128 @@ impl From<Settings> for RelayInfo {
             limitation: Some(limitations),
             payment_url,
             fees,
-            icon: i.relay_icon,
+            icon,
+            language_tags: (!i.language_tags.is_empty()).then_some(i.language_tags),
+            capabilities: (!capabilities.is_empty()).then_some(capabilities),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pay_to_relay_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.info.relay_url = Some("wss://relay.example.com/".to_owned());
+        settings.pay_to_relay.enabled = true;
+        settings
+    }
+
+    #[test]
+    fn payment_url_derived_from_relay_url() {
+        let info = RelayInfo::from(pay_to_relay_settings());
+        assert_eq!(
+            info.payment_url,
+            Some("https://relay.example.com/join".to_owned())
+        );
+    }
+
+    #[test]
+    fn free_events_are_described() {
+        let mut settings = pay_to_relay_settings();
+        settings.pay_to_relay.cost_per_event = 2;
+        let json = serde_json::to_string(&RelayInfo::from(settings.clone())).unwrap();
+        assert!(json.contains(r#""publication":[{"amount":2000,"unit":"msats"}]"#));
+        settings.pay_to_relay.free_events = 10;
+        let json = serde_json::to_string(&RelayInfo::from(settings)).unwrap();
+        assert!(json.contains(r#""description":"per event, after the first 10 events""#));
+    }
+
+    #[test]
+    fn payment_url_override() {
+        let mut settings = pay_to_relay_settings();
+        settings.pay_to_relay.payment_url = Some("https://pay.example.com/signup".to_owned());
+        let info = RelayInfo::from(settings);
+        assert_eq!(
+            info.payment_url,
+            Some("https://pay.example.com/signup".to_owned())
+        );
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""payments_url":"https://pay.example.com/signup""#));
+    }
+
+    #[test]
+    fn served_icon_replaces_external_icon() {
+        let mut settings = Settings::default();
+        settings.info.relay_icon = Some("https://cdn.example.com/relay.png".to_owned());
+        let info = RelayInfo::from(settings.clone());
+        assert_eq!(
+            info.icon.as_deref(),
+            Some("https://cdn.example.com/relay.png")
+        );
+        settings.info.relay_icon_file = Some("icon.png".to_owned());
+        settings.info.relay_url = Some("wss://relay.example.com/".to_owned());
+        let info = RelayInfo::from(settings);
+        assert_eq!(info.icon.as_deref(), Some("https://relay.example.com/icon"));
+    }
+
+    #[test]
+    fn read_only_restricts_writes() {
+        let restricted = |info: RelayInfo| info.limitation.unwrap().restricted_writes;
+        let info = RelayInfo::from(Settings::default());
+        assert_eq!(restricted(info.with_read_only(false)), Some(false));
+        let info = RelayInfo::from(Settings::default());
+        assert_eq!(restricted(info.with_read_only(true)), Some(true));
+    }
+
+    #[test]
+    fn starting_is_advertised() {
+        let json = serde_json::to_string(&RelayInfo::from(Settings::default())).unwrap();
+        assert!(!json.contains("starting"));
+        let info = RelayInfo::from(Settings::default()).with_starting();
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""restricted_writes":true"#));
+        assert!(json.contains(r#""starting":true"#));
+    }
+
+    #[test]
+    fn detected_languages_follow_configured_ones() {
+        let info = RelayInfo::from(Settings::default()).with_languages(&[]);
+        assert!(info.language_tags.is_none());
+        let mut settings = Settings::default();
+        settings.info.language_tags = vec!["pt-BR".to_owned(), "en".to_owned()];
+        let detected = ["EN".to_owned(), "es".to_owned()];
+        let info = RelayInfo::from(settings).with_languages(&detected);
+        assert_eq!(
+            info.language_tags,
+            Some(vec!["pt-BR".to_owned(), "en".to_owned(), "es".to_owned()])
+        );
+    }
+
+    #[test]
+    fn query_limits_are_advertised() {
+        let mut settings = Settings::default();
+        settings.limits.default_limit = Some(1000);
+        settings.limits.max_limit = Some(500);
+        let json = serde_json::to_string(&RelayInfo::from(settings)).unwrap();
+        assert!(json.contains(r#""max_limit":500"#));
+        assert!(json.contains(r#""default_limit":500"#));
+        assert!(!json.contains("default_since_window"));
+        let mut settings = Settings::default();
+        settings.limits.default_since_window_secs = Some(86400);
+        let json = serde_json::to_string(&RelayInfo::from(settings)).unwrap();
+        assert!(json.contains(r#""default_since_window":86400"#));
+    }
+
+    #[test]
+    fn capabilities_advertised_when_enabled() {
+        let mut settings = Settings::default();
+        settings.options.req_progress_secs = None;
+        let json = serde_json::to_string(&RelayInfo::from(settings.clone())).unwrap();
+        assert!(!json.contains("close_all"));
+        settings.options.close_all = true;
+        let json = serde_json::to_string(&RelayInfo::from(settings)).unwrap();
+        assert!(json.contains(r#""capabilities":{"close_all":true,"resume_from":true}"#));
+    }
+}
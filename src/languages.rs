@@ -0,0 +1,202 @@
+//! Languages of stored content, for NIP-11 `language_tags`
+//!
+//! With `language_detection.enabled`, the newest
+//! `language_detection.sample_size` text notes are sampled every
+//! `refresh_secs`, and the language of each is detected.  Languages
+//! written in at least `min_share` of the notes are advertised in the
+//! relay information document, after any `info.language_tags` set by
+//! the operator.  Detection requires a relay built with the
+//! `language-detection` feature.
+use crate::config::LanguageDetection;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::subscription::Subscription;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tracing::{debug, info, warn};
+
+/// Kinds sampled: text notes
+const SAMPLED_KINDS: [u64; 1] = [1];
+
+/// Notes shorter than this (in characters) are skipped, since their
+/// language can not be told reliably.
+const MIN_TEXT_CHARS: usize = 20;
+
+/// Detections less confident than this are discarded.  Whatlang's own
+/// reliability flag is too strict for notes of a sentence or two.
+#[cfg(feature = "language-detection")]
+const MIN_CONFIDENCE: f64 = 0.2;
+
+/// Languages detected in recently stored notes, shared with the
+/// listeners serving the relay information document
+#[derive(Debug, Clone)]
+pub struct DetectedLanguages {
+    settings: LanguageDetection,
+    languages: Arc<RwLock<Vec<String>>>,
+}
+
+/// Language of a note, as an ISO 639-1 code, if it can be told
+#[cfg(feature = "language-detection")]
+fn detect(text: &str) -> Option<String> {
+    let info = whatlang::detect(text).filter(|info| info.confidence() >= MIN_CONFIDENCE)?;
+    // Mandarin has no ISO 639-1 code of its own; Chinese does
+    let code = match info.lang().code() {
+        "cmn" => "zho",
+        code => code,
+    };
+    let language = isolang::Language::from_639_3(code)?;
+    language.to_639_1().map(str::to_owned)
+}
+
+/// Languages written in at least `min_share` of `sampled` notes, most
+/// common first, and at most `max` of them.
+fn dominant(detected: &[String], sampled: usize, min_share: f64, max: usize) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for language in detected {
+        *counts.entry(language.as_str()).or_default() += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts
+        .into_iter()
+        .filter(|(_, count)| sampled > 0 && *count as f64 / sampled as f64 >= min_share)
+        .collect();
+    // ties are broken by code, so the order is stable
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    counts
+        .into_iter()
+        .take(max)
+        .map(|(language, _)| language.to_owned())
+        .collect()
+}
+
+impl DetectedLanguages {
+    /// Set up language detection, if enabled.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if detection is enabled, but the relay was
+    /// built without the `language-detection` feature.
+    pub fn from_settings(settings: &LanguageDetection) -> Result<Option<DetectedLanguages>> {
+        if !settings.enabled {
+            return Ok(None);
+        }
+        if cfg!(not(feature = "language-detection")) {
+            return Err(Error::CustomError(
+                "language_detection.enabled is set, but the relay was built without the language-detection feature".into(),
+            ));
+        }
+        Ok(Some(DetectedLanguages {
+            settings: settings.clone(),
+            languages: Arc::new(RwLock::new(vec![])),
+        }))
+    }
+
+    /// Languages found by the last sample
+    #[must_use]
+    pub fn get(&self) -> Vec<String> {
+        self.languages.read().map_or(vec![], |l| l.clone())
+    }
+
+    /// Sample stored notes every `refresh_secs`, until the relay shuts
+    /// down.
+    pub async fn run(self, repo: Arc<dyn NostrRepo>, mut shutdown: Receiver<()>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.settings.refresh_secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.recv() => break,
+            };
+            if let Err(e) = self.refresh(&repo).await {
+                warn!("could not sample notes for language detection: {:?}", e);
+            }
+        }
+    }
+
+    async fn refresh(&self, repo: &Arc<dyn NostrRepo>) -> Result<()> {
+        let filter = json!({"kinds": SAMPLED_KINDS, "limit": self.settings.sample_size});
+        let sub: Subscription = serde_json::from_value(json!(["REQ", "languages", filter]))?;
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(16);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "languages".to_owned(), query_tx, abandon_rx)
+            .await?;
+        let mut texts = vec![];
+        while let Some(result) = query_rx.recv().await {
+            if result.event == "EOSE" {
+                break;
+            }
+            if let Ok(event) = serde_json::from_str::<Event>(&result.event) {
+                if event.content.chars().count() >= MIN_TEXT_CHARS {
+                    texts.push(event.content);
+                }
+            }
+        }
+        let sampled = texts.len();
+        // detection is CPU-bound, so kept off the async workers
+        let detected = tokio::task::spawn_blocking(move || detect_all(&texts)).await?;
+        let languages = dominant(
+            &detected,
+            sampled,
+            self.settings.min_share,
+            self.settings.max_languages,
+        );
+        debug!(
+            "detected languages in {} sampled notes: {:?}",
+            sampled, languages
+        );
+        let Ok(mut current) = self.languages.write() else {
+            warn!("detected languages lock poisoned, not updated");
+            return Ok(());
+        };
+        if *current != languages {
+            info!("advertising detected languages: {:?}", languages);
+            *current = languages;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "language-detection")]
+fn detect_all(texts: &[String]) -> Vec<String> {
+    texts.iter().filter_map(|t| detect(t)).collect()
+}
+
+/// Never called: without the feature, detection can not be enabled
+#[cfg(not(feature = "language-detection"))]
+fn detect_all(_texts: &[String]) -> Vec<String> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|c| (*c).to_owned()).collect()
+    }
+
+    #[test]
+    fn only_common_languages_are_dominant() {
+        let detected = languages(&["en", "de", "en", "ja", "de", "en", "fr"]);
+        // 8 sampled, one of which was not detected
+        assert_eq!(dominant(&detected, 8, 0.2, 5), languages(&["en", "de"]));
+        assert_eq!(dominant(&detected, 8, 0.1, 2), languages(&["en", "de"]));
+        assert_eq!(
+            dominant(&detected, 8, 0.1, 5),
+            languages(&["en", "de", "fr", "ja"])
+        );
+        assert!(dominant(&[], 0, 0.1, 5).is_empty());
+    }
+
+    #[cfg(feature = "language-detection")]
+    #[test]
+    fn notes_are_detected() {
+        let english = "The quick brown fox jumps over the lazy dog near the river bank.";
+        let german = "Der schnelle braune Fuchs springt über den faulen Hund am Flussufer.";
+        assert_eq!(detect(english).as_deref(), Some("en"));
+        assert_eq!(detect(german).as_deref(), Some("de"));
+    }
+}
@@ -0,0 +1,150 @@
+//! Maintenance commands for the account ledger
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::payment::{InvoiceRefund, LedgerEntry, LedgerMismatch};
+use crate::repo::postgres::{self, PostgresPool};
+use crate::repo::sqlite::{self, build_pool};
+use nostr::key::{FromPkStr, Keys};
+use rusqlite::OpenFlags;
+use std::fmt;
+
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{:+}\t{}\t{}\t{}",
+            self.created_at,
+            self.delta,
+            self.balance,
+            self.reason.as_str(),
+            self.reference.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+impl fmt::Display for InvoiceRefund {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "refunded {} ({} sats): debited {} from {}",
+            self.payment_hash, self.amount, self.debited, self.pubkey
+        )
+    }
+}
+
+impl fmt::Display for LedgerMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: balance {}, ledger {}",
+            self.pubkey, self.balance, self.ledger_balance
+        )
+    }
+}
+
+/// Read the ledger for a pubkey (hex or npub) from the configured
+/// database, newest first.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey is invalid, or the database could
+/// not be read.
+pub fn run_ledger(
+    settings: &Settings,
+    pubkey: &str,
+    limit: Option<u64>,
+) -> Result<Vec<LedgerEntry>> {
+    let pubkey = Keys::from_pk_str(pubkey)?.public_key().to_string();
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "ledger",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                1,
+                1,
+                false,
+            );
+            let conn = pool.get()?;
+            sqlite::ledger_entries(&conn, &pubkey, limit)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool = connect_postgres(settings).await?;
+                postgres::ledger_entries(&pool, &pubkey, limit).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+/// Find accounts whose balance does not equal the sum of their ledger
+/// entries, without making any changes.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be read.
+pub fn run_verify_ledger(settings: &Settings) -> Result<Vec<LedgerMismatch>> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "verify-ledger",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                1,
+                1,
+                false,
+            );
+            let conn = pool.get()?;
+            sqlite::ledger_mismatches(&conn)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool = connect_postgres(settings).await?;
+                postgres::ledger_mismatches(&pool).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+/// Mark a paid invoice as refunded, and debit the payment from the
+/// account balance (recorded in the ledger).
+///
+/// # Errors
+///
+/// Will return `Err` if there is no such invoice, it is not paid, or
+/// the database could not be updated.
+pub fn run_refund_invoice(settings: &Settings, payment_hash: &str) -> Result<InvoiceRefund> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "refund-invoice",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_WRITE,
+                1,
+                1,
+                false,
+            );
+            let mut conn = pool.get()?;
+            sqlite::refund_invoice(&mut conn, payment_hash)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool = connect_postgres(settings).await?;
+                postgres::refund_invoice(&pool, payment_hash).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+async fn connect_postgres(settings: &Settings) -> Result<PostgresPool> {
+    Ok(postgres::pool_options(settings)
+        .max_connections(2)
+        .connect(&settings.database.connection)
+        .await?)
+}
@@ -1,19 +1,56 @@
+pub mod admin;
+pub mod archive;
 pub mod cli;
 pub mod close;
+pub mod coalesce;
+pub mod compact;
+pub mod compression;
 pub mod config;
 pub mod conn;
 pub mod db;
+pub mod debounce;
 pub mod delegation;
+pub mod disconnect;
+pub mod eose;
 pub mod error;
 pub mod event;
+pub mod geoip;
 pub mod hexrange;
+pub mod hooks;
+pub mod icon;
+pub mod identity;
+pub mod import;
 pub mod info;
+pub mod languages;
+pub mod ledger;
+pub mod listener;
+pub mod maintenance;
+pub mod membership;
 pub mod nauthz;
 pub mod nip05;
+pub mod nip98;
 pub mod notice;
+pub mod pending;
+pub mod progress;
+pub mod quarantine;
+pub mod ratelimit;
+pub mod read_policy;
+pub mod rejection_log;
+pub mod relay_keys;
+pub mod relay_proof;
 pub mod repo;
+pub mod source;
+pub mod startup;
+pub mod stats;
+pub mod status;
 pub mod subscription;
+pub mod supported;
+pub mod synthetic;
+pub mod undelete;
+pub mod user_data;
 pub mod utils;
+pub mod verify;
+pub mod watermark;
 // Public API for creating relays programmatically
 pub mod payment;
 pub mod server;
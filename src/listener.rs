@@ -0,0 +1,215 @@
+//! Network listeners, with optional TLS
+use crate::config::{Listener, Network};
+use crate::error::{Error, Result};
+use futures::Stream;
+use rustls_pemfile::Item;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+/// Clients must finish the TLS handshake within this time
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build a TLS acceptor from the listener's PEM certificate chain and
+/// private key, if both are configured.
+///
+/// # Errors
+///
+/// Will return `Err` if the files can not be read, or do not contain
+/// a usable certificate and key.
+pub fn tls_acceptor(listener: &Listener) -> Result<Option<TlsAcceptor>> {
+    let (Some(cert_path), Some(key_path)) = (&listener.tls_cert, &listener.tls_key) else {
+        return Ok(None);
+    };
+    let certs: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(Error::CustomError(format!(
+            "no certificates found in {cert_path}"
+        )));
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::CustomError(format!("no private key found in {key_path}")))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::CustomError(format!("invalid TLS certificate or key: {e}")))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn read_pem(path: &str) -> Result<Vec<Item>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::read_all(&mut reader)?)
+}
+
+/// Bind a listening socket with the configured backlog and
+/// `SO_REUSEADDR`.
+///
+/// # Errors
+///
+/// Will return `Err` if the address can not be bound.
+pub fn bind_tcp(addr: SocketAddr, network: &Network) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // on Windows, SO_REUSEADDR allows stealing a bound port
+    #[cfg(unix)]
+    socket.set_reuse_address(network.tcp_reuse_address)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(network.accept_backlog.try_into().unwrap_or(i32::MAX))?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Keepalive settings for accepted connections, if enabled
+#[must_use]
+pub fn tcp_keepalive(network: &Network) -> Option<TcpKeepalive> {
+    let time = Duration::from_secs(network.tcp_keepalive_secs?);
+    let keepalive = TcpKeepalive::new().with_time(time);
+    // the interval and retries are not supported everywhere, and are
+    // left to the OS where they are not.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows"
+    ))]
+    let keepalive = match network.tcp_keepalive_interval_secs {
+        Some(secs) => keepalive.with_interval(Duration::from_secs(secs)),
+        None => keepalive,
+    };
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))]
+    let keepalive = match network.tcp_keepalive_retries {
+        Some(retries) => keepalive.with_retries(retries),
+        None => keepalive,
+    };
+    Some(keepalive)
+}
+
+/// Apply `TCP_NODELAY` and keepalive settings to an accepted
+/// connection.  Failures are logged, and leave the connection as is.
+pub fn tune_stream(stream: &TcpStream, nodelay: bool, keepalive: Option<&TcpKeepalive>) {
+    if let Err(e) = stream.set_nodelay(nodelay) {
+        debug!("could not set TCP_NODELAY: {:?}", e);
+    }
+    if let Some(keepalive) = keepalive {
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(keepalive) {
+            debug!("could not set TCP keepalive: {:?}", e);
+        }
+    }
+}
+
+/// Accept TCP connections and perform TLS handshakes concurrently,
+/// yielding only connections that completed the handshake.  Failed
+/// handshakes are logged and dropped, so they can not stop the server.
+pub fn tls_incoming(
+    tcp: TcpListener,
+    acceptor: TlsAcceptor,
+    network: &Network,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel::<TlsStream<TcpStream>>(64);
+    let nodelay = network.tcp_nodelay.unwrap_or(true);
+    let keepalive = tcp_keepalive(network);
+    tokio::spawn(async move {
+        loop {
+            // stop accepting once the server has shut down
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = tcp.accept() => accepted,
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("could not accept connection: {:?}", e);
+                    continue;
+                }
+            };
+            tune_stream(&stream, nodelay, keepalive.as_ref());
+            let acceptor = acceptor.clone();
+            let conn_tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls)) => {
+                        conn_tx.send(tls).await.ok();
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed (ip: {}): {:?}", remote_addr, e),
+                    Err(_) => debug!("TLS handshake timed out (ip: {})", remote_addr),
+                }
+            });
+        }
+    });
+    futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|tls| (Ok(tls), rx))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    #[test]
+    fn tls_is_optional() {
+        let mut listener = Settings::default().network.listeners().remove(0);
+        assert!(tls_acceptor(&listener).unwrap().is_none());
+        listener.tls_cert = Some("/nonexistent/cert.pem".to_owned());
+        listener.tls_key = Some("/nonexistent/key.pem".to_owned());
+        assert!(tls_acceptor(&listener).is_err());
+    }
+
+    #[tokio::test]
+    async fn listeners_are_tuned() -> Result<()> {
+        let mut network = Settings::default().network;
+        network.accept_backlog = 16;
+        network.tcp_keepalive_secs = Some(120);
+        network.tcp_keepalive_interval_secs = Some(30);
+        let tcp = bind_tcp("127.0.0.1:0".parse().unwrap(), &network)?;
+        assert!(SockRef::from(&tcp).reuse_address()?);
+        let client = TcpStream::connect(tcp.local_addr()?).await?;
+        let (stream, _) = tcp.accept().await?;
+        tune_stream(&stream, true, tcp_keepalive(&network).as_ref());
+        assert!(stream.nodelay()?);
+        let sock = SockRef::from(&stream);
+        assert!(sock.keepalive()?);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(sock.keepalive_time()?, Duration::from_secs(120));
+            assert_eq!(sock.keepalive_interval()?, Duration::from_secs(30));
+        }
+        // keepalives are off unless configured
+        tune_stream(
+            &stream,
+            false,
+            tcp_keepalive(&Settings::default().network).as_ref(),
+        );
+        assert!(!stream.nodelay()?);
+        drop(client);
+        Ok(())
+    }
+}
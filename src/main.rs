@@ -1,9 +1,21 @@
 //! Server process
 use clap::Parser;
 use console_subscriber::ConsoleLayer;
-use nostr_rs_relay::cli::CLIArgs;
+use nostr_rs_relay::admin::{
+    run_broadcast_notice, run_connections, run_delete_events, run_import, run_quarantine_action,
+    run_quarantine_list, run_read_only, run_sources, run_whitelist, BroadcastRequest,
+};
+use nostr_rs_relay::cli::{CLIArgs, Command, QuarantineCommand, StatsCommand};
+use nostr_rs_relay::compact::{run_compact, run_reindex};
 use nostr_rs_relay::config;
+use nostr_rs_relay::ledger::{run_ledger, run_refund_invoice, run_verify_ledger};
+use nostr_rs_relay::repo::postgres_partition::run_partition_events;
 use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::stats::{parse_day, run_stats_top, today};
+use nostr_rs_relay::synthetic::{parse_kind_weights, run_gen_events, GenOutput, GenProfile};
+use nostr_rs_relay::undelete::run_undelete;
+use nostr_rs_relay::user_data::{run_user_erase, run_user_export};
+use nostr_rs_relay::verify::{run_canonical_audit, run_tag_coverage, run_verify, VerifyOptions};
 use std::fs;
 use std::path::Path;
 use std::process;
@@ -12,7 +24,7 @@ use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
 use std::thread;
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::EnvFilter;
 
@@ -94,6 +106,369 @@ fn main() {
     if let Some(db_dir) = db_dir_arg {
         settings.database.data_directory = db_dir;
     }
+    if args.partitioned {
+        settings.database.partitioned = true;
+    }
+    // check the event store, if requested
+    if let Some(Command::Verify(verify_args)) = &args.command {
+        let opts = VerifyOptions {
+            output: verify_args.output.clone(),
+            repair_tags: verify_args.repair_tags,
+            hide_invalid: verify_args.hide_invalid,
+            max_indexed_tag_value_bytes: settings.limits.max_indexed_tag_value_bytes,
+            ..Default::default()
+        };
+        match run_verify(&settings, &opts) {
+            Ok(report) => {
+                println!("{report}");
+                process::exit(i32::from(!report.is_ok()));
+            }
+            Err(e) => {
+                eprintln!("Integrity check failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::CheckTags) = &args.command {
+        match run_tag_coverage(&settings) {
+            Ok(report) => {
+                for id in &report.missing {
+                    println!("{id}");
+                }
+                println!("{report}");
+                process::exit(i32::from(!report.missing.is_empty()));
+            }
+            Err(e) => {
+                eprintln!("Tag coverage check failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::CheckCanonical) = &args.command {
+        match run_canonical_audit(&settings) {
+            Ok(report) => {
+                for id in &report.non_canonical {
+                    println!("{id}");
+                }
+                println!("{report}");
+                process::exit(i32::from(
+                    !report.non_canonical.is_empty() || report.unparseable > 0,
+                ));
+            }
+            Err(e) => {
+                eprintln!("Canonical check failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Compact(compact_args)) = &args.command {
+        match run_compact(&settings, compact_args.full) {
+            Ok(report) => {
+                println!("{report}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Compact failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Reindex(reindex_args)) = &args.command {
+        match run_reindex(&settings, &reindex_args.index, reindex_args.blocking) {
+            Ok(report) => {
+                println!("{report}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Reindex failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::GenEvents(gen_args)) = &args.command {
+        let kinds = match parse_kind_weights(&gen_args.kinds) {
+            Ok(kinds) => kinds,
+            Err(e) => {
+                eprintln!("Invalid --kinds: {e}");
+                process::exit(1);
+            }
+        };
+        let profile = GenProfile {
+            count: gen_args.count,
+            seed: gen_args.seed,
+            kinds,
+            pubkeys: gen_args.pubkeys,
+            max_tags: gen_args.max_tags,
+            max_content: gen_args.max_content,
+            until: gen_args.until,
+            spread_secs: gen_args.spread_secs,
+        };
+        let output = match &gen_args.output {
+            Some(path) => GenOutput::Jsonl(path.clone()),
+            None => GenOutput::Database,
+        };
+        match run_gen_events(&settings, &profile, &output) {
+            Ok(report) => {
+                eprintln!("{report}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Generating events failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::PartitionEvents) = &args.command {
+        match run_partition_events(&settings) {
+            Ok(moved) => {
+                println!("partitioned {moved} events");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Partitioning failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Undelete(undelete_args)) = &args.command {
+        match run_undelete(&settings, &undelete_args.event_id) {
+            Ok(true) => {
+                println!("restored event {}", undelete_args.event_id);
+                process::exit(0);
+            }
+            Ok(false) => {
+                eprintln!("No deleted event awaiting purge with that id");
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Undelete failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Ledger(ledger_args)) = &args.command {
+        match run_ledger(&settings, &ledger_args.pubkey, ledger_args.limit) {
+            Ok(entries) => {
+                for entry in &entries {
+                    println!("{entry}");
+                }
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not read ledger: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::UserExport(export_args)) = &args.command {
+        let output = export_args
+            .output
+            .clone()
+            .unwrap_or_else(|| format!("{}.zip", export_args.pubkey));
+        match run_user_export(&settings, &export_args.pubkey, &output, export_args.force) {
+            Ok(counts) => {
+                println!("exported {counts} to {output}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Export failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::UserErase(erase_args)) = &args.command {
+        match run_user_erase(&settings, &erase_args.pubkey, erase_args.force) {
+            Ok(receipt) => {
+                println!("{receipt}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Erasure failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::VerifyLedger) = &args.command {
+        match run_verify_ledger(&settings) {
+            Ok(mismatches) => {
+                for m in &mismatches {
+                    println!("{m}");
+                }
+                println!("accounts with ledger mismatches: {}", mismatches.len());
+                process::exit(i32::from(!mismatches.is_empty()));
+            }
+            Err(e) => {
+                eprintln!("Ledger check failed: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Stats(stats_args)) = &args.command {
+        let StatsCommand::Top(top_args) = &stats_args.command;
+        let day = match top_args.day.as_deref().map(parse_day) {
+            Some(Ok(day)) => day,
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+            None => today(),
+        };
+        match run_stats_top(&settings, day, top_args.kind, top_args.limit) {
+            Ok(writers) => {
+                for writer in &writers {
+                    println!("{writer}");
+                }
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not read write statistics: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::RefundInvoice(refund_args)) = &args.command {
+        match run_refund_invoice(&settings, &refund_args.payment_hash) {
+            Ok(refund) => {
+                println!("{refund}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not refund invoice: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::BroadcastNotice(notice_args)) = &args.command {
+        let notice = BroadcastRequest {
+            message: notice_args.message.clone(),
+            signed: !notice_args.unsigned,
+            target: notice_args.target.clone(),
+            pubkeys: notice_args.pubkeys.clone(),
+        };
+        match run_broadcast_notice(&settings, &notice, notice_args.url.as_deref()) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not broadcast notice: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::ReadOnly(read_only_args)) = &args.command {
+        let active = read_only_args.state == "on";
+        match run_read_only(&settings, active, read_only_args.url.as_deref()) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not change read-only mode: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Connections(connections_args)) = &args.command {
+        match run_connections(
+            &settings,
+            connections_args.limit,
+            connections_args.url.as_deref(),
+        ) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not list connections: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::DeleteEvents(delete_args)) = &args.command {
+        match run_delete_events(
+            &settings,
+            &delete_args.filter,
+            delete_args.confirm,
+            delete_args.url.as_deref(),
+        ) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not delete events: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Import(import_args)) = &args.command {
+        match run_import(&settings, &import_args.file, import_args.url.as_deref()) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not import events: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Whitelist(whitelist_args)) = &args.command {
+        match run_whitelist(&settings, whitelist_args.url.as_deref()) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not get whitelist status: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Sources(sources_args)) = &args.command {
+        match run_sources(&settings, sources_args.url.as_deref()) {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not count events by source: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(Command::Quarantine(quarantine_args)) = &args.command {
+        let url = quarantine_args.url.as_deref();
+        let response = match &quarantine_args.command {
+            QuarantineCommand::List => run_quarantine_list(&settings, url),
+            QuarantineCommand::Release(p) => {
+                run_quarantine_action(&settings, &p.pubkey, "release", url)
+            }
+            QuarantineCommand::Ban(p) => run_quarantine_action(&settings, &p.pubkey, "ban", url),
+        };
+        match response {
+            Ok(response) => {
+                println!("{response}");
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Could not update quarantine: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    if args.verify_on_start {
+        let opts = VerifyOptions {
+            max_indexed_tag_value_bytes: settings.limits.max_indexed_tag_value_bytes,
+            ..Default::default()
+        };
+        match run_verify(&settings, &opts) {
+            Ok(report) if report.is_ok() => info!("integrity check passed ({})", report),
+            Ok(report) => warn!("integrity check found problems ({})", report),
+            Err(e) => warn!("integrity check could not run: {}", e),
+        }
+    }
     // we should have a 'control plane' channel to monitor and bump
     // the server.  this will let us do stuff like clear the database,
     // shutdown, etc.; for now all this does is initiate shutdown if
@@ -101,9 +476,10 @@ fn main() {
     // stopgap to shutdown the relay when it is used as a library.
     let (_, ctrl_rx): (MpscSender<()>, MpscReceiver<()>) = syncmpsc::channel();
     // run this in a new thread
-    let handle = thread::spawn(move || {
-        let _svr = start_server(&settings, ctrl_rx);
-    });
+    let handle = thread::spawn(move || start_server(&settings, ctrl_rx));
     // block on nostr thread to finish.
-    handle.join().unwrap();
+    if let Err(e) = handle.join().unwrap() {
+        eprintln!("Relay stopped: {e}");
+        process::exit(1);
+    }
 }
@@ -0,0 +1,58 @@
+//! Read-only (maintenance) mode
+use crate::config::Options;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Whether the relay is rejecting new events.  Clones share state, so
+/// toggling it takes effect for every connection.
+#[derive(Debug, Clone)]
+pub struct ReadOnlyMode {
+    active: Arc<AtomicBool>,
+    message: Arc<String>,
+}
+
+impl ReadOnlyMode {
+    /// Start in the mode set by `options.read_only`
+    #[must_use]
+    pub fn new(options: &Options) -> Self {
+        ReadOnlyMode {
+            active: Arc::new(AtomicBool::new(options.read_only)),
+            message: Arc::new(options.read_only_message.clone()),
+        }
+    }
+
+    /// Are events currently rejected?
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Turn read-only mode on or off, returning the previous state
+    pub fn set(&self, active: bool) -> bool {
+        self.active.swap(active, Ordering::Relaxed)
+    }
+
+    /// Reason given to clients for rejecting events
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    #[test]
+    fn toggle_is_shared() {
+        let mode = ReadOnlyMode::new(&Settings::default().options);
+        let other = mode.clone();
+        assert!(!mode.is_active());
+        assert!(!other.set(true));
+        assert!(mode.is_active());
+        assert!(mode.set(false));
+        assert!(!other.is_active());
+        assert_eq!(mode.message(), "relay in maintenance mode");
+    }
+}
@@ -0,0 +1,480 @@
+//! Relay membership managed by signed lists
+//!
+//! Instead of editing `authorization.pubkey_whitelist`, an admin can
+//! publish a follow set (kind 30000) whose `d` tag names the
+//! membership list.  The `p` tags of the newest such event are the
+//! pubkeys allowed to publish.  Until one has been received, the
+//! configured whitelist applies.
+//!
+//! Alternatively, everyone followed by a community account (the `p`
+//! tags of its newest contact list, kind 3) may publish, along with
+//! the configured whitelist.  The contact list is read from storage,
+//! or fetched from a bootstrap relay, at startup and then periodically.
+use crate::config::Authorization;
+use crate::error::Result;
+use crate::event::Event;
+use crate::repo::NostrRepo;
+use crate::subscription::Subscription;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+/// Event kind for membership lists (NIP-51 follow sets)
+pub const MEMBERSHIP_KIND: u64 = 30000;
+
+/// Event kind for contact lists (NIP-02)
+pub const CONTACT_LIST_KIND: u64 = 3;
+
+/// How long to wait on the bootstrap relay for a contact list
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A signed list of pubkeys currently in force.
+#[derive(Debug)]
+pub struct MembershipList {
+    /// Id of the event the list was read from
+    pub event_id: String,
+    pub created_at: u64,
+    pub members: HashSet<String>,
+}
+
+/// Where the pubkeys allowed to publish currently come from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WhitelistStatus {
+    /// `membership_list`, `contact_list`, `config`, or `none` (anyone
+    /// may publish)
+    pub source: &'static str,
+    /// Id of the event the list was read from, for signed lists
+    pub event_id: Option<String>,
+    /// Number of pubkeys allowed to publish
+    pub count: usize,
+}
+
+/// Shared, atomically updated membership state.
+#[derive(Debug, Clone, Default)]
+pub struct Membership {
+    admin: Option<String>,
+    list_name: String,
+    active: Arc<RwLock<Option<Arc<MembershipList>>>>,
+    /// Pubkey whose follows are whitelisted
+    contact_owner: Option<String>,
+    /// Relay to fetch the contact list from
+    contact_relay: Option<String>,
+    contacts: Arc<RwLock<Option<Arc<MembershipList>>>>,
+    /// The configured whitelist
+    whitelist: Option<HashSet<String>>,
+}
+
+impl Membership {
+    #[must_use]
+    pub fn new(auth: &Authorization) -> Self {
+        Membership {
+            admin: auth.membership_admin.clone(),
+            list_name: auth.membership_list.clone(),
+            active: Arc::new(RwLock::new(None)),
+            contact_owner: auth.whitelist_from_contact_list.clone(),
+            contact_relay: auth.contact_list_relay.clone(),
+            contacts: Arc::new(RwLock::new(None)),
+            whitelist: auth
+                .pubkey_whitelist
+                .as_ref()
+                .map(|w| w.iter().cloned().collect()),
+        }
+    }
+
+    /// Is this event a membership list signed by the admin, or the
+    /// contact list whose follows are whitelisted?  Either may always
+    /// be published.
+    #[must_use]
+    pub fn is_membership_event(&self, event: &Event) -> bool {
+        self.is_admin_list(event) || self.is_contact_list(event)
+    }
+
+    /// Is this event the admin's membership list?
+    fn is_admin_list(&self, event: &Event) -> bool {
+        match &self.admin {
+            Some(admin) => {
+                event.kind == MEMBERSHIP_KIND
+                    && &event.pubkey == admin
+                    && event.distinct_param().as_deref() == Some(self.list_name.as_str())
+            }
+            None => false,
+        }
+    }
+
+    /// Is this event the contact list of the whitelisting account?
+    fn is_contact_list(&self, event: &Event) -> bool {
+        self.contact_owner.as_ref().map_or(false, |owner| {
+            event.kind == CONTACT_LIST_KIND && &event.pubkey == owner
+        })
+    }
+
+    /// Replace the active list with the `p` tags of a membership
+    /// event or contact list.  Events older than the active list are
+    /// ignored.  Returns true if the list was replaced.
+    pub fn update(&self, event: &Event) -> bool {
+        let (slot, name) = if self.is_admin_list(event) {
+            (&self.active, "membership list")
+        } else if self.is_contact_list(event) {
+            (&self.contacts, "contact list")
+        } else {
+            return false;
+        };
+        // build the new set before taking the lock, so readers see
+        // either the old list or the new one.
+        let list = Arc::new(MembershipList {
+            event_id: event.id.clone(),
+            created_at: event.created_at,
+            members: event.tag_values_by_name("p").into_iter().collect(),
+        });
+        let mut active = match slot.write() {
+            Ok(active) => active,
+            Err(_) => {
+                warn!("{} lock poisoned", name);
+                return false;
+            }
+        };
+        if let Some(current) = active.as_ref() {
+            if current.created_at > list.created_at || current.event_id == list.event_id {
+                return false;
+            }
+        }
+        info!(
+            "{} updated (event: {}, members: {})",
+            name,
+            list.event_id,
+            list.members.len()
+        );
+        *active = Some(list);
+        true
+    }
+
+    /// Is the pubkey a member?  Returns `None` if no membership list
+    /// has been received, so callers can fall back to the config.
+    #[must_use]
+    pub fn is_member(&self, pubkey: &str) -> Option<bool> {
+        self.active
+            .read()
+            .ok()?
+            .as_ref()
+            .map(|list| list.members.contains(pubkey))
+    }
+
+    /// May the pubkey publish?  A membership list replaces the
+    /// configured whitelist, while a contact list adds to it.  Returns
+    /// `None` if no list of any kind applies.
+    #[must_use]
+    pub fn whitelisted(&self, pubkey: &str) -> Option<bool> {
+        if let Some(member) = self.is_member(pubkey) {
+            return Some(member);
+        }
+        let configured = self.whitelist.as_ref().map(|w| w.contains(pubkey));
+        match self.contacts.read().ok()?.as_ref() {
+            Some(list) => Some(list.members.contains(pubkey) || configured == Some(true)),
+            None => configured,
+        }
+    }
+
+    /// Id of the membership event currently in force.
+    #[must_use]
+    pub fn active_event_id(&self) -> Option<String> {
+        self.active
+            .read()
+            .ok()?
+            .as_ref()
+            .map(|list| list.event_id.clone())
+    }
+
+    /// Where the effective whitelist comes from, and its size.
+    #[must_use]
+    pub fn status(&self) -> WhitelistStatus {
+        let signed = |slot: &RwLock<Option<Arc<MembershipList>>>| {
+            slot.read().ok().and_then(|list| list.clone())
+        };
+        if let Some(list) = signed(&self.active) {
+            return WhitelistStatus {
+                source: "membership_list",
+                event_id: Some(list.event_id.clone()),
+                count: list.members.len(),
+            };
+        }
+        if let Some(list) = signed(&self.contacts) {
+            let configured = self.whitelist.iter().flatten();
+            return WhitelistStatus {
+                source: "contact_list",
+                event_id: Some(list.event_id.clone()),
+                count: list
+                    .members
+                    .iter()
+                    .chain(configured)
+                    .collect::<HashSet<_>>()
+                    .len(),
+            };
+        }
+        match &self.whitelist {
+            Some(whitelist) => WhitelistStatus {
+                source: "config",
+                event_id: None,
+                count: whitelist.len(),
+            },
+            None => WhitelistStatus {
+                source: "none",
+                event_id: None,
+                count: 0,
+            },
+        }
+    }
+
+    /// Restore the most recent stored membership list and contact
+    /// list.
+    pub async fn load(&self, repo: &Arc<dyn NostrRepo>) -> Result<()> {
+        if let Some(admin) = &self.admin {
+            self.load_stored(
+                repo,
+                json!({"kinds": [MEMBERSHIP_KIND], "authors": [admin], "#d": [self.list_name]}),
+            )
+            .await?;
+            if self.active_event_id().is_none() {
+                info!("no membership list found, using configured whitelist");
+            }
+        }
+        self.refresh_contact_list(repo).await
+    }
+
+    /// Apply the stored events matching a filter.
+    async fn load_stored(
+        &self,
+        repo: &Arc<dyn NostrRepo>,
+        filter: serde_json::Value,
+    ) -> Result<()> {
+        let sub: Subscription = serde_json::from_value(json!(["REQ", "membership", filter]))?;
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(16);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "membership".to_owned(), query_tx, abandon_rx)
+            .await?;
+        while let Some(result) = query_rx.recv().await {
+            if result.event == "EOSE" {
+                break;
+            }
+            if let Ok(event) = serde_json::from_str::<Event>(&result.event) {
+                self.update(&event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick up the newest contact list of the whitelisting account,
+    /// from storage and the bootstrap relay (if configured).  Contact
+    /// lists fetched from the relay are stored.
+    pub async fn refresh_contact_list(&self, repo: &Arc<dyn NostrRepo>) -> Result<()> {
+        let Some(owner) = &self.contact_owner else {
+            return Ok(());
+        };
+        self.load_stored(
+            repo,
+            json!({"kinds": [CONTACT_LIST_KIND], "authors": [owner]}),
+        )
+        .await?;
+        if let Some(relay) = &self.contact_relay {
+            match tokio::time::timeout(RELAY_TIMEOUT, fetch_contact_list(relay, owner)).await {
+                Ok(Ok(Some(event))) => {
+                    if self.update(&event) {
+                        repo.write_event(&event).await?;
+                    }
+                }
+                Ok(Ok(None)) => debug!("no contact list for {} on {}", owner, relay),
+                Ok(Err(e)) => warn!("could not fetch contact list from {}: {:?}", relay, e),
+                Err(_) => warn!("timed out fetching contact list from {}", relay),
+            }
+        }
+        let found = self.contacts.read().map_or(false, |list| list.is_some());
+        if !found {
+            warn!(
+                "no contact list found for {}, using configured whitelist",
+                owner
+            );
+        }
+        Ok(())
+    }
+
+    /// Refresh the contact list at an interval, until the relay shuts
+    /// down.  The first refresh happens after one interval.
+    pub async fn run_contact_list_refresh(self, repo: Arc<dyn NostrRepo>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick is immediate, and the list was just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh_contact_list(&repo).await {
+                warn!("could not refresh contact list: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Fetch the newest contact list of a pubkey from another relay.  Only
+/// events with a valid signature are returned.
+async fn fetch_contact_list(relay: &str, owner: &str) -> Result<Option<Event>> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(relay).await?;
+    let req = json!([
+        "REQ",
+        "contact-list",
+        {"kinds": [CONTACT_LIST_KIND], "authors": [owner], "limit": 1}
+    ]);
+    ws.send(Message::Text(req.to_string())).await?;
+    let mut newest: Option<Event> = None;
+    while let Some(msg) = ws.next().await {
+        let Message::Text(reply) = msg? else {
+            continue;
+        };
+        let Ok(reply) = serde_json::from_str::<Vec<serde_json::Value>>(&reply) else {
+            continue;
+        };
+        match reply.first().and_then(serde_json::Value::as_str) {
+            Some("EVENT") => {
+                let Some(event) = reply
+                    .get(2)
+                    .and_then(|e| serde_json::from_value::<Event>(e.clone()).ok())
+                else {
+                    continue;
+                };
+                if event.kind == CONTACT_LIST_KIND
+                    && event.pubkey == owner
+                    && newest
+                        .as_ref()
+                        .map_or(true, |n| n.created_at < event.created_at)
+                    && event.validate().is_ok()
+                {
+                    newest = Some(event);
+                }
+            }
+            Some("EOSE" | "CLOSED") => break,
+            _ => {}
+        }
+    }
+    ws.send(Message::Text(json!(["CLOSE", "contact-list"]).to_string()))
+        .await
+        .ok();
+    ws.close(None).await.ok();
+    Ok(newest.map(|mut event| {
+        event.build_index();
+        event
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    fn membership() -> Membership {
+        let mut auth = crate::config::Settings::default().authorization;
+        auth.membership_admin = Some(ADMIN.to_owned());
+        Membership::new(&auth)
+    }
+
+    fn list_event(id: &str, created_at: u64, members: &[&str]) -> Event {
+        let mut event = Event::simple_event();
+        event.id = id.to_owned();
+        event.pubkey = ADMIN.to_owned();
+        event.kind = MEMBERSHIP_KIND;
+        event.created_at = created_at;
+        event.tags = vec![vec!["d".to_owned(), "relay-members".to_owned()]];
+        for m in members {
+            event.tags.push(vec!["p".to_owned(), (*m).to_owned()]);
+        }
+        event
+    }
+
+    #[test]
+    fn no_list_falls_back() {
+        assert_eq!(membership().is_member("bob"), None);
+    }
+
+    #[test]
+    fn newest_list_applies() {
+        let m = membership();
+        assert!(m.update(&list_event("1", 10, &["alice", "bob"])));
+        assert_eq!(m.is_member("bob"), Some(true));
+        // removing a member takes effect immediately
+        assert!(m.update(&list_event("2", 20, &["alice"])));
+        assert_eq!(m.is_member("bob"), Some(false));
+        assert_eq!(m.active_event_id(), Some("2".to_owned()));
+        // older lists are ignored
+        assert!(!m.update(&list_event("3", 15, &["bob"])));
+        assert_eq!(m.active_event_id(), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn ignores_other_authors_and_lists() {
+        let m = membership();
+        let mut e = list_event("1", 10, &["mallory"]);
+        e.pubkey = "bb".repeat(32);
+        assert!(!m.update(&e));
+        let mut e = list_event("2", 10, &["mallory"]);
+        e.tags[0][1] = "friends".to_owned();
+        assert!(!m.update(&e));
+        assert_eq!(m.is_member("mallory"), None);
+    }
+
+    fn contact_list(id: &str, created_at: u64, follows: &[&str]) -> Event {
+        let mut event = list_event(id, created_at, follows);
+        event.kind = CONTACT_LIST_KIND;
+        event.tags.remove(0);
+        event
+    }
+
+    fn following(whitelist: Option<&[&str]>) -> Membership {
+        let mut auth = crate::config::Settings::default().authorization;
+        auth.whitelist_from_contact_list = Some(ADMIN.to_owned());
+        auth.pubkey_whitelist = whitelist.map(|w| w.iter().map(|p| (*p).to_owned()).collect());
+        Membership::new(&auth)
+    }
+
+    #[test]
+    fn contact_list_adds_to_whitelist() {
+        let m = following(Some(&["carol"]));
+        // until the contact list is found, the configured list applies
+        assert_eq!(m.whitelisted("alice"), Some(false));
+        assert_eq!(m.whitelisted("carol"), Some(true));
+        assert_eq!(m.status().source, "config");
+        assert!(m.is_membership_event(&contact_list("1", 10, &[])));
+        assert!(m.update(&contact_list("1", 10, &["alice", "carol"])));
+        assert_eq!(m.whitelisted("alice"), Some(true));
+        assert_eq!(m.whitelisted("carol"), Some(true));
+        assert_eq!(m.whitelisted("mallory"), Some(false));
+        assert_eq!(
+            m.status(),
+            WhitelistStatus {
+                source: "contact_list",
+                event_id: Some("1".to_owned()),
+                count: 2,
+            }
+        );
+        // unfollowing takes effect on the next update
+        assert!(m.update(&contact_list("2", 20, &["bob"])));
+        assert_eq!(m.whitelisted("alice"), Some(false));
+        assert_eq!(m.status().count, 2);
+        // the same list is not applied twice
+        assert!(!m.update(&contact_list("2", 20, &["bob"])));
+    }
+
+    #[test]
+    fn contact_list_without_configured_whitelist() {
+        let m = following(None);
+        assert_eq!(m.whitelisted("alice"), None);
+        assert_eq!(m.status().source, "none");
+        // other accounts' contact lists are ignored
+        let mut e = contact_list("1", 10, &["alice"]);
+        e.pubkey = "bb".repeat(32);
+        assert!(!m.update(&e));
+        assert!(m.update(&contact_list("2", 10, &["alice"])));
+        assert_eq!(m.whitelisted("alice"), Some(true));
+        assert_eq!(m.whitelisted("bob"), Some(false));
+    }
+}
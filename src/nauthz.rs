@@ -25,6 +25,7 @@ impl AuthzDecision for EventReply {
 }
 
 // A connection to an event admission GRPC server
+#[derive(Clone)]
 pub struct EventAuthzService {
     server_addr: String,
     conn: Option<AuthorizationClient<tonic::transport::Channel>>,
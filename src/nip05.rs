@@ -6,7 +6,7 @@
 //! updated with the current NIP-05 verification status.
 use crate::config::VerifiedUsers;
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::repo::NostrRepo;
 use hyper::body::HttpBody;
 use hyper::client::connect::HttpConnector;
@@ -26,7 +26,7 @@ pub struct Verifier {
     /// Metadata events for us to inspect
     metadata_rx: tokio::sync::broadcast::Receiver<Event>,
     /// Newly validated events get written and then broadcast on this channel to subscribers
-    event_tx: tokio::sync::broadcast::Sender<Event>,
+    event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     /// Settings
     settings: crate::config::Settings,
     /// HTTP client
@@ -128,7 +128,7 @@ impl Verifier {
     pub fn new(
         repo: Arc<dyn NostrRepo>,
         metadata_rx: tokio::sync::broadcast::Receiver<Event>,
-        event_tx: tokio::sync::broadcast::Sender<Event>,
+        event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
         settings: crate::config::Settings,
     ) -> Result<Self> {
         info!("creating NIP-05 verifier");
@@ -389,10 +389,7 @@ impl Verifier {
                     }
                 }
             }
-            Err(
-                Error::SqlError(rusqlite::Error::QueryReturnedNoRows)
-                | Error::SqlxError(sqlx::Error::RowNotFound),
-            ) => {
+            Err(Error::NotFound) => {
                 // No users need verification.  Reset the interval to
                 // the next verification attempt.
                 let start = tokio::time::Instant::now() + self.wait_after_finish;
@@ -423,13 +420,13 @@ impl Verifier {
         if should_write_event {
             match self.repo.write_event(event).await {
                 Ok(updated) => {
-                    if updated != 0 {
+                    if updated.rows_added() != 0 {
                         info!(
                             "persisted event (new verified pubkey): {:?} in {:?}",
                             event.get_event_id_prefix(),
                             start.elapsed()
                         );
-                        self.event_tx.send(event.clone()).ok();
+                        self.event_tx.send(event.clone().into()).ok();
                     }
                 }
                 Err(err) => {
@@ -491,6 +488,12 @@ pub fn is_domain_allowed(
     true
 }
 
+/// Check if a domain is in an allowlist, ignoring case.
+#[must_use]
+pub fn is_domain_listed(domain: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|d| d.eq_ignore_ascii_case(domain))
+}
+
 impl VerificationRecord {
     /// Check if the record is recent enough to be considered valid,
     /// and the domain is allowed.
@@ -556,6 +559,16 @@ mod tests {
         assert_eq!(v.domain, "example.com");
     }
 
+    #[test]
+    fn domain_allowlist_ignores_case() {
+        let allowed = vec!["Example.com".to_owned(), "corp.example".to_owned()];
+        assert!(is_domain_listed("example.COM", &allowed));
+        assert!(is_domain_listed("corp.example", &allowed));
+        assert!(!is_domain_listed("sub.example.com", &allowed));
+        assert!(!is_domain_listed("example.org", &allowed));
+        assert!(!is_domain_listed("example.com", &[]));
+    }
+
     #[test]
     fn not_enough_sep() {
         let addr = "bob_example.com";
@@ -0,0 +1,147 @@
+//! HTTP authentication using NIP-98
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::utils::unix_time;
+use base64::Engine;
+use nostr::prelude::{EventBuilder, Keys, Kind, Tag};
+
+/// Event kind for HTTP auth events
+pub const HTTP_AUTH_KIND: u64 = 27235;
+
+/// Allowed clock skew (in seconds) for the auth event `created_at`
+const MAX_AUTH_AGE_SECS: u64 = 60;
+
+/// Verify an `Authorization: Nostr <base64 event>` header value for a
+/// request, returning the authenticated pubkey.
+///
+/// The event must be a valid, signed kind 27235 event created within
+/// the last minute, whose `method` tag matches the request method and
+/// whose `u` tag matches the request path (and query, if any).
+pub fn verify_auth_header(header: &str, method: &str, path_and_query: &str) -> Result<String> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or(Error::AuthFailure)?
+        .trim();
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| Error::AuthFailure)?;
+    let event: Event = serde_json::from_slice(&decoded).map_err(|_| Error::AuthFailure)?;
+    event.validate().map_err(|_| Error::AuthFailure)?;
+    if event.kind != HTTP_AUTH_KIND {
+        return Err(Error::AuthFailure);
+    }
+    let now = unix_time();
+    if event.created_at + MAX_AUTH_AGE_SECS < now || event.created_at > now + MAX_AUTH_AGE_SECS {
+        return Err(Error::AuthFailure);
+    }
+    let tag_method = event.tag_values_by_name("method");
+    if !tag_method.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+        return Err(Error::AuthFailure);
+    }
+    // the u tag holds an absolute URL; only the path and query are
+    // compared, since the relay may sit behind a proxy.
+    let url_matches = event.tag_values_by_name("u").iter().any(|u| {
+        url::Url::parse(u).map_or(false, |url| {
+            let mut p = url.path().to_owned();
+            if let Some(q) = url.query() {
+                p.push('?');
+                p.push_str(q);
+            }
+            p == path_and_query
+        })
+    });
+    if !url_matches {
+        return Err(Error::AuthFailure);
+    }
+    Ok(event.pubkey)
+}
+
+/// Build an `Authorization` header value for a request, signed with
+/// the given keys.
+///
+/// # Errors
+///
+/// Will return `Err` if the auth event could not be signed.
+pub fn sign_auth_header(keys: &Keys, url: &str, method: &str) -> Result<String> {
+    let tags = [
+        Tag::Generic("u".into(), vec![url.to_owned()]),
+        Tag::Generic("method".into(), vec![method.to_owned()]),
+    ];
+    let event = EventBuilder::new(Kind::from(HTTP_AUTH_KIND), "", &tags).to_event(keys)?;
+    let json = serde_json::to_string(&event)?;
+    Ok(format!(
+        "Nostr {}",
+        base64::engine::general_purpose::STANDARD.encode(json)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_header(keys: &Keys, kind: u64, url: &str, method: &str) -> String {
+        let tags = vec![
+            Tag::parse(vec!["u".to_owned(), url.to_owned()]).unwrap(),
+            Tag::parse(vec!["method".to_owned(), method.to_owned()]).unwrap(),
+        ];
+        let event = EventBuilder::new(Kind::from(kind), "", &tags)
+            .to_event(keys)
+            .unwrap();
+        format!(
+            "Nostr {}",
+            base64::engine::general_purpose::STANDARD.encode(event.as_json().unwrap())
+        )
+    }
+
+    #[test]
+    fn valid_auth_header() {
+        let keys = Keys::generate();
+        let header = auth_header(
+            &keys,
+            HTTP_AUTH_KIND,
+            "https://relay.example.com/join/invoice",
+            "POST",
+        );
+        let pubkey = verify_auth_header(&header, "POST", "/join/invoice").unwrap();
+        assert_eq!(pubkey, keys.public_key().to_string());
+    }
+
+    #[test]
+    fn wrong_method_or_path() {
+        let keys = Keys::generate();
+        let header = auth_header(
+            &keys,
+            HTTP_AUTH_KIND,
+            "https://relay.example.com/join/invoice",
+            "POST",
+        );
+        assert!(verify_auth_header(&header, "GET", "/join/invoice").is_err());
+        assert!(verify_auth_header(&header, "POST", "/join/info").is_err());
+    }
+
+    #[test]
+    fn wrong_kind() {
+        let keys = Keys::generate();
+        let header = auth_header(&keys, 1, "https://relay.example.com/join/info", "GET");
+        assert!(verify_auth_header(&header, "GET", "/join/info").is_err());
+    }
+
+    #[test]
+    fn signed_header_verifies() -> Result<()> {
+        let keys = Keys::generate();
+        let header = sign_auth_header(
+            &keys,
+            "http://127.0.0.1:8080/admin/broadcast-notice",
+            "POST",
+        )?;
+        let pubkey = verify_auth_header(&header, "POST", "/admin/broadcast-notice")?;
+        assert_eq!(pubkey, keys.public_key().to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_header() {
+        assert!(verify_auth_header("Bearer abc", "GET", "/join/info").is_err());
+        assert!(verify_auth_header("Nostr !!!", "GET", "/join/info").is_err());
+    }
+}
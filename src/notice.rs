@@ -1,11 +1,22 @@
+use crate::error::RejectReason;
+use crate::event::Event;
+use crate::pending::PENDING_MESSAGE;
+use crate::relay_keys::RelayNotice;
+
+/// Subscription id that newer versions of replaceable events are sent
+/// on, without the client asking for them
+pub const NEWER_VERSION_SUB_ID: &str = "relay:newer-version";
+
 pub enum EventResultStatus {
     Saved,
     Duplicate,
+    Superseded,
     Invalid,
     Blocked,
     RateLimited,
     Error,
     Restricted,
+    AuthRequired,
 }
 
 pub struct EventResult {
@@ -18,6 +29,9 @@ pub enum Notice {
     Message(String),
     EventResult(EventResult),
     AuthChallenge(String),
+    Closed(String, String),
+    Signed(Box<RelayNotice>),
+    NewerVersion(Box<Event>),
 }
 
 impl EventResultStatus {
@@ -25,20 +39,28 @@ impl EventResultStatus {
     pub fn to_bool(&self) -> bool {
         match self {
             Self::Duplicate | Self::Saved => true,
-            Self::Invalid | Self::Blocked | Self::RateLimited | Self::Error | Self::Restricted => false,
+            Self::Superseded
+            | Self::Invalid
+            | Self::Blocked
+            | Self::RateLimited
+            | Self::Error
+            | Self::Restricted
+            | Self::AuthRequired => false,
         }
     }
+}
 
-    #[must_use]
-    pub fn prefix(&self) -> &'static str {
-        match self {
-            Self::Saved => "saved",
-            Self::Duplicate => "duplicate",
-            Self::Invalid => "invalid",
-            Self::Blocked => "blocked",
-            Self::RateLimited => "rate-limited",
-            Self::Error => "error",
-            Self::Restricted => "restricted",
+impl From<&RejectReason> for EventResultStatus {
+    fn from(reason: &RejectReason) -> Self {
+        match reason {
+            RejectReason::Invalid(_) => Self::Invalid,
+            RejectReason::Blocked(_) => Self::Blocked,
+            RejectReason::RateLimited(_) => Self::RateLimited,
+            RejectReason::AuthRequired(_) => Self::AuthRequired,
+            RejectReason::Restricted(_) => Self::Restricted,
+            RejectReason::Duplicate(_) => Self::Duplicate,
+            RejectReason::Superseded(_) => Self::Superseded,
+            RejectReason::Error(_) => Self::Error,
         }
     }
 }
@@ -53,46 +75,63 @@ impl Notice {
         Notice::Message(msg)
     }
 
-    fn prefixed(id: String, msg: &str, status: EventResultStatus) -> Notice {
-        let msg = format!("{}: {}", status.prefix(), msg);
-        Notice::EventResult(EventResult { id, msg, status })
+    /// An operator notice, sent with the relay-signed event
+    #[must_use]
+    pub fn signed(notice: RelayNotice) -> Notice {
+        Notice::Signed(Box::new(notice))
     }
 
+    /// Result of an event that was not stored.  Duplicates are still
+    /// reported as accepted.
     #[must_use]
-    pub fn invalid(id: String, msg: &str) -> Notice {
-        Notice::prefixed(id, msg, EventResultStatus::Invalid)
+    pub fn rejected(id: String, reason: RejectReason) -> Notice {
+        Notice::EventResult(EventResult {
+            id,
+            msg: reason.to_string(),
+            status: EventResultStatus::from(&reason),
+        })
     }
 
+    /// Subscription closed by the relay
     #[must_use]
-    pub fn blocked(id: String, msg: &str) -> Notice {
-        Notice::prefixed(id, msg, EventResultStatus::Blocked)
+    pub fn closed(sub_id: String, reason: RejectReason) -> Notice {
+        Notice::Closed(sub_id, reason.to_string())
     }
 
+    /// Subscription closed at the client's request
     #[must_use]
-    pub fn rate_limited(id: String, msg: &str) -> Notice {
-        Notice::prefixed(id, msg, EventResultStatus::RateLimited)
+    pub fn ended(sub_id: String) -> Notice {
+        Notice::Closed(sub_id, String::new())
     }
 
+    /// A rejected message that has no event or subscription to refer to
     #[must_use]
-    pub fn duplicate(id: String) -> Notice {
-        Notice::prefixed(id, "", EventResultStatus::Duplicate)
+    pub fn rejection(reason: RejectReason) -> Notice {
+        Notice::Message(reason.to_string())
     }
 
     #[must_use]
-    pub fn error(id: String, msg: &str) -> Notice {
-        Notice::prefixed(id, msg, EventResultStatus::Error)
+    pub fn saved(id: String) -> Notice {
+        Notice::EventResult(EventResult {
+            id,
+            msg: "".into(),
+            status: EventResultStatus::Saved,
+        })
     }
 
+    /// The stored version of a replaceable event the client published
+    /// an older version of, sent on [`NEWER_VERSION_SUB_ID`]
     #[must_use]
-    pub fn restricted(id: String, msg: &str) -> Notice {
-        Notice::prefixed(id, msg, EventResultStatus::Restricted)
+    pub fn newer_version(event: Event) -> Notice {
+        Notice::NewerVersion(Box::new(event))
     }
 
+    /// Event stored, but not visible until it is admitted
     #[must_use]
-    pub fn saved(id: String) -> Notice {
+    pub fn pending(id: String) -> Notice {
         Notice::EventResult(EventResult {
             id,
-            msg: "".into(),
+            msg: PENDING_MESSAGE.into(),
             status: EventResultStatus::Saved,
         })
     }
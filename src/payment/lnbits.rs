@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use async_trait::async_trait;
+use bitcoin_hashes::{sha256, Hash};
 use rand::Rng;
 
 use std::str::FromStr;
@@ -29,6 +30,9 @@ pub struct LNBitsCreateInvoice {
     unit: String,
     internal: bool,
     expiry: u64,
+    /// Hex SHA-256 of the description, for LNURL-pay invoices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description_hash: Option<String>,
 }
 
 /// Invoice response for LN bits
@@ -84,12 +88,15 @@ impl LNBitsPaymentProcessor {
             settings: settings.clone(),
         }
     }
-}
 
-#[async_trait]
-impl PaymentProcessor for LNBitsPaymentProcessor {
-    /// Calls LNBits api to ger new invoice
-    async fn get_invoice(&self, key: &Keys, amount: u64) -> Result<InvoiceInfo, Error> {
+    /// Calls LNBits api to get a new invoice, with a description hash
+    /// if one is given.
+    async fn create_invoice(
+        &self,
+        key: &Keys,
+        amount: u64,
+        description_hash: Option<String>,
+    ) -> Result<InvoiceInfo, Error> {
         let random_number: u16 = rand::thread_rng().gen();
         let memo = format!("{}: {}", random_number, key.public_key());
 
@@ -111,7 +118,8 @@ impl PaymentProcessor for LNBitsPaymentProcessor {
             webhook: callback_url.to_string(),
             unit: "sat".to_string(),
             internal: false,
-            expiry: 3600,
+            expiry: super::INVOICE_EXPIRY_SECS,
+            description_hash,
         };
         let url = Url::parse(&self.settings.pay_to_relay.node_url)?.join(APIPATH)?;
         let uri = Uri::from_str(url.as_str().strip_suffix('/').unwrap_or(url.as_str())).unwrap();
@@ -139,6 +147,24 @@ impl PaymentProcessor for LNBitsPaymentProcessor {
             confirmed_at: None,
         })
     }
+}
+
+#[async_trait]
+impl PaymentProcessor for LNBitsPaymentProcessor {
+    /// Calls LNBits api to ger new invoice
+    async fn get_invoice(&self, key: &Keys, amount: u64) -> Result<InvoiceInfo, Error> {
+        self.create_invoice(key, amount, None).await
+    }
+
+    async fn get_described_invoice(
+        &self,
+        key: &Keys,
+        amount: u64,
+        description: &str,
+    ) -> Result<InvoiceInfo, Error> {
+        let hash = sha256::Hash::hash(description.as_bytes()).to_string();
+        self.create_invoice(key, amount, Some(hash)).await
+    }
 
     /// Calls LNBits Api to check the payment status of invoice
     async fn check_invoice(&self, payment_hash: &str) -> Result<InvoiceStatus, Error> {
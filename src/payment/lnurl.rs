@@ -0,0 +1,115 @@
+//! LNURL-pay (LUD-06) admission
+//!
+//! `GET /.well-known/lnurlp/<pubkey>` describes a payment of the
+//! admission fee, so LNURL-capable wallets (and LUD-16 lightning
+//! addresses, `<pubkey>@<relay host>`) can pay it directly.  The wallet
+//! then calls back with the amount, and gets an invoice tied to the
+//! pubkey, which admits it once paid.
+use crate::error::Result;
+use crate::utils::relay_http_url;
+use serde_json::{json, Value};
+
+/// Prefix of the pay request path, followed by the pubkey to admit
+pub const LNURLP_PATH: &str = "/.well-known/lnurlp/";
+
+/// Prefix of the callback path, followed by the pubkey to admit
+pub const CALLBACK_PATH: &str = "/lnurlp/callback/";
+
+/// The pubkey (hex or npub) at the end of a pay request or callback
+/// path.
+#[must_use]
+pub fn path_pubkey<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix)
+        .filter(|p| !p.is_empty() && !p.contains('/'))
+}
+
+/// Metadata of an admission payment.  The invoice description hash
+/// commits to it, so it must be the same for the pay request and the
+/// callback.
+#[must_use]
+pub fn metadata(relay_name: Option<&str>, pubkey: &str) -> String {
+    let relay_name = relay_name.unwrap_or("this relay");
+    let text = format!("Admission to {relay_name} for {pubkey}");
+    json!([["text/plain", text]]).to_string()
+}
+
+/// URL of the callback for a pubkey, under the relay's URL.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay URL could not be parsed.
+pub fn callback_url(relay_url: &str, pubkey: &str) -> Result<String> {
+    let base = relay_http_url(relay_url)?;
+    let path = format!("{}{pubkey}", CALLBACK_PATH.trim_start_matches('/'));
+    Ok(base.join(&path)?.to_string())
+}
+
+/// Pay request for an admission fee of `amount` sats
+#[must_use]
+pub fn pay_request(callback: &str, amount: u64, metadata: &str) -> Value {
+    json!({
+        "tag": "payRequest",
+        "callback": callback,
+        "minSendable": amount * 1000,
+        "maxSendable": amount * 1000,
+        "metadata": metadata,
+    })
+}
+
+/// Callback response with the invoice to pay
+#[must_use]
+pub fn invoice_response(bolt11: &str) -> Value {
+    json!({ "pr": bolt11, "routes": [] })
+}
+
+/// Error response, in the form wallets display
+#[must_use]
+pub fn error(reason: &str) -> Value {
+    json!({ "status": "ERROR", "reason": reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pubkey_is_read_from_path() {
+        assert_eq!(
+            path_pubkey("/.well-known/lnurlp/abc", LNURLP_PATH),
+            Some("abc")
+        );
+        assert_eq!(path_pubkey("/.well-known/lnurlp/", LNURLP_PATH), None);
+        assert_eq!(path_pubkey("/.well-known/lnurlp/a/b", LNURLP_PATH), None);
+        assert_eq!(
+            path_pubkey("/lnurlp/callback/abc", CALLBACK_PATH),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn pay_request_asks_for_the_admission_fee() -> Result<()> {
+        let callback = callback_url("wss://relay.example.com/", "abc")?;
+        assert_eq!(callback, "https://relay.example.com/lnurlp/callback/abc");
+        let metadata = metadata(Some("Example"), "abc");
+        let request = pay_request(&callback, 21, &metadata);
+        assert_eq!(request["tag"], "payRequest");
+        assert_eq!(request["minSendable"], 21000);
+        assert_eq!(request["maxSendable"], 21000);
+        let metadata: Value = serde_json::from_str(request["metadata"].as_str().unwrap())?;
+        assert_eq!(metadata[0][1], "Admission to Example for abc");
+        Ok(())
+    }
+
+    #[test]
+    fn callback_keeps_hosts_containing_ws() -> Result<()> {
+        assert_eq!(
+            callback_url("wss://news.example.com/", "abc")?,
+            "https://news.example.com/lnurlp/callback/abc"
+        );
+        assert_eq!(
+            callback_url("ws://localhost:8080/", "abc")?,
+            "http://localhost:8080/lnurlp/callback/abc"
+        );
+        Ok(())
+    }
+}
@@ -1,9 +1,14 @@
 use crate::error::{Error, Result};
-use crate::event::Event;
+use crate::event::{BroadcastEvent, Event};
 use crate::payment::lnbits::LNBitsPaymentProcessor;
 use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use crate::utils::unix_time;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Interval;
 use tracing::{info, warn};
 
 use async_trait::async_trait;
@@ -11,13 +16,27 @@ use nostr::key::{FromPkStr, FromSkStr};
 use nostr::{key::Keys, Event as NostrEvent, EventBuilder};
 
 pub mod lnbits;
+pub mod lnurl;
+
+/// Lifetime of admission invoices, in seconds
+pub const INVOICE_EXPIRY_SECS: u64 = 3600;
+
+/// How often unpaid invoices are checked against the payment processor
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How often the unpaid invoice and collected sats gauges are refreshed
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fewest invoices created in the window before the paid ratio is
+/// checked
+const MIN_PAID_RATIO_SAMPLE: usize = 5;
 
 /// Payment handler
 pub struct Payment {
     /// Repository for saving/retrieving events and events
     repo: Arc<dyn NostrRepo>,
     /// Newly validated events get written and then broadcast on this channel to subscribers
-    event_tx: tokio::sync::broadcast::Sender<Event>,
+    event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
     /// Payment message sender
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
     /// Payment message receiver
@@ -28,12 +47,31 @@ pub struct Payment {
     nostr_keys: Option<Keys>,
     /// Payment Processor
     processor: Arc<dyn PaymentProcessor>,
+    /// Interval for reconciling unpaid invoices (first tick is immediate)
+    reconcile_interval: Interval,
+    /// Interval for refreshing payment gauges
+    stats_interval: Interval,
+    /// Metrics
+    metrics: NostrMetrics,
+    /// Recently created and paid invoices
+    paid_ratio: Mutex<PaidRatio>,
 }
 
 #[async_trait]
 pub trait PaymentProcessor: Send + Sync {
     /// Get invoice from processor
     async fn get_invoice(&self, keys: &Keys, amount: u64) -> Result<InvoiceInfo, Error>;
+    /// Get an invoice whose description hash commits to `description`,
+    /// as LNURL-pay requires.  Processors that cannot set it give a
+    /// plain invoice.
+    async fn get_described_invoice(
+        &self,
+        keys: &Keys,
+        amount: u64,
+        _description: &str,
+    ) -> Result<InvoiceInfo, Error> {
+        self.get_invoice(keys, amount).await
+    }
     /// Check payment status of an invoice
     async fn check_invoice(&self, payment_hash: &str) -> Result<InvoiceStatus, Error>;
 }
@@ -43,25 +81,56 @@ pub enum Processor {
     LNBits,
 }
 
-/// Possible states of an invoice
+/// Possible states of an invoice.  In Postgres this is the `status`
+/// enum type; adding a variant needs a migration that adds the value
+/// to the type (see `m008`), and to the `invoice.status` check in
+/// SQLite.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::Type)]
 #[sqlx(type_name = "status")]
 pub enum InvoiceStatus {
     Unpaid,
     Paid,
     Expired,
+    /// Paid, then returned to the payer by the operator
+    Refunded,
 }
 
-impl ToString for InvoiceStatus {
-    fn to_string(&self) -> String {
+impl InvoiceStatus {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
         match self {
-            InvoiceStatus::Paid => "Paid".to_string(),
-            InvoiceStatus::Unpaid => "Unpaid".to_string(),
-            InvoiceStatus::Expired => "Expired".to_string(),
+            InvoiceStatus::Unpaid => "Unpaid",
+            InvoiceStatus::Paid => "Paid",
+            InvoiceStatus::Expired => "Expired",
+            InvoiceStatus::Refunded => "Refunded",
         }
     }
 }
 
+impl std::fmt::Display for InvoiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for InvoiceStatus {
+    type Err = Error;
+
+    /// Parse a stored status.  Case is ignored, since rows written by
+    /// hand or by other tools do not always match the enum labels.
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            InvoiceStatus::Unpaid,
+            InvoiceStatus::Paid,
+            InvoiceStatus::Expired,
+            InvoiceStatus::Refunded,
+        ]
+        .into_iter()
+        .find(|status| status.as_str().eq_ignore_ascii_case(s.trim()))
+        .ok_or_else(|| Error::CustomError(format!("unknown invoice status: {s}")))
+    }
+}
+
 /// Invoice information
 #[derive(Debug, Clone)]
 pub struct InvoiceInfo {
@@ -74,6 +143,128 @@ pub struct InvoiceInfo {
     pub confirmed_at: Option<u64>,
 }
 
+/// Why an account balance changed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerReason {
+    /// Admission cost charged when the account was admitted
+    Admission,
+    /// Cost of publishing an event
+    Publication,
+    /// Invoice paid to the relay
+    Payment,
+    Zap,
+    /// Manual adjustment (and opening balances from before the ledger)
+    Admin,
+    Refund,
+}
+
+impl LedgerReason {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedgerReason::Admission => "admission",
+            LedgerReason::Publication => "publication",
+            LedgerReason::Payment => "payment",
+            LedgerReason::Zap => "zap",
+            LedgerReason::Admin => "admin",
+            LedgerReason::Refund => "refund",
+        }
+    }
+}
+
+impl std::str::FromStr for LedgerReason {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "admission" => Ok(LedgerReason::Admission),
+            "publication" => Ok(LedgerReason::Publication),
+            "payment" => Ok(LedgerReason::Payment),
+            "zap" => Ok(LedgerReason::Zap),
+            "admin" => Ok(LedgerReason::Admin),
+            "refund" => Ok(LedgerReason::Refund),
+            _ => Err(Error::CustomError(format!("unknown ledger reason: {s}"))),
+        }
+    }
+}
+
+/// A single change to an account balance
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    pub pubkey: String,
+    /// Amount added to (or, if negative, removed from) the balance
+    pub delta: i64,
+    /// Balance after the change
+    pub balance: i64,
+    pub reason: LedgerReason,
+    /// Event id or payment hash the change relates to
+    pub reference: Option<String>,
+    pub created_at: u64,
+}
+
+/// A paid invoice that was refunded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceRefund {
+    pub payment_hash: String,
+    pub pubkey: String,
+    pub amount: u64,
+    /// Amount removed from the account balance; less than `amount` if
+    /// some of the payment was already spent
+    pub debited: u64,
+}
+
+/// An account whose stored balance disagrees with its ledger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerMismatch {
+    pub pubkey: String,
+    pub balance: i64,
+    /// Sum of the ledger entries for the account
+    pub ledger_balance: i64,
+}
+
+/// Invoice totals, for metrics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymentStats {
+    /// Unpaid invoices that have not expired
+    pub unpaid_invoices: u64,
+    /// Total amount of paid invoices
+    pub sats_collected: u64,
+}
+
+/// Times that invoices were created and paid, over a rolling window.
+#[derive(Debug, Default)]
+pub struct PaidRatio {
+    created: VecDeque<u64>,
+    paid: VecDeque<u64>,
+}
+
+impl PaidRatio {
+    pub fn invoice_created(&mut self, at: u64) {
+        self.created.push_back(at);
+    }
+
+    pub fn invoice_paid(&mut self, at: u64) {
+        self.paid.push_back(at);
+    }
+
+    /// Fraction of invoices paid, of those created in the `window`
+    /// seconds before `now`.  None until enough invoices were created
+    /// for the ratio to mean anything.
+    pub fn ratio(&mut self, now: u64, window: u64) -> Option<f64> {
+        let cutoff = now.saturating_sub(window);
+        for times in [&mut self.created, &mut self.paid] {
+            while times.front().map_or(false, |t| *t < cutoff) {
+                times.pop_front();
+            }
+        }
+        if self.created.len() < MIN_PAID_RATIO_SAMPLE {
+            return None;
+        }
+        Some(self.paid.len() as f64 / self.created.len() as f64)
+    }
+}
+
 /// Message variants for the payment channel
 #[derive(Debug, Clone)]
 pub enum PaymentMessage {
@@ -85,19 +276,32 @@ pub enum PaymentMessage {
     AccountAdmitted(String),
     /// Invoice generated
     Invoice(String, InvoiceInfo),
+    /// New invoice for an LNURL-pay callback, committing to the
+    /// pay request metadata
+    LnurlInvoice(String, String),
     /// Invoice call back
     /// Payment hash is passed
     // This may have to be changed to better support other processors
     InvoicePaid(String),
 }
 
+/// The payment processor named in the settings
+#[must_use]
+pub fn configured_processor(settings: &crate::config::Settings) -> Arc<dyn PaymentProcessor> {
+    match &settings.pay_to_relay.processor {
+        Processor::LNBits => Arc::new(LNBitsPaymentProcessor::new(settings)),
+    }
+}
+
 impl Payment {
     pub fn new(
         repo: Arc<dyn NostrRepo>,
         payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
         payment_rx: tokio::sync::broadcast::Receiver<PaymentMessage>,
-        event_tx: tokio::sync::broadcast::Sender<Event>,
+        event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
         settings: crate::config::Settings,
+        processor: Arc<dyn PaymentProcessor>,
+        metrics: NostrMetrics,
     ) -> Result<Self> {
         info!("Create payment handler");
 
@@ -108,11 +312,6 @@ impl Payment {
             None
         };
 
-        // Create processor kind defined in settings
-        let processor = match &settings.pay_to_relay.processor {
-            Processor::LNBits => Arc::new(LNBitsPaymentProcessor::new(&settings)),
-        };
-
         Ok(Payment {
             repo,
             payment_tx,
@@ -121,6 +320,10 @@ impl Payment {
             settings,
             nostr_keys,
             processor,
+            reconcile_interval: tokio::time::interval(RECONCILE_INTERVAL),
+            stats_interval: tokio::time::interval(STATS_INTERVAL),
+            metrics,
+            paid_ratio: Mutex::new(PaidRatio::default()),
         })
     }
 
@@ -155,7 +358,7 @@ impl Payment {
                         if let Ok(Some(invoice_info)) = self.repo.get_unpaid_invoice(&keys).await {
                             match self.check_invoice_status(&invoice_info.payment_hash).await? {
                                 InvoiceStatus::Paid => {
-                                    self.repo.admit_account(&keys, self.settings.pay_to_relay.admission_cost).await?;
+                                    self.admit_account(&keys, &invoice_info.payment_hash).await?;
                                     self.payment_tx.send(PaymentMessage::AccountAdmitted(pubkey)).ok();
                                 }
                                 _ => {
@@ -168,6 +371,11 @@ impl Payment {
                             self.payment_tx.send(PaymentMessage::Invoice(pubkey, invoice_info)).ok();
                         }
                     }
+                    Ok(PaymentMessage::LnurlInvoice(pubkey, metadata)) => {
+                        let amount = self.settings.pay_to_relay.admission_cost;
+                        let invoice_info = self.get_lnurl_invoice_info(&pubkey, amount, &metadata).await?;
+                        self.payment_tx.send(PaymentMessage::Invoice(pubkey, invoice_info)).ok();
+                    }
                     Ok(PaymentMessage::InvoicePaid(payment_hash)) => {
                         if self.check_invoice_status(&payment_hash).await?.eq(&InvoiceStatus::Paid) {
                             let pubkey = self.repo
@@ -175,7 +383,7 @@ impl Payment {
                                 .await?;
 
                             let key = Keys::from_pk_str(&pubkey)?;
-                            self.repo.admit_account(&key, self.settings.pay_to_relay.admission_cost).await?;
+                            self.admit_account(&key, &payment_hash).await?;
                         }
                     }
                     Ok(_) => {
@@ -185,6 +393,13 @@ impl Payment {
                     Err(err) => warn!("Payment RX: {err}")
                 }
             }
+            _ = self.reconcile_interval.tick() => {
+                self.reconcile_invoices().await?;
+                self.check_paid_ratio();
+            }
+            _ = self.stats_interval.tick() => {
+                self.update_stats().await?;
+            }
         }
 
         Ok(())
@@ -225,8 +440,8 @@ impl Payment {
         self.repo.write_event(&invoice_event.clone().into()).await?;
 
         // Broadcast DM events
-        self.event_tx.send(message_event.clone().into()).ok();
-        self.event_tx.send(invoice_event.clone().into()).ok();
+        self.event_tx.send(Event::from(message_event).into()).ok();
+        self.event_tx.send(Event::from(invoice_event).into()).ok();
 
         Ok(())
     }
@@ -249,11 +464,7 @@ impl Payment {
         let key = Keys::from_pk_str(pubkey)?;
 
         let invoice_info = self.processor.get_invoice(&key, amount).await?;
-
-        // Persist invoice to DB
-        self.repo
-            .create_invoice_record(&key, invoice_info.clone())
-            .await?;
+        self.record_invoice(&key, &invoice_info).await?;
 
         if self.settings.pay_to_relay.direct_message {
             // Admission event invoice and terms to pubkey that is joining
@@ -263,14 +474,189 @@ impl Payment {
         Ok(invoice_info)
     }
 
+    /// Get a new invoice for an LNURL-pay callback.  The wallet pays it
+    /// directly, so no direct message is sent.
+    pub async fn get_lnurl_invoice_info(
+        &self,
+        pubkey: &str,
+        amount: u64,
+        metadata: &str,
+    ) -> Result<InvoiceInfo> {
+        let key = Keys::from_pk_str(pubkey)?;
+        self.repo.create_account(&key).await?;
+        let invoice_info = self
+            .processor
+            .get_described_invoice(&key, amount, metadata)
+            .await?;
+        self.record_invoice(&key, &invoice_info).await?;
+        Ok(invoice_info)
+    }
+
+    /// Persist a new invoice, and count it
+    async fn record_invoice(&self, key: &Keys, invoice_info: &InvoiceInfo) -> Result<()> {
+        self.repo
+            .create_invoice_record(key, invoice_info.clone())
+            .await?;
+        self.metrics
+            .payment_funnel
+            .with_label_values(&["invoice_created"])
+            .inc();
+        if let Ok(mut paid_ratio) = self.paid_ratio.lock() {
+            paid_ratio.invoice_created(unix_time());
+        }
+        Ok(())
+    }
+
+    /// Check the status of unpaid invoices that have not yet expired,
+    /// admitting accounts whose payment confirmed while the relay was
+    /// not listening (for example, during downtime).
+    pub async fn reconcile_invoices(&self) -> Result<()> {
+        let since = unix_time().saturating_sub(INVOICE_EXPIRY_SECS);
+        let invoices = self.repo.get_unpaid_invoices(since).await?;
+        if invoices.is_empty() {
+            return Ok(());
+        }
+        info!("reconciling {} unpaid invoices", invoices.len());
+        for invoice in invoices {
+            // the invoice status (and balance) is updated by the check
+            match self.check_invoice_status(&invoice.payment_hash).await {
+                Ok(InvoiceStatus::Paid) => {
+                    let key = Keys::from_pk_str(&invoice.pubkey)?;
+                    // don't charge admission twice if the account was
+                    // admitted another way
+                    if let Ok((false, _)) = self.repo.get_account_balance(&key).await {
+                        self.admit_account(&key, &invoice.payment_hash).await?;
+                        info!("admitted {} after reconciling invoice", invoice.pubkey);
+                        self.payment_tx
+                            .send(PaymentMessage::AccountAdmitted(invoice.pubkey))
+                            .ok();
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("could not check invoice {}: {:?}", invoice.payment_hash, e),
+            }
+        }
+        Ok(())
+    }
+
     /// Check paid status of invoice with LNbits
     pub async fn check_invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, Error> {
         // Check base if passed expiry time
         let status = self.processor.check_invoice(payment_hash).await?;
+        // note when an invoice is first seen paid, for metrics
+        let newly_paid = if status == InvoiceStatus::Paid {
+            self.repo.confirm_invoice(payment_hash).await?
+        } else {
+            None
+        };
         self.repo
             .update_invoice(payment_hash, status.clone())
             .await?;
+        if let Some(created_at) = newly_paid {
+            let now = unix_time();
+            self.metrics
+                .payment_funnel
+                .with_label_values(&["invoice_paid"])
+                .inc();
+            self.metrics
+                .invoice_paid_delay
+                .observe(now.saturating_sub(created_at) as f64);
+            if let Ok(mut paid_ratio) = self.paid_ratio.lock() {
+                paid_ratio.invoice_paid(now);
+            }
+        }
 
         Ok(status)
     }
+
+    /// Admit an account that paid the given invoice
+    async fn admit_account(&self, key: &Keys, payment_hash: &str) -> Result<()> {
+        self.repo
+            .admit_account(
+                key,
+                self.settings.pay_to_relay.admission_cost,
+                Some(payment_hash),
+            )
+            .await?;
+        self.metrics
+            .payment_funnel
+            .with_label_values(&["admitted"])
+            .inc();
+        Ok(())
+    }
+
+    /// Refresh the unpaid invoice and collected sats gauges
+    async fn update_stats(&self) -> Result<()> {
+        let since = unix_time().saturating_sub(INVOICE_EXPIRY_SECS);
+        let stats = self.repo.get_payment_stats(since).await?;
+        self.metrics
+            .invoices_unpaid
+            .set(stats.unpaid_invoices as i64);
+        self.metrics.sats_collected.set(stats.sats_collected as i64);
+        Ok(())
+    }
+
+    /// Warn if too few recent invoices have been paid, which usually
+    /// means the payment processor is not working.
+    fn check_paid_ratio(&self) {
+        let Some(min_ratio) = self.settings.pay_to_relay.min_paid_ratio else {
+            return;
+        };
+        let window = self.settings.pay_to_relay.paid_ratio_window_secs;
+        let ratio = match self.paid_ratio.lock() {
+            Ok(mut paid_ratio) => paid_ratio.ratio(unix_time(), window),
+            Err(_) => None,
+        };
+        if let Some(ratio) = ratio {
+            if ratio < min_ratio {
+                warn!(
+                    "only {:.0}% of invoices created in the last {}s were paid (minimum {:.0}%); check the payment processor",
+                    ratio * 100.0,
+                    window,
+                    min_ratio * 100.0
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invoice_status_round_trips() -> Result<()> {
+        for status in [
+            InvoiceStatus::Unpaid,
+            InvoiceStatus::Paid,
+            InvoiceStatus::Expired,
+            InvoiceStatus::Refunded,
+        ] {
+            assert_eq!(status.to_string().parse::<InvoiceStatus>()?, status);
+        }
+        assert_eq!("paid".parse::<InvoiceStatus>()?, InvoiceStatus::Paid);
+        assert!("Disputed".parse::<InvoiceStatus>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn paid_ratio_over_window() {
+        let mut r = PaidRatio::default();
+        for t in 0..4 {
+            r.invoice_created(1000 + t);
+        }
+        r.invoice_paid(1010);
+        // too few invoices to judge
+        assert_eq!(r.ratio(1100, 3600), None);
+        r.invoice_created(1020);
+        assert_eq!(r.ratio(1100, 3600), Some(0.2));
+        // old invoices leave the window
+        r.invoice_created(5000);
+        for t in 0..4 {
+            r.invoice_created(5000 + t);
+            r.invoice_paid(5000 + t);
+        }
+        assert_eq!(r.ratio(5100, 3600), Some(0.8));
+        assert_eq!(r.ratio(20000, 3600), None);
+    }
 }
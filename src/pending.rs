@@ -0,0 +1,191 @@
+//! Asynchronous event admission
+//!
+//! With `grpc.async_admission`, events are not held back while the
+//! event admission server decides on them.  They are stored hidden,
+//! acknowledged with `OK true "pending: ..."`, and admitted (made
+//! visible and broadcast) or removed once the decision arrives.
+//!
+//! An admitted event is broadcast only once it is marked as admitted,
+//! so subscribers never see an event that is later removed.  A relay
+//! that stops in between leaves the event visible to queries, but not
+//! broadcast.
+//!
+//! At most `PENDING_QUEUE_SIZE` events wait for a decision, and at
+//! most `MAX_VALIDATIONS` are decided at once.  Events that arrive
+//! while the queue is full are rejected as rate-limited.
+use crate::db::enforce;
+use crate::event::{BroadcastEvent, Event};
+use crate::nauthz::EventAuthzService;
+use crate::nip05::Nip05Name;
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tracing::{debug, info, warn};
+
+/// Message for events that were stored, but are not yet visible
+pub const PENDING_MESSAGE: &str = "pending: awaiting validation";
+
+/// Most events waiting for a decision before new ones are refused
+pub const PENDING_QUEUE_SIZE: usize = 1024;
+
+/// Most admission requests in flight at once
+const MAX_VALIDATIONS: usize = 64;
+
+/// An event stored while waiting for an admission decision, with the
+/// context of the connection that submitted it
+#[derive(Debug, Clone)]
+pub struct PendingEvent {
+    pub event: Event,
+    pub source_ip: String,
+    pub origin: Option<String>,
+    pub user_agent: Option<String>,
+    pub nip05: Option<Nip05Name>,
+    pub auth_pubkey: Option<Vec<u8>>,
+}
+
+impl PendingEvent {
+    /// A pending event found at startup; the submitting connection is
+    /// no longer known.
+    fn recovered(event: Event) -> Self {
+        PendingEvent {
+            event,
+            source_ip: String::new(),
+            origin: None,
+            user_agent: None,
+            nip05: None,
+            auth_pubkey: None,
+        }
+    }
+}
+
+/// Can this event be stored before it is admitted?  Events that
+/// replace or delete other events, or are never stored, are always
+/// decided before they are written.
+#[must_use]
+pub fn can_admit_later(e: &Event) -> bool {
+    !e.is_ephemeral() && !e.is_replaceable() && e.distinct_param().is_none() && e.kind != 5
+}
+
+/// Spawn the task that decides on pending events, starting with any
+/// left over from a previous run.
+pub fn pending_validator(
+    repo: Arc<dyn NostrRepo>,
+    authz: EventAuthzService,
+    mut pending_rx: mpsc::Receiver<PendingEvent>,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    metrics: NostrMetrics,
+    shadow: bool,
+) {
+    let check = Validation {
+        repo: repo.clone(),
+        authz,
+        bcast_tx,
+        metrics,
+        shadow,
+    };
+    // the queue only fills while every validation slot is taken
+    let slots = Arc::new(Semaphore::new(MAX_VALIDATIONS));
+    let spawn = move |check: Validation, pending: PendingEvent| {
+        let slots = slots.clone();
+        async move {
+            let Ok(slot) = slots.acquire_owned().await else {
+                return;
+            };
+            tokio::task::spawn(async move {
+                check.run(pending).await;
+                drop(slot);
+            });
+        }
+    };
+    tokio::task::spawn(async move {
+        match repo.get_pending_events().await {
+            Ok(events) => {
+                if !events.is_empty() {
+                    info!("re-validating {} pending events", events.len());
+                }
+                for event in events {
+                    spawn(check.clone(), PendingEvent::recovered(event)).await;
+                }
+            }
+            Err(e) => warn!("could not load pending events: {:?}", e),
+        }
+        while let Some(pending) = pending_rx.recv().await {
+            spawn(check.clone(), pending).await;
+        }
+        debug!("pending event validator stopped");
+    });
+}
+
+/// Everything needed to decide on a single pending event
+#[derive(Clone)]
+struct Validation {
+    repo: Arc<dyn NostrRepo>,
+    authz: EventAuthzService,
+    bcast_tx: broadcast::Sender<BroadcastEvent>,
+    metrics: NostrMetrics,
+    shadow: bool,
+}
+
+impl Validation {
+    async fn run(mut self, p: PendingEvent) {
+        let event = p.event;
+        let decision = self
+            .authz
+            .admit_event(
+                &event,
+                &p.source_ip,
+                p.origin,
+                p.user_agent,
+                p.nip05,
+                p.auth_pubkey,
+            )
+            .await;
+        // like synchronous admission, a server error permits the event
+        let accepted = match decision {
+            Ok(d) => {
+                d.permitted()
+                    || !enforce(
+                        self.shadow,
+                        &self.metrics,
+                        "grpc",
+                        &event.id,
+                        &d.message().unwrap_or_default(),
+                    )
+            }
+            Err(e) => {
+                warn!("GRPC server error: {:?}", e);
+                true
+            }
+        };
+        let outcome = match self.repo.resolve_pending_event(&event.id, accepted).await {
+            Ok(true) if accepted => {
+                // only once it is visible, and will not be removed
+                self.bcast_tx.send(event.clone().into()).ok();
+                "admitted"
+            }
+            Ok(true) => "rejected",
+            // deleted while it was waiting
+            Ok(false) => "gone",
+            Err(e) => {
+                warn!(
+                    "could not resolve pending event {:?}: {:?}",
+                    event.get_event_id_prefix(),
+                    e
+                );
+                "error"
+            }
+        };
+        info!(
+            "pending event: {:?} (kind: {}) from: {:?} {}",
+            event.get_event_id_prefix(),
+            event.kind,
+            event.get_author_prefix(),
+            outcome
+        );
+        self.metrics
+            .pending_events
+            .with_label_values(&[outcome])
+            .inc();
+    }
+}
@@ -0,0 +1,175 @@
+//! Progress reports for subscriptions sending many stored events
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a subscription sends nothing before progress is reported
+pub const PROGRESS_IDLE: Duration = Duration::from_secs(1);
+
+/// How progress is reported to clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// A NOTICE describing the subscription's progress
+    Notice,
+    /// A draft `["PROGRESS", <sub>, {"sent": <count>}]` message
+    Progress,
+}
+
+impl ProgressFormat {
+    /// Parse the `options.req_progress_format` setting
+    #[must_use]
+    pub fn from_setting(format: &str) -> Self {
+        if format == "progress" {
+            ProgressFormat::Progress
+        } else {
+            ProgressFormat::Notice
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Backfill {
+    /// When the query for stored events started
+    started: Instant,
+    /// Stored events sent so far
+    sent: u64,
+    /// When the last stored event was sent
+    last_sent: Instant,
+}
+
+/// Per-connection record of subscriptions that are still sending
+/// stored events, and asked for progress reports.
+#[derive(Debug)]
+pub struct QueryProgress {
+    /// How long a subscription runs before progress is reported
+    threshold: Duration,
+    running: HashMap<String, Backfill>,
+}
+
+impl QueryProgress {
+    #[must_use]
+    pub fn new(threshold: Duration) -> Self {
+        QueryProgress {
+            threshold,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Start tracking a subscription, replacing any earlier one with
+    /// the same id.
+    pub fn start(&mut self, sub_id: &str, now: Instant) {
+        self.running.insert(
+            sub_id.to_owned(),
+            Backfill {
+                started: now,
+                sent: 0,
+                last_sent: now,
+            },
+        );
+    }
+
+    /// Count a stored event sent to a subscription.
+    pub fn sent(&mut self, sub_id: &str, now: Instant) {
+        if let Some(b) = self.running.get_mut(sub_id) {
+            b.sent += 1;
+            b.last_sent = now;
+        }
+    }
+
+    /// Stop tracking a subscription, after EOSE or when it is closed.
+    pub fn finish(&mut self, sub_id: &str) {
+        self.running.remove(sub_id);
+    }
+
+    /// Are any subscriptions being tracked?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Subscriptions that have been sending stored events for longer
+    /// than the threshold, but have sent none for [`PROGRESS_IDLE`],
+    /// with the number sent and time elapsed.
+    #[must_use]
+    pub fn due(&self, now: Instant) -> Vec<(&str, u64, Duration)> {
+        self.running
+            .iter()
+            .filter_map(|(id, b)| {
+                let elapsed = now.saturating_duration_since(b.started);
+                let idle = now.saturating_duration_since(b.last_sent);
+                let due = elapsed >= self.threshold && idle >= PROGRESS_IDLE;
+                due.then_some((id.as_str(), b.sent, elapsed))
+            })
+            .collect()
+    }
+}
+
+/// Message reporting that `sent` stored events have been sent to a
+/// subscription so far.
+#[must_use]
+pub fn progress_message(
+    format: ProgressFormat,
+    sub_id: &str,
+    sent: u64,
+    elapsed: Duration,
+) -> Value {
+    match format {
+        ProgressFormat::Notice => json!([
+            "NOTICE",
+            format!(
+                "subscription {sub_id}: sent {sent} stored events in {}s, still working",
+                elapsed.as_secs()
+            )
+        ]),
+        ProgressFormat::Progress => json!(["PROGRESS", sub_id, { "sent": sent }]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_after_threshold_until_finished() {
+        let start = Instant::now();
+        let mut progress = QueryProgress::new(Duration::from_secs(10));
+        progress.start("feed", start);
+        progress.sent("feed", start);
+        progress.sent("feed", start);
+        progress.sent("other", start);
+        assert!(progress.due(start + Duration::from_secs(5)).is_empty());
+        let due = progress.due(start + Duration::from_secs(12));
+        assert_eq!(due, vec![("feed", 2, Duration::from_secs(12))]);
+        // a replacement subscription starts over
+        progress.start("feed", start + Duration::from_secs(12));
+        assert!(progress.due(start + Duration::from_secs(13)).is_empty());
+        progress.finish("feed");
+        assert!(progress.is_empty());
+    }
+
+    #[test]
+    fn reports_only_while_idle() {
+        let start = Instant::now();
+        let mut progress = QueryProgress::new(Duration::from_secs(10));
+        progress.start("feed", start);
+        // events are still arriving, so there is nothing to report
+        progress.sent("feed", start + Duration::from_millis(11_500));
+        assert!(progress.due(start + Duration::from_secs(12)).is_empty());
+        let due = progress.due(start + Duration::from_millis(12_500));
+        assert_eq!(due, vec![("feed", 1, Duration::from_millis(12_500))]);
+    }
+
+    #[test]
+    fn message_formats() {
+        let notice = progress_message(ProgressFormat::Notice, "feed", 500, Duration::from_secs(12));
+        assert_eq!(notice[0], "NOTICE");
+        assert!(notice[1].as_str().unwrap().contains("sent 500"));
+        let draft = progress_message(
+            ProgressFormat::Progress,
+            "feed",
+            500,
+            Duration::from_secs(12),
+        );
+        assert_eq!(draft.to_string(), r#"["PROGRESS","feed",{"sent":500}]"#);
+    }
+}
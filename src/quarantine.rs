@@ -0,0 +1,366 @@
+//! Probation for pubkeys new to the relay
+//!
+//! Events from a pubkey the relay has not seen before are stored and
+//! acknowledged as usual, but withheld from other clients while the
+//! pubkey is quarantined.  A pubkey is released once its probation
+//! period has passed, once it has written enough events, or by an
+//! admin.  Quarantine is checked as events are sent, so a released
+//! pubkey's earlier events become visible.  Banned pubkeys stay
+//! withheld, and may not publish.
+use crate::config::{Quarantine, Settings};
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::read_policy::ReadPolicy;
+use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often pubkeys whose probation has passed are released
+pub const RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most released pubkeys held in memory.  Once full, they are
+/// forgotten, and looked up again as they write.
+const MAX_RELEASED: usize = 100_000;
+
+/// Where a pubkey stands with the relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PubkeyStatus {
+    /// New to the relay; its events are withheld
+    Quarantined,
+    /// Its events are visible to everyone
+    Released,
+    /// Its events are withheld, and new ones are rejected
+    Banned,
+}
+
+impl PubkeyStatus {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PubkeyStatus::Quarantined => "quarantined",
+            PubkeyStatus::Released => "released",
+            PubkeyStatus::Banned => "banned",
+        }
+    }
+}
+
+impl fmt::Display for PubkeyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for PubkeyStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            PubkeyStatus::Quarantined,
+            PubkeyStatus::Released,
+            PubkeyStatus::Banned,
+        ]
+        .into_iter()
+        .find(|status| status.as_str() == s)
+        .ok_or_else(|| Error::CustomError(format!("unknown pubkey status: {s}")))
+    }
+}
+
+/// A pubkey seen by the relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuarantinedPubkey {
+    pub pubkey: String,
+    /// When the relay received the pubkey's first event
+    pub first_seen: u64,
+    /// Events written while quarantined
+    pub event_count: u64,
+    pub status: PubkeyStatus,
+}
+
+/// Shared quarantine state.  Quarantined and banned pubkeys are held
+/// in memory, along with recently seen released pubkeys, so most
+/// events need no lookup.
+#[derive(Debug, Clone, Default)]
+pub struct PubkeyQuarantine {
+    enabled: bool,
+    probation_secs: u64,
+    release_after_events: Option<u64>,
+    exempt_kinds: Vec<u64>,
+    withheld: Arc<RwLock<HashMap<String, PubkeyStatus>>>,
+    released: Arc<RwLock<HashSet<String>>>,
+}
+
+impl PubkeyQuarantine {
+    #[must_use]
+    pub fn new(settings: &Quarantine) -> Self {
+        PubkeyQuarantine {
+            enabled: settings.enabled,
+            probation_secs: settings.probation_hours * 3600,
+            release_after_events: settings.release_after_events,
+            exempt_kinds: settings.exempt_kinds.clone(),
+            withheld: Arc::new(RwLock::new(HashMap::new())),
+            released: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Is the pubkey quarantined or banned?
+    #[must_use]
+    pub fn withheld_status(&self, pubkey: &str) -> Option<PubkeyStatus> {
+        self.withheld.read().ok()?.get(pubkey).copied()
+    }
+
+    /// Is this event withheld from clients other than its author and
+    /// the reviewers?
+    #[must_use]
+    pub fn is_withheld(&self, event: &Event) -> bool {
+        self.enabled
+            && !self.exempt_kinds.contains(&event.kind)
+            && self.withheld_status(&event.pubkey).is_some()
+    }
+
+    /// Why an accepted event will not reach other clients, if it is
+    /// withheld.
+    #[must_use]
+    pub fn delivery_note(&self, event: &Event) -> Option<String> {
+        self.is_withheld(event).then(|| {
+            format!(
+                "event {} was stored, but is withheld from other clients while its author is new to this relay",
+                event.id
+            )
+        })
+    }
+
+    /// Record the status of a pubkey in memory.
+    fn remember(&self, pubkey: &str, status: PubkeyStatus) {
+        let (Ok(mut withheld), Ok(mut released)) = (self.withheld.write(), self.released.write())
+        else {
+            warn!("quarantine lock poisoned");
+            return;
+        };
+        if status == PubkeyStatus::Released {
+            withheld.remove(pubkey);
+            if released.len() >= MAX_RELEASED {
+                released.clear();
+            }
+            released.insert(pubkey.to_owned());
+        } else {
+            released.remove(pubkey);
+            withheld.insert(pubkey.to_owned(), status);
+        }
+    }
+
+    /// Restore the quarantined and banned pubkeys.
+    pub async fn load(&self, repo: &dyn NostrRepo) -> Result<()> {
+        let pubkeys = repo.withheld_pubkeys().await?;
+        info!("{} pubkeys quarantined or banned", pubkeys.len());
+        for p in pubkeys {
+            self.remember(&p.pubkey, p.status);
+        }
+        Ok(())
+    }
+
+    /// Status of the author of an event about to be written.  A pubkey
+    /// seen for the first time is quarantined, unless it has events
+    /// stored from before quarantine was enabled.
+    pub async fn admit(&self, repo: &dyn NostrRepo, pubkey: &str) -> Result<PubkeyStatus> {
+        if let Some(status) = self.withheld_status(pubkey) {
+            return Ok(status);
+        }
+        if self.released.read().map_or(false, |r| r.contains(pubkey)) {
+            return Ok(PubkeyStatus::Released);
+        }
+        let status = match repo.get_pubkey_status(pubkey).await? {
+            Some(status) => status,
+            None => {
+                let status = if repo.latest_created_at(pubkey).await?.is_some() {
+                    PubkeyStatus::Released
+                } else {
+                    info!("quarantining new pubkey {}", pubkey);
+                    PubkeyStatus::Quarantined
+                };
+                repo.add_first_seen_pubkey(pubkey, unix_time(), status)
+                    .await?;
+                status
+            }
+        };
+        self.remember(pubkey, status);
+        Ok(status)
+    }
+
+    /// Count an event written by a quarantined pubkey, releasing it
+    /// once it has written `release_after_events`.
+    pub async fn count_event(&self, repo: &dyn NostrRepo, pubkey: &str) -> Result<()> {
+        if self.withheld_status(pubkey) != Some(PubkeyStatus::Quarantined) {
+            return Ok(());
+        }
+        let count = repo.count_quarantined_event(pubkey).await?;
+        if self.release_after_events.map_or(false, |n| count >= n) {
+            info!("releasing pubkey {} after {} events", pubkey, count);
+            self.set_status(repo, pubkey, PubkeyStatus::Released)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Release or ban a pubkey.
+    pub async fn set_status(
+        &self,
+        repo: &dyn NostrRepo,
+        pubkey: &str,
+        status: PubkeyStatus,
+    ) -> Result<()> {
+        repo.set_pubkey_status(pubkey, status).await?;
+        self.remember(pubkey, status);
+        Ok(())
+    }
+
+    /// Release the pubkeys whose probation has passed.  Returns how
+    /// many were released.
+    pub async fn release_expired(&self, repo: &dyn NostrRepo) -> Result<usize> {
+        let cutoff = unix_time().saturating_sub(self.probation_secs);
+        let released = repo.release_quarantined(cutoff).await?;
+        for pubkey in &released {
+            self.remember(pubkey, PubkeyStatus::Released);
+        }
+        Ok(released.len())
+    }
+
+    /// Release pubkeys whose probation has passed every
+    /// `RELEASE_INTERVAL`.
+    pub async fn run_release(self, repo: Arc<dyn NostrRepo>) {
+        let mut interval = tokio::time::interval(RELEASE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.release_expired(repo.as_ref()).await {
+                Ok(0) => {}
+                Ok(n) => info!("released {} pubkeys from quarantine", n),
+                Err(e) => warn!("could not release quarantined pubkeys: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Withholds the events of quarantined and banned pubkeys from every
+/// client but their author, the admin pubkey and the reviewers, then
+/// applies another policy.
+pub struct QuarantineReadPolicy {
+    inner: Arc<dyn ReadPolicy>,
+    quarantine: PubkeyQuarantine,
+    reviewers: Vec<String>,
+}
+
+impl QuarantineReadPolicy {
+    #[must_use]
+    pub fn new(
+        inner: Arc<dyn ReadPolicy>,
+        quarantine: PubkeyQuarantine,
+        settings: &Settings,
+    ) -> Self {
+        let mut reviewers = settings.quarantine.reviewer_pubkeys.clone();
+        reviewers.extend(settings.info.pubkey.clone());
+        QuarantineReadPolicy {
+            inner,
+            quarantine,
+            reviewers,
+        }
+    }
+}
+
+impl ReadPolicy for QuarantineReadPolicy {
+    fn restricted_kinds(&self) -> Vec<u64> {
+        self.inner.restricted_kinds()
+    }
+
+    fn withholds_events(&self) -> bool {
+        true
+    }
+
+    fn can_read(&self, event: &Event, auth_pubkey: Option<&str>) -> bool {
+        if self.quarantine.is_withheld(event) {
+            let reviewer = auth_pubkey.map_or(false, |pubkey| {
+                pubkey == event.pubkey || self.reviewers.iter().any(|r| r == pubkey)
+            });
+            if !reviewer {
+                return false;
+            }
+        }
+        self.inner.can_read(event, auth_pubkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_policy::ParticipantReadPolicy;
+
+    fn note(author: &str, kind: u64) -> Event {
+        let mut event = Event::simple_event();
+        event.pubkey = author.to_owned();
+        event.kind = kind;
+        event
+    }
+
+    #[test]
+    fn status_round_trips() -> Result<()> {
+        for status in [
+            PubkeyStatus::Quarantined,
+            PubkeyStatus::Released,
+            PubkeyStatus::Banned,
+        ] {
+            assert_eq!(status.as_str().parse::<PubkeyStatus>()?, status);
+        }
+        assert!("muted".parse::<PubkeyStatus>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn withheld_from_all_but_author_and_reviewers() {
+        let mut settings = Settings::default();
+        settings.quarantine.enabled = true;
+        settings.quarantine.reviewer_pubkeys = vec!["reviewer".to_owned()];
+        settings.info.pubkey = Some("admin".to_owned());
+        let quarantine = PubkeyQuarantine::new(&settings.quarantine);
+        quarantine.remember("newbie", PubkeyStatus::Quarantined);
+        let policy = QuarantineReadPolicy::new(
+            Arc::new(ParticipantReadPolicy::default()),
+            quarantine.clone(),
+            &settings,
+        );
+        let event = note("newbie", 1);
+        assert!(!policy.can_read(&event, None));
+        assert!(!policy.can_read(&event, Some("someone")));
+        assert!(policy.can_read(&event, Some("newbie")));
+        assert!(policy.can_read(&event, Some("reviewer")));
+        assert!(policy.can_read(&event, Some("admin")));
+        // profiles and contact lists are exempt
+        assert!(policy.can_read(&note("newbie", 0), None));
+        assert!(quarantine.delivery_note(&event).is_some());
+        // released pubkeys' events become visible
+        quarantine.remember("newbie", PubkeyStatus::Released);
+        assert!(policy.can_read(&event, None));
+        assert!(quarantine.delivery_note(&event).is_none());
+        quarantine.remember("newbie", PubkeyStatus::Banned);
+        assert!(!policy.can_read(&event, None));
+    }
+
+    #[test]
+    fn released_pubkeys_are_bounded() {
+        let quarantine = PubkeyQuarantine::default();
+        for i in 0..=MAX_RELEASED {
+            quarantine.remember(&i.to_string(), PubkeyStatus::Released);
+        }
+        let released = quarantine.released.read().unwrap();
+        assert_eq!(released.len(), 1);
+        assert!(released.contains(&MAX_RELEASED.to_string()));
+    }
+}
@@ -0,0 +1,629 @@
+//! Rate limits and connection counts, optionally shared between relay
+//! instances through Redis
+use crate::config::Limits;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use prometheus::IntCounter;
+use redis::aio::MultiplexedConnection;
+use redis::Script;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Window for the per-minute event limits
+const EVENT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Failed AUTH attempts from an address are remembered for this long
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Longest delay before answering a failed AUTH attempt
+const MAX_AUTH_FAILURE_DELAY: Duration = Duration::from_secs(30);
+
+/// Connection counts expire if they are not updated for this long, so
+/// counts from an instance that died without disconnecting its
+/// clients do not last forever.
+const CONNECTION_TTL: Duration = Duration::from_secs(3600);
+
+/// Give up on a Redis command after this long
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// After Redis fails, wait this long before trying it again
+const REDIS_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often store errors are logged
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where rate limit counters and connection counts are kept
+#[async_trait]
+pub trait LimitStore: Send + Sync {
+    /// Count a hit for `key` in the current window, returning the
+    /// number of hits in the window so far (including this one).  The
+    /// count is dropped when the window ends.
+    async fn hit(&self, key: &str, window: Duration) -> Result<u64>;
+
+    /// Count a new connection for `key`, returning the number open
+    async fn connect(&self, key: &str) -> Result<u64>;
+
+    /// Count a closed connection for `key`
+    async fn disconnect(&self, key: &str) -> Result<()>;
+
+    /// Keys with the most open connections, most first
+    async fn connection_counts(&self, limit: usize) -> Result<Vec<(String, u64)>>;
+}
+
+/// Delay before answering a failed AUTH attempt, doubling from `base`
+/// with each earlier failure, up to a limit.
+#[must_use]
+pub fn auth_failure_delay(base: Duration, failures: u64) -> Duration {
+    let doublings = u32::try_from(failures.saturating_sub(1))
+        .unwrap_or(u32::MAX)
+        .min(16);
+    base.saturating_mul(1 << doublings)
+        .min(MAX_AUTH_FAILURE_DELAY)
+}
+
+/// Sort connection counts, most first, and keep the first `limit`
+fn top_counts(mut counts: Vec<(String, u64)>, limit: usize) -> Vec<(String, u64)> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit);
+    counts
+}
+
+#[derive(Debug)]
+struct Windows {
+    /// End of the current window, and hits in it
+    counts: HashMap<String, (Instant, u64)>,
+    last_prune: Instant,
+}
+
+/// Counters for a single relay instance
+#[derive(Debug)]
+pub struct MemoryStore {
+    windows: Mutex<Windows>,
+    connections: Mutex<HashMap<String, u64>>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            windows: Mutex::new(Windows {
+                counts: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl LimitStore for MemoryStore {
+    async fn hit(&self, key: &str, window: Duration) -> Result<u64> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // drop finished windows, at most once a second
+        if now.duration_since(windows.last_prune) > Duration::from_secs(1) {
+            windows.counts.retain(|_, (end, _)| *end > now);
+            windows.last_prune = now;
+        }
+        let entry = windows
+            .counts
+            .entry(key.to_owned())
+            .or_insert((now + window, 0));
+        if entry.0 <= now {
+            *entry = (now + window, 0);
+        }
+        entry.1 += 1;
+        Ok(entry.1)
+    }
+
+    async fn connect(&self, key: &str) -> Result<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(key.to_owned()).or_insert(0);
+        *count += 1;
+        Ok(*count)
+    }
+
+    async fn disconnect(&self, key: &str) -> Result<()> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn connection_counts(&self, limit: usize) -> Result<Vec<(String, u64)>> {
+        let counts = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        Ok(top_counts(counts, limit))
+    }
+}
+
+/// Counters shared by every relay instance using the same Redis
+/// server.  All keys are set to expire.
+pub struct RedisStore {
+    client: redis::Client,
+    prefix: String,
+    /// Open connection, and when a failed one may be retried
+    conn: tokio::sync::Mutex<(Option<MultiplexedConnection>, Option<Instant>)>,
+    hit_script: Script,
+    connect_script: Script,
+    disconnect_script: Script,
+}
+
+impl RedisStore {
+    /// Create a store for the Redis server at `url`.  No connection
+    /// is made until the first command.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the URL is not valid.
+    pub fn new(url: &str, prefix: &str) -> Result<Self> {
+        Ok(RedisStore {
+            client: redis::Client::open(url)?,
+            prefix: prefix.to_owned(),
+            conn: tokio::sync::Mutex::new((None, None)),
+            hit_script: Script::new(
+                r"
+local n = redis.call('INCR', KEYS[1])
+if n == 1 then redis.call('PEXPIRE', KEYS[1], ARGV[1]) end
+return n",
+            ),
+            connect_script: Script::new(
+                r"
+local n = redis.call('INCR', KEYS[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[1])
+return n",
+            ),
+            disconnect_script: Script::new(
+                r"
+local n = redis.call('DECR', KEYS[1])
+if n <= 0 then redis.call('DEL', KEYS[1]) else redis.call('PEXPIRE', KEYS[1], ARGV[1]) end
+return n",
+            ),
+        })
+    }
+
+    /// Connect, or reuse the open connection.  Fails fast for a while
+    /// after Redis could not be reached.
+    async fn connection(&self) -> Result<MultiplexedConnection> {
+        let mut state = self.conn.lock().await;
+        if let Some(retry_at) = state.1 {
+            if Instant::now() < retry_at {
+                return Err(Error::CustomError("redis is unavailable".to_owned()));
+            }
+        }
+        if let Some(conn) = &state.0 {
+            return Ok(conn.clone());
+        }
+        let connected = tokio::time::timeout(
+            REDIS_TIMEOUT,
+            self.client.get_multiplexed_tokio_connection(),
+        )
+        .await;
+        match connected {
+            Ok(Ok(conn)) => {
+                info!("connected to redis");
+                *state = (Some(conn.clone()), None);
+                Ok(conn)
+            }
+            Ok(Err(e)) => {
+                state.1 = Some(Instant::now() + REDIS_RETRY_DELAY);
+                Err(e.into())
+            }
+            Err(_) => {
+                state.1 = Some(Instant::now() + REDIS_RETRY_DELAY);
+                Err(Error::CustomError("redis connect timed out".to_owned()))
+            }
+        }
+    }
+
+    /// Wait for a Redis command, reconnecting (after a pause) if it
+    /// failed
+    async fn finish<T>(
+        &self,
+        command: impl std::future::Future<Output = redis::RedisResult<T>>,
+    ) -> Result<T> {
+        let err = match tokio::time::timeout(REDIS_TIMEOUT, command).await {
+            Ok(Ok(v)) => return Ok(v),
+            Ok(Err(e)) => Error::from(e),
+            Err(_) => Error::CustomError("redis command timed out".to_owned()),
+        };
+        *self.conn.lock().await = (None, Some(Instant::now() + REDIS_RETRY_DELAY));
+        Err(err)
+    }
+
+    /// Run a script on a single key
+    async fn run(&self, script: &Script, key: String, arg: Duration) -> Result<i64> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}:{key}", self.prefix);
+        self.finish(
+            script
+                .key(key)
+                .arg(arg.as_millis() as u64)
+                .invoke_async::<_, i64>(&mut conn),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl LimitStore for RedisStore {
+    async fn hit(&self, key: &str, window: Duration) -> Result<u64> {
+        let n = self
+            .run(&self.hit_script, format!("hit:{key}"), window)
+            .await?;
+        Ok(n.max(0) as u64)
+    }
+
+    async fn connect(&self, key: &str) -> Result<u64> {
+        let n = self
+            .run(&self.connect_script, format!("conn:{key}"), CONNECTION_TTL)
+            .await?;
+        Ok(n.max(0) as u64)
+    }
+
+    async fn disconnect(&self, key: &str) -> Result<()> {
+        self.run(
+            &self.disconnect_script,
+            format!("conn:{key}"),
+            CONNECTION_TTL,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn connection_counts(&self, limit: usize) -> Result<Vec<(String, u64)>> {
+        let mut conn = self.connection().await?;
+        let pattern = format!("{}:conn:*", self.prefix);
+        let key_start = pattern.len() - 1;
+        let mut counts = vec![];
+        let mut cursor = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = self
+                .finish(
+                    redis::cmd("SCAN")
+                        .cursor_arg(cursor)
+                        .arg("MATCH")
+                        .arg(&pattern)
+                        .arg("COUNT")
+                        .arg(1000)
+                        .query_async(&mut conn),
+                )
+                .await?;
+            if !keys.is_empty() {
+                let values: Vec<Option<i64>> = self
+                    .finish(redis::cmd("MGET").arg(&keys).query_async(&mut conn))
+                    .await?;
+                counts.extend(keys.iter().zip(values).filter_map(|(key, n)| {
+                    n.filter(|&n| n > 0)
+                        .map(|n| (key[key_start..].to_owned(), n as u64))
+                }));
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(top_counts(counts, limit))
+    }
+}
+
+/// Rate limits and connection counts for clients.  If the store
+/// fails, clients are allowed through (with a warning), rather than
+/// blocking all traffic.
+#[derive(Clone)]
+pub struct RateLimits {
+    store: Arc<dyn LimitStore>,
+    events_per_min_per_ip: Option<u32>,
+    events_per_min_per_pubkey: Option<u32>,
+    max_connections_per_ip: Option<u32>,
+    auth_attempts_per_min: Option<u32>,
+    /// Addresses that are counted, but never refused
+    exempt_ips: Arc<Vec<String>>,
+    /// Count of store errors
+    errors: IntCounter,
+    last_warning: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RateLimits {
+    /// Use the store chosen by `limits.rate_limit_store`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the Redis URL is not valid.
+    pub fn from_limits(limits: &Limits, errors: IntCounter) -> Result<Self> {
+        let store: Arc<dyn LimitStore> = match limits.rate_limit_store.as_str() {
+            "redis" => {
+                let url = limits.redis_url.as_deref().unwrap_or_default();
+                info!("sharing rate limits through redis");
+                Arc::new(RedisStore::new(url, &limits.redis_key_prefix)?)
+            }
+            _ => Arc::new(MemoryStore::default()),
+        };
+        Ok(Self::new(store, limits, errors))
+    }
+
+    #[must_use]
+    pub fn new(store: Arc<dyn LimitStore>, limits: &Limits, errors: IntCounter) -> Self {
+        RateLimits {
+            store,
+            events_per_min_per_ip: limits.events_per_min_per_ip.filter(|&l| l > 0),
+            events_per_min_per_pubkey: limits.events_per_min_per_pubkey.filter(|&l| l > 0),
+            max_connections_per_ip: limits.max_connections_per_ip.filter(|&l| l > 0),
+            auth_attempts_per_min: limits.auth_attempts_per_min.filter(|&l| l > 0),
+            exempt_ips: Arc::new(limits.connection_limit_exempt_ips.clone()),
+            errors,
+            last_warning: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// May a client at `ip` publish another event by `pubkey`?  Both
+    /// limits are counted, even if the first is exceeded.
+    pub async fn allow_event(&self, ip: &str, pubkey: &str) -> bool {
+        let ip_ok = match self.events_per_min_per_ip {
+            Some(limit) => self.allow(&format!("ip:{ip}"), limit).await,
+            None => true,
+        };
+        let pubkey_ok = match self.events_per_min_per_pubkey {
+            Some(limit) => self.allow(&format!("pubkey:{pubkey}"), limit).await,
+            None => true,
+        };
+        ip_ok && pubkey_ok
+    }
+
+    /// May a client at `ip` make another AUTH attempt?
+    pub async fn allow_auth(&self, ip: &str) -> bool {
+        match self.auth_attempts_per_min {
+            Some(limit) => self.allow(&format!("auth:{ip}"), limit).await,
+            None => true,
+        }
+    }
+
+    /// Count a failed AUTH attempt as `pubkey`, returning the number
+    /// of recent failures as it (including this one), from any
+    /// connection.
+    pub async fn auth_failed(&self, pubkey: &str) -> u64 {
+        match self
+            .store
+            .hit(&format!("auth-failure:{pubkey}"), AUTH_FAILURE_WINDOW)
+            .await
+        {
+            Ok(failures) => failures,
+            Err(e) => {
+                self.store_failed(&e);
+                1
+            }
+        }
+    }
+
+    async fn allow(&self, key: &str, limit: u32) -> bool {
+        match self.store.hit(&format!("event:{key}"), EVENT_WINDOW).await {
+            Ok(hits) => hits <= u64::from(limit),
+            Err(e) => {
+                self.store_failed(&e);
+                true
+            }
+        }
+    }
+
+    /// Count a new connection from `ip`.  Returns a guard that keeps
+    /// it counted until dropped, or `None` if `ip` already has
+    /// `max_connections_per_ip` connections open.
+    pub async fn open_connection(&self, ip: &str) -> Option<ConnectionGuard> {
+        let open = match self.store.connect(ip).await {
+            Ok(open) => open,
+            Err(e) => {
+                self.store_failed(&e);
+                return Some(ConnectionGuard {
+                    limits: self.clone(),
+                    ip: ip.to_owned(),
+                    counted: false,
+                });
+            }
+        };
+        let over_limit = self
+            .max_connections_per_ip
+            .map_or(false, |max| open > u64::from(max))
+            && !self.exempt_ips.iter().any(|e| e == ip);
+        if over_limit {
+            self.disconnect(ip).await;
+            return None;
+        }
+        Some(ConnectionGuard {
+            limits: self.clone(),
+            ip: ip.to_owned(),
+            counted: true,
+        })
+    }
+
+    /// Addresses with the most open connections, most first
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store could not be read.
+    pub async fn connection_counts(&self, limit: usize) -> Result<Vec<(String, u64)>> {
+        self.store.connection_counts(limit).await
+    }
+
+    async fn disconnect(&self, ip: &str) {
+        if let Err(e) = self.store.disconnect(ip).await {
+            self.store_failed(&e);
+        }
+    }
+
+    fn store_failed(&self, e: &Error) {
+        self.errors.inc();
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.map_or(true, |t| t.elapsed() > WARNING_INTERVAL) {
+            warn!("rate limit store failed, allowing clients through: {e}");
+            *last_warning = Some(Instant::now());
+        }
+    }
+}
+
+/// An open connection, counted until this is dropped (including when
+/// the connection task panics)
+pub struct ConnectionGuard {
+    limits: RateLimits,
+    ip: String,
+    counted: bool,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if !self.counted {
+            return;
+        }
+        let limits = self.limits.clone();
+        let ip = std::mem::take(&mut self.ip);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move { limits.disconnect(&ip).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    struct FailingStore;
+
+    #[async_trait]
+    impl LimitStore for FailingStore {
+        async fn hit(&self, _key: &str, _window: Duration) -> Result<u64> {
+            Err(Error::CustomError("unreachable".to_owned()))
+        }
+        async fn connect(&self, _key: &str) -> Result<u64> {
+            Err(Error::CustomError("unreachable".to_owned()))
+        }
+        async fn disconnect(&self, _key: &str) -> Result<()> {
+            Err(Error::CustomError("unreachable".to_owned()))
+        }
+        async fn connection_counts(&self, _limit: usize) -> Result<Vec<(String, u64)>> {
+            Err(Error::CustomError("unreachable".to_owned()))
+        }
+    }
+
+    fn limits() -> Limits {
+        let mut limits = Settings::default().limits;
+        limits.events_per_min_per_ip = Some(2);
+        limits.events_per_min_per_pubkey = Some(2);
+        limits.max_connections_per_ip = Some(2);
+        limits.auth_attempts_per_min = Some(2);
+        limits.connection_limit_exempt_ips = vec!["10.0.0.1".to_owned()];
+        limits
+    }
+
+    #[tokio::test]
+    async fn windows_expire() -> Result<()> {
+        let store = MemoryStore::default();
+        let window = Duration::from_millis(50);
+        assert_eq!(store.hit("a", window).await?, 1);
+        assert_eq!(store.hit("a", window).await?, 2);
+        assert_eq!(store.hit("b", window).await?, 1);
+        tokio::time::sleep(window).await;
+        assert_eq!(store.hit("a", window).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_are_counted() -> Result<()> {
+        let store = MemoryStore::default();
+        assert_eq!(store.connect("a").await?, 1);
+        assert_eq!(store.connect("a").await?, 2);
+        store.disconnect("a").await?;
+        store.disconnect("a").await?;
+        assert!(store.connections.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_limited_per_ip() -> Result<()> {
+        let errors = IntCounter::new("errors", "errors").unwrap();
+        let limits = RateLimits::new(Arc::new(MemoryStore::default()), &limits(), errors);
+        let first = limits.open_connection("1.2.3.4").await.unwrap();
+        let _second = limits.open_connection("1.2.3.4").await.unwrap();
+        assert!(limits.open_connection("1.2.3.4").await.is_none());
+        let _other = limits.open_connection("5.6.7.8").await.unwrap();
+        let mut exempt = vec![];
+        for _ in 0..3 {
+            exempt.push(limits.open_connection("10.0.0.1").await.unwrap());
+        }
+        assert_eq!(
+            limits.connection_counts(2).await?,
+            vec![("10.0.0.1".to_owned(), 3), ("1.2.3.4".to_owned(), 2)]
+        );
+        // closing a connection makes room for another
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(limits.open_connection("1.2.3.4").await.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_limited_by_ip_and_pubkey() {
+        let errors = IntCounter::new("errors", "errors").unwrap();
+        let limits = RateLimits::new(Arc::new(MemoryStore::default()), &limits(), errors);
+        assert!(limits.allow_event("1.2.3.4", "aa").await);
+        assert!(limits.allow_event("1.2.3.4", "aa").await);
+        assert!(!limits.allow_event("1.2.3.4", "bb").await);
+        // another address, but the pubkey is already at its limit
+        assert!(!limits.allow_event("5.6.7.8", "aa").await);
+        assert!(limits.allow_event("5.6.7.8", "bb").await);
+    }
+
+    #[tokio::test]
+    async fn auth_attempts_limited_by_ip() {
+        let errors = IntCounter::new("errors", "errors").unwrap();
+        let limits = RateLimits::new(Arc::new(MemoryStore::default()), &limits(), errors);
+        assert!(limits.allow_auth("1.2.3.4").await);
+        assert!(limits.allow_auth("1.2.3.4").await);
+        assert!(!limits.allow_auth("1.2.3.4").await);
+        assert!(limits.allow_auth("5.6.7.8").await);
+        assert_eq!(limits.auth_failed("aa").await, 1);
+        assert_eq!(limits.auth_failed("aa").await, 2);
+        assert_eq!(limits.auth_failed("bb").await, 1);
+    }
+
+    #[test]
+    fn auth_failure_delays_escalate() {
+        let base = Duration::from_millis(250);
+        assert_eq!(auth_failure_delay(base, 1), base);
+        assert_eq!(auth_failure_delay(base, 2), base * 2);
+        assert_eq!(auth_failure_delay(base, 4), base * 8);
+        assert_eq!(auth_failure_delay(base, 1000), MAX_AUTH_FAILURE_DELAY);
+        assert_eq!(auth_failure_delay(Duration::ZERO, 5), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn store_errors_fail_open() {
+        let errors = IntCounter::new("errors", "errors").unwrap();
+        let limits = RateLimits::new(Arc::new(FailingStore), &limits(), errors.clone());
+        for _ in 0..5 {
+            assert!(limits.allow_event("1.2.3.4", "aa").await);
+        }
+        // uncounted, so nothing is decremented when it closes
+        drop(limits.open_connection("1.2.3.4").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(errors.get(), 11);
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_fails_open() {
+        let errors = IntCounter::new("errors", "errors").unwrap();
+        let store = RedisStore::new("redis://127.0.0.1:1/", "test").unwrap();
+        let limits = RateLimits::new(Arc::new(store), &limits(), errors.clone());
+        assert!(limits.allow_event("1.2.3.4", "aa").await);
+        assert!(errors.get() > 0);
+    }
+}
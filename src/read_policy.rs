@@ -0,0 +1,153 @@
+//! Per-kind authorization for reading events
+use crate::config::Settings;
+use crate::event::Event;
+
+/// Decides which events a client may receive, based on its NIP-42
+/// authenticated pubkey.  Applied to both stored and streamed events.
+pub trait ReadPolicy: Send + Sync {
+    /// Kinds that this policy may withhold.  Unauthenticated clients
+    /// requesting only these kinds get no matches.
+    fn restricted_kinds(&self) -> Vec<u64>;
+
+    /// Might this policy withhold any event?  If not, events are sent
+    /// without being checked.
+    fn withholds_events(&self) -> bool {
+        !self.restricted_kinds().is_empty()
+    }
+
+    /// May a client authenticated as `auth_pubkey` read this event?
+    fn can_read(&self, event: &Event, auth_pubkey: Option<&str>) -> bool;
+}
+
+/// Is the pubkey the author of the event, or its recipient (in the
+/// first `p` tag)?
+#[must_use]
+pub fn is_participant(event: &Event, pubkey: &str) -> bool {
+    event.pubkey == pubkey
+        || event
+            .tag_values_by_name("p")
+            .first()
+            .map_or(false, |recipient| recipient == pubkey)
+}
+
+/// Why an accepted event will not reach every subscriber, if it will
+/// not.  Sent to publishers when `options.verbose_notices` is set.
+#[must_use]
+pub fn delivery_note(event: &Event, policy: &dyn ReadPolicy) -> Option<String> {
+    if event.is_ephemeral() {
+        Some(format!(
+            "event {} was sent to current subscribers, but not stored (kind {} is ephemeral)",
+            event.id, event.kind
+        ))
+    } else if !policy.can_read(event, None) {
+        Some(format!(
+            "event {} was stored, but kind {} is only delivered to its author and recipient, after they authenticate",
+            event.id, event.kind
+        ))
+    } else {
+        None
+    }
+}
+
+/// Restricts configured kinds to their participants: the author, and
+/// the recipient in the first `p` tag.
+#[derive(Debug, Clone, Default)]
+pub struct ParticipantReadPolicy {
+    kinds: Vec<u64>,
+}
+
+impl ParticipantReadPolicy {
+    #[must_use]
+    pub fn new(kinds: Vec<u64>) -> Self {
+        ParticipantReadPolicy { kinds }
+    }
+
+    /// Build the policy from `authorization.restricted_read_kinds`,
+    /// when `nip42_dms` is enabled.
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Self {
+        let auth = &settings.authorization;
+        let kinds = if auth.nip42_dms {
+            auth.restricted_read_kinds.clone()
+        } else {
+            vec![]
+        };
+        ParticipantReadPolicy { kinds }
+    }
+}
+
+impl ReadPolicy for ParticipantReadPolicy {
+    fn restricted_kinds(&self) -> Vec<u64> {
+        self.kinds.clone()
+    }
+
+    fn can_read(&self, event: &Event, auth_pubkey: Option<&str>) -> bool {
+        if !self.kinds.contains(&event.kind) {
+            return true;
+        }
+        auth_pubkey.map_or(false, |pubkey| is_participant(event, pubkey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dm(author: &str, recipients: &[&str]) -> Event {
+        let mut event = Event::simple_event();
+        event.kind = 4;
+        event.pubkey = author.to_owned();
+        event.tags = recipients
+            .iter()
+            .map(|r| vec!["p".to_owned(), (*r).to_owned()])
+            .collect();
+        event
+    }
+
+    #[test]
+    fn participants_can_read() {
+        let policy = ParticipantReadPolicy::new(vec![4]);
+        let event = dm("alice", &["bob", "carol"]);
+        assert!(policy.can_read(&event, Some("alice")));
+        assert!(policy.can_read(&event, Some("bob")));
+        // only the first `p` tag is the recipient
+        assert!(!policy.can_read(&event, Some("carol")));
+        assert!(!policy.can_read(&event, Some("mallory")));
+        assert!(!policy.can_read(&event, None));
+    }
+
+    #[test]
+    fn other_kinds_are_public() {
+        let policy = ParticipantReadPolicy::new(vec![4]);
+        let mut event = dm("alice", &["bob"]);
+        event.kind = 1;
+        assert!(policy.can_read(&event, None));
+    }
+
+    #[test]
+    fn notes_for_limited_delivery() {
+        let policy = ParticipantReadPolicy::new(vec![4]);
+        let mut event = dm("alice", &["bob"]);
+        assert!(delivery_note(&event, &policy)
+            .unwrap()
+            .contains("only delivered to its author"));
+        event.kind = 20001;
+        assert!(delivery_note(&event, &policy)
+            .unwrap()
+            .contains("not stored"));
+        event.kind = 1;
+        assert_eq!(delivery_note(&event, &policy), None);
+    }
+
+    #[test]
+    fn kinds_from_settings() {
+        let mut settings = Settings::default();
+        settings.authorization.restricted_read_kinds = vec![4, 7];
+        assert!(!ParticipantReadPolicy::from_settings(&settings).withholds_events());
+        settings.authorization.nip42_dms = true;
+        assert_eq!(
+            ParticipantReadPolicy::from_settings(&settings).restricted_kinds(),
+            vec![4, 7]
+        );
+    }
+}
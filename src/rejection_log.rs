@@ -0,0 +1,228 @@
+//! A separate log of rejected events, for abuse analysis
+//!
+//! Every event answered with `OK false` is recorded as one line of JSON
+//! (time, client IP, event id and reason) in the file given by
+//! `logging.rejection_log`.  Records are queued for a background
+//! writer, and dropped (and counted) when the queue is full or more
+//! than `rejection_log_per_minute` arrive.  The file is rotated once it
+//! reaches `rejection_log_max_mb`, keeping one previous file, so a
+//! flood of rejections cannot fill the disk.
+use crate::config::Logging;
+use crate::error::Result;
+use crate::notice::Notice;
+use crate::utils::unix_time;
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use prometheus::IntCounter;
+use serde::Serialize;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Records waiting for the writer, beyond which they are dropped
+pub const QUEUE_SIZE: usize = 1024;
+
+/// One rejected event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    pub time: u64,
+    pub ip: String,
+    pub id: String,
+    /// The `OK` message, prefixed with the machine-readable reason
+    pub reason: String,
+}
+
+/// Queues rejections for the writer.  Clones share the queue; the
+/// default records nothing.
+#[derive(Clone, Default)]
+pub struct RejectionLog {
+    tx: Option<mpsc::Sender<Rejection>>,
+    limiter: Option<Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>>,
+    dropped: Option<IntCounter>,
+}
+
+impl RejectionLog {
+    /// Open the rejection log, if configured.  The returned writer must
+    /// be run for records to be written.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the log file could not be opened.
+    pub fn open(
+        settings: &Logging,
+        dropped: IntCounter,
+    ) -> Result<(RejectionLog, Option<RejectionWriter>)> {
+        let Some(path) = &settings.rejection_log else {
+            return Ok((RejectionLog::default(), None));
+        };
+        let path = PathBuf::from(path);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let written = file.metadata()?.len();
+        info!("logging rejected events to {:?}", path);
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let limiter = NonZeroU32::new(settings.rejection_log_per_minute)
+            .map(|quota| Arc::new(RateLimiter::direct(Quota::per_minute(quota))));
+        let log = RejectionLog {
+            tx: Some(tx),
+            limiter,
+            dropped: Some(dropped.clone()),
+        };
+        let writer = RejectionWriter {
+            rx,
+            file: File::from_std(file),
+            path,
+            written,
+            max_bytes: settings.rejection_log_max_mb * 1024 * 1024,
+            dropped,
+        };
+        Ok((log, Some(writer)))
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Record a notice sent to a client at `ip`, if it rejects an
+    /// event.  Never waits on the writer.
+    pub fn observe(&self, ip: &str, notice: &Notice) {
+        let (Some(tx), Notice::EventResult(result)) = (&self.tx, notice) else {
+            return;
+        };
+        if result.status.to_bool() {
+            return;
+        }
+        let limited = self.limiter.as_ref().map_or(false, |l| l.check().is_err());
+        let queued = !limited
+            && tx
+                .try_send(Rejection {
+                    time: unix_time(),
+                    ip: ip.to_owned(),
+                    id: result.id.clone(),
+                    reason: result.msg.clone(),
+                })
+                .is_ok();
+        if !queued {
+            if let Some(dropped) = &self.dropped {
+                dropped.inc();
+            }
+        }
+    }
+}
+
+/// Appends queued rejections to the log file
+pub struct RejectionWriter {
+    rx: mpsc::Receiver<Rejection>,
+    file: File,
+    path: PathBuf,
+    written: u64,
+    max_bytes: u64,
+    dropped: IntCounter,
+}
+
+impl RejectionWriter {
+    /// The log file is renamed to this once it is full, replacing the
+    /// previous one.
+    fn rotated_path(&self) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        rotated.into()
+    }
+
+    /// Append one record, rotating the file first if it would grow
+    /// past `max_bytes`.
+    async fn write(&mut self, rejection: &Rejection) -> Result<()> {
+        let mut line = serde_json::to_vec(rejection)?;
+        line.push(b'\n');
+        let len = line.len() as u64;
+        if self.max_bytes > 0 && self.written > 0 && self.written + len > self.max_bytes {
+            self.file.flush().await?;
+            fs::rename(&self.path, self.rotated_path()).await?;
+            self.file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            self.written = 0;
+        }
+        self.file.write_all(&line).await?;
+        self.written += len;
+        Ok(())
+    }
+
+    /// Write records until every `RejectionLog` is gone.
+    pub async fn run(mut self) {
+        while let Some(rejection) = self.rx.recv().await {
+            if let Err(e) = self.write(&rejection).await {
+                warn!("could not write to the rejection log: {:?}", e);
+                self.dropped.inc();
+            }
+        }
+        self.file.flush().await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RejectReason;
+
+    fn settings(path: &std::path::Path) -> Logging {
+        let mut logging = crate::config::Settings::default().logging;
+        logging.rejection_log = Some(path.to_string_lossy().into_owned());
+        logging.rejection_log_per_minute = 3;
+        logging
+    }
+
+    #[tokio::test]
+    async fn rejections_are_logged_and_bounded() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("rejections-{}.log", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let dropped = IntCounter::new("dropped", "dropped").unwrap();
+        let (log, writer) = RejectionLog::open(&settings(&path), dropped.clone())?;
+        let mut writer = writer.unwrap();
+        // accepted events are not recorded
+        log.observe("10.0.0.1", &Notice::saved("aa".to_owned()));
+        for id in ["bb", "cc", "dd", "ee"] {
+            let notice = Notice::rejected(id.to_owned(), RejectReason::Blocked("go away".into()));
+            log.observe("10.0.0.1", &notice);
+        }
+        // the fourth rejection is over the rate limit
+        assert_eq!(dropped.get(), 1);
+        drop(log);
+        let mut ids = vec![];
+        while let Some(rejection) = writer.rx.recv().await {
+            assert_eq!(rejection.ip, "10.0.0.1");
+            assert_eq!(rejection.reason, "blocked: go away");
+            ids.push(rejection.id.clone());
+            writer.write(&rejection).await?;
+        }
+        assert_eq!(ids, vec!["bb", "cc", "dd"]);
+        writer.file.flush().await?;
+        let contents = std::fs::read_to_string(&path)?;
+        assert_eq!(contents.lines().count(), 3);
+        // a full file is rotated
+        writer.max_bytes = writer.written + 10;
+        let rejection = Rejection {
+            time: 0,
+            ip: "10.0.0.2".to_owned(),
+            id: "ff".to_owned(),
+            reason: "invalid: bad".to_owned(),
+        };
+        writer.write(&rejection).await?;
+        writer.file.flush().await?;
+        assert_eq!(std::fs::read_to_string(writer.rotated_path())?, contents);
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 1);
+        std::fs::remove_file(writer.rotated_path()).ok();
+        std::fs::remove_file(&path).ok();
+        Ok(())
+    }
+}
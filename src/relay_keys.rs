@@ -0,0 +1,252 @@
+//! The relay's own keypair, for signing relay-authored events
+use crate::config::Info;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use nostr::key::{FromPkStr, FromSkStr, Keys};
+use nostr::prelude::Kind;
+use nostr::EventBuilder;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+/// Keys the relay signs its own events with.  The secret key is never
+/// printed; `Debug` only shows the public key.
+#[derive(Clone)]
+pub struct RelayKeys {
+    keys: Keys,
+}
+
+impl fmt::Debug for RelayKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayKeys")
+            .field("pubkey", &self.public_key_hex())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A message from the operator to connected clients.  Authenticated
+/// clients also get the event signed by the relay, if there is one, so
+/// they can verify where it came from.
+#[derive(Debug, Clone)]
+pub struct RelayNotice {
+    pub message: String,
+    pub event: Option<Event>,
+    /// Which clients the notice is sent to
+    pub target: NoticeTarget,
+    /// Clients are sent the notice at random times within this long,
+    /// rather than all at once
+    pub spread: Duration,
+}
+
+/// Which connected clients an operator notice is sent to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NoticeTarget {
+    #[default]
+    All,
+    /// Clients authenticated as an admitted (paid) pubkey
+    Admitted,
+    /// Every other client, including those not authenticated
+    Unadmitted,
+    /// Clients authenticated as one of these pubkeys
+    Pubkeys(HashSet<String>),
+}
+
+impl NoticeTarget {
+    /// Parse a target name: `all`, `admitted` or `unadmitted`.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(NoticeTarget::All),
+            "admitted" => Some(NoticeTarget::Admitted),
+            "unadmitted" => Some(NoticeTarget::Unadmitted),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoticeTarget::All => "all",
+            NoticeTarget::Admitted => "admitted",
+            NoticeTarget::Unadmitted => "unadmitted",
+            NoticeTarget::Pubkeys(_) => "pubkeys",
+        }
+    }
+
+    /// Does reaching a client depend on whether its pubkey was
+    /// admitted?
+    #[must_use]
+    pub fn needs_admission(&self) -> bool {
+        matches!(self, NoticeTarget::Admitted | NoticeTarget::Unadmitted)
+    }
+
+    /// Is a client, authenticated as `pubkey` (if at all), sent the
+    /// notice?  `admitted` is whether that pubkey was admitted.
+    #[must_use]
+    pub fn reaches(&self, pubkey: Option<&str>, admitted: bool) -> bool {
+        match self {
+            NoticeTarget::All => true,
+            NoticeTarget::Admitted => pubkey.is_some() && admitted,
+            NoticeTarget::Unadmitted => pubkey.is_none() || !admitted,
+            NoticeTarget::Pubkeys(pubkeys) => pubkey.map_or(false, |p| pubkeys.contains(p)),
+        }
+    }
+}
+
+impl RelayKeys {
+    /// Load the relay keys from `info.relay_secret_key`, or the file
+    /// named by `info.relay_secret_key_file`.  Returns `None` if
+    /// neither is set.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the key can not be read or parsed, or if
+    /// `info.pubkey` is set and belongs to a different key.
+    pub fn load(info: &Info) -> Result<Option<RelayKeys>> {
+        let secret = match (&info.relay_secret_key, &info.relay_secret_key_file) {
+            (Some(_), Some(_)) => {
+                return Err(Error::CustomError(
+                    "only one of relay_secret_key and relay_secret_key_file may be set".to_owned(),
+                ))
+            }
+            (Some(secret), None) => secret.expose().to_owned(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|e| {
+                    Error::CustomError(format!("could not read relay secret key file {path}: {e}"))
+                })?
+                .trim()
+                .to_owned(),
+            (None, None) => return Ok(None),
+        };
+        // the parse error could echo the input, so it is not included
+        let keys = Keys::from_sk_str(&secret)
+            .map_err(|_| Error::CustomError("relay secret key is not valid".to_owned()))?;
+        let relay_keys = RelayKeys { keys };
+        if let Some(pubkey) = &info.pubkey {
+            let expected = Keys::from_pk_str(pubkey)
+                .map(|k| k.public_key().to_string())
+                .unwrap_or_else(|_| pubkey.clone());
+            if expected != relay_keys.public_key_hex() {
+                return Err(Error::CustomError(format!(
+                    "relay secret key belongs to {}, not info.pubkey ({pubkey})",
+                    relay_keys.public_key_hex()
+                )));
+            }
+        }
+        Ok(Some(relay_keys))
+    }
+
+    /// Keys for signing events
+    #[must_use]
+    pub fn keys(&self) -> &Keys {
+        &self.keys
+    }
+
+    /// Relay public key, in hex
+    #[must_use]
+    pub fn public_key_hex(&self) -> String {
+        self.keys.public_key().to_string()
+    }
+
+    /// Sign an operator notice, as a text note from the relay, for
+    /// every client.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event could not be signed.
+    pub fn sign_notice(&self, message: &str) -> Result<RelayNotice> {
+        let nostr_event = EventBuilder::new(Kind::TextNote, message, &[]).to_event(&self.keys)?;
+        Ok(RelayNotice {
+            message: message.to_owned(),
+            event: Some(Event::from(nostr_event)),
+            target: NoticeTarget::All,
+            spread: Duration::ZERO,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    #[test]
+    fn keys_are_optional() -> Result<()> {
+        assert!(RelayKeys::load(&Settings::default().info)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn key_must_match_pubkey() -> Result<()> {
+        let keys = Keys::generate();
+        let secret = keys.secret_key()?.display_secret().to_string();
+        let mut info = Settings::default().info;
+        info.relay_secret_key = Some(secret.clone().into());
+        info.pubkey = Some(keys.public_key().to_string());
+        let relay_keys = RelayKeys::load(&info)?.unwrap();
+        assert_eq!(relay_keys.public_key_hex(), keys.public_key().to_string());
+        assert!(!format!("{relay_keys:?}").contains(&secret));
+        info.pubkey = Some(Keys::generate().public_key().to_string());
+        assert!(RelayKeys::load(&info).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn key_from_file() -> Result<()> {
+        let keys = Keys::generate();
+        let path = std::env::temp_dir().join(format!("relay-key-{}", std::process::id()));
+        let secret = keys.secret_key()?.display_secret().to_string();
+        std::fs::write(&path, format!("{secret}\n"))?;
+        let mut info = Settings::default().info;
+        info.relay_secret_key_file = Some(path.to_string_lossy().into_owned());
+        let loaded = RelayKeys::load(&info);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(
+            loaded?.unwrap().public_key_hex(),
+            keys.public_key().to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_key_is_not_echoed() {
+        let mut info = Settings::default().info;
+        info.relay_secret_key = Some("not-a-secret-key".to_owned().into());
+        let err = RelayKeys::load(&info).unwrap_err();
+        assert!(!format!("{err:?}").contains("not-a-secret-key"));
+    }
+
+    #[test]
+    fn notice_is_signed_by_relay() -> Result<()> {
+        let keys = Keys::generate();
+        let relay_keys = RelayKeys { keys: keys.clone() };
+        let notice = relay_keys.sign_notice("maintenance at 12:00 UTC")?;
+        let event = notice.event.unwrap();
+        event.validate()?;
+        assert_eq!(event.pubkey, keys.public_key().to_string());
+        assert_eq!(event.content, notice.message);
+        Ok(())
+    }
+
+    #[test]
+    fn notices_reach_their_target() {
+        let alice = Some("alice");
+        assert!(NoticeTarget::All.reaches(None, false));
+        assert!(NoticeTarget::Admitted.reaches(alice, true));
+        assert!(!NoticeTarget::Admitted.reaches(alice, false));
+        assert!(!NoticeTarget::Admitted.reaches(None, false));
+        assert!(NoticeTarget::Unadmitted.reaches(alice, false));
+        assert!(NoticeTarget::Unadmitted.reaches(None, false));
+        assert!(!NoticeTarget::Unadmitted.reaches(alice, true));
+        let pubkeys = NoticeTarget::Pubkeys(HashSet::from(["alice".to_owned()]));
+        assert!(pubkeys.reaches(alice, false));
+        assert!(!pubkeys.reaches(Some("bob"), true));
+        assert!(!pubkeys.reaches(None, false));
+        assert!(!pubkeys.needs_admission());
+        assert_eq!(
+            NoticeTarget::from_name("admitted"),
+            Some(NoticeTarget::Admitted)
+        );
+        assert_eq!(NoticeTarget::from_name("pubkeys"), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Relay identity proofs with `PROOF` messages
+//!
+//! A client that wants to know it is talking to the relay named in the
+//! relay information document (NIP-11) sends `["PROOF", <challenge>]`.
+//! The relay answers with an event signed by its own keys (the ones it
+//! signs notices with), tagged with the challenge and its URL.  Proofs
+//! use their own kind, so one can never be replayed as a NIP-42 `AUTH`
+//! event.
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::relay_keys::RelayKeys;
+use crate::utils::unix_time;
+use nostr::prelude::{Kind, Tag, TagKind};
+use nostr::EventBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Kind of relay identity proofs; one more than NIP-42's 22242
+pub const RELAY_PROOF_KIND: u64 = 22243;
+
+/// Longest challenge the relay will sign, in characters
+pub const MAX_CHALLENGE_LEN: usize = 256;
+
+/// The only command this message accepts, so that other two-element
+/// messages (such as `CLOSE`) are never mistaken for it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+enum ProofTag {
+    #[serde(rename = "PROOF")]
+    Proof,
+}
+
+/// Relay identity proof request in network format
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ProofCmd {
+    /// Protocol command, always "PROOF".
+    cmd: ProofTag,
+    /// Challenge chosen by the client, to be signed by the relay.
+    challenge: String,
+}
+
+impl ProofCmd {
+    /// The challenge, if it is one the relay will sign: not empty, and
+    /// at most [`MAX_CHALLENGE_LEN`] characters.
+    #[must_use]
+    pub fn challenge(&self) -> Option<&str> {
+        let len = self.challenge.chars().count();
+        (len > 0 && len <= MAX_CHALLENGE_LEN).then_some(self.challenge.as_str())
+    }
+}
+
+/// Sign a proof of the relay's identity for a client's challenge.
+///
+/// # Errors
+///
+/// Will return `Err` if the event could not be signed.
+pub fn sign_proof(
+    relay_keys: &RelayKeys,
+    challenge: &str,
+    relay_url: Option<&str>,
+) -> Result<Event> {
+    let mut tags = vec![Tag::Generic(
+        TagKind::Custom("challenge".to_owned()),
+        vec![challenge.to_owned()],
+    )];
+    if let Some(url) = relay_url {
+        tags.push(Tag::Generic(
+            TagKind::Custom("relay".to_owned()),
+            vec![url.to_owned()],
+        ));
+    }
+    let nostr_event =
+        EventBuilder::new(Kind::Custom(RELAY_PROOF_KIND), "", &tags).to_event(relay_keys.keys())?;
+    Ok(Event::from(nostr_event))
+}
+
+/// Response to a `PROOF` request
+///
+/// # Errors
+///
+/// Will return `Err` if the proof could not be signed.
+pub fn proof_response(
+    relay_keys: &RelayKeys,
+    challenge: &str,
+    relay_url: Option<&str>,
+) -> Result<Value> {
+    let event = sign_proof(relay_keys, challenge, relay_url)?;
+    Ok(json!(["PROOF", event]))
+}
+
+/// First value of the first tag with this name
+fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event
+        .tags
+        .iter()
+        .find(|t| t.first().map(String::as_str) == Some(name))
+        .and_then(|t| t.get(1))
+        .map(String::as_str)
+}
+
+/// Check a proof the way a client should: it is validly signed by
+/// `pubkey` (the relay's NIP-11 pubkey, in hex), has the proof kind,
+/// answers `challenge`, names `relay_url` if one is given, and was
+/// created within `max_age_secs` of now.
+///
+/// # Errors
+///
+/// Will return `Err` describing the first check that failed.
+pub fn verify_proof(
+    event: &Event,
+    pubkey: &str,
+    challenge: &str,
+    relay_url: Option<&str>,
+    max_age_secs: u64,
+) -> Result<()> {
+    event.validate()?;
+    let fail = |msg: &str| Err(Error::CustomError(format!("invalid relay proof: {msg}")));
+    if event.pubkey != pubkey {
+        return fail("signed by another pubkey");
+    }
+    if event.kind != RELAY_PROOF_KIND {
+        return fail("wrong kind");
+    }
+    if tag_value(event, "challenge") != Some(challenge) {
+        return fail("challenge does not match");
+    }
+    if relay_url.is_some() && tag_value(event, "relay") != relay_url {
+        return fail("relay does not match");
+    }
+    if unix_time().abs_diff(event.created_at) > max_age_secs {
+        return fail("not recent");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use nostr::key::Keys;
+
+    fn relay_keys() -> RelayKeys {
+        let keys = Keys::generate();
+        let mut info = Settings::default().info;
+        info.relay_secret_key = Some(
+            keys.secret_key()
+                .unwrap()
+                .display_secret()
+                .to_string()
+                .into(),
+        );
+        RelayKeys::load(&info).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_proof_request() {
+        let pc: ProofCmd = serde_json::from_str(r#"["PROOF", "abc"]"#).unwrap();
+        assert_eq!(pc.challenge(), Some("abc"));
+        assert!(serde_json::from_str::<ProofCmd>(r#"["CLOSE", "abc"]"#).is_err());
+        let empty: ProofCmd = serde_json::from_str(r#"["PROOF", ""]"#).unwrap();
+        assert!(empty.challenge().is_none());
+        let long = format!(r#"["PROOF", "{}"]"#, "a".repeat(MAX_CHALLENGE_LEN + 1));
+        let long: ProofCmd = serde_json::from_str(&long).unwrap();
+        assert!(long.challenge().is_none());
+    }
+
+    #[test]
+    fn proof_is_verified() -> Result<()> {
+        let keys = relay_keys();
+        let pubkey = keys.public_key_hex();
+        let url = Some("wss://relay.example.com");
+        let proof = sign_proof(&keys, "abc", url)?;
+        verify_proof(&proof, &pubkey, "abc", url, 60)?;
+        assert!(verify_proof(&proof, &pubkey, "abd", url, 60).is_err());
+        assert!(verify_proof(&proof, &pubkey, "abc", Some("wss://other.example.com"), 60).is_err());
+        let other = relay_keys().public_key_hex();
+        assert!(verify_proof(&proof, &other, "abc", url, 60).is_err());
+        Ok(())
+    }
+}
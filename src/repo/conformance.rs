@@ -0,0 +1,378 @@
+//! Conformance checks for storage backends
+//!
+//! These exercise the behaviour of a [`NostrRepo`] that the relay
+//! depends on: writing and querying events, replaceable events and
+//! deletions, NIP-05 verification records, pay-to-relay accounts,
+//! invoices and the ledger, and subscription watermarks.  A backend
+//! passes if [`check_repo`] returns `Ok` for a new (migrated, empty)
+//! repository; a check that fails panics, naming what was expected.
+//! Backends in other crates can run them from their own tests, with
+//! the `test-support` feature enabled.
+use super::{NostrRepo, WriteResult};
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::payment::{InvoiceInfo, InvoiceStatus, LedgerReason};
+use crate::subscription::Subscription;
+use crate::utils::unix_time;
+use nostr::Keys;
+use serde_json::{json, Value};
+
+/// Authors of the events written by the checks
+const ALICE: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+const BOB: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+const CAROL: &str = "cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc";
+
+/// Run every check against a new, empty repository.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository returns one.
+///
+/// # Panics
+///
+/// Will panic if the repository does not behave as the relay expects.
+pub async fn check_repo(repo: &dyn NostrRepo) -> Result<()> {
+    check_events(repo).await?;
+    check_verification(repo).await?;
+    check_accounts(repo).await?;
+    check_watermarks(repo).await
+}
+
+/// An unsigned event; backends are not expected to check signatures.
+fn event(id: u64, pubkey: &str, kind: u64, created_at: u64, tags: &[&[&str]]) -> Event {
+    let mut event = Event {
+        id: format!("{id:064x}"),
+        pubkey: pubkey.to_owned(),
+        delegated_by: None,
+        created_at,
+        kind,
+        tags: tags
+            .iter()
+            .map(|tag| tag.iter().map(|v| (*v).to_owned()).collect())
+            .collect(),
+        content: String::new(),
+        sig: "0".to_owned(),
+        tagidx: None,
+    };
+    event.build_index();
+    event
+}
+
+/// Ids of events, sorted
+fn ids(events: &[&Event]) -> Vec<String> {
+    let mut ids: Vec<String> = events.iter().map(|e| e.id.clone()).collect();
+    ids.sort();
+    ids
+}
+
+/// Ids of the stored events matching a filter, sorted.
+async fn query(repo: &dyn NostrRepo, filter: Value) -> Result<Vec<String>> {
+    let sub: Subscription = serde_json::from_value(json!(["REQ", "conformance", filter]))?;
+    let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(16);
+    let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+    repo.query_subscription(sub, "conformance".to_owned(), query_tx, abandon_rx)
+        .await?;
+    let mut ids = vec![];
+    loop {
+        let result = query_rx
+            .recv()
+            .await
+            .expect("query ended without an EOSE result");
+        if result.event == "EOSE" {
+            break;
+        }
+        ids.push(serde_json::from_str::<Event>(&result.event)?.id);
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Writing and querying events, replaceable events and deletions.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository returns one.
+///
+/// # Panics
+///
+/// Will panic if the repository does not behave as the relay expects.
+pub async fn check_events(repo: &dyn NostrRepo) -> Result<()> {
+    let first = event(1, ALICE, 1, 1000, &[&["t", "conformance"]]);
+    let second = event(2, ALICE, 1, 2000, &[]);
+    let reaction = event(3, BOB, 7, 1500, &[&["e", &first.id]]);
+    for e in [&first, &second, &reaction] {
+        assert_eq!(
+            repo.write_event(e).await?,
+            WriteResult::Added(1),
+            "new event"
+        );
+    }
+    assert_eq!(
+        repo.write_event(&first).await?.rows_added(),
+        0,
+        "duplicate event"
+    );
+    assert_eq!(
+        query(repo, json!({"ids": [first.id]})).await?,
+        ids(&[&first])
+    );
+    assert_eq!(
+        query(repo, json!({"kinds": [1], "authors": [ALICE]})).await?,
+        ids(&[&first, &second])
+    );
+    assert_eq!(
+        query(repo, json!({"authors": [BOB]})).await?,
+        ids(&[&reaction])
+    );
+    assert_eq!(
+        query(repo, json!({"#t": ["conformance"]})).await?,
+        ids(&[&first]),
+        "generic tag filter"
+    );
+    assert_eq!(
+        query(repo, json!({"#e": [first.id]})).await?,
+        ids(&[&reaction]),
+        "event reference filter"
+    );
+    assert_eq!(
+        query(repo, json!({"kinds": [1], "since": 1500})).await?,
+        ids(&[&second])
+    );
+    assert_eq!(
+        query(repo, json!({"kinds": [1], "until": 1500})).await?,
+        ids(&[&first])
+    );
+    assert_eq!(
+        query(repo, json!({"kinds": [1], "limit": 1})).await?,
+        ids(&[&second]),
+        "limit keeps the newest events"
+    );
+
+    // replaceable events keep only the newest version
+    let old_profile = event(4, ALICE, 0, 1000, &[]);
+    let profile = event(5, ALICE, 0, 2000, &[]);
+    let stale_profile = event(6, ALICE, 0, 1500, &[]);
+    repo.write_event(&old_profile).await?;
+    repo.write_event(&profile).await?;
+    assert_eq!(
+        query(repo, json!({"kinds": [0], "authors": [ALICE]})).await?,
+        ids(&[&profile]),
+        "replaced event"
+    );
+    match repo.write_event(&stale_profile).await? {
+        WriteResult::Superseded(newer) => assert_eq!(newer.id, profile.id),
+        other => panic!("older replaceable event was not superseded: {other:?}"),
+    }
+
+    // parameterized replaceable events, per d tag
+    let old_list = event(7, ALICE, 30000, 1000, &[&["d", "friends"]]);
+    let list = event(8, ALICE, 30000, 2000, &[&["d", "friends"]]);
+    let stale_list = event(9, ALICE, 30000, 1500, &[&["d", "friends"]]);
+    repo.write_event(&old_list).await?;
+    repo.write_event(&list).await?;
+    assert_eq!(
+        query(repo, json!({"kinds": [30000], "#d": ["friends"]})).await?,
+        ids(&[&list]),
+        "replaced parameterized event"
+    );
+    match repo.write_event(&stale_list).await? {
+        WriteResult::Superseded(newer) => assert_eq!(newer.id, list.id),
+        other => panic!("older parameterized event was not superseded: {other:?}"),
+    }
+
+    // deletions hide events from the same author only
+    let doomed = event(10, ALICE, 1, 3000, &[]);
+    let deletion = event(11, ALICE, 5, 3001, &[&["e", &doomed.id]]);
+    let forged = event(12, BOB, 5, 3002, &[&["e", &second.id]]);
+    repo.write_event(&doomed).await?;
+    assert_eq!(repo.write_event(&deletion).await?, WriteResult::Added(1));
+    repo.write_event(&forged).await?;
+    assert!(
+        query(repo, json!({"ids": [doomed.id]})).await?.is_empty(),
+        "deleted event was returned"
+    );
+    assert_eq!(
+        query(repo, json!({"ids": [second.id]})).await?,
+        ids(&[&second]),
+        "deletion by another author"
+    );
+    assert_eq!(
+        repo.write_event(&doomed).await?.rows_added(),
+        0,
+        "deleted event written again"
+    );
+    // the notes, reaction, profile, list and both deletions
+    assert_eq!(repo.count_events().await?, 7, "visible events");
+    let by_kind = repo.count_by_kind().await?;
+    assert_eq!(
+        by_kind.iter().map(|(_, n)| n).sum::<u64>(),
+        7,
+        "visible events by kind"
+    );
+    Ok(())
+}
+
+/// NIP-05 verification records.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository returns one.
+///
+/// # Panics
+///
+/// Will panic if the repository does not behave as the relay expects.
+pub async fn check_verification(repo: &dyn NostrRepo) -> Result<()> {
+    let metadata = event(20, CAROL, 0, 1000, &[]);
+    repo.write_event(&metadata).await?;
+    repo.create_verification_record(&metadata.id, "carol@example.com")
+        .await?;
+    let record = repo.get_latest_user_verification(CAROL).await?;
+    assert_eq!(record.name.to_string(), "carol@example.com");
+    assert_eq!(record.address, CAROL);
+    assert_eq!(record.event, metadata.id);
+    assert_eq!(record.event_created, 1000);
+    assert_eq!(record.failure_count, 0);
+    repo.fail_verification(record.rowid).await?;
+    let failed = repo.get_latest_user_verification(CAROL).await?;
+    assert_eq!(failed.failure_count, 1);
+    assert!(failed.last_failure.is_some());
+    repo.update_verification_timestamp(record.rowid).await?;
+    let verified = repo.get_latest_user_verification(CAROL).await?;
+    assert_eq!(verified.failure_count, 0, "success resets failures");
+    assert!(verified.last_success.is_some());
+    repo.delete_verification(record.rowid).await?;
+    assert!(
+        matches!(
+            repo.get_latest_user_verification(CAROL).await,
+            Err(Error::NotFound)
+        ),
+        "deleted verification record"
+    );
+    assert!(
+        matches!(
+            repo.get_oldest_user_verification(unix_time()).await,
+            Err(Error::NotFound)
+        ),
+        "no verification records"
+    );
+    Ok(())
+}
+
+/// Pay-to-relay accounts, invoices and the ledger.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository returns one.
+///
+/// # Panics
+///
+/// Will panic if the repository does not behave as the relay expects.
+pub async fn check_accounts(repo: &dyn NostrRepo) -> Result<()> {
+    let keys = Keys::generate();
+    let pubkey = keys.public_key().to_string();
+    assert!(
+        matches!(repo.get_account_balance(&keys).await, Err(Error::NotFound)),
+        "no account"
+    );
+    assert!(repo.create_account(&keys).await?, "new account");
+    assert!(!repo.create_account(&keys).await?, "existing account");
+    assert_eq!(repo.get_account_balance(&keys).await?, (false, 0));
+    repo.update_account_balance(&keys, true, 1000, LedgerReason::Admin, None)
+        .await?;
+    repo.admit_account(&keys, 100, Some("admission")).await?;
+    repo.update_account_balance(&keys, false, 50, LedgerReason::Publication, None)
+        .await?;
+    assert_eq!(repo.get_account_balance(&keys).await?, (true, 850));
+    let ledger = repo.get_ledger(&keys, None).await?;
+    let changes: Vec<(i64, i64, LedgerReason)> = ledger
+        .iter()
+        .map(|entry| (entry.delta, entry.balance, entry.reason))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            (-50, 850, LedgerReason::Publication),
+            (-100, 900, LedgerReason::Admission),
+            (1000, 1000, LedgerReason::Admin),
+        ],
+        "ledger, newest first"
+    );
+    assert_eq!(ledger[1].reference.as_deref(), Some("admission"));
+    assert_eq!(repo.get_ledger(&keys, Some(1)).await?.len(), 1);
+    assert_eq!(repo.published_event_count(&keys).await?, 0);
+    assert_eq!(repo.count_published_event(&keys).await?, 1);
+    assert_eq!(repo.published_event_count(&keys).await?, 1);
+
+    // paying an invoice credits the account
+    let payment_hash = "ef".repeat(32);
+    let invoice = InvoiceInfo {
+        pubkey: pubkey.clone(),
+        payment_hash: payment_hash.clone(),
+        bolt11: "lnbc5u1conformance".to_owned(),
+        amount: 500,
+        status: InvoiceStatus::Unpaid,
+        memo: "conformance".to_owned(),
+        confirmed_at: None,
+    };
+    repo.create_invoice_record(&keys, invoice).await?;
+    let unpaid = repo
+        .get_unpaid_invoice(&keys)
+        .await?
+        .expect("unpaid invoice");
+    assert_eq!(unpaid.payment_hash, payment_hash);
+    assert_eq!(unpaid.amount, 500);
+    let created_at = repo.confirm_invoice(&payment_hash).await?;
+    assert!(created_at.map_or(false, |t| t.abs_diff(unix_time()) < 60));
+    // a payment is only confirmed once
+    assert_eq!(repo.confirm_invoice(&payment_hash).await?, None);
+    assert_eq!(
+        repo.update_invoice(&payment_hash, InvoiceStatus::Paid)
+            .await?,
+        pubkey
+    );
+    assert_eq!(repo.get_account_balance(&keys).await?, (true, 1350));
+    assert!(repo.get_unpaid_invoice(&keys).await?.is_none());
+    assert_eq!(repo.confirm_invoice(&payment_hash).await?, None);
+    let payment = &repo.get_ledger(&keys, Some(1)).await?[0];
+    assert_eq!(payment.delta, 500);
+    assert_eq!(payment.reason, LedgerReason::Payment);
+    assert_eq!(payment.reference.as_deref(), Some(payment_hash.as_str()));
+    assert!(repo.verify_ledger().await?.is_empty(), "ledger mismatch");
+    Ok(())
+}
+
+/// Watermarks of delivered subscription events.
+///
+/// # Errors
+///
+/// Will return `Err` if the repository returns one.
+///
+/// # Panics
+///
+/// Will panic if the repository does not behave as the relay expects.
+pub async fn check_watermarks(repo: &dyn NostrRepo) -> Result<()> {
+    assert_eq!(repo.get_watermark(ALICE, "feed").await?, None);
+    repo.save_watermark(ALICE, "feed", 1000).await?;
+    repo.save_watermark(ALICE, "feed", 2000).await?;
+    assert_eq!(repo.get_watermark(ALICE, "feed").await?, Some(2000));
+    assert_eq!(repo.get_watermark(BOB, "feed").await?, None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::repo::sqlite::SqliteRepo;
+
+    #[tokio::test]
+    async fn sqlite_conforms() -> Result<()> {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        settings.database.data_directory = "conformance-test".to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        check_repo(&repo).await
+    }
+}
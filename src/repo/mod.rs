@@ -1,19 +1,82 @@
+//! Storage for events, accounts and verification records
+//!
+//! The relay reaches its database only through the [`NostrRepo`]
+//! trait, which SQLite ([`sqlite`]), PostgreSQL ([`postgres`]) and
+//! sharding over several databases ([`sharded`]) implement.  Another
+//! store (LMDB or redb, for instance) can be used without changes to
+//! the server, by implementing the trait and returning it from
+//! [`crate::hooks::LifecycleHooks::repo`].  The maintenance
+//! subcommands (`verify`, `compact`, `bulkloader` and the like) work
+//! on the SQL databases directly, and are not available for other
+//! backends.
+//!
+//! The `conformance` module, built for tests and with the
+//! `test-support` feature, checks the behaviour the relay depends on,
+//! and can be run against any implementation.
 use crate::db::QueryResult;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::event::Event;
 use crate::nip05::VerificationRecord;
-use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::subscription::Subscription;
+use crate::payment::{
+    InvoiceInfo, InvoiceStatus, LedgerEntry, LedgerMismatch, LedgerReason, PaymentStats,
+};
+use crate::quarantine::{PubkeyStatus, QuarantinedPubkey};
+use crate::server::NostrMetrics;
+use crate::source::SourceCount;
+use crate::stats::{EventStat, TopWriter};
+use crate::subscription::{ReqFilter, Subscription};
+use crate::user_data::{UserDataCounts, UserRecord};
 use crate::utils::unix_time;
 use async_trait::async_trait;
+use chrono::NaiveDate;
 use nostr::Keys;
 use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
 
+#[cfg(any(test, feature = "test-support"))]
+pub mod conformance;
 pub mod postgres;
 pub mod postgres_migration;
+pub mod postgres_partition;
+pub mod sharded;
 pub mod sqlite;
 pub mod sqlite_migration;
 
+/// Outcome of persisting an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteResult {
+    /// Rows added; none for a duplicate or deleted event
+    Added(u64),
+    /// A (parameterized) replaceable event was not stored, because the
+    /// version held here supersedes it
+    Superseded(Box<Event>),
+}
+
+impl WriteResult {
+    /// Rows added by the write
+    #[must_use]
+    pub fn rows_added(&self) -> u64 {
+        match self {
+            Self::Added(count) => *count,
+            Self::Superseded(_) => 0,
+        }
+    }
+}
+
+/// Storage backend for the relay.
+///
+/// Events are identified by their hex `id`, and written once; a
+/// duplicate, or an event already deleted by its author, adds no rows.
+/// Writing a replaceable event removes older versions from the same
+/// author (and `d` tag, for parameterized ones), and a deletion (kind
+/// 5) hides the events it references from the same author.  Hidden
+/// events are not returned by queries, or counted.  Tag filters match
+/// the tags of stored events, so every tag must be indexed for
+/// querying by the time `write_event` returns (unless deferred tag
+/// indexing is enabled).
 #[async_trait]
 pub trait NostrRepo: Send + Sync {
     /// Start the repository (any initialization or maintenance tasks can be kicked off here)
@@ -22,15 +85,23 @@ pub trait NostrRepo: Send + Sync {
     /// Run migrations and return current version
     async fn migrate_up(&self) -> Result<usize>;
 
-    /// Persist event to database
-    async fn write_event(&self, e: &Event) -> Result<u64>;
+    /// Persist event to database.  A (parameterized) replaceable event
+    /// older than the stored version is not written, and the stored
+    /// version is returned instead.
+    async fn write_event(&self, e: &Event) -> Result<WriteResult>;
 
     /// Perform a database query using a subscription.
     ///
-    /// The [`Subscription`] is converted into a SQL query.  Each result
-    /// is published on the `query_tx` channel as it is returned.  If a
-    /// message becomes available on the `abandon_query_rx` channel, the
-    /// query is immediately aborted.
+    /// Events matching any filter of the [`Subscription`] are published
+    /// on the `query_tx` channel as they are returned, in chunks of
+    /// [`QUERY_CHUNK_ROWS`], followed by a result whose `event` is
+    /// `EOSE`.  The query may run after this returns.  If a message
+    /// becomes available on the `abandon_query_rx` channel, or
+    /// `query_tx` is closed, the query is aborted at the end of the
+    /// chunk.  A query that has spent `database.query_timeout_ms`
+    /// fetching (not counting time waiting for room on `query_tx`)
+    /// stops, and its `EOSE` result is marked `timed_out`, as it is
+    /// when the query is aborted under load or for a slow client.
     async fn query_subscription(
         &self,
         sub: Subscription,
@@ -42,6 +113,26 @@ pub trait NostrRepo: Send + Sync {
     /// Perform normal maintenance
     async fn optimize_db(&self) -> Result<()>;
 
+    /// Count stored events (excluding deleted ones)
+    async fn count_events(&self) -> Result<u64>;
+
+    /// Count stored events (excluding deleted ones) first seen at or
+    /// after a unix time
+    async fn count_events_since(&self, since: u64) -> Result<u64>;
+
+    /// Count stored events (excluding deleted ones) by their source
+    async fn count_by_source(&self) -> Result<Vec<SourceCount>>;
+
+    /// Count stored events (excluding deleted ones) by their kind, as
+    /// `(kind, count)` in kind order
+    async fn count_by_kind(&self) -> Result<Vec<(u64, u64)>>;
+
+    /// Delete the stored events matching a filter (hidden ones too),
+    /// with their tags, committing in batches of `DELETE_BATCH` so
+    /// writers are not held up for long.  With `dry_run`, they are only
+    /// counted.  Returns how many were (or would be) deleted.
+    async fn delete_matching(&self, filter: &ReqFilter, dry_run: bool) -> Result<u64>;
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()>;
 
@@ -54,29 +145,53 @@ pub trait NostrRepo: Send + Sync {
     /// Delete verification record
     async fn delete_verification(&self, id: u64) -> Result<()>;
 
-    /// Get the latest verification record for a given pubkey.
+    /// Get the latest verification record for a given pubkey, or
+    /// `Error::NotFound` if it has none.
     async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord>;
 
-    /// Get oldest verification before timestamp
+    /// Get oldest verification before timestamp, or `Error::NotFound`
+    /// if there is none.
     async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord>;
 
     /// Create a new account
     async fn create_account(&self, pubkey: &Keys) -> Result<bool>;
 
-    /// Admit an account
-    async fn admit_account(&self, pubkey: &Keys, admission_cost: u64) -> Result<()>;
+    /// Admit an account, charging the admission cost.  The reference
+    /// (usually the payment hash) is recorded in the ledger.
+    async fn admit_account(
+        &self,
+        pubkey: &Keys,
+        admission_cost: u64,
+        reference: Option<&str>,
+    ) -> Result<()>;
 
-    /// Gets user balance if they are an admitted pubkey
+    /// Gets user balance if they are an admitted pubkey, or
+    /// `Error::NotFound` if they have no account.
     async fn get_account_balance(&self, pubkey: &Keys) -> Result<(bool, u64)>;
 
-    /// Update account balance
+    /// Update account balance, recording the change in the ledger
     async fn update_account_balance(
         &self,
         pub_key: &Keys,
         positive: bool,
         new_balance: u64,
+        reason: LedgerReason,
+        reference: Option<&str>,
     ) -> Result<()>;
 
+    /// Events published by an account
+    async fn published_event_count(&self, pubkey: &Keys) -> Result<u64>;
+
+    /// Count an event published by an account.  Returns the events
+    /// counted so far.
+    async fn count_published_event(&self, pubkey: &Keys) -> Result<u64>;
+
+    /// Get the most recent ledger entries for an account, newest first
+    async fn get_ledger(&self, pubkey: &Keys, limit: Option<u64>) -> Result<Vec<LedgerEntry>>;
+
+    /// Find accounts whose balance differs from the sum of their ledger
+    async fn verify_ledger(&self) -> Result<Vec<LedgerMismatch>>;
+
     /// Create invoice record
     async fn create_invoice_record(&self, pubkey: &Keys, invoice_info: InvoiceInfo) -> Result<()>;
 
@@ -86,6 +201,101 @@ pub trait NostrRepo: Send + Sync {
     /// Get the most recent invoice for a given pubkey
     /// invoice must be unpaid and not expired
     async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>>;
+
+    /// Get all unpaid invoices created at or after `since`
+    async fn get_unpaid_invoices(&self, since: u64) -> Result<Vec<InvoiceInfo>>;
+
+    /// Record that an invoice was seen paid.  Returns when it was
+    /// created the first time only, so each payment is counted once.
+    async fn confirm_invoice(&self, payment_hash: &str) -> Result<Option<u64>>;
+
+    /// Count unpaid invoices created at or after `unpaid_since`, and
+    /// total the amount of paid invoices
+    async fn get_payment_stats(&self, unpaid_since: u64) -> Result<PaymentStats>;
+
+    /// Get the newest event delivered to a client's subscription
+    async fn get_watermark(&self, pubkey: &str, sub_key: &str) -> Result<Option<u64>>;
+
+    /// Record the newest event delivered to a client's subscription
+    async fn save_watermark(&self, pubkey: &str, sub_key: &str, created_at: u64) -> Result<()>;
+
+    /// Add to the daily write statistics
+    async fn add_event_stats(&self, stats: &[EventStat]) -> Result<()>;
+
+    /// The authors that wrote the most events on a day, of one kind or
+    /// of all kinds
+    async fn top_writers(
+        &self,
+        day: NaiveDate,
+        kind: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<TopWriter>>;
+
+    /// The newest `created_at` of the events stored for an author
+    async fn latest_created_at(&self, pubkey: &str) -> Result<Option<u64>>;
+
+    /// Quarantine status of a pubkey, if it has been seen
+    async fn get_pubkey_status(&self, pubkey: &str) -> Result<Option<PubkeyStatus>>;
+
+    /// Record a pubkey seen for the first time.  A pubkey already
+    /// recorded is left as it is.
+    async fn add_first_seen_pubkey(
+        &self,
+        pubkey: &str,
+        first_seen: u64,
+        status: PubkeyStatus,
+    ) -> Result<()>;
+
+    /// Count an event written by a quarantined pubkey.  Returns the
+    /// events counted so far.
+    async fn count_quarantined_event(&self, pubkey: &str) -> Result<u64>;
+
+    /// Release or ban a pubkey, recording it if it was not seen
+    async fn set_pubkey_status(&self, pubkey: &str, status: PubkeyStatus) -> Result<()>;
+
+    /// Release the quarantined pubkeys first seen before
+    /// `first_seen_before`.  Returns the pubkeys released.
+    async fn release_quarantined(&self, first_seen_before: u64) -> Result<Vec<String>>;
+
+    /// The quarantined and banned pubkeys, oldest first
+    async fn withheld_pubkeys(&self) -> Result<Vec<QuarantinedPubkey>>;
+
+    /// Persist an event hidden, while it waits for asynchronous
+    /// admission.  Returns rows added.
+    async fn write_pending_event(&self, e: &Event) -> Result<u64>;
+
+    /// Make a pending event visible, or remove it if it was not
+    /// accepted.  Returns whether a pending event was found.
+    async fn resolve_pending_event(&self, event_id: &str, accepted: bool) -> Result<bool>;
+
+    /// Events still waiting for asynchronous admission
+    async fn get_pending_events(&self) -> Result<Vec<Event>>;
+
+    /// Send every event stored for an author, including hidden ones.
+    /// Returns events sent.
+    async fn export_events_by(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<u64>;
+
+    /// Send the verification records, account, invoices and ledger
+    /// entries of a pubkey, in that order
+    async fn export_account_data(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<()>;
+
+    /// Delete the events of an author, respecting the purge delay.
+    /// Returns events deleted.
+    async fn erase_events_by(&self, pubkey: &str) -> Result<u64>;
+
+    /// Delete the verification records and invoices of a pubkey,
+    /// withdraw its account's admission (the account and ledger are
+    /// kept for reconciliation), and refuse its events created before
+    /// `erased_at` until `tombstone_until`.  Returns records changed.
+    async fn erase_account_data(
+        &self,
+        pubkey: &str,
+        erased_at: u64,
+        tombstone_until: Option<u64>,
+    ) -> Result<UserDataCounts>;
+
+    /// When a pubkey was erased, while its tombstone lasts
+    async fn erased_at(&self, pubkey: &str) -> Result<Option<u64>>;
 }
 
 // Current time, with a slight forward jitter in seconds
@@ -96,3 +306,215 @@ pub(crate) fn now_jitter(sec: u64) -> u64 {
     let now = unix_time();
     now.saturating_add(jitter_amount)
 }
+
+/// Rows of a stored query sent between checks that its subscription
+/// is still open.  Queries running on the async runtime also yield to
+/// other tasks after each chunk.
+pub(crate) const QUERY_CHUNK_ROWS: usize = 100;
+
+/// Is a stored query no longer wanted?  Either the subscription was
+/// closed (or replaced by a new `REQ`), or the client is gone.
+pub(crate) fn query_abandoned(
+    abandon_query_rx: &mut tokio::sync::oneshot::Receiver<()>,
+    query_tx: &tokio::sync::mpsc::Sender<QueryResult>,
+) -> bool {
+    abandon_query_rx.try_recv().is_ok() || query_tx.is_closed()
+}
+
+/// Most events whose tags are indexed in one deferred batch
+pub(crate) const TAG_INDEX_BATCH: usize = 50;
+
+/// Can the tags of this event be indexed after it is stored?
+/// Deletions and parameterized replaceable events are found through
+/// their tags when later events are written, so those are always
+/// indexed immediately.
+pub(crate) fn can_defer_tags(e: &Event) -> bool {
+    !e.tags.is_empty() && e.kind != 5 && e.distinct_param().is_none()
+}
+
+/// Result of indexing a batch of events stored with deferred tags
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct TagIndexBatch {
+    /// Events whose tags were indexed
+    pub indexed: usize,
+    /// When the earliest of those events was first seen
+    pub oldest_first_seen: Option<u64>,
+    /// Events still waiting to be indexed
+    pub pending: u64,
+}
+
+impl TagIndexBatch {
+    /// Update the indexing metrics after a batch.
+    pub fn record(&self, metrics: &NostrMetrics) {
+        metrics.tag_index_pending.set(self.pending as i64);
+        if let Some(first_seen) = self.oldest_first_seen {
+            let lag = unix_time().saturating_sub(first_seen);
+            metrics.tag_index_lag.observe(lag as f64);
+        }
+    }
+
+    /// Is there more work waiting for another batch?
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.indexed >= TAG_INDEX_BATCH
+    }
+}
+
+/// Most events deleted in one transaction by
+/// [`NostrRepo::delete_matching`]
+pub(crate) const DELETE_BATCH: u64 = 1000;
+
+/// Most events deleted in one batch by `max_stored_events`
+pub(crate) const EVICTION_BATCH: u64 = 1000;
+
+/// Result of evicting a batch of the oldest events
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct EvictionBatch {
+    /// Events deleted
+    pub evicted: u64,
+    /// Events left stored
+    pub stored: u64,
+}
+
+impl EvictionBatch {
+    /// Update the eviction metrics after a batch.
+    pub fn record(&self, metrics: &NostrMetrics) {
+        metrics.stored_events.set(self.stored as i64);
+        metrics.events_evicted.inc_by(self.evicted);
+    }
+
+    /// Could another batch still be over the limit?
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.evicted >= EVICTION_BATCH
+    }
+}
+
+/// Delay before the first retry, doubled for each further retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(20);
+
+/// Longest delay between retries, before jitter
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Delay before retrying after the given (1-based) attempt
+fn retry_delay(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    RETRY_BASE_DELAY.saturating_mul(factor).min(RETRY_MAX_DELAY)
+}
+
+/// Run a database write up to `attempts` times, retrying with jittered
+/// exponential backoff while it fails with an error that `is_transient`
+/// considers safe to retry (such as a deadlock).  Other errors are
+/// returned immediately.
+pub(crate) async fn retry_transient<T, F, Fut>(
+    metrics: &NostrMetrics,
+    attempts: u32,
+    is_transient: fn(&Error) -> bool,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if is_transient(&e) => {
+                if attempt >= attempts {
+                    metrics
+                        .db_write_retries
+                        .with_label_values(&["exhausted"])
+                        .inc();
+                    warn!("database write failed after {} attempts: {:?}", attempt, e);
+                    return Err(e);
+                }
+                metrics
+                    .db_write_retries
+                    .with_label_values(&["retried"])
+                    .inc();
+                let delay = retry_delay(attempt);
+                let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64);
+                debug!(
+                    "retrying database write (attempt {}) after transient error: {:?}",
+                    attempt, e
+                );
+                tokio::time::sleep(delay + Duration::from_millis(jitter)).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::create_metrics;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const ATTEMPTS: u32 = 4;
+
+    fn is_conflict(e: &Error) -> bool {
+        matches!(e, Error::CustomError(msg) if msg == "conflict")
+    }
+
+    #[tokio::test]
+    async fn retry_transient_recovers_from_conflict() {
+        let (_, metrics) = create_metrics();
+        let calls = AtomicU32::new(0);
+        let res = retry_transient(&metrics, ATTEMPTS, is_conflict, || async {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(Error::CustomError("conflict".to_owned()))
+            } else {
+                Ok(1)
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let retries = &metrics.db_write_retries;
+        assert_eq!(retries.with_label_values(&["retried"]).get(), 1);
+        assert_eq!(retries.with_label_values(&["exhausted"]).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_gives_up() {
+        let (_, metrics) = create_metrics();
+        let calls = AtomicU32::new(0);
+        let res: Result<()> = retry_transient(&metrics, ATTEMPTS, is_conflict, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Error::CustomError("conflict".to_owned()))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), ATTEMPTS);
+        let retries = &metrics.db_write_retries;
+        assert_eq!(
+            retries.with_label_values(&["retried"]).get(),
+            u64::from(ATTEMPTS - 1)
+        );
+        assert_eq!(retries.with_label_values(&["exhausted"]).get(), 1);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(retry_delay(1), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(20), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(40), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn retry_transient_skips_other_errors() {
+        let (_, metrics) = create_metrics();
+        let calls = AtomicU32::new(0);
+        let res: Result<()> = retry_transient(&metrics, ATTEMPTS, is_conflict, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(Error::CustomError("constraint".to_owned()))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,22 +1,38 @@
+use crate::compact::{DatabaseSize, RelationSize};
+use crate::config::{RetentionRule, Settings};
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::{is_indexable_tag_value, normalize_tag_value, single_char_tagname, Event};
 use crate::nip05::{Nip05Name, VerificationRecord};
-use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::repo::{now_jitter, NostrRepo};
+use crate::payment::{
+    InvoiceInfo, InvoiceRefund, InvoiceStatus, LedgerEntry, LedgerMismatch, LedgerReason,
+    PaymentStats,
+};
+use crate::repo::{
+    can_defer_tags, now_jitter, query_abandoned, retry_transient, EvictionBatch, NostrRepo,
+    TagIndexBatch, WriteResult, DELETE_BATCH, EVICTION_BATCH, QUERY_CHUNK_ROWS, TAG_INDEX_BATCH,
+};
 use crate::subscription::{ReqFilter, Subscription};
-use async_std::stream::StreamExt;
+use async_std::stream::{Stream, StreamExt};
 use async_trait::async_trait;
-use chrono::{DateTime, TimeZone, Utc};
-use sqlx::postgres::PgRow;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use rand::Rng;
+use sqlx::pool::PoolOptions;
+use sqlx::postgres::{PgConnection, PgRow};
 use sqlx::Error::RowNotFound;
-use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row};
+use sqlx::{Error, Execute, Executor, FromRow, Postgres, QueryBuilder, Row, Transaction};
 use std::time::{Duration, Instant};
 
 use crate::error;
 use crate::hexrange::{hex_range, HexSearch};
-use crate::repo::postgres_migration::run_migrations;
+use crate::quarantine::{PubkeyStatus, QuarantinedPubkey};
+use crate::repo::postgres_migration::{oversize_tag_count, run_migrations};
+use crate::repo::postgres_partition;
 use crate::server::NostrMetrics;
+use crate::source::SourceCount;
+use crate::startup;
+use crate::stats::{day_of, EventStat, TopWriter};
+use crate::user_data::{UserDataCounts, UserRecord, UserTable};
 use crate::utils::{self, is_hex, is_lower_hex};
 use nostr::key::Keys;
 use tokio::sync::mpsc::Sender;
@@ -25,73 +41,409 @@ use tracing::{debug, error, info, trace, warn};
 
 pub type PostgresPool = sqlx::pool::Pool<Postgres>;
 
+/// Options for a connection pool.  If `database.postgres_schema` is
+/// set, every new connection uses it as its only `search_path`, so
+/// unqualified table names resolve to (and are created in) that schema.
+#[must_use]
+pub fn pool_options(settings: &Settings) -> PoolOptions<Postgres> {
+    let search_path = settings
+        .database
+        .postgres_schema
+        .as_ref()
+        .map(|schema| format!("SET search_path TO \"{schema}\""));
+    PoolOptions::new().after_connect(move |conn: &mut PgConnection, _meta| {
+        let search_path = search_path.clone();
+        Box::pin(async move {
+            if let Some(sql) = search_path {
+                conn.execute(sql.as_str()).await?;
+            }
+            Ok(())
+        })
+    })
+}
+
+/// SQLSTATE codes for transaction conflicts that may succeed when
+/// retried: `serialization_failure` and `deadlock_detected`.
+const TRANSIENT_PG_CODES: [&str; 2] = ["40001", "40P01"];
+
+/// Check if an error is a transaction conflict that is safe to retry
+fn is_transient_pg_error(e: &error::Error) -> bool {
+    match e {
+        error::Error::SqlxError(Error::Database(db_err)) => db_err
+            .code()
+            .map_or(false, |code| TRANSIENT_PG_CODES.contains(&code.as_ref())),
+        _ => false,
+    }
+}
+
+/// A unix time as a postgres timestamp, or an error for times chrono
+/// can not represent.
+fn pg_timestamp(secs: u64) -> Result<DateTime<Utc>> {
+    i64::try_from(secs)
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+        .ok_or_else(|| error::Error::CustomError(format!("timestamp out of range: {secs}")))
+}
+
+/// Attempts at connecting to (and migrating) the database at startup
+pub(crate) const STARTUP_ATTEMPTS: u32 = 6;
+
+/// Delay before the first startup retry, doubled for each further retry
+pub(crate) const STARTUP_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Check if an error means the database could not be reached, rather
+/// than that it refused a statement.  SQLSTATE class 08 is a connection
+/// exception, and `cannot_connect_now` is sent while the server starts.
+fn is_connection_error(e: &error::Error) -> bool {
+    match e {
+        error::Error::SqlxError(e) | error::Error::SqlxDatabasePoolError(e) => match e {
+            Error::Io(_) | Error::Tls(_) | Error::PoolTimedOut => true,
+            Error::Database(db_err) => db_err
+                .code()
+                .map_or(false, |code| code.starts_with("08") || code == "57P03"),
+            _ => false,
+        },
+        error::Error::MigrationError(_, e) => is_connection_error(e),
+        _ => false,
+    }
+}
+
+/// Run a startup step, retrying with exponential backoff while the
+/// database can not be reached.  Other errors are returned immediately.
+pub(crate) async fn retry_startup<T, F, Fut>(
+    what: &str,
+    attempts: u32,
+    base_delay: Duration,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < attempts && is_connection_error(&e) => {
+                let delay = base_delay * 2u32.pow(attempt - 1);
+                warn!(
+                    "could not {} (attempt {}), retrying in {:?}: {:?}",
+                    what, attempt, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct PostgresRepo {
     conn: PostgresPool,
     conn_write: PostgresPool,
     metrics: NostrMetrics,
+    max_indexed_tag_value_bytes: Option<usize>,
+    max_tag_value_bytes: Option<usize>,
+    retention_rules: Vec<RetentionRule>,
+    restricted_read_kinds: Vec<u64>,
+    write_attempts: u32,
+    watermark_days: Option<u64>,
+    stats_days: Option<u64>,
+    purge_delay: u64,
+    schema: Option<String>,
+    explain_slow_queries: Option<Duration>,
+    explain_sample_rate: f64,
+    deferred_tags: bool,
+    tag_index_delay: Duration,
+    max_stored_events: Option<u64>,
+    partitioned: bool,
+    query_timeout: Option<Duration>,
 }
 
 impl PostgresRepo {
-    pub fn new(c: PostgresPool, cw: PostgresPool, m: NostrMetrics) -> PostgresRepo {
+    pub fn new(
+        c: PostgresPool,
+        cw: PostgresPool,
+        m: NostrMetrics,
+        settings: &Settings,
+    ) -> PostgresRepo {
         PostgresRepo {
             conn: c,
             conn_write: cw,
             metrics: m,
+            max_indexed_tag_value_bytes: settings.limits.max_indexed_tag_value_bytes,
+            max_tag_value_bytes: settings.limits.max_tag_value_bytes,
+            retention_rules: settings.retention.rules(),
+            restricted_read_kinds: settings.authorization.restricted_read_kinds.clone(),
+            write_attempts: settings.database.write_attempts,
+            watermark_days: settings
+                .options
+                .resume_subscriptions
+                .then_some(settings.options.resume_watermark_days),
+            stats_days: settings
+                .stats
+                .enabled
+                .then_some(settings.stats.retention_days),
+            purge_delay: settings.retention.purge_delay_hours * 3600,
+            schema: settings.database.postgres_schema.clone(),
+            explain_slow_queries: settings
+                .database
+                .explain_slow_queries_ms
+                .map(Duration::from_millis),
+            explain_sample_rate: settings.database.explain_sample_rate,
+            deferred_tags: settings.database.deferred_tag_indexing,
+            tag_index_delay: Duration::from_millis(settings.database.tag_index_delay_ms),
+            max_stored_events: settings.limits.max_stored_events.filter(|max| *max > 0),
+            partitioned: settings.database.partitioned,
+            query_timeout: settings
+                .database
+                .query_timeout_ms
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
         }
     }
-}
 
-/// Cleanup expired events on a regular basis
-async fn cleanup_expired(conn: PostgresPool, frequency: Duration) -> Result<()> {
-    tokio::task::spawn(async move {
-        loop {
-            tokio::select! {
-                _ = tokio::time::sleep(frequency) => {
-                    let start = Instant::now();
-                    let exp_res = delete_expired(conn.clone()).await;
-                    match exp_res {
-                        Ok(exp_count) => {
-                            if exp_count > 0 {
-                                info!("removed {} expired events in: {:?}", exp_count, start.elapsed());
-                            }
-                        },
-                        Err(e) => {
-                            warn!("could not remove expired events due to error: {:?}", e);
-                        }
-                    }
+    /// Should a filter query that took `elapsed` be explained?
+    fn sample_slow_query(&self, elapsed: Duration) -> bool {
+        self.explain_slow_queries
+            .map_or(false, |cutoff| elapsed >= cutoff)
+            && rand::thread_rng().gen_bool(self.explain_sample_rate)
+    }
+
+    /// Plan a slow filter query again with `EXPLAIN`, in the
+    /// background, and warn if it sequentially scans the event or tag
+    /// table.
+    fn explain_slow_query(&self, filter: &ReqFilter, elapsed: Duration) {
+        let conn = self.conn.clone();
+        let metrics = self.metrics.clone();
+        let filter = filter.clone();
+        tokio::spawn(async move {
+            let Some(mut query) = filter_query(&filter, "EXPLAIN ") else {
+                return;
+            };
+            let plan: Vec<String> = match query.build().fetch_all(&conn).await {
+                Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                Err(e) => {
+                    info!("could not explain slow query: {:?}", e);
+                    return;
                 }
+            };
+            for table in seq_scanned_tables(&plan) {
+                metrics
+                    .query_plan_seq_scans
+                    .with_label_values(&[table])
+                    .inc();
+                warn!(
+                    "slow query ({:?}) sequentially scans the {} table, check that its indexes exist and are valid: {:?}\n{}",
+                    elapsed,
+                    table,
+                    filter,
+                    plan.join("\n")
+                );
             }
-            ;
-        }
-    });
-    Ok(())
-}
+        });
+    }
 
-/// One-time deletion of all expired events
-async fn delete_expired(conn: PostgresPool) -> Result<u64> {
-    let mut tx = conn.begin().await?;
-    let update_count = sqlx::query("DELETE FROM \"event\" WHERE expires_at <= $1;")
-        .bind(Utc.timestamp_opt(utils::unix_time() as i64, 0).unwrap())
-        .execute(&mut tx)
-        .await?
-        .rows_affected();
-    tx.commit().await?;
-    Ok(update_count)
-}
+    /// Send the results of a subscription's stored query, in chunks of
+    /// `QUERY_CHUNK_ROWS`, followed by `EOSE`.
+    async fn stream_query(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: Sender<QueryResult>,
+        mut abandon_query_rx: Receiver<()>,
+    ) {
+        let start = Instant::now();
+        // moved back by the time spent waiting for the client
+        let mut deadline = self.query_timeout.map(|timeout| start + timeout);
+        // set when the query stops early, at its deadline or aborted
+        let mut timed_out = false;
+        let mut row_count: usize = 0;
+        let metrics = &self.metrics;
+        let wants_first_seen = sub.wants_first_seen();
 
-#[async_trait]
-impl NostrRepo for PostgresRepo {
-    async fn start(&self) -> Result<()> {
-        // begin a cleanup task for expired events.
-        cleanup_expired(self.conn_write.clone(), Duration::from_secs(600)).await?;
-        Ok(())
-    }
+        'filters: for filter in sub.filters.iter() {
+            let start = Instant::now();
+            // generate SQL query
+            let Some(mut q_filter) = query_from_filter(filter) else {
+                debug!("Failed to generate query!");
+                continue;
+            };
 
-    async fn migrate_up(&self) -> Result<usize> {
-        Ok(run_migrations(&self.conn_write).await?)
+            debug!("SQL generated in {:?}", start.elapsed());
+
+            // cutoff for displaying slow queries
+            let slow_cutoff = Duration::from_millis(2000);
+
+            // any client that doesn't cause us to generate new rows in 5
+            // seconds gets dropped.
+            let abort_cutoff = Duration::from_secs(5);
+
+            let start = Instant::now();
+            // time spent waiting for the client, not the database
+            let mut client_wait = Duration::ZERO;
+            let mut slow_first_event;
+            let mut last_successful_send = Instant::now();
+
+            // execute the query. Don't cache, since queries vary so much.
+            let q_build = q_filter.build();
+            let sql = q_build.sql();
+            let mut results = q_build.fetch(&self.conn);
+
+            let mut first_result = true;
+            loop {
+                let Some(next) = before_deadline(deadline, results.next()).await else {
+                    timed_out = true;
+                    break;
+                };
+                let Some(row) = next else {
+                    break;
+                };
+                let row = match row {
+                    Ok(row) => row,
+                    Err(e) => {
+                        error!("Query failed: {} {} {:?}", e, sql, filter);
+                        break;
+                    }
+                };
+                let first_event_elapsed = start.elapsed().saturating_sub(client_wait);
+                slow_first_event = first_event_elapsed >= slow_cutoff;
+                if first_result {
+                    debug!(
+                        "first result in {:?} (cid: {}, sub: {:?})",
+                        first_event_elapsed, client_id, sub.id
+                    );
+                    first_result = false;
+                }
+
+                // logging for slow queries; show sub and SQL.
+                // to reduce logging; only show 1/16th of clients (leading 0)
+                if slow_first_event && client_id.starts_with("00") {
+                    debug!(
+                        "query req (slow): {:?} (cid: {}, sub: {:?})",
+                        &sub, client_id, sub.id
+                    );
+                } else {
+                    trace!(
+                        "query req: {:?} (cid: {}, sub: {:?})",
+                        &sub,
+                        client_id,
+                        sub.id
+                    );
+                }
+
+                // between chunks, let other tasks run, and check the
+                // subscription is still open.
+                if row_count % QUERY_CHUNK_ROWS == 0 {
+                    if row_count > 0 {
+                        tokio::task::yield_now().await;
+                    }
+                    if query_abandoned(&mut abandon_query_rx, &query_tx) {
+                        debug!(
+                            "query cancelled by client (cid: {}, sub: {:?}, rows: {})",
+                            client_id, sub.id, row_count
+                        );
+                        return;
+                    }
+                }
+
+                row_count += 1;
+                let event_json: Vec<u8> = row.get(0);
+                let first_seen = if wants_first_seen {
+                    let ts: DateTime<Utc> = row.get(2);
+                    Some(ts.timestamp() as u64)
+                } else {
+                    None
+                };
+                let wait_start = Instant::now();
+                loop {
+                    if query_tx.capacity() != 0 {
+                        // we have capacity to add another item
+                        break;
+                    } else {
+                        // the queue is full
+                        trace!("db reader thread is stalled");
+                        if last_successful_send + abort_cutoff < Instant::now() {
+                            // the queue has been full for too long, abort
+                            info!("aborting database query due to slow client");
+                            metrics
+                                .query_aborts
+                                .with_label_values(&["slowclient"])
+                                .inc();
+                            timed_out = true;
+                            break 'filters;
+                        }
+                        if query_abandoned(&mut abandon_query_rx, &query_tx) {
+                            debug!(
+                                "query cancelled by client (cid: {}, sub: {:?}, rows: {})",
+                                client_id, sub.id, row_count
+                            );
+                            return;
+                        }
+                        // give the queue a chance to clear before trying again
+                        async_std::task::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+
+                // TODO: we could use try_send, but we'd have to juggle
+                // getting the query result back as part of the error
+                // result.
+                query_tx
+                    .send(QueryResult {
+                        sub_id: sub.get_id(),
+                        event: String::from_utf8_lossy(&event_json).into_owned(),
+                        first_seen,
+                        generation: sub.generation,
+                        timed_out: false,
+                    })
+                    .await
+                    .ok();
+                last_successful_send = Instant::now();
+                // waiting for the client does not count against the
+                // deadline, or as query time
+                client_wait += wait_start.elapsed();
+                deadline = deadline.map(|d| d + wait_start.elapsed());
+            }
+            let query_elapsed = start.elapsed().saturating_sub(client_wait);
+            if self.sample_slow_query(query_elapsed) {
+                self.explain_slow_query(filter, query_elapsed);
+            }
+            if timed_out {
+                // what was found is sent, but the rest is not looked for
+                debug!(
+                    "query stopped at its deadline (cid: {}, sub: {:?}, rows: {})",
+                    client_id, sub.id, row_count
+                );
+                metrics.query_aborts.with_label_values(&["timeout"]).inc();
+                break;
+            }
+        }
+        query_tx
+            .send(QueryResult {
+                sub_id: sub.get_id(),
+                event: "EOSE".to_string(),
+                first_seen: None,
+                generation: sub.generation,
+                timed_out,
+            })
+            .await
+            .ok();
+        self.metrics
+            .query_sub
+            .observe(start.elapsed().as_secs_f64());
+        debug!(
+            "query completed in {:?} (cid: {}, sub: {:?}, db_time: {:?}, rows: {})",
+            start.elapsed(),
+            client_id,
+            sub.id,
+            start.elapsed(),
+            row_count
+        );
     }
 
-    async fn write_event(&self, e: &Event) -> Result<u64> {
+    /// Persist an event in a single transaction, without retries.  A
+    /// `pending` event is stored hidden until it is resolved.
+    async fn write_event_once(&self, e: &Event, pending: bool) -> Result<WriteResult> {
         // start transaction
         let mut tx = self.conn_write.begin().await?;
         let start = Instant::now();
@@ -101,113 +453,92 @@ impl NostrRepo for PostgresRepo {
         let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
         let delegator_blob: Option<Vec<u8>> =
             e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
-        let event_str = serde_json::to_string(&e).unwrap();
+        let event_str = serde_json::to_string(&e)?;
+        let created_at = pg_timestamp(e.created_at)?;
+        let defer_tags = self.deferred_tags && can_defer_tags(e) && !pending;
 
         // determine if this event would be shadowed by an existing
-        // replaceable event or parameterized replaceable event.
+        // replaceable event or parameterized replaceable event.  Within
+        // the same second, the lowest id wins.
         if e.is_replaceable() {
-            let repl_count = sqlx::query(
-                "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
+            let newer = sqlx::query(
+                "SELECT e.\"content\", (e.hidden = 1::bit(1) OR e.pending) FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND (e.created_at > $3 OR (e.created_at = $3 AND e.id < $4)) ORDER BY e.created_at DESC, e.id ASC LIMIT 1;")
                 .bind(&pubkey_blob)
                 .bind(e.kind as i64)
-                .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .bind(created_at)
+                .bind(&id_blob)
                 .fetch_optional(&mut tx)
                 .await?;
-            if repl_count.is_some() {
-                return Ok(0);
+            if let Some(newer) = newer {
+                return Ok(superseded_by(&newer));
             }
         }
         if let Some(d_tag) = e.distinct_param() {
-            let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
+            let newer = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
+                sqlx::query(
+                    "SELECT e.\"content\", (e.hidden = 1::bit(1) OR e.pending) FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND (e.created_at > $4 OR (e.created_at = $4 AND e.id < $5)) ORDER BY e.created_at DESC, e.id ASC LIMIT 1;")
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(e.kind as i64)
                     .bind(hex::decode(d_tag).ok())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
+                    .bind(created_at)
+                    .bind(&id_blob)
+                    .fetch_optional(&mut tx)
                     .await?
             } else {
-                sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
+                sqlx::query(
+                    "SELECT e.\"content\", (e.hidden = 1::bit(1) OR e.pending) FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND (e.created_at > $4 OR (e.created_at = $4 AND e.id < $5)) ORDER BY e.created_at DESC, e.id ASC LIMIT 1;")
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(e.kind as i64)
                     .bind(d_tag.as_bytes())
-                    .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-                    .fetch_one(&mut tx)
+                    .bind(created_at)
+                    .bind(&id_blob)
+                    .fetch_optional(&mut tx)
                     .await?
             };
             // if any rows were returned, then some newer event with
             // the same author/kind/tag value exist, and we can ignore
             // this event.
-            if repl_count > 0 {
-                return Ok(0);
+            if let Some(newer) = newer {
+                return Ok(superseded_by(&newer));
             }
         }
         // ignore if the event hash is a duplicate.
         let mut ins_count = sqlx::query(
             r#"INSERT INTO "event"
-(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6, $7)
-ON CONFLICT (id) DO NOTHING"#,
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by, tags_indexed, hidden, pending)
+VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9::integer::bit(1), $10)
+ON CONFLICT DO NOTHING"#,
         )
-            .bind(&id_blob)
-            .bind(&pubkey_blob)
-            .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
-            .bind(
-                e.expiration()
-                    .and_then(|x| Utc.timestamp_opt(x as i64, 0).latest()),
-            )
-            .bind(e.kind as i64)
-            .bind(event_str.into_bytes())
-            .bind(delegator_blob)
-            .execute(&mut tx)
-            .await?
-            .rows_affected();
+        .bind(&id_blob)
+        .bind(&pubkey_blob)
+        .bind(created_at)
+        .bind(
+            e.expiration()
+                .and_then(|x| Utc.timestamp_opt(x as i64, 0).latest()),
+        )
+        .bind(e.kind as i64)
+        .bind(event_str.into_bytes())
+        .bind(delegator_blob)
+        .bind(!defer_tags)
+        .bind(i32::from(pending))
+        .bind(pending)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
 
         if ins_count == 0 {
             // if the event was a duplicate, no need to insert event or
             // pubkey references.  This will abort the txn.
-            return Ok(0);
+            return Ok(WriteResult::Added(0));
         }
 
-        // add all tags to the tag table
-        for tag in e.tags.iter() {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tag_name = &tag[0];
-                let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                match &tag_char_opt {
-                    Some(_) => {
-                        // if tag value is lowercase hex;
-                        if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
-                    ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(hex::decode(tag_val).ok())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        } else {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
-                    ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(tag_val.as_bytes())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    None => {}
-                }
-            }
+        // add all tags to the tag table, unless that is left to the
+        // background indexer.
+        if !defer_tags {
+            insert_tags(&mut tx, &id_blob, e, self.max_indexed_tag_value_bytes).await?;
         }
         if e.is_replaceable() {
-            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
+            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc, id asc limit 1);")
                 .bind(e.kind as i64)
                 .bind(hex::decode(&e.pubkey).ok())
                 .execute(&mut tx)
@@ -225,14 +556,14 @@ ON CONFLICT (id) DO NOTHING"#,
         // check for parameterized replaceable events that would be hidden; don't insert these either.
         if let Some(d_tag) = e.distinct_param() {
             let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
+                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY e.created_at DESC, e.id ASC OFFSET 1);")
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(hex::decode(d_tag).ok())
                     .execute(&mut tx)
                     .await?.rows_affected()
             } else {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
+                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY e.created_at DESC, e.id ASC OFFSET 1);")
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(d_tag.as_bytes())
@@ -258,8 +589,10 @@ ON CONFLICT (id) DO NOTHING"#,
                 .collect();
 
             let mut builder = QueryBuilder::new(
-                "UPDATE \"event\" SET hidden = 1::bit(1) WHERE kind != 5 AND pub_key = ",
+                "UPDATE \"event\" SET hidden = 1::bit(1), pending = FALSE, purge_after = ",
             );
+            builder.push_bind(purge_time(self.purge_delay));
+            builder.push(" WHERE kind != 5 AND pub_key = ");
             builder.push_bind(hex::decode(&e.pubkey).ok());
             builder.push(" AND id IN (");
 
@@ -283,10 +616,10 @@ ON CONFLICT (id) DO NOTHING"#,
             LEFT JOIN tag t ON e.id = t.event_id \
             WHERE e.pub_key = $1 AND t.\"name\" = 'e' AND e.kind = 5 AND t.value = $2 LIMIT 1",
             )
-                .bind(&pubkey_blob)
-                .bind(&id_blob)
-                .fetch_optional(&mut tx)
-                .await?;
+            .bind(&pubkey_blob)
+            .bind(&id_blob)
+            .fetch_optional(&mut tx)
+            .await?;
 
             // check if a the query returned a result, meaning we should
             // hid the current event
@@ -297,7 +630,8 @@ ON CONFLICT (id) DO NOTHING"#,
                     e.get_event_id_prefix(),
                     e.get_author_prefix()
                 );
-                sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1) WHERE id = $1")
+                sqlx::query("UPDATE \"event\" SET hidden = 1::bit(1), pending = FALSE, purge_after = $1 WHERE id = $2")
+                    .bind(purge_time(self.purge_delay))
                     .bind(&id_blob)
                     .execute(&mut tx)
                     .await?;
@@ -311,143 +645,594 @@ ON CONFLICT (id) DO NOTHING"#,
         self.metrics
             .write_events
             .observe(start.elapsed().as_secs_f64());
-        Ok(ins_count)
+        Ok(WriteResult::Added(ins_count))
+    }
+}
+
+/// Wait for a future, unless `deadline` passes first.  Returns
+/// [`None`] if it does.
+async fn before_deadline<F: std::future::Future>(
+    deadline: Option<Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future).await.ok(),
+        None => Some(future.await),
     }
+}
 
-    async fn query_subscription(
-        &self,
-        sub: Subscription,
-        client_id: String,
-        query_tx: Sender<QueryResult>,
-        mut abandon_query_rx: Receiver<()>,
-    ) -> Result<()> {
-        let start = Instant::now();
-        let mut row_count: usize = 0;
-        let metrics = &self.metrics;
+/// Result for a replaceable event superseded by a stored one, given
+/// the stored row (its JSON, and whether it is hidden).  Hidden
+/// (deleted or pending) versions still supersede, but are not shown.
+fn superseded_by(row: &PgRow) -> WriteResult {
+    let hidden: bool = row.get(1);
+    let json: Vec<u8> = row.get(0);
+    match serde_json::from_slice::<Event>(&json) {
+        Ok(newer) if !hidden => WriteResult::Superseded(Box::new(newer)),
+        _ => WriteResult::Added(0),
+    }
+}
 
-        for filter in sub.filters.iter() {
-            let start = Instant::now();
-            // generate SQL query
-            let q_filter = query_from_filter(filter);
-            if q_filter.is_none() {
-                debug!("Failed to generate query!");
-                continue;
+/// Cleanup expired events on a regular basis
+async fn cleanup_expired(conn: PostgresPool, frequency: Duration) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    let start = Instant::now();
+                    let exp_res = delete_expired(conn.clone()).await;
+                    match exp_res {
+                        Ok(exp_count) => {
+                            if exp_count > 0 {
+                                info!("removed {} expired events in: {:?}", exp_count, start.elapsed());
+                            }
+                        },
+                        Err(e) => {
+                            warn!("could not remove expired events due to error: {:?}", e);
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// One-time deletion of all expired events
+async fn delete_expired(conn: PostgresPool) -> Result<u64> {
+    let mut tx = conn.begin().await?;
+    let update_count = sqlx::query("DELETE FROM \"event\" WHERE expires_at <= $1;")
+        .bind(pg_timestamp(utils::unix_time())?)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    Ok(update_count)
+}
+
+/// Remove unused subscription watermarks on a regular basis
+async fn cleanup_watermarks(
+    conn: PostgresPool,
+    frequency: Duration,
+    watermark_days: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(frequency).await;
+            let cutoff = utils::unix_time().saturating_sub(watermark_days * 86400);
+            let del_res = sqlx::query("DELETE FROM subscription_watermark WHERE updated_at < $1;")
+                .bind(pg_timestamp(cutoff).ok())
+                .execute(&conn)
+                .await;
+            match del_res {
+                Ok(res) => {
+                    if res.rows_affected() > 0 {
+                        info!(
+                            "removed {} unused subscription watermarks",
+                            res.rows_affected()
+                        );
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "could not remove subscription watermarks due to error: {:?}",
+                        e
+                    );
+                }
             }
+        }
+    });
+    Ok(())
+}
 
-            debug!("SQL generated in {:?}", start.elapsed());
+/// Remove write statistics older than the retention window on a
+/// regular basis
+async fn cleanup_event_stats(
+    conn: PostgresPool,
+    frequency: Duration,
+    stats_days: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(frequency).await;
+            let cutoff = day_of(utils::unix_time().saturating_sub(stats_days * 86400));
+            let del_res = sqlx::query("DELETE FROM event_stats WHERE day < $1;")
+                .bind(cutoff)
+                .execute(&conn)
+                .await;
+            match del_res {
+                Ok(res) => {
+                    if res.rows_affected() > 0 {
+                        info!("removed {} expired write statistics", res.rows_affected());
+                    }
+                }
+                Err(e) => {
+                    warn!("could not remove write statistics due to error: {:?}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
 
-            // cutoff for displaying slow queries
-            let slow_cutoff = Duration::from_millis(2000);
+/// Cleanup events older than the retention period on a regular basis
+async fn cleanup_old_events(
+    conn: PostgresPool,
+    frequency: Duration,
+    rules: Vec<RetentionRule>,
+    restricted_kinds: Vec<u64>,
+    purge_delay: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    for rule in &rules {
+                        let start = Instant::now();
+                        let cutoff = utils::unix_time().saturating_sub(rule.days as u64 * 86400);
+                        let del_res = delete_older_than(conn.clone(), cutoff, &restricted_kinds, purge_delay, rule.source.as_deref()).await;
+                        match del_res {
+                            Ok(count) => {
+                                if count > 0 {
+                                    info!("removed {} events ({}) older than {} days in: {:?}", count, rule.source.as_deref().unwrap_or("all sources"), rule.days, start.elapsed());
+                                }
+                            },
+                            Err(e) => {
+                                warn!("could not remove old events due to error: {:?}", e);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
 
-            // any client that doesn't cause us to generate new rows in 5
-            // seconds gets dropped.
-            let abort_cutoff = Duration::from_secs(5);
+/// Delete all events older than `cutoff`.
+///
+/// Restricted kinds (such as gift wraps) have intentionally
+/// randomized timestamps, so their age is based on when they were
+/// first seen, instead of when they were created.
+///
+/// With a non-zero `purge_delay`, events are hidden and left for the
+/// purge task instead.  A hidden deletion request (kind 5) still
+/// refuses the events it deleted, until it is purged.
+///
+/// With a `source`, only events from matching sources are removed.
+async fn delete_older_than(
+    conn: PostgresPool,
+    cutoff: u64,
+    restricted_kinds: &[u64],
+    purge_delay: u64,
+    source: Option<&str>,
+) -> Result<u64> {
+    let kinds: Vec<i64> = restricted_kinds.iter().map(|k| *k as i64).collect();
+    let mut tx = conn.begin().await?;
+    // `mirror` matches `mirror:<url>`, as in `source::source_matches`
+    let age = |source_param: &str| {
+        let source_match = if source.is_some() {
+            format!(
+                " AND (source = {p} OR left(source, length({p}) + 1) = {p} || ':')",
+                p = source_param
+            )
+        } else {
+            String::new()
+        };
+        format!("((NOT (kind = ANY($2)) AND created_at < $1) OR (kind = ANY($2) AND first_seen < $1)){source_match}")
+    };
+    let sql = if purge_delay > 0 {
+        format!("UPDATE \"event\" SET hidden = 1::bit(1), purge_after = $3 WHERE purge_after IS NULL AND ({});", age("$4"))
+    } else {
+        format!("DELETE FROM \"event\" WHERE {};", age("$3"))
+    };
+    let mut query = sqlx::query(&sql).bind(pg_timestamp(cutoff)?).bind(kinds);
+    if purge_delay > 0 {
+        query = query.bind(purge_time(purge_delay));
+    }
+    if let Some(source) = source {
+        query = query.bind(source);
+    }
+    let update_count = query.execute(&mut tx).await?.rows_affected();
+    tx.commit().await?;
+    Ok(update_count)
+}
 
-            let start = Instant::now();
-            let mut slow_first_event;
-            let mut last_successful_send = Instant::now();
+/// Add the indexable tags of an event to the tag table.
+async fn insert_tags(
+    tx: &mut Transaction<'_, Postgres>,
+    id_blob: &Option<Vec<u8>>,
+    e: &Event,
+    max_indexed_tag_value_bytes: Option<usize>,
+) -> Result<()> {
+    // tags are partitioned by the creation time of their event
+    let created_at = pg_timestamp(e.created_at)?;
+    for tag in e.tags.iter() {
+        // ensure we have 2 values.
+        if tag.len() >= 2 {
+            let tag_name = &tag[0];
+            // ids and pubkeys are indexed in lowercase
+            let tag_val = normalize_tag_value(tag_name, &tag[1]);
+            let tag_val = tag_val.as_ref();
+            // only single-char tags are searchable
+            let tag_char_opt = single_char_tagname(tag_name);
+            match &tag_char_opt {
+                Some(_)
+                    if is_indexable_tag_value(tag_name, tag_val, max_indexed_tag_value_bytes) =>
+                {
+                    // if tag value is lowercase hex;
+                    if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex, event_created_at) VALUES($1, $2, NULL, $3, $4) \
+                    ON CONFLICT DO NOTHING")
+                            .bind(id_blob)
+                            .bind(tag_name)
+                            .bind(hex::decode(tag_val).ok())
+                            .bind(created_at)
+                            .execute(&mut *tx)
+                            .await?;
+                    } else {
+                        sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex, event_created_at) VALUES($1, $2, $3, NULL, $4) \
+                    ON CONFLICT DO NOTHING")
+                            .bind(id_blob)
+                            .bind(tag_name)
+                            .bind(tag_val.as_bytes())
+                            .bind(created_at)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
 
-            // execute the query. Don't cache, since queries vary so much.
-            let mut q_filter = q_filter.unwrap();
-            let q_build = q_filter.build();
-            let sql = q_build.sql();
-            let mut results = q_build.fetch(&self.conn);
+/// Index the tags of a batch of events that were stored with deferred
+/// tag indexing, oldest first.
+async fn index_deferred_tags(
+    conn: &PostgresPool,
+    max_indexed_tag_value_bytes: Option<usize>,
+) -> Result<TagIndexBatch> {
+    let mut tx = conn.begin().await?;
+    let mut batch = TagIndexBatch::default();
+    // skip rows another relay sharing the database is indexing
+    let rows = sqlx::query(
+        "SELECT id, \"content\", first_seen FROM \"event\" WHERE NOT tags_indexed ORDER BY first_seen LIMIT $1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(TAG_INDEX_BATCH as i64)
+    .fetch_all(&mut tx)
+    .await?;
+    for row in rows {
+        let id_blob: Option<Vec<u8>> = Some(row.get(0));
+        let content: Vec<u8> = row.get(1);
+        let first_seen: DateTime<Utc> = row.get(2);
+        match serde_json::from_slice::<Event>(&content) {
+            Ok(e) => insert_tags(&mut tx, &id_blob, &e, max_indexed_tag_value_bytes).await?,
+            Err(err) => warn!("could not index tags of unparseable event: {:?}", err),
+        }
+        sqlx::query("UPDATE \"event\" SET tags_indexed=TRUE WHERE id=$1")
+            .bind(&id_blob)
+            .execute(&mut tx)
+            .await?;
+        batch.indexed += 1;
+        let first_seen = first_seen.timestamp() as u64;
+        batch.oldest_first_seen = Some(
+            batch
+                .oldest_first_seen
+                .map_or(first_seen, |t| t.min(first_seen)),
+        );
+    }
+    let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM \"event\" WHERE NOT tags_indexed")
+        .fetch_one(&mut tx)
+        .await?;
+    batch.pending = pending as u64;
+    tx.commit().await?;
+    Ok(batch)
+}
 
-            let mut first_result = true;
-            while let Some(row) = results.next().await {
-                if let Err(e) = row {
-                    error!("Query failed: {} {} {:?}", e, sql, filter);
-                    break;
+/// Index the tags of events stored with deferred indexing, waiting up
+/// to `delay` between batches.  Unless `keep_running` is set, the task
+/// stops once no events are waiting.
+async fn tag_indexer_task(
+    conn: PostgresPool,
+    delay: Duration,
+    max_indexed_tag_value_bytes: Option<usize>,
+    metrics: NostrMetrics,
+    keep_running: bool,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        let mut backlog = false;
+        loop {
+            if !backlog {
+                tokio::time::sleep(delay).await;
+            }
+            backlog = false;
+            match index_deferred_tags(&conn, max_indexed_tag_value_bytes).await {
+                Ok(batch) => {
+                    batch.record(&metrics);
+                    backlog = batch.is_full();
+                    if batch.pending == 0 && !keep_running {
+                        debug!("no events are waiting for tag indexing");
+                        break;
+                    }
                 }
-                let first_event_elapsed = start.elapsed();
-                slow_first_event = first_event_elapsed >= slow_cutoff;
-                if first_result {
-                    debug!(
-                        "first result in {:?} (cid: {}, sub: {:?})",
-                        first_event_elapsed, client_id, sub.id
-                    );
-                    first_result = false;
+                Err(err) => {
+                    info!("there was an error indexing deferred tags: {:?}", err);
                 }
+            }
+        }
+    });
+    Ok(())
+}
 
-                // logging for slow queries; show sub and SQL.
-                // to reduce logging; only show 1/16th of clients (leading 0)
-                if slow_first_event && client_id.starts_with("00") {
-                    debug!(
-                        "query req (slow): {:?} (cid: {}, sub: {:?})",
-                        &sub, client_id, sub.id
-                    );
-                } else {
-                    trace!(
-                        "query req: {:?} (cid: {}, sub: {:?})",
-                        &sub,
-                        client_id,
-                        sub.id
-                    );
-                }
+/// Delete a batch of the events first seen longest ago, if more than
+/// `max_events` are stored.  Tags are removed with their events.
+///
+/// Only the latest metadata and contact list (kinds 0 and 3) of each
+/// author are ever stored, and those are never evicted.
+async fn evict_oldest_events(conn: &PostgresPool, max_events: u64) -> Result<EvictionBatch> {
+    let mut tx = conn.begin().await?;
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM \"event\"")
+        .fetch_one(&mut tx)
+        .await?;
+    let stored = stored as u64;
+    let excess = stored.saturating_sub(max_events).min(EVICTION_BATCH);
+    if excess == 0 {
+        return Ok(EvictionBatch { evicted: 0, stored });
+    }
+    let evicted = sqlx::query(
+        "DELETE FROM \"event\" WHERE id IN (SELECT id FROM \"event\" WHERE kind NOT IN (0, 3) ORDER BY first_seen LIMIT $1 FOR UPDATE SKIP LOCKED)",
+    )
+    .bind(excess as i64)
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok(EvictionBatch {
+        evicted,
+        stored: stored - evicted,
+    })
+}
 
-                // check if this is still active; every 100 rows
-                if row_count % 100 == 0 && abandon_query_rx.try_recv().is_ok() {
-                    debug!(
-                        "query cancelled by client (cid: {}, sub: {:?})",
-                        client_id, sub.id
-                    );
-                    return Ok(());
+/// Evict the oldest events on a regular basis, until no more than
+/// `max_events` are stored.  Batches are repeated without waiting
+/// while the limit is still exceeded.
+async fn evict_events_task(
+    conn: PostgresPool,
+    frequency: Duration,
+    max_events: u64,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        let mut backlog = false;
+        loop {
+            if !backlog {
+                tokio::time::sleep(frequency).await;
+            }
+            backlog = false;
+            let start = Instant::now();
+            match evict_oldest_events(&conn, max_events).await {
+                Ok(batch) => {
+                    batch.record(&metrics);
+                    backlog = batch.is_full();
+                    if batch.evicted > 0 {
+                        info!(
+                            "evicted {} oldest events to stay under max_stored_events ({}) in: {:?}",
+                            batch.evicted,
+                            max_events,
+                            start.elapsed()
+                        );
+                    }
+                }
+                Err(err) => {
+                    warn!("could not evict old events due to error: {:?}", err);
                 }
+            }
+        }
+    });
+    Ok(())
+}
 
-                row_count += 1;
-                let event_json: Vec<u8> = row.unwrap().get(0);
-                loop {
-                    if query_tx.capacity() != 0 {
-                        // we have capacity to add another item
-                        break;
-                    } else {
-                        // the queue is full
-                        trace!("db reader thread is stalled");
-                        if last_successful_send + abort_cutoff < Instant::now() {
-                            // the queue has been full for too long, abort
-                            info!("aborting database query due to slow client");
-                            metrics
-                                .query_aborts
-                                .with_label_values(&["slowclient"])
-                                .inc();
-                            return Ok(());
-                        }
-                        // give the queue a chance to clear before trying again
-                        async_std::task::sleep(Duration::from_millis(100)).await;
+/// Time after which an event deleted now may be purged, if deleted
+/// events are kept at all.
+fn purge_time(purge_delay: u64) -> Option<DateTime<Utc>> {
+    (purge_delay > 0).then(|| Utc::now() + chrono::Duration::seconds(purge_delay as i64))
+}
+
+/// Purge deleted events past their recovery window on a regular basis
+async fn purge_deleted_events(conn: PostgresPool, frequency: Duration) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(frequency).await;
+            let del_res = sqlx::query("DELETE FROM \"event\" WHERE purge_after <= now()")
+                .execute(&conn)
+                .await;
+            match del_res {
+                Ok(res) => {
+                    if res.rows_affected() > 0 {
+                        info!("purged {} deleted events", res.rows_affected());
                     }
                 }
+                Err(e) => {
+                    warn!("could not purge deleted events due to error: {:?}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
 
-                // TODO: we could use try_send, but we'd have to juggle
-                // getting the query result back as part of the error
-                // result.
-                query_tx
-                    .send(QueryResult {
-                        sub_id: sub.get_id(),
-                        event: String::from_utf8(event_json).unwrap(),
-                    })
-                    .await
-                    .ok();
-                last_successful_send = Instant::now();
+/// Restore a deleted event that has not been purged yet.  Returns
+/// whether an event was restored.
+pub async fn undelete_event(conn: &PostgresPool, event_id: &str) -> Result<bool> {
+    let id_blob = hex::decode(event_id)?;
+    let count = sqlx::query(
+        "UPDATE \"event\" SET hidden = 0::bit(1), purge_after = NULL WHERE id = $1 AND purge_after > now()",
+    )
+    .bind(id_blob)
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(count > 0)
+}
+
+#[async_trait]
+impl NostrRepo for PostgresRepo {
+    async fn start(&self) -> Result<()> {
+        // begin a cleanup task for expired events.
+        cleanup_expired(self.conn_write.clone(), Duration::from_secs(600)).await?;
+        // partitioned tables keep upcoming months ready, and drop
+        // whole months past the retention period.
+        let mut retention_rules = self.retention_rules.clone();
+        if postgres_partition::is_partitioned(&self.conn_write).await? {
+            let created =
+                postgres_partition::ensure_partitions(&self.conn_write, Utc::now()).await?;
+            if !created.is_empty() {
+                info!("created partitions: {:?}", created);
             }
+            let persist_days = retention_rules
+                .iter()
+                .find(|r| r.source.is_none())
+                .map(|r| r.days);
+            postgres_partition::maintenance_task(
+                self.conn_write.clone(),
+                persist_days,
+                self.restricted_read_kinds.clone(),
+            );
+            retention_rules.retain(|r| r.source.is_some());
         }
-        query_tx
-            .send(QueryResult {
-                sub_id: sub.get_id(),
-                event: "EOSE".to_string(),
-            })
-            .await
-            .ok();
-        self.metrics
-            .query_sub
-            .observe(start.elapsed().as_secs_f64());
-        debug!(
-            "query completed in {:?} (cid: {}, sub: {:?}, db_time: {:?}, rows: {})",
-            start.elapsed(),
-            client_id,
-            sub.id,
-            start.elapsed(),
-            row_count
-        );
+        // and one for events past the retention period.
+        if !retention_rules.is_empty() {
+            cleanup_old_events(
+                self.conn_write.clone(),
+                Duration::from_secs(3600),
+                retention_rules,
+                self.restricted_read_kinds.clone(),
+                self.purge_delay,
+            )
+            .await?;
+        }
+        // and one for purging deleted events.
+        purge_deleted_events(self.conn_write.clone(), Duration::from_secs(3600)).await?;
+        // and one for evicting the oldest events beyond the limit.
+        if let Some(max_events) = self.max_stored_events {
+            evict_events_task(
+                self.conn_write.clone(),
+                Duration::from_secs(60),
+                max_events,
+                self.metrics.clone(),
+            )
+            .await?;
+        }
+        // and one for unused subscription watermarks.
+        if let Some(days) = self.watermark_days {
+            cleanup_watermarks(self.conn_write.clone(), Duration::from_secs(3600), days).await?;
+        }
+        // and one for expired write statistics.
+        if let Some(days) = self.stats_days {
+            cleanup_event_stats(self.conn_write.clone(), Duration::from_secs(3600), days).await?;
+        }
+        // and one for indexing deferred tags, which also finishes any
+        // left by an earlier run if deferred indexing was disabled.
+        tag_indexer_task(
+            self.conn_write.clone(),
+            self.tag_index_delay,
+            self.max_indexed_tag_value_bytes,
+            self.metrics.clone(),
+            self.deferred_tags,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        let version = retry_startup(
+            "migrate the database",
+            STARTUP_ATTEMPTS,
+            STARTUP_RETRY_DELAY,
+            || {
+                run_migrations(
+                    &self.conn_write,
+                    self.schema.as_deref(),
+                    self.max_indexed_tag_value_bytes,
+                )
+            },
+        )
+        .await?;
+        // a new database can be partitioned right away; existing events
+        // are moved by the partition-events command.
+        if self.partitioned && !postgres_partition::is_partitioned(&self.conn_write).await? {
+            let empty: bool = sqlx::query_scalar("SELECT NOT EXISTS (SELECT 1 FROM \"event\")")
+                .fetch_one(&self.conn_write)
+                .await?;
+            if !empty {
+                return Err(error::Error::CustomError(
+                    "database.partitioned is set, but the event table is not partitioned; stop the relay and run partition-events".to_owned(),
+                ));
+            }
+            startup::migration_started("partition events by month");
+            postgres_partition::convert(&self.conn_write).await?;
+            startup::migrations_finished();
+            info!("partitioned the event and tag tables by month");
+        }
+        // report (but keep) tags stored before the limit was in place.
+        if let Some(max_bytes) = self.max_tag_value_bytes {
+            let oversize = oversize_tag_count(&self.conn_write, max_bytes).await?;
+            if oversize > 0 {
+                warn!(
+                    "found {} stored tags exceeding max_tag_value_bytes ({})",
+                    oversize, max_bytes
+                );
+            }
+        }
+        Ok(version)
+    }
+
+    async fn write_event(&self, e: &Event) -> Result<WriteResult> {
+        retry_transient(
+            &self.metrics,
+            self.write_attempts,
+            is_transient_pg_error,
+            || self.write_event_once(e, false),
+        )
+        .await
+    }
+
+    /// The query runs on its own task, so the client's connection can
+    /// keep handling messages (such as a `CLOSE`) while it streams.
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: Sender<QueryResult>,
+        abandon_query_rx: Receiver<()>,
+    ) -> Result<()> {
+        let repo = self.clone();
+        tokio::task::spawn(async move {
+            repo.stream_query(sub, client_id, query_tx, abandon_query_rx)
+                .await;
+        });
         Ok(())
     }
 
@@ -456,6 +1241,82 @@ ON CONFLICT (id) DO NOTHING"#,
         Ok(())
     }
 
+    async fn count_events(&self) -> Result<u64> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM \"event\" WHERE hidden != 1::bit(1)")
+                .fetch_one(&self.conn)
+                .await?;
+        Ok(count as u64)
+    }
+
+    async fn count_events_since(&self, since: u64) -> Result<u64> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM \"event\" WHERE first_seen >= $1 AND hidden != 1::bit(1)",
+        )
+        .bind(pg_timestamp(since)?)
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(count as u64)
+    }
+
+    async fn count_by_source(&self) -> Result<Vec<SourceCount>> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT source, COUNT(*) FROM \"event\" WHERE hidden != 1::bit(1) GROUP BY source ORDER BY source",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(source, count)| SourceCount {
+                source,
+                count: count as u64,
+            })
+            .collect())
+    }
+
+    async fn count_by_kind(&self) -> Result<Vec<(u64, u64)>> {
+        let rows: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT kind::bigint, COUNT(*) FROM \"event\" WHERE hidden != 1::bit(1) GROUP BY kind ORDER BY kind",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(kind, count)| (kind as u64, count as u64))
+            .collect())
+    }
+
+    async fn delete_matching(&self, filter: &ReqFilter, dry_run: bool) -> Result<u64> {
+        if dry_run {
+            let Some(mut query) = matching_query(filter, "SELECT count(*) FROM \"event\" e WHERE ")
+            else {
+                return Ok(0);
+            };
+            let count: i64 = query.build().fetch_one(&self.conn).await?.get(0);
+            return Ok(count as u64);
+        }
+        let mut deleted = 0;
+        loop {
+            // each batch is its own transaction
+            let Some(mut query) = matching_query(
+                filter,
+                "DELETE FROM \"event\" WHERE id IN (SELECT e.id FROM \"event\" e WHERE ",
+            ) else {
+                return Ok(0);
+            };
+            query.push(" LIMIT ").push(DELETE_BATCH).push(")");
+            let batch = query
+                .build()
+                .execute(&self.conn_write)
+                .await?
+                .rows_affected();
+            deleted += batch;
+            if batch < DELETE_BATCH {
+                return Ok(deleted);
+            }
+        }
+    }
+
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let mut tx = self.conn_write.begin().await?;
 
@@ -481,7 +1342,7 @@ ON CONFLICT (id) DO NOTHING"#,
 
         // update verification time and reset any failure count
         sqlx::query("UPDATE user_verification SET verified_at = $1, fail_count = 0 WHERE id = $2")
-            .bind(Utc.timestamp_opt(verify_time as i64, 0).unwrap())
+            .bind(pg_timestamp(verify_time)?)
             .bind(id as i64)
             .execute(&self.conn_write)
             .await?;
@@ -525,7 +1386,7 @@ ON CONFLICT (id) DO NOTHING"#,
             .bind(hex::decode(pub_key).ok())
             .fetch_optional(&self.conn)
             .await?
-            .ok_or(error::Error::SqlxError(RowNotFound))
+            .ok_or(error::Error::NotFound)
     }
 
     async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
@@ -545,10 +1406,10 @@ ON CONFLICT (id) DO NOTHING"#,
             ORDER BY v.verified_at ASC, v.failed_at ASC
             LIMIT 1"#;
         sqlx::query_as::<_, VerificationRecord>(query)
-            .bind(Utc.timestamp_opt(before as i64, 0).unwrap())
+            .bind(pg_timestamp(before)?)
             .fetch_optional(&self.conn)
             .await?
-            .ok_or(error::Error::SqlxError(RowNotFound))
+            .ok_or(error::Error::NotFound)
     }
 
     async fn create_account(&self, pub_key: &Keys) -> Result<bool> {
@@ -572,15 +1433,27 @@ ON CONFLICT (id) DO NOTHING"#,
     }
 
     /// Admit account
-    async fn admit_account(&self, pub_key: &Keys, admission_cost: u64) -> Result<()> {
+    async fn admit_account(
+        &self,
+        pub_key: &Keys,
+        admission_cost: u64,
+        reference: Option<&str>,
+    ) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
-        sqlx::query(
-            "UPDATE account SET is_admitted = TRUE, balance = balance - $1 WHERE pubkey = $2",
-        )
-            .bind(admission_cost as i64)
-            .bind(pub_key)
-            .execute(&self.conn_write)
+        let mut tx = self.conn_write.begin().await?;
+        sqlx::query("UPDATE account SET is_admitted = TRUE WHERE pubkey = $1")
+            .bind(&pub_key)
+            .execute(&mut tx)
             .await?;
+        record_balance_change(
+            &mut tx,
+            &pub_key,
+            -(admission_cost as i64),
+            LedgerReason::Admission,
+            reference,
+        )
+        .await?;
+        tx.commit().await?;
         Ok(())
     }
 
@@ -598,7 +1471,7 @@ ON CONFLICT (id) DO NOTHING"#,
             .bind(pub_key)
             .fetch_optional(&self.conn_write)
             .await?
-            .ok_or(error::Error::SqlxError(RowNotFound))?;
+            .ok_or(error::Error::NotFound)?;
 
         Ok((result.0, result.1 as u64))
     }
@@ -609,27 +1482,50 @@ ON CONFLICT (id) DO NOTHING"#,
         pub_key: &Keys,
         positive: bool,
         new_balance: u64,
+        reason: LedgerReason,
+        reference: Option<&str>,
     ) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
-        match positive {
-            true => {
-                sqlx::query("UPDATE account SET balance = balance + $1 WHERE pubkey = $2")
-                    .bind(new_balance as i64)
-                    .bind(pub_key)
-                    .execute(&self.conn_write)
-                    .await?
-            }
-            false => {
-                sqlx::query("UPDATE account SET balance = balance - $1 WHERE pubkey = $2")
-                    .bind(new_balance as i64)
-                    .bind(pub_key)
-                    .execute(&self.conn_write)
-                    .await?
-            }
+        let delta = if positive {
+            new_balance as i64
+        } else {
+            -(new_balance as i64)
         };
+        let mut tx = self.conn_write.begin().await?;
+        record_balance_change(&mut tx, &pub_key, delta, reason, reference).await?;
+        tx.commit().await?;
         Ok(())
     }
 
+    async fn published_event_count(&self, pub_key: &Keys) -> Result<u64> {
+        let count: Option<i64> =
+            sqlx::query_scalar("SELECT event_count FROM account WHERE pubkey = $1")
+                .bind(pub_key.public_key().to_string())
+                .fetch_optional(&self.conn)
+                .await?;
+        Ok(count.map_or(0, |c| c as u64))
+    }
+
+    async fn count_published_event(&self, pub_key: &Keys) -> Result<u64> {
+        let count: Option<i64> = sqlx::query_scalar(
+            "UPDATE account SET event_count = event_count + 1 WHERE pubkey = $1 RETURNING event_count",
+        )
+        .bind(pub_key.public_key().to_string())
+        .fetch_optional(&self.conn_write)
+        .await?;
+        Ok(count.map_or(0, |c| c as u64))
+    }
+
+    /// Get the most recent ledger entries for an account
+    async fn get_ledger(&self, pub_key: &Keys, limit: Option<u64>) -> Result<Vec<LedgerEntry>> {
+        ledger_entries(&self.conn, &pub_key.public_key().to_string(), limit).await
+    }
+
+    /// Find accounts whose balance differs from their ledger
+    async fn verify_ledger(&self) -> Result<Vec<LedgerMismatch>> {
+        ledger_mismatches(&self.conn).await
+    }
+
     /// Create invoice record
     async fn create_invoice_record(&self, pub_key: &Keys, invoice_info: InvoiceInfo) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
@@ -645,88 +1541,793 @@ ON CONFLICT (id) DO NOTHING"#,
             .bind(invoice_info.memo)
             .bind(invoice_info.bolt11)
             .execute(&mut tx)
-            .await.unwrap();
+            .await?;
 
         debug!("Invoice added");
 
-        tx.commit().await?;
-        Ok(())
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Update invoice record
+    async fn update_invoice(&self, payment_hash: &str, status: InvoiceStatus) -> Result<String> {
+        debug!("Payment Hash: {}", payment_hash);
+        let mut tx = self.conn_write.begin().await?;
+        // read the status as text, so a value this build does not know
+        // about is an error rather than a decode failure
+        let query =
+            "SELECT pubkey, status::text, amount FROM invoice WHERE payment_hash=$1 FOR UPDATE;";
+        let (pubkey, prev_invoice_status, amount) =
+            sqlx::query_as::<_, (String, String, i64)>(query)
+                .bind(payment_hash)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(error::Error::SqlxError(RowNotFound))?;
+        let prev_invoice_status: InvoiceStatus = prev_invoice_status.parse()?;
+
+        // If the invoice is paid update the confirmed at timestamp
+        let query = if status.eq(&InvoiceStatus::Paid) {
+            "UPDATE invoice SET status=$1, confirmed_at = now() WHERE payment_hash=$2;"
+        } else {
+            "UPDATE invoice SET status=$1 WHERE payment_hash=$2;"
+        };
+
+        sqlx::query(query)
+            .bind(&status)
+            .bind(payment_hash)
+            .execute(&mut tx)
+            .await?;
+
+        if prev_invoice_status.eq(&InvoiceStatus::Unpaid) && status.eq(&InvoiceStatus::Paid) {
+            record_balance_change(
+                &mut tx,
+                &pubkey,
+                amount,
+                LedgerReason::Payment,
+                Some(payment_hash),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(pubkey)
+    }
+
+    /// Get the most recent invoice for a given pubkey
+    /// invoice must be unpaid and not expired
+    async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>> {
+        let query = r#"
+SELECT amount, payment_hash, description, invoice
+FROM invoice
+WHERE pubkey = $1
+ORDER BY created_at DESC
+LIMIT 1;
+        "#;
+        match sqlx::query_as::<_, (i64, String, String, String)>(query)
+            .bind(pubkey.public_key().to_string())
+            .fetch_optional(&self.conn_write)
+            .await?
+        {
+            Some((amount, payment_hash, description, invoice)) => Ok(Some(InvoiceInfo {
+                pubkey: pubkey.public_key().to_string(),
+                payment_hash,
+                bolt11: invoice,
+                amount: amount as u64,
+                status: InvoiceStatus::Unpaid,
+                memo: description,
+                confirmed_at: None,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Get all unpaid invoices created at or after `since`
+    async fn get_unpaid_invoices(&self, since: u64) -> Result<Vec<InvoiceInfo>> {
+        let query = r#"
+SELECT pubkey, payment_hash, invoice, amount, description
+FROM invoice
+WHERE status = 'Unpaid' AND created_at >= to_timestamp($1)::timestamp
+ORDER BY created_at;
+        "#;
+        let rows = sqlx::query(query)
+            .bind(since as i64)
+            .fetch_all(&self.conn_write)
+            .await?;
+        // one bad row should not stop the others being checked
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                invoice_from_row(row)
+                    .map_err(|e| warn!("skipping unreadable invoice: {e}"))
+                    .ok()
+            })
+            .collect())
+    }
+
+    async fn confirm_invoice(&self, payment_hash: &str) -> Result<Option<u64>> {
+        // invoice times are stored without a time zone, in the
+        // session's, so are converted back before taking the epoch
+        let row: Option<(Option<i64>,)> = sqlx::query_as(
+            "UPDATE invoice SET confirmed_at = now() WHERE payment_hash = $1 AND confirmed_at IS NULL RETURNING EXTRACT(EPOCH FROM created_at::timestamptz)::bigint",
+        )
+        .bind(payment_hash)
+        .fetch_optional(&self.conn_write)
+        .await?;
+        Ok(row.map(|(created_at,)| created_at.unwrap_or_default() as u64))
+    }
+
+    async fn get_payment_stats(&self, unpaid_since: u64) -> Result<PaymentStats> {
+        let (unpaid_invoices, sats_collected): (i64, i64) = sqlx::query_as(
+            r#"SELECT COUNT(*) FILTER (WHERE status = 'Unpaid' AND created_at::timestamptz >= to_timestamp($1)),
+            COALESCE(SUM(amount) FILTER (WHERE status = 'Paid'), 0)::bigint FROM invoice"#,
+        )
+        .bind(unpaid_since as i64)
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(PaymentStats {
+            unpaid_invoices: unpaid_invoices as u64,
+            sats_collected: sats_collected as u64,
+        })
+    }
+
+    async fn get_watermark(&self, pubkey: &str, sub_key: &str) -> Result<Option<u64>> {
+        let row: Option<(i64,)> = sqlx::query_as(
+            "SELECT created_at FROM subscription_watermark WHERE pubkey = $1 AND sub_key = $2",
+        )
+        .bind(pubkey)
+        .bind(sub_key)
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(row.map(|(created_at,)| created_at as u64))
+    }
+
+    async fn save_watermark(&self, pubkey: &str, sub_key: &str, created_at: u64) -> Result<()> {
+        // a watermark never moves backwards
+        sqlx::query(
+            r#"INSERT INTO subscription_watermark (pubkey, sub_key, created_at) VALUES ($1, $2, $3)
+            ON CONFLICT (pubkey, sub_key) DO UPDATE
+            SET created_at = GREATEST(subscription_watermark.created_at, excluded.created_at), updated_at = now()"#,
+        )
+        .bind(pubkey)
+        .bind(sub_key)
+        .bind(created_at as i64)
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn add_event_stats(&self, stats: &[EventStat]) -> Result<()> {
+        let mut tx = self.conn_write.begin().await?;
+        for s in stats {
+            sqlx::query(
+                r#"INSERT INTO event_stats (pubkey, kind, day, count, bytes) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (day, kind, pubkey) DO UPDATE
+                SET count = event_stats.count + excluded.count, bytes = event_stats.bytes + excluded.bytes"#,
+            )
+            .bind(&s.pubkey)
+            .bind(s.kind as i64)
+            .bind(s.day)
+            .bind(s.count as i64)
+            .bind(s.bytes as i64)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn top_writers(
+        &self,
+        day: NaiveDate,
+        kind: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<TopWriter>> {
+        top_writers(&self.conn, day, kind, limit).await
+    }
+
+    async fn latest_created_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        // answered from the (pub_key, created_at) index
+        let latest: Option<i64> = sqlx::query_scalar(
+            "SELECT EXTRACT(EPOCH FROM MAX(created_at))::BIGINT FROM \"event\" WHERE pub_key = $1",
+        )
+        .bind(hex::decode(pubkey)?)
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(latest.map(|secs| secs as u64))
+    }
+
+    async fn get_pubkey_status(&self, pubkey: &str) -> Result<Option<PubkeyStatus>> {
+        let status: Option<String> =
+            sqlx::query_scalar("SELECT status FROM first_seen_pubkey WHERE pubkey = $1")
+                .bind(pubkey)
+                .fetch_optional(&self.conn)
+                .await?;
+        status.map(|s| s.parse()).transpose()
+    }
+
+    async fn add_first_seen_pubkey(
+        &self,
+        pubkey: &str,
+        first_seen: u64,
+        status: PubkeyStatus,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO first_seen_pubkey (pubkey, first_seen, status) VALUES ($1, $2, $3) ON CONFLICT (pubkey) DO NOTHING",
+        )
+        .bind(pubkey)
+        .bind(first_seen as i64)
+        .bind(status.as_str())
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn count_quarantined_event(&self, pubkey: &str) -> Result<u64> {
+        let count: Option<i64> = sqlx::query_scalar(
+            "UPDATE first_seen_pubkey SET event_count = event_count + 1 WHERE pubkey = $1 AND status = 'quarantined' RETURNING event_count",
+        )
+        .bind(pubkey)
+        .fetch_optional(&self.conn_write)
+        .await?;
+        Ok(count.map_or(0, |c| c as u64))
+    }
+
+    async fn set_pubkey_status(&self, pubkey: &str, status: PubkeyStatus) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO first_seen_pubkey (pubkey, first_seen, status) VALUES ($1, $2, $3)
+            ON CONFLICT (pubkey) DO UPDATE SET status = excluded.status"#,
+        )
+        .bind(pubkey)
+        .bind(utils::unix_time() as i64)
+        .bind(status.as_str())
+        .execute(&self.conn_write)
+        .await?;
+        Ok(())
+    }
+
+    async fn release_quarantined(&self, first_seen_before: u64) -> Result<Vec<String>> {
+        let released: Vec<String> = sqlx::query_scalar(
+            "UPDATE first_seen_pubkey SET status = 'released' WHERE status = 'quarantined' AND first_seen < $1 RETURNING pubkey",
+        )
+        .bind(first_seen_before as i64)
+        .fetch_all(&self.conn_write)
+        .await?;
+        Ok(released)
+    }
+
+    async fn withheld_pubkeys(&self) -> Result<Vec<QuarantinedPubkey>> {
+        let rows: Vec<(String, i64, i64, String)> = sqlx::query_as(
+            "SELECT pubkey, first_seen, event_count, status FROM first_seen_pubkey WHERE status IN ('quarantined', 'banned') ORDER BY first_seen, pubkey",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        rows.into_iter()
+            .map(|(pubkey, first_seen, event_count, status)| {
+                Ok(QuarantinedPubkey {
+                    pubkey,
+                    first_seen: first_seen as u64,
+                    event_count: event_count as u64,
+                    status: status.parse()?,
+                })
+            })
+            .collect()
+    }
+
+    async fn write_pending_event(&self, e: &Event) -> Result<u64> {
+        retry_transient(
+            &self.metrics,
+            self.write_attempts,
+            is_transient_pg_error,
+            || self.write_event_once(e, true),
+        )
+        .await
+        .map(|written| written.rows_added())
+    }
+
+    async fn resolve_pending_event(&self, event_id: &str, accepted: bool) -> Result<bool> {
+        let id_blob = hex::decode(event_id)?;
+        let query = if accepted {
+            "UPDATE \"event\" SET hidden = 0::bit(1), pending = FALSE WHERE id = $1 AND pending"
+        } else {
+            "DELETE FROM \"event\" WHERE id = $1 AND pending"
+        };
+        let count = sqlx::query(query)
+            .bind(id_blob)
+            .execute(&self.conn_write)
+            .await?
+            .rows_affected();
+        Ok(count > 0)
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<Event>> {
+        let rows: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT \"content\" FROM \"event\" WHERE pending ORDER BY first_seen",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        rows.iter()
+            .map(|content| Ok(serde_json::from_slice(content)?))
+            .collect()
+    }
+
+    async fn export_events_by(&self, pubkey: &str, tx: Sender<UserRecord>) -> Result<u64> {
+        let rows = sqlx::query_scalar(
+            "SELECT convert_from(\"content\", 'UTF8') FROM \"event\" WHERE pub_key = $1 ORDER BY created_at",
+        )
+        .bind(hex::decode(pubkey)?)
+        .fetch(&self.conn);
+        send_rows(rows, UserTable::Events, &tx).await
+    }
+
+    async fn export_account_data(&self, pubkey: &str, tx: Sender<UserRecord>) -> Result<()> {
+        let verifications = sqlx::query_scalar(
+            r#"SELECT json_build_object(
+                'metadata_event', encode(e.id, 'hex'),
+                'name', v."name",
+                'verified_at', EXTRACT(EPOCH FROM v.verified_at)::BIGINT,
+                'failed_at', EXTRACT(EPOCH FROM v.failed_at)::BIGINT,
+                'failure_count', v.fail_count)::text
+            FROM user_verification v
+            INNER JOIN "event" e ON e.id = v.event_id
+            WHERE e.pub_key = $1
+            ORDER BY v.id"#,
+        )
+        .bind(hex::decode(pubkey)?)
+        .fetch(&self.conn);
+        send_rows(verifications, UserTable::Verifications, &tx).await?;
+        let account = sqlx::query_scalar(
+            r#"SELECT json_build_object(
+                'pubkey', pubkey,
+                'is_admitted', is_admitted,
+                'balance', balance,
+                'tos_accepted_at', EXTRACT(EPOCH FROM tos_accepted_at)::BIGINT)::text
+            FROM "account"
+            WHERE pubkey = $1"#,
+        )
+        .bind(pubkey)
+        .fetch(&self.conn);
+        send_rows(account, UserTable::Account, &tx).await?;
+        let invoices = sqlx::query_scalar(
+            r#"SELECT json_build_object(
+                'payment_hash', payment_hash,
+                'bolt11', invoice,
+                'amount', amount,
+                'status', status::text,
+                'memo', description,
+                'created_at', EXTRACT(EPOCH FROM created_at)::BIGINT,
+                'confirmed_at', EXTRACT(EPOCH FROM confirmed_at)::BIGINT)::text
+            FROM "invoice"
+            WHERE pubkey = $1
+            ORDER BY created_at"#,
+        )
+        .bind(pubkey)
+        .fetch(&self.conn);
+        send_rows(invoices, UserTable::Invoices, &tx).await?;
+        let ledger = sqlx::query_scalar(
+            r#"SELECT json_build_object(
+                'delta', delta,
+                'balance', balance,
+                'reason', reason,
+                'reference', reference,
+                'created_at', EXTRACT(EPOCH FROM created_at)::BIGINT)::text
+            FROM ledger
+            WHERE pubkey = $1
+            ORDER BY id"#,
+        )
+        .bind(pubkey)
+        .fetch(&self.conn);
+        send_rows(ledger, UserTable::Ledger, &tx).await?;
+        Ok(())
+    }
+
+    async fn erase_events_by(&self, pubkey: &str) -> Result<u64> {
+        let author = hex::decode(pubkey)?;
+        let query = if self.purge_delay > 0 {
+            sqlx::query(
+                "UPDATE \"event\" SET hidden = 1::bit(1), purge_after = $2 WHERE pub_key = $1 AND purge_after IS NULL",
+            )
+            .bind(author)
+            .bind(purge_time(self.purge_delay))
+        } else {
+            sqlx::query("DELETE FROM \"event\" WHERE pub_key = $1").bind(author)
+        };
+        Ok(query.execute(&self.conn_write).await?.rows_affected())
+    }
+
+    async fn erase_account_data(
+        &self,
+        pubkey: &str,
+        erased_at: u64,
+        tombstone_until: Option<u64>,
+    ) -> Result<UserDataCounts> {
+        let mut tx = self.conn_write.begin().await?;
+        let verifications = sqlx::query(
+            "DELETE FROM user_verification WHERE event_id IN (SELECT id FROM \"event\" WHERE pub_key = $1)",
+        )
+        .bind(hex::decode(pubkey)?)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        let invoices = sqlx::query("DELETE FROM invoice WHERE pubkey = $1")
+            .bind(pubkey)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        // the account and its ledger stay, so balances still reconcile,
+        // but admission and terms acceptance are withdrawn
+        let account = sqlx::query(
+            "UPDATE account SET is_admitted = FALSE, tos_accepted_at = NULL WHERE pubkey = $1",
+        )
+        .bind(pubkey)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        if let Some(expires_at) = tombstone_until {
+            sqlx::query(
+                r#"INSERT INTO erased_pubkey (pubkey, erased_at, expires_at) VALUES ($1, $2, $3)
+                ON CONFLICT (pubkey) DO UPDATE SET erased_at = excluded.erased_at, expires_at = excluded.expires_at"#,
+            )
+            .bind(pubkey)
+            .bind(erased_at as i64)
+            .bind(expires_at as i64)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(UserDataCounts {
+            events: 0,
+            verifications,
+            invoices,
+            ledger: 0,
+            account,
+        })
+    }
+
+    async fn erased_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        let erased_at: Option<i64> = sqlx::query_scalar(
+            "SELECT erased_at FROM erased_pubkey WHERE pubkey = $1 AND expires_at > $2",
+        )
+        .bind(pubkey)
+        .bind(utils::unix_time() as i64)
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(erased_at.map(|t| t as u64))
+    }
+}
+
+/// Pass the JSON rows of a query on to an export, failing if the export
+/// was abandoned.  Returns rows sent.
+async fn send_rows<S>(mut rows: S, table: UserTable, tx: &Sender<UserRecord>) -> Result<u64>
+where
+    S: Stream<Item = std::result::Result<String, Error>> + Unpin,
+{
+    let mut sent = 0;
+    while let Some(json) = rows.next().await {
+        tx.send(UserRecord { table, json: json? })
+            .await
+            .map_err(|_| error::Error::CustomError("export abandoned".to_owned()))?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Decode an unpaid invoice row
+fn invoice_from_row(row: &PgRow) -> std::result::Result<InvoiceInfo, Error> {
+    Ok(InvoiceInfo {
+        pubkey: row.try_get(0)?,
+        payment_hash: row.try_get(1)?,
+        bolt11: row.try_get(2)?,
+        amount: row.try_get::<i64, _>(3)? as u64,
+        status: InvoiceStatus::Unpaid,
+        memo: row.try_get::<Option<String>, _>(4)?.unwrap_or_default(),
+        confirmed_at: None,
+    })
+}
+
+/// Mark a paid invoice as refunded, and take the payment back out of
+/// the account balance.  The debit is capped at the current balance,
+/// so an account never goes negative for funds it already spent.
+pub async fn refund_invoice(db: &PostgresPool, payment_hash: &str) -> Result<InvoiceRefund> {
+    let mut tx = db.begin().await?;
+    let (pubkey, status, amount) = sqlx::query_as::<_, (String, String, i64)>(
+        "SELECT pubkey, status::text, amount FROM invoice WHERE payment_hash = $1 FOR UPDATE",
+    )
+    .bind(payment_hash)
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| {
+        error::Error::CustomError(format!("no invoice with payment hash {payment_hash}"))
+    })?;
+    let status: InvoiceStatus = status.parse()?;
+    if status != InvoiceStatus::Paid {
+        return Err(error::Error::CustomError(format!(
+            "only paid invoices can be refunded (invoice is {status})"
+        )));
+    }
+    sqlx::query("UPDATE invoice SET status = $1 WHERE payment_hash = $2")
+        .bind(InvoiceStatus::Refunded)
+        .bind(payment_hash)
+        .execute(&mut tx)
+        .await?;
+    let balance =
+        sqlx::query_as::<_, (i64,)>("SELECT balance FROM account WHERE pubkey = $1 FOR UPDATE")
+            .bind(&pubkey)
+            .fetch_optional(&mut tx)
+            .await?
+            .map_or(0, |(balance,)| balance);
+    let debited = amount.min(balance).max(0);
+    if debited > 0 {
+        record_balance_change(
+            &mut tx,
+            &pubkey,
+            -debited,
+            LedgerReason::Refund,
+            Some(payment_hash),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(InvoiceRefund {
+        payment_hash: payment_hash.to_owned(),
+        pubkey,
+        amount: amount as u64,
+        debited: debited as u64,
+    })
+}
+
+/// Apply a change to an account balance, and record it in the ledger,
+/// as part of the given transaction.  Unknown accounts are left
+/// untouched.
+async fn record_balance_change(
+    tx: &mut Transaction<'_, Postgres>,
+    pubkey: &str,
+    delta: i64,
+    reason: LedgerReason,
+    reference: Option<&str>,
+) -> Result<()> {
+    let balance = sqlx::query_as::<_, (i64,)>(
+        "UPDATE account SET balance = balance + $1 WHERE pubkey = $2 RETURNING balance",
+    )
+    .bind(delta)
+    .bind(pubkey)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some((balance,)) = balance {
+        sqlx::query(
+            "INSERT INTO ledger (pubkey, delta, balance, reason, reference) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(pubkey)
+        .bind(delta)
+        .bind(balance)
+        .bind(reason.as_str())
+        .bind(reference)
+        .execute(&mut *tx)
+        .await?;
     }
+    Ok(())
+}
 
-    /// Update invoice record
-    async fn update_invoice(&self, payment_hash: &str, status: InvoiceStatus) -> Result<String> {
-        debug!("Payment Hash: {}", payment_hash);
-        let query = "SELECT pubkey, status, amount FROM invoice WHERE payment_hash=$1;";
-        let (pubkey, prev_invoice_status, amount) =
-            sqlx::query_as::<_, (String, InvoiceStatus, i64)>(query)
-                .bind(payment_hash)
-                .fetch_optional(&self.conn_write)
-                .await?
-                .ok_or(error::Error::SqlxError(RowNotFound))?;
+/// Ledger entries for an account, newest first.
+pub async fn ledger_entries(
+    db: &PostgresPool,
+    pubkey: &str,
+    limit: Option<u64>,
+) -> Result<Vec<LedgerEntry>> {
+    let query = r#"SELECT
+        delta,
+        balance,
+        reason,
+        reference,
+        EXTRACT(EPOCH FROM created_at)::BIGINT
+        FROM ledger
+        WHERE pubkey = $1
+        ORDER BY id DESC
+        LIMIT $2"#;
+    let rows = sqlx::query_as::<_, (i64, i64, String, Option<String>, i64)>(query)
+        .bind(pubkey)
+        .bind(limit.map(|l| l as i64))
+        .fetch_all(db)
+        .await?;
+    rows.into_iter()
+        .map(|(delta, balance, reason, reference, created_at)| {
+            Ok(LedgerEntry {
+                pubkey: pubkey.to_owned(),
+                delta,
+                balance,
+                reason: reason.parse()?,
+                reference,
+                created_at: created_at as u64,
+            })
+        })
+        .collect()
+}
 
-        // If the invoice is paid update the confirmed at timestamp
-        let query = if status.eq(&InvoiceStatus::Paid) {
-            "UPDATE invoice SET status=$1, confirmed_at = now() WHERE payment_hash=$2;"
-        } else {
-            "UPDATE invoice SET status=$1 WHERE payment_hash=$2;"
-        };
+/// The authors that wrote the most events on a day, of one kind or of
+/// all kinds.
+pub async fn top_writers(
+    db: &PostgresPool,
+    day: NaiveDate,
+    kind: Option<u64>,
+    limit: u64,
+) -> Result<Vec<TopWriter>> {
+    let query = r#"SELECT pubkey, SUM(count)::BIGINT AS total, SUM(bytes)::BIGINT
+        FROM event_stats
+        WHERE day = $1 AND ($2::BIGINT IS NULL OR kind = $2)
+        GROUP BY pubkey
+        ORDER BY total DESC, pubkey
+        LIMIT $3"#;
+    let rows = sqlx::query_as::<_, (String, i64, i64)>(query)
+        .bind(day)
+        .bind(kind.map(|k| k as i64))
+        .bind(limit as i64)
+        .fetch_all(db)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(pubkey, count, bytes)| TopWriter {
+            pubkey,
+            count: count as u64,
+            bytes: bytes as u64,
+        })
+        .collect())
+}
 
-        sqlx::query(query)
-            .bind(&status)
-            .bind(payment_hash)
-            .execute(&self.conn_write)
-            .await?;
+/// Accounts whose stored balance is not the sum of their ledger.
+pub async fn ledger_mismatches(db: &PostgresPool) -> Result<Vec<LedgerMismatch>> {
+    let query = r#"SELECT a.pubkey, a.balance, COALESCE(SUM(l.delta), 0)::BIGINT
+        FROM account a LEFT JOIN ledger l ON l.pubkey = a.pubkey
+        GROUP BY a.pubkey, a.balance
+        HAVING a.balance != COALESCE(SUM(l.delta), 0)
+        ORDER BY a.pubkey"#;
+    let rows = sqlx::query_as::<_, (String, i64, i64)>(query)
+        .fetch_all(db)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(pubkey, balance, ledger_balance)| LedgerMismatch {
+            pubkey,
+            balance,
+            ledger_balance,
+        })
+        .collect())
+}
 
-        if prev_invoice_status.eq(&InvoiceStatus::Unpaid) && status.eq(&InvoiceStatus::Paid) {
-            sqlx::query("UPDATE account SET balance = balance + $1 WHERE pubkey = $2")
-                .bind(amount)
-                .bind(&pubkey)
-                .execute(&self.conn_write)
-                .await?;
-        }
+/// Size of the database, and of each table and index in the current
+/// schema.  Row counts are the statistics collector's estimates.
+pub async fn database_size(db: &PostgresPool) -> Result<DatabaseSize> {
+    let query = r#"SELECT c.relname::TEXT, c.relkind = 'i',
+        CASE WHEN c.relkind = 'i' THEN pg_relation_size(c.oid) ELSE pg_table_size(c.oid) END,
+        s.n_live_tup, s.n_dead_tup
+        FROM pg_class c
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        LEFT JOIN pg_stat_user_tables s ON s.relid = c.oid
+        WHERE n.nspname = current_schema() AND c.relkind IN ('r', 'i')
+        ORDER BY c.relname"#;
+    let rows = sqlx::query_as::<_, (String, bool, i64, Option<i64>, Option<i64>)>(query)
+        .fetch_all(db)
+        .await?;
+    let (total,): (i64,) = sqlx::query_as("SELECT pg_database_size(current_database())")
+        .fetch_one(db)
+        .await?;
+    Ok(DatabaseSize {
+        total_bytes: total as u64,
+        relations: rows
+            .into_iter()
+            .map(|(name, is_index, bytes, rows, dead_rows)| RelationSize {
+                name,
+                is_index,
+                rows: rows.map(|r| r as u64),
+                dead_rows: dead_rows.map(|r| r as u64),
+                bytes: bytes as u64,
+            })
+            .collect(),
+    })
+}
 
-        Ok(pubkey)
-    }
+/// Vacuum and analyze every table.  A plain `VACUUM` runs alongside
+/// reads and writes; `VACUUM FULL` takes an exclusive lock on each
+/// table while rewriting it, but returns space to the OS.
+pub async fn compact(db: &PostgresPool, full: bool) -> Result<()> {
+    let sql = if full {
+        "VACUUM (FULL, VERBOSE, ANALYZE);"
+    } else {
+        "VACUUM (VERBOSE, ANALYZE);"
+    };
+    // VACUUM cannot run inside a transaction, so use the simple
+    // query protocol directly.
+    db.execute(sql).await?;
+    Ok(())
+}
 
-    /// Get the most recent invoice for a given pubkey
-    /// invoice must be unpaid and not expired
-    async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>> {
-        let query = r#"
-SELECT amount, payment_hash, description, invoice
-FROM invoice
-WHERE pubkey = $1
-ORDER BY created_at DESC
-LIMIT 1;
-        "#;
-        match sqlx::query_as::<_, (i64, String, String, String)>(query)
-            .bind(pubkey.public_key().to_string())
-            .fetch_optional(&self.conn_write)
-            .await
-            .unwrap()
-        {
-            Some((amount, payment_hash, description, invoice)) => Ok(Some(InvoiceInfo {
-                pubkey: pubkey.public_key().to_string(),
-                payment_hash,
-                bolt11: invoice,
-                amount: amount as u64,
-                status: InvoiceStatus::Unpaid,
-                memo: description,
-                confirmed_at: None,
-            })),
-            None => Ok(None),
-        }
-    }
+/// Rebuild an index.  With `concurrently`, a replacement is built
+/// alongside the old index without blocking reads or writes; otherwise
+/// writes to the table wait until the rebuild completes.
+pub async fn reindex(db: &PostgresPool, name: &str, concurrently: bool) -> Result<()> {
+    let sql = format!(
+        "REINDEX INDEX {}\"{}\";",
+        if concurrently { "CONCURRENTLY " } else { "" },
+        name.replace('"', "\"\"")
+    );
+    // REINDEX CONCURRENTLY cannot run inside a transaction
+    db.execute(sql.as_str()).await?;
+    Ok(())
 }
 
 /// Create a dynamic SQL query and params from a subscription filter.
 fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
+    filter_query(f, "")
+}
+
+/// Create the query for a subscription filter, after `prefix` (such
+/// as `EXPLAIN `).
+fn filter_query<'a>(f: &'a ReqFilter, prefix: &str) -> Option<QueryBuilder<'a, Postgres>> {
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
         return None;
     }
 
-    let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    let mut query = QueryBuilder::new(format!(
+        "{prefix}SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE "
+    ));
+
+    // fetches of complete ids are primary key lookups, which return at
+    // most one row per id, so they need no sorting or result cap.
+    if f.is_direct_fetch() {
+        let ids: Vec<Vec<u8>> = f
+            .ids
+            .iter()
+            .flatten()
+            .filter_map(|id| hex::decode(id).ok())
+            .collect();
+        query.push("e.id = ANY(");
+        query.push_bind(ids);
+        query
+            .push(") AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now())");
+        return Some(query);
+    }
+
+    let push_and = push_filter_conditions(&mut query, f)?;
+
+    // never display hidden events
+    if push_and {
+        query.push(" AND e.hidden != 1::bit(1)");
+    } else {
+        query.push("e.hidden != 1::bit(1)");
+    }
+    // never display expired events
+    query.push(" AND (e.expires_at IS NULL OR e.expires_at > now())");
+
+    // Apply per-filter limit to this query.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        query.push(" ORDER BY e.created_at DESC LIMIT ");
+        query.push(lim.min(1000));
+    } else {
+        query.push(" ORDER BY e.created_at ASC LIMIT ");
+        query.push(1000);
+    }
+    Some(query)
+}
+
+/// Query for every event a filter matches (hidden ones too), after
+/// `head`.  `None` if no event can match.
+fn matching_query<'a>(f: &'a ReqFilter, head: &str) -> Option<QueryBuilder<'a, Postgres>> {
+    if f.force_no_match {
+        return None;
+    }
+    let mut query = QueryBuilder::new(head);
+    if !push_filter_conditions(&mut query, f)? {
+        query.push("TRUE");
+    }
+    Some(query)
+}
 
+/// Add the conditions of a filter to a query, joined by `AND`.
+/// Returns whether any were added, or `None` if no event can match.
+fn push_filter_conditions<'a>(
+    query: &mut QueryBuilder<'a, Postgres>,
+    f: &'a ReqFilter,
+) -> Option<bool> {
     // This tracks whether we need to push a prefix AND before adding another clause
     let mut push_and = false;
     // Query for "authors", allowing prefix matches
@@ -812,6 +2413,23 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         }
     }
 
+    // Query for kinds to leave out (the _excludeKinds extension)
+    if let Some(ks) = &f.exclude_kinds {
+        if !ks.is_empty() {
+            if push_and {
+                query.push(" AND ");
+            }
+            push_and = true;
+
+            query.push("e.kind NOT IN (");
+            let mut list_query = query.separated(", ");
+            for k in ks.iter() {
+                list_query.push_bind(*k as i64);
+            }
+            query.push(")");
+        }
+    }
+
     // Query for event, allowing prefix matches
     if let Some(id_vec) = &f.ids {
         // filter out non-hex values
@@ -870,89 +2488,113 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         }
     }
 
-    // Query for tags
+    // Query for tags.  Each tag name must match (AND), with any of
+    // its values matching (OR).
     if let Some(map) = &f.tags {
-        if !map.is_empty() {
+        // sort tag names, so the generated SQL is stable
+        let mut keys: Vec<&char> = map.keys().collect();
+        keys.sort();
+        for key in keys {
+            let val = &map[key];
             if push_and {
                 query.push(" AND ");
             }
             push_and = true;
 
-            for (key, val) in map.iter() {
-                query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
-                    .push_bind(key.to_string())
-                    .push(" AND (");
-
-                let has_plain_values = val.iter().any(|v| !is_lower_hex(v));
-                let has_hex_values = val.iter().any(|v| v.len() % 2 == 0 && is_lower_hex(v));
-                if has_plain_values {
-                    query.push("value in (");
-                    // plain value match first
-                    let mut tag_query = query.separated(", ");
-                    for v in val.iter()
-                        .filter(|v| !is_lower_hex(v)) {
-                        tag_query.push_bind(v.as_bytes());
-                    }
-                }
-                if has_plain_values && has_hex_values {
-                    query.push(") OR ");
+            // values are stored as blobs if they are lowercase hex
+            // with an even length, and as text otherwise.
+            let is_hex_value = |v: &&String| v.len() % 2 == 0 && is_lower_hex(v);
+            let has_plain_values = val.iter().any(|v| !is_hex_value(&v));
+            let has_hex_values = val.iter().any(|v| is_hex_value(&v));
+            if val.is_empty() {
+                // no value can match this tag
+                query.push("false");
+                continue;
+            }
+            query.push("e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = ")
+                .push_bind(key.to_string())
+                .push(" AND (");
+            if has_plain_values {
+                query.push("value in (");
+                // plain value match first
+                let mut tag_query = query.separated(", ");
+                for v in val.iter().filter(|v| !is_hex_value(v)) {
+                    tag_query.push_bind(v.as_bytes());
                 }
-                if has_hex_values {
-                    query.push("value_hex in (");
-                    // plain value match first
-                    let mut tag_query = query.separated(", ");
-                    for v in val.iter()
-                        .filter(|v| v.len() % 2 == 0 && is_lower_hex(v)) {
-                        tag_query.push_bind(hex::decode(v).ok());
-                    }
+            }
+            if has_plain_values && has_hex_values {
+                query.push(") OR ");
+            }
+            if has_hex_values {
+                query.push("value_hex in (");
+                // plain value match first
+                let mut tag_query = query.separated(", ");
+                for v in val.iter().filter(is_hex_value) {
+                    tag_query.push_bind(hex::decode(v).ok());
                 }
-
-                query.push("))))");
             }
+
+            query.push("))))");
         }
     }
 
     // Query for timestamp
-    if f.since.is_some() {
+    // (no event can be newer than an unrepresentable time)
+    if let Some(since) = f.since {
         if push_and {
             query.push(" AND ");
         }
         push_and = true;
         query
             .push("e.created_at >= ")
-            .push_bind(Utc.timestamp_opt(f.since.unwrap() as i64, 0).unwrap());
+            .push_bind(pg_timestamp(since).ok()?);
     }
 
     // Query for timestamp
-    if f.until.is_some() {
+    // (and every event is older than one)
+    if let Some(until) = f.until.and_then(|until| pg_timestamp(until).ok()) {
         if push_and {
             query.push(" AND ");
         }
         push_and = true;
-        query
-            .push("e.created_at <= ")
-            .push_bind(Utc.timestamp_opt(f.until.unwrap() as i64, 0).unwrap());
+        query.push("e.created_at <= ").push_bind(until);
     }
 
-    // never display hidden events
-    if push_and {
-        query.push(" AND e.hidden != 1::bit(1)");
-    } else {
-        query.push("e.hidden != 1::bit(1)");
+    // Query for the time the relay first saw the event
+    if let Some(received_since) = f.first_seen_since() {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query
+            .push("e.first_seen >= ")
+            .push_bind(pg_timestamp(received_since).ok()?);
     }
-    // never display expired events
-    query.push(" AND (e.expires_at IS NULL OR e.expires_at > now())");
+    Some(push_and)
+}
 
-    // Apply per-filter limit to this query.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        query.push(" ORDER BY e.created_at DESC LIMIT ");
-        query.push(lim.min(1000));
-    } else {
-        query.push(" ORDER BY e.created_at ASC LIMIT ");
-        query.push(1000);
+/// Tables (`event` or `tag`) that an `EXPLAIN` plan reads with a
+/// sequential scan.
+fn seq_scanned_tables(plan: &[String]) -> Vec<&'static str> {
+    let mut tables = vec![];
+    for line in plan {
+        let Some((_, scanned)) = line.split_once("Seq Scan on ") else {
+            continue;
+        };
+        // the table may be schema qualified
+        let name = scanned.split_whitespace().next().unwrap_or_default();
+        let name = name
+            .rsplit('.')
+            .next()
+            .unwrap_or_default()
+            .trim_matches('"');
+        for table in ["event", "tag"] {
+            if name == table && !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
     }
-    Some(query)
+    tables
 }
 
 impl FromRow<'_, PgRow> for VerificationRecord {
@@ -977,29 +2619,82 @@ impl FromRow<'_, PgRow> for VerificationRecord {
     }
 }
 
-
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashMap, HashSet};
     use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicU32, Ordering};
 
     #[test]
     fn test_query_gen_tag_value_hex() {
         let filter = ReqFilter {
             ids: None,
             kinds: Some(vec![1000]),
+            exclude_kinds: None,
             since: None,
             until: None,
-            authors: Some(vec!["84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned()]),
+            authors: Some(vec![
+                "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned(),
+            ]),
             limit: None,
-            tags: Some(HashMap::from([
-                ('p', HashSet::from(["63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned()]))
-            ])),
+            tags: Some(HashMap::from([(
+                'p',
+                HashSet::from([
+                    "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned(),
+                ]),
+            )])),
+            received_since: None,
+            resume_from: None,
+            progress: None,
+            force_no_match: false,
+        };
+
+        let q = query_from_filter(&filter).unwrap();
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value_hex in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+    }
+
+    #[test]
+    fn test_query_gen_direct_fetch() {
+        let filter = ReqFilter {
+            ids: Some(vec!["aa".repeat(32), "bb".repeat(32)]),
+            kinds: None,
+            exclude_kinds: None,
+            since: None,
+            until: None,
+            authors: None,
+            limit: Some(5000),
+            tags: None,
+            received_since: None,
+            resume_from: None,
+            progress: None,
             force_no_match: false,
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value_hex in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE e.id = ANY($1) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now())");
+        let explain = filter_query(&filter, "EXPLAIN ").unwrap();
+        assert_eq!(explain.sql(), format!("EXPLAIN {}", q.sql()));
+    }
+
+    #[test]
+    fn seq_scans_in_plan() {
+        let plan = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<String>>();
+        let indexed = plan(&[
+            "Limit  (cost=0.56..8.58 rows=1 width=8)",
+            "  ->  Index Scan using event_pkey on event e  (cost=0.56..8.58 rows=1 width=8)",
+            "        Index Cond: (id = ANY ($1))",
+        ]);
+        assert!(seq_scanned_tables(&indexed).is_empty());
+        let scanned = plan(&[
+            "Limit  (cost=1000.00..52001.20 rows=1000 width=8)",
+            "  ->  Nested Loop  (cost=1000.00..52001.20 rows=1000 width=8)",
+            "        ->  Parallel Seq Scan on tag t  (cost=0.00..41666.67 rows=4167 width=8)",
+            "              Filter: (value_hex = ANY ($5))",
+            "        ->  Seq Scan on nostr.\"event\" ee  (cost=0.00..4.17 rows=1 width=8)",
+            "        ->  Seq Scan on tag t2  (cost=0.00..4.17 rows=1 width=8)",
+            "        ->  Seq Scan on event_tags x  (cost=0.00..4.17 rows=1 width=8)",
+        ]);
+        assert_eq!(seq_scanned_tables(&scanned), vec!["tag", "event"]);
     }
 
     #[test]
@@ -1007,18 +2702,45 @@ mod tests {
         let filter = ReqFilter {
             ids: None,
             kinds: Some(vec![1000]),
+            exclude_kinds: None,
             since: None,
             until: None,
-            authors: Some(vec!["84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned()]),
+            authors: Some(vec![
+                "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned(),
+            ]),
             limit: None,
-            tags: Some(HashMap::from([
-                ('d', HashSet::from(["test".to_owned()]))
-            ])),
+            tags: Some(HashMap::from([('d', HashSet::from(["test".to_owned()]))])),
+            received_since: None,
+            resume_from: None,
+            progress: None,
+            force_no_match: false,
+        };
+
+        let q = query_from_filter(&filter).unwrap();
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+    }
+
+    #[test]
+    fn test_query_gen_exclude_kinds() {
+        let filter = ReqFilter {
+            ids: None,
+            kinds: Some(vec![1, 7]),
+            exclude_kinds: Some(vec![7]),
+            since: None,
+            until: None,
+            authors: Some(vec![
+                "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned(),
+            ]),
+            limit: None,
+            tags: None,
+            received_since: None,
+            resume_from: None,
+            progress: None,
             force_no_match: false,
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3, $4) AND e.kind NOT IN ($5) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
     }
 
     #[test]
@@ -1026,17 +2748,288 @@ mod tests {
         let filter = ReqFilter {
             ids: None,
             kinds: Some(vec![1000]),
+            exclude_kinds: None,
+            since: None,
+            until: None,
+            authors: Some(vec![
+                "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned(),
+            ]),
+            limit: None,
+            tags: Some(HashMap::from([(
+                'd',
+                HashSet::from([
+                    "test".to_owned(),
+                    "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned(),
+                ]),
+            )])),
+            received_since: None,
+            resume_from: None,
+            progress: None,
+            force_no_match: false,
+        };
+
+        let q = query_from_filter(&filter).unwrap();
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5) OR value_hex in ($6)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+    }
+    #[test]
+    fn test_query_gen_multiple_tag_names() {
+        let filter = ReqFilter {
+            ids: None,
+            kinds: None,
+            exclude_kinds: None,
+            since: None,
+            until: None,
+            authors: None,
+            limit: None,
+            tags: Some(HashMap::from([
+                (
+                    'p',
+                    HashSet::from([
+                        "84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864"
+                            .to_owned(),
+                    ]),
+                ),
+                (
+                    'e',
+                    HashSet::from([
+                        "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed"
+                            .to_owned(),
+                    ]),
+                ),
+                ('d', HashSet::from(["test".to_owned()])),
+            ])),
+            received_since: None,
+            resume_from: None,
+            progress: None,
+            force_no_match: false,
+        };
+
+        let q = query_from_filter(&filter).unwrap();
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $1 AND (value in ($2)))) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $3 AND (value_hex in ($4)))) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $5 AND (value_hex in ($6)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+    }
+
+    #[test]
+    fn test_query_gen_tag_without_values_or_odd_hex() {
+        let filter = ReqFilter {
+            ids: None,
+            kinds: Some(vec![1]),
+            exclude_kinds: None,
             since: None,
             until: None,
-            authors: Some(vec!["84de35e2584d2b144aae823c9ed0b0f3deda09648530b93d1a2a146d1dea9864".to_owned()]),
+            authors: None,
             limit: None,
             tags: Some(HashMap::from([
-                ('d', HashSet::from(["test".to_owned(), "63fe6318dc58583cfe16810f86dd09e18bfd76aabc24a0081ce2856f330504ed".to_owned()]))
+                ('e', HashSet::new()),
+                ('p', HashSet::from(["abc".to_owned()])),
             ])),
+            received_since: None,
+            resume_from: None,
+            progress: None,
             force_no_match: false,
         };
 
         let q = query_from_filter(&filter).unwrap();
-        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at FROM \"event\" e WHERE (e.pub_key in ($1) OR e.delegated_by in ($2)) AND e.kind in ($3) AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $4 AND (value in ($5) OR value_hex in ($6)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+        assert_eq!(q.sql(), "SELECT e.\"content\", e.created_at, e.first_seen FROM \"event\" e WHERE e.kind in ($1) AND false AND e.id IN (SELECT ee.id FROM \"event\" ee LEFT JOIN tag t on ee.id = t.event_id WHERE ee.hidden != 1::bit(1) and (t.\"name\" = $2 AND (value in ($3)))) AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > now()) ORDER BY e.created_at ASC LIMIT 1000")
+    }
+
+    #[test]
+    fn connection_errors_are_retried() {
+        let io = || {
+            let refused = std::io::ErrorKind::ConnectionRefused.into();
+            error::Error::SqlxDatabasePoolError(Error::Io(refused))
+        };
+        assert!(is_connection_error(&io()));
+        assert!(is_connection_error(&error::Error::SqlxError(
+            Error::PoolTimedOut
+        )));
+        assert!(is_connection_error(&error::Error::MigrationError(
+            3,
+            Box::new(io())
+        )));
+        assert!(!is_connection_error(&error::Error::SqlxDatabasePoolError(
+            RowNotFound
+        )));
+        assert!(!is_connection_error(&error::Error::MigrationError(
+            3,
+            Box::new(error::Error::CustomError("syntax error".to_owned()))
+        )));
+    }
+
+    #[test]
+    fn migration_errors_name_the_migration() {
+        let cause = error::Error::SqlxDatabasePoolError(RowNotFound);
+        let e = error::Error::MigrationError(7, Box::new(cause));
+        assert_eq!(e.to_string(), format!("migration 7 failed: {RowNotFound}"));
+    }
+
+    /// Run `retry_startup` with `attempts`, failing with `errors` in
+    /// turn, then succeeding.  Returns the result and number of calls.
+    async fn startup_with(attempts: u32, errors: Vec<Error>) -> (Result<u32>, u32) {
+        let calls = AtomicU32::new(0);
+        let errors = std::sync::Mutex::new(errors.into_iter());
+        let res = retry_startup("connect", attempts, Duration::ZERO, || async {
+            let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            match errors.lock().unwrap().next() {
+                Some(e) => Err(error::Error::SqlxDatabasePoolError(e)),
+                None => Ok(call),
+            }
+        })
+        .await;
+        (res, calls.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn startup_retries_until_connected() {
+        let (res, calls) = startup_with(3, vec![Error::PoolTimedOut, Error::PoolTimedOut]).await;
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(calls, 3);
+        // connection errors on the last attempt are returned
+        let (res, calls) = startup_with(2, vec![Error::PoolTimedOut, Error::PoolTimedOut]).await;
+        assert!(res.is_err());
+        assert_eq!(calls, 2);
+        // other errors are not retried
+        let (res, calls) = startup_with(3, vec![RowNotFound]).await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    /// Two relays sharing one database, each in its own schema.  Needs a
+    /// PostgreSQL server: set `NOSTR_TEST_POSTGRES` to a connection string
+    /// and run with `--ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn schemas_keep_relays_apart() -> Result<()> {
+        let url = std::env::var("NOSTR_TEST_POSTGRES").expect("NOSTR_TEST_POSTGRES is not set");
+        let suffix = utils::unix_time();
+        let mut relays = vec![];
+        for name in ["a", "b"] {
+            let mut settings = Settings::default();
+            settings.database.engine = "postgres".to_owned();
+            settings.database.connection = url.clone();
+            settings.database.postgres_schema = Some(format!("relay_{name}_{suffix}"));
+            let pool = pool_options(&settings)
+                .max_connections(2)
+                .connect(&url)
+                .await?;
+            let repo = PostgresRepo::new(
+                pool.clone(),
+                pool.clone(),
+                crate::server::create_metrics().1,
+                &settings,
+            );
+            repo.migrate_up().await?;
+            relays.push((settings.database.postgres_schema.unwrap(), pool, repo));
+        }
+        let event = Event {
+            id: "01".repeat(32),
+            pubkey: "ab".repeat(32),
+            delegated_by: None,
+            created_at: 1000,
+            kind: 1,
+            tags: vec![],
+            content: "".to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        };
+        assert_eq!(
+            relays[0].2.write_event(&event).await?,
+            WriteResult::Added(1)
+        );
+        let filter: ReqFilter = serde_json::from_value(serde_json::json!({ "ids": [event.id] }))?;
+        for (i, (schema, pool, _)) in relays.iter().enumerate() {
+            let current: String = sqlx::query_scalar("SELECT current_schema()::TEXT")
+                .fetch_one(pool)
+                .await?;
+            assert_eq!(&current, schema);
+            // migrations and tables were created in the relay's schema
+            let tables: i64 = sqlx::query_scalar("SELECT count(*) FROM pg_tables WHERE schemaname = $1 AND tablename IN ('event', 'tag', 'migrations')")
+                .bind(schema)
+                .fetch_one(pool)
+                .await?;
+            assert_eq!(tables, 3);
+            // the event is only visible to the relay it was written to
+            let found = query_from_filter(&filter)
+                .unwrap()
+                .build()
+                .fetch_all(pool)
+                .await?
+                .len();
+            assert_eq!(found, usize::from(i == 0));
+        }
+        for (schema, pool, _) in &relays {
+            sqlx::query(&format!("DROP SCHEMA \"{schema}\" CASCADE"))
+                .execute(pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Older replaceable events return the stored version, and ties go
+    /// to the lowest id.  Needs a PostgreSQL server, like
+    /// `schemas_keep_relays_apart`.
+    #[tokio::test]
+    #[ignore]
+    async fn replaceable_ties_keep_lowest_id() -> Result<()> {
+        let url = std::env::var("NOSTR_TEST_POSTGRES").expect("NOSTR_TEST_POSTGRES is not set");
+        let schema = format!("replaceable_{}", utils::unix_time());
+        let mut settings = Settings::default();
+        settings.database.postgres_schema = Some(schema.clone());
+        let pool = pool_options(&settings)
+            .max_connections(2)
+            .connect(&url)
+            .await?;
+        let repo = PostgresRepo::new(
+            pool.clone(),
+            pool.clone(),
+            crate::server::create_metrics().1,
+            &settings,
+        );
+        repo.migrate_up().await?;
+        let event = |id: u8, kind: u64, created_at: u64, d: Option<&str>| Event {
+            id: format!("{id:02x}").repeat(32),
+            pubkey: "ab".repeat(32),
+            delegated_by: None,
+            created_at,
+            kind,
+            tags: d
+                .map(|d| vec![vec!["d".to_owned(), d.to_owned()]])
+                .unwrap_or_default(),
+            content: "".to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        };
+        // "ab" is stored as hex, "x" as text
+        for (base, kind, d) in [
+            (0x10, 0, None),
+            (0x20, 30000, Some("ab")),
+            (0x30, 30000, Some("x")),
+        ] {
+            let stored = event(base + 5, kind, 1000, d);
+            let superseded = WriteResult::Superseded(Box::new(stored.clone()));
+            assert_eq!(repo.write_event(&stored).await?, WriteResult::Added(1));
+            assert_eq!(
+                repo.write_event(&event(base + 1, kind, 999, d)).await?,
+                superseded
+            );
+            assert_eq!(
+                repo.write_event(&event(base + 6, kind, 1000, d)).await?,
+                superseded
+            );
+            assert_eq!(repo.write_event(&stored).await?, WriteResult::Added(0));
+            assert_eq!(
+                repo.write_event(&event(base + 4, kind, 1000, d)).await?,
+                WriteResult::Added(1)
+            );
+            let kept: Vec<Vec<u8>> = sqlx::query_scalar("SELECT id FROM event WHERE kind = $1")
+                .bind(kind as i64)
+                .fetch_all(&pool)
+                .await?;
+            assert!(kept.contains(&vec![base + 4; 32]));
+            assert!(!kept.contains(&vec![base + 5; 32]));
+        }
+        sqlx::query(&format!("DROP SCHEMA \"{schema}\" CASCADE"))
+            .execute(&pool)
+            .await?;
+        Ok(())
     }
-}
\ No newline at end of file
+}
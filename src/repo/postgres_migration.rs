@@ -1,11 +1,13 @@
+use crate::error::{Error, Result};
 use crate::repo::postgres::PostgresPool;
+use crate::startup;
 use async_trait::async_trait;
 use sqlx::{Executor, Postgres, Transaction};
 
 #[async_trait]
 pub trait Migration {
     fn serial_number(&self) -> i64;
-    async fn run(&self, tx: &mut Transaction<Postgres>);
+    async fn run(&self, tx: &mut Transaction<Postgres>) -> Result<()>;
 }
 
 struct SimpleSqlMigration {
@@ -19,39 +21,94 @@ impl Migration for SimpleSqlMigration {
         self.serial_number
     }
 
-    async fn run(&self, tx: &mut Transaction<Postgres>) {
+    async fn run(&self, tx: &mut Transaction<Postgres>) -> Result<()> {
         for sql in self.sql.iter() {
-            tx.execute(*sql).await.unwrap();
+            tx.execute(*sql).await?;
         }
+        Ok(())
     }
 }
 
 /// Execute all migrations on the database.
-pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
-    prepare_migrations_table(db).await;
-    run_migration(m001::migration(), db).await;
-    let m002_result = run_migration(m002::migration(), db).await;
+///
+/// If a `schema` is given, it is created first.  Connections must
+/// already have it as their `search_path` (see
+/// [`pool_options`](crate::repo::postgres::pool_options)), so that the
+/// migrations table and every migration land in it.
+///
+/// Each migration is recorded in the same transaction as its changes,
+/// so a failed migration leaves the database as it was, and is run
+/// again next time.  Tag values longer than `max_tag_bytes` are left
+/// out when the tag table is rebuilt.
+pub async fn run_migrations(
+    db: &PostgresPool,
+    schema: Option<&str>,
+    max_tag_bytes: Option<usize>,
+) -> Result<usize> {
+    if let Some(schema) = schema {
+        create_schema(db, schema).await?;
+    }
+    prepare_migrations_table(db).await?;
+    run_migration(m001::migration(), db).await?;
+    let m002_result = run_migration(m002::migration(), db).await?;
     if m002_result == MigrationResult::Upgraded {
-        m002::rebuild_tags(db).await?;
+        m002::rebuild_tags(db, max_tag_bytes).await?;
     }
-    run_migration(m003::migration(), db).await;
-    run_migration(m004::migration(), db).await;
-    run_migration(m005::migration(), db).await;
-    Ok(current_version(db).await as usize)
+    run_migration(m003::migration(), db).await?;
+    run_migration(m004::migration(), db).await?;
+    run_migration(m005::migration(), db).await?;
+    run_migration(m006::migration(), db).await?;
+    run_migration(m007::migration(), db).await?;
+    run_migration(m008::migration(), db).await?;
+    run_migration(m009::migration(), db).await?;
+    run_migration(m010::migration(), db).await?;
+    run_migration(m011::migration(), db).await?;
+    run_migration(m012::migration(), db).await?;
+    run_migration(m013::migration(), db).await?;
+    run_migration(m014::migration(), db).await?;
+    run_migration(m015::migration(), db).await?;
+    run_migration(m016::migration(), db).await?;
+    run_migration(m017::migration(), db).await?;
+    run_migration(m018::migration(), db).await?;
+    run_migration(m019::migration(), db).await?;
+    run_migration(m020::migration(), db).await?;
+    startup::migrations_finished();
+    Ok(current_version(db).await? as usize)
+}
+
+/// Count tag rows with a value longer than `max_bytes`.
+///
+/// Hex values are stored decoded, so their length is doubled to
+/// compare against the original string length.
+pub async fn oversize_tag_count(db: &PostgresPool, max_bytes: usize) -> Result<i64> {
+    let count = sqlx::query_scalar(
+        "SELECT count(*) FROM tag WHERE octet_length(value) > $1 OR octet_length(value_hex)*2 > $1;",
+    )
+    .bind(max_bytes as i64)
+    .fetch_one(db)
+    .await?;
+    Ok(count)
 }
 
-async fn current_version(db: &PostgresPool) -> i64 {
-    sqlx::query_scalar("SELECT max(serial_number) FROM migrations;")
+async fn current_version(db: &PostgresPool) -> Result<i64> {
+    let version = sqlx::query_scalar("SELECT max(serial_number) FROM migrations;")
         .fetch_one(db)
-        .await
-        .unwrap()
+        .await?;
+    Ok(version)
+}
+
+async fn create_schema(db: &PostgresPool, schema: &str) -> Result<()> {
+    sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS \"{schema}\""))
+        .execute(db)
+        .await?;
+    Ok(())
 }
 
-async fn prepare_migrations_table(db: &PostgresPool) {
+async fn prepare_migrations_table(db: &PostgresPool) -> Result<()> {
     sqlx::query("CREATE TABLE IF NOT EXISTS migrations (serial_number bigint)")
         .execute(db)
-        .await
-        .unwrap();
+        .await?;
+    Ok(())
 }
 
 // Running a migration was either unnecessary, or completed
@@ -61,29 +118,37 @@ enum MigrationResult {
     NotNeeded,
 }
 
-async fn run_migration(migration: impl Migration, db: &PostgresPool) -> MigrationResult {
+/// Run a migration, unless it already was.  Errors name the migration.
+async fn run_migration(migration: impl Migration, db: &PostgresPool) -> Result<MigrationResult> {
+    let serial_number = migration.serial_number();
+    try_migration(migration, db)
+        .await
+        .map_err(|e| Error::MigrationError(serial_number, Box::new(e)))
+}
+
+async fn try_migration(migration: impl Migration, db: &PostgresPool) -> Result<MigrationResult> {
     let row: i64 =
         sqlx::query_scalar("SELECT COUNT(*) AS count FROM migrations WHERE serial_number = $1")
             .bind(migration.serial_number())
             .fetch_one(db)
-            .await
-            .unwrap();
+            .await?;
 
     if row > 0 {
-        return MigrationResult::NotNeeded;
+        return Ok(MigrationResult::NotNeeded);
     }
 
-    let mut transaction = db.begin().await.unwrap();
-    migration.run(&mut transaction).await;
+    startup::migration_started(format!("postgres migration {}", migration.serial_number()));
+    // dropping the transaction on an error rolls it back
+    let mut transaction = db.begin().await?;
+    migration.run(&mut transaction).await?;
 
     sqlx::query("INSERT INTO migrations VALUES ($1)")
         .bind(migration.serial_number())
         .execute(&mut transaction)
-        .await
-        .unwrap();
+        .await?;
 
-    transaction.commit().await.unwrap();
-    MigrationResult::Upgraded
+    transaction.commit().await?;
+    Ok(MigrationResult::Upgraded)
 }
 
 mod m001 {
@@ -142,17 +207,19 @@ CREATE INDEX user_verification_name_idx ON user_verification USING btree (name);
     }
 }
 
-mod m002 {
+pub mod m002 {
     use async_std::stream::StreamExt;
-    use indicatif::{ProgressBar, ProgressStyle};
     use sqlx::Row;
     use std::time::Instant;
     use tracing::info;
 
-    use crate::event::{single_char_tagname, Event};
+    use crate::event::{is_indexable_tag_value, normalize_tag_value, single_char_tagname, Event};
     use crate::repo::postgres::PostgresPool;
     use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+    use crate::startup;
     use crate::utils::is_lower_hex;
+    use crate::verify::TagCoverageReport;
+    use std::collections::HashSet;
 
     pub const VERSION: i64 = 2;
 
@@ -172,11 +239,84 @@ CREATE INDEX tag_value_hex_idx ON tag USING btree (value_hex);
         }
     }
 
-    pub async fn rebuild_tags(db: &PostgresPool) -> crate::error::Result<()> {
+    /// A tag row: name, text value, and hex value.
+    pub type TagRow = (String, Option<Vec<u8>>, Option<Vec<u8>>);
+
+    /// Determine the tag rows that should exist for an event.
+    ///
+    /// Tag values longer than `max_tag_bytes` are not indexed.
+    pub fn derive_tag_rows(event: &Event, max_tag_bytes: Option<usize>) -> Vec<TagRow> {
+        let mut rows = vec![];
+        for t in event.tags.iter().filter(|x| x.len() > 1) {
+            let tagname = t.get(0).unwrap();
+            let tagnamechar_opt = single_char_tagname(tagname);
+            if tagnamechar_opt.is_none() {
+                continue;
+            }
+            // safe because len was > 1
+            let tagval = t.get(1).unwrap();
+            if !is_indexable_tag_value(tagname, tagval, max_tag_bytes) {
+                continue;
+            }
+            // insert as BLOB if we can restore it losslessly.
+            // this means it needs to be even length and lowercase.
+            if (tagval.len() % 2 == 0) && is_lower_hex(tagval) {
+                rows.push((tagname.clone(), None, hex::decode(tagval).ok()));
+            } else {
+                rows.push((tagname.clone(), Some(tagval.as_bytes().to_vec()), None));
+            }
+        }
+        rows
+    }
+
+    /// Compare the tag table to the tags derived from each event,
+    /// without making any changes.
+    pub async fn check_tags(
+        db: &PostgresPool,
+        max_tag_bytes: Option<usize>,
+    ) -> crate::error::Result<TagCoverageReport> {
+        let mut report = TagCoverageReport::default();
+        // events waiting for deferred tag indexing are not expected to be covered yet
+        let mut events =
+            sqlx::query("SELECT id, content FROM event WHERE tags_indexed ORDER BY id;").fetch(db);
+        while let Some(row) = events.next().await {
+            let row = row?;
+            let event_id: Vec<u8> = row.get(0);
+            let event_bytes: Vec<u8> = row.get(1);
+            let mut event: Event = serde_json::from_slice(&event_bytes)?;
+            // ids and pubkeys are indexed in lowercase since m020
+            for t in event.tags.iter_mut().filter(|t| t.len() > 1) {
+                t[1] = normalize_tag_value(&t[0], &t[1]).into_owned();
+            }
+            report.events += 1;
+            let stored: HashSet<TagRow> =
+                sqlx::query("SELECT \"name\", value, value_hex FROM tag WHERE event_id=$1;")
+                    .bind(&event_id)
+                    .fetch_all(db)
+                    .await?
+                    .iter()
+                    .map(|r| (r.get(0), r.get(1), r.get(2)))
+                    .collect();
+            let missing = derive_tag_rows(&event, max_tag_bytes)
+                .iter()
+                .any(|t| !stored.contains(t));
+            if missing {
+                report.missing.push(event.id);
+            } else {
+                report.covered += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    pub async fn rebuild_tags(
+        db: &PostgresPool,
+        max_tag_bytes: Option<usize>,
+    ) -> crate::error::Result<()> {
         // Check how many events we have to process
         let start = Instant::now();
-        let mut tx = db.begin().await.unwrap();
-        let mut update_tx = db.begin().await.unwrap();
+        let mut tx = db.begin().await?;
+        let mut update_tx = db.begin().await?;
         // Clear out table
         sqlx::query("DELETE FROM tag;")
             .execute(&mut update_tx)
@@ -184,53 +324,28 @@ CREATE INDEX tag_value_hex_idx ON tag USING btree (value_hex);
         {
             let event_count: i64 = sqlx::query_scalar("SELECT COUNT(*) from event;")
                 .fetch_one(&mut tx)
-                .await
-                .unwrap();
-            let bar = ProgressBar::new(event_count.try_into().unwrap())
-                .with_message("rebuilding tags table");
-            bar.set_style(
-                ProgressStyle::with_template(
-                    "[{elapsed_precise}] {bar:40.white/blue} {pos:>7}/{len:7} [{percent}%] {msg}",
-                )
-                .unwrap(),
-            );
+                .await?;
+            let bar =
+                startup::progress_bar(event_count.try_into().unwrap_or(0), "rebuilding tags table");
             let mut events =
                 sqlx::query("SELECT id, content FROM event ORDER BY id;").fetch(&mut tx);
             while let Some(row) = events.next().await {
                 bar.inc(1);
                 // get the row id and content
-                let row = row.unwrap();
+                let row = row?;
                 let event_id: Vec<u8> = row.get(0);
                 let event_bytes: Vec<u8> = row.get(1);
-                let event: Event = serde_json::from_str(&String::from_utf8(event_bytes).unwrap())?;
-
-                for t in event.tags.iter().filter(|x| x.len() > 1) {
-                    let tagname = t.get(0).unwrap();
-                    let tagnamechar_opt = single_char_tagname(tagname);
-                    if tagnamechar_opt.is_none() {
-                        continue;
-                    }
-                    // safe because len was > 1
-                    let tagval = t.get(1).unwrap();
-                    // insert as BLOB if we can restore it losslessly.
-                    // this means it needs to be even length and lowercase.
-                    if (tagval.len() % 2 == 0) && is_lower_hex(tagval) {
-                        let q = "INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES ($1, $2, NULL, $3) ON CONFLICT DO NOTHING;";
-                        sqlx::query(q)
-                            .bind(&event_id)
-                            .bind(tagname)
-                            .bind(hex::decode(tagval).ok())
-                            .execute(&mut update_tx)
-                            .await?;
-                    } else {
-                        let q = "INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES ($1, $2, $3, NULL) ON CONFLICT DO NOTHING;";
-                        sqlx::query(q)
-                            .bind(&event_id)
-                            .bind(tagname)
-                            .bind(tagval.as_bytes())
-                            .execute(&mut update_tx)
-                            .await?;
-                    }
+                let event: Event = serde_json::from_slice(&event_bytes)?;
+
+                for (tagname, value, value_hex) in derive_tag_rows(&event, max_tag_bytes) {
+                    let q = "INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING;";
+                    sqlx::query(q)
+                        .bind(&event_id)
+                        .bind(tagname)
+                        .bind(value)
+                        .bind(value_hex)
+                        .execute(&mut update_tx)
+                        .await?;
                 }
             }
             update_tx.commit().await?;
@@ -318,3 +433,389 @@ CREATE TABLE "invoice" (
         }
     }
 }
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Ledger of account balance changes
+CREATE TABLE "ledger" (
+    id BIGSERIAL PRIMARY KEY,
+    pubkey varchar NOT NULL,
+    delta BIGINT NOT NULL,
+    balance BIGINT NOT NULL,
+    reason varchar NOT NULL CHECK (reason IN ('admission', 'publication', 'payment', 'zap', 'admin', 'refund')),
+    reference varchar,
+    created_at timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT ledger_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey) ON DELETE CASCADE
+);
+CREATE INDEX ledger_pubkey_idx ON ledger (pubkey, id);
+-- Existing balances become opening entries, so the ledger sums to the balance
+INSERT INTO ledger (pubkey, delta, balance, reason, reference)
+SELECT pubkey, balance, balance, 'admin', 'opening balance' FROM account WHERE balance != 0;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Index for resuming subscriptions by server receive time
+CREATE INDEX event_first_seen_idx ON "event" (first_seen);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Invoices can be refunded.  Enum values can only be added, never
+-- removed or renamed, so InvoiceStatus must only ever grow.  Adding a
+-- value inside a transaction needs PostgreSQL 12 or later.
+ALTER TYPE status ADD VALUE IF NOT EXISTS 'Refunded';
+        "#,
+            ],
+        }
+    }
+}
+
+mod m009 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 9;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Newest event delivered to a repeated subscription
+CREATE TABLE "subscription_watermark" (
+    pubkey varchar NOT NULL,
+    sub_key varchar NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at timestamp with time zone NOT NULL DEFAULT now(),
+    PRIMARY KEY (pubkey, sub_key)
+);
+CREATE INDEX subscription_watermark_updated_idx ON subscription_watermark (updated_at);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m010 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 10;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Deleted events are hidden until they are purged
+ALTER TABLE "event" ADD COLUMN purge_after timestamp with time zone;
+CREATE INDEX event_purge_after_idx ON "event" (purge_after) WHERE purge_after IS NOT NULL;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m011 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 11;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Events whose tags are waiting to be indexed
+ALTER TABLE "event" ADD COLUMN tags_indexed boolean NOT NULL DEFAULT TRUE;
+CREATE INDEX event_unindexed_tags_idx ON "event" (first_seen) WHERE NOT tags_indexed;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m012 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 12;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Events stored (hidden) while waiting for asynchronous admission
+ALTER TABLE "event" ADD COLUMN pending boolean NOT NULL DEFAULT FALSE;
+CREATE INDEX event_pending_idx ON "event" (first_seen) WHERE pending;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m013 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 13;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Events written per author, kind and day
+CREATE TABLE "event_stats" (
+    pubkey varchar NOT NULL,
+    kind BIGINT NOT NULL,
+    day date NOT NULL,
+    count BIGINT NOT NULL,
+    bytes BIGINT NOT NULL,
+    PRIMARY KEY (day, kind, pubkey)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m014 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 14;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Find an author's newest event without scanning their events
+CREATE INDEX event_pub_key_created_at_idx ON "event" (pub_key, created_at);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m015 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 15;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Pubkeys seen by the relay, and whether they are quarantined
+CREATE TABLE "first_seen_pubkey" (
+    pubkey varchar NOT NULL PRIMARY KEY,
+    first_seen BIGINT NOT NULL,
+    event_count BIGINT NOT NULL DEFAULT 0,
+    status varchar NOT NULL
+);
+CREATE INDEX first_seen_pubkey_status_idx ON "first_seen_pubkey" (status, first_seen);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m016 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 16;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Where each event came from; existing events were published locally
+ALTER TABLE "event" ADD COLUMN source varchar NOT NULL DEFAULT 'local';
+CREATE INDEX event_source_idx ON "event" (source, created_at) WHERE source != 'local';
+        "#,
+            ],
+        }
+    }
+}
+
+mod m017 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 17;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Creation time of each tag's event, to partition tags by.  Existing
+-- tags are filled in when the tables are partitioned.
+ALTER TABLE tag ADD COLUMN event_created_at timestamptz;
+        "#,
+            ],
+        }
+    }
+}
+
+mod m018 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 18;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Pubkeys whose data was erased, whose older events are refused
+CREATE TABLE "erased_pubkey" (
+    pubkey varchar NOT NULL PRIMARY KEY,
+    erased_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m019 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 19;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Events published by each account, for free publications
+ALTER TABLE "account" ADD COLUMN event_count BIGINT NOT NULL DEFAULT 0;
+-- accounts that already paid for events have used their free ones
+UPDATE "account" a SET event_count = (SELECT COUNT(*) FROM ledger l WHERE l.pubkey = a.pubkey AND l.reason = 'publication');
+        "#,
+            ],
+        }
+    }
+}
+
+mod m020 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 20;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Ids and pubkeys in e and p tags are indexed in lowercase.  Values
+-- with uppercase letters were stored as text; drop those the event
+-- already has in lowercase (or in another case), and store the rest
+-- as hex.
+DELETE FROM tag t
+WHERE t.value_hex IS NULL AND t."name" IN ('e', 'p')
+AND encode(t.value, 'escape') ~ '^[0-9a-fA-F]{64}$'
+AND EXISTS (
+    SELECT 1 FROM tag o WHERE o.event_id = t.event_id AND o."name" = t."name"
+    AND (o.value_hex = decode(lower(encode(t.value, 'escape')), 'hex')
+        OR (o.value IS NOT NULL AND o.id < t.id
+            AND lower(encode(o.value, 'escape')) = lower(encode(t.value, 'escape'))))
+);
+UPDATE tag SET value_hex = decode(lower(encode(value, 'escape')), 'hex'), value = NULL
+WHERE value_hex IS NULL AND "name" IN ('e', 'p')
+AND encode(value, 'escape') ~ '^[0-9a-fA-F]{64}$';
+        "#,
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::repo::postgres::pool_options;
+
+    /// A migration that fails is rolled back without being recorded,
+    /// and runs once fixed.  Needs a PostgreSQL server: set
+    /// `NOSTR_TEST_POSTGRES` to a connection string and run with
+    /// `--ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn failed_migration_is_not_recorded() -> Result<()> {
+        let url = std::env::var("NOSTR_TEST_POSTGRES").expect("NOSTR_TEST_POSTGRES is not set");
+        let schema = format!("migration_test_{}", crate::utils::unix_time());
+        let mut settings = Settings::default();
+        settings.database.postgres_schema = Some(schema.clone());
+        let db = pool_options(&settings)
+            .max_connections(2)
+            .connect(&url)
+            .await?;
+        run_migrations(&db, Some(&schema), None).await?;
+        let migration = |check: &'static str| SimpleSqlMigration {
+            serial_number: 1000,
+            sql: vec!["CREATE TABLE extra (id bigint);", check],
+        };
+        let err = run_migration(migration("SELECT missing FROM extra;"), &db)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("migration 1000 failed: "),
+            "{err}"
+        );
+        assert!(err.to_string().contains("missing"), "{err}");
+        // neither the table nor the migration were recorded
+        assert_eq!(current_version(&db).await?, m020::VERSION);
+        let tables: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_tables WHERE schemaname = $1 AND tablename = 'extra'",
+        )
+        .bind(&schema)
+        .fetch_one(&db)
+        .await?;
+        assert_eq!(tables, 0);
+        // a fixed migration runs, once
+        let fixed = run_migration(migration("SELECT id FROM extra;"), &db).await?;
+        assert_eq!(fixed, MigrationResult::Upgraded);
+        assert_eq!(current_version(&db).await?, 1000);
+        let again = run_migration(migration("SELECT id FROM extra;"), &db).await?;
+        assert_eq!(again, MigrationResult::NotNeeded);
+        sqlx::query(&format!("DROP SCHEMA \"{schema}\" CASCADE"))
+            .execute(&db)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,731 @@
+//! Monthly range partitioning of the PostgreSQL event store
+//!
+//! Partitioning is opt-in: a fresh database is partitioned at startup
+//! with `--partitioned`, and an existing one is converted offline with
+//! the `partition-events` command.  Events are partitioned by the month
+//! of `created_at`, and tags by the `event_created_at` of their event
+//! into matching partitions, so retention drops a month of events and
+//! tags as two tables instead of deleting rows.  Queries are unchanged;
+//! the planner prunes partitions from `created_at` conditions.  Rows
+//! with no monthly partition (far in the past or future) land in a
+//! default partition, and are moved once their month is created.  The
+//! default partition is expired row by row.
+//!
+//! A foreign key to a partitioned table would keep its partitions from
+//! being dropped, so tags and verification records are instead removed
+//! with their event by a trigger.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::postgres::{pool_options, PostgresPool};
+use crate::repo::postgres_migration::run_migrations;
+use crate::startup;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use sqlx::{Executor, Postgres, Transaction};
+use tracing::info;
+
+/// Months of partitions kept ready after the current one
+pub const PARTITIONS_AHEAD: u32 = 3;
+
+/// Removes the tags and verification records of deleted events
+const CASCADE_TRIGGER_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION event_delete_cascade() RETURNS trigger AS $$
+BEGIN
+    -- rows moved between partitions are not deleted
+    IF current_setting('nostr.moving_rows', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    DELETE FROM tag WHERE event_id = OLD.id AND event_created_at = OLD.created_at;
+    DELETE FROM user_verification WHERE event_id = OLD.id;
+    RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER event_delete_cascade AFTER DELETE ON "event"
+    FOR EACH ROW EXECUTE FUNCTION event_delete_cascade();
+"#;
+
+/// First instant of the month containing `t`
+#[must_use]
+pub fn month_start(t: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(t.year(), t.month(), 1, 0, 0, 0)
+        .unwrap()
+}
+
+/// First instant of the month after the one containing `t`
+#[must_use]
+pub fn next_month(t: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = if t.month() == 12 {
+        (t.year() + 1, 1)
+    } else {
+        (t.year(), t.month() + 1)
+    };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap()
+}
+
+/// Name of the partition of `table` for the month containing `t`,
+/// such as `event_y2024m05`
+#[must_use]
+pub fn partition_name(table: &str, t: DateTime<Utc>) -> String {
+    format!("{table}_y{:04}m{:02}", t.year(), t.month())
+}
+
+/// Month of a partition of `table`, from its name
+fn partition_month(table: &str, name: &str) -> Option<DateTime<Utc>> {
+    let (year, month) = name
+        .strip_prefix(table)?
+        .strip_prefix("_y")?
+        .split_once('m')?;
+    Utc.with_ymd_and_hms(year.parse().ok()?, month.parse().ok()?, 1, 0, 0, 0)
+        .single()
+}
+
+/// Rewrite an index definition (from `pg_indexes`) of `table` for its
+/// partitioned copy, adding a `_p` suffix to the index name until the
+/// tables are swapped.
+fn partitioned_index_def(name: &str, def: &str, table: &str) -> Option<String> {
+    let (head, rest) = def.split_once(" ON ")?;
+    let (_, tail) = rest.split_once(" USING ")?;
+    let head = head.strip_suffix(name)?;
+    Some(format!(
+        "{head}{name}_p ON {table}_partitioned USING {tail}"
+    ))
+}
+
+/// Are the event and tag tables partitioned?
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be queried.
+pub async fn is_partitioned(db: &PostgresPool) -> Result<bool> {
+    Ok(sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_partitioned_table p JOIN pg_class c ON c.oid = p.partrelid \
+         WHERE c.relname = 'event' AND c.relnamespace = current_schema()::regnamespace)",
+    )
+    .fetch_one(db)
+    .await?)
+}
+
+/// Partitions of a table, with their months.  The default partition is
+/// not included.
+async fn partitions(db: &PostgresPool, table: &str) -> Result<Vec<(String, DateTime<Utc>)>> {
+    let names: Vec<String> = sqlx::query_scalar(
+        "SELECT c.relname::TEXT FROM pg_inherits i \
+         JOIN pg_class c ON c.oid = i.inhrelid JOIN pg_class p ON p.oid = i.inhparent \
+         WHERE p.relname = $1 AND p.relnamespace = current_schema()::regnamespace",
+    )
+    .bind(table)
+    .fetch_all(db)
+    .await?;
+    let mut months: Vec<_> = names
+        .into_iter()
+        .filter_map(|name| partition_month(table, &name).map(|m| (name, m)))
+        .collect();
+    months.sort_by_key(|(_, m)| *m);
+    Ok(months)
+}
+
+/// Create the event and tag partitions for the month containing `t`,
+/// moving in any rows already held by the default partitions.
+async fn create_month(tx: &mut Transaction<'_, Postgres>, t: DateTime<Utc>) -> Result<()> {
+    let (start, end) = (month_start(t), next_month(t));
+    let bounds = format!(
+        "FOR VALUES FROM ('{}') TO ('{}')",
+        start.to_rfc3339(),
+        end.to_rfc3339()
+    );
+    let stranded: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM event_default WHERE created_at >= $1 AND created_at < $2)",
+    )
+    .bind(start)
+    .bind(end)
+    .fetch_one(&mut *tx)
+    .await?;
+    let event_part = partition_name("event", t);
+    let tag_part = partition_name("tag", t);
+    if !stranded {
+        tx.execute(
+            format!(
+                "CREATE TABLE {event_part} PARTITION OF \"event\" {bounds}; \
+                 CREATE TABLE {tag_part} PARTITION OF tag {bounds};"
+            )
+            .as_str(),
+        )
+        .await?;
+        return Ok(());
+    }
+    // copy the rows out of the default partitions, then attach.
+    tx.execute("SET LOCAL nostr.moving_rows = 'on';").await?;
+    for (table, part, column) in [
+        ("tag", &tag_part, "event_created_at"),
+        ("event", &event_part, "created_at"),
+    ] {
+        let range = format!(
+            "{column} >= '{}' AND {column} < '{}'",
+            start.to_rfc3339(),
+            end.to_rfc3339()
+        );
+        tx.execute(
+            format!(
+                "CREATE TABLE {part} (LIKE {table} INCLUDING DEFAULTS INCLUDING CONSTRAINTS); \
+                 INSERT INTO {part} SELECT * FROM {table}_default WHERE {range}; \
+                 DELETE FROM {table}_default WHERE {range};"
+            )
+            .as_str(),
+        )
+        .await?;
+    }
+    tx.execute(
+        format!(
+            "ALTER TABLE \"event\" ATTACH PARTITION {event_part} {bounds}; \
+             ALTER TABLE tag ATTACH PARTITION {tag_part} {bounds}; \
+             SET LOCAL nostr.moving_rows = 'off';"
+        )
+        .as_str(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Create partitions for the current month and the
+/// [`PARTITIONS_AHEAD`] months after it, where missing.  Returns the
+/// event partitions created.
+///
+/// # Errors
+///
+/// Will return `Err` if a partition could not be created.
+pub async fn ensure_partitions(db: &PostgresPool, now: DateTime<Utc>) -> Result<Vec<String>> {
+    let existing: Vec<DateTime<Utc>> = partitions(db, "event")
+        .await?
+        .into_iter()
+        .map(|(_, m)| m)
+        .collect();
+    let mut created = vec![];
+    let mut month = month_start(now);
+    for _ in 0..=PARTITIONS_AHEAD {
+        if !existing.contains(&month) {
+            let mut tx = db.begin().await?;
+            create_month(&mut tx, month).await?;
+            tx.commit().await?;
+            created.push(partition_name("event", month));
+        }
+        month = next_month(month);
+    }
+    Ok(created)
+}
+
+/// Drop the event and tag partitions of months that ended before
+/// `cutoff`, and the verification records of their events.  Returns
+/// the event partitions dropped.
+///
+/// Whole months are dropped, so events are kept for up to a month
+/// longer than the retention period.  Deletion requests (kind 5), so
+/// deleted events can not be published again, and events of
+/// `restricted_kinds` first seen after `cutoff`, are moved to the
+/// default partition with their tags instead.
+///
+/// # Errors
+///
+/// Will return `Err` if a partition could not be dropped.
+pub async fn drop_partitions_before(
+    db: &PostgresPool,
+    cutoff: DateTime<Utc>,
+    restricted_kinds: &[u64],
+) -> Result<Vec<String>> {
+    let kinds: Vec<i64> = restricted_kinds.iter().map(|k| *k as i64).collect();
+    let mut dropped = vec![];
+    for (name, month) in partitions(db, "event").await? {
+        if next_month(month) > cutoff {
+            break;
+        }
+        let mut tx = db.begin().await?;
+        tx.execute(
+            format!(
+                "CREATE TEMPORARY TABLE kept_events ON COMMIT DROP AS SELECT * FROM {name} WITH NO DATA;"
+            )
+            .as_str(),
+        )
+        .await?;
+        sqlx::query(&format!(
+            "INSERT INTO kept_events SELECT * FROM {name} \
+             WHERE kind = 5 OR (kind = ANY($1) AND first_seen >= $2)"
+        ))
+        .bind(&kinds)
+        .bind(cutoff)
+        .execute(&mut tx)
+        .await?;
+        tx.execute(
+            format!(
+                "CREATE TEMPORARY TABLE kept_tags ON COMMIT DROP AS SELECT t.* FROM tag t \
+                 JOIN kept_events e ON e.id = t.event_id AND e.created_at = t.event_created_at; \
+                 DROP TABLE IF EXISTS {}; DROP TABLE {name}; \
+                 INSERT INTO \"event\" SELECT * FROM kept_events; \
+                 INSERT INTO tag SELECT * FROM kept_tags;",
+                partition_name("tag", month)
+            )
+            .as_str(),
+        )
+        .await?;
+        tx.commit().await?;
+        dropped.push(name);
+    }
+    if !dropped.is_empty() {
+        sqlx::query(
+            "DELETE FROM user_verification v WHERE NOT EXISTS (SELECT 1 FROM \"event\" e WHERE e.id = v.event_id)",
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(dropped)
+}
+
+/// Delete the events in the default partition that are past `cutoff`,
+/// one row at a time, as they have no month to drop.  As in the
+/// unpartitioned table, events of `restricted_kinds` are aged by when
+/// they were first seen.  Deletion requests are kept, as they are when
+/// a month is dropped.  Returns the number of events deleted.
+///
+/// # Errors
+///
+/// Will return `Err` if the events could not be deleted.
+pub async fn expire_default_partition(
+    db: &PostgresPool,
+    cutoff: DateTime<Utc>,
+    restricted_kinds: &[u64],
+) -> Result<u64> {
+    let kinds: Vec<i64> = restricted_kinds.iter().map(|k| *k as i64).collect();
+    Ok(sqlx::query(
+        "DELETE FROM event_default WHERE kind != 5 AND \
+         ((NOT (kind = ANY($2)) AND created_at < $1) OR (kind = ANY($2) AND first_seen < $1))",
+    )
+    .bind(cutoff)
+    .bind(kinds)
+    .execute(db)
+    .await?
+    .rows_affected())
+}
+
+/// Copy the index definitions of `table` to its partitioned copy,
+/// leaving out the primary key and unique constraints.  Returns the
+/// names of the indexes copied.
+async fn copy_indexes(tx: &mut Transaction<'_, Postgres>, table: &str) -> Result<Vec<String>> {
+    let defs: Vec<(String, String)> = sqlx::query_as(
+        "SELECT indexname::TEXT, indexdef FROM pg_indexes \
+         WHERE schemaname = current_schema() AND tablename = $1 \
+         AND indexname NOT IN (SELECT conname FROM pg_constraint WHERE conrelid = to_regclass(quote_ident($1)))",
+    )
+    .bind(table)
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut names = vec![];
+    for (name, def) in defs {
+        let sql = partitioned_index_def(&name, &def, table)
+            .ok_or_else(|| Error::CustomError(format!("could not copy index {name}: {def}")))?;
+        tx.execute(sql.as_str()).await?;
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Convert the event and tag tables to monthly partitions, moving the
+/// existing rows one month at a time.  The relay must not be running.
+/// Returns the number of events moved.
+///
+/// The rows are copied into new tables, which replace the old ones
+/// only once every row is copied, so an interrupted conversion leaves
+/// the old tables in place, and can be started again.
+///
+/// # Errors
+///
+/// Will return `Err` if the tables are already partitioned, if rows
+/// were written during the conversion, or on any database error.
+pub async fn convert(db: &PostgresPool) -> Result<u64> {
+    if is_partitioned(db).await? {
+        return Err(Error::CustomError(
+            "the event table is already partitioned".to_owned(),
+        ));
+    }
+    // clear out any earlier, interrupted, conversion
+    db.execute("DROP TABLE IF EXISTS event_partitioned, tag_partitioned CASCADE; DROP SEQUENCE IF EXISTS tag_partitioned_id_seq;")
+        .await?;
+    let mut tx = db.begin().await?;
+    tx.execute(
+        r#"
+CREATE TABLE event_partitioned (LIKE "event" INCLUDING DEFAULTS INCLUDING CONSTRAINTS) PARTITION BY RANGE (created_at);
+ALTER TABLE event_partitioned ADD CONSTRAINT event_partitioned_pkey PRIMARY KEY (id, created_at);
+CREATE TABLE event_partitioned_default PARTITION OF event_partitioned DEFAULT;
+CREATE TABLE tag_partitioned (LIKE tag INCLUDING DEFAULTS) PARTITION BY RANGE (event_created_at);
+CREATE SEQUENCE tag_partitioned_id_seq OWNED BY tag_partitioned.id;
+ALTER TABLE tag_partitioned ALTER COLUMN id SET DEFAULT nextval('tag_partitioned_id_seq');
+ALTER TABLE tag_partitioned ADD CONSTRAINT tag_partitioned_unique UNIQUE (event_id, "name", value, value_hex, event_created_at);
+CREATE TABLE tag_partitioned_default PARTITION OF tag_partitioned DEFAULT;
+        "#,
+    )
+    .await?;
+    let mut indexes = copy_indexes(&mut tx, "event").await?;
+    indexes.extend(copy_indexes(&mut tx, "tag").await?);
+    tx.commit().await?;
+    // months with events, found through the created_at index
+    let mut months = vec![];
+    let mut next: Option<DateTime<Utc>> =
+        sqlx::query_scalar("SELECT min(created_at) FROM \"event\"")
+            .fetch_one(db)
+            .await?;
+    while let Some(t) = next {
+        months.push(month_start(t));
+        next = sqlx::query_scalar("SELECT min(created_at) FROM \"event\" WHERE created_at >= $1")
+            .bind(next_month(t))
+            .fetch_one(db)
+            .await?;
+    }
+    let mut month = month_start(Utc::now());
+    for _ in 0..=PARTITIONS_AHEAD {
+        if !months.contains(&month) {
+            months.push(month);
+        }
+        month = next_month(month);
+    }
+    let total: i64 = sqlx::query_scalar("SELECT count(*) FROM \"event\"")
+        .fetch_one(db)
+        .await?;
+    let bar = startup::progress_bar(total.try_into().unwrap_or(0), "partitioning events");
+    let mut moved = 0;
+    for month in months {
+        let mut tx = db.begin().await?;
+        tx.execute(
+            format!(
+                "CREATE TABLE {} PARTITION OF event_partitioned FOR VALUES FROM ('{start}') TO ('{end}'); \
+                 CREATE TABLE {} PARTITION OF tag_partitioned FOR VALUES FROM ('{start}') TO ('{end}');",
+                partition_name("event", month),
+                partition_name("tag", month),
+                start = month.to_rfc3339(),
+                end = next_month(month).to_rfc3339(),
+            )
+            .as_str(),
+        )
+        .await?;
+        let rows = sqlx::query(
+            "INSERT INTO event_partitioned SELECT * FROM \"event\" WHERE created_at >= $1 AND created_at < $2",
+        )
+        .bind(month)
+        .bind(next_month(month))
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        sqlx::query(
+            "INSERT INTO tag_partitioned (id, event_id, \"name\", value, value_hex, event_created_at) \
+             SELECT t.id, t.event_id, t.\"name\", t.value, t.value_hex, e.created_at FROM tag t \
+             JOIN \"event\" e ON e.id = t.event_id WHERE e.created_at >= $1 AND e.created_at < $2",
+        )
+        .bind(month)
+        .bind(next_month(month))
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        moved += rows;
+        bar.inc(rows);
+        info!(
+            "partitioned {} events from {}",
+            rows,
+            partition_name("event", month)
+        );
+    }
+    bar.finish();
+    // swap the tables, if nothing was written meanwhile
+    let mut tx = db.begin().await?;
+    let remaining: i64 = sqlx::query_scalar("SELECT count(*) FROM \"event\"")
+        .fetch_one(&mut tx)
+        .await?;
+    if remaining as u64 != moved {
+        return Err(Error::CustomError(
+            "events were written during the conversion; stop the relay, and convert again"
+                .to_owned(),
+        ));
+    }
+    tx.execute(
+        r#"
+SELECT setval('tag_partitioned_id_seq', GREATEST((SELECT max(id) FROM tag), 1));
+ALTER TABLE user_verification DROP CONSTRAINT IF EXISTS user_verification_fk;
+DROP TABLE tag;
+DROP TABLE "event";
+ALTER TABLE event_partitioned RENAME TO "event";
+ALTER TABLE event_partitioned_default RENAME TO event_default;
+ALTER TABLE "event" RENAME CONSTRAINT event_partitioned_pkey TO event_pkey;
+ALTER TABLE tag_partitioned RENAME TO tag;
+ALTER TABLE tag_partitioned_default RENAME TO tag_default;
+ALTER TABLE tag RENAME CONSTRAINT tag_partitioned_unique TO unique_constraint_name;
+ALTER SEQUENCE tag_partitioned_id_seq RENAME TO tag_id_seq;
+        "#,
+    )
+    .await?;
+    for name in indexes {
+        tx.execute(format!("ALTER INDEX {name}_p RENAME TO {name};").as_str())
+            .await?;
+    }
+    tx.execute(CASCADE_TRIGGER_SQL).await?;
+    tx.commit().await?;
+    Ok(moved)
+}
+
+/// Convert the configured PostgreSQL database to monthly partitions,
+/// for the `partition-events` command.  Returns the number of events
+/// moved.
+///
+/// # Errors
+///
+/// Will return `Err` if the database is not PostgreSQL, or the
+/// conversion failed.
+pub fn run_partition_events(settings: &Settings) -> Result<u64> {
+    if settings.database.engine != "postgres" {
+        return Err(Error::CustomError(
+            "partitioning is only supported for postgres".to_owned(),
+        ));
+    }
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let pool: PostgresPool = pool_options(settings)
+            .max_connections(1)
+            .connect(&settings.database.connection)
+            .await?;
+        run_migrations(
+            &pool,
+            settings.database.postgres_schema.as_deref(),
+            settings.limits.max_indexed_tag_value_bytes,
+        )
+        .await?;
+        convert(&pool).await
+    })
+}
+
+/// Wait between partition maintenance runs
+pub const MAINTENANCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Keep upcoming partitions ready, and drop those past `persist_days`,
+/// on a regular basis.
+pub fn maintenance_task(db: PostgresPool, persist_days: Option<usize>, restricted_kinds: Vec<u64>) {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+            let now = Utc::now();
+            match ensure_partitions(&db, now).await {
+                Ok(created) if !created.is_empty() => info!("created partitions: {:?}", created),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("could not create partitions: {:?}", e),
+            }
+            if let Some(days) = persist_days {
+                let cutoff = now - Duration::days(days as i64);
+                match drop_partitions_before(&db, cutoff, &restricted_kinds).await {
+                    Ok(dropped) if !dropped.is_empty() => {
+                        info!(
+                            "dropped partitions past the retention period: {:?}",
+                            dropped
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("could not drop old partitions: {:?}", e),
+                }
+                match expire_default_partition(&db, cutoff, &restricted_kinds).await {
+                    Ok(count) if count > 0 => {
+                        info!("removed {} events past the retention period from the default partition", count);
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("could not remove old events: {:?}", e),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::repo::postgres::PostgresRepo;
+    use crate::repo::{NostrRepo, WriteResult};
+
+    fn at(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 12, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn months_and_names() {
+        assert_eq!(
+            month_start(at(2024, 5, 17)),
+            Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_month(at(2024, 5, 17)),
+            Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_month(at(2024, 12, 31)),
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()
+        );
+        let name = partition_name("event", at(2024, 5, 17));
+        assert_eq!(name, "event_y2024m05");
+        assert_eq!(
+            partition_month("event", &name),
+            Some(month_start(at(2024, 5, 1)))
+        );
+        assert_eq!(partition_month("tag", &name), None);
+        assert_eq!(partition_month("event", "event_default"), None);
+    }
+
+    #[test]
+    fn index_definitions_are_copied() {
+        assert_eq!(
+            partitioned_index_def(
+                "event_source_idx",
+                "CREATE INDEX event_source_idx ON public.event USING btree (source, created_at) WHERE ((source)::text <> 'local'::text)",
+                "event"
+            )
+            .as_deref(),
+            Some("CREATE INDEX event_source_idx_p ON event_partitioned USING btree (source, created_at) WHERE ((source)::text <> 'local'::text)")
+        );
+        assert_eq!(
+            partitioned_index_def(
+                "other_idx",
+                "CREATE INDEX tag_idx ON tag USING btree (a)",
+                "tag"
+            ),
+            None
+        );
+    }
+
+    fn event(id: u8, created_at: DateTime<Utc>, tags: Vec<Vec<String>>) -> Event {
+        Event {
+            id: format!("{id:02x}").repeat(32),
+            pubkey: "ab".repeat(32),
+            delegated_by: None,
+            created_at: created_at.timestamp() as u64,
+            kind: 1,
+            tags,
+            content: "".to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        }
+    }
+
+    async fn partition_of(db: &PostgresPool, table: &str, id: u8) -> Result<String> {
+        let column = if table == "tag" { "event_id" } else { "id" };
+        Ok(sqlx::query_scalar(&format!(
+            "SELECT tableoid::regclass::TEXT FROM {table} WHERE {column} = $1 LIMIT 1"
+        ))
+        .bind(vec![id; 32])
+        .fetch_one(db)
+        .await?)
+    }
+
+    /// Events and their tags are routed to their month's partitions, and
+    /// dropping a month keeps the tags of the others, which still go
+    /// with their event, and the deletion requests of the month.  Needs a PostgreSQL server: set
+    /// `NOSTR_TEST_POSTGRES` to a connection string and run with
+    /// `--ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn events_and_tags_route_to_month_partitions() -> Result<()> {
+        let url = std::env::var("NOSTR_TEST_POSTGRES").expect("NOSTR_TEST_POSTGRES is not set");
+        let schema = format!("partition_test_{}", crate::utils::unix_time());
+        let mut settings = Settings::default();
+        settings.database.postgres_schema = Some(schema.clone());
+        let db = pool_options(&settings)
+            .max_connections(2)
+            .connect(&url)
+            .await?;
+        run_migrations(&db, Some(&schema), None).await?;
+        let repo = PostgresRepo::new(
+            db.clone(),
+            db.clone(),
+            crate::server::create_metrics().1,
+            &settings,
+        );
+        let tagged = |v: &str| vec![vec!["t".to_owned(), v.to_owned()]];
+        // an event stored before the conversion is moved
+        repo.write_event(&event(1, at(2023, 1, 10), tagged("old")))
+            .await?;
+        assert_eq!(convert(&db).await?, 1);
+        assert!(is_partitioned(&db).await?);
+        assert!(convert(&db).await.is_err());
+        assert_eq!(partition_of(&db, "event", 1).await?, "event_y2023m01");
+        assert_eq!(partition_of(&db, "tag", 1).await?, "tag_y2023m01");
+        // new events go to their month, creating it if needed
+        for (id, t) in [(2, at(2023, 1, 20)), (3, at(2023, 2, 1)), (4, Utc::now())] {
+            assert_eq!(
+                repo.write_event(&event(id, t, tagged("x"))).await?,
+                WriteResult::Added(1)
+            );
+        }
+        assert_eq!(
+            repo.write_event(&event(3, at(2023, 2, 1), tagged("x")))
+                .await?,
+            WriteResult::Added(0)
+        );
+        assert_eq!(partition_of(&db, "event", 2).await?, "event_y2023m01");
+        assert_eq!(partition_of(&db, "event", 3).await?, "event_default");
+        assert_eq!(partition_of(&db, "tag", 3).await?, "tag_default");
+        assert_eq!(
+            partition_of(&db, "event", 4).await?,
+            partition_name("event", Utc::now())
+        );
+        // creating the month moves stranded rows out of the default
+        let mut tx = db.begin().await?;
+        create_month(&mut tx, at(2023, 2, 1)).await?;
+        tx.commit().await?;
+        assert_eq!(partition_of(&db, "event", 3).await?, "event_y2023m02");
+        assert_eq!(partition_of(&db, "tag", 3).await?, "tag_y2023m02");
+        // a deletion request, and a restricted kind first seen now,
+        // are kept when their month is dropped
+        let mut deletion = event(
+            5,
+            at(2023, 1, 25),
+            vec![vec!["e".to_owned(), "ee".repeat(32)]],
+        );
+        deletion.kind = 5;
+        let mut wrap = event(6, at(2023, 1, 26), tagged("wrap"));
+        wrap.kind = 1059;
+        for e in [&deletion, &wrap] {
+            assert_eq!(repo.write_event(e).await?, WriteResult::Added(1));
+        }
+        // an event from before the first month lands in the default
+        repo.write_event(&event(7, at(2022, 6, 1), tagged("backdated")))
+            .await?;
+        assert_eq!(partition_of(&db, "event", 7).await?, "event_default");
+        // dropping January removes its other events and tags only
+        let dropped = drop_partitions_before(&db, at(2023, 2, 15), &[1059]).await?;
+        assert_eq!(dropped, vec!["event_y2023m01"]);
+        for id in [5, 6] {
+            assert_eq!(partition_of(&db, "event", id).await?, "event_default");
+            assert_eq!(partition_of(&db, "tag", id).await?, "tag_default");
+        }
+        let tags: i64 = sqlx::query_scalar("SELECT count(*) FROM tag")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(tags, 5);
+        // the default partition is expired row by row
+        assert_eq!(
+            expire_default_partition(&db, at(2023, 2, 15), &[1059]).await?,
+            1
+        );
+        assert!(partition_of(&db, "event", 7).await.is_err());
+        assert_eq!(partition_of(&db, "event", 5).await?, "event_default");
+        assert_eq!(partition_of(&db, "event", 6).await?, "event_default");
+        // deleting an event still removes its tags
+        sqlx::query("DELETE FROM \"event\" WHERE id = $1")
+            .bind(vec![3u8; 32])
+            .execute(&db)
+            .await?;
+        let tags: i64 = sqlx::query_scalar("SELECT count(*) FROM tag")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(tags, 3);
+        // upcoming months are kept ready
+        assert!(ensure_partitions(&db, Utc::now()).await?.is_empty());
+        let ahead = (0..PARTITIONS_AHEAD).fold(Utc::now(), |t, _| next_month(t));
+        assert!(partitions(&db, "event")
+            .await?
+            .iter()
+            .any(|(_, m)| *m == month_start(ahead)));
+        sqlx::query(&format!("DROP SCHEMA \"{schema}\" CASCADE"))
+            .execute(&db)
+            .await?;
+        Ok(())
+    }
+}
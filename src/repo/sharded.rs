@@ -0,0 +1,807 @@
+//! Event storage split across several repositories by event id
+//!
+//! Each event is stored on the shard that owns its id.  Events that
+//! change other events (deletions, and replaceable events) are written
+//! to every shard, so each shard applies them to the events it holds;
+//! queries drop the extra copies.  Other tables (accounts, invoices,
+//! verification records, watermarks, write statistics and quarantined
+//! pubkeys) are only kept on the first shard.
+use crate::db::QueryResult;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::nip05::VerificationRecord;
+use crate::payment::{
+    InvoiceInfo, InvoiceStatus, LedgerEntry, LedgerMismatch, LedgerReason, PaymentStats,
+};
+use crate::quarantine::{PubkeyStatus, QuarantinedPubkey};
+use crate::repo::{NostrRepo, WriteResult};
+use crate::source::SourceCount;
+use crate::stats::{EventStat, TopWriter};
+use crate::subscription::{ReqFilter, Subscription};
+use crate::user_data::{UserDataCounts, UserRecord};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use nostr::Keys;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+/// Leading hex digits of an event id that choose its shard
+const ROUTING_HEX_DIGITS: usize = 16;
+
+/// Results buffered from each shard while merging a query
+const SHARD_QUERY_BUFFER: usize = 64;
+
+/// The shard that owns an event id (or an id prefix long enough to
+/// route), if it can be determined.  Ids are hashes, so their leading
+/// digits spread events evenly.
+#[must_use]
+pub fn shard_for_id(id: &str, shards: usize) -> Option<usize> {
+    let prefix = id.get(..ROUTING_HEX_DIGITS)?;
+    let n = u64::from_str_radix(prefix, 16).ok()?;
+    Some((n % shards as u64) as usize)
+}
+
+/// Is an event of this kind written to every shard?  Deletions and
+/// replaceable events must reach the shards holding the events they
+/// replace.
+fn is_replicated_kind(kind: u64) -> bool {
+    kind == 5
+        || kind == 0
+        || kind == 3
+        || kind == 41
+        || (10000..20000).contains(&kind)
+        || (30000..40000).contains(&kind)
+}
+
+/// Repository that spreads events over several inner repositories
+pub struct ShardedRepo {
+    shards: Vec<Arc<dyn NostrRepo>>,
+}
+
+impl ShardedRepo {
+    /// Shard events over `shards`, which must not be empty.  The
+    /// order decides which shard owns each id, so it must not change
+    /// once events are stored.
+    #[must_use]
+    pub fn new(shards: Vec<Arc<dyn NostrRepo>>) -> ShardedRepo {
+        assert!(!shards.is_empty(), "a sharded repository needs shards");
+        ShardedRepo { shards }
+    }
+
+    /// Repository holding tables that are not split by event id
+    fn first(&self) -> &Arc<dyn NostrRepo> {
+        &self.shards[0]
+    }
+
+    /// Index of the shard that stores an event
+    fn owner(&self, event_id: &str) -> usize {
+        shard_for_id(event_id, self.shards.len()).unwrap_or(0)
+    }
+}
+
+/// Which shards can hold results for a filter, and the filter each one
+/// should run.  Filters for complete ids only go to the shards owning
+/// those ids.
+fn route_filter(f: &ReqFilter, shards: usize) -> Vec<(usize, ReqFilter)> {
+    let routed = f
+        .ids
+        .as_ref()
+        .filter(|ids| !ids.is_empty())
+        .and_then(|ids| {
+            let mut by_shard: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+            for id in ids {
+                by_shard
+                    .entry(shard_for_id(id, shards)?)
+                    .or_default()
+                    .push(id.clone());
+            }
+            Some(by_shard)
+        });
+    match routed {
+        Some(by_shard) => by_shard
+            .into_iter()
+            .map(|(shard, ids)| {
+                (
+                    shard,
+                    ReqFilter {
+                        ids: Some(ids),
+                        ..f.clone()
+                    },
+                )
+            })
+            .collect(),
+        None => (0..shards).map(|shard| (shard, f.clone())).collect(),
+    }
+}
+
+/// The parts of a serialized event needed to merge query results
+#[derive(Deserialize)]
+struct SortKey {
+    id: String,
+    created_at: u64,
+    kind: u64,
+}
+
+/// Results of one filter's query on one shard.  The shard's query is
+/// abandoned if this is dropped before the results run out.
+struct ShardResults {
+    rx: mpsc::Receiver<QueryResult>,
+    abandon: Option<oneshot::Sender<()>>,
+    head: Option<(SortKey, QueryResult)>,
+    /// The shard's query stopped at its deadline
+    timed_out: bool,
+}
+
+impl ShardResults {
+    /// Move to the next result, which is [`None`] once the shard has
+    /// finished.
+    async fn advance(&mut self) {
+        self.head = None;
+        while let Some(result) = self.rx.recv().await {
+            if result.event == "EOSE" {
+                self.abandon = None;
+                self.timed_out = result.timed_out;
+                return;
+            }
+            match serde_json::from_str::<SortKey>(&result.event) {
+                Ok(key) => {
+                    self.head = Some((key, result));
+                    return;
+                }
+                Err(err) => warn!("skipping unparseable stored event: {:?}", err),
+            }
+        }
+        self.abandon = None;
+    }
+}
+
+impl Drop for ShardResults {
+    fn drop(&mut self) {
+        if let Some(abandon) = self.abandon.take() {
+            abandon.send(()).ok();
+        }
+    }
+}
+
+/// Query every shard that may hold events for one filter, and send
+/// their results in order, up to the filter's limit.  Like a single
+/// repository, results are newest first when limited, and oldest
+/// first otherwise.  Returns whether a shard's query stopped at its
+/// deadline, or [`None`] if the results could not be sent.
+async fn merge_filter(
+    shards: &[Arc<dyn NostrRepo>],
+    sub: &Subscription,
+    filter: &ReqFilter,
+    client_id: &str,
+    query_tx: &mpsc::Sender<QueryResult>,
+) -> Option<bool> {
+    let mut streams = vec![];
+    for (shard, shard_filter) in route_filter(filter, shards.len()) {
+        let (tx, rx) = mpsc::channel(SHARD_QUERY_BUFFER);
+        let (abandon_tx, abandon_rx) = oneshot::channel();
+        let shard_sub = Subscription {
+            filters: vec![shard_filter],
+            ..sub.clone()
+        };
+        let started = shards[shard]
+            .query_subscription(shard_sub, client_id.to_owned(), tx, abandon_rx)
+            .await;
+        if let Err(err) = started {
+            warn!("could not query shard {}: {:?}", shard, err);
+            continue;
+        }
+        let mut results = ShardResults {
+            rx,
+            abandon: Some(abandon_tx),
+            head: None,
+            timed_out: false,
+        };
+        results.advance().await;
+        streams.push(results);
+    }
+    let newest_first = filter.limit.is_some();
+    let ordering = |a: &SortKey, b: &SortKey| {
+        let by_time = a.created_at.cmp(&b.created_at);
+        if newest_first {
+            by_time.reverse()
+        } else {
+            by_time
+        }
+    };
+    // replicated events are returned by every shard
+    let mut replicated_sent = HashSet::new();
+    let mut sent = 0;
+    while filter.limit.map_or(true, |limit| sent < limit) {
+        let next = streams
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.head.as_ref().map(|(key, _)| (i, key)))
+            .reduce(|best, cand| match ordering(cand.1, best.1) {
+                Ordering::Less => cand,
+                _ => best,
+            })
+            .map(|(i, _)| i);
+        let Some(i) = next else {
+            break;
+        };
+        let (key, result) = streams[i].head.take().unwrap();
+        streams[i].advance().await;
+        if is_replicated_kind(key.kind) && !replicated_sent.insert(key.id) {
+            continue;
+        }
+        if query_tx.send(result).await.is_err() {
+            return None;
+        }
+        sent += 1;
+    }
+    Some(streams.iter().any(|s| s.timed_out))
+}
+
+#[async_trait]
+impl NostrRepo for ShardedRepo {
+    async fn start(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.start().await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_up(&self) -> Result<usize> {
+        let mut version = 0;
+        for shard in &self.shards {
+            version = shard.migrate_up().await?;
+        }
+        Ok(version)
+    }
+
+    /// Write an event to the shard owning it, or to every shard for
+    /// deletions and replaceable events.  Returns the result on the
+    /// owning shard.
+    async fn write_event(&self, e: &Event) -> Result<WriteResult> {
+        let owner = self.owner(&e.id);
+        if !is_replicated_kind(e.kind) {
+            return self.shards[owner].write_event(e).await;
+        }
+        let mut written = WriteResult::Added(0);
+        for (i, shard) in self.shards.iter().enumerate() {
+            let result = shard.write_event(e).await?;
+            if i == owner {
+                written = result;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Query the shards filter by filter, merging their results.
+    async fn query_subscription(
+        &self,
+        sub: Subscription,
+        client_id: String,
+        query_tx: mpsc::Sender<QueryResult>,
+        mut abandon_query_rx: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let shards = self.shards.clone();
+        tokio::task::spawn(async move {
+            let mut timed_out = false;
+            for filter in &sub.filters {
+                tokio::select! {
+                    sent = merge_filter(&shards, &sub, filter, &client_id, &query_tx) => {
+                        match sent {
+                            None => return,
+                            // later filters would only run past the deadline too
+                            Some(true) => {
+                                timed_out = true;
+                                break;
+                            }
+                            Some(false) => {}
+                        }
+                    }
+                    _ = &mut abandon_query_rx => {
+                        debug!(
+                            "sharded query cancelled by client (cid: {}, sub: {:?})",
+                            client_id, sub.id
+                        );
+                        return;
+                    }
+                }
+            }
+            query_tx
+                .send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: "EOSE".to_string(),
+                    first_seen: None,
+                    generation: sub.generation,
+                    timed_out,
+                })
+                .await
+                .ok();
+        });
+        Ok(())
+    }
+
+    async fn optimize_db(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.optimize_db().await?;
+        }
+        Ok(())
+    }
+
+    /// Total of the events on each shard, counting deletions and
+    /// replaceable events once.
+    async fn count_events(&self) -> Result<u64> {
+        Ok(self.count_by_kind().await?.iter().map(|(_, n)| n).sum())
+    }
+
+    async fn count_events_since(&self, since: u64) -> Result<u64> {
+        let mut total = 0;
+        for shard in &self.shards {
+            total += shard.count_events_since(since).await?;
+        }
+        Ok(total)
+    }
+
+    /// Count events by source across every shard.  Replicated events
+    /// are counted on each shard.
+    async fn count_by_source(&self) -> Result<Vec<SourceCount>> {
+        let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+        for shard in &self.shards {
+            for c in shard.count_by_source().await? {
+                *totals.entry(c.source).or_default() += c.count;
+            }
+        }
+        Ok(totals
+            .into_iter()
+            .map(|(source, count)| SourceCount { source, count })
+            .collect())
+    }
+
+    /// Count events by kind across every shard.  Every replicated event
+    /// is written to the first shard, so only its copies are counted.
+    async fn count_by_kind(&self) -> Result<Vec<(u64, u64)>> {
+        let mut totals: BTreeMap<u64, u64> = BTreeMap::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            for (kind, count) in shard.count_by_kind().await? {
+                if i == 0 || !is_replicated_kind(kind) {
+                    *totals.entry(kind).or_default() += count;
+                }
+            }
+        }
+        Ok(totals.into_iter().collect())
+    }
+
+    /// Delete matching events from every shard.  Like
+    /// [`count_by_source`](NostrRepo::count_by_source), replicated
+    /// events are counted on each shard.
+    async fn delete_matching(&self, filter: &ReqFilter, dry_run: bool) -> Result<u64> {
+        let mut total = 0;
+        for shard in &self.shards {
+            total += shard.delete_matching(filter, dry_run).await?;
+        }
+        Ok(total)
+    }
+
+    async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
+        // metadata events are replicated, so the first shard has it
+        self.first()
+            .create_verification_record(event_id, name)
+            .await
+    }
+
+    async fn update_verification_timestamp(&self, id: u64) -> Result<()> {
+        self.first().update_verification_timestamp(id).await
+    }
+
+    async fn fail_verification(&self, id: u64) -> Result<()> {
+        self.first().fail_verification(id).await
+    }
+
+    async fn delete_verification(&self, id: u64) -> Result<()> {
+        self.first().delete_verification(id).await
+    }
+
+    async fn get_latest_user_verification(&self, pub_key: &str) -> Result<VerificationRecord> {
+        self.first().get_latest_user_verification(pub_key).await
+    }
+
+    async fn get_oldest_user_verification(&self, before: u64) -> Result<VerificationRecord> {
+        self.first().get_oldest_user_verification(before).await
+    }
+
+    async fn create_account(&self, pubkey: &Keys) -> Result<bool> {
+        self.first().create_account(pubkey).await
+    }
+
+    async fn admit_account(
+        &self,
+        pubkey: &Keys,
+        admission_cost: u64,
+        reference: Option<&str>,
+    ) -> Result<()> {
+        self.first()
+            .admit_account(pubkey, admission_cost, reference)
+            .await
+    }
+
+    async fn get_account_balance(&self, pubkey: &Keys) -> Result<(bool, u64)> {
+        self.first().get_account_balance(pubkey).await
+    }
+
+    async fn update_account_balance(
+        &self,
+        pub_key: &Keys,
+        positive: bool,
+        new_balance: u64,
+        reason: LedgerReason,
+        reference: Option<&str>,
+    ) -> Result<()> {
+        self.first()
+            .update_account_balance(pub_key, positive, new_balance, reason, reference)
+            .await
+    }
+
+    async fn published_event_count(&self, pubkey: &Keys) -> Result<u64> {
+        self.first().published_event_count(pubkey).await
+    }
+
+    async fn count_published_event(&self, pubkey: &Keys) -> Result<u64> {
+        self.first().count_published_event(pubkey).await
+    }
+
+    async fn get_ledger(&self, pubkey: &Keys, limit: Option<u64>) -> Result<Vec<LedgerEntry>> {
+        self.first().get_ledger(pubkey, limit).await
+    }
+
+    async fn verify_ledger(&self) -> Result<Vec<LedgerMismatch>> {
+        self.first().verify_ledger().await
+    }
+
+    async fn create_invoice_record(&self, pubkey: &Keys, invoice_info: InvoiceInfo) -> Result<()> {
+        self.first()
+            .create_invoice_record(pubkey, invoice_info)
+            .await
+    }
+
+    async fn update_invoice(&self, payment_hash: &str, status: InvoiceStatus) -> Result<String> {
+        self.first().update_invoice(payment_hash, status).await
+    }
+
+    async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>> {
+        self.first().get_unpaid_invoice(pubkey).await
+    }
+
+    async fn get_unpaid_invoices(&self, since: u64) -> Result<Vec<InvoiceInfo>> {
+        self.first().get_unpaid_invoices(since).await
+    }
+
+    async fn confirm_invoice(&self, payment_hash: &str) -> Result<Option<u64>> {
+        self.first().confirm_invoice(payment_hash).await
+    }
+
+    async fn get_payment_stats(&self, unpaid_since: u64) -> Result<PaymentStats> {
+        self.first().get_payment_stats(unpaid_since).await
+    }
+
+    async fn get_watermark(&self, pubkey: &str, sub_key: &str) -> Result<Option<u64>> {
+        self.first().get_watermark(pubkey, sub_key).await
+    }
+
+    async fn save_watermark(&self, pubkey: &str, sub_key: &str, created_at: u64) -> Result<()> {
+        self.first()
+            .save_watermark(pubkey, sub_key, created_at)
+            .await
+    }
+
+    async fn add_event_stats(&self, stats: &[EventStat]) -> Result<()> {
+        self.first().add_event_stats(stats).await
+    }
+
+    async fn top_writers(
+        &self,
+        day: NaiveDate,
+        kind: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<TopWriter>> {
+        self.first().top_writers(day, kind, limit).await
+    }
+
+    async fn latest_created_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        // an author's events are spread over every shard
+        let mut latest = None;
+        for shard in &self.shards {
+            latest = latest.max(shard.latest_created_at(pubkey).await?);
+        }
+        Ok(latest)
+    }
+
+    async fn get_pubkey_status(&self, pubkey: &str) -> Result<Option<PubkeyStatus>> {
+        self.first().get_pubkey_status(pubkey).await
+    }
+
+    async fn add_first_seen_pubkey(
+        &self,
+        pubkey: &str,
+        first_seen: u64,
+        status: PubkeyStatus,
+    ) -> Result<()> {
+        self.first()
+            .add_first_seen_pubkey(pubkey, first_seen, status)
+            .await
+    }
+
+    async fn count_quarantined_event(&self, pubkey: &str) -> Result<u64> {
+        self.first().count_quarantined_event(pubkey).await
+    }
+
+    async fn set_pubkey_status(&self, pubkey: &str, status: PubkeyStatus) -> Result<()> {
+        self.first().set_pubkey_status(pubkey, status).await
+    }
+
+    async fn release_quarantined(&self, first_seen_before: u64) -> Result<Vec<String>> {
+        self.first().release_quarantined(first_seen_before).await
+    }
+
+    async fn withheld_pubkeys(&self) -> Result<Vec<QuarantinedPubkey>> {
+        self.first().withheld_pubkeys().await
+    }
+
+    async fn write_pending_event(&self, e: &Event) -> Result<u64> {
+        // only events that do not change other events are admitted
+        // asynchronously, so these are never replicated.
+        self.shards[self.owner(&e.id)].write_pending_event(e).await
+    }
+
+    async fn resolve_pending_event(&self, event_id: &str, accepted: bool) -> Result<bool> {
+        self.shards[self.owner(event_id)]
+            .resolve_pending_event(event_id, accepted)
+            .await
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<Event>> {
+        let mut pending = vec![];
+        for shard in &self.shards {
+            pending.extend(shard.get_pending_events().await?);
+        }
+        Ok(pending)
+    }
+
+    async fn export_events_by(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<u64> {
+        let mut sent = 0;
+        for (index, shard) in self.shards.iter().enumerate() {
+            let (shard_tx, mut shard_rx) = mpsc::channel::<UserRecord>(SHARD_QUERY_BUFFER);
+            let forward = async {
+                while let Some(record) = shard_rx.recv().await {
+                    // replicated events are only exported from their owner
+                    let key: SortKey = serde_json::from_str(&record.json)?;
+                    if self.owner(&key.id) != index {
+                        continue;
+                    }
+                    tx.send(record)
+                        .await
+                        .map_err(|_| Error::CustomError("export abandoned".to_owned()))?;
+                    sent += 1;
+                }
+                Ok::<_, Error>(())
+            };
+            let (exported, forwarded) =
+                tokio::join!(shard.export_events_by(pubkey, shard_tx), forward);
+            forwarded?;
+            exported?;
+        }
+        Ok(sent)
+    }
+
+    async fn export_account_data(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<()> {
+        self.first().export_account_data(pubkey, tx).await
+    }
+
+    async fn erase_events_by(&self, pubkey: &str) -> Result<u64> {
+        // copies of replicated events are counted on each shard
+        let mut erased = 0;
+        for shard in &self.shards {
+            erased += shard.erase_events_by(pubkey).await?;
+        }
+        Ok(erased)
+    }
+
+    async fn erase_account_data(
+        &self,
+        pubkey: &str,
+        erased_at: u64,
+        tombstone_until: Option<u64>,
+    ) -> Result<UserDataCounts> {
+        self.first()
+            .erase_account_data(pubkey, erased_at, tombstone_until)
+            .await
+    }
+
+    async fn erased_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        self.first().erased_at(pubkey).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::repo::sqlite::SqliteRepo;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+
+    /// A repository backed by its own in-memory sqlite database
+    async fn memory_repo() -> Result<Arc<dyn NostrRepo>> {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        settings.database.data_directory = format!("sharded-test-{}", NEXT_DB.fetch_add(1, SeqCst));
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        Ok(Arc::new(repo))
+    }
+
+    /// An event whose id repeats the byte `id`.  With two shards, odd
+    /// ids are stored on the second.
+    fn test_event(id: u8, kind: u64, created_at: u64) -> Event {
+        Event {
+            id: format!("{id:02x}").repeat(32),
+            pubkey: "ab".repeat(32),
+            delegated_by: None,
+            created_at,
+            kind,
+            tags: vec![],
+            content: String::new(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        }
+    }
+
+    fn event_id(id: u8) -> String {
+        format!("{id:02x}").repeat(32)
+    }
+
+    /// Ids of the events a REQ returns, in the order they are sent
+    async fn query(repo: &dyn NostrRepo, req: &str) -> Result<Vec<String>> {
+        let sub: Subscription = serde_json::from_str(req)?;
+        let (tx, mut rx) = mpsc::channel(4);
+        let (_abandon_tx, abandon_rx) = oneshot::channel();
+        repo.query_subscription(sub, "test".to_owned(), tx, abandon_rx)
+            .await?;
+        let mut ids = vec![];
+        while let Some(result) = rx.recv().await {
+            if result.event == "EOSE" {
+                break;
+            }
+            let key: SortKey = serde_json::from_str(&result.event)?;
+            ids.push(key.id);
+        }
+        Ok(ids)
+    }
+
+    #[test]
+    fn ids_route_by_leading_digits() {
+        assert_eq!(shard_for_id(&event_id(1), 2), Some(1));
+        assert_eq!(shard_for_id(&event_id(2), 2), Some(0));
+        assert_eq!(shard_for_id(&event_id(2)[..16], 2), Some(0));
+        // short prefixes could match events on any shard
+        assert_eq!(shard_for_id("0202", 2), None);
+        assert_eq!(shard_for_id(&"zz".repeat(32), 2), None);
+        let f: ReqFilter = serde_json::from_str(&format!(
+            "{{\"ids\":[\"{}\",\"{}\",\"{}\"]}}",
+            event_id(1),
+            event_id(2),
+            event_id(3)
+        ))
+        .unwrap();
+        let routed = route_filter(&f, 2);
+        assert_eq!(routed.len(), 2);
+        assert_eq!(routed[0].1.ids, Some(vec![event_id(2)]));
+        assert_eq!(routed[1].1.ids, Some(vec![event_id(1), event_id(3)]));
+        let prefixes: ReqFilter = serde_json::from_str("{\"ids\":[\"0101\"]}").unwrap();
+        assert_eq!(route_filter(&prefixes, 2).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn two_shards_conform_to_one_repository() -> Result<()> {
+        let single = memory_repo().await?;
+        let sharded = ShardedRepo::new(vec![memory_repo().await?, memory_repo().await?]);
+        let mut deletion = test_event(9, 5, 1009);
+        deletion.tags = vec![vec!["e".to_owned(), event_id(4)]];
+        let writes = [
+            (test_event(1, 1, 1001), 1),
+            (test_event(2, 1, 1002), 1),
+            (test_event(3, 1, 1003), 1),
+            (test_event(4, 1, 1004), 1),
+            (test_event(5, 0, 1005), 1),
+            // replaces the metadata stored on the other shard
+            (test_event(6, 0, 1006), 1),
+            // older than the stored metadata
+            (test_event(7, 0, 900), 0),
+            (deletion, 1),
+            // a duplicate is not written again
+            (test_event(1, 1, 1001), 0),
+        ];
+        for (e, added) in &writes {
+            assert_eq!(
+                single.write_event(e).await?.rows_added(),
+                *added,
+                "{}",
+                e.id
+            );
+            assert_eq!(
+                sharded.write_event(e).await?.rows_added(),
+                *added,
+                "{}",
+                e.id
+            );
+        }
+        let requests = [
+            "[\"REQ\",\"s\",{}]".to_owned(),
+            "[\"REQ\",\"s\",{\"limit\":3}]".to_owned(),
+            "[\"REQ\",\"s\",{\"kinds\":[0]}]".to_owned(),
+            "[\"REQ\",\"s\",{\"kinds\":[1],\"limit\":1},{\"kinds\":[5]}]".to_owned(),
+            format!(
+                "[\"REQ\",\"s\",{{\"ids\":[\"{}\",\"{}\",\"{}\"]}}]",
+                event_id(3),
+                event_id(4),
+                event_id(6)
+            ),
+        ];
+        for req in &requests {
+            assert_eq!(
+                query(&sharded, req).await?,
+                query(single.as_ref(), req).await?,
+                "{req}"
+            );
+        }
+        assert_eq!(
+            query(&sharded, &requests[0]).await?,
+            [1, 2, 3, 6, 9].map(event_id)
+        );
+        assert_eq!(
+            query(&sharded, &requests[1]).await?,
+            [9, 6, 3].map(event_id)
+        );
+        // the copies of replicated events are only counted once
+        assert_eq!(sharded.count_events().await?, single.count_events().await?);
+        assert_eq!(
+            sharded.count_by_kind().await?,
+            single.count_by_kind().await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn id_lookups_only_query_the_owning_shard() -> Result<()> {
+        let (first, second) = (memory_repo().await?, memory_repo().await?);
+        let sharded = ShardedRepo::new(vec![first.clone(), second]);
+        // stored on a shard that does not own the id
+        first.write_event(&test_event(1, 1, 1000)).await?;
+        sharded.write_event(&test_event(2, 1, 1001)).await?;
+        let by_ids = format!(
+            "[\"REQ\",\"s\",{{\"ids\":[\"{}\",\"{}\"]}}]",
+            event_id(1),
+            event_id(2)
+        );
+        assert_eq!(query(&sharded, &by_ids).await?, vec![event_id(2)]);
+        assert_eq!(
+            query(&sharded, "[\"REQ\",\"s\",{\"kinds\":[1]}]").await?,
+            [1, 2].map(event_id)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accounts_are_kept_on_the_first_shard() -> Result<()> {
+        let (first, second) = (memory_repo().await?, memory_repo().await?);
+        let sharded = ShardedRepo::new(vec![first.clone(), second.clone()]);
+        let keys = Keys::generate();
+        assert!(sharded.create_account(&keys).await?);
+        assert!(!first.create_account(&keys).await?);
+        assert!(second.create_account(&keys).await?);
+        Ok(())
+    }
+}
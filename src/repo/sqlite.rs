@@ -1,41 +1,61 @@
 //! Event persistence and querying
 //use crate::config::SETTINGS;
-use crate::config::Settings;
+use crate::compact::{DatabaseSize, RelationSize};
+use crate::config::{RetentionRule, Settings};
 use crate::db::QueryResult;
-use crate::error::{Error::SqlError, Result};
-use crate::event::{single_char_tagname, Event};
+use crate::error::{Error, Error::SqlError, Result};
+use crate::event::{is_indexable_tag_value, normalize_tag_value, single_char_tagname, Event};
 use crate::hexrange::hex_range;
 use crate::hexrange::HexSearch;
 use crate::nip05::{Nip05Name, VerificationRecord};
-use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::repo::sqlite_migration::{upgrade_db, STARTUP_SQL};
+use crate::payment::{
+    InvoiceInfo, InvoiceRefund, InvoiceStatus, LedgerEntry, LedgerMismatch, LedgerReason,
+    PaymentStats,
+};
+use crate::quarantine::{PubkeyStatus, QuarantinedPubkey};
+use crate::repo::sqlite_migration::{db_oversize_tag_count, upgrade_db, STARTUP_SQL};
 use crate::server::NostrMetrics;
+use crate::source::SourceCount;
+use crate::stats::{day_of, EventStat, TopWriter};
 use crate::subscription::{ReqFilter, Subscription};
+use crate::user_data::{UserDataCounts, UserRecord, UserTable};
 use crate::utils::{is_hex, unix_time};
 use async_trait::async_trait;
+use chrono::NaiveDate;
 use hex;
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
 use rusqlite::types::ToSql;
 use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use serde_json::json;
+use std::borrow::Cow;
 use std::fmt::Write as _;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
-use tokio::sync::{Mutex, MutexGuard, Semaphore};
+use tokio::sync::{mpsc, Mutex, MutexGuard, Semaphore};
 use tokio::task;
 use tracing::{debug, info, trace, warn};
 
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::{
+    can_defer_tags, now_jitter, query_abandoned, retry_transient, EvictionBatch, NostrRepo,
+    TagIndexBatch, WriteResult, DELETE_BATCH, EVICTION_BATCH, QUERY_CHUNK_ROWS, TAG_INDEX_BATCH,
+};
 use nostr::key::Keys;
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 pub const DB_FILE: &str = "nostr.db";
 
+/// Most ids looked up in a single query, well under SQLite's limit on
+/// bound parameters.
+const DIRECT_FETCH_CHUNK: usize = 1000;
+
 #[derive(Clone)]
 pub struct SqliteRepo {
     /// Metrics
@@ -52,31 +72,79 @@ pub struct SqliteRepo {
     write_in_progress: Arc<Mutex<u64>>,
     /// Semaphore for readers to acquire blocking threads
     reader_threads_ready: Arc<Semaphore>,
+    /// Maximum length of a tag value added to the tag index
+    max_indexed_tag_value_bytes: Option<usize>,
+    /// Maximum length of a tag value accepted by the relay
+    max_tag_value_bytes: Option<usize>,
+    /// Days to keep events (of some sources) for, if limited
+    retention_rules: Vec<RetentionRule>,
+    /// Kinds whose retention is based on when they were first seen
+    restricted_read_kinds: Vec<u64>,
+    /// Attempts for event writes that find the database busy or locked
+    write_attempts: u32,
+    /// Days to keep subscription watermarks, if resuming is enabled
+    watermark_days: Option<u64>,
+    /// Days to keep write statistics, if they are recorded
+    stats_days: Option<u64>,
+    /// Seconds that deleted events stay recoverable before being purged
+    purge_delay: u64,
+    /// Store events before indexing their tags in the background
+    deferred_tags: bool,
+    /// Longest wait before deferred tags are indexed
+    tag_index_delay: Duration,
+    /// Most events to store, evicting the oldest beyond this
+    max_stored_events: Option<u64>,
+    /// Longest a stored query runs before its results are cut short
+    query_timeout: Option<Duration>,
+}
+
+/// How [`SqliteRepo::store_event`] stores an event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StoreOptions {
+    /// Seconds that events hidden by a deletion can be restored,
+    /// before they are purged; zero hides them with no purge time
+    pub purge_delay: u64,
+    /// Leave the tags, if the event allows it, to be indexed later
+    /// by [`index_deferred_tags`]
+    pub defer_tags: bool,
+    /// Store the event hidden until [`resolve_pending`] admits or
+    /// removes it
+    pub pending: bool,
 }
 
 impl SqliteRepo {
     // build all the pools needed
     #[must_use]
     pub fn new(settings: &Settings, metrics: NostrMetrics) -> SqliteRepo {
-        let write_pool = build_pool(
+        // each repository kept in memory gets its own database, even
+        // with the same data directory as another
+        let memory_db = format!(
+            "{}-{}",
+            settings.database.data_directory,
+            MEMORY_DATABASES.fetch_add(1, Ordering::SeqCst)
+        );
+        let write_pool = build_named_pool(
             "writer",
             settings,
+            &memory_db,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             0,
             2,
             false,
         );
-        let maint_pool = build_pool(
+        let maint_pool = build_named_pool(
             "maintenance",
             settings,
+            &memory_db,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             0,
             2,
             true,
         );
-        let read_pool = build_pool(
+        let read_pool = build_named_pool(
             "reader",
             settings,
+            &memory_db,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
             settings.database.min_conn,
             settings.database.max_conn,
@@ -100,11 +168,57 @@ impl SqliteRepo {
             checkpoint_in_progress,
             write_in_progress,
             reader_threads_ready,
+            max_indexed_tag_value_bytes: settings.limits.max_indexed_tag_value_bytes,
+            max_tag_value_bytes: settings.limits.max_tag_value_bytes,
+            retention_rules: settings.retention.rules(),
+            restricted_read_kinds: settings.authorization.restricted_read_kinds.clone(),
+            write_attempts: settings.database.write_attempts,
+            watermark_days: settings
+                .options
+                .resume_subscriptions
+                .then_some(settings.options.resume_watermark_days),
+            stats_days: settings
+                .stats
+                .enabled
+                .then_some(settings.stats.retention_days),
+            purge_delay: settings.retention.purge_delay_hours * 3600,
+            deferred_tags: settings.database.deferred_tag_indexing,
+            tag_index_delay: Duration::from_millis(settings.database.tag_index_delay_ms),
+            max_stored_events: settings.limits.max_stored_events.filter(|max| *max > 0),
+            query_timeout: settings
+                .database
+                .query_timeout_ms
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
         }
     }
 
     /// Persist an event to the database, returning rows added.
-    pub fn persist_event(conn: &mut PooledConnection, e: &Event) -> Result<u64> {
+    ///
+    /// Tag values longer than `max_tag_bytes` are not added to the
+    /// tag table.
+    pub fn persist_event(
+        conn: &mut PooledConnection,
+        e: &Event,
+        max_tag_bytes: Option<usize>,
+    ) -> Result<WriteResult> {
+        SqliteRepo::store_event(conn, e, max_tag_bytes, StoreOptions::default())
+    }
+
+    /// Persist an event to the database, like
+    /// [`SqliteRepo::persist_event`], as set by `options`.
+    pub fn store_event(
+        conn: &mut PooledConnection,
+        e: &Event,
+        max_tag_bytes: Option<usize>,
+        options: StoreOptions,
+    ) -> Result<WriteResult> {
+        let StoreOptions {
+            purge_delay,
+            defer_tags,
+            pending,
+        } = options;
+        let defer_tags = defer_tags && can_defer_tags(e);
         // enable auto vacuum
         conn.execute_batch("pragma auto_vacuum = FULL")?;
 
@@ -116,58 +230,47 @@ impl SqliteRepo {
         let delegator_blob: Option<Vec<u8>> =
             e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
         let event_str = serde_json::to_string(&e).ok();
-        // check for replaceable events that would hide this one; we won't even attempt to insert these.
+        let purge_after = purge_time(purge_delay);
+        // check for replaceable events that would hide this one; we
+        // won't even attempt to insert these.  Within the same second,
+        // the lowest id wins.
         if e.is_replaceable() {
-            let repl_count = tx.query_row(
-                "SELECT e.id FROM event e INDEXED BY author_index WHERE e.author=? AND e.kind=? AND e.created_at >= ? LIMIT 1;",
-                params![pubkey_blob, e.kind, e.created_at], |row| row.get::<usize, usize>(0));
-            if repl_count.ok().is_some() {
-                return Ok(0);
+            let newer = tx.query_row(
+                "SELECT e.content, e.hidden OR e.pending FROM event e INDEXED BY author_index WHERE e.author=? AND e.kind=? AND (e.created_at > ? OR (e.created_at = ? AND e.event_hash < ?)) ORDER BY e.created_at DESC, e.event_hash ASC LIMIT 1;",
+                params![pubkey_blob, e.kind, e.created_at, e.created_at, id_blob], |row| Ok((row.get(0)?, row.get(1)?)));
+            if let Some(newer) = newer.optional()? {
+                return Ok(superseded_by(newer));
             }
         }
         // check for parameterized replaceable events that would be hidden; don't insert these either.
         if let Some(d_tag) = e.distinct_param() {
-            let repl_count = tx.query_row(
-                "SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.author=? AND e.kind=? AND t.name='d' AND t.value=? AND e.created_at >= ? LIMIT 1;",
-                params![pubkey_blob, e.kind, d_tag, e.created_at],|row| row.get::<usize, usize>(0));
+            let newer = tx.query_row(
+                "SELECT e.content, e.hidden OR e.pending FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.author=? AND e.kind=? AND t.name='d' AND t.value=? AND (e.created_at > ? OR (e.created_at = ? AND e.event_hash < ?)) ORDER BY e.created_at DESC, e.event_hash ASC LIMIT 1;",
+                params![pubkey_blob, e.kind, d_tag, e.created_at, e.created_at, id_blob], |row| Ok((row.get(0)?, row.get(1)?)));
             // if any rows were returned, then some newer event with
             // the same author/kind/tag value exist, and we can ignore
             // this event.
-            if repl_count.ok().is_some() {
-                return Ok(0);
+            if let Some(newer) = newer.optional()? {
+                return Ok(superseded_by(newer));
             }
         }
         // ignore if the event hash is a duplicate.
         let mut ins_count = tx.execute(
-            "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), FALSE);",
-            params![id_blob, e.created_at, e.expiration(), e.kind, pubkey_blob, delegator_blob, event_str]
+            "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden, tags_indexed, pending) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), ?8, ?9, ?8);",
+            params![id_blob, e.created_at, e.expiration(), e.kind, pubkey_blob, delegator_blob, event_str, pending, !defer_tags]
         )? as u64;
         if ins_count == 0 {
             // if the event was a duplicate, no need to insert event or
             // pubkey references.
             tx.rollback().ok();
-            return Ok(ins_count);
+            return Ok(WriteResult::Added(ins_count));
         }
         // remember primary key of the event most recently inserted.
         let ev_id = tx.last_insert_rowid();
-        // add all tags to the tag table
-        for tag in &e.tags {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tagname = &tag[0];
-                let tagval = &tag[1];
-                // only single-char tags are searchable
-                let tagchar_opt = single_char_tagname(tagname);
-                match &tagchar_opt {
-                    Some(_) => {
-                        tx.execute(
-                            "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-                            params![ev_id, &tagname, &tagval, e.kind, e.created_at],
-                        )?;
-                    }
-                    None => {}
-                }
-            }
+        // add all tags to the tag table, unless that is left to the
+        // background indexer.
+        if !defer_tags {
+            write_tags(&tx, ev_id, e, max_tag_bytes)?;
         }
         // if this event is replaceable update, remove other replaceable
         // event with the same kind from the same author that was issued
@@ -176,7 +279,7 @@ impl SqliteRepo {
             let author = hex::decode(&e.pubkey).ok();
             // this is a backwards check - hide any events that were older.
             let update_count = tx.execute(
-                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_index WHERE kind=? AND author=? ORDER BY created_at DESC LIMIT 1)",
+                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_index WHERE kind=? AND author=? ORDER BY created_at DESC, event_hash ASC LIMIT 1)",
                 params![e.kind, author, e.kind, author],
             )?;
             if update_count > 0 {
@@ -191,7 +294,7 @@ impl SqliteRepo {
         // if this event is parameterized replaceable, remove other events.
         if let Some(d_tag) = e.distinct_param() {
             let update_count = tx.execute(
-                "DELETE FROM event WHERE kind=? AND author=? AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=? AND e.author=? AND t.name='d' AND t.value=? ORDER BY t.created_at DESC LIMIT -1 OFFSET 1);",
+                "DELETE FROM event WHERE kind=? AND author=? AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=? AND e.author=? AND t.name='d' AND t.value=? ORDER BY t.created_at DESC, e.event_hash ASC LIMIT -1 OFFSET 1);",
                 params![e.kind, pubkey_blob, e.kind, pubkey_blob, d_tag])?;
             if update_count > 0 {
                 info!(
@@ -206,15 +309,16 @@ impl SqliteRepo {
         if e.kind == 5 {
             let event_candidates = e.tag_values_by_name("e");
             // first parameter will be author
-            let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(hex::decode(&e.pubkey)?)];
+            let mut params: Vec<Box<dyn ToSql>> =
+                vec![Box::new(purge_after), Box::new(hex::decode(&e.pubkey)?)];
             event_candidates
                 .iter()
                 .filter(|x| is_hex(x) && x.len() == 64)
                 .filter_map(|x| hex::decode(x).ok())
                 .for_each(|x| params.push(Box::new(x)));
             let query = format!(
-                "UPDATE event SET hidden=TRUE WHERE kind!=5 AND author=? AND event_hash IN ({})",
-                repeat_vars(params.len() - 1)
+                "UPDATE event SET hidden=TRUE, pending=FALSE, purge_after=? WHERE kind!=5 AND author=? AND event_hash IN ({})",
+                repeat_vars(params.len() - 2)
             );
             let mut stmt = tx.prepare(&query)?;
             let update_count = stmt.execute(rusqlite::params_from_iter(params))?;
@@ -238,8 +342,10 @@ impl SqliteRepo {
                     e.get_event_id_prefix(),
                     e.get_author_prefix()
                 );
-                let _update_count =
-                    tx.execute("UPDATE event SET hidden=TRUE WHERE id=?", params![ev_id])?;
+                let _update_count = tx.execute(
+                    "UPDATE event SET hidden=TRUE, pending=FALSE, purge_after=? WHERE id=?",
+                    params![purge_after, ev_id],
+                )?;
                 // event was deleted, so let caller know nothing new
                 // arrived, preventing this from being sent to active
                 // subscriptions
@@ -247,7 +353,125 @@ impl SqliteRepo {
             }
         }
         tx.commit()?;
-        Ok(ins_count)
+        Ok(WriteResult::Added(ins_count))
+    }
+}
+
+/// Result for a replaceable event superseded by a stored one, given
+/// the stored event's JSON and whether it is hidden.  Hidden (deleted
+/// or pending) versions still supersede, but are not shown.
+fn superseded_by((json, hidden): (String, bool)) -> WriteResult {
+    match serde_json::from_str::<Event>(&json) {
+        Ok(newer) if !hidden => WriteResult::Superseded(Box::new(newer)),
+        _ => WriteResult::Added(0),
+    }
+}
+
+/// Interrupts the statement running on a reader connection once its
+/// query has run for its time limit, unless dropped first.  Time spent
+/// paused, waiting for the client to take results, does not count.
+struct QueryWatchdog(Arc<std::sync::Mutex<WatchdogState>>);
+
+struct WatchdogState {
+    interrupt: Option<rusqlite::InterruptHandle>,
+    deadline: Instant,
+    paused_at: Option<Instant>,
+}
+
+impl QueryWatchdog {
+    fn start(
+        runtime: &tokio::runtime::Handle,
+        conn: &rusqlite::Connection,
+        timeout: Duration,
+    ) -> Self {
+        let state = Arc::new(std::sync::Mutex::new(WatchdogState {
+            interrupt: Some(conn.get_interrupt_handle()),
+            deadline: Instant::now() + timeout,
+            paused_at: None,
+        }));
+        let armed = state.clone();
+        runtime.spawn(async move {
+            loop {
+                let wake = {
+                    // the lock keeps a finished query from being interrupted
+                    let mut state = armed.lock().unwrap();
+                    if state.interrupt.is_none() {
+                        return;
+                    }
+                    let now = Instant::now();
+                    match state.paused_at {
+                        None if now >= state.deadline => {
+                            if let Some(interrupt) = state.interrupt.take() {
+                                interrupt.interrupt();
+                            }
+                            return;
+                        }
+                        None => state.deadline,
+                        // look again once the time left could have run out
+                        Some(paused_at) => {
+                            now + state
+                                .deadline
+                                .saturating_duration_since(paused_at)
+                                .max(Duration::from_millis(10))
+                        }
+                    }
+                };
+                tokio::time::sleep_until(wake.into()).await;
+            }
+        });
+        QueryWatchdog(state)
+    }
+
+    /// Has the query run for its time limit?
+    fn expired(&self) -> bool {
+        let state = self.0.lock().unwrap();
+        state.paused_at.is_none() && Instant::now() >= state.deadline
+    }
+
+    /// Stop counting time, while waiting for the client
+    fn pause(&self) {
+        self.0
+            .lock()
+            .unwrap()
+            .paused_at
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Count time again, moving the deadline back by the time paused
+    fn resume(&self) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(paused_at) = state.paused_at.take() {
+            state.deadline += paused_at.elapsed();
+        }
+    }
+}
+
+impl Drop for QueryWatchdog {
+    fn drop(&mut self) {
+        // the connection goes back to the pool; never interrupt it
+        self.0.lock().unwrap().interrupt.take();
+    }
+}
+
+/// The next row of a stored query, or [`None`] once the rows run out,
+/// or the query runs out of time (and the watchdog interrupts the step
+/// fetching the row).
+fn next_row<'r, 's>(
+    rows: &'r mut rusqlite::Rows<'s>,
+    watchdog: Option<&QueryWatchdog>,
+    timed_out: &mut bool,
+) -> Result<Option<&'r rusqlite::Row<'s>>> {
+    let past_deadline = || watchdog.map_or(false, QueryWatchdog::expired);
+    if past_deadline() {
+        *timed_out = true;
+        return Ok(None);
+    }
+    match rows.next() {
+        Err(_) if past_deadline() => {
+            *timed_out = true;
+            Ok(None)
+        }
+        row => Ok(row?),
     }
 }
 
@@ -261,6 +485,62 @@ impl NostrRepo for SqliteRepo {
             self.checkpoint_in_progress.clone(),
         )
         .await?;
+        if !self.retention_rules.is_empty() {
+            cleanup_old_events(
+                self.maint_pool.clone(),
+                Duration::from_secs(3600),
+                self.write_in_progress.clone(),
+                self.retention_rules.clone(),
+                self.restricted_read_kinds.clone(),
+                self.purge_delay,
+            )
+            .await?;
+        }
+        purge_deleted_events(
+            self.maint_pool.clone(),
+            Duration::from_secs(3600),
+            self.write_in_progress.clone(),
+        )
+        .await?;
+        if let Some(max_events) = self.max_stored_events {
+            evict_events_task(
+                self.maint_pool.clone(),
+                Duration::from_secs(60),
+                self.write_in_progress.clone(),
+                max_events,
+                self.metrics.clone(),
+            )
+            .await?;
+        }
+        if let Some(days) = self.watermark_days {
+            cleanup_watermarks(
+                self.maint_pool.clone(),
+                Duration::from_secs(3600),
+                self.write_in_progress.clone(),
+                days,
+            )
+            .await?;
+        }
+        if let Some(days) = self.stats_days {
+            cleanup_event_stats(
+                self.maint_pool.clone(),
+                Duration::from_secs(3600),
+                self.write_in_progress.clone(),
+                days,
+            )
+            .await?;
+        }
+        // events left unindexed by an earlier run are indexed even
+        // if deferred indexing has since been disabled.
+        tag_indexer_task(
+            self.maint_pool.clone(),
+            self.tag_index_delay,
+            self.write_in_progress.clone(),
+            self.max_indexed_tag_value_bytes,
+            self.metrics.clone(),
+            self.deferred_tags,
+        )
+        .await?;
         cleanup_expired(
             self.maint_pool.clone(),
             Duration::from_secs(600),
@@ -272,50 +552,62 @@ impl NostrRepo for SqliteRepo {
     async fn migrate_up(&self) -> Result<usize> {
         let _write_guard = self.write_in_progress.lock().await;
         let mut conn = self.write_pool.get()?;
-        task::spawn_blocking(move || upgrade_db(&mut conn)).await?
+        let max_tag_bytes = self.max_tag_value_bytes;
+        task::spawn_blocking(move || {
+            let version = upgrade_db(&mut conn)?;
+            // report (but keep) tags stored before the limit was in place.
+            if let Some(max_bytes) = max_tag_bytes {
+                let oversize = db_oversize_tag_count(&mut conn, max_bytes)?;
+                if oversize > 0 {
+                    warn!(
+                        "found {} stored tags exceeding max_tag_value_bytes ({})",
+                        oversize, max_bytes
+                    );
+                }
+            }
+            Ok(version)
+        })
+        .await?
     }
     /// Persist event to database
-    async fn write_event(&self, e: &Event) -> Result<u64> {
+    async fn write_event(&self, e: &Event) -> Result<WriteResult> {
         let start = Instant::now();
-        let max_write_attempts = 10;
-        let mut attempts = 0;
+        // SQLite can only write single threaded; hold the lock across
+        // retries so other writers can not jump ahead.
         let _write_guard = self.write_in_progress.lock().await;
-        // spawn a blocking thread
-        //let mut conn = self.write_pool.get()?;
-        let pool = self.write_pool.clone();
-        let e = e.clone();
-        let event_count = task::spawn_blocking(move || {
-            let mut conn = pool.get()?;
-            // this could fail because the database was busy; try
-            // multiple times before giving up.
-            loop {
-                attempts += 1;
-                let wr = SqliteRepo::persist_event(&mut conn, &e);
-                match wr {
-                    Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
-                        // this basically means that NIP-05 or another
-                        // writer was using the database between us
-                        // reading and promoting the connection to a
-                        // write lock.
-                        info!(
-                            "event write failed, DB locked (attempt: {}); sqlite err: {}",
-                            attempts, e.extended_code
-                        );
-                    }
-                    _ => {
-                        return wr;
-                    }
-                }
-                if attempts >= max_write_attempts {
-                    return wr;
+        let max_tag_bytes = self.max_indexed_tag_value_bytes;
+        let purge_delay = self.purge_delay;
+        let defer_tags = self.deferred_tags;
+        let written = retry_transient(
+            &self.metrics,
+            self.write_attempts,
+            is_transient_sqlite_error,
+            || {
+                let pool = self.write_pool.clone();
+                let e = e.clone();
+                async move {
+                    task::spawn_blocking(move || {
+                        let mut conn = pool.get()?;
+                        SqliteRepo::store_event(
+                            &mut conn,
+                            &e,
+                            max_tag_bytes,
+                            StoreOptions {
+                                purge_delay,
+                                defer_tags,
+                                pending: false,
+                            },
+                        )
+                    })
+                    .await?
                 }
-            }
-        })
-        .await?;
+            },
+        )
+        .await;
         self.metrics
             .write_events
             .observe(start.elapsed().as_secs_f64());
-        event_count
+        written
     }
 
     /// Perform a database query using a subscription.
@@ -344,6 +636,8 @@ impl NostrRepo for SqliteRepo {
             .unwrap();
         let self = self.clone();
         let metrics = self.metrics.clone();
+        let wants_first_seen = sub.wants_first_seen();
+        let runtime = tokio::runtime::Handle::current();
         task::spawn_blocking(move || {
             {
                 // if we are waiting on a checkpoint, stop until it is complete
@@ -357,6 +651,16 @@ impl NostrRepo for SqliteRepo {
                     db_queue_time, client_id, sub.id
                 );
                 metrics.query_aborts.with_label_values(&["loadshed"]).inc();
+                // the client is still told, as for a timeout
+                query_tx
+                    .blocking_send(QueryResult {
+                        sub_id: sub.get_id(),
+                        event: "EOSE".to_string(),
+                        first_seen: None,
+                        generation: sub.generation,
+                        timed_out: true,
+                    })
+                    .ok();
                 return Ok(());
             }
             // otherwise, report queuing time if it is slow
@@ -376,23 +680,32 @@ impl NostrRepo for SqliteRepo {
             }
 
             let start = Instant::now();
+            // set when the query stops early, at its deadline or aborted
+            let mut timed_out = false;
             let mut row_count: usize = 0;
             // cutoff for displaying slow queries
             let slow_cutoff = Duration::from_millis(250);
             let mut filter_count = 0;
             // remove duplicates from the filter list.
             if let Ok(mut conn) = self.read_pool.get() {
+                let watchdog = self
+                    .query_timeout
+                    .map(|timeout| QueryWatchdog::start(&runtime, &conn, timeout));
                 {
                     let pool_state = self.read_pool.state();
                     metrics
                         .db_connections
                         .set((pool_state.connections - pool_state.idle_connections).into());
                 }
-                for filter in sub.filters.iter() {
+                let filters = sub
+                    .filters
+                    .iter()
+                    .flat_map(|f| f.split_direct_fetch(DIRECT_FETCH_CHUNK));
+                'filters: for filter in filters {
                     let filter_start = Instant::now();
                     filter_count += 1;
                     let sql_gen_elapsed = filter_start.elapsed();
-                    let (q, p, idx) = query_from_filter(filter);
+                    let (q, p, idx) = query_from_filter(&filter);
                     if sql_gen_elapsed > Duration::from_millis(10) {
                         debug!("SQL (slow) generated in {:?}", filter_start.elapsed());
                     }
@@ -401,6 +714,8 @@ impl NostrRepo for SqliteRepo {
                     let abort_cutoff = Duration::from_secs(2);
                     let mut slow_first_event;
                     let mut last_successful_send = Instant::now();
+                    // time spent waiting for the client, not the database
+                    let mut client_wait = Duration::ZERO;
                     // execute the query.
                     // make the actual SQL query (with parameters inserted) available
                     conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
@@ -408,8 +723,11 @@ impl NostrRepo for SqliteRepo {
                     let mut event_rows = stmt.query(rusqlite::params_from_iter(p))?;
 
                     let mut first_result = true;
-                    while let Some(row) = event_rows.next()? {
-                        let first_event_elapsed = filter_start.elapsed();
+                    while let Some(row) =
+                        next_row(&mut event_rows, watchdog.as_ref(), &mut timed_out)?
+                    {
+                        let first_event_elapsed =
+                            filter_start.elapsed().saturating_sub(client_wait);
                         slow_first_event = first_event_elapsed >= slow_cutoff;
                         if first_result {
                             debug!(
@@ -430,7 +748,7 @@ impl NostrRepo for SqliteRepo {
                             first_result = false;
                         }
                         // check if a checkpoint is trying to run, and abort
-                        if row_count % 100 == 0 {
+                        if row_count % QUERY_CHUNK_ROWS == 0 {
                             {
                                 if self.checkpoint_in_progress.try_lock().is_err() {
                                     // lock was held, abort this query
@@ -442,21 +760,35 @@ impl NostrRepo for SqliteRepo {
                                         .query_aborts
                                         .with_label_values(&["checkpoint"])
                                         .inc();
-                                    return Ok(());
+                                    timed_out = true;
+                                    break 'filters;
                                 }
                             }
                         }
 
-                        // check if this is still active; every 100 rows
-                        if row_count % 100 == 0 && abandon_query_rx.try_recv().is_ok() {
+                        // check the subscription is still open, between chunks
+                        if row_count % QUERY_CHUNK_ROWS == 0
+                            && query_abandoned(&mut abandon_query_rx, &query_tx)
+                        {
                             debug!(
-                                "query cancelled by client (cid: {}, sub: {:?})",
-                                client_id, sub.id
+                                "query cancelled by client (cid: {}, sub: {:?}, rows: {})",
+                                client_id, sub.id, row_count
                             );
                             return Ok(());
                         }
                         row_count += 1;
                         let event_json = row.get(0)?;
+                        let first_seen = if wants_first_seen {
+                            Some(row.get(1)?)
+                        } else {
+                            None
+                        };
+                        // waiting for the client does not count against
+                        // the deadline, or as query time
+                        let wait_start = Instant::now();
+                        if let Some(watchdog) = &watchdog {
+                            watchdog.pause();
+                        }
                         loop {
                             if query_tx.capacity() != 0 {
                                 // we have capacity to add another item
@@ -472,8 +804,8 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["slowclient"])
                                     .inc();
-                                let ok: Result<()> = Ok(());
-                                return ok;
+                                timed_out = true;
+                                break 'filters;
                             }
                             // check if a checkpoint is trying to run, and abort
                             if self.checkpoint_in_progress.try_lock().is_err() {
@@ -486,6 +818,14 @@ impl NostrRepo for SqliteRepo {
                                     .query_aborts
                                     .with_label_values(&["checkpoint"])
                                     .inc();
+                                timed_out = true;
+                                break 'filters;
+                            }
+                            if query_abandoned(&mut abandon_query_rx, &query_tx) {
+                                debug!(
+                                    "query cancelled by client (cid: {}, sub: {:?}, rows: {})",
+                                    client_id, sub.id, row_count
+                                );
                                 return Ok(());
                             }
                             // give the queue a chance to clear before trying again
@@ -502,15 +842,21 @@ impl NostrRepo for SqliteRepo {
                             .blocking_send(QueryResult {
                                 sub_id: sub.get_id(),
                                 event: event_json,
+                                first_seen,
+                                generation: sub.generation,
+                                timed_out: false,
                             })
                             .ok();
                         last_successful_send = Instant::now();
+                        client_wait += wait_start.elapsed();
+                        if let Some(watchdog) = &watchdog {
+                            watchdog.resume();
+                        }
                     }
-                    metrics
-                        .query_db
-                        .observe(filter_start.elapsed().as_secs_f64());
+                    let filter_elapsed = filter_start.elapsed().saturating_sub(client_wait);
+                    metrics.query_db.observe(filter_elapsed.as_secs_f64());
                     // if the filter took too much db_time, print out the JSON.
-                    if filter_start.elapsed() > slow_cutoff && client_id.starts_with('0') {
+                    if filter_elapsed > slow_cutoff && client_id.starts_with('0') {
                         debug!(
                             "query filter req (slow): {} (cid: {}, sub: {:?}, filter: {})",
                             serde_json::to_string(&filter)?,
@@ -519,6 +865,15 @@ impl NostrRepo for SqliteRepo {
                             filter_count
                         );
                     }
+                    if timed_out {
+                        // what was found is sent, but the rest is not looked for
+                        debug!(
+                            "query stopped at its deadline (cid: {}, sub: {:?}, rows: {})",
+                            client_id, sub.id, row_count
+                        );
+                        metrics.query_aborts.with_label_values(&["timeout"]).inc();
+                        break;
+                    }
                 }
             } else {
                 warn!("Could not get a database connection for querying");
@@ -536,6 +891,9 @@ impl NostrRepo for SqliteRepo {
                 .blocking_send(QueryResult {
                     sub_id: sub.get_id(),
                     event: "EOSE".to_string(),
+                    first_seen: None,
+                    generation: sub.generation,
+                    timed_out,
                 })
                 .ok();
             metrics
@@ -559,6 +917,86 @@ impl NostrRepo for SqliteRepo {
         Ok(())
     }
 
+    /// Count stored events (excluding deleted ones)
+    async fn count_events(&self) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let count: u64 =
+                conn.query_row("SELECT COUNT(*) FROM event WHERE hidden!=TRUE;", [], |r| {
+                    r.get(0)
+                })?;
+            Ok(count)
+        })
+        .await?
+    }
+
+    async fn count_events_since(&self, since: u64) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let count: u64 = conn.query_row(
+                "SELECT COUNT(*) FROM event WHERE first_seen>=? AND hidden!=TRUE;",
+                [since],
+                |r| r.get(0),
+            )?;
+            Ok(count)
+        })
+        .await?
+    }
+
+    async fn count_by_source(&self) -> Result<Vec<SourceCount>> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT source, COUNT(*) FROM event WHERE hidden!=TRUE GROUP BY source ORDER BY source;",
+            )?;
+            let counts = stmt
+                .query_map([], |r| {
+                    Ok(SourceCount {
+                        source: r.get(0)?,
+                        count: r.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(counts)
+        })
+        .await?
+    }
+
+    async fn count_by_kind(&self) -> Result<Vec<(u64, u64)>> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || {
+            let mut stmt = conn.prepare(
+                "SELECT kind, COUNT(*) FROM event WHERE hidden!=TRUE GROUP BY kind ORDER BY kind;",
+            )?;
+            let counts = stmt
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(counts)
+        })
+        .await?
+    }
+
+    async fn delete_matching(&self, filter: &ReqFilter, dry_run: bool) -> Result<u64> {
+        if dry_run {
+            let conn = self.read_pool.get()?;
+            let filter = filter.clone();
+            return task::spawn_blocking(move || count_matching(&conn, &filter)).await?;
+        }
+        let mut deleted = 0;
+        loop {
+            // the write lock is released between batches
+            let _write_guard = self.write_in_progress.lock().await;
+            let mut conn = self.write_pool.get()?;
+            let filter = filter.clone();
+            let batch =
+                task::spawn_blocking(move || delete_matching_batch(&mut conn, &filter)).await??;
+            deleted += batch;
+            if batch < DELETE_BATCH {
+                return Ok(deleted);
+            }
+        }
+    }
+
     /// Create a new verification record connected to a specific event
     async fn create_verification_record(&self, event_id: &str, name: &str) -> Result<()> {
         let e = hex::decode(event_id).ok();
@@ -672,7 +1110,9 @@ impl NostrRepo for SqliteRepo {
                     r.get(5).ok(),
                     r.get(6)?,
                 ))
-            })?;
+            })
+            .optional()?
+            .ok_or(Error::NotFound)?;
             Ok(VerificationRecord {
                 rowid: fields.0,
                 name: Nip05Name::try_from(&fields.1[..])?,
@@ -710,7 +1150,9 @@ impl NostrRepo for SqliteRepo {
                     r.get(6).ok(),
                     r.get(7)?,
                 ))
-            })?;
+            })
+            .optional()?
+            .ok_or(Error::NotFound)?;
             let vr = VerificationRecord {
                 rowid: fields.0,
                 name: Nip05Name::try_from(&fields.1[..])?,
@@ -752,17 +1194,29 @@ impl NostrRepo for SqliteRepo {
     }
 
     /// Admit account
-    async fn admit_account(&self, pub_key: &Keys, admission_cost: u64) -> Result<()> {
+    async fn admit_account(
+        &self,
+        pub_key: &Keys,
+        admission_cost: u64,
+        reference: Option<&str>,
+    ) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
+        let reference = reference.map(str::to_owned);
         let mut conn = self.write_pool.get()?;
-        let pub_key = pub_key.to_owned();
         tokio::task::spawn_blocking(move || {
             let tx = conn.transaction()?;
             {
-                let query = "UPDATE account SET is_admitted = TRUE, tos_accepted_at =  strftime('%s','now'), balance = balance - ?1 WHERE pubkey=?2;";
+                let query = "UPDATE account SET is_admitted = TRUE, tos_accepted_at =  strftime('%s','now') WHERE pubkey=?1;";
                 let mut stmt = tx.prepare(query)?;
-                stmt.execute(params![admission_cost, pub_key])?;
+                stmt.execute(params![pub_key])?;
             }
+            record_balance_change(
+                &tx,
+                &pub_key,
+                -(admission_cost as i64),
+                LedgerReason::Admission,
+                reference.as_deref(),
+            )?;
             tx.commit()?;
             let ok: Result<()> = Ok(());
             ok
@@ -779,12 +1233,15 @@ impl NostrRepo for SqliteRepo {
             let tx = conn.transaction()?;
             let query = "SELECT is_admitted, balance FROM account WHERE pubkey = ?1;";
             let mut stmt = tx.prepare_cached(query)?;
-            let fields = stmt.query_row(params![pub_key], |r| {
-                let is_admitted: bool = r.get(0)?;
-                let balance: u64 = r.get(1)?;
-                // create a tuple since we can't throw non-rusqlite errors in this closure
-                Ok((is_admitted, balance))
-            })?;
+            let fields = stmt
+                .query_row(params![pub_key], |r| {
+                    let is_admitted: bool = r.get(0)?;
+                    let balance: u64 = r.get(1)?;
+                    // create a tuple since we can't throw non-rusqlite errors in this closure
+                    Ok((is_admitted, balance))
+                })
+                .optional()?
+                .ok_or(Error::NotFound)?;
             Ok(fields)
         })
         .await?
@@ -796,21 +1253,21 @@ impl NostrRepo for SqliteRepo {
         pub_key: &Keys,
         positive: bool,
         new_balance: u64,
+        reason: LedgerReason,
+        reference: Option<&str>,
     ) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
+        let reference = reference.map(str::to_owned);
+        let delta = if positive {
+            new_balance as i64
+        } else {
+            -(new_balance as i64)
+        };
 
         let mut conn = self.write_pool.get()?;
         tokio::task::spawn_blocking(move || {
             let tx = conn.transaction()?;
-            {
-                let query = if positive {
-                    "UPDATE account SET balance=balance + ?1 WHERE pubkey=?2"
-                } else {
-                    "UPDATE account SET balance=balance - ?1 WHERE pubkey=?2"
-                };
-                let mut stmt = tx.prepare(query)?;
-                stmt.execute(params![new_balance, pub_key])?;
-            }
+            record_balance_change(&tx, &pub_key, delta, reason, reference.as_deref())?;
             tx.commit()?;
             let ok: Result<()> = Ok(());
             ok
@@ -818,6 +1275,37 @@ impl NostrRepo for SqliteRepo {
         .await?
     }
 
+    async fn published_event_count(&self, pub_key: &Keys) -> Result<u64> {
+        let pub_key = pub_key.public_key().to_string();
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let mut stmt =
+                conn.prepare_cached("SELECT event_count FROM account WHERE pubkey = ?1;")?;
+            let count: Option<u64> = stmt.query_row(params![pub_key], |r| r.get(0)).optional()?;
+            Ok(count.unwrap_or(0))
+        })
+        .await?
+    }
+
+    async fn count_published_event(&self, pub_key: &Keys) -> Result<u64> {
+        let pub_key = pub_key.public_key().to_string();
+        let mut conn = self.write_pool.get()?;
+        tokio::task::spawn_blocking(move || count_published_event(&mut conn, &pub_key)).await?
+    }
+
+    /// Get the most recent ledger entries for an account
+    async fn get_ledger(&self, pub_key: &Keys, limit: Option<u64>) -> Result<Vec<LedgerEntry>> {
+        let pub_key = pub_key.public_key().to_string();
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || ledger_entries(&conn, &pub_key, limit)).await?
+    }
+
+    /// Find accounts whose balance differs from their ledger
+    async fn verify_ledger(&self) -> Result<Vec<LedgerMismatch>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || ledger_mismatches(&conn)).await?
+    }
+
     /// Create invoice record
     async fn create_invoice_record(&self, pub_key: &Keys, invoice_info: InvoiceInfo) -> Result<()> {
         let pub_key = pub_key.public_key().to_string();
@@ -860,6 +1348,7 @@ impl NostrRepo for SqliteRepo {
                     Ok((pub_key, status, amount))
 
                 })?;
+                let prev_status: InvoiceStatus = prev_status.parse()?;
 
                 // If the invoice is paid update the confirmed_at timestamp
                 let query =  if status.eq(&InvoiceStatus::Paid) {
@@ -871,11 +1360,14 @@ impl NostrRepo for SqliteRepo {
                 stmt.execute(params![status.to_string(), payment_hash])?;
 
                 // Increase account balance by given invoice amount
-                if prev_status == "Unpaid" && status.eq(&InvoiceStatus::Paid) {
-                    let query =
-                            "UPDATE account SET balance = balance + ?1 WHERE pubkey = ?2;";
-                    let mut stmt = tx.prepare(query)?;
-                    stmt.execute(params![amount, pub_key])?;
+                if prev_status.eq(&InvoiceStatus::Unpaid) && status.eq(&InvoiceStatus::Paid) {
+                    record_balance_change(
+                        &tx,
+                        &pub_key,
+                        amount as i64,
+                        LedgerReason::Payment,
+                        Some(payment_hash.as_str()),
+                    )?;
                 }
 
                 pubkey = pub_key;
@@ -895,7 +1387,7 @@ impl NostrRepo for SqliteRepo {
 
         let pubkey = pubkey.to_owned();
         let pubkey_str = pubkey.clone().public_key().to_string();
-        let (payment_hash, invoice, amount, description) = tokio::task::spawn_blocking(move || {
+        let unpaid = tokio::task::spawn_blocking(move || {
             let tx = conn.transaction()?;
 
             let query = r#"
@@ -905,7 +1397,7 @@ WHERE pubkey = ?1 AND status = 'Unpaid'
 ORDER BY created_at DESC
 LIMIT 1;
         "#;
-            let mut stmt = tx.prepare(query).unwrap();
+            let mut stmt = tx.prepare(query)?;
             stmt.query_row(params![&pubkey_str], |r| {
                 let amount: u64 = r.get(0)?;
                 let payment_hash: String = r.get(1)?;
@@ -914,109 +1406,604 @@ LIMIT 1;
 
                 Ok((payment_hash, invoice, amount, description))
             })
+            .optional()
         })
         .await??;
 
-        Ok(Some(InvoiceInfo {
-            pubkey: pubkey.public_key().to_string(),
-            payment_hash,
-            bolt11: invoice,
-            amount,
-            status: InvoiceStatus::Unpaid,
-            memo: description,
-            confirmed_at: None,
-        }))
+        Ok(
+            unpaid.map(|(payment_hash, invoice, amount, description)| InvoiceInfo {
+                pubkey: pubkey.public_key().to_string(),
+                payment_hash,
+                bolt11: invoice,
+                amount,
+                status: InvoiceStatus::Unpaid,
+                memo: description,
+                confirmed_at: None,
+            }),
+        )
     }
-}
 
-/// Decide if there is an index that should be used explicitly
-fn override_index(f: &ReqFilter) -> Option<String> {
-    if f.ids.is_some() {
-        return Some("event_hash_index".into());
+    /// Get all unpaid invoices created at or after `since`
+    async fn get_unpaid_invoices(&self, since: u64) -> Result<Vec<InvoiceInfo>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let query = r#"
+SELECT pubkey, payment_hash, invoice, amount, description
+FROM invoice
+WHERE status = 'Unpaid' AND created_at >= ?1
+ORDER BY created_at;
+        "#;
+            let mut stmt = conn.prepare(query)?;
+            let invoices = stmt
+                .query_map(params![since], |r| {
+                    Ok(InvoiceInfo {
+                        pubkey: r.get(0)?,
+                        payment_hash: r.get(1)?,
+                        bolt11: r.get(2)?,
+                        amount: r.get(3)?,
+                        status: InvoiceStatus::Unpaid,
+                        memo: r.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                        confirmed_at: None,
+                    })
+                })?
+                // one bad row should not stop the others being checked
+                .filter_map(|invoice| {
+                    invoice
+                        .map_err(|e| warn!("skipping unreadable invoice: {e}"))
+                        .ok()
+                })
+                .collect();
+            Ok(invoices)
+        })
+        .await?
     }
-    // queries for multiple kinds default to kind_index, which is
-    // significantly slower than kind_created_at_index.
-    if let Some(ks) = &f.kinds {
-        if f.ids.is_none()
-            && ks.len() > 1
-            && f.since.is_none()
-            && f.until.is_none()
-            && f.tags.is_none()
-            && f.authors.is_none()
-        {
-            return Some("kind_created_at_index".into());
-        }
+
+    async fn confirm_invoice(&self, payment_hash: &str) -> Result<Option<u64>> {
+        let conn = self.write_pool.get()?;
+        let payment_hash = payment_hash.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached(
+                "UPDATE invoice SET confirmed_at = strftime('%s', 'now') WHERE payment_hash = ?1 AND confirmed_at IS NULL RETURNING created_at;",
+            )?;
+            Ok(stmt
+                .query_row(params![payment_hash], |r| r.get(0))
+                .optional()?)
+        })
+        .await?
     }
-    // if there is an author, it is much better to force the authors index.
-    if f.authors.is_some() {
-        if f.since.is_none() && f.until.is_none() && f.limit.is_none() {
-            if f.kinds.is_none() {
-                // with no use of kinds/created_at, just author
-                return Some("author_index".into());
-            }
-            // prefer author_kind if there are kinds
-            return Some("author_kind_index".into());
-        }
-        // finally, prefer author_created_at if time is provided
-        return Some("author_created_at_index".into());
+
+    async fn get_payment_stats(&self, unpaid_since: u64) -> Result<PaymentStats> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || payment_stats(&conn, unpaid_since)).await?
     }
-    None
-}
 
-/// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
-fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
-    // build a dynamic SQL query.  all user-input is either an integer
-    // (sqli-safe), or a string that is filtered to only contain
-    // hexadecimal characters.  Strings that require escaping (tag
-    // names/values) use parameters.
+    async fn get_watermark(&self, pubkey: &str, sub_key: &str) -> Result<Option<u64>> {
+        let conn = self.read_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        let sub_key = sub_key.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut stmt = conn.prepare_cached(
+                "SELECT created_at FROM subscription_watermark WHERE pubkey = ?1 AND sub_key = ?2;",
+            )?;
+            Ok(stmt
+                .query_row(params![pubkey, sub_key], |r| r.get(0))
+                .optional()?)
+        })
+        .await?
+    }
 
-    // if the filter is malformed, don't return anything.
-    if f.force_no_match {
-        let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
-        // query parameters for SQLite
-        let empty_params: Vec<Box<dyn ToSql>> = vec![];
-        return (empty_query, empty_params, None);
+    async fn save_watermark(&self, pubkey: &str, sub_key: &str, created_at: u64) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pubkey = pubkey.to_owned();
+        let sub_key = sub_key.to_owned();
+        tokio::task::spawn_blocking(move || {
+            save_watermark(&mut conn, &pubkey, &sub_key, created_at)
+        })
+        .await?
     }
 
-    // check if the index needs to be overridden
-    let idx_name = override_index(f);
-    let idx_stmt = idx_name
-        .as_ref()
-        .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
-    let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
-    // query parameters for SQLite
-    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    async fn add_event_stats(&self, stats: &[EventStat]) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let stats = stats.to_vec();
+        tokio::task::spawn_blocking(move || add_event_stats(&mut conn, &stats)).await?
+    }
 
-    // individual filter components (single conditions such as an author or event ID)
-    let mut filter_components: Vec<String> = Vec::new();
-    // Query for "authors", allowing prefix matches
-    if let Some(authvec) = &f.authors {
-        // take each author and convert to a hexsearch
-        let mut auth_searches: Vec<String> = vec![];
-        for auth in authvec {
-            match hex_range(auth) {
-                Some(HexSearch::Exact(ex)) => {
-                    auth_searches.push("author=?".to_owned());
-                    params.push(Box::new(ex));
-                }
-                Some(HexSearch::Range(lower, upper)) => {
-                    auth_searches.push("(author>? AND author<?)".to_owned());
-                    params.push(Box::new(lower));
-                    params.push(Box::new(upper));
-                }
-                Some(HexSearch::LowerOnly(lower)) => {
-                    auth_searches.push("author>?".to_owned());
-                    params.push(Box::new(lower));
-                }
-                None => {
-                    trace!("Could not parse hex range from author {:?}", auth);
-                }
-            }
-        }
-        if !authvec.is_empty() {
-            let auth_clause = format!("({})", auth_searches.join(" OR "));
-            filter_components.push(auth_clause);
-        } else {
+    async fn top_writers(
+        &self,
+        day: NaiveDate,
+        kind: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<TopWriter>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || top_writers(&conn, day, kind, limit)).await?
+    }
+
+    async fn latest_created_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        let conn = self.read_pool.get()?;
+        let author = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            // answered from author_created_at_index
+            let mut stmt =
+                conn.prepare_cached("SELECT MAX(created_at) FROM event WHERE author = ?1;")?;
+            Ok(stmt.query_row(params![author], |r| r.get(0))?)
+        })
+        .await?
+    }
+
+    async fn get_pubkey_status(&self, pubkey: &str) -> Result<Option<PubkeyStatus>> {
+        let conn = self.read_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || pubkey_status(&conn, &pubkey)).await?
+    }
+
+    async fn add_first_seen_pubkey(
+        &self,
+        pubkey: &str,
+        first_seen: u64,
+        status: PubkeyStatus,
+    ) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || {
+            add_first_seen_pubkey(&mut conn, &pubkey, first_seen, status)
+        })
+        .await?
+    }
+
+    async fn count_quarantined_event(&self, pubkey: &str) -> Result<u64> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || count_quarantined_event(&mut conn, &pubkey)).await?
+    }
+
+    async fn set_pubkey_status(&self, pubkey: &str, status: PubkeyStatus) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || set_pubkey_status(&mut conn, &pubkey, status)).await?
+    }
+
+    async fn release_quarantined(&self, first_seen_before: u64) -> Result<Vec<String>> {
+        let mut conn = self.write_pool.get()?;
+        let _write_guard = self.write_in_progress.lock().await;
+        tokio::task::spawn_blocking(move || release_quarantined(&mut conn, first_seen_before))
+            .await?
+    }
+
+    async fn withheld_pubkeys(&self) -> Result<Vec<QuarantinedPubkey>> {
+        let conn = self.read_pool.get()?;
+        tokio::task::spawn_blocking(move || withheld_pubkeys(&conn)).await?
+    }
+
+    async fn write_pending_event(&self, e: &Event) -> Result<u64> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        let max_tag_bytes = self.max_indexed_tag_value_bytes;
+        let purge_delay = self.purge_delay;
+        let e = e.clone();
+        task::spawn_blocking(move || {
+            let options = StoreOptions {
+                purge_delay,
+                pending: true,
+                ..StoreOptions::default()
+            };
+            SqliteRepo::store_event(&mut conn, &e, max_tag_bytes, options)
+                .map(|written| written.rows_added())
+        })
+        .await?
+    }
+
+    async fn resolve_pending_event(&self, event_id: &str, accepted: bool) -> Result<bool> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        let event_id = event_id.to_owned();
+        task::spawn_blocking(move || resolve_pending(&mut conn, &event_id, accepted)).await?
+    }
+
+    async fn get_pending_events(&self) -> Result<Vec<Event>> {
+        let conn = self.read_pool.get()?;
+        task::spawn_blocking(move || pending_events(&conn)).await?
+    }
+
+    async fn export_events_by(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<u64> {
+        let conn = self.read_pool.get()?;
+        let author = hex::decode(pubkey)?;
+        task::spawn_blocking(move || export_events_by(&conn, &author, &tx)).await?
+    }
+
+    async fn export_account_data(&self, pubkey: &str, tx: mpsc::Sender<UserRecord>) -> Result<()> {
+        let conn = self.read_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || export_account_data(&conn, &pubkey, &tx)).await?
+    }
+
+    async fn erase_events_by(&self, pubkey: &str) -> Result<u64> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        let author = hex::decode(pubkey)?;
+        let purge_delay = self.purge_delay;
+        task::spawn_blocking(move || erase_events_by(&mut conn, &author, purge_delay)).await?
+    }
+
+    async fn erase_account_data(
+        &self,
+        pubkey: &str,
+        erased_at: u64,
+        tombstone_until: Option<u64>,
+    ) -> Result<UserDataCounts> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || {
+            erase_account_data(&mut conn, &pubkey, erased_at, tombstone_until)
+        })
+        .await?
+    }
+
+    async fn erased_at(&self, pubkey: &str) -> Result<Option<u64>> {
+        let conn = self.read_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        task::spawn_blocking(move || erased_at(&conn, &pubkey, unix_time())).await?
+    }
+}
+
+/// Decide if there is an index that should be used explicitly
+fn override_index(f: &ReqFilter) -> Option<String> {
+    if f.ids.is_some() {
+        return Some("event_hash_index".into());
+    }
+    // resumed subscriptions usually cover a short, recent window of
+    // receive times, unless scoped to specific authors.
+    if f.first_seen_since().is_some() && f.authors.is_none() {
+        return Some("event_first_seen_index".into());
+    }
+    // queries for multiple kinds default to kind_index, which is
+    // significantly slower than kind_created_at_index.
+    if let Some(ks) = &f.kinds {
+        if f.ids.is_none()
+            && ks.len() > 1
+            && f.since.is_none()
+            && f.until.is_none()
+            && f.tags.is_none()
+            && f.authors.is_none()
+        {
+            return Some("kind_created_at_index".into());
+        }
+    }
+    // if there is an author, it is much better to force the authors index.
+    if f.authors.is_some() {
+        if f.since.is_none() && f.until.is_none() && f.limit.is_none() {
+            if f.kinds.is_none() {
+                // with no use of kinds/created_at, just author
+                return Some("author_index".into());
+            }
+            // prefer author_kind if there are kinds
+            return Some("author_kind_index".into());
+        }
+        // finally, prefer author_created_at if time is provided
+        return Some("author_created_at_index".into());
+    }
+    None
+}
+
+/// Apply a change to an account balance, and record it in the ledger.
+/// Must be called in the same transaction as any other changes the
+/// balance update belongs with.  Unknown accounts are left untouched.
+pub fn record_balance_change(
+    tx: &rusqlite::Transaction,
+    pubkey: &str,
+    delta: i64,
+    reason: LedgerReason,
+    reference: Option<&str>,
+) -> Result<()> {
+    let balance: Option<i64> = tx
+        .query_row(
+            "UPDATE account SET balance = balance + ?1 WHERE pubkey = ?2 RETURNING balance;",
+            params![delta, pubkey],
+            |r| r.get(0),
+        )
+        .optional()?;
+    if let Some(balance) = balance {
+        tx.execute(
+            "INSERT INTO ledger (pubkey, delta, balance, reason, reference, created_at) VALUES (?1, ?2, ?3, ?4, ?5, strftime('%s','now'));",
+            params![pubkey, delta, balance, reason.as_str(), reference],
+        )?;
+    }
+    Ok(())
+}
+
+/// Mark a paid invoice as refunded, and take the payment back out of
+/// the account balance.  The debit is capped at the current balance,
+/// so an account never goes negative for funds it already spent.
+pub fn refund_invoice(
+    conn: &mut rusqlite::Connection,
+    payment_hash: &str,
+) -> Result<InvoiceRefund> {
+    let tx = conn.transaction()?;
+    let (pubkey, status, amount): (String, String, u64) = tx
+        .query_row(
+            "SELECT pubkey, status, amount FROM invoice WHERE payment_hash = ?1;",
+            params![payment_hash],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .optional()?
+        .ok_or_else(|| {
+            Error::CustomError(format!("no invoice with payment hash {payment_hash}"))
+        })?;
+    let status: InvoiceStatus = status.parse()?;
+    if status != InvoiceStatus::Paid {
+        return Err(Error::CustomError(format!(
+            "only paid invoices can be refunded (invoice is {status})"
+        )));
+    }
+    tx.execute(
+        "UPDATE invoice SET status = ?1 WHERE payment_hash = ?2;",
+        params![InvoiceStatus::Refunded.as_str(), payment_hash],
+    )?;
+    let balance: i64 = tx
+        .query_row(
+            "SELECT balance FROM account WHERE pubkey = ?1;",
+            params![pubkey],
+            |r| r.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let debited = amount.min(balance.max(0) as u64);
+    if debited > 0 {
+        record_balance_change(
+            &tx,
+            &pubkey,
+            -(debited as i64),
+            LedgerReason::Refund,
+            Some(payment_hash),
+        )?;
+    }
+    tx.commit()?;
+    Ok(InvoiceRefund {
+        payment_hash: payment_hash.to_owned(),
+        pubkey,
+        amount,
+        debited,
+    })
+}
+
+/// Count an event published by an account.  Returns the events
+/// counted so far.
+pub fn count_published_event(conn: &mut rusqlite::Connection, pubkey: &str) -> Result<u64> {
+    let count: Option<u64> = conn
+        .query_row(
+            "UPDATE account SET event_count = event_count + 1 WHERE pubkey = ?1 RETURNING event_count;",
+            params![pubkey],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(count.unwrap_or(0))
+}
+
+/// Ledger entries for an account, newest first.
+pub fn ledger_entries(
+    conn: &rusqlite::Connection,
+    pubkey: &str,
+    limit: Option<u64>,
+) -> Result<Vec<LedgerEntry>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT delta, balance, reason, reference, created_at FROM ledger WHERE pubkey = ?1 ORDER BY id DESC LIMIT ?2;",
+    )?;
+    // a negative limit means no limit in sqlite
+    let limit = limit.map_or(-1, |l| l as i64);
+    let mut rows = stmt.query(params![pubkey, limit])?;
+    let mut entries = vec![];
+    while let Some(row) = rows.next()? {
+        let reason: String = row.get(2)?;
+        entries.push(LedgerEntry {
+            pubkey: pubkey.to_owned(),
+            delta: row.get(0)?,
+            balance: row.get(1)?,
+            reason: reason.parse()?,
+            reference: row.get(3)?,
+            created_at: row.get(4)?,
+        });
+    }
+    Ok(entries)
+}
+
+/// Accounts whose stored balance is not the sum of their ledger.
+pub fn ledger_mismatches(conn: &rusqlite::Connection) -> Result<Vec<LedgerMismatch>> {
+    let mut stmt = conn.prepare(
+        r#"
+SELECT a.pubkey, a.balance, COALESCE(SUM(l.delta), 0) AS ledger_balance
+FROM account a LEFT JOIN ledger l ON l.pubkey = a.pubkey
+GROUP BY a.pubkey, a.balance
+HAVING a.balance != ledger_balance
+ORDER BY a.pubkey;
+"#,
+    )?;
+    let mismatches = stmt
+        .query_map([], |r| {
+            Ok(LedgerMismatch {
+                pubkey: r.get(0)?,
+                balance: r.get(1)?,
+                ledger_balance: r.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(mismatches)
+}
+
+/// Size of the database file, and of each table and index in it.
+pub fn database_size(conn: &rusqlite::Connection) -> Result<DatabaseSize> {
+    let page_count: u64 = conn.query_row("PRAGMA page_count", [], |r| r.get(0))?;
+    let page_size: u64 = conn.query_row("PRAGMA page_size", [], |r| r.get(0))?;
+    let mut stmt = conn.prepare(
+        "SELECT m.name, m.type = 'index', (SELECT COALESCE(SUM(pgsize), 0) FROM dbstat d WHERE d.name = m.name) \
+         FROM sqlite_master m WHERE m.type IN ('table', 'index') AND m.name NOT LIKE 'sqlite_%' ORDER BY m.name",
+    )?;
+    let mut relations = stmt
+        .query_map([], |r| {
+            Ok(RelationSize {
+                name: r.get(0)?,
+                is_index: r.get(1)?,
+                rows: None,
+                dead_rows: None,
+                bytes: r.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for rel in relations.iter_mut().filter(|r| !r.is_index) {
+        let count: u64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", rel.name.replace('"', "\"\"")),
+            [],
+            |r| r.get(0),
+        )?;
+        rel.rows = Some(count);
+    }
+    Ok(DatabaseSize {
+        total_bytes: page_count * page_size,
+        relations,
+    })
+}
+
+/// Count unpaid invoices created at or after `unpaid_since`, and
+/// total the amount of paid invoices.
+pub fn payment_stats(conn: &rusqlite::Connection, unpaid_since: u64) -> Result<PaymentStats> {
+    let (unpaid_invoices, sats_collected) = conn.query_row(
+        "SELECT COUNT(*) FILTER (WHERE status = 'Unpaid' AND created_at >= ?1), \
+         IFNULL(SUM(amount) FILTER (WHERE status = 'Paid'), 0) FROM invoice;",
+        params![unpaid_since],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    Ok(PaymentStats {
+        unpaid_invoices,
+        sats_collected,
+    })
+}
+
+/// Rebuild the database file, reclaiming free pages, and refresh
+/// planner statistics.  Writers are blocked until this completes.
+pub fn compact(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute_batch("VACUUM; ANALYZE;")?;
+    // truncate the WAL, which VACUUM can grow to the size of the database
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// Drop an index and create it again from its stored definition, in
+/// one transaction.  Writers are blocked until this completes.
+pub fn recreate_index(conn: &mut rusqlite::Connection, name: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    let sql: String = tx.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'index' AND name = ?1 AND sql IS NOT NULL",
+        [name],
+        |r| r.get(0),
+    )?;
+    tx.execute_batch(&format!(
+        "DROP INDEX \"{}\"; {sql};",
+        name.replace('"', "\"\"")
+    ))?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
+fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+    // build a dynamic SQL query.  all user-input is either an integer
+    // (sqli-safe), or a string that is filtered to only contain
+    // hexadecimal characters.  Strings that require escaping (tag
+    // names/values) use parameters.
+
+    // if the filter is malformed, don't return anything.
+    if f.force_no_match {
+        let empty_query = "SELECT e.content, e.first_seen FROM event e WHERE 1=0".to_owned();
+        // query parameters for SQLite
+        let empty_params: Vec<Box<dyn ToSql>> = vec![];
+        return (empty_query, empty_params, None);
+    }
+
+    // fetches of complete ids are primary key lookups, and never
+    // need sorting.
+    if f.is_direct_fetch() {
+        let ids = f.ids.as_deref().unwrap_or_default();
+        let query = format!(
+            "SELECT e.content, e.first_seen FROM event e INDEXED BY event_hash_index WHERE event_hash IN ({}) AND hidden!=TRUE AND (expires_at IS NULL OR expires_at > ?)",
+            repeat_vars(ids.len())
+        );
+        let mut params: Vec<Box<dyn ToSql>> = vec![];
+        for id in ids {
+            if let Ok(bytes) = hex::decode(id) {
+                params.push(Box::new(bytes));
+            }
+        }
+        params.push(Box::new(unix_time()));
+        return (query, params, Some("event_hash_index".into()));
+    }
+
+    // check if the index needs to be overridden
+    let idx_name = override_index(f);
+    let idx_stmt = idx_name
+        .as_ref()
+        .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
+    let mut query = format!("SELECT e.content, e.first_seen FROM event e {idx_stmt}");
+    // individual filter components (single conditions such as an
+    // author or event ID), and their query parameters
+    let (mut filter_components, mut params) = filter_conditions(f);
+    // never display hidden events
+    query.push_str(" WHERE hidden!=TRUE");
+    // never display hidden events
+    filter_components.push("(expires_at IS NULL OR expires_at > ?)".to_string());
+    params.push(Box::new(unix_time()));
+    // build filter component conditions
+    if !filter_components.is_empty() {
+        query.push_str(" AND ");
+        query.push_str(&filter_components.join(" AND "));
+    }
+    // Apply per-filter limit to this subquery.
+    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
+    if let Some(lim) = f.limit {
+        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
+    } else {
+        query.push_str(" ORDER BY e.created_at ASC");
+    }
+    (query, params, idx_name)
+}
+
+/// Conditions (to be joined with `AND`) and query parameters matching
+/// a filter.  Hidden and expired events are not excluded.
+fn filter_conditions(f: &ReqFilter) -> (Vec<String>, Vec<Box<dyn ToSql>>) {
+    // query parameters for SQLite
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    let mut filter_components: Vec<String> = Vec::new();
+    // Query for "authors", allowing prefix matches
+    if let Some(authvec) = &f.authors {
+        // take each author and convert to a hexsearch
+        let mut auth_searches: Vec<String> = vec![];
+        for auth in authvec {
+            match hex_range(auth) {
+                Some(HexSearch::Exact(ex)) => {
+                    auth_searches.push("author=?".to_owned());
+                    params.push(Box::new(ex));
+                }
+                Some(HexSearch::Range(lower, upper)) => {
+                    auth_searches.push("(author>? AND author<?)".to_owned());
+                    params.push(Box::new(lower));
+                    params.push(Box::new(upper));
+                }
+                Some(HexSearch::LowerOnly(lower)) => {
+                    auth_searches.push("author>?".to_owned());
+                    params.push(Box::new(lower));
+                }
+                None => {
+                    trace!("Could not parse hex range from author {:?}", auth);
+                }
+            }
+        }
+        if !authvec.is_empty() {
+            let auth_clause = format!("({})", auth_searches.join(" OR "));
+            filter_components.push(auth_clause);
+        } else {
             filter_components.push("false".to_owned());
         }
     }
@@ -1027,6 +2014,11 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         let kind_clause = format!("kind IN ({})", str_kinds.join(", "));
         filter_components.push(kind_clause);
     }
+    // Query for kinds to leave out (the _excludeKinds extension)
+    if let Some(ks) = f.exclude_kinds.as_ref().filter(|ks| !ks.is_empty()) {
+        let str_kinds: Vec<String> = ks.iter().map(std::string::ToString::to_string).collect();
+        filter_components.push(format!("kind NOT IN ({})", str_kinds.join(", ")));
+    }
     // Query for event, allowing prefix matches
     if let Some(idvec) = &f.ids {
         // take each author and convert to a hexsearch
@@ -1060,9 +2052,14 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
             filter_components.push(id_clause);
         }
     }
-    // Query for tags
+    // Query for tags.  Each tag name gets its own subquery, so all
+    // tag names must match (AND), with any of their values (OR).
     if let Some(map) = &f.tags {
-        for (key, val) in map.iter() {
+        // sort tag names, so the generated SQL is stable
+        let mut keys: Vec<&char> = map.keys().collect();
+        keys.sort();
+        for key in keys {
+            let val = &map[key];
             let mut str_vals: Vec<Box<dyn ToSql>> = vec![];
             for v in val {
                 str_vals.push(Box::new(v.clone()));
@@ -1113,31 +2110,57 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         let until_clause = format!("created_at <= {}", f.until.unwrap());
         filter_components.push(until_clause);
     }
-    // never display hidden events
-    query.push_str(" WHERE hidden!=TRUE");
-    // never display hidden events
-    filter_components.push("(expires_at IS NULL OR expires_at > ?)".to_string());
-    params.push(Box::new(unix_time()));
-    // build filter component conditions
-    if !filter_components.is_empty() {
-        query.push_str(" AND ");
-        query.push_str(&filter_components.join(" AND "));
+    // Query for the time the relay first saw the event
+    if let Some(received_since) = f.first_seen_since() {
+        filter_components.push(format!("first_seen >= {received_since}"));
     }
-    // Apply per-filter limit to this subquery.
-    // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
-    } else {
-        query.push_str(" ORDER BY e.created_at ASC");
+    (filter_components, params)
+}
+
+/// `WHERE` clause and parameters matching every event a filter does,
+/// including hidden ones
+fn matching_clause(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>) {
+    if f.force_no_match {
+        return ("1=0".to_owned(), vec![]);
     }
-    (query, params, idx_name)
+    let (conditions, params) = filter_conditions(f);
+    if conditions.is_empty() {
+        return ("1=1".to_owned(), params);
+    }
+    (conditions.join(" AND "), params)
 }
 
-/// Create a dynamic SQL query string and params from a subscription.
-fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<String>) {
-    // build a dynamic SQL query for an entire subscription, based on
-    // SQL subqueries for filters.
-    let mut subqueries: Vec<String> = Vec::new();
+/// Count the events matching a filter, including hidden ones
+pub fn count_matching(conn: &rusqlite::Connection, f: &ReqFilter) -> Result<u64> {
+    let (clause, params) = matching_clause(f);
+    let count = conn.query_row(
+        &format!("SELECT COUNT(*) FROM event e WHERE {clause}"),
+        rusqlite::params_from_iter(params),
+        |r| r.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Delete up to `DELETE_BATCH` events matching a filter, with their
+/// tags, in one transaction.  Returns the number deleted.
+pub fn delete_matching_batch(conn: &mut PooledConnection, f: &ReqFilter) -> Result<u64> {
+    let (clause, params) = matching_clause(f);
+    let tx = conn.transaction()?;
+    let deleted = tx.execute(
+        &format!(
+            "DELETE FROM event WHERE id IN (SELECT e.id FROM event e WHERE {clause} LIMIT {DELETE_BATCH})"
+        ),
+        rusqlite::params_from_iter(params),
+    )?;
+    tx.commit()?;
+    Ok(deleted as u64)
+}
+
+/// Create a dynamic SQL query string and params from a subscription.
+fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<String>) {
+    // build a dynamic SQL query for an entire subscription, based on
+    // SQL subqueries for filters.
+    let mut subqueries: Vec<String> = Vec::new();
     let mut indexes = vec![];
     // subquery params
     let mut params: Vec<Box<dyn ToSql>> = vec![];
@@ -1159,6 +2182,19 @@ fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<Stri
     (query, params, indexes)
 }
 
+/// Check if an error is from the database being busy or locked by
+/// another connection, so the write may succeed if retried.
+/// Constraint violations and other failures are not retryable.
+fn is_transient_sqlite_error(e: &Error) -> bool {
+    match e {
+        SqlError(rusqlite::Error::SqliteFailure(err, _)) => matches!(
+            err.code,
+            rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+        ),
+        _ => false,
+    }
+}
+
 /// Build a database connection pool.
 /// # Panics
 ///
@@ -1171,6 +2207,32 @@ pub fn build_pool(
     min_size: u32,
     max_size: u32,
     wait_for_db: bool,
+) -> SqlitePool {
+    let memory_db = &settings.database.data_directory;
+    build_named_pool(
+        name,
+        settings,
+        memory_db,
+        flags,
+        min_size,
+        max_size,
+        wait_for_db,
+    )
+}
+
+/// In-memory databases opened by repositories in this process
+static MEMORY_DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Build a database connection pool, naming the database with
+/// `memory_db` if it is kept in memory.
+fn build_named_pool(
+    name: &str,
+    settings: &Settings,
+    memory_db: &str,
+    flags: OpenFlags,
+    min_size: u32,
+    max_size: u32,
+    wait_for_db: bool,
 ) -> SqlitePool {
     let db_dir = &settings.database.data_directory;
     let full_path = Path::new(db_dir).join(DB_FILE);
@@ -1184,87 +2246,883 @@ pub fn build_pool(
             thread::sleep(Duration::from_millis(500));
         }
     }
+    let busy_timeout = Duration::from_millis(settings.database.busy_timeout_ms);
+    let init = move |c: &mut rusqlite::Connection| {
+        c.busy_timeout(busy_timeout)?;
+        c.execute_batch(STARTUP_SQL)
+    };
     let manager = if settings.database.in_memory {
-        SqliteConnectionManager::file("file::memory:?cache=shared")
+        SqliteConnectionManager::file(format!("file:{memory_db}?mode=memory&cache=shared"))
+            .with_flags(flags | OpenFlags::SQLITE_OPEN_URI)
+            .with_init(init)
+    } else {
+        SqliteConnectionManager::file(&full_path)
             .with_flags(flags)
-            .with_init(|c| c.execute_batch(STARTUP_SQL))
+            .with_init(init)
+    };
+    let pool: SqlitePool = r2d2::Pool::builder()
+        .test_on_check_out(true) // no noticeable performance hit
+        .min_idle(Some(min_size))
+        .max_size(max_size)
+        .idle_timeout(Some(Duration::from_secs(10)))
+        .max_lifetime(Some(Duration::from_secs(30)))
+        .build(manager)
+        .unwrap();
+    // retrieve a connection to ensure the startup statements run immediately
+    {
+        let _ = pool.get();
+    }
+
+    info!(
+        "Built a connection pool {:?} (min={}, max={})",
+        name, min_size, max_size
+    );
+    pool
+}
+
+/// Cleanup expired events on a regular basis
+async fn cleanup_expired(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    if let Ok(mut conn) = pool.get() {
+                        let mut _guard:Option<MutexGuard<u64>> = None;
+                        // take a write lock to prevent event writes
+                        // from proceeding while we are deleting
+                        // events.  This isn't necessary, but
+                        // minimizes the chances of forcing event
+                        // persistence to be retried.
+                        _guard = Some(write_in_progress.lock().await);
+                        let start = Instant::now();
+                        let exp_res = tokio::task::spawn_blocking(move || {
+                            delete_expired(&mut conn)
+                        }).await;
+                        match exp_res {
+                            Ok(Ok(count)) => {
+                                if count > 0 {
+                                    info!("removed {} expired events in: {:?}", count, start.elapsed());
+                                }
+                            },
+                            _ => {
+                                // either the task or underlying query failed
+                                info!("there was an error cleaning up expired events: {:?}", exp_res);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Execute a query to delete all expired events
+pub fn delete_expired(conn: &mut PooledConnection) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let update_count = tx.execute(
+        "DELETE FROM event WHERE expires_at <= ?",
+        params![unix_time()],
+    )?;
+    tx.commit()?;
+    Ok(update_count)
+}
+
+/// Determine the tag (name, value) pairs of an event that belong in
+/// the tag table.
+///
+/// Only single-char tag names are searchable, and tag values longer
+/// than `max_tag_bytes` are skipped.  Ids and pubkeys in `e` and `p`
+/// tags are lowercased.
+#[must_use]
+pub fn indexed_tags(e: &Event, max_tag_bytes: Option<usize>) -> Vec<(&String, Cow<'_, str>)> {
+    e.tags
+        .iter()
+        // ensure we have 2 values.
+        .filter(|tag| tag.len() >= 2)
+        .filter(|tag| {
+            single_char_tagname(&tag[0]).is_some()
+                && is_indexable_tag_value(&tag[0], &tag[1], max_tag_bytes)
+        })
+        .map(|tag| (&tag[0], normalize_tag_value(&tag[0], &tag[1])))
+        .collect()
+}
+
+/// Add an event's searchable tags to the tag table.
+pub fn write_tags(
+    conn: &rusqlite::Connection,
+    ev_id: i64,
+    e: &Event,
+    max_tag_bytes: Option<usize>,
+) -> Result<()> {
+    for (tagname, tagval) in indexed_tags(e, max_tag_bytes) {
+        conn.execute(
+            "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![ev_id, tagname, tagval, e.kind, e.created_at],
+        )?;
+    }
+    Ok(())
+}
+
+/// Index the tags of a batch of events that were stored with deferred
+/// tag indexing, oldest first.
+pub(crate) fn index_deferred_tags(
+    conn: &mut PooledConnection,
+    max_tag_bytes: Option<usize>,
+) -> Result<TagIndexBatch> {
+    let tx = conn.transaction()?;
+    let mut batch = TagIndexBatch::default();
+    {
+        let mut stmt = tx.prepare(
+            "SELECT id, content, first_seen FROM event WHERE tags_indexed=0 ORDER BY id LIMIT ?",
+        )?;
+        let rows = stmt
+            .query_map(params![TAG_INDEX_BATCH], |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (ev_id, content, first_seen) in rows {
+            match serde_json::from_str::<Event>(&content) {
+                Ok(e) => write_tags(&tx, ev_id, &e, max_tag_bytes)?,
+                Err(err) => {
+                    warn!("could not index tags of unparseable event (rowid: {ev_id}): {err:?}")
+                }
+            }
+            tx.execute("UPDATE event SET tags_indexed=1 WHERE id=?", params![ev_id])?;
+            batch.indexed += 1;
+            let first_seen = first_seen as u64;
+            batch.oldest_first_seen = Some(
+                batch
+                    .oldest_first_seen
+                    .map_or(first_seen, |t| t.min(first_seen)),
+            );
+        }
+    }
+    batch.pending = tx.query_row("SELECT COUNT(*) FROM event WHERE tags_indexed=0", [], |r| {
+        r.get(0)
+    })?;
+    tx.commit()?;
+    Ok(batch)
+}
+
+/// Index the tags of events stored with deferred indexing, waiting up
+/// to `delay` between batches.  Unless `keep_running` is set, the task
+/// stops once no events are waiting.
+async fn tag_indexer_task(
+    pool: SqlitePool,
+    delay: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    max_tag_bytes: Option<usize>,
+    metrics: NostrMetrics,
+    keep_running: bool,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        let mut backlog = false;
+        loop {
+            if !backlog {
+                tokio::time::sleep(delay).await;
+            }
+            backlog = false;
+            if let Ok(mut conn) = pool.get() {
+                let _guard = write_in_progress.lock().await;
+                let res = tokio::task::spawn_blocking(move || {
+                    index_deferred_tags(&mut conn, max_tag_bytes)
+                })
+                .await;
+                match res {
+                    Ok(Ok(batch)) => {
+                        batch.record(&metrics);
+                        backlog = batch.is_full();
+                        if batch.pending == 0 && !keep_running {
+                            debug!("no events are waiting for tag indexing");
+                            break;
+                        }
+                    }
+                    _ => {
+                        info!("there was an error indexing deferred tags: {:?}", res);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Cleanup events older than the retention period on a regular basis
+async fn cleanup_old_events(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    rules: Vec<RetentionRule>,
+    restricted_kinds: Vec<u64>,
+    purge_delay: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(frequency) => {
+                    for rule in &rules {
+                        let Ok(mut conn) = pool.get() else {
+                            continue;
+                        };
+                        let _guard = write_in_progress.lock().await;
+                        let start = Instant::now();
+                        let cutoff = unix_time().saturating_sub(rule.days as u64 * 86400);
+                        let kinds = restricted_kinds.clone();
+                        let source = rule.source.clone();
+                        let del_res = tokio::task::spawn_blocking(move || {
+                            delete_older_than(&mut conn, cutoff, &kinds, purge_delay, source.as_deref())
+                        }).await;
+                        match del_res {
+                            Ok(Ok(count)) => {
+                                if count > 0 {
+                                    info!("removed {} events ({}) older than {} days in: {:?}", count, rule.source.as_deref().unwrap_or("all sources"), rule.days, start.elapsed());
+                                }
+                            },
+                            _ => {
+                                info!("there was an error cleaning up old events: {:?}", del_res);
+                            }
+                        }
+                    }
+                }
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Evict the oldest events on a regular basis, until no more than
+/// `max_events` are stored.  Batches are repeated without waiting
+/// while the limit is still exceeded.
+async fn evict_events_task(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    max_events: u64,
+    metrics: NostrMetrics,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        let mut backlog = false;
+        loop {
+            if !backlog {
+                tokio::time::sleep(frequency).await;
+            }
+            backlog = false;
+            if let Ok(mut conn) = pool.get() {
+                let _guard = write_in_progress.lock().await;
+                let start = Instant::now();
+                let res =
+                    tokio::task::spawn_blocking(move || evict_oldest_events(&mut conn, max_events))
+                        .await;
+                match res {
+                    Ok(Ok(batch)) => {
+                        batch.record(&metrics);
+                        backlog = batch.is_full();
+                        if batch.evicted > 0 {
+                            info!(
+                                "evicted {} oldest events to stay under max_stored_events ({}) in: {:?}",
+                                batch.evicted,
+                                max_events,
+                                start.elapsed()
+                            );
+                        }
+                    }
+                    _ => {
+                        info!("there was an error evicting old events: {:?}", res);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Delete a batch of the events first seen longest ago, if more than
+/// `max_events` are stored.  Tags are removed with their events.
+///
+/// Only the latest metadata and contact list (kinds 0 and 3) of each
+/// author are ever stored, and those are never evicted.
+pub(crate) fn evict_oldest_events(
+    conn: &mut PooledConnection,
+    max_events: u64,
+) -> Result<EvictionBatch> {
+    let tx = conn.transaction()?;
+    let stored: u64 = tx.query_row("SELECT COUNT(*) FROM event;", [], |r| r.get(0))?;
+    let excess = stored.saturating_sub(max_events).min(EVICTION_BATCH);
+    if excess == 0 {
+        return Ok(EvictionBatch { evicted: 0, stored });
+    }
+    let evicted = tx.execute(
+        "DELETE FROM event WHERE id IN (SELECT id FROM event INDEXED BY event_first_seen_index WHERE kind NOT IN (0, 3) ORDER BY first_seen, id LIMIT ?1);",
+        params![excess],
+    )? as u64;
+    tx.commit()?;
+    Ok(EvictionBatch {
+        evicted,
+        stored: stored - evicted,
+    })
+}
+
+/// Record the newest event delivered to a subscription.  A watermark
+/// never moves backwards.
+pub fn save_watermark(
+    conn: &mut rusqlite::Connection,
+    pubkey: &str,
+    sub_key: &str,
+    created_at: u64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO subscription_watermark (pubkey, sub_key, created_at, updated_at) VALUES (?1, ?2, ?3, ?4) \
+         ON CONFLICT (pubkey, sub_key) DO UPDATE SET created_at = MAX(created_at, excluded.created_at), updated_at = excluded.updated_at;",
+        params![pubkey, sub_key, created_at, unix_time()],
+    )?;
+    Ok(())
+}
+
+/// Remove watermarks that have not been updated since `cutoff`.
+pub fn delete_watermarks_before(conn: &mut rusqlite::Connection, cutoff: u64) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM subscription_watermark WHERE updated_at < ?1;",
+        params![cutoff],
+    )?)
+}
+
+/// Remove unused subscription watermarks on a regular basis
+async fn cleanup_watermarks(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    watermark_days: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(frequency).await;
+            if let Ok(mut conn) = pool.get() {
+                let _guard = write_in_progress.lock().await;
+                let cutoff = unix_time().saturating_sub(watermark_days * 86400);
+                let del_res = tokio::task::spawn_blocking(move || {
+                    delete_watermarks_before(&mut conn, cutoff)
+                })
+                .await;
+                match del_res {
+                    Ok(Ok(count)) => {
+                        if count > 0 {
+                            info!("removed {} unused subscription watermarks", count);
+                        }
+                    }
+                    _ => {
+                        info!(
+                            "there was an error cleaning up subscription watermarks: {:?}",
+                            del_res
+                        );
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Add to the daily write statistics.
+pub fn add_event_stats(conn: &mut rusqlite::Connection, stats: &[EventStat]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO event_stats (pubkey, kind, day, count, bytes) VALUES (?1, ?2, ?3, ?4, ?5) \
+             ON CONFLICT (day, kind, pubkey) DO UPDATE SET count = count + excluded.count, bytes = bytes + excluded.bytes;",
+        )?;
+        for s in stats {
+            stmt.execute(params![
+                s.pubkey,
+                s.kind,
+                s.day.to_string(),
+                s.count,
+                s.bytes
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// The authors that wrote the most events on a day, of one kind or of
+/// all kinds.
+pub fn top_writers(
+    conn: &rusqlite::Connection,
+    day: NaiveDate,
+    kind: Option<u64>,
+    limit: u64,
+) -> Result<Vec<TopWriter>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT pubkey, SUM(count) AS total, SUM(bytes) FROM event_stats WHERE day = ?1 AND (?2 IS NULL OR kind = ?2) \
+         GROUP BY pubkey ORDER BY total DESC, pubkey LIMIT ?3;",
+    )?;
+    let mut rows = stmt.query(params![day.to_string(), kind, limit])?;
+    let mut writers = vec![];
+    while let Some(row) = rows.next()? {
+        writers.push(TopWriter {
+            pubkey: row.get(0)?,
+            count: row.get(1)?,
+            bytes: row.get(2)?,
+        });
+    }
+    Ok(writers)
+}
+
+/// Quarantine status of a pubkey, if it has been seen.
+pub fn pubkey_status(conn: &rusqlite::Connection, pubkey: &str) -> Result<Option<PubkeyStatus>> {
+    let status: Option<String> = conn
+        .prepare_cached("SELECT status FROM first_seen_pubkey WHERE pubkey = ?1;")?
+        .query_row(params![pubkey], |r| r.get(0))
+        .optional()?;
+    status.map(|s| s.parse()).transpose()
+}
+
+/// Record a pubkey seen for the first time, unless it already was.
+pub fn add_first_seen_pubkey(
+    conn: &mut rusqlite::Connection,
+    pubkey: &str,
+    first_seen: u64,
+    status: PubkeyStatus,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO first_seen_pubkey (pubkey, first_seen, status) VALUES (?1, ?2, ?3);",
+        params![pubkey, first_seen, status.as_str()],
+    )?;
+    Ok(())
+}
+
+/// Count an event written by a quarantined pubkey, returning the
+/// events counted so far.
+pub fn count_quarantined_event(conn: &mut rusqlite::Connection, pubkey: &str) -> Result<u64> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE first_seen_pubkey SET event_count = event_count + 1 WHERE pubkey = ?1 AND status = 'quarantined';",
+        params![pubkey],
+    )?;
+    let count = tx
+        .query_row(
+            "SELECT event_count FROM first_seen_pubkey WHERE pubkey = ?1;",
+            params![pubkey],
+            |r| r.get(0),
+        )
+        .optional()?;
+    tx.commit()?;
+    Ok(count.unwrap_or(0))
+}
+
+/// Release or ban a pubkey, recording it if it was not seen.
+pub fn set_pubkey_status(
+    conn: &mut rusqlite::Connection,
+    pubkey: &str,
+    status: PubkeyStatus,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO first_seen_pubkey (pubkey, first_seen, status) VALUES (?1, ?2, ?3) \
+         ON CONFLICT (pubkey) DO UPDATE SET status = excluded.status;",
+        params![pubkey, unix_time(), status.as_str()],
+    )?;
+    Ok(())
+}
+
+/// Release the quarantined pubkeys first seen before
+/// `first_seen_before`, returning them.
+pub fn release_quarantined(
+    conn: &mut rusqlite::Connection,
+    first_seen_before: u64,
+) -> Result<Vec<String>> {
+    let tx = conn.transaction()?;
+    let released = tx
+        .prepare_cached(
+            "SELECT pubkey FROM first_seen_pubkey WHERE status = 'quarantined' AND first_seen < ?1;",
+        )?
+        .query_map(params![first_seen_before], |r| r.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    tx.execute(
+        "UPDATE first_seen_pubkey SET status = 'released' WHERE status = 'quarantined' AND first_seen < ?1;",
+        params![first_seen_before],
+    )?;
+    tx.commit()?;
+    Ok(released)
+}
+
+/// The quarantined and banned pubkeys, oldest first.
+pub fn withheld_pubkeys(conn: &rusqlite::Connection) -> Result<Vec<QuarantinedPubkey>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT pubkey, first_seen, event_count, status FROM first_seen_pubkey \
+         WHERE status IN ('quarantined', 'banned') ORDER BY first_seen, pubkey;",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut pubkeys = vec![];
+    while let Some(row) = rows.next()? {
+        let status: String = row.get(3)?;
+        pubkeys.push(QuarantinedPubkey {
+            pubkey: row.get(0)?,
+            first_seen: row.get(1)?,
+            event_count: row.get(2)?,
+            status: status.parse()?,
+        });
+    }
+    Ok(pubkeys)
+}
+
+/// Remove write statistics for days before `cutoff`.
+pub fn delete_event_stats_before(
+    conn: &mut rusqlite::Connection,
+    cutoff: NaiveDate,
+) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM event_stats WHERE day < ?1;",
+        params![cutoff.to_string()],
+    )?)
+}
+
+/// Remove write statistics older than the retention window on a
+/// regular basis
+async fn cleanup_event_stats(
+    pool: SqlitePool,
+    frequency: Duration,
+    write_in_progress: Arc<Mutex<u64>>,
+    stats_days: u64,
+) -> Result<()> {
+    tokio::task::spawn(async move {
+        loop {
+            tokio::time::sleep(frequency).await;
+            if let Ok(mut conn) = pool.get() {
+                let _guard = write_in_progress.lock().await;
+                let cutoff = day_of(unix_time().saturating_sub(stats_days * 86400));
+                let del_res = tokio::task::spawn_blocking(move || {
+                    delete_event_stats_before(&mut conn, cutoff)
+                })
+                .await;
+                match del_res {
+                    Ok(Ok(count)) => {
+                        if count > 0 {
+                            info!("removed {} expired write statistics", count);
+                        }
+                    }
+                    _ => {
+                        info!(
+                            "there was an error cleaning up write statistics: {:?}",
+                            del_res
+                        );
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Execute a query to delete all events older than `cutoff`.
+///
+/// Restricted kinds (such as gift wraps) have intentionally
+/// randomized timestamps, so their age is based on when they were
+/// first seen, instead of when they were created.
+///
+/// With a non-zero `purge_delay`, events are hidden and left for
+/// [`purge_deleted`] instead.  A hidden deletion request (kind 5)
+/// still refuses the events it deleted, until it is purged.
+///
+/// With a `source`, only events from matching sources are removed.
+pub fn delete_older_than(
+    conn: &mut PooledConnection,
+    cutoff: u64,
+    restricted_kinds: &[u64],
+    purge_delay: u64,
+    source: Option<&str>,
+) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let kinds = restricted_kinds
+        .iter()
+        .map(std::string::ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(cutoff)];
+    if purge_delay > 0 {
+        params.push(Box::new(purge_time(purge_delay)));
+    }
+    // `mirror` matches `mirror:<url>`, as in `source::source_matches`
+    let source_match = if let Some(source) = source {
+        params.push(Box::new(source.to_owned()));
+        let p = params.len();
+        format!(" AND (source = ?{p} OR substr(source, 1, length(?{p}) + 1) = ?{p} || ':')")
+    } else {
+        String::new()
+    };
+    let age = format!(
+        "((kind NOT IN ({kinds}) AND created_at < ?1) OR (kind IN ({kinds}) AND first_seen < ?1)){source_match}"
+    );
+    let query = if purge_delay > 0 {
+        format!(
+            "UPDATE event SET hidden=TRUE, purge_after=?2 WHERE purge_after IS NULL AND ({age})"
+        )
+    } else {
+        format!("DELETE FROM event WHERE {age}")
+    };
+    let update_count = tx.execute(&query, rusqlite::params_from_iter(params))?;
+    tx.commit()?;
+    Ok(update_count)
+}
+
+/// Time after which an event deleted now may be purged, if deleted
+/// events are kept at all.
+fn purge_time(purge_delay: u64) -> Option<u64> {
+    (purge_delay > 0).then(|| unix_time() + purge_delay)
+}
+
+/// Permanently remove deleted events whose purge time is before `now`.
+pub fn purge_deleted(conn: &mut PooledConnection, now: u64) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM event WHERE purge_after IS NOT NULL AND purge_after <= ?1;",
+        params![now],
+    )?)
+}
+
+/// Restore a deleted event that has not been purged yet.  Returns
+/// whether an event was restored.
+pub fn undelete_event(conn: &mut PooledConnection, event_id: &str) -> Result<bool> {
+    let id_blob = hex::decode(event_id)?;
+    let count = conn.execute(
+        "UPDATE event SET hidden=FALSE, purge_after=NULL WHERE event_hash=?1 AND purge_after > ?2;",
+        params![id_blob, unix_time()],
+    )?;
+    Ok(count > 0)
+}
+
+/// Admit (make visible) or remove an event stored as pending.
+/// Returns whether a pending event was found; an event deleted while
+/// it waited is no longer pending.
+pub fn resolve_pending(
+    conn: &mut PooledConnection,
+    event_id: &str,
+    accepted: bool,
+) -> Result<bool> {
+    let id_blob = hex::decode(event_id)?;
+    let count = if accepted {
+        conn.execute(
+            "UPDATE event SET hidden=FALSE, pending=FALSE WHERE event_hash=?1 AND pending=TRUE;",
+            params![id_blob],
+        )?
+    } else {
+        conn.execute(
+            "DELETE FROM event WHERE event_hash=?1 AND pending=TRUE;",
+            params![id_blob],
+        )?
+    };
+    Ok(count > 0)
+}
+
+/// Events still waiting for asynchronous admission, oldest first.
+pub fn pending_events(conn: &rusqlite::Connection) -> Result<Vec<Event>> {
+    let mut stmt = conn.prepare(
+        "SELECT content FROM event INDEXED BY event_pending_index WHERE pending = 1 ORDER BY first_seen;",
+    )?;
+    let rows = stmt.query_map([], |r| r.get::<usize, String>(0))?;
+    let mut events = vec![];
+    for row in rows {
+        events.push(serde_json::from_str(&row?)?);
+    }
+    Ok(events)
+}
+
+/// Pass a record on to an export, failing if the export was abandoned.
+fn send_record(tx: &mpsc::Sender<UserRecord>, table: UserTable, json: String) -> Result<()> {
+    tx.blocking_send(UserRecord { table, json })
+        .map_err(|_| Error::CustomError("export abandoned".to_owned()))
+}
+
+/// Send every event stored for an author, oldest first.  Returns
+/// events sent.
+pub fn export_events_by(
+    conn: &rusqlite::Connection,
+    author: &[u8],
+    tx: &mpsc::Sender<UserRecord>,
+) -> Result<u64> {
+    let mut stmt =
+        conn.prepare("SELECT content FROM event WHERE author = ?1 ORDER BY created_at;")?;
+    let mut rows = stmt.query(params![author])?;
+    let mut sent = 0;
+    while let Some(row) = rows.next()? {
+        send_record(tx, UserTable::Events, row.get(0)?)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Send the verification records, account, invoices and ledger entries
+/// of a pubkey.
+pub fn export_account_data(
+    conn: &rusqlite::Connection,
+    pubkey: &str,
+    tx: &mpsc::Sender<UserRecord>,
+) -> Result<()> {
+    let author = hex::decode(pubkey)?;
+    let mut stmt = conn.prepare(
+        "SELECT e.event_hash, v.name, v.verified_at, v.failed_at, v.failure_count FROM user_verification v INNER JOIN event e ON e.id = v.metadata_event WHERE e.author = ?1 ORDER BY v.id;",
+    )?;
+    let mut rows = stmt.query(params![author])?;
+    while let Some(row) = rows.next()? {
+        let record = json!({
+            "metadata_event": hex::encode(row.get::<_, Vec<u8>>(0)?),
+            "name": row.get::<_, String>(1)?,
+            "verified_at": row.get::<_, Option<u64>>(2)?,
+            "failed_at": row.get::<_, Option<u64>>(3)?,
+            "failure_count": row.get::<_, Option<u64>>(4)?,
+        });
+        send_record(tx, UserTable::Verifications, record.to_string())?;
+    }
+    let mut stmt = conn
+        .prepare("SELECT is_admitted, balance, tos_accepted_at FROM account WHERE pubkey = ?1;")?;
+    let mut rows = stmt.query(params![pubkey])?;
+    while let Some(row) = rows.next()? {
+        let record = json!({
+            "pubkey": pubkey,
+            "is_admitted": row.get::<_, bool>(0)?,
+            "balance": row.get::<_, u64>(1)?,
+            "tos_accepted_at": row.get::<_, Option<u64>>(2)?,
+        });
+        send_record(tx, UserTable::Account, record.to_string())?;
+    }
+    let mut stmt = conn.prepare(
+        "SELECT payment_hash, invoice, amount, status, description, created_at, confirmed_at FROM invoice WHERE pubkey = ?1 ORDER BY created_at;",
+    )?;
+    let mut rows = stmt.query(params![pubkey])?;
+    while let Some(row) = rows.next()? {
+        let record = json!({
+            "payment_hash": row.get::<_, String>(0)?,
+            "bolt11": row.get::<_, String>(1)?,
+            "amount": row.get::<_, u64>(2)?,
+            "status": row.get::<_, String>(3)?,
+            "memo": row.get::<_, Option<String>>(4)?,
+            "created_at": row.get::<_, u64>(5)?,
+            "confirmed_at": row.get::<_, Option<u64>>(6)?,
+        });
+        send_record(tx, UserTable::Invoices, record.to_string())?;
+    }
+    let mut stmt = conn.prepare(
+        "SELECT delta, balance, reason, reference, created_at FROM ledger WHERE pubkey = ?1 ORDER BY id;",
+    )?;
+    let mut rows = stmt.query(params![pubkey])?;
+    while let Some(row) = rows.next()? {
+        let record = json!({
+            "delta": row.get::<_, i64>(0)?,
+            "balance": row.get::<_, i64>(1)?,
+            "reason": row.get::<_, String>(2)?,
+            "reference": row.get::<_, Option<String>>(3)?,
+            "created_at": row.get::<_, u64>(4)?,
+        });
+        send_record(tx, UserTable::Ledger, record.to_string())?;
+    }
+    Ok(())
+}
+
+/// Delete the events of an author.  With a non-zero `purge_delay`,
+/// they are hidden and left for [`purge_deleted`] instead.  Returns
+/// events deleted.
+pub fn erase_events_by(
+    conn: &mut PooledConnection,
+    author: &[u8],
+    purge_delay: u64,
+) -> Result<u64> {
+    let count = if purge_delay > 0 {
+        conn.execute(
+            "UPDATE event SET hidden=TRUE, purge_after=?2 WHERE author=?1 AND purge_after IS NULL;",
+            params![author, purge_time(purge_delay)],
+        )?
     } else {
-        SqliteConnectionManager::file(&full_path)
-            .with_flags(flags)
-            .with_init(|c| c.execute_batch(STARTUP_SQL))
+        conn.execute("DELETE FROM event WHERE author=?1;", params![author])?
     };
-    let pool: SqlitePool = r2d2::Pool::builder()
-        .test_on_check_out(true) // no noticeable performance hit
-        .min_idle(Some(min_size))
-        .max_size(max_size)
-        .idle_timeout(Some(Duration::from_secs(10)))
-        .max_lifetime(Some(Duration::from_secs(30)))
-        .build(manager)
-        .unwrap();
-    // retrieve a connection to ensure the startup statements run immediately
-    {
-        let _ = pool.get();
+    Ok(count as u64)
+}
+
+/// Delete the verification records, account, invoices and ledger
+/// entries of a pubkey, and record its tombstone, if any.  Returns
+/// records deleted.
+pub fn erase_account_data(
+    conn: &mut PooledConnection,
+    pubkey: &str,
+    erased_at: u64,
+    tombstone_until: Option<u64>,
+) -> Result<UserDataCounts> {
+    let author = hex::decode(pubkey)?;
+    let tx = conn.transaction()?;
+    let verifications = tx.execute(
+        "DELETE FROM user_verification WHERE metadata_event IN (SELECT id FROM event WHERE author = ?1);",
+        params![author],
+    )?;
+    let invoices = tx.execute("DELETE FROM invoice WHERE pubkey = ?1;", params![pubkey])?;
+    // the account and its ledger stay, so balances still reconcile,
+    // but admission and terms acceptance are withdrawn
+    let account = tx.execute(
+        "UPDATE account SET is_admitted = 0, tos_accepted_at = NULL WHERE pubkey = ?1;",
+        params![pubkey],
+    )?;
+    if let Some(expires_at) = tombstone_until {
+        tx.execute(
+            "INSERT INTO erased_pubkey (pubkey, erased_at, expires_at) VALUES (?1, ?2, ?3) \
+             ON CONFLICT (pubkey) DO UPDATE SET erased_at = excluded.erased_at, expires_at = excluded.expires_at;",
+            params![pubkey, erased_at, expires_at],
+        )?;
     }
+    tx.commit()?;
+    Ok(UserDataCounts {
+        events: 0,
+        verifications: verifications as u64,
+        account: account as u64,
+        invoices: invoices as u64,
+        ledger: 0,
+    })
+}
 
-    info!(
-        "Built a connection pool {:?} (min={}, max={})",
-        name, min_size, max_size
-    );
-    pool
+/// When a pubkey was erased, if its tombstone lasts past `now`.
+pub fn erased_at(conn: &rusqlite::Connection, pubkey: &str, now: u64) -> Result<Option<u64>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT erased_at FROM erased_pubkey WHERE pubkey = ?1 AND expires_at > ?2;",
+    )?;
+    Ok(stmt
+        .query_row(params![pubkey, now], |r| r.get(0))
+        .optional()?)
 }
 
-/// Cleanup expired events on a regular basis
-async fn cleanup_expired(
+/// Purge deleted events past their recovery window on a regular basis
+async fn purge_deleted_events(
     pool: SqlitePool,
     frequency: Duration,
     write_in_progress: Arc<Mutex<u64>>,
 ) -> Result<()> {
     tokio::task::spawn(async move {
         loop {
-            tokio::select! {
-                _ = tokio::time::sleep(frequency) => {
-                    if let Ok(mut conn) = pool.get() {
-                        let mut _guard:Option<MutexGuard<u64>> = None;
-                        // take a write lock to prevent event writes
-                        // from proceeding while we are deleting
-                        // events.  This isn't necessary, but
-                        // minimizes the chances of forcing event
-                        // persistence to be retried.
-                        _guard = Some(write_in_progress.lock().await);
-                        let start = Instant::now();
-                        let exp_res = tokio::task::spawn_blocking(move || {
-                            delete_expired(&mut conn)
-                        }).await;
-                        match exp_res {
-                            Ok(Ok(count)) => {
-                                if count > 0 {
-                                    info!("removed {} expired events in: {:?}", count, start.elapsed());
-                                }
-                            },
-                            _ => {
-                                // either the task or underlying query failed
-                                info!("there was an error cleaning up expired events: {:?}", exp_res);
-                            }
+            tokio::time::sleep(frequency).await;
+            if let Ok(mut conn) = pool.get() {
+                let _guard = write_in_progress.lock().await;
+                let del_res =
+                    tokio::task::spawn_blocking(move || purge_deleted(&mut conn, unix_time()))
+                        .await;
+                match del_res {
+                    Ok(Ok(count)) => {
+                        if count > 0 {
+                            info!("purged {} deleted events", count);
                         }
                     }
+                    _ => {
+                        info!("there was an error purging deleted events: {:?}", del_res);
+                    }
                 }
-            };
+            }
         }
     });
     Ok(())
 }
 
-/// Execute a query to delete all expired events
-pub fn delete_expired(conn: &mut PooledConnection) -> Result<usize> {
-    let tx = conn.transaction()?;
-    let update_count = tx.execute(
-        "DELETE FROM event WHERE expires_at <= ?",
-        params![unix_time()],
-    )?;
-    tx.commit()?;
-    Ok(update_count)
-}
-
 /// Perform database WAL checkpoint on a regular basis
 pub async fn db_checkpoint_task(
     pool: SqlitePool,
@@ -1385,3 +3243,1192 @@ fn _pool_at_capacity(pool: &SqlitePool) -> bool {
     let state: r2d2::State = pool.state();
     state.idle_connections == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_conn() -> PooledConnection {
+        let manager = SqliteConnectionManager::memory();
+        let pool: SqlitePool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        conn
+    }
+
+    fn test_event(id: u8, kind: u64, created_at: u64) -> Event {
+        Event {
+            id: format!("{id:02x}").repeat(32),
+            pubkey: "ab".repeat(32),
+            delegated_by: None,
+            created_at,
+            kind,
+            tags: vec![],
+            content: "".to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        }
+    }
+
+    #[test]
+    fn retention_uses_first_seen_for_restricted_kinds() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let day = 86400;
+        // an old note, and a gift wrap with a randomized (old) timestamp
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 1, now - 10 * day), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 1059, now - 10 * day), None)?;
+        // a gift wrap that was received long ago
+        SqliteRepo::persist_event(&mut conn, &test_event(3, 1059, now), None)?;
+        conn.execute(
+            "UPDATE event SET first_seen=? WHERE kind=1059 AND created_at=?",
+            params![now - 10 * day, now],
+        )?;
+        let removed = delete_older_than(&mut conn, now - 5 * day, &[4, 44, 1059], 0, None)?;
+        assert_eq!(removed, 2);
+        // only the recently received gift wrap remains
+        let remaining: u64 = conn.query_row("SELECT created_at FROM event", [], |r| r.get(0))?;
+        assert_eq!(remaining, now - 10 * day);
+        Ok(())
+    }
+
+    #[test]
+    fn source_retention_only_removes_matching_events() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let day = 86400;
+        for (id, source) in [
+            (1, "local"),
+            (2, "mirror:wss://a.example.com"),
+            (3, "mirror:wss://b.example.com"),
+            (4, "mirrored:wss://c.example.com"),
+        ] {
+            SqliteRepo::persist_event(&mut conn, &test_event(id, 1, now - 10 * day), None)?;
+            conn.execute(
+                "UPDATE event SET source=? WHERE event_hash=?",
+                params![source, hex::decode(format!("{id:02x}").repeat(32))?],
+            )?;
+        }
+        let cutoff = now - 5 * day;
+        let removed = delete_older_than(
+            &mut conn,
+            cutoff,
+            &[],
+            0,
+            Some("mirror:wss://a.example.com"),
+        )?;
+        assert_eq!(removed, 1);
+        assert_eq!(
+            delete_older_than(&mut conn, cutoff, &[], 0, Some("mirror"))?,
+            1
+        );
+        let mut stmt = conn.prepare("SELECT source FROM event ORDER BY source")?;
+        let remaining = stmt
+            .query_map([], |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(remaining, vec!["local", "mirrored:wss://c.example.com"]);
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_events_are_recoverable_until_purged() -> Result<()> {
+        let mut conn = test_conn();
+        let options = StoreOptions {
+            purge_delay: 3600,
+            ..StoreOptions::default()
+        };
+        let note = test_event(1, 1, 1000);
+        SqliteRepo::store_event(&mut conn, &note, None, options)?;
+        let mut deletion = test_event(9, 5, 1001);
+        deletion.tags = vec![vec!["e".to_owned(), note.id.clone()]];
+        deletion.build_index();
+        SqliteRepo::store_event(&mut conn, &deletion, None, options)?;
+        let (hidden, purge_after): (bool, Option<u64>) = conn.query_row(
+            "SELECT hidden, purge_after FROM event WHERE kind=1",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        assert!(hidden);
+        assert!(purge_after.unwrap() > unix_time());
+        // restoring makes the event visible, and not subject to purging
+        assert!(undelete_event(&mut conn, &note.id)?);
+        assert!(!undelete_event(&mut conn, &note.id)?);
+        let hidden: bool =
+            conn.query_row("SELECT hidden FROM event WHERE kind=1", [], |r| r.get(0))?;
+        assert!(!hidden);
+        // once past the purge time, the event is gone for good
+        conn.execute(
+            "UPDATE event SET hidden=TRUE, purge_after=1 WHERE kind=1",
+            [],
+        )?;
+        assert!(!undelete_event(&mut conn, &note.id)?);
+        assert_eq!(purge_deleted(&mut conn, unix_time())?, 1);
+        // the deletion request is kept, so a re-broadcast stays hidden
+        assert_eq!(
+            SqliteRepo::store_event(&mut conn, &note, None, options)?,
+            WriteResult::Added(0)
+        );
+        let hidden: bool =
+            conn.query_row("SELECT hidden FROM event WHERE kind=1", [], |r| r.get(0))?;
+        assert!(hidden);
+        Ok(())
+    }
+
+    #[test]
+    fn deferred_tags_are_indexed_later() -> Result<()> {
+        let mut conn = test_conn();
+        let tag_count = |conn: &PooledConnection| -> Result<u64> {
+            Ok(conn.query_row("SELECT COUNT(*) FROM tag", [], |r| r.get(0))?)
+        };
+        let mut contacts = test_event(1, 3, 1000);
+        contacts.tags = (0..200)
+            .map(|i: u8| vec!["p".to_owned(), format!("{i:02x}").repeat(32)])
+            .collect();
+        let deferred = StoreOptions {
+            defer_tags: true,
+            ..StoreOptions::default()
+        };
+        SqliteRepo::store_event(&mut conn, &contacts, None, deferred)?;
+        assert_eq!(tag_count(&conn)?, 0);
+        // deletions are indexed immediately, so they apply to later writes
+        let mut deletion = test_event(2, 5, 1001);
+        deletion.tags = vec![vec!["e".to_owned(), "09".repeat(32)]];
+        SqliteRepo::store_event(&mut conn, &deletion, None, deferred)?;
+        assert_eq!(tag_count(&conn)?, 1);
+        let batch = index_deferred_tags(&mut conn, None)?;
+        assert_eq!(batch.indexed, 1);
+        assert_eq!(batch.pending, 0);
+        assert!(batch.oldest_first_seen.is_some());
+        assert_eq!(tag_count(&conn)?, 201);
+        // nothing is indexed twice
+        assert_eq!(
+            index_deferred_tags(&mut conn, None)?,
+            TagIndexBatch::default()
+        );
+        assert_eq!(tag_count(&conn)?, 201);
+        Ok(())
+    }
+
+    #[test]
+    fn eviction_keeps_newest_events_and_identity() -> Result<()> {
+        let mut conn = test_conn();
+        let stored_ids = |conn: &PooledConnection| -> Result<Vec<u8>> {
+            let mut stmt = conn.prepare("SELECT event_hash FROM event ORDER BY id")?;
+            let ids = stmt
+                .query_map([], |r| r.get::<_, Vec<u8>>(0))?
+                .map(|id| id.map(|id| id[0]))
+                .collect::<std::result::Result<_, _>>()?;
+            Ok(ids)
+        };
+        let mut profile = test_event(1, 0, 900);
+        profile.tags = vec![vec!["t".to_owned(), "profile".to_owned()]];
+        SqliteRepo::persist_event(&mut conn, &profile, None)?;
+        let mut note = test_event(2, 1, 1000);
+        note.tags = vec![vec!["t".to_owned(), "note".to_owned()]];
+        SqliteRepo::persist_event(&mut conn, &note, None)?;
+        for id in 3..=5 {
+            SqliteRepo::persist_event(&mut conn, &test_event(id, 1, 1000), None)?;
+        }
+        // stored in the same second; ties are evicted in insert order
+        conn.execute("UPDATE event SET first_seen = 100", [])?;
+        let batch = evict_oldest_events(&mut conn, 3)?;
+        assert_eq!(
+            batch,
+            EvictionBatch {
+                evicted: 2,
+                stored: 3
+            }
+        );
+        assert_eq!(stored_ids(&conn)?, vec![1, 4, 5]);
+        // the evicted note's tags went with it
+        let tags: u64 = conn.query_row("SELECT COUNT(*) FROM tag", [], |r| r.get(0))?;
+        assert_eq!(tags, 1);
+        // under the limit, nothing more is evicted
+        assert_eq!(
+            evict_oldest_events(&mut conn, 3)?,
+            EvictionBatch {
+                evicted: 0,
+                stored: 3
+            }
+        );
+        // metadata is kept even if that leaves the relay over the limit
+        let batch = evict_oldest_events(&mut conn, 0)?;
+        assert_eq!(
+            batch,
+            EvictionBatch {
+                evicted: 2,
+                stored: 1
+            }
+        );
+        assert_eq!(stored_ids(&conn)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn pending_events_are_hidden_until_resolved() -> Result<()> {
+        let mut conn = test_conn();
+        let visible = |conn: &PooledConnection| -> Result<u64> {
+            Ok(
+                conn.query_row("SELECT COUNT(*) FROM event WHERE hidden!=TRUE", [], |r| {
+                    r.get(0)
+                })?,
+            )
+        };
+        let admitted = test_event(1, 1, 1000);
+        let rejected = test_event(2, 1, 1000);
+        let deleted = test_event(3, 1, 1000);
+        let pending = StoreOptions {
+            pending: true,
+            ..StoreOptions::default()
+        };
+        for e in [&admitted, &rejected, &deleted] {
+            assert_eq!(
+                SqliteRepo::store_event(&mut conn, e, None, pending)?,
+                WriteResult::Added(1)
+            );
+        }
+        // a second copy is a duplicate
+        assert_eq!(
+            SqliteRepo::store_event(&mut conn, &admitted, None, pending)?,
+            WriteResult::Added(0)
+        );
+        assert_eq!(visible(&conn)?, 0);
+        let ids: Vec<String> = pending_events(&conn)?.into_iter().map(|e| e.id).collect();
+        assert_eq!(
+            ids,
+            vec![admitted.id.clone(), rejected.id.clone(), deleted.id.clone()]
+        );
+        // a deletion while waiting means it is never admitted
+        let mut deletion = test_event(9, 5, 1001);
+        deletion.tags = vec![vec!["e".to_owned(), deleted.id.clone()]];
+        SqliteRepo::persist_event(&mut conn, &deletion, None)?;
+        assert!(resolve_pending(&mut conn, &admitted.id, true)?);
+        assert!(resolve_pending(&mut conn, &rejected.id, false)?);
+        assert!(!resolve_pending(&mut conn, &deleted.id, true)?);
+        // resolving again finds nothing
+        assert!(!resolve_pending(&mut conn, &admitted.id, false)?);
+        assert!(pending_events(&conn)?.is_empty());
+        // the admitted event and the deletion are visible
+        assert_eq!(visible(&conn)?, 2);
+        let stored: u64 = conn.query_row("SELECT COUNT(*) FROM event", [], |r| r.get(0))?;
+        assert_eq!(stored, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn retention_hides_events_with_purge_delay() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let day = 86400;
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 1, now - 10 * day), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 5, now - 10 * day), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(3, 1, now), None)?;
+        // deletion requests age out like other events
+        let hidden = delete_older_than(&mut conn, now - 5 * day, &[], 3600, None)?;
+        assert_eq!(hidden, 2);
+        let count: u64 = conn.query_row("SELECT COUNT(*) FROM event", [], |r| r.get(0))?;
+        assert_eq!(count, 3);
+        // an event already waiting to be purged keeps its purge time
+        assert_eq!(
+            delete_older_than(&mut conn, now - 5 * day, &[], 3600, None)?,
+            0
+        );
+        assert_eq!(purge_deleted(&mut conn, now + 7200)?, 2);
+        Ok(())
+    }
+
+    /// Ids of the stored events of a kind
+    fn stored_ids(conn: &PooledConnection, kind: u64) -> Result<Vec<String>> {
+        let mut stmt = conn.prepare("SELECT event_hash FROM event WHERE kind=? ORDER BY id")?;
+        let ids = stmt
+            .query_map([kind], |r| r.get::<_, Vec<u8>>(0))?
+            .map(|id| id.map(hex::encode))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    #[test]
+    fn replaceable_ties_keep_lowest_id() -> Result<()> {
+        let mut conn = test_conn();
+        let stored = test_event(5, 0, 1000);
+        let superseded = WriteResult::Superseded(Box::new(stored.clone()));
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &stored, None)?,
+            WriteResult::Added(1)
+        );
+        // older, or from the same second with a higher id
+        for older in [test_event(1, 0, 999), test_event(6, 0, 1000)] {
+            assert_eq!(
+                SqliteRepo::persist_event(&mut conn, &older, None)?,
+                superseded
+            );
+        }
+        // a copy of the stored version is only a duplicate
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &stored, None)?,
+            WriteResult::Added(0)
+        );
+        // the same second with a lower id replaces it
+        let lower = test_event(4, 0, 1000);
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &lower, None)?,
+            WriteResult::Added(1)
+        );
+        assert_eq!(stored_ids(&conn, 0)?, vec![lower.id]);
+        Ok(())
+    }
+
+    #[test]
+    fn parameterized_replaceable_ties_keep_lowest_id() -> Result<()> {
+        let mut conn = test_conn();
+        let with_d = |id, created_at, d: &str| {
+            let mut e = test_event(id, 30000, created_at);
+            e.tags = vec![vec!["d".to_owned(), d.to_owned()]];
+            e
+        };
+        let stored = with_d(5, 1000, "a");
+        SqliteRepo::persist_event(&mut conn, &stored, None)?;
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &with_d(6, 1000, "a"), None)?,
+            WriteResult::Superseded(Box::new(stored.clone()))
+        );
+        // other d tags are unaffected
+        let other = with_d(7, 900, "b");
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &other, None)?,
+            WriteResult::Added(1)
+        );
+        let lower = with_d(4, 1000, "a");
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &lower, None)?,
+            WriteResult::Added(1)
+        );
+        assert_eq!(stored_ids(&conn, 30000)?, vec![other.id, lower.id]);
+        // a deleted version still supersedes, but is not shown
+        conn.execute("UPDATE event SET hidden=TRUE", [])?;
+        assert_eq!(
+            SqliteRepo::persist_event(&mut conn, &with_d(1, 900, "a"), None)?,
+            WriteResult::Added(0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn direct_fetch_by_ids() -> Result<()> {
+        let mut conn = test_conn();
+        for id in 1..=3 {
+            SqliteRepo::persist_event(&mut conn, &test_event(id, 1, 1000 + u64::from(id)), None)?;
+        }
+        conn.execute("UPDATE event SET hidden=TRUE WHERE created_at=1003", [])?;
+        let ids: Vec<String> = (1..=4)
+            .map(|id: u8| format!("{id:02x}").repeat(32))
+            .collect();
+        let filter: ReqFilter = serde_json::from_value(serde_json::json!({ "ids": ids }))?;
+        assert!(filter.is_direct_fetch());
+        let (q, p, idx) = query_from_filter(&filter);
+        assert_eq!(idx.as_deref(), Some("event_hash_index"));
+        assert!(!q.contains("ORDER BY"));
+        let mut stmt = conn.prepare(&q)?;
+        let found = stmt
+            .query_map(rusqlite::params_from_iter(p), |r| r.get::<_, String>(0))?
+            .count();
+        // the hidden and missing events are not returned
+        assert_eq!(found, 2);
+        Ok(())
+    }
+
+    fn create_account(conn: &PooledConnection, pubkey: &str, balance: i64) -> Result<()> {
+        conn.execute(
+            "INSERT INTO account (pubkey, balance) VALUES (?1, ?2)",
+            params![pubkey, balance],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn ledger_sums_to_balance() -> Result<()> {
+        let mut conn = test_conn();
+        let alice = "aa".repeat(32);
+        create_account(&conn, &alice, 0)?;
+        let tx = conn.transaction()?;
+        record_balance_change(&tx, &alice, 1000, LedgerReason::Payment, Some("hash"))?;
+        record_balance_change(&tx, &alice, -500, LedgerReason::Admission, Some("hash"))?;
+        record_balance_change(&tx, &alice, -10, LedgerReason::Publication, Some("ev"))?;
+        record_balance_change(&tx, &alice, 10, LedgerReason::Refund, Some("ev"))?;
+        record_balance_change(&tx, &alice, -10, LedgerReason::Publication, Some("ev2"))?;
+        // changes to unknown accounts are not recorded
+        record_balance_change(&tx, &"bb".repeat(32), 5, LedgerReason::Zap, None)?;
+        tx.commit()?;
+
+        let entries = ledger_entries(&conn, &alice, None)?;
+        assert_eq!(entries.len(), 5);
+        let balance: i64 = conn.query_row(
+            "SELECT balance FROM account WHERE pubkey = ?1",
+            [&alice],
+            |r| r.get(0),
+        )?;
+        assert_eq!(balance, 490);
+        assert_eq!(entries.iter().map(|e| e.delta).sum::<i64>(), balance);
+        // newest first, with the running balance
+        assert_eq!(entries[0].reason, LedgerReason::Publication);
+        assert_eq!(entries[0].reference.as_deref(), Some("ev2"));
+        assert_eq!(entries[0].balance, 490);
+        assert_eq!(ledger_entries(&conn, &alice, Some(2))?.len(), 2);
+        assert!(ledger_mismatches(&conn)?.is_empty());
+
+        // an update that bypasses the ledger is detected
+        conn.execute("UPDATE account SET balance = 7 WHERE pubkey = ?1", [&alice])?;
+        assert_eq!(
+            ledger_mismatches(&conn)?,
+            vec![LedgerMismatch {
+                pubkey: alice,
+                balance: 7,
+                ledger_balance: 490,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ledger_migration_records_opening_balances() -> Result<()> {
+        let mut conn = test_conn();
+        create_account(&conn, &"aa".repeat(32), 250)?;
+        create_account(&conn, &"bb".repeat(32), 0)?;
+        // roll back to the schema before the ledger existed
+        conn.execute_batch("DROP TABLE ledger; PRAGMA user_version = 18;")?;
+        upgrade_db(&mut conn)?;
+        assert!(ledger_mismatches(&conn)?.is_empty());
+        let entries = ledger_entries(&conn, &"aa".repeat(32), None)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].reason, LedgerReason::Admin);
+        assert_eq!(entries[0].balance, 250);
+        assert!(ledger_entries(&conn, &"bb".repeat(32), None)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn published_events_are_counted() -> Result<()> {
+        let mut conn = test_conn();
+        let alice = "aa".repeat(32);
+        create_account(&conn, &alice, 100)?;
+        let tx = conn.transaction()?;
+        record_balance_change(&tx, &alice, -10, LedgerReason::Publication, Some("ev"))?;
+        record_balance_change(&tx, &alice, -10, LedgerReason::Publication, Some("ev2"))?;
+        tx.commit()?;
+        // accounts upgraded from before the counter have paid for events
+        conn.execute_batch("PRAGMA user_version = 29;")?;
+        upgrade_db(&mut conn)?;
+        assert_eq!(count_published_event(&mut conn, &alice)?, 3);
+        // unknown accounts have no count
+        assert_eq!(count_published_event(&mut conn, &"bb".repeat(32))?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn refund_debits_paid_invoice() -> Result<()> {
+        let mut conn = test_conn();
+        let alice = "aa".repeat(32);
+        create_account(&conn, &alice, 0)?;
+        conn.execute(
+            "INSERT INTO invoice (payment_hash, pubkey, invoice, amount, created_at) VALUES ('hash', ?1, 'lnbc', 1000, 0)",
+            [&alice],
+        )?;
+        assert!(refund_invoice(&mut conn, "hash").is_err());
+        assert!(refund_invoice(&mut conn, "missing").is_err());
+        let tx = conn.transaction()?;
+        tx.execute("UPDATE invoice SET status = 'Paid'", [])?;
+        record_balance_change(&tx, &alice, 1000, LedgerReason::Payment, Some("hash"))?;
+        record_balance_change(&tx, &alice, -700, LedgerReason::Publication, Some("ev"))?;
+        tx.commit()?;
+
+        // only the unspent part of the payment can be taken back
+        let refund = refund_invoice(&mut conn, "hash")?;
+        assert_eq!((refund.amount, refund.debited), (1000, 300));
+        let status: String = conn.query_row("SELECT status FROM invoice", [], |r| r.get(0))?;
+        assert_eq!(status.parse::<InvoiceStatus>()?, InvoiceStatus::Refunded);
+        let entries = ledger_entries(&conn, &alice, Some(1))?;
+        assert_eq!(entries[0].reason, LedgerReason::Refund);
+        assert_eq!((entries[0].delta, entries[0].balance), (-300, 0));
+        assert!(ledger_mismatches(&conn)?.is_empty());
+        assert!(refund_invoice(&mut conn, "hash").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn payment_stats_count_unpaid_and_paid() -> Result<()> {
+        let conn = test_conn();
+        let alice = "aa".repeat(32);
+        create_account(&conn, &alice, 0)?;
+        for (hash, amount, status, created_at) in [
+            ("old", 100, "Unpaid", 10),
+            ("new", 200, "Unpaid", 1000),
+            ("paid", 300, "Paid", 1000),
+            ("paid2", 400, "Paid", 10),
+            ("refunded", 500, "Refunded", 1000),
+        ] {
+            conn.execute(
+                "INSERT INTO invoice (payment_hash, pubkey, invoice, amount, status, created_at) VALUES (?1, ?2, 'lnbc', ?3, ?4, ?5)",
+                params![hash, alice, amount, status, created_at],
+            )?;
+        }
+        assert_eq!(
+            payment_stats(&conn, 500)?,
+            PaymentStats {
+                unpaid_invoices: 1,
+                sats_collected: 700,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_by_first_seen() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 1, now), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 1, now), None)?;
+        // the first event was received an hour ago
+        conn.execute(
+            "UPDATE event SET first_seen=? WHERE event_hash=?",
+            params![now - 3600, hex::decode("01".repeat(32)).ok()],
+        )?;
+        let filter: ReqFilter =
+            serde_json::from_str(&format!(r#"{{"_receivedSince":{}}}"#, now - 60))?;
+        let (q, p, _) = query_from_filter(&filter);
+        let mut stmt = conn.prepare(&q)?;
+        let rows: Vec<(String, u64)> = stmt
+            .query_map(rusqlite::params_from_iter(p.iter()), |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(rows.len(), 1);
+        let event: Event = serde_json::from_str(&rows[0].0)?;
+        assert_eq!(event.id, "02".repeat(32));
+        assert!(rows[0].1 >= now);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_uses_first_seen_index() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 1, now), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 1, now), None)?;
+        conn.execute(
+            "UPDATE event SET first_seen=? WHERE event_hash=?",
+            params![now - 3600, hex::decode("01".repeat(32)).ok()],
+        )?;
+        let filter: ReqFilter =
+            serde_json::from_str(&format!(r#"{{"kinds":[1,7],"_resumeFrom":{}}}"#, now - 60))?;
+        let (q, p, idx) = query_from_filter(&filter);
+        assert_eq!(idx.as_deref(), Some("event_first_seen_index"));
+        let plan: Vec<String> = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {q}"))?
+            .query_map(rusqlite::params_from_iter(p.iter()), |r| r.get(3))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert!(plan.iter().any(|d| d.contains("event_first_seen_index")));
+        let count = conn
+            .prepare(&q)?
+            .query_map(rusqlite::params_from_iter(p.iter()), |_| Ok(()))?
+            .count();
+        assert_eq!(count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn excluded_kinds_are_left_out() -> Result<()> {
+        let mut conn = test_conn();
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 1, 100), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 7, 200), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(3, 6, 300), None)?;
+        let matching = |filter: &str| -> Result<usize> {
+            let filter: ReqFilter = serde_json::from_str(filter)?;
+            let (q, p, _) = query_from_filter(&filter);
+            Ok(conn
+                .prepare(&q)?
+                .query_map(rusqlite::params_from_iter(p.iter()), |_| Ok(()))?
+                .count())
+        };
+        assert_eq!(matching(r#"{"_excludeKinds":[7]}"#)?, 2);
+        // exclusions win over kinds, and combine with other conditions
+        assert_eq!(matching(r#"{"kinds":[1,7],"_excludeKinds":[7]}"#)?, 1);
+        let author = "ab".repeat(32);
+        let filter = format!(r#"{{"authors":["{author}"],"since":150,"_excludeKinds":[7]}}"#);
+        assert_eq!(matching(&filter)?, 1);
+        assert_eq!(matching(r#"{"_excludeKinds":[]}"#)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn only_busy_errors_are_retried() {
+        let failure = |code| {
+            SqlError(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(code),
+                None,
+            ))
+        };
+        assert!(is_transient_sqlite_error(&failure(
+            rusqlite::ffi::SQLITE_BUSY
+        )));
+        assert!(is_transient_sqlite_error(&failure(
+            rusqlite::ffi::SQLITE_LOCKED
+        )));
+        assert!(!is_transient_sqlite_error(&failure(
+            rusqlite::ffi::SQLITE_CONSTRAINT
+        )));
+    }
+
+    #[tokio::test]
+    async fn write_retries_while_database_is_busy() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("busy-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let mut settings = Settings::default();
+        settings.database.data_directory = dir.to_string_lossy().into_owned();
+        settings.database.busy_timeout_ms = 10;
+        settings.database.write_attempts = 10;
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics.clone());
+        repo.migrate_up().await?;
+        // a competing writer holds the lock for a while
+        let competitor = rusqlite::Connection::open(dir.join(DB_FILE))?;
+        competitor.execute_batch("BEGIN IMMEDIATE;")?;
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            competitor.execute_batch("COMMIT;")
+        });
+        let written = repo.write_event(&test_event(1, 1, unix_time())).await;
+        release.join().unwrap()?;
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(written?, WriteResult::Added(1));
+        let retries = &metrics.db_write_retries;
+        assert!(retries.with_label_values(&["retried"]).get() > 0);
+        assert_eq!(retries.with_label_values(&["exhausted"]).get(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_subscription_stops_query_between_chunks() -> Result<()> {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        settings.database.data_directory = "query-chunks-test".to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        let stored = QUERY_CHUNK_ROWS * 10;
+        {
+            let mut conn = repo.write_pool.get()?;
+            for i in 0..stored {
+                let mut event = test_event(0, 1, 1000 + i as u64);
+                event.id = format!("{i:064x}");
+                SqliteRepo::persist_event(&mut conn, &event, None)?;
+            }
+        }
+        let sub: Subscription = serde_json::from_str(r#"["REQ","all",{"kinds":[1]}]"#)?;
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(QUERY_CHUNK_ROWS);
+        let (abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "test".to_owned(), query_tx, abandon_rx)
+            .await?;
+        // the client closes the subscription after its first result
+        assert!(query_rx.recv().await.is_some());
+        abandon_tx.send(()).ok();
+        let mut received = 1;
+        while let Some(result) = query_rx.recv().await {
+            assert_ne!(result.event, "EOSE", "query ran to completion");
+            received += 1;
+        }
+        // the scan stops at the end of the chunk being sent
+        assert!(received <= 2 * QUERY_CHUNK_ROWS, "received {received}");
+        Ok(())
+    }
+
+    /// A repository holding `stored` events of kind 1, whose queries
+    /// time out after `timeout_ms`.
+    async fn timeout_repo(name: &str, timeout_ms: u64, stored: usize) -> Result<SqliteRepo> {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        settings.database.data_directory = name.to_owned();
+        settings.database.query_timeout_ms = Some(timeout_ms);
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        let mut conn = repo.write_pool.get()?;
+        for i in 0..stored {
+            let mut event = test_event(0, 1, 1000 + i as u64);
+            event.id = format!("{i:064x}");
+            SqliteRepo::persist_event(&mut conn, &event, None)?;
+        }
+        Ok(repo)
+    }
+
+    /// Events received for a query, until its `EOSE`, and whether
+    /// it was marked `timed_out`.
+    async fn query_results(
+        mut query_rx: tokio::sync::mpsc::Receiver<QueryResult>,
+    ) -> (usize, bool) {
+        let mut received = 0;
+        while let Some(result) = query_rx.recv().await {
+            if result.event == "EOSE" {
+                return (received, result.timed_out);
+            }
+            received += 1;
+        }
+        panic!("query ended without EOSE");
+    }
+
+    #[tokio::test]
+    async fn timed_out_query_sends_eose() -> Result<()> {
+        let mut repo = timeout_repo("query-timeout-test", 1, 10).await?;
+        // out of time before the first row
+        repo.query_timeout = Some(Duration::ZERO);
+        let sub: Subscription = serde_json::from_str(r#"["REQ","all",{"kinds":[1]}]"#)?;
+        let (query_tx, query_rx) = tokio::sync::mpsc::channel(100);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "test".to_owned(), query_tx, abandon_rx)
+            .await?;
+        assert_eq!(query_results(query_rx).await, (0, true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waiting_for_a_slow_client_is_not_timed() -> Result<()> {
+        let repo = timeout_repo("query-wait-test", 200, 3).await?;
+        let sub: Subscription = serde_json::from_str(r#"["REQ","all",{"kinds":[1]}]"#)?;
+        let (query_tx, query_rx) = tokio::sync::mpsc::channel(1);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "test".to_owned(), query_tx, abandon_rx)
+            .await?;
+        // the client is slow past the deadline, but the query is not
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(query_results(query_rx).await, (3, false));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aborted_query_keeps_results_sent() -> Result<()> {
+        let repo = timeout_repo("query-abort-test", 60_000, 10).await?;
+        let sub: Subscription = serde_json::from_str(r#"["REQ","all",{"kinds":[1]}]"#)?;
+        let (query_tx, query_rx) = tokio::sync::mpsc::channel(1);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "test".to_owned(), query_tx, abandon_rx)
+            .await?;
+        // a client too slow to take results aborts the query
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let (received, timed_out) = query_results(query_rx).await;
+        assert!(timed_out);
+        assert!(received > 0 && received < 10, "received {received}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watchdog_pauses() {
+        let conn = test_conn();
+        let watchdog = QueryWatchdog::start(
+            &tokio::runtime::Handle::current(),
+            &conn,
+            Duration::from_millis(100),
+        );
+        watchdog.pause();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!watchdog.expired());
+        watchdog.resume();
+        assert!(!watchdog.expired());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(watchdog.expired());
+    }
+
+    #[tokio::test]
+    async fn latest_created_at_is_per_author() -> Result<()> {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        settings.database.data_directory = "latest-created-at-test".to_owned();
+        let (_, metrics) = crate::server::create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        let author = "ab".repeat(32);
+        assert_eq!(repo.latest_created_at(&author).await?, None);
+        repo.write_event(&test_event(1, 1, 2000)).await?;
+        repo.write_event(&test_event(2, 1, 1000)).await?;
+        let mut other = test_event(3, 1, 3000);
+        other.pubkey = "cd".repeat(32);
+        repo.write_event(&other).await?;
+        assert_eq!(repo.latest_created_at(&author).await?, Some(2000));
+        Ok(())
+    }
+
+    #[test]
+    fn quarantined_pubkeys_are_counted_and_released() -> Result<()> {
+        let mut conn = test_conn();
+        let (old, new, spam) = ("aa".repeat(32), "bb".repeat(32), "cc".repeat(32));
+        assert_eq!(pubkey_status(&conn, &old)?, None);
+        add_first_seen_pubkey(&mut conn, &old, 100, PubkeyStatus::Quarantined)?;
+        add_first_seen_pubkey(&mut conn, &new, 200, PubkeyStatus::Quarantined)?;
+        // a pubkey is only recorded once
+        add_first_seen_pubkey(&mut conn, &old, 300, PubkeyStatus::Released)?;
+        assert_eq!(pubkey_status(&conn, &old)?, Some(PubkeyStatus::Quarantined));
+        assert_eq!(count_quarantined_event(&mut conn, &new)?, 1);
+        assert_eq!(count_quarantined_event(&mut conn, &new)?, 2);
+        set_pubkey_status(&mut conn, &spam, PubkeyStatus::Banned)?;
+        let withheld: Vec<_> = withheld_pubkeys(&conn)?
+            .into_iter()
+            .map(|p| (p.pubkey, p.event_count, p.status))
+            .collect();
+        assert_eq!(withheld[0], (old.clone(), 0, PubkeyStatus::Quarantined));
+        assert_eq!(withheld[1], (new.clone(), 2, PubkeyStatus::Quarantined));
+        assert_eq!(withheld[2].2, PubkeyStatus::Banned);
+        // only pubkeys past their probation are released
+        assert_eq!(release_quarantined(&mut conn, 150)?, vec![old.clone()]);
+        assert_eq!(pubkey_status(&conn, &old)?, Some(PubkeyStatus::Released));
+        // released pubkeys are no longer counted
+        assert_eq!(count_quarantined_event(&mut conn, &old)?, 0);
+        assert_eq!(withheld_pubkeys(&conn)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn tag_filters_and_across_names() -> Result<()> {
+        let mut conn = test_conn();
+        let now = unix_time();
+        let (e1, e2) = ("aa".repeat(32), "bb".repeat(32));
+        let (p1, p2) = ("cc".repeat(32), "dd".repeat(32));
+        let tagged = |id: u8, tags: &[(&str, &str)]| {
+            let mut event = test_event(id, 1, now);
+            event.tags = tags
+                .iter()
+                .map(|(n, v)| vec![(*n).to_owned(), (*v).to_owned()])
+                .collect();
+            event.build_index();
+            event
+        };
+        let events = vec![
+            tagged(1, &[("e", &e1), ("p", &p1), ("d", "x")]),
+            tagged(2, &[("e", &e1), ("p", &p2)]),
+            tagged(3, &[("e", &e2), ("p", &p1), ("d", "x")]),
+            tagged(4, &[("p", &p1), ("d", "y")]),
+        ];
+        for event in &events {
+            SqliteRepo::persist_event(&mut conn, event, None)?;
+        }
+        let cases = [
+            (format!(r##"{{"#e":["{e1}"],"#p":["{p1}"]}}"##), vec![1]),
+            (
+                format!(r##"{{"#e":["{e1}","{e2}"],"#p":["{p1}"]}}"##),
+                vec![1, 3],
+            ),
+            (format!(r##"{{"#p":["{p1}"],"#d":["x"]}}"##), vec![1, 3]),
+            (
+                format!(r##"{{"#e":["{e1}"],"#p":["{p1}","{p2}"],"#d":["x"]}}"##),
+                vec![1],
+            ),
+            (format!(r##"{{"#p":["{p1}","{p2}"]}}"##), vec![1, 2, 3, 4]),
+            (format!(r##"{{"#e":["{e1}"],"#d":["y"]}}"##), vec![]),
+            (
+                r##"{"#d":["x","y"],"kinds":[1]}"##.to_owned(),
+                vec![1, 3, 4],
+            ),
+        ];
+        for (json, expected) in cases {
+            let filter: ReqFilter = serde_json::from_str(&json)?;
+            let (q, p, _) = query_from_filter(&filter);
+            let mut found: Vec<u8> = conn
+                .prepare(&q)?
+                .query_map(rusqlite::params_from_iter(p.iter()), |r| r.get(0))?
+                .map(|content: rusqlite::Result<String>| {
+                    let event: Event = serde_json::from_str(&content?).unwrap();
+                    Ok(u8::from_str_radix(&event.id[..2], 16).unwrap())
+                })
+                .collect::<rusqlite::Result<_>>()?;
+            found.sort_unstable();
+            assert_eq!(found, expected, "filter {json}");
+            // the database agrees with matching for live subscriptions
+            let live: Vec<u8> = events
+                .iter()
+                .filter(|e| filter.interested_in_event(e))
+                .map(|e| u8::from_str_radix(&e.id[..2], 16).unwrap())
+                .collect();
+            assert_eq!(live, expected, "filter {json}");
+            // every tag subquery is served by a tag index
+            let plan: Vec<String> = conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {q}"))?
+                .query_map(rusqlite::params_from_iter(p.iter()), |r| r.get(3))?
+                .collect::<rusqlite::Result<_>>()?;
+            let tag_scans = plan.iter().filter(|d| d.contains(" t ")).count();
+            assert_eq!(tag_scans, filter.tags.as_ref().map_or(0, |t| t.len()));
+            assert!(
+                plan.iter()
+                    .filter(|d| d.contains(" t "))
+                    .all(|d| d.contains("INDEX")),
+                "filter {json}: {plan:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compact_reclaims_deleted_events() -> Result<()> {
+        let mut conn = test_conn();
+        for id in 0..50 {
+            let mut event = test_event(id, 1, 1_000 + u64::from(id));
+            event.content = "x".repeat(4096);
+            SqliteRepo::persist_event(&mut conn, &event, None)?;
+        }
+        let before = database_size(&conn)?;
+        let events = before.relations.iter().find(|r| r.name == "event").unwrap();
+        assert_eq!(events.rows, Some(50));
+        assert!(!events.is_index);
+        assert!(before
+            .relations
+            .iter()
+            .any(|r| r.is_index && r.rows.is_none()));
+        conn.execute("DELETE FROM event", [])?;
+        compact(&conn)?;
+        let after = database_size(&conn)?;
+        let events = after.relations.iter().find(|r| r.name == "event").unwrap();
+        assert_eq!(events.rows, Some(0));
+        assert!(after.total_bytes < before.total_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn recreated_index_keeps_its_definition() -> Result<()> {
+        let mut conn = test_conn();
+        let definition = |conn: &PooledConnection| -> Result<String> {
+            Ok(conn.query_row(
+                "SELECT sql FROM sqlite_master WHERE name = 'event_hash_index'",
+                [],
+                |r| r.get(0),
+            )?)
+        };
+        let before = definition(&conn)?;
+        recreate_index(&mut conn, "event_hash_index")?;
+        assert_eq!(definition(&conn)?, before);
+        // tables, and indexes that do not exist, are not touched
+        assert!(recreate_index(&mut conn, "event").is_err());
+        assert!(recreate_index(&mut conn, "no_such_index").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn watermarks_only_move_forward() -> Result<()> {
+        let mut conn = test_conn();
+        let get = |conn: &PooledConnection| -> Result<Option<u64>> {
+            Ok(conn
+                .query_row(
+                    "SELECT created_at FROM subscription_watermark WHERE pubkey = 'aa' AND sub_key = 'feed'",
+                    [],
+                    |r| r.get(0),
+                )
+                .optional()?)
+        };
+        save_watermark(&mut conn, "aa", "feed", 200)?;
+        save_watermark(&mut conn, "aa", "feed", 100)?;
+        assert_eq!(get(&conn)?, Some(200));
+        save_watermark(&mut conn, "aa", "feed", 300)?;
+        assert_eq!(get(&conn)?, Some(300));
+        assert_eq!(delete_watermarks_before(&mut conn, unix_time() - 60)?, 0);
+        assert_eq!(delete_watermarks_before(&mut conn, unix_time() + 60)?, 1);
+        assert_eq!(get(&conn)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn event_stats_add_up_per_day() -> Result<()> {
+        use crate::stats::parse_day;
+        let mut conn = test_conn();
+        let day = parse_day("2024-05-01")?;
+        let stat = |pubkey: &str, kind, day, count, bytes| EventStat {
+            pubkey: pubkey.to_owned(),
+            kind,
+            day,
+            count,
+            bytes,
+        };
+        add_event_stats(
+            &mut conn,
+            &[stat("aa", 1, day, 2, 200), stat("bb", 1, day, 3, 90)],
+        )?;
+        // flushes add to the counts already stored
+        add_event_stats(
+            &mut conn,
+            &[stat("aa", 1, day, 2, 200), stat("aa", 7, day, 1, 10)],
+        )?;
+        add_event_stats(
+            &mut conn,
+            &[stat("cc", 1, parse_day("2024-04-01")?, 9, 900)],
+        )?;
+        let top = top_writers(&conn, day, Some(1), 10)?;
+        let counts: Vec<_> = top
+            .iter()
+            .map(|w| (w.pubkey.as_str(), w.count, w.bytes))
+            .collect();
+        assert_eq!(counts, vec![("aa", 4, 400), ("bb", 3, 90)]);
+        assert_eq!(top_writers(&conn, day, None, 1)?[0].count, 5);
+        assert_eq!(delete_event_stats_before(&mut conn, day)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn matching_events_are_deleted_with_tags() -> Result<()> {
+        let mut conn = test_conn();
+        for id in 1..=3 {
+            let mut note = test_event(id, 1, 1000 + u64::from(id));
+            note.tags = vec![vec!["t".to_owned(), "spam".to_owned()]];
+            note.build_index();
+            SqliteRepo::persist_event(&mut conn, &note, None)?;
+        }
+        SqliteRepo::persist_event(&mut conn, &test_event(4, 7, 1000), None)?;
+        // hidden events are deleted as well
+        conn.execute("UPDATE event SET hidden=TRUE WHERE created_at=1001", [])?;
+        let filter: ReqFilter = serde_json::from_str(r##"{"kinds":[1],"#t":["spam"]}"##)?;
+        assert_eq!(count_matching(&conn, &filter)?, 3);
+        assert_eq!(delete_matching_batch(&mut conn, &filter)?, 3);
+        assert_eq!(count_matching(&conn, &filter)?, 0);
+        let tags: u64 = conn.query_row("SELECT COUNT(*) FROM tag", [], |r| r.get(0))?;
+        assert_eq!(tags, 0);
+        // a filter without conditions matches everything left
+        let everything: ReqFilter = serde_json::from_str("{}")?;
+        assert_eq!(count_matching(&conn, &everything)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_case_tag_values_are_found() -> Result<()> {
+        let mut conn = test_conn();
+        let referenced = "ab".repeat(32);
+        let mut reply = test_event(1, 1, 1000);
+        reply.tags = vec![
+            vec!["e".to_owned(), referenced.to_uppercase()],
+            vec!["t".to_owned(), "Nostr".to_owned()],
+        ];
+        reply.build_index();
+        SqliteRepo::persist_event(&mut conn, &reply, None)?;
+        // ids are stored in lowercase, other tag values as sent
+        let stored: Vec<String> = conn
+            .prepare("SELECT value FROM tag ORDER BY name")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(stored, vec![referenced.clone(), "Nostr".to_owned()]);
+        for value in [
+            referenced.clone(),
+            referenced.to_uppercase(),
+            "aB".repeat(32),
+        ] {
+            let filter: ReqFilter = serde_json::from_str(&format!(r##"{{"#e":["{value}"]}}"##))?;
+            assert_eq!(count_matching(&conn, &filter)?, 1, "{value}");
+        }
+        let filter: ReqFilter = serde_json::from_str(&format!(
+            r#"{{"ids":["{}"],"authors":["{}"]}}"#,
+            reply.id.to_uppercase(),
+            reply.pubkey.to_uppercase()
+        ))?;
+        assert_eq!(count_matching(&conn, &filter)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_case_tag_rows_are_migrated() -> Result<()> {
+        let mut conn = test_conn();
+        let (first, second) = ("ab".repeat(32), "cd".repeat(32));
+        let mut reply = test_event(1, 1, 1000);
+        reply.tags = vec![
+            vec!["e".to_owned(), first.clone()],
+            vec!["p".to_owned(), second.clone()],
+            vec!["t".to_owned(), "Nostr".to_owned()],
+        ];
+        reply.build_index();
+        SqliteRepo::persist_event(&mut conn, &reply, None)?;
+        // rows as stored before tag values were normalized: the e tag
+        // also in uppercase, the p tag in two other cases
+        conn.execute_batch(&format!(
+            "INSERT INTO tag (event_id, name, value, kind, created_at) SELECT event_id, 'e', '{}', kind, created_at FROM tag WHERE name = 'e';
+             UPDATE tag SET value = '{}' WHERE name = 'p';
+             INSERT INTO tag (event_id, name, value, kind, created_at) SELECT event_id, 'p', '{}', kind, created_at FROM tag WHERE name = 'p';
+             PRAGMA user_version = 30;",
+            first.to_uppercase(),
+            second.to_uppercase(),
+            "cD".repeat(32),
+        ))?;
+        upgrade_db(&mut conn)?;
+        let stored: Vec<(String, String)> = conn
+            .prepare("SELECT name, value FROM tag ORDER BY name")?
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(
+            stored,
+            vec![
+                ("e".to_owned(), first),
+                ("p".to_owned(), second),
+                ("t".to_owned(), "Nostr".to_owned())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn user_data_is_exported_and_erased() -> Result<()> {
+        let mut conn = test_conn();
+        let pubkey = "ab".repeat(32);
+        let author = hex::decode(&pubkey)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(1, 0, 1000), None)?;
+        SqliteRepo::persist_event(&mut conn, &test_event(2, 1, 1001), None)?;
+        let mut other = test_event(3, 1, 1002);
+        other.pubkey = "cd".repeat(32);
+        SqliteRepo::persist_event(&mut conn, &other, None)?;
+        conn.execute_batch(&format!(
+            "INSERT INTO user_verification (metadata_event, name) SELECT id, 'a@example.com' FROM event WHERE kind=0;
+             INSERT INTO account (pubkey, is_admitted, balance) VALUES ('{pubkey}', 1, 5);
+             INSERT INTO invoice (payment_hash, pubkey, invoice, amount, status, created_at) VALUES ('h', '{pubkey}', 'lnbc', 5, 'Paid', 900);
+             INSERT INTO ledger (pubkey, delta, balance, reason, created_at) VALUES ('{pubkey}', 5, 5, 'payment', 901);"
+        ))?;
+        let (tx, mut rx) = mpsc::channel(100);
+        assert_eq!(export_events_by(&conn, &author, &tx)?, 2);
+        export_account_data(&conn, &pubkey, &tx)?;
+        drop(tx);
+        let mut tables = vec![];
+        while let Ok(record) = rx.try_recv() {
+            tables.push(record.table);
+        }
+        assert_eq!(
+            tables,
+            vec![
+                UserTable::Events,
+                UserTable::Events,
+                UserTable::Verifications,
+                UserTable::Account,
+                UserTable::Invoices,
+                UserTable::Ledger
+            ]
+        );
+        let removed = erase_account_data(&mut conn, &pubkey, 1500, Some(2000))?;
+        assert_eq!(
+            removed,
+            UserDataCounts {
+                events: 0,
+                verifications: 1,
+                account: 1,
+                invoices: 1,
+                ledger: 0,
+            }
+        );
+        // the ledger still reconciles with the closed account
+        assert!(ledger_mismatches(&conn)?.is_empty());
+        let admitted: bool = conn.query_row(
+            "SELECT is_admitted FROM account WHERE pubkey = ?1",
+            params![pubkey],
+            |r| r.get(0),
+        )?;
+        assert!(!admitted);
+        // events stay recoverable, and the other author's are untouched
+        assert_eq!(erase_events_by(&mut conn, &author, 3600)?, 2);
+        let visible: u64 =
+            conn.query_row("SELECT COUNT(*) FROM event WHERE hidden=0", [], |r| {
+                r.get(0)
+            })?;
+        assert_eq!(visible, 1);
+        assert!(undelete_event(&mut conn, &test_event(2, 1, 1001).id)?);
+        // the tombstone lasts until it expires
+        assert_eq!(erased_at(&conn, &pubkey, 1999)?, Some(1500));
+        assert_eq!(erased_at(&conn, &pubkey, 2000)?, None);
+        assert_eq!(erased_at(&conn, &other.pubkey, 1999)?, None);
+        Ok(())
+    }
+}
@@ -1,10 +1,10 @@
 //! Database schema and migrations
 use crate::db::PooledConnection;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::{is_indexable_tag_value, single_char_tagname, Event};
+use crate::startup;
 use crate::utils::is_lower_hex;
 use const_format::formatcp;
-use indicatif::{ProgressBar, ProgressStyle};
 use rusqlite::limits::Limit;
 use rusqlite::params;
 use rusqlite::Connection;
@@ -23,7 +23,7 @@ pragma mmap_size = 0; -- disable mmap (default)
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 18;
+pub const DB_VERSION: usize = 31;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -48,6 +48,10 @@ author BLOB NOT NULL, -- author pubkey
 delegated_by BLOB, -- delegator pubkey (NIP-26)
 kind INTEGER NOT NULL, -- event kind
 hidden INTEGER, -- relevant for queries
+purge_after INTEGER, -- when a hidden event may be permanently deleted
+tags_indexed INTEGER NOT NULL DEFAULT 1, -- false until the event's tags are in the tag table
+pending INTEGER NOT NULL DEFAULT 0, -- stored (hidden) while waiting for asynchronous admission
+source TEXT NOT NULL DEFAULT 'local', -- where the event came from (local, mirror:<url>, ingest:<url>)
 content TEXT NOT NULL -- serialized json of event object
 );
 
@@ -63,6 +67,8 @@ CREATE INDEX IF NOT EXISTS kind_created_at_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS author_created_at_index ON event(author,created_at);
 CREATE INDEX IF NOT EXISTS author_kind_index ON event(author,kind);
 CREATE INDEX IF NOT EXISTS event_expiration ON event(expires_at);
+CREATE INDEX IF NOT EXISTS event_first_seen_index ON event(first_seen);
+CREATE INDEX IF NOT EXISTS event_source_index ON event(source, created_at) WHERE source != 'local';
 
 -- Tag Table
 -- Tag values are stored as either a BLOB (if they come in as a
@@ -102,7 +108,8 @@ CREATE TABLE IF NOT EXISTS account (
 pubkey TEXT PRIMARY KEY,
 is_admitted INTEGER NOT NULL DEFAULT 0,
 balance INTEGER NOT NULL DEFAULT 0,
-tos_accepted_at INTEGER
+tos_accepted_at INTEGER,
+event_count INTEGER NOT NULL DEFAULT 0 -- events published, for free publications
 );
 
 -- Create account index
@@ -114,7 +121,7 @@ payment_hash TEXT PRIMARY KEY,
 pubkey TEXT NOT NULL,
 invoice TEXT NOT NULL,
 amount INTEGER NOT NULL,
-status TEXT CHECK ( status IN ('Paid', 'Unpaid', 'Expired' ) ) NOT NUll DEFAULT 'Unpaid',
+status TEXT CHECK ( status IN ('Paid', 'Unpaid', 'Expired', 'Refunded' ) ) NOT NUll DEFAULT 'Unpaid',
 description TEXT,
 created_at INTEGER NOT NULL,
 confirmed_at INTEGER,
@@ -124,6 +131,65 @@ CONSTRAINT invoice_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey)
 -- Create invoice index
 CREATE INDEX IF NOT EXISTS invoice_pubkey_index ON invoice(pubkey);
 
+-- Ledger of account balance changes
+CREATE TABLE IF NOT EXISTS ledger (
+id INTEGER PRIMARY KEY,
+pubkey TEXT NOT NULL,
+delta INTEGER NOT NULL,
+balance INTEGER NOT NULL,
+reason TEXT CHECK ( reason IN ('admission', 'publication', 'payment', 'zap', 'admin', 'refund') ) NOT NULL,
+reference TEXT,
+created_at INTEGER NOT NULL,
+CONSTRAINT ledger_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey) ON DELETE CASCADE
+);
+
+-- Create ledger index
+CREATE INDEX IF NOT EXISTS ledger_pubkey_index ON ledger(pubkey, id);
+
+-- Newest event delivered to a repeated subscription
+CREATE TABLE IF NOT EXISTS subscription_watermark (
+pubkey TEXT NOT NULL, -- authenticated client pubkey
+sub_key TEXT NOT NULL, -- hash of the subscription id and filters
+created_at INTEGER NOT NULL, -- newest event delivered
+updated_at INTEGER NOT NULL,
+PRIMARY KEY (pubkey, sub_key)
+);
+CREATE INDEX IF NOT EXISTS subscription_watermark_updated_index ON subscription_watermark(updated_at);
+
+-- Deleted events waiting to be purged
+CREATE INDEX IF NOT EXISTS event_purge_index ON event(purge_after) WHERE purge_after IS NOT NULL;
+
+-- Events whose tags are waiting to be indexed
+CREATE INDEX IF NOT EXISTS event_unindexed_tags_index ON event(id) WHERE tags_indexed = 0;
+
+-- Events waiting for asynchronous admission
+CREATE INDEX IF NOT EXISTS event_pending_index ON event(first_seen) WHERE pending = 1;
+
+-- Events written per author, kind and day
+CREATE TABLE IF NOT EXISTS event_stats (
+pubkey TEXT NOT NULL, -- author pubkey
+kind INTEGER NOT NULL,
+day TEXT NOT NULL, -- UTC date, YYYY-MM-DD
+count INTEGER NOT NULL,
+bytes INTEGER NOT NULL, -- total size of the events, serialized
+PRIMARY KEY (day, kind, pubkey)
+) WITHOUT ROWID;
+
+-- Pubkeys seen by the relay, and whether they are quarantined
+CREATE TABLE IF NOT EXISTS first_seen_pubkey (
+pubkey TEXT PRIMARY KEY, -- author pubkey
+first_seen INTEGER NOT NULL, -- when the first event of the pubkey was received
+event_count INTEGER NOT NULL DEFAULT 0, -- events written while quarantined
+status TEXT NOT NULL -- quarantined, released or banned
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS first_seen_pubkey_status_index ON first_seen_pubkey(status, first_seen);
+
+-- Pubkeys whose data was erased, whose older events are refused
+CREATE TABLE IF NOT EXISTS erased_pubkey (
+pubkey TEXT PRIMARY KEY,
+erased_at INTEGER NOT NULL, -- events created before this are refused
+expires_at INTEGER NOT NULL -- when events are accepted again
+) WITHOUT ROWID;
 
 "##,
     DB_VERSION
@@ -150,6 +216,16 @@ pub fn db_tag_count(conn: &mut Connection) -> Result<usize> {
     Ok(count)
 }
 
+/// Count tag rows with a value longer than `max_bytes`.
+///
+/// Hex values are stored as blobs, so their length is doubled to
+/// compare against the original string length.
+pub fn db_oversize_tag_count(conn: &mut Connection, max_bytes: usize) -> Result<usize> {
+    let query = "SELECT count(*) FROM tag WHERE length(CAST(value AS BLOB)) > ?1 OR length(value_hex)*2 > ?1;";
+    let count = conn.query_row(query, [max_bytes], |row| row.get(0))?;
+    Ok(count)
+}
+
 fn mig_init(conn: &mut PooledConnection) -> usize {
     match conn.execute_batch(INIT_SQL) {
         Ok(()) => {
@@ -188,6 +264,7 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
     match curr_version.cmp(&DB_VERSION) {
         // Database is new or not current
         Ordering::Less => {
+            startup::migration_started(format!("sqlite v{curr_version} -> v{DB_VERSION}"));
             // initialize from scratch
             if curr_version == 0 {
                 curr_version = mig_init(conn);
@@ -245,6 +322,45 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 17 {
                 curr_version = mig_17_to_18(conn)?;
             }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
+            if curr_version == 21 {
+                curr_version = mig_21_to_22(conn)?;
+            }
+            if curr_version == 22 {
+                curr_version = mig_22_to_23(conn)?;
+            }
+            if curr_version == 23 {
+                curr_version = mig_23_to_24(conn)?;
+            }
+            if curr_version == 24 {
+                curr_version = mig_24_to_25(conn)?;
+            }
+            if curr_version == 25 {
+                curr_version = mig_25_to_26(conn)?;
+            }
+            if curr_version == 26 {
+                curr_version = mig_26_to_27(conn)?;
+            }
+            if curr_version == 27 {
+                curr_version = mig_27_to_28(conn)?;
+            }
+            if curr_version == 28 {
+                curr_version = mig_28_to_29(conn)?;
+            }
+            if curr_version == 29 {
+                curr_version = mig_29_to_30(conn)?;
+            }
+            if curr_version == 30 {
+                curr_version = mig_30_to_31(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -252,6 +368,7 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
                     DB_VERSION
                 );
             }
+            startup::migrations_finished();
         }
         // Database is current, all is good
         Ordering::Equal => {
@@ -271,7 +388,9 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
     Ok(DB_VERSION)
 }
 
-pub fn rebuild_tags(conn: &mut PooledConnection) -> Result<()> {
+/// Rebuild the tag table from event contents.  Tag values longer than
+/// `max_tag_bytes` are not indexed.
+pub fn rebuild_tags(conn: &mut PooledConnection, max_tag_bytes: Option<usize>) -> Result<()> {
     // Check how many events we have to process
     let count = db_event_count(conn)?;
     let update_each_percent = 0.05;
@@ -302,6 +421,10 @@ pub fn rebuild_tags(conn: &mut PooledConnection) -> Result<()> {
                 }
                 // safe because len was > 1
                 let tagval = t.get(1).unwrap();
+                // skip values too long to index
+                if !is_indexable_tag_value(tagname, tagval, max_tag_bytes) {
+                    continue;
+                }
                 // insert as BLOB if we can restore it losslessly.
                 // this means it needs to be even length and lowercase.
                 if (tagval.len() % 2 == 0) && is_lower_hex(tagval) {
@@ -724,13 +847,7 @@ CREATE INDEX IF NOT EXISTS tag_covering_index ON tag(name,kind,value,created_at,
     let start = Instant::now();
     let tx = conn.transaction()?;
 
-    let bar = ProgressBar::new(count.try_into().unwrap()).with_message("rebuilding tags table");
-    bar.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.white/blue} {pos:>7}/{len:7} [{percent}%] {msg}",
-        )
-        .unwrap(),
-    );
+    let bar = startup::progress_bar(count.try_into().unwrap(), "rebuilding tags table");
     {
         tx.execute_batch(upgrade_sql)?;
         let mut stmt =
@@ -839,3 +956,305 @@ PRAGMA user_version = 18;
     }
     Ok(18)
 }
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let upgrade_sql = r##"
+-- Ledger of account balance changes
+CREATE TABLE IF NOT EXISTS ledger (
+id INTEGER PRIMARY KEY,
+pubkey TEXT NOT NULL,
+delta INTEGER NOT NULL,
+balance INTEGER NOT NULL,
+reason TEXT CHECK ( reason IN ('admission', 'publication', 'payment', 'zap', 'admin', 'refund') ) NOT NULL,
+reference TEXT,
+created_at INTEGER NOT NULL,
+CONSTRAINT ledger_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS ledger_pubkey_index ON ledger(pubkey, id);
+-- Existing balances become opening entries, so the ledger sums to the balance
+INSERT INTO ledger (pubkey, delta, balance, reason, reference, created_at)
+SELECT pubkey, balance, balance, 'admin', 'opening balance', strftime('%s','now') FROM account WHERE balance != 0;
+pragma optimize;
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v18 -> v19");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+-- Index for resuming subscriptions by server receive time
+CREATE INDEX IF NOT EXISTS event_first_seen_index ON event(first_seen);
+pragma optimize;
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    // SQLite can not alter a check constraint, so the invoice table is
+    // rebuilt to allow the new status.  Nothing references it.
+    let upgrade_sql = r##"
+CREATE TABLE invoice_new (
+payment_hash TEXT PRIMARY KEY,
+pubkey TEXT NOT NULL,
+invoice TEXT NOT NULL,
+amount INTEGER NOT NULL,
+status TEXT CHECK ( status IN ('Paid', 'Unpaid', 'Expired', 'Refunded' ) ) NOT NUll DEFAULT 'Unpaid',
+description TEXT,
+created_at INTEGER NOT NULL,
+confirmed_at INTEGER,
+CONSTRAINT invoice_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey) ON DELETE CASCADE
+);
+INSERT INTO invoice_new SELECT payment_hash, pubkey, invoice, amount, status, description, created_at, confirmed_at FROM invoice;
+DROP TABLE invoice;
+ALTER TABLE invoice_new RENAME TO invoice;
+CREATE INDEX IF NOT EXISTS invoice_pubkey_index ON invoice(pubkey);
+PRAGMA user_version = 21;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v20 -> v21");
+    Ok(21)
+}
+
+fn mig_21_to_22(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 21->22");
+    let upgrade_sql = r##"
+-- Newest event delivered to a repeated subscription
+CREATE TABLE IF NOT EXISTS subscription_watermark (
+pubkey TEXT NOT NULL,
+sub_key TEXT NOT NULL,
+created_at INTEGER NOT NULL,
+updated_at INTEGER NOT NULL,
+PRIMARY KEY (pubkey, sub_key)
+);
+CREATE INDEX IF NOT EXISTS subscription_watermark_updated_index ON subscription_watermark(updated_at);
+PRAGMA user_version = 22;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v21 -> v22");
+    Ok(22)
+}
+
+fn mig_22_to_23(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 22->23");
+    let upgrade_sql = r##"
+-- Deleted events are hidden until they are purged
+CREATE INDEX IF NOT EXISTS event_purge_index ON event(purge_after) WHERE purge_after IS NOT NULL;
+PRAGMA user_version = 23;
+"##;
+    let tx = conn.transaction()?;
+    // the column may already exist, if an earlier upgrade was re-run
+    let has_column: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('event') WHERE name='purge_after'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_column {
+        tx.execute_batch("ALTER TABLE event ADD COLUMN purge_after INTEGER;")?;
+    }
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v22 -> v23");
+    Ok(23)
+}
+
+fn mig_23_to_24(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 23->24");
+    let upgrade_sql = r##"
+-- Events whose tags are waiting to be indexed
+CREATE INDEX IF NOT EXISTS event_unindexed_tags_index ON event(id) WHERE tags_indexed = 0;
+PRAGMA user_version = 24;
+"##;
+    let tx = conn.transaction()?;
+    // the column may already exist, if an earlier upgrade was re-run
+    let has_column: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('event') WHERE name='tags_indexed'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_column {
+        tx.execute_batch("ALTER TABLE event ADD COLUMN tags_indexed INTEGER NOT NULL DEFAULT 1;")?;
+    }
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v23 -> v24");
+    Ok(24)
+}
+
+fn mig_24_to_25(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 24->25");
+    let upgrade_sql = r##"
+-- Events waiting for asynchronous admission
+CREATE INDEX IF NOT EXISTS event_pending_index ON event(first_seen) WHERE pending = 1;
+PRAGMA user_version = 25;
+"##;
+    let tx = conn.transaction()?;
+    // the column may already exist, if an earlier upgrade was re-run
+    let has_column: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('event') WHERE name='pending'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_column {
+        tx.execute_batch("ALTER TABLE event ADD COLUMN pending INTEGER NOT NULL DEFAULT 0;")?;
+    }
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v24 -> v25");
+    Ok(25)
+}
+
+fn mig_25_to_26(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 25->26");
+    let upgrade_sql = r##"
+-- Events written per author, kind and day
+CREATE TABLE IF NOT EXISTS event_stats (
+pubkey TEXT NOT NULL,
+kind INTEGER NOT NULL,
+day TEXT NOT NULL,
+count INTEGER NOT NULL,
+bytes INTEGER NOT NULL,
+PRIMARY KEY (day, kind, pubkey)
+) WITHOUT ROWID;
+PRAGMA user_version = 26;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v25 -> v26");
+    Ok(26)
+}
+
+fn mig_26_to_27(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 26->27");
+    let upgrade_sql = r##"
+-- Pubkeys seen by the relay, and whether they are quarantined
+CREATE TABLE IF NOT EXISTS first_seen_pubkey (
+pubkey TEXT PRIMARY KEY,
+first_seen INTEGER NOT NULL,
+event_count INTEGER NOT NULL DEFAULT 0,
+status TEXT NOT NULL
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS first_seen_pubkey_status_index ON first_seen_pubkey(status, first_seen);
+PRAGMA user_version = 27;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v26 -> v27");
+    Ok(27)
+}
+
+fn mig_27_to_28(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 27->28");
+    let upgrade_sql = r##"
+-- Mirrored and ingested events, for retention by source
+CREATE INDEX IF NOT EXISTS event_source_index ON event(source, created_at) WHERE source != 'local';
+PRAGMA user_version = 28;
+"##;
+    let tx = conn.transaction()?;
+    // existing events were all published to this relay
+    let has_column: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('event') WHERE name='source'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_column {
+        tx.execute_batch("ALTER TABLE event ADD COLUMN source TEXT NOT NULL DEFAULT 'local';")?;
+    }
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v27 -> v28");
+    Ok(28)
+}
+
+fn mig_28_to_29(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 28->29");
+    let upgrade_sql = r##"
+-- Pubkeys whose data was erased, whose older events are refused
+CREATE TABLE IF NOT EXISTS erased_pubkey (
+pubkey TEXT PRIMARY KEY,
+erased_at INTEGER NOT NULL,
+expires_at INTEGER NOT NULL
+) WITHOUT ROWID;
+PRAGMA user_version = 29;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v28 -> v29");
+    Ok(29)
+}
+
+fn mig_29_to_30(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 29->30");
+    let upgrade_sql = r##"
+-- accounts that already paid for events have used their free ones
+UPDATE account SET event_count = (SELECT COUNT(*) FROM ledger WHERE ledger.pubkey = account.pubkey AND ledger.reason = 'publication');
+PRAGMA user_version = 30;
+"##;
+    let tx = conn.transaction()?;
+    // events published by each account, for free publications
+    let has_column: bool = tx.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('account') WHERE name='event_count'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !has_column {
+        tx.execute_batch("ALTER TABLE account ADD COLUMN event_count INTEGER NOT NULL DEFAULT 0;")?;
+    }
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v29 -> v30");
+    Ok(30)
+}
+
+fn mig_30_to_31(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 30->31");
+    let upgrade_sql = r##"
+-- Ids and pubkeys in e and p tags are indexed in lowercase.  Drop
+-- values the event already has in lowercase (or in another case),
+-- and lowercase the rest.
+DELETE FROM tag
+WHERE name IN ('e', 'p') AND length(value) = 64 AND value != lower(value)
+AND value NOT GLOB '*[^0-9A-Fa-f]*'
+AND EXISTS (
+  SELECT 1 FROM tag o WHERE o.event_id = tag.event_id AND o.name = tag.name
+  AND lower(o.value) = lower(tag.value) AND (o.value = lower(o.value) OR o.id < tag.id)
+);
+UPDATE tag SET value = lower(value)
+WHERE name IN ('e', 'p') AND length(value) = 64 AND value != lower(value)
+AND value NOT GLOB '*[^0-9A-Fa-f]*';
+PRAGMA user_version = 31;
+"##;
+    let tx = conn.transaction()?;
+    tx.execute_batch(upgrade_sql)?;
+    tx.commit()?;
+    info!("database schema upgraded v30 -> v31");
+    Ok(31)
+}
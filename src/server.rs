@@ -1,34 +1,74 @@
 //! Server process
+use crate::admin::{
+    BroadcastRequest, BROADCAST_NOTICE_PATH, CONNECTIONS_PATH, DELETE_EVENTS_PATH, IMPORT_PATH,
+    QUARANTINE_PATH, READ_ONLY_PATH, SOURCES_PATH, STATS_PATH, WHITELIST_PATH,
+};
+use crate::archive::EventArchive;
 use crate::close::Close;
 use crate::close::CloseCmd;
-use crate::config::{Settings, VerifiedUsersMode};
+use crate::close::CLOSE_ALL;
+use crate::coalesce::CoalescingStream;
+use crate::compression::{accepts_gzip, gzip_response};
+use crate::config::{
+    Listener, ListenerPolicy, Network, PayToRelay, Settings, UnknownMessages, VerifiedUsersMode,
+};
 use crate::conn;
 use crate::db;
-use crate::db::SubmittedEvent;
-use crate::error::{Error, Result};
+use crate::db::{enforce, SubmittedEvent};
+use crate::disconnect::{close_connection, CloseReason};
+use crate::eose::{EndOfStored, StoredPhase};
+use crate::error::{malformed_json, Error, RejectReason, Result};
+use crate::event::malformed_event_field;
+use crate::event::BroadcastEvent;
 use crate::event::Event;
 use crate::event::EventCmd;
 use crate::event::EventWrapper;
+use crate::geoip::GeoFilter;
+use crate::hooks::{AppState, LifecycleHooks, NoopHooks};
+use crate::icon::{RelayIcon, ICON_PATH};
+use crate::identity::{ClientIdentities, IdentityGuard};
+use crate::import::{self, ImportStop};
 use crate::info::RelayInfo;
+use crate::languages::DetectedLanguages;
+use crate::listener::{bind_tcp, tls_acceptor, tls_incoming};
+use crate::maintenance::ReadOnlyMode;
+use crate::membership::Membership;
 use crate::nip05;
-use crate::notice::Notice;
+use crate::nip98;
+use crate::notice::{Notice, NEWER_VERSION_SUB_ID};
 use crate::payment;
+use crate::payment::lnurl;
 use crate::payment::InvoiceInfo;
 use crate::payment::PaymentMessage;
+use crate::progress::{progress_message, ProgressFormat, QueryProgress};
+use crate::quarantine::{PubkeyQuarantine, PubkeyStatus, QuarantineReadPolicy};
+use crate::ratelimit::{auth_failure_delay, ConnectionGuard, RateLimits};
+use crate::read_policy::ReadPolicy;
+use crate::rejection_log::RejectionLog;
+use crate::relay_keys::{NoticeTarget, RelayKeys, RelayNotice};
+use crate::relay_proof::{proof_response, ProofCmd, MAX_CHALLENGE_LEN};
 use crate::repo::NostrRepo;
 use crate::server::Error::CommandUnknownError;
 use crate::server::EventWrapper::{WrappedAuth, WrappedEvent};
-use crate::subscription::Subscription;
+use crate::startup::{self, HEALTH_PATH, READY_PATH};
+use crate::stats::{self, WriteStats};
+use crate::status::{RelaySummary, StatusPublisher};
+use crate::subscription::{check_req_limits, ReqFilter, ReqLimits, Subscription};
+use crate::supported::{supported_response, SupportedCmd};
+use crate::utils::{forwarded_client_ip, html_escape, is_lower_hex, relay_http_url, unix_time};
+use crate::watermark::{self, Pending, Watermarks};
 use futures::SinkExt;
 use futures::StreamExt;
 use governor::{Jitter, Quota, RateLimiter};
 use http::header::HeaderMap;
 use hyper::body::to_bytes;
 use hyper::header::ACCEPT;
+use hyper::server::accept;
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::upgrade::Upgraded;
 use hyper::{
-    header, server::conn::AddrStream, upgrade, Body, Request, Response, Server, StatusCode,
+    header, server::conn::AddrStream, upgrade, Body, Method, Request, Response, Server, StatusCode,
 };
 use nostr::key::FromPkStr;
 use nostr::key::Keys;
@@ -39,9 +79,10 @@ use qrcode::render::svg;
 use qrcode::QrCode;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
 use std::fs::File;
+use std::future::Future;
 use std::io::BufReader;
 use std::io::Read;
 use std::net::SocketAddr;
@@ -51,10 +92,15 @@ use std::sync::mpsc::Receiver as MpscReceiver;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use tokio::net::TcpStream;
 use tokio::runtime::Builder;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, error, info, trace, warn};
 use tungstenite::error::CapacityError::MessageTooLong;
@@ -63,6 +109,92 @@ use tungstenite::handshake;
 use tungstenite::protocol::Message;
 use tungstenite::protocol::WebSocketConfig;
 
+/// Shared state for serving connections accepted on one listener
+#[derive(Clone)]
+struct ListenerState {
+    repo: Arc<dyn NostrRepo>,
+    settings: Settings,
+    broadcast: Sender<BroadcastEvent>,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+    payment_tx: broadcast::Sender<PaymentMessage>,
+    shutdown: Sender<()>,
+    favicon: Option<Vec<u8>>,
+    icon: Option<RelayIcon>,
+    registry: Registry,
+    metrics: NostrMetrics,
+    read_policy: Arc<dyn ReadPolicy>,
+    listener: Arc<ListenerPolicy>,
+    relay_keys: Option<RelayKeys>,
+    notices: Sender<RelayNotice>,
+    read_only: ReadOnlyMode,
+    rate_limits: RateLimits,
+    geo_filter: Option<Arc<GeoFilter>>,
+    identities: Option<ClientIdentities>,
+    membership: Membership,
+    quarantine: PubkeyQuarantine,
+    rejections: RejectionLog,
+    summary: Option<RelaySummary>,
+    languages: Option<DetectedLanguages>,
+}
+
+impl ListenerState {
+    fn handle(
+        self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+    ) -> impl Future<Output = Result<Response<Body>, Infallible>> {
+        let gzip = self.settings.network.http_compression && wants_gzip(&request);
+        let metrics = self.metrics.clone();
+        let response = handle_web_request(
+            request,
+            self.repo,
+            self.settings,
+            remote_addr,
+            self.broadcast,
+            self.event_tx,
+            self.payment_tx,
+            self.shutdown.subscribe(),
+            self.favicon,
+            self.icon,
+            self.registry,
+            self.metrics,
+            self.read_policy,
+            self.listener,
+            self.relay_keys,
+            self.notices,
+            self.read_only,
+            self.rate_limits,
+            self.geo_filter,
+            self.identities,
+            self.membership,
+            self.quarantine,
+            self.rejections,
+            self.summary,
+            self.languages,
+        );
+        async move {
+            let response = response.await?;
+            Ok::<_, Infallible>(if gzip {
+                gzip_response(response, &metrics).await
+            } else {
+                response
+            })
+        }
+    }
+}
+
+/// Is this a request for relay information or an admin route, from a
+/// client that accepts gzip?  Websocket traffic is never compressed.
+fn wants_gzip(request: &Request<Body>) -> bool {
+    let path = request.uri().path();
+    let compressible = match path {
+        "/" => !request.headers().contains_key(header::UPGRADE),
+        "/metrics" => true,
+        p => p.starts_with("/admin/"),
+    };
+    compressible && accepts_gzip(request.headers())
+}
+
 /// Handle arbitrary HTTP requests, including for `WebSocket` upgrades.
 #[allow(clippy::too_many_arguments)]
 async fn handle_web_request(
@@ -70,13 +202,27 @@ async fn handle_web_request(
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
     remote_addr: SocketAddr,
-    broadcast: Sender<Event>,
+    broadcast: Sender<BroadcastEvent>,
     event_tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
     shutdown: Receiver<()>,
     favicon: Option<Vec<u8>>,
+    icon: Option<RelayIcon>,
     registry: Registry,
     metrics: NostrMetrics,
+    read_policy: Arc<dyn ReadPolicy>,
+    listener: Arc<ListenerPolicy>,
+    relay_keys: Option<RelayKeys>,
+    notices: Sender<RelayNotice>,
+    read_only: ReadOnlyMode,
+    rate_limits: RateLimits,
+    geo_filter: Option<Arc<GeoFilter>>,
+    identities: Option<ClientIdentities>,
+    membership: Membership,
+    quarantine: PubkeyQuarantine,
+    rejections: RejectionLog,
+    summary: Option<RelaySummary>,
+    languages: Option<DetectedLanguages>,
 ) -> Result<Response<Body>, Infallible> {
     match (
         request.uri().path(),
@@ -85,6 +231,35 @@ async fn handle_web_request(
         // Request for / as websocket
         ("/", true) => {
             trace!("websocket with upgrade request");
+            // determine the remote IP from headers if the exist
+            let header_ip = settings
+                .network
+                .remote_ip_header
+                .as_ref()
+                .and_then(|x| get_header_string(x, request.headers()))
+                .and_then(|x| forwarded_client_ip(&x).map(str::to_owned));
+            // use the socket addr as a backup
+            let remote_ip = header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
+            // refuse clients from blocked countries, or with too many
+            // connections, before any relay state is set up for them.
+            // They are still upgraded, so the refusal arrives as a
+            // close frame, which (unlike an HTTP status) browsers let
+            // clients see.
+            let geoblocked = geo_filter.map_or(false, |g| !g.allows(&remote_ip));
+            let connection = if geoblocked {
+                info!("refusing connection from ip: {:?} (geoblocked)", remote_ip);
+                metrics.connections_geoblocked.inc();
+                None
+            } else {
+                rate_limits.open_connection(&remote_ip).await
+            };
+            if connection.is_none() && !geoblocked {
+                info!(
+                    "refusing connection from ip: {:?} (too many connections)",
+                    remote_ip
+                );
+                metrics.connections_refused.inc();
+            }
             //assume request is a handshake, so create the handshake response
             let response = match handshake::server::create_response_with_body(&request, || {
                 Body::empty()
@@ -105,29 +280,42 @@ async fn handle_web_request(
                                     ..Default::default()
                                 };
                                 //create a websocket stream from the upgraded object
-                                let ws_stream = WebSocketStream::from_raw_socket(
+                                let mut ws_stream = WebSocketStream::from_raw_socket(
                                     //pass the upgraded object
                                     //as the base layer stream of the Websocket
-                                    upgraded,
+                                    CoalescingStream::new(
+                                        upgraded,
+                                        settings.network.broadcast_flush_ms.is_some(),
+                                    ),
                                     tokio_tungstenite::tungstenite::protocol::Role::Server,
                                     Some(config),
                                 )
                                 .await;
+                                let Some(connection) = connection else {
+                                    let reason = if geoblocked {
+                                        CloseReason::Geoblocked
+                                    } else {
+                                        CloseReason::TooManyConnections
+                                    };
+                                    close_connection(&mut ws_stream, reason).await;
+                                    return;
+                                };
                                 let origin = get_header_string("origin", request.headers());
                                 let user_agent = get_header_string("user-agent", request.headers());
-                                // determine the remote IP from headers if the exist
-                                let header_ip = settings
-                                    .network
-                                    .remote_ip_header
-                                    .as_ref()
-                                    .and_then(|x| get_header_string(x, request.headers()));
-                                // use the socket addr as a backup
-                                let remote_ip =
-                                    header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
+                                let identity = identities
+                                    .map(|i| i.register(&remote_ip, user_agent.as_deref()));
                                 let client_info = ClientInfo {
                                     remote_ip,
                                     user_agent,
+                                    identity,
                                     origin,
+                                    listener,
+                                    read_only,
+                                    rate_limits,
+                                    rejections,
+                                    relay_keys,
+                                    quarantine,
+                                    _connection: connection,
                                 };
                                 // spawn a nostr server with our websocket
                                 tokio::spawn(nostr_server(
@@ -138,7 +326,9 @@ async fn handle_web_request(
                                     broadcast,
                                     event_tx,
                                     shutdown,
+                                    notices.subscribe(),
                                     metrics,
+                                    read_policy,
                                 ));
                             }
                             // todo: trace, don't print...
@@ -173,7 +363,10 @@ async fn handle_web_request(
                     if mt_str.contains("application/nostr+json") {
                         // build a relay info response
                         debug!("Responding to server info request");
-                        let rinfo = RelayInfo::from(settings);
+                        let detected = languages.map(|l| l.get()).unwrap_or_default();
+                        let rinfo = RelayInfo::from(settings)
+                            .with_read_only(read_only.is_active())
+                            .with_languages(&detected);
                         let b = Body::from(serde_json::to_string_pretty(&rinfo).unwrap());
                         return Ok(Response::builder()
                             .status(200)
@@ -200,7 +393,8 @@ async fn handle_web_request(
                 .body(Body::from("Please use a Nostr client to connect."))
                 .unwrap())
         }
-        ("/metrics", false) => {
+        ("/metrics", false) if listener.admin_api => {
+            metrics.read_only.set(i64::from(read_only.is_active()));
             let mut buffer = vec![];
             let encoder = TextEncoder::new();
             let metric_families = registry.gather();
@@ -212,6 +406,72 @@ async fn handle_web_request(
                 .body(Body::from(buffer))
                 .unwrap())
         }
+        (READ_ONLY_PATH, false) if listener.admin_api => {
+            Ok(read_only_request(request, relay_keys.as_ref(), &read_only).await)
+        }
+        (CONNECTIONS_PATH, false) if listener.admin_api => Ok(connections_request(
+            request,
+            relay_keys.as_ref(),
+            &rate_limits,
+            identities.as_ref(),
+        )
+        .await),
+        (DELETE_EVENTS_PATH, false) if listener.admin_api => {
+            Ok(
+                delete_events_request(request, relay_keys.as_ref(), repo.as_ref(), remote_addr)
+                    .await,
+            )
+        }
+        (IMPORT_PATH, false) if listener.admin_api => Ok(import_request(
+            request,
+            relay_keys.as_ref(),
+            &settings,
+            &event_tx,
+            &read_only,
+            remote_addr,
+        )
+        .await),
+        (STATS_PATH, false) if listener.admin_api => {
+            Ok(stats_request(&request, relay_keys.as_ref(), repo.as_ref()).await)
+        }
+        (SOURCES_PATH, false) if listener.admin_api => {
+            Ok(sources_request(&request, relay_keys.as_ref(), repo.as_ref()).await)
+        }
+        (WHITELIST_PATH, false) if listener.admin_api => Ok(whitelist_request(
+            &request,
+            relay_keys.as_ref(),
+            &membership,
+        )),
+        (QUARANTINE_PATH, false) if listener.admin_api => Ok(quarantine_request(
+            request,
+            relay_keys.as_ref(),
+            repo.as_ref(),
+            &quarantine,
+            remote_addr,
+        )
+        .await),
+        (BROADCAST_NOTICE_PATH, false) if listener.admin_api => Ok(broadcast_notice(
+            request,
+            relay_keys.as_ref(),
+            &notices,
+            &settings,
+            remote_addr,
+        )
+        .await),
+        (path, false) if summary.is_some() && path == settings.status_page.path => {
+            if request.method() != Method::GET {
+                return Ok(json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET"));
+            }
+            if settings.status_page.require_auth {
+                if let Err(res) = verify_relay_auth(&request, relay_keys.as_ref()) {
+                    return Ok(res);
+                }
+            }
+            let report = summary.map(|s| s.report(&metrics));
+            Ok(json_response(StatusCode::OK, &json!(report)))
+        }
+        (HEALTH_PATH, false) => Ok(json_response(StatusCode::OK, &startup::health(true))),
+        (READY_PATH, false) => Ok(json_response(StatusCode::OK, &json!({ "ready": true }))),
         ("/favicon.ico", false) => {
             if let Some(favicon_bytes) = favicon {
                 info!("returning favicon");
@@ -229,6 +489,13 @@ async fn handle_web_request(
                     .unwrap())
             }
         }
+        (ICON_PATH, false) => Ok(match icon {
+            Some(icon) => icon.response(request.headers()),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(""))
+                .unwrap(),
+        }),
         // LN bits callback endpoint for paid invoices
         ("/lnbits", false) => {
             let callback: payment::lnbits::LNBitsCallback =
@@ -248,6 +515,12 @@ async fn handle_web_request(
                 .body(Body::from("ok"))
                 .unwrap())
         }
+        // LNURL-pay admission, for wallets
+        (path, false)
+            if path.starts_with(lnurl::LNURLP_PATH) || path.starts_with(lnurl::CALLBACK_PATH) =>
+        {
+            Ok(lnurl_request(&request, &settings, &repo, &payment_tx, &read_only).await)
+        }
         // Endpoint for relays terms
         ("/terms", false) => Ok(Response::builder()
             .status(200)
@@ -257,7 +530,7 @@ async fn handle_web_request(
         // Endpoint to allow users to sign up
         ("/join", false) => {
             // Stops sign ups if disabled
-            if !settings.pay_to_relay.sign_ups {
+            if !sign_ups_open(&settings, &read_only) {
                 return Ok(Response::builder()
                     .status(401)
                     .header("Content-Type", "text/plain")
@@ -265,84 +538,114 @@ async fn handle_web_request(
                     .unwrap());
             }
 
-            let html = r#"
-<!doctype HTML>
-<head>
-  <meta charset="UTF-8">
-  <style>
-    body {
-      display: flex;
-      flex-direction: column;
-      align-items: center;
-      text-align: center;
-      font-family: Arial, sans-serif;
-      background-color: #6320a7;
-      color: white;
-    }
-
-    .container {
-      display: flex;
-      justify-content: center;
-      align-items: center;
-      height: 400px;
-    }
-
-    a {
-      color: pink;
-    }
-
-    input[type="text"] {
-        width: 100%;
-        max-width: 500px;
-        box-sizing: border-box;
-        overflow-x: auto;
-        white-space: nowrap;
-    }
-  </style>
-</head>
-<body>
-  <div style="width:75%;">
-    <h1>Enter your pubkey</h1>
-    <form action="/invoice" onsubmit="return checkForm(this);">
-      <input type="text" name="pubkey" id="pubkey-input"><br><br>
-      <input type="checkbox" id="terms" required>
-      <label for="terms">I agree to the <a href="/terms">terms and conditions</a></label><br><br>
-      <button type="submit">Submit</button>
-    </form>
-    <button id="get-public-key-btn">Get Public Key</button>
-  </div>
-  <script>
-    function checkForm(form) {
-      if (!form.terms.checked) {
-        alert("Please agree to the terms and conditions");
-        return false;
-      }
-      return true;
-    }
-
-    const pubkeyInput = document.getElementById('pubkey-input');
-      const getPublicKeyBtn = document.getElementById('get-public-key-btn');
-      getPublicKeyBtn.addEventListener('click', async function() {
-        try {
-          const publicKey = await window.nostr.getPublicKey();
-          pubkeyInput.value = publicKey;
-        } catch (error) {
-          console.error(error);
-        }
-      });
-  </script>
-</body>
-</html>
-            "#;
+            metrics
+                .payment_funnel
+                .with_label_values(&["join_page"])
+                .inc();
+            let html = join_page(&settings.pay_to_relay);
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .body(Body::from(html))
                 .unwrap())
         }
+        // JSON endpoints for clients integrating the join flow
+        ("/join/info" | "/join/invoice", false) if request.method() == Method::OPTIONS => Ok(
+            cors_headers(Response::builder().status(StatusCode::NO_CONTENT))
+                .body(Body::empty())
+                .unwrap(),
+        ),
+        ("/join/info", false) => {
+            if !settings.pay_to_relay.enabled {
+                return Ok(json_error(StatusCode::NOT_FOUND, "this relay is not paid"));
+            }
+            let auth = match join_request_pubkey(&request) {
+                Ok(auth) => auth,
+                Err(resp) => return Ok(resp),
+            };
+            let p = &settings.pay_to_relay;
+            let terms_url = settings
+                .info
+                .relay_url
+                .as_deref()
+                .and_then(|url| relay_http_url(url).ok()?.join("terms").ok())
+                .map_or_else(|| "/terms".to_owned(), |url| url.to_string());
+            let mut info = json!({
+                "sign_ups": sign_ups_open(&settings, &read_only),
+                "admission_fee": p.admission_cost,
+                "cost_per_event": p.cost_per_event,
+                "free_events": p.free_events,
+                "unit": "sats",
+                "terms_url": terms_url,
+            });
+            if let Some((pubkey, authenticated)) = auth {
+                let Ok(key) = Keys::from_pk_str(&pubkey) else {
+                    return Ok(json_error(StatusCode::BAD_REQUEST, "invalid pubkey"));
+                };
+                // unknown accounts have not been admitted
+                let (admitted, balance) =
+                    repo.get_account_balance(&key).await.unwrap_or((false, 0));
+                info["pubkey"] = json!(pubkey);
+                info["admitted"] = json!(admitted);
+                // the balance and ledger are only revealed to the owner of the key
+                if authenticated {
+                    info["balance"] = json!(balance);
+                    let ledger = repo
+                        .get_ledger(&key, Some(ACCOUNT_LEDGER_ENTRIES))
+                        .await
+                        .unwrap_or_default();
+                    info["ledger"] = json!(ledger);
+                }
+            }
+            Ok(json_response(StatusCode::OK, &info))
+        }
+        ("/join/invoice", false) => {
+            if request.method() != Method::POST {
+                return Ok(json_error(StatusCode::METHOD_NOT_ALLOWED, "use POST"));
+            }
+            if !settings.pay_to_relay.enabled || !sign_ups_open(&settings, &read_only) {
+                return Ok(json_error(
+                    StatusCode::FORBIDDEN,
+                    "joining is not allowed at the moment",
+                ));
+            }
+            let pubkey = match join_request_pubkey(&request) {
+                Ok(Some((pubkey, _))) => pubkey,
+                Ok(None) => return Ok(json_error(StatusCode::BAD_REQUEST, "missing pubkey")),
+                Err(resp) => return Ok(resp),
+            };
+            let Ok(key) = Keys::from_pk_str(&pubkey) else {
+                return Ok(json_error(StatusCode::BAD_REQUEST, "invalid pubkey"));
+            };
+            match request_join_invoice(&repo, &payment_tx, &pubkey, &key, None).await {
+                Ok(JoinInvoice::Admitted) => Ok(json_response(
+                    StatusCode::OK,
+                    &json!({ "pubkey": pubkey, "admitted": true }),
+                )),
+                Ok(JoinInvoice::Invoice(invoice_info)) => Ok(json_response(
+                    StatusCode::OK,
+                    &json!({
+                        "pubkey": pubkey,
+                        "admitted": false,
+                        "bolt11": invoice_info.bolt11,
+                        "payment_hash": invoice_info.payment_hash,
+                        "amount": invoice_info.amount,
+                        "unit": "sats",
+                        "expiry": payment::INVOICE_EXPIRY_SECS,
+                    }),
+                )),
+                Err(e) => {
+                    warn!("could not get invoice: {:?}", e);
+                    Ok(json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "could not get invoice",
+                    ))
+                }
+            }
+        }
         // Endpoint to display invoice
         ("/invoice", false) => {
             // Stops sign ups if disabled
-            if !settings.pay_to_relay.sign_ups {
+            if !sign_ups_open(&settings, &read_only) {
                 return Ok(Response::builder()
                     .status(401)
                     .header("Content-Type", "text/plain")
@@ -351,7 +654,7 @@ async fn handle_web_request(
             }
 
             // Get query pubkey from query string
-            let pubkey = get_pubkey(request);
+            let pubkey = get_pubkey(&request);
 
             // Redirect back to join page if no pub key is found in query string
             if pubkey.is_none() {
@@ -364,72 +667,31 @@ async fn handle_web_request(
 
             // Checks key is valid
             let pubkey = pubkey.unwrap();
-            let key = Keys::from_pk_str(&pubkey);
-            if key.is_err() {
+            let Ok(key) = Keys::from_pk_str(&pubkey) else {
                 return Ok(Response::builder()
                     .status(401)
                     .header("Content-Type", "text/plain")
                     .body(Body::from("Looks like your key is invalid"))
                     .unwrap());
-            }
-
-            // Checks if user is already admitted
-            let payment_message;
-            if let Ok((admission_status, _)) = repo.get_account_balance(&key.unwrap()).await {
-                if admission_status {
-                    return Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .body(Body::from("Already admitted"))
-                        .unwrap());
-                } else {
-                    payment_message = PaymentMessage::CheckAccount(pubkey.clone());
-                }
-            } else {
-                payment_message = PaymentMessage::NewAccount(pubkey.clone());
-            }
-
-            // Send message on payment channel requesting invoice
-            if payment_tx.send(payment_message).is_err() {
-                warn!("Could not send payment tx");
-                return Ok(Response::builder()
-                    .status(501)
-                    .header("Content-Type", "text/plain")
-                    .body(Body::from("Sorry, something went wrong"))
-                    .unwrap());
-            }
+            };
 
-            // wait for message with invoice back that matched the pub key
-            let mut invoice_info: Option<InvoiceInfo> = None;
-            while let Ok(msg) = payment_tx.subscribe().recv().await {
-                match msg {
-                    PaymentMessage::Invoice(m_pubkey, m_invoice_info) => {
-                        if m_pubkey == pubkey.clone() {
-                            invoice_info = Some(m_invoice_info);
-                            break;
-                        }
+            let invoice_info =
+                match request_join_invoice(&repo, &payment_tx, &pubkey, &key, None).await {
+                    Ok(JoinInvoice::Admitted) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::OK)
+                            .body(Body::from("Already admitted"))
+                            .unwrap());
                     }
-                    PaymentMessage::AccountAdmitted(m_pubkey) => {
-                        if m_pubkey == pubkey.clone() {
-                            return Ok(Response::builder()
-                                .status(StatusCode::OK)
-                                .body(Body::from("Already admitted"))
-                                .unwrap());
-                        }
+                    Ok(JoinInvoice::Invoice(invoice_info)) => invoice_info,
+                    Err(e) => {
+                        warn!("could not get invoice: {:?}", e);
+                        return Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Body::from("Sorry, could not get invoice"))
+                            .unwrap());
                     }
-                    _ => (),
-                }
-            }
-
-            // Return early if cant get invoice
-            if invoice_info.is_none() {
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("Sorry, could not get invoice"))
-                    .unwrap());
-            }
-
-            // Since invoice is checked to be not none, unwrap
-            let invoice_info = invoice_info.unwrap();
+                };
 
             let qr_code: String;
             if let Ok(code) = QrCode::new(invoice_info.bolt11.as_bytes()) {
@@ -546,7 +808,7 @@ async fn handle_web_request(
             }
 
             // Gets the pubkey from query string
-            let pubkey = get_pubkey(request);
+            let pubkey = get_pubkey(&request);
 
             // Redirect back to join page if no pub key is found in query string
             if pubkey.is_none() {
@@ -627,8 +889,842 @@ async fn handle_web_request(
     }
 }
 
+/// Join page template; `{logo}`, `{title}`, and `{custom_html}` are
+/// filled in from the pay-to-relay settings.
+const JOIN_PAGE_TEMPLATE: &str = r#"
+<!doctype HTML>
+<head>
+  <meta charset="UTF-8">
+  <style>
+    body {
+      display: flex;
+      flex-direction: column;
+      align-items: center;
+      text-align: center;
+      font-family: Arial, sans-serif;
+      background-color: #6320a7;
+      color: white;
+    }
+
+    .container {
+      display: flex;
+      justify-content: center;
+      align-items: center;
+      height: 400px;
+    }
+
+    a {
+      color: pink;
+    }
+
+    input[type="text"] {
+        width: 100%;
+        max-width: 500px;
+        box-sizing: border-box;
+        overflow-x: auto;
+        white-space: nowrap;
+    }
+  </style>
+</head>
+<body>
+  <div style="width:75%;">
+    {logo}
+    <h1>{title}</h1>
+    {custom_html}
+    <form action="/invoice" onsubmit="return checkForm(this);">
+      <input type="text" name="pubkey" id="pubkey-input"><br><br>
+      <input type="checkbox" id="terms" required>
+      <label for="terms">I agree to the <a href="/terms">terms and conditions</a></label><br><br>
+      <button type="submit">Submit</button>
+    </form>
+    <button id="get-public-key-btn">Get Public Key</button>
+  </div>
+  <script>
+    function checkForm(form) {
+      if (!form.terms.checked) {
+        alert("Please agree to the terms and conditions");
+        return false;
+      }
+      return true;
+    }
+
+    const pubkeyInput = document.getElementById('pubkey-input');
+      const getPublicKeyBtn = document.getElementById('get-public-key-btn');
+      getPublicKeyBtn.addEventListener('click', async function() {
+        try {
+          const publicKey = await window.nostr.getPublicKey();
+          pubkeyInput.value = publicKey;
+        } catch (error) {
+          console.error(error);
+        }
+      });
+  </script>
+</body>
+</html>
+"#;
+
+/// Render the join page, applying any operator customizations.
+fn join_page(p: &PayToRelay) -> String {
+    let title = p
+        .join_page_title
+        .as_deref()
+        .map_or_else(|| "Enter your pubkey".to_owned(), html_escape);
+    let logo = p.join_page_logo.as_deref().map_or_else(String::new, |url| {
+        format!(
+            r#"<img src="{}" alt="logo" style="max-width:200px;">"#,
+            html_escape(url)
+        )
+    });
+    // custom HTML is trusted operator content, and is not escaped.
+    let custom_html = p.join_page_html.clone().unwrap_or_default();
+    JOIN_PAGE_TEMPLATE
+        .replace("{logo}", &logo)
+        .replace("{title}", &title)
+        .replace("{custom_html}", &custom_html)
+}
+
+/// Number of ledger entries included in the account status
+const ACCOUNT_LEDGER_ENTRIES: u64 = 5;
+
+/// Result of requesting an admission invoice for a pubkey
+enum JoinInvoice {
+    /// The pubkey has already been admitted
+    Admitted,
+    /// Unpaid admission invoice (reused if one is pending)
+    Invoice(InvoiceInfo),
+}
+
+/// Ask the payment handler for an admission invoice, waiting for the
+/// reply that matches the pubkey.  With LNURL-pay `metadata`, a new
+/// invoice committing to it is always created.
+async fn request_join_invoice(
+    repo: &Arc<dyn NostrRepo>,
+    payment_tx: &broadcast::Sender<PaymentMessage>,
+    pubkey: &str,
+    key: &Keys,
+    metadata: Option<&str>,
+) -> Result<JoinInvoice> {
+    let payment_message = match (repo.get_account_balance(key).await, metadata) {
+        (Ok((true, _)), _) => return Ok(JoinInvoice::Admitted),
+        (_, Some(metadata)) => PaymentMessage::LnurlInvoice(pubkey.to_owned(), metadata.to_owned()),
+        (Ok((false, _)), None) => PaymentMessage::CheckAccount(pubkey.to_owned()),
+        (Err(_), None) => PaymentMessage::NewAccount(pubkey.to_owned()),
+    };
+    // subscribe before sending so the reply cannot be missed
+    let mut payment_rx = payment_tx.subscribe();
+    payment_tx
+        .send(payment_message)
+        .map_err(|_| Error::ChannelClosed)?;
+    let wait_for_reply = async {
+        while let Ok(msg) = payment_rx.recv().await {
+            match msg {
+                PaymentMessage::Invoice(m_pubkey, invoice_info) if m_pubkey == pubkey => {
+                    return Ok(JoinInvoice::Invoice(invoice_info));
+                }
+                PaymentMessage::AccountAdmitted(m_pubkey) if m_pubkey == pubkey => {
+                    return Ok(JoinInvoice::Admitted);
+                }
+                _ => (),
+            }
+        }
+        Err(Error::ChannelClosed)
+    };
+    tokio::time::timeout(Duration::from_secs(30), wait_for_reply)
+        .await
+        .map_err(|_| Error::CustomError("timed out waiting for invoice".to_owned()))?
+}
+
+/// LNURL-pay admission: the pay request for a pubkey, or (at the
+/// callback) an invoice for the admission fee.
+async fn lnurl_request(
+    request: &Request<Body>,
+    settings: &Settings,
+    repo: &Arc<dyn NostrRepo>,
+    payment_tx: &broadcast::Sender<PaymentMessage>,
+    read_only: &ReadOnlyMode,
+) -> Response<Body> {
+    let lnurl_error =
+        |status: StatusCode, reason: &str| json_response(status, &lnurl::error(reason));
+    if !settings.pay_to_relay.enabled || !sign_ups_open(settings, read_only) {
+        return lnurl_error(
+            StatusCode::FORBIDDEN,
+            "joining is not allowed at the moment",
+        );
+    }
+    let path = request.uri().path();
+    let (pubkey, callback) = match lnurl::path_pubkey(path, lnurl::LNURLP_PATH) {
+        Some(pubkey) => (pubkey, false),
+        None => match lnurl::path_pubkey(path, lnurl::CALLBACK_PATH) {
+            Some(pubkey) => (pubkey, true),
+            None => return lnurl_error(StatusCode::NOT_FOUND, "no pubkey given"),
+        },
+    };
+    let Ok(key) = Keys::from_pk_str(pubkey) else {
+        return lnurl_error(StatusCode::BAD_REQUEST, "invalid pubkey");
+    };
+    let pubkey = key.public_key().to_string();
+    let Some(relay_url) = settings.info.relay_url.as_deref() else {
+        return lnurl_error(StatusCode::NOT_FOUND, "relay_url is not configured");
+    };
+    let amount = settings.pay_to_relay.admission_cost;
+    let metadata = lnurl::metadata(settings.info.name.as_deref(), &pubkey);
+    if !callback {
+        return match lnurl::callback_url(relay_url, &pubkey) {
+            Ok(url) => json_response(StatusCode::OK, &lnurl::pay_request(&url, amount, &metadata)),
+            Err(e) => {
+                warn!("could not build LNURL callback: {:?}", e);
+                lnurl_error(StatusCode::INTERNAL_SERVER_ERROR, "invalid relay_url")
+            }
+        };
+    }
+    let requested = request
+        .uri()
+        .query()
+        .and_then(|q| url::form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == "amount"))
+        .and_then(|(_, a)| a.parse::<u64>().ok());
+    if requested != Some(amount * 1000) {
+        return lnurl_error(StatusCode::BAD_REQUEST, "amount must be the admission fee");
+    }
+    match request_join_invoice(repo, payment_tx, &pubkey, &key, Some(&metadata)).await {
+        Ok(JoinInvoice::Admitted) => lnurl_error(StatusCode::OK, "already admitted"),
+        Ok(JoinInvoice::Invoice(invoice_info)) => json_response(
+            StatusCode::OK,
+            &lnurl::invoice_response(&invoice_info.bolt11),
+        ),
+        Err(e) => {
+            warn!("could not get LNURL invoice: {:?}", e);
+            lnurl_error(StatusCode::INTERNAL_SERVER_ERROR, "could not get invoice")
+        }
+    }
+}
+
+/// Determine the pubkey for a JSON join request.  A NIP-98
+/// `Authorization` header takes precedence over the `pubkey` query
+/// parameter, and the two must agree if both are given.  Returns the
+/// pubkey and whether it was authenticated.
+fn join_request_pubkey(request: &Request<Body>) -> Result<Option<(String, bool)>, Response<Body>> {
+    let query_pubkey = get_pubkey(request);
+    let Some(header) = get_header_string("authorization", request.headers()) else {
+        return Ok(query_pubkey.map(|p| (p, false)));
+    };
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map_or(request.uri().path(), |pq| pq.as_str());
+    let pubkey = nip98::verify_auth_header(&header, request.method().as_str(), path_and_query)
+        .map_err(|_| json_error(StatusCode::UNAUTHORIZED, "invalid NIP-98 authorization"))?;
+    if query_pubkey.map_or(false, |q| q != pubkey) {
+        return Err(json_error(
+            StatusCode::FORBIDDEN,
+            "pubkey does not match authorization",
+        ));
+    }
+    Ok(Some((pubkey, true)))
+}
+
+/// Check that an admin request has NIP-98 authorization from the
+/// relay key, returning the relay keys, or an error response.
+fn verify_relay_auth<'a>(
+    request: &Request<Body>,
+    relay_keys: Option<&'a RelayKeys>,
+) -> Result<&'a RelayKeys, Response<Body>> {
+    let Some(relay_keys) = relay_keys else {
+        return Err(json_error(
+            StatusCode::NOT_FOUND,
+            "relay keys are not configured",
+        ));
+    };
+    let Some(header) = get_header_string("authorization", request.headers()) else {
+        return Err(json_error(
+            StatusCode::UNAUTHORIZED,
+            "NIP-98 authorization required",
+        ));
+    };
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    match nip98::verify_auth_header(&header, request.method().as_str(), path) {
+        Ok(pubkey) if pubkey == relay_keys.public_key_hex() => Ok(relay_keys),
+        Ok(_) => Err(json_error(
+            StatusCode::FORBIDDEN,
+            "not authorized as the relay",
+        )),
+        Err(_) => Err(json_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid NIP-98 authorization",
+        )),
+    }
+}
+
+/// Are new accounts accepted?  Sign ups are paused while read-only,
+/// since new members could not publish anyway.
+fn sign_ups_open(settings: &Settings, read_only: &ReadOnlyMode) -> bool {
+    settings.pay_to_relay.sign_ups && !read_only.is_active()
+}
+
+/// Report whether the relay is read-only (GET), or turn read-only
+/// mode on or off (POST, with a body of `{"read_only": <bool>}` and
+/// NIP-98 authorization from the relay key).
+async fn read_only_request(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    read_only: &ReadOnlyMode,
+) -> Response<Body> {
+    if request.method() == Method::GET {
+        return json_response(
+            StatusCode::OK,
+            &json!({ "read_only": read_only.is_active() }),
+        );
+    }
+    if request.method() != Method::POST {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET or POST");
+    }
+    if let Err(res) = verify_relay_auth(&request, relay_keys) {
+        return res;
+    }
+    let active = match to_bytes(request.into_body()).await.map(|body| {
+        serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v.get("read_only").and_then(serde_json::Value::as_bool))
+    }) {
+        Ok(Some(active)) => active,
+        _ => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "expected a body of {\"read_only\": true|false}",
+            )
+        }
+    };
+    if read_only.set(active) != active {
+        warn!(
+            "read-only mode {}",
+            if active { "enabled" } else { "disabled" }
+        );
+    }
+    json_response(StatusCode::OK, &json!({ "read_only": active }))
+}
+
+/// Addresses with the most open connections (GET, with NIP-98
+/// authorization from the relay key).  `?limit=<n>` sets how many are
+/// listed (default 20).  Unless `logging.client_identity` is off, the
+/// User-Agent and `client` tag of each connection to this relay are
+/// listed with its address.
+async fn connections_request(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    rate_limits: &RateLimits,
+    identities: Option<&ClientIdentities>,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET");
+    }
+    if let Err(res) = verify_relay_auth(&request, relay_keys) {
+        return res;
+    }
+    let limit = request
+        .uri()
+        .query()
+        .and_then(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .find(|(k, _)| k == "limit")
+                .and_then(|(_, v)| v.parse::<usize>().ok())
+        })
+        .unwrap_or(20);
+    match rate_limits.connection_counts(limit).await {
+        Ok(counts) => {
+            let connections: Vec<_> = counts
+                .into_iter()
+                .map(|(ip, count)| {
+                    let mut entry = json!({ "ip": &ip, "connections": count });
+                    if let Some(identities) = identities {
+                        let clients: Vec<_> = identities
+                            .from_ip(&ip)
+                            .into_iter()
+                            .map(|c| json!({ "user_agent": c.user_agent, "client": c.client }))
+                            .collect();
+                        entry["clients"] = json!(clients);
+                    }
+                    entry
+                })
+                .collect();
+            json_response(StatusCode::OK, &json!({ "connections": connections }))
+        }
+        Err(e) => json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &format!("connection counts are unavailable: {e}"),
+        ),
+    }
+}
+
+/// Report where the pubkeys allowed to publish come from, and how many
+/// there are (GET, with NIP-98 authorization from the relay key).
+fn whitelist_request(
+    request: &Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    membership: &Membership,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET");
+    }
+    if let Err(res) = verify_relay_auth(request, relay_keys) {
+        return res;
+    }
+    json_response(StatusCode::OK, &json!(membership.status()))
+}
+
+/// List the quarantined and banned pubkeys (GET), or release or ban a
+/// pubkey (POST, with a body of `{"pubkey": <hex>, "action":
+/// "release"|"ban"}`).  Both need NIP-98 authorization from the relay
+/// key.
+async fn quarantine_request(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    repo: &dyn NostrRepo,
+    quarantine: &PubkeyQuarantine,
+    remote_addr: SocketAddr,
+) -> Response<Body> {
+    let listing = request.method() == Method::GET;
+    if !listing && request.method() != Method::POST {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET or POST");
+    }
+    let relay_keys = match verify_relay_auth(&request, relay_keys) {
+        Ok(relay_keys) => relay_keys,
+        Err(res) => return res,
+    };
+    if !quarantine.is_enabled() {
+        return json_error(StatusCode::NOT_FOUND, "quarantine is not enabled");
+    }
+    if listing {
+        return match repo.withheld_pubkeys().await {
+            Ok(pubkeys) => json_response(StatusCode::OK, &json!({ "pubkeys": pubkeys })),
+            Err(e) => {
+                warn!("could not list quarantined pubkeys: {:?}", e);
+                json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not list quarantined pubkeys",
+                )
+            }
+        };
+    }
+    let body = to_bytes(request.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok());
+    let field = |name: &str| {
+        body.as_ref()
+            .and_then(|b| b.get(name))
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_owned)
+    };
+    let status = match field("action").as_deref() {
+        Some("release") => PubkeyStatus::Released,
+        Some("ban") => PubkeyStatus::Banned,
+        _ => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "expected a body of {\"pubkey\": <hex>, \"action\": \"release\"|\"ban\"}",
+            )
+        }
+    };
+    let pubkey = match field("pubkey") {
+        Some(p) if p.len() == 64 && is_lower_hex(&p) => p,
+        _ => return json_error(StatusCode::BAD_REQUEST, "pubkey must be 64 lowercase hex"),
+    };
+    let admin = relay_keys.public_key_hex();
+    match quarantine.set_status(repo, &pubkey, status).await {
+        Ok(()) => {
+            warn!(
+                "admin {} (from {}) set pubkey {} to {}",
+                admin, remote_addr, pubkey, status
+            );
+            json_response(
+                StatusCode::OK,
+                &json!({ "pubkey": pubkey, "status": status }),
+            )
+        }
+        Err(e) => {
+            warn!("could not set pubkey {} to {}: {:?}", pubkey, status, e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not update quarantine",
+            )
+        }
+    }
+}
+
+/// The authors that wrote the most events on a day (GET, with NIP-98
+/// authorization from the relay key).  `?day=<YYYY-MM-DD>` (default
+/// today, UTC), `?kind=<n>` (default all kinds) and `?limit=<n>`
+/// (default 20) select the authors listed.  Counts reach the database
+/// once a minute.
+async fn stats_request(
+    request: &Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    repo: &dyn NostrRepo,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET");
+    }
+    if let Err(res) = verify_relay_auth(request, relay_keys) {
+        return res;
+    }
+    let params: HashMap<String, String> = request
+        .uri()
+        .query()
+        .map(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+    let day = match params.get("day").map(|d| stats::parse_day(d)) {
+        Some(Ok(day)) => day,
+        Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+        None => stats::today(),
+    };
+    let kind = match params.get("kind").map(|k| k.parse::<u64>()) {
+        Some(Ok(kind)) => Some(kind),
+        Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST, "kind must be a number"),
+        None => None,
+    };
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<u64>().ok())
+        .unwrap_or(20);
+    match repo.top_writers(day, kind, limit).await {
+        Ok(writers) => json_response(
+            StatusCode::OK,
+            &json!({ "day": day.to_string(), "kind": kind, "writers": writers }),
+        ),
+        Err(e) => {
+            warn!("could not read write statistics: {:?}", e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not read write statistics",
+            )
+        }
+    }
+}
+
+/// How many stored events came from each source (GET, with NIP-98
+/// authorization from the relay key): `local` for events published
+/// here, `mirror:<url>` or `ingest:<url>` for copied events.
+async fn sources_request(
+    request: &Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    repo: &dyn NostrRepo,
+) -> Response<Body> {
+    if request.method() != Method::GET {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use GET");
+    }
+    if let Err(res) = verify_relay_auth(request, relay_keys) {
+        return res;
+    }
+    match repo.count_by_source().await {
+        Ok(sources) => json_response(StatusCode::OK, &json!({ "sources": sources })),
+        Err(e) => {
+            warn!("could not count events by source: {:?}", e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not count events by source",
+            )
+        }
+    }
+}
+
+/// Does a filter narrow down which events it matches?  An empty filter
+/// would match every stored event.
+fn has_conditions(filter: &ReqFilter) -> bool {
+    filter.ids.is_some()
+        || filter.authors.is_some()
+        || filter.kinds.is_some()
+        || filter.tags.is_some()
+        || filter.since.is_some()
+        || filter.until.is_some()
+}
+
+/// Delete every stored event matching a filter (POST, with NIP-98
+/// authorization from the relay key, and a body of `{"filter": {...},
+/// "dry_run": <bool>}`).  Unless `dry_run` is explicitly false, the
+/// matching events are only counted.
+async fn delete_events_request(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    repo: &dyn NostrRepo,
+    remote_addr: SocketAddr,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    let relay_keys = match verify_relay_auth(&request, relay_keys) {
+        Ok(relay_keys) => relay_keys,
+        Err(res) => return res,
+    };
+    let body = to_bytes(request.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok());
+    let Some(filter_json) = body.as_ref().and_then(|b| b.get("filter")) else {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "expected a body of {\"filter\": {...}, \"dry_run\": true|false}",
+        );
+    };
+    let dry_run = body
+        .as_ref()
+        .and_then(|b| b.get("dry_run"))
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(true);
+    let filter = match serde_json::from_value::<ReqFilter>(filter_json.clone()) {
+        Ok(filter) => filter,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &format!("invalid filter: {e}")),
+    };
+    if !has_conditions(&filter) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "filter must have ids, authors, kinds, tags, since or until",
+        );
+    }
+    if filter.limit.is_some() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "limit is not supported; every matching event is deleted",
+        );
+    }
+    let admin = relay_keys.public_key_hex();
+    match repo.delete_matching(&filter, dry_run).await {
+        Ok(count) => {
+            if dry_run {
+                info!(
+                    "admin {} (from {}) checked deletion of {} events matching {}",
+                    admin, remote_addr, count, filter_json
+                );
+            } else {
+                warn!(
+                    "admin {} (from {}) deleted {} events matching {}",
+                    admin, remote_addr, count, filter_json
+                );
+            }
+            json_response(
+                StatusCode::OK,
+                &json!({ "dry_run": dry_run, "count": count }),
+            )
+        }
+        Err(e) => {
+            warn!(
+                "admin {} (from {}) could not delete events matching {}: {:?}",
+                admin, remote_addr, filter_json, e
+            );
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "could not delete events")
+        }
+    }
+}
+
+/// Import events in bulk (POST, with NIP-98 authorization from the
+/// relay key, and a body of gzip-compressed NDJSON), when
+/// `import.enabled`.  Answers with the result for each line; see
+/// [`import::import_events`].
+async fn import_request(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    settings: &Settings,
+    event_tx: &tokio::sync::mpsc::Sender<SubmittedEvent>,
+    read_only: &ReadOnlyMode,
+    remote_addr: SocketAddr,
+) -> Response<Body> {
+    if !settings.import.enabled {
+        return json_error(StatusCode::NOT_FOUND, "importing events is not enabled");
+    }
+    if request.method() != Method::POST {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    let relay_keys = match verify_relay_auth(&request, relay_keys) {
+        Ok(relay_keys) => relay_keys,
+        Err(res) => return res,
+    };
+    if read_only.is_active() {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, read_only.message());
+    }
+    // larger uploads are also cut short as they arrive
+    let max_upload = settings.import.max_upload_mb * 1024 * 1024;
+    let declared = get_header_string("content-length", request.headers())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.map_or(false, |len| len > max_upload) {
+        return json_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("upload is larger than {} MB", settings.import.max_upload_mb),
+        );
+    }
+    let source_ip = remote_addr.ip().to_string();
+    let admin = relay_keys.public_key_hex();
+    match import::import_events(request.into_body(), settings, event_tx, &source_ip).await {
+        Ok(summary) => {
+            info!(
+                "admin {} (from {}) imported events: {}",
+                admin, remote_addr, summary
+            );
+            let status = match summary.stopped {
+                None => StatusCode::OK,
+                Some(ImportStop::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+                Some(ImportStop::Unreadable(_)) => StatusCode::BAD_REQUEST,
+            };
+            json_response(status, &json!(summary))
+        }
+        Err(e) => {
+            warn!(
+                "admin {} (from {}) could not import events: {:?}",
+                admin, remote_addr, e
+            );
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "could not import events")
+        }
+    }
+}
+
+/// Longest operator notice accepted, in bytes
+const MAX_NOTICE_BYTES: usize = 4096;
+
+/// Send an operator notice to connected clients, signed with the relay
+/// keys unless asked otherwise.  The request must be a POST with NIP-98
+/// authorization from the relay key, and a [`BroadcastRequest`] (or
+/// just the notice text) as its body.  Delivery is spread over time,
+/// at `broadcast_notices_per_sec`.
+async fn broadcast_notice(
+    request: Request<Body>,
+    relay_keys: Option<&RelayKeys>,
+    notices: &Sender<RelayNotice>,
+    settings: &Settings,
+    remote_addr: SocketAddr,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    let relay_keys = match verify_relay_auth(&request, relay_keys) {
+        Ok(relay_keys) => relay_keys,
+        Err(res) => return res,
+    };
+    let body = match to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return json_error(StatusCode::BAD_REQUEST, "could not read notice"),
+    };
+    let Ok(body) = std::str::from_utf8(&body) else {
+        return json_error(StatusCode::BAD_REQUEST, "notice must be UTF-8");
+    };
+    // a plain text body is a signed notice for everyone, but a JSON
+    // object that is not a valid request must not be broadcast as text
+    let broadcast = match serde_json::from_str::<BroadcastRequest>(body) {
+        Ok(broadcast) => broadcast,
+        Err(e) if body.trim_start().starts_with('{') => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid broadcast request: {e}"),
+            );
+        }
+        Err(_) => BroadcastRequest {
+            message: body.to_owned(),
+            signed: true,
+            target: None,
+            pubkeys: vec![],
+        },
+    };
+    let message = broadcast.message.trim();
+    if message.is_empty() || message.len() > MAX_NOTICE_BYTES {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "notice must be non-empty, up to 4096 bytes",
+        );
+    }
+    let target = match broadcast.target.as_deref().unwrap_or("all") {
+        "all" if !broadcast.pubkeys.is_empty() => {
+            let mut pubkeys = HashSet::new();
+            for pk in &broadcast.pubkeys {
+                match Keys::from_pk_str(pk) {
+                    Ok(keys) => pubkeys.insert(keys.public_key().to_string()),
+                    Err(_) => return json_error(StatusCode::BAD_REQUEST, "invalid pubkey"),
+                };
+            }
+            NoticeTarget::Pubkeys(pubkeys)
+        }
+        _ if !broadcast.pubkeys.is_empty() => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "pubkeys cannot be combined with a target",
+            )
+        }
+        name => match NoticeTarget::from_name(name) {
+            Some(target) => target,
+            None => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "target must be all, admitted or unadmitted",
+                )
+            }
+        },
+    };
+    let mut notice = match relay_keys.sign_notice(message) {
+        Ok(notice) => notice,
+        Err(e) => {
+            warn!("could not sign operator notice: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "could not sign notice");
+        }
+    };
+    let id = notice.event.as_ref().map(|e| e.id.clone());
+    if !broadcast.signed {
+        notice.event = None;
+    }
+    notice.target = target;
+    // every client may be sent the notice, so spread it over as long
+    // as sending to all of them would take
+    let connected = notices.receiver_count();
+    notice.spread = Duration::from_secs_f64(
+        connected as f64 / f64::from(settings.limits.broadcast_notices_per_sec),
+    );
+    let target = notice.target.name();
+    let spread_secs = notice.spread.as_secs_f64();
+    // sending fails only if there are no connected clients
+    let clients = notices.send(notice).unwrap_or(0);
+    info!(
+        "admin {} (from {}) sent notice {:?} to {} clients ({}) over {:.1}s: {:?}",
+        relay_keys.public_key_hex(),
+        remote_addr,
+        id,
+        clients,
+        target,
+        spread_secs,
+        message
+    );
+    json_response(
+        StatusCode::OK,
+        &json!({
+            "id": id,
+            "clients": clients,
+            "target": target,
+            "spread_secs": spread_secs,
+        }),
+    )
+}
+
+/// Add CORS headers so browser clients can call the JSON endpoints
+fn cors_headers(builder: http::response::Builder) -> http::response::Builder {
+    builder
+        .header("Access-Control-Allow-Origin", "*")
+        .header(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        )
+        .header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
+}
+
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    cors_headers(Response::builder().status(status))
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": message }))
+}
+
 // Get pubkey from request query string
-fn get_pubkey(request: Request<Body>) -> Option<String> {
+fn get_pubkey(request: &Request<Body>) -> Option<String> {
     let query = request.uri().query().unwrap_or("").to_string();
 
     // Gets the pubkey value from query string
@@ -672,7 +1768,7 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+pub(crate) fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -698,28 +1794,177 @@ fn create_metrics() -> (Registry, NostrMetrics) {
     .unwrap();
     let connections =
         IntCounter::with_opts(Opts::new("nostr_connections_total", "New connections")).unwrap();
+    let clients =
+        IntGauge::with_opts(Opts::new("nostr_clients", "Connected websocket clients")).unwrap();
+    let subscriptions = IntGauge::with_opts(Opts::new(
+        "nostr_subscriptions",
+        "Open subscriptions of connected clients",
+    ))
+    .unwrap();
     let db_connections = IntGauge::with_opts(Opts::new(
         "nostr_db_connections",
         "Active database connections",
     ))
     .unwrap();
-    let query_aborts = IntCounterVec::new(
-        Opts::new("nostr_query_abort_total", "Aborted queries"),
-        vec!["reason"].as_slice(),
+    let query_aborts = IntCounterVec::new(
+        Opts::new("nostr_query_abort_total", "Aborted queries"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let cmd_req = IntCounter::with_opts(Opts::new("nostr_cmd_req_total", "REQ commands")).unwrap();
+    let cmd_event =
+        IntCounter::with_opts(Opts::new("nostr_cmd_event_total", "EVENT commands")).unwrap();
+    let cmd_close =
+        IntCounter::with_opts(Opts::new("nostr_cmd_close_total", "CLOSE commands")).unwrap();
+    let cmd_auth =
+        IntCounter::with_opts(Opts::new("nostr_cmd_auth_total", "AUTH commands")).unwrap();
+    let disconnects = IntCounterVec::new(
+        Opts::new("nostr_disconnects_total", "Client disconnects"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let broadcast_lag = Histogram::with_opts(
+        HistogramOpts::new(
+            "nostr_broadcast_delivery_seconds",
+            "Delay from broadcast to handing an event to a client",
+        )
+        .buckets(vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0]),
+    )
+    .unwrap();
+    let broadcast_dropped = IntCounterVec::new(
+        Opts::new(
+            "nostr_broadcast_dropped_total",
+            "Broadcast events not delivered to clients",
+        ),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let shadow_rejections = IntCounterVec::new(
+        Opts::new(
+            "nostr_shadow_rejections_total",
+            "Events accepted in shadow mode that a rule would have rejected",
+        ),
+        vec!["rule"].as_slice(),
+    )
+    .unwrap();
+    let db_write_retries = IntCounterVec::new(
+        Opts::new(
+            "nostr_db_write_retries_total",
+            "Database writes retried after transient errors",
+        ),
+        vec!["outcome"].as_slice(),
+    )
+    .unwrap();
+    let query_plan_seq_scans = IntCounterVec::new(
+        Opts::new(
+            "nostr_db_query_plan_seq_scans_total",
+            "Sampled slow queries whose plan sequentially scans a table",
+        ),
+        vec!["table"].as_slice(),
+    )
+    .unwrap();
+    let tag_index_pending = IntGauge::with_opts(Opts::new(
+        "nostr_tag_index_pending",
+        "Stored events waiting for their tags to be indexed",
+    ))
+    .unwrap();
+    let tag_index_lag = Histogram::with_opts(
+        HistogramOpts::new(
+            "nostr_tag_index_lag_seconds",
+            "Time from storing an event to indexing its tags",
+        )
+        .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0]),
+    )
+    .unwrap();
+    let read_only = IntGauge::with_opts(Opts::new(
+        "nostr_read_only",
+        "1 while the relay is read-only, rejecting all events",
+    ))
+    .unwrap();
+    let stored_events = IntGauge::with_opts(Opts::new(
+        "nostr_db_stored_events",
+        "Events stored, as of the last max_stored_events check",
+    ))
+    .unwrap();
+    let events_evicted = IntCounter::with_opts(Opts::new(
+        "nostr_db_events_evicted_total",
+        "Oldest events deleted to stay under max_stored_events",
+    ))
+    .unwrap();
+    let pending_events = IntCounterVec::new(
+        Opts::new(
+            "nostr_pending_events_total",
+            "Events admitted asynchronously, by outcome",
+        ),
+        vec!["outcome"].as_slice(),
+    )
+    .unwrap();
+    let connections_geoblocked = IntCounter::with_opts(Opts::new(
+        "nostr_connections_geoblocked_total",
+        "Websocket connections refused by geoip country filtering",
+    ))
+    .unwrap();
+    let http_compression_bytes = IntCounterVec::new(
+        Opts::new(
+            "nostr_http_compression_bytes_total",
+            "HTTP response bytes compressed with gzip, before and after",
+        ),
+        vec!["stage"].as_slice(),
+    )
+    .unwrap();
+    let rejection_log_dropped = IntCounter::with_opts(Opts::new(
+        "nostr_rejection_log_dropped_total",
+        "Rejected events left out of the rejection log by its bounds",
+    ))
+    .unwrap();
+    let archive_dropped = IntCounter::with_opts(Opts::new(
+        "nostr_archive_dropped_total",
+        "Accepted events left out of the event archive",
+    ))
+    .unwrap();
+    let connections_refused = IntCounter::with_opts(Opts::new(
+        "nostr_connections_refused_total",
+        "Websocket connections refused for exceeding max_connections_per_ip",
+    ))
+    .unwrap();
+    let rate_limit_store_errors = IntCounter::with_opts(Opts::new(
+        "nostr_rate_limit_store_errors_total",
+        "Rate limit store failures (clients are allowed through)",
+    ))
+    .unwrap();
+    let payment_funnel = IntCounterVec::new(
+        Opts::new(
+            "nostr_payment_funnel_total",
+            "Pay-to-relay sign up and payment stages reached",
+        ),
+        vec!["stage"].as_slice(),
     )
     .unwrap();
-    let cmd_req = IntCounter::with_opts(Opts::new("nostr_cmd_req_total", "REQ commands")).unwrap();
-    let cmd_event =
-        IntCounter::with_opts(Opts::new("nostr_cmd_event_total", "EVENT commands")).unwrap();
-    let cmd_close =
-        IntCounter::with_opts(Opts::new("nostr_cmd_close_total", "CLOSE commands")).unwrap();
-    let cmd_auth =
-        IntCounter::with_opts(Opts::new("nostr_cmd_auth_total", "AUTH commands")).unwrap();
-    let disconnects = IntCounterVec::new(
-        Opts::new("nostr_disconnects_total", "Client disconnects"),
-        vec!["reason"].as_slice(),
+    let invoices_unpaid = IntGauge::with_opts(Opts::new(
+        "nostr_payment_invoices_unpaid",
+        "Unpaid invoices that have not expired",
+    ))
+    .unwrap();
+    let sats_collected = IntGauge::with_opts(Opts::new(
+        "nostr_payment_sats_collected",
+        "Total amount of paid invoices, in sats",
+    ))
+    .unwrap();
+    let invoice_paid_delay = Histogram::with_opts(
+        HistogramOpts::new(
+            "nostr_payment_invoice_paid_seconds",
+            "Time from creating an invoice to seeing it paid",
+        )
+        .buckets(vec![
+            10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+        ]),
     )
     .unwrap();
+    let send_queue_depth = IntGauge::with_opts(Opts::new(
+        "nostr_conn_send_queue_depth",
+        "Messages waiting to be sent to clients, as last sampled on each connection",
+    ))
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
@@ -732,12 +1977,66 @@ fn create_metrics() -> (Registry, NostrMetrics) {
     registry.register(Box::new(cmd_close.clone())).unwrap();
     registry.register(Box::new(cmd_auth.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
+    registry.register(Box::new(clients.clone())).unwrap();
+    registry.register(Box::new(subscriptions.clone())).unwrap();
+    registry.register(Box::new(broadcast_lag.clone())).unwrap();
+    registry
+        .register(Box::new(broadcast_dropped.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(send_queue_depth.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(shadow_rejections.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(db_write_retries.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(query_plan_seq_scans.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(tag_index_pending.clone()))
+        .unwrap();
+    registry.register(Box::new(tag_index_lag.clone())).unwrap();
+    registry.register(Box::new(stored_events.clone())).unwrap();
+    registry.register(Box::new(read_only.clone())).unwrap();
+    registry.register(Box::new(events_evicted.clone())).unwrap();
+    registry.register(Box::new(pending_events.clone())).unwrap();
+    registry
+        .register(Box::new(rate_limit_store_errors.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(connections_geoblocked.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(connections_refused.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(http_compression_bytes.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(rejection_log_dropped.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(archive_dropped.clone()))
+        .unwrap();
+    registry.register(Box::new(payment_funnel.clone())).unwrap();
+    registry
+        .register(Box::new(invoices_unpaid.clone()))
+        .unwrap();
+    registry.register(Box::new(sats_collected.clone())).unwrap();
+    registry
+        .register(Box::new(invoice_paid_delay.clone()))
+        .unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
         write_events,
         sent_events,
         connections,
+        clients,
+        subscriptions,
         db_connections,
         disconnects,
         query_aborts,
@@ -745,6 +2044,28 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         cmd_event,
         cmd_close,
         cmd_auth,
+        broadcast_lag,
+        broadcast_dropped,
+        send_queue_depth,
+        shadow_rejections,
+        db_write_retries,
+        query_plan_seq_scans,
+        tag_index_pending,
+        tag_index_lag,
+        stored_events,
+        events_evicted,
+        pending_events,
+        read_only,
+        rate_limit_store_errors,
+        connections_refused,
+        connections_geoblocked,
+        http_compression_bytes,
+        rejection_log_dropped,
+        archive_dropped,
+        payment_funnel,
+        invoices_unpaid,
+        sats_collected,
+        invoice_paid_delay,
     };
     (registry, metrics)
 }
@@ -760,18 +2081,31 @@ fn file_bytes(path: &str) -> Result<Vec<u8>> {
 
 /// Start running a Nostr relay server.
 pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Result<(), Error> {
+    start_server_with_hooks(settings, shutdown_rx, Arc::new(NoopHooks))
+}
+
+/// Start running a Nostr relay server, invoking the provided
+/// lifecycle hooks before listening and during shutdown.
+pub fn start_server_with_hooks(
+    settings: &Settings,
+    shutdown_rx: MpscReceiver<()>,
+    hooks: Arc<dyn LifecycleHooks>,
+) -> Result<(), Error> {
     trace!("Config: {:?}", settings);
     // do some config validation.
     if !Path::new(&settings.database.data_directory).is_dir() {
         error!("Database directory does not exist");
         return Err(Error::DatabaseDirError);
     }
-    let addr = format!(
-        "{}:{}",
-        settings.network.address.trim(),
-        settings.network.port
-    );
-    let socket_addr = addr.parse().expect("listening address not valid");
+    let listeners: Vec<(Listener, SocketAddr)> = settings
+        .network
+        .listeners()
+        .into_iter()
+        .map(|l| {
+            let socket_addr = l.bind_addr().parse().expect("listening address not valid");
+            (l, socket_addr)
+        })
+        .collect();
     // address whitelisting settings
     if let Some(addr_whitelist) = &settings.authorization.pubkey_whitelist {
         info!(
@@ -819,23 +2153,76 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         .build()
         .unwrap();
     // start tokio
-    rt.block_on(async {
+    let res = rt.block_on(async {
         let broadcast_buffer_limit = settings.limits.broadcast_buffer;
         let persist_buffer_limit = settings.limits.event_persist_buffer;
         let verified_users_active = settings.verified_users.is_active();
         let settings = settings.clone();
-        info!("listening on: {}", socket_addr);
         // all client-submitted valid events are broadcast to every
         // other client on this channel.  This should be large enough
         // to accommodate slower readers (messages are dropped if
         // clients can not keep up).
-        let (bcast_tx, _) = broadcast::channel::<Event>(broadcast_buffer_limit);
+        let (bcast_tx, _) = broadcast::channel::<BroadcastEvent>(broadcast_buffer_limit);
         // validated events that need to be persisted are sent to the
         // database on via this channel.
         let (event_tx, event_rx) = mpsc::channel::<SubmittedEvent>(persist_buffer_limit);
         // establish a channel for letting all threads now about a
         // requested server shutdown.
         let (invoke_shutdown, shutdown_listen) = broadcast::channel::<()>(1);
+        // start listening before the database is migrated (unless
+        // configured not to), answering with the startup state until
+        // the relay is ready.
+        let starting_info = Arc::new(
+            serde_json::to_string_pretty(&RelayInfo::from(settings.clone()).with_starting())
+                .unwrap(),
+        );
+        let mut servers = vec![];
+        let mut held_listeners = vec![];
+        let listeners: Vec<_> = listeners
+            .into_iter()
+            .map(|(listener, socket_addr)| {
+                let acceptor = tls_acceptor(&listener).unwrap_or_else(|e| {
+                    panic!("could not load TLS for {}: {e}", listener.bind_addr())
+                });
+                let policy = Arc::new(listener.policy(&settings));
+                let ready = Arc::new(OnceCell::new());
+                if settings.database.block_until_migrated {
+                    held_listeners.push((
+                        policy.name.clone(),
+                        socket_addr,
+                        acceptor,
+                        ready.clone(),
+                    ));
+                } else {
+                    servers.push(tokio::spawn(serve_listener(
+                        policy.name.clone(),
+                        socket_addr,
+                        acceptor,
+                        &settings.network,
+                        ready.clone(),
+                        starting_info.clone(),
+                        invoke_shutdown.subscribe(),
+                    )));
+                }
+                (policy, ready)
+            })
+            .collect();
+        // listen for (external to tokio) shutdown request, also while
+        // starting up.  The wait blocks, so it must not hold a worker
+        // the listeners need.
+        let controlled_shutdown = invoke_shutdown.clone();
+        tokio::task::spawn_blocking(move || {
+            info!("control message listener started");
+            match shutdown_rx.recv() {
+                Ok(()) => {
+                    info!("control message requesting shutdown");
+                    controlled_shutdown.send(()).ok();
+                }
+                Err(std::sync::mpsc::RecvError) => {
+                    trace!("shutdown requestor is disconnected (this is normal)");
+                }
+            };
+        });
         // create a channel for sending any new metadata event.  These
         // will get processed relatively slowly (a potentially
         // multi-second blocking HTTP call) on a single thread, so we
@@ -848,13 +2235,110 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
 
         let (payment_tx, payment_rx) = broadcast::channel::<PaymentMessage>(4096);
 
+        // events are rejected while in read-only mode
+        let read_only = ReadOnlyMode::new(&settings.options);
+        if read_only.is_active() {
+            warn!("starting in read-only mode; events will be rejected");
+        }
+
+        // operator notices are sent to every connected client
+        let (notice_tx, _) = broadcast::channel::<RelayNotice>(16);
+
+        // features that sign as the relay are disabled without keys
+        let relay_keys = RelayKeys::load(&settings.info)
+            .unwrap_or_else(|e| panic!("could not load relay keys: {e}"));
+        match &relay_keys {
+            Some(k) => info!("relay pubkey: {}", k.public_key_hex()),
+            None => info!("no relay secret key; relay-signed events are disabled"),
+        }
+
         let (registry, metrics) = create_metrics();
+        metrics.read_only.set(i64::from(read_only.is_active()));
+
+        // rate limits, shared between relays if kept in redis
+        let rate_limits =
+            RateLimits::from_limits(&settings.limits, metrics.rate_limit_store_errors.clone())
+                .unwrap_or_else(|e| panic!("could not set up rate limits: {e}"));
+        // refuse connections by country, if configured
+        let geo_filter = GeoFilter::from_settings(&settings.geoip)
+            .unwrap_or_else(|e| panic!("could not set up geoip filtering: {e}"));
+        // client software of open connections, for tracing abuse
+        let identities = settings
+            .logging
+            .client_identity
+            .then(ClientIdentities::default);
+        // record rejected events in a separate log, if configured
+        let (rejections, rejection_writer) =
+            RejectionLog::open(&settings.logging, metrics.rejection_log_dropped.clone())
+                .unwrap_or_else(|e| panic!("could not open the rejection log: {e}"));
+        if let Some(writer) = rejection_writer {
+            tokio::task::spawn(writer.run());
+        }
 
-        // build a repository for events
-        let repo = db::build_repo(&settings, metrics.clone()).await;
+        // build a repository for events.  A relay stopped while
+        // starting up exits right away, rather than finishing its
+        // migrations first.
+        let repo = tokio::select! {
+            repo = hooks.repo(&settings, metrics.clone()) => repo,
+            res = first_stopped(&mut servers) => {
+                return Err(res.err().unwrap_or(Error::StoppedWhileStarting));
+            }
+        };
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
+        // restore the membership list, if managed by signed events
+        let membership = Membership::new(&settings.authorization);
+        if let Err(e) = membership.load(&repo).await {
+            warn!("could not load membership list: {:?}", e);
+        }
+        if settings.authorization.whitelist_from_contact_list.is_some() {
+            tokio::task::spawn(membership.clone().run_contact_list_refresh(
+                repo.clone(),
+                Duration::from_secs(settings.authorization.contact_list_refresh_secs),
+            ));
+        }
+        // count events written per author, kind and day, if enabled
+        let write_stats = settings.stats.enabled.then(WriteStats::default);
+        if let Some(stats) = write_stats.clone() {
+            tokio::task::spawn(stats.run(repo.clone(), invoke_shutdown.subscribe()));
+        }
+        // count stored events for the status page, if enabled
+        let summary = settings.status_page.enabled.then(RelaySummary::default);
+        if let Some(summary) = summary.clone() {
+            tokio::task::spawn(summary.run(
+                repo.clone(),
+                Duration::from_secs(settings.status_page.refresh_secs),
+                invoke_shutdown.subscribe(),
+            ));
+        }
+        // sample stored notes for the languages advertised, if enabled
+        let languages = DetectedLanguages::from_settings(&settings.language_detection)
+            .unwrap_or_else(|e| panic!("could not set up language detection: {e}"));
+        if let Some(languages) = languages.clone() {
+            tokio::task::spawn(languages.run(repo.clone(), invoke_shutdown.subscribe()));
+        }
+        // append accepted events to the archive, if enabled
+        if settings.archive.enabled {
+            let archive = EventArchive::open(&settings.archive, metrics.archive_dropped.clone())
+                .unwrap_or_else(|e| panic!("could not open the event archive: {e}"));
+            tokio::task::spawn(archive.run(bcast_tx.subscribe(), invoke_shutdown.subscribe()));
+        }
+        // withhold the events of pubkeys new to the relay, if enabled
+        let quarantine = PubkeyQuarantine::new(&settings.quarantine);
+        // decides which events each client may read
+        let mut read_policy = hooks.read_policy(&settings);
+        if quarantine.is_enabled() {
+            if let Err(e) = quarantine.load(repo.as_ref()).await {
+                warn!("could not load quarantined pubkeys: {:?}", e);
+            }
+            tokio::task::spawn(quarantine.clone().run_release(repo.clone()));
+            read_policy = Arc::new(QuarantineReadPolicy::new(
+                read_policy,
+                quarantine.clone(),
+                &settings,
+            ));
+        }
         tokio::task::spawn(db::db_writer(
             repo.clone(),
             settings.clone(),
@@ -862,6 +2346,11 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             bcast_tx.clone(),
             metadata_tx.clone(),
             payment_tx.clone(),
+            membership.clone(),
+            write_stats,
+            quarantine.clone(),
+            metrics.clone(),
+            read_policy.clone(),
             shutdown_listen,
         ));
         info!("db writer created");
@@ -892,6 +2381,8 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
                 payment_rx,
                 bcast_tx.clone(),
                 settings.clone(),
+                hooks.payment_processor(&settings),
+                metrics.clone(),
             );
             if let Ok(mut p) = payment_opt {
                 tokio::task::spawn(async move {
@@ -901,24 +2392,27 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             }
         }
 
-        // listen for (external to tokio) shutdown request
-        let controlled_shutdown = invoke_shutdown.clone();
-        tokio::spawn(async move {
-            info!("control message listener started");
-            match shutdown_rx.recv() {
-                Ok(()) => {
-                    info!("control message requesting shutdown");
-                    controlled_shutdown.send(()).ok();
-                }
-                Err(std::sync::mpsc::RecvError) => {
-                    trace!("shutdown requestor is disconnected (this is normal)");
-                }
-            };
-        });
+        // publish relay-signed status events, if enabled
+        if settings.status_events.enabled {
+            if let Some(keys) = &relay_keys {
+                let mut s = StatusPublisher::new(
+                    repo.clone(),
+                    bcast_tx.clone(),
+                    settings.clone(),
+                    metrics.clone(),
+                    keys,
+                );
+                tokio::task::spawn(async move {
+                    info!("starting status event publisher...");
+                    s.run().await;
+                });
+            } else {
+                warn!("status events disabled: no relay secret key");
+            }
+        }
+
         // listen for ctrl-c interruupts
         let ctrl_c_shutdown = invoke_shutdown.clone();
-        // listener for webserver shutdown
-        let webserver_shutdown_listen = invoke_shutdown.subscribe();
 
         tokio::spawn(async move {
             tokio::signal::ctrl_c().await.unwrap();
@@ -934,48 +2428,236 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             info!("reading favicon...");
             file_bytes(x).ok()
         });
-
-        // A `Service` is needed for every connection, so this
-        // creates one from our `handle_request` function.
-        let make_svc = make_service_fn(|conn: &AddrStream| {
-            let repo = repo.clone();
-            let remote_addr = conn.remote_addr();
-            let bcast = bcast_tx.clone();
-            let event = event_tx.clone();
-            let payment_tx = payment_tx.clone();
-            let stop = invoke_shutdown.clone();
-            let settings = settings.clone();
-            let favicon = favicon.clone();
-            let registry = registry.clone();
-            let metrics = metrics.clone();
-            async move {
-                // service_fn converts our function into a `Service`
-                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                    handle_web_request(
-                        request,
-                        repo.clone(),
-                        settings.clone(),
-                        remote_addr,
-                        bcast.clone(),
-                        event.clone(),
-                        payment_tx.clone(),
-                        stop.subscribe(),
-                        favicon.clone(),
-                        registry.clone(),
-                        metrics.clone(),
-                    )
-                }))
-            }
+        // Read in the relay icon, if it is served by the relay
+        let icon = settings.info.relay_icon_file.as_ref().map(|path| {
+            info!("reading relay icon...");
+            RelayIcon::load(path)
+                .unwrap_or_else(|e| panic!("could not read relay icon {path}: {e}"))
         });
-        let server = Server::bind(&socket_addr)
-            .serve(make_svc)
-            .with_graceful_shutdown(ctrl_c_or_signal(webserver_shutdown_listen));
+
+        let app_state = AppState {
+            settings: settings.clone(),
+            repo: repo.clone(),
+            broadcast: bcast_tx.clone(),
+            metrics: metrics.clone(),
+            membership: membership.clone(),
+            relay_keys: relay_keys.clone(),
+            read_only: read_only.clone(),
+        };
+        hooks.before_listen(&app_state).await;
+        // serve clients on each listener, starting any that were held
+        // back until the database was ready.
+        for (policy, ready) in listeners {
+            // A `Service` is needed for every connection; each is
+            // tagged with the policy of the listener that accepted it.
+            let state = ListenerState {
+                repo: repo.clone(),
+                settings: settings.clone(),
+                broadcast: bcast_tx.clone(),
+                event_tx: event_tx.clone(),
+                payment_tx: payment_tx.clone(),
+                shutdown: invoke_shutdown.clone(),
+                favicon: favicon.clone(),
+                icon: icon.clone(),
+                registry: registry.clone(),
+                metrics: metrics.clone(),
+                read_policy: read_policy.clone(),
+                listener: policy,
+                relay_keys: relay_keys.clone(),
+                notices: notice_tx.clone(),
+                read_only: read_only.clone(),
+                rate_limits: rate_limits.clone(),
+                geo_filter: geo_filter.clone(),
+                identities: identities.clone(),
+                membership: membership.clone(),
+                quarantine: quarantine.clone(),
+                rejections: rejections.clone(),
+                summary: summary.clone(),
+                languages: languages.clone(),
+            };
+            ready.set(state).ok();
+        }
+        for (name, socket_addr, acceptor, ready) in held_listeners {
+            servers.push(tokio::spawn(serve_listener(
+                name,
+                socket_addr,
+                acceptor,
+                &settings.network,
+                ready,
+                starting_info.clone(),
+                invoke_shutdown.subscribe(),
+            )));
+        }
+        info!("relay is ready");
         // run hyper in this thread.  This is why the thread does not return.
-        if let Err(e) = server.await {
-            eprintln!("server error: {e}");
+        for res in futures::future::join_all(servers).await {
+            match res {
+                Ok(Err(e)) => eprintln!("server error: {e}"),
+                Err(e) => eprintln!("server task failed: {e}"),
+                Ok(Ok(())) => {}
+            }
         }
+        hooks.on_shutdown(&app_state).await;
+        Ok(())
     });
-    Ok(())
+    if res.is_err() {
+        // do not wait for a migration still running
+        rt.shutdown_background();
+    }
+    res
+}
+
+/// Wait for the first of the listeners to stop, if any are serving.
+async fn first_stopped(servers: &mut [JoinHandle<Result<()>>]) -> Result<()> {
+    if servers.is_empty() {
+        return futures::future::pending().await;
+    }
+    let (res, _, _) = futures::future::select_all(servers.iter_mut()).await;
+    res?
+}
+
+/// Accept connections on a listener until shutdown.  Requests are
+/// served with the listener's state once it is `ready`, and answered
+/// with the relay's startup state before.  Shutting down before the
+/// listener is ready is an error.
+fn serve_listener(
+    name: String,
+    socket_addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    network: &Network,
+    ready: Arc<OnceCell<ListenerState>>,
+    starting_info: Arc<String>,
+    shutdown: Receiver<()>,
+) -> impl Future<Output = Result<()>> + Send + 'static {
+    let network = network.clone();
+    let tcp = bind_tcp(socket_addr, &network).expect("could not bind listening address");
+    info!(
+        "listening on: {} ({}, tls: {})",
+        socket_addr,
+        name,
+        acceptor.is_some()
+    );
+    async move {
+        let started = ready.clone();
+        let shutdown = ctrl_c_or_signal(shutdown);
+        let served = if let Some(acceptor) = acceptor {
+            let incoming = accept::from_stream(tls_incoming(tcp, acceptor, &network));
+            let make_svc = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                let ready = ready.clone();
+                let starting_info = starting_info.clone();
+                let remote_addr = conn.get_ref().0.peer_addr().unwrap_or(socket_addr);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        serve_request(&ready, request, remote_addr, &starting_info)
+                    }))
+                }
+            });
+            Server::builder(incoming)
+                .serve(make_svc)
+                .with_graceful_shutdown(shutdown)
+                .await
+        } else {
+            let mut incoming =
+                AddrIncoming::from_listener(tcp).expect("could not bind listening address");
+            incoming.set_nodelay(network.tcp_nodelay.unwrap_or(false));
+            if let Some(secs) = network.tcp_keepalive_secs {
+                incoming
+                    .set_keepalive(Some(Duration::from_secs(secs)))
+                    .set_keepalive_interval(
+                        network.tcp_keepalive_interval_secs.map(Duration::from_secs),
+                    )
+                    .set_keepalive_retries(network.tcp_keepalive_retries);
+            }
+            let make_svc = make_service_fn(move |conn: &AddrStream| {
+                let ready = ready.clone();
+                let starting_info = starting_info.clone();
+                let remote_addr = conn.remote_addr();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        serve_request(&ready, request, remote_addr, &starting_info)
+                    }))
+                }
+            });
+            Server::builder(incoming)
+                .serve(make_svc)
+                .with_graceful_shutdown(shutdown)
+                .await
+        };
+        served?;
+        if started.initialized() {
+            Ok(())
+        } else {
+            warn!("stopped while starting up");
+            Err(Error::StoppedWhileStarting)
+        }
+    }
+}
+
+/// Serve a request with the listener's state, once it is ready.
+fn serve_request(
+    ready: &OnceCell<ListenerState>,
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+    starting_info: &Arc<String>,
+) -> impl Future<Output = Result<Response<Body>, Infallible>> + Send + 'static {
+    let state = ready.get().cloned();
+    let starting_info = starting_info.clone();
+    async move {
+        match state {
+            Some(state) => state.handle(request, remote_addr).await,
+            None => Ok(starting_request(request, &starting_info)),
+        }
+    }
+}
+
+/// Answer a request while the relay is starting up.  Relay
+/// information says so, websocket connections are closed with 1013
+/// (try again later), and health checks report the migration running.
+fn starting_request(mut request: Request<Body>, starting_info: &str) -> Response<Body> {
+    let accepts_info = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |mt| mt.contains("application/nostr+json"));
+    match (
+        request.uri().path(),
+        request.headers().contains_key(header::UPGRADE),
+    ) {
+        ("/", true) => match handshake::server::create_response_with_body(&request, Body::empty) {
+            Ok(response) => {
+                tokio::spawn(async move {
+                    if let Ok(upgraded) = upgrade::on(&mut request).await {
+                        let mut ws_stream = WebSocketStream::from_raw_socket(
+                            upgraded,
+                            tokio_tungstenite::tungstenite::protocol::Role::Server,
+                            None,
+                        )
+                        .await;
+                        close_connection(&mut ws_stream, CloseReason::Starting).await;
+                    }
+                });
+                response
+            }
+            Err(error) => json_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Failed to create websocket: {error}"),
+            ),
+        },
+        ("/", false) if accepts_info => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/nostr+json")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(starting_info.to_owned()))
+            .unwrap(),
+        (HEALTH_PATH, false) => json_response(StatusCode::OK, &startup::health(false)),
+        (READY_PATH, false) => {
+            json_response(StatusCode::SERVICE_UNAVAILABLE, &json!({ "ready": false }))
+        }
+        _ => json_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "relay is starting up, try again later",
+        ),
+    }
 }
 
 /// Nostr protocol messages from a client
@@ -986,34 +2668,53 @@ pub enum NostrMessage {
     EventMsg(EventCmd),
     /// A `REQ` message
     SubMsg(Subscription),
+    /// A `PROOF` message; tried before `CLOSE`, which has the same shape
+    ProofMsg(ProofCmd),
     /// A `CLOSE` message
     CloseMsg(CloseCmd),
+    /// A `SUPPORTED` message
+    SupportedMsg(SupportedCmd),
+}
+
+/// Message types parsed into a `NostrMessage`
+const MESSAGE_TYPES: [&str; 6] = ["EVENT", "AUTH", "REQ", "CLOSE", "SUPPORTED", "PROOF"];
+
+/// The type (first element) of a client message, read without parsing
+/// the rest of it.  `None` if the message does not start with a plain
+/// string.
+fn message_type(msg: &str) -> Option<&str> {
+    let rest = msg
+        .trim_start()
+        .strip_prefix('[')?
+        .trim_start()
+        .strip_prefix('"')?;
+    let name = &rest[..rest.find('"')?];
+    (!name.contains('\\')).then_some(name)
 }
 
 /// Convert Message to `NostrMessage`
-fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
-    let parsed_res: Result<NostrMessage> =
-        serde_json::from_str(msg).map_err(std::convert::Into::into);
+fn convert_to_msg(msg: &str) -> Result<NostrMessage> {
+    // message types from future NIPs are not parse errors
+    if let Some(name) = message_type(msg).filter(|name| !MESSAGE_TYPES.contains(name)) {
+        return Err(Error::UnsupportedMessageType(
+            name.chars().take(32).collect(),
+        ));
+    }
+    let parsed_res: serde_json::Result<NostrMessage> = serde_json::from_str(msg);
     match parsed_res {
         Ok(m) => {
             if let NostrMessage::SubMsg(_) = m {
                 // note; this only prints the first 16k of a REQ and then truncates.
                 trace!("REQ: {:?}", msg);
             };
-            if let NostrMessage::EventMsg(_) = m {
-                if let Some(max_size) = max_bytes {
-                    // check length, ensure that some max size is set.
-                    if msg.len() > max_size && max_size > 0 {
-                        return Err(Error::EventMaxLengthError(msg.len()));
-                    }
-                }
-            }
             Ok(m)
         }
         Err(e) => {
             trace!("proto parse error: {:?}", e);
             trace!("parse error on message: {:?}", msg.trim());
-            Err(Error::ProtoParseError)
+            Err(malformed_json(msg, &e)
+                .or_else(|| malformed_event_field(msg))
+                .unwrap_or(Error::ProtoParseError))
         }
     }
 }
@@ -1024,38 +2725,59 @@ fn make_notice_message(notice: &Notice) -> Message {
         Notice::Message(ref msg) => json!(["NOTICE", msg]),
         Notice::EventResult(ref res) => json!(["OK", res.id, res.status.to_bool(), res.msg]),
         Notice::AuthChallenge(ref challenge) => json!(["AUTH", challenge]),
+        Notice::Closed(ref sub_id, ref msg) => json!(["CLOSED", sub_id, msg]),
+        Notice::Signed(ref notice) => match notice.event {
+            Some(ref event) => json!(["NOTICE", notice.message, event]),
+            None => json!(["NOTICE", notice.message]),
+        },
+        Notice::NewerVersion(ref event) => json!(["EVENT", NEWER_VERSION_SUB_ID, event]),
     };
 
     Message::text(json.to_string())
 }
 
-fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, settings: &Settings) -> bool {
+fn allowed_to_send(event_str: &str, conn: &conn::ClientConn, read_policy: &dyn ReadPolicy) -> bool {
     // TODO: pass in kind so that we can avoid deserialization for most events
-    if settings.authorization.nip42_dms {
-        match serde_json::from_str::<Event>(event_str) {
-            Ok(event) => {
-                if event.kind == 4 || event.kind == 44 || event.kind == 1059 {
-                    match (conn.auth_pubkey(), event.tag_values_by_name("p").first()) {
-                        (Some(auth_pubkey), Some(recipient_pubkey)) => {
-                            recipient_pubkey == auth_pubkey || &event.pubkey == auth_pubkey
-                        }
-                        (_, _) => false,
-                    }
-                } else {
-                    true
-                }
+    if !read_policy.withholds_events() {
+        return true;
+    }
+    match serde_json::from_str::<Event>(event_str) {
+        Ok(event) => read_policy.can_read(&event, conn.auth_pubkey().map(String::as_str)),
+        Err(_) => false,
+    }
+}
+
+/// Store subscription watermarks for an authenticated client, without
+/// holding up the connection.
+fn save_watermarks(repo: &Arc<dyn NostrRepo>, pubkey: Option<&String>, pending: Vec<Pending>) {
+    let Some(pubkey) = pubkey.cloned() else {
+        return;
+    };
+    if pending.is_empty() {
+        return;
+    }
+    let repo = repo.clone();
+    tokio::spawn(async move {
+        for (key, created_at) in pending {
+            if let Err(e) = repo.save_watermark(&pubkey, &key, created_at).await {
+                warn!("could not save subscription watermark: {:?}", e);
             }
-            Err(_) => false,
         }
-    } else {
-        true
-    }
+    });
 }
 
 struct ClientInfo {
     remote_ip: String,
     user_agent: Option<String>,
     origin: Option<String>,
+    listener: Arc<ListenerPolicy>,
+    read_only: ReadOnlyMode,
+    rate_limits: RateLimits,
+    rejections: RejectionLog,
+    relay_keys: Option<RelayKeys>,
+    quarantine: PubkeyQuarantine,
+    identity: Option<IdentityGuard>, // listed for admins until the client is gone
+    _connection: ConnectionGuard,    // counted as open until the client is gone
 }
 
 /// Handle new client connections.  This runs through an event loop
@@ -1065,11 +2787,13 @@ async fn nostr_server(
     repo: Arc<dyn NostrRepo>,
     client_info: ClientInfo,
     settings: Settings,
-    mut ws_stream: WebSocketStream<Upgraded>,
-    broadcast: Sender<Event>,
+    mut ws_stream: WebSocketStream<CoalescingStream<Upgraded>>,
+    broadcast: Sender<BroadcastEvent>,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
+    mut relay_notices: Receiver<RelayNotice>,
     metrics: NostrMetrics,
+    read_policy: Arc<dyn ReadPolicy>,
 ) {
     // the time this websocket nostr server started
     let orig_start = Instant::now();
@@ -1077,6 +2801,17 @@ async fn nostr_server(
     let mut bcast_rx = broadcast.subscribe();
     // Track internal client state
     let mut conn = conn::ClientConn::new(client_info.remote_ip);
+    conn.set_auth_window(
+        Duration::from_secs(settings.authorization.auth_challenge_ttl_secs),
+        settings.authorization.auth_max_skew_secs,
+    );
+    // AUTH attempts are limited per connection, as well as per IP
+    let auth_lim_opt = settings
+        .limits
+        .auth_attempts_per_min
+        .and_then(core::num::NonZeroU32::new)
+        .map(|n| RateLimiter::direct(Quota::per_minute(n)));
+    let auth_failure_base = Duration::from_millis(settings.authorization.auth_failure_delay_ms);
     // subscription creation rate limiting
     let mut sub_lim_opt = None;
     // 100ms jitter when the rate limiter returns
@@ -1090,6 +2825,16 @@ async fn nostr_server(
             sub_lim_opt = Some(RateLimiter::direct(quota));
         }
     }
+    let req_limits = ReqLimits {
+        max_bytes: settings.limits.max_ws_message_bytes,
+        max_values: settings.limits.max_filter_values,
+        max_ids: settings.limits.max_filter_ids,
+    };
+    // in shadow mode, oversized events are accepted
+    let shadow = settings.options.shadow_enforcement;
+    // listeners may require authentication, regardless of nip42_auth
+    let listener = client_info.listener.clone();
+    let nip42_auth = settings.authorization.nip42_auth || listener.auth_required;
     // Use the remote IP as the client identifier
     let cid = conn.get_client_prefix();
     // Create a channel for receiving query results from the database.
@@ -1112,28 +2857,76 @@ async fn nostr_server(
     let start = tokio::time::Instant::now() + default_ping_dur;
     let mut ping_interval = tokio::time::interval_at(start, default_ping_dur);
 
+    // periodically sample how many messages are waiting to be sent.
+    let queue_sample_dur = Duration::from_secs(15);
+    let mut queue_sample_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + queue_sample_dur,
+        queue_sample_dur,
+    );
+    // this connection's share of the send queue gauge
+    let mut queue_depth: i64 = 0;
+
     // maintain a hashmap of a oneshot channel for active subscriptions.
     // when these subscriptions are cancelled, make a message
     // available to the executing query so it knows to stop.
     let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // when broadcast batching is enabled, flushes are held back
+    // until this deadline, so bursts of events share a single write.
+    let broadcast_flush = settings
+        .network
+        .broadcast_flush_ms
+        .map(Duration::from_millis);
+    let flush_hold = ws_stream.get_ref().flush_hold();
+    let mut flush_deadline: Option<tokio::time::Instant> = None;
+    // operator notices waiting for their (randomly spread) delivery time
+    let mut queued_notices: VecDeque<(tokio::time::Instant, RelayNotice)> = VecDeque::new();
+    // answers to failed AUTH attempts, held back until their delay ends
+    let mut auth_replies: VecDeque<(tokio::time::Instant, Notice)> = VecDeque::new();
     // for stats, keep track of how many events the client published,
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
     let mut client_received_event_count: usize = 0;
+    // newest event sent to each subscription, for resuming repeated REQs
+    let resume_subscriptions = settings.options.resume_subscriptions;
+    let mut watermarks = Watermarks::default();
+    // live events are held until a subscription's EOSE
+    let mut stored_phase: StoredPhase<(Message, u64)> =
+        StoredPhase::new(settings.limits.live_events_before_eose)
+            .with_quota(settings.limits.max_events_before_eose);
+    // subscriptions still sending stored events, that want progress reports
+    let progress_default = settings.options.req_progress_default;
+    let progress_format = ProgressFormat::from_setting(&settings.options.req_progress_format);
+    let unknown_messages = settings.options.unknown_messages;
+    let mut progress = settings
+        .options
+        .req_progress_secs
+        .map(|secs| QueryProgress::new(Duration::from_secs(secs)));
+    let mut progress_interval = tokio::time::interval(Duration::from_secs(1));
+    progress_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     let unspec = "<unspecified>".to_string();
-    info!("new client connection (cid: {}, ip: {:?})", cid, conn.ip());
-    let origin = client_info.origin.as_ref().unwrap_or(&unspec);
-    let user_agent = client_info.user_agent.as_ref().unwrap_or(&unspec);
     info!(
-        "cid: {}, origin: {:?}, user-agent: {:?}",
-        cid, origin, user_agent
+        "new client connection (cid: {}, ip: {:?}, listener: {})",
+        cid,
+        conn.ip(),
+        listener.name
     );
+    let origin = client_info.origin.as_ref().unwrap_or(&unspec);
+    if client_info.identity.is_some() {
+        let user_agent = client_info.user_agent.as_ref().unwrap_or(&unspec);
+        info!(
+            "cid: {}, origin: {:?}, user-agent: {:?}",
+            cid, origin, user_agent
+        );
+    } else {
+        info!("cid: {}, origin: {:?}", cid, origin);
+    }
 
     // Measure connections
     metrics.connections.inc();
+    metrics.clients.inc();
 
-    if settings.authorization.nip42_auth {
+    if nip42_auth {
         conn.generate_auth_challenge();
         if let Some(challenge) = conn.auth_challenge() {
             ws_stream
@@ -1145,12 +2938,25 @@ async fn nostr_server(
         }
     }
 
+    // let clients know up front that events will be rejected
+    if client_info.read_only.is_active() {
+        let msg = format!(
+            "{}; new events are not accepted",
+            client_info.read_only.message()
+        );
+        ws_stream
+            .send(make_notice_message(&Notice::message(msg)))
+            .await
+            .ok();
+    }
+
     loop {
         tokio::select! {
             _ = shutdown.recv() => {
         metrics.disconnects.with_label_values(&["shutdown"]).inc();
                 info!("Close connection down due to shutdown, client: {}, ip: {:?}, connected: {:?}", cid, conn.ip(), orig_start.elapsed());
                 // server shutting down, exit loop
+                close_connection(&mut ws_stream, CloseReason::Shutdown).await;
                 break;
             },
             _ = ping_interval.tick() => {
@@ -1159,30 +2965,188 @@ async fn nostr_server(
                 if last_message_time.elapsed() > max_quiet_time {
                     debug!("ending connection due to lack of client ping response");
             metrics.disconnects.with_label_values(&["timeout"]).inc();
+                    close_connection(&mut ws_stream, CloseReason::Idle).await;
                     break;
                 }
                 // Send a ping
                 ws_stream.send(Message::Ping(Vec::new())).await.ok();
             },
+            _ = tokio::time::sleep_until(flush_deadline.unwrap_or_else(tokio::time::Instant::now)), if flush_deadline.is_some() => {
+                flush_deadline = None;
+                flush_hold.store(false, Ordering::Relaxed);
+                ws_stream.flush().await.ok();
+            },
+            _ = queue_sample_interval.tick() => {
+                let query_depth = query_tx.max_capacity() - query_tx.capacity();
+                let depth = (bcast_rx.len() + query_depth) as i64;
+                metrics.send_queue_depth.add(depth - queue_depth);
+                queue_depth = depth;
+            },
+            _ = progress_interval.tick(), if progress.as_ref().map_or(false, |p| !p.is_empty()) => {
+                // keep clients waiting on a large backfill informed
+                if let Some(p) = &progress {
+                    for (sub_id, sent, elapsed) in p.due(Instant::now()) {
+                        let msg = progress_message(progress_format, sub_id, sent, elapsed);
+                        ws_stream.send(Message::Text(msg.to_string())).await.ok();
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(conn.auth_challenge_expiry().map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)), if conn.auth_challenge().is_some() => {
+                // replace the challenge before it can only fail
+                conn.generate_auth_challenge();
+                if let Some(challenge) = conn.auth_challenge() {
+                    ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                }
+            },
+            _ = tokio::time::sleep_until(auth_replies.front().map_or_else(tokio::time::Instant::now, |(d, _)| *d)), if !auth_replies.is_empty() => {
+                if let Some((_, notice)) = auth_replies.pop_front() {
+                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                    // the failed challenge was replaced
+                    if let Some(challenge) = conn.auth_challenge() {
+                        ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                    }
+                }
+            },
             Some(notice_msg) = notice_rx.recv() => {
+                client_info.rejections.observe(conn.ip(), &notice_msg);
                 ws_stream.send(make_notice_message(&notice_msg)).await.ok();
             },
+            Ok(relay_notice) = relay_notices.recv() => {
+                // an operator notice, delivered at a random time within its
+                // spread (but after any notice already waiting)
+                let delay = relay_notice.spread.mul_f64(rand::random::<f64>());
+                let mut deadline = tokio::time::Instant::now() + delay;
+                if let Some((last, _)) = queued_notices.back() {
+                    deadline = deadline.max(*last);
+                }
+                queued_notices.push_back((deadline, relay_notice));
+            },
+            _ = tokio::time::sleep_until(queued_notices.front().map_or_else(tokio::time::Instant::now, |(d, _)| *d)), if !queued_notices.is_empty() => {
+                if let Some((_, mut relay_notice)) = queued_notices.pop_front() {
+                    let pubkey = conn.auth_pubkey().cloned();
+                    let mut admitted = false;
+                    if let (true, Some(pk)) = (relay_notice.target.needs_admission(), &pubkey) {
+                        if let Ok(keys) = Keys::from_pk_str(pk) {
+                            admitted = matches!(repo.get_account_balance(&keys).await, Ok((true, _)));
+                        }
+                    }
+                    if relay_notice.target.reaches(pubkey.as_deref(), admitted) {
+                        // only authenticated clients get the signed event
+                        if pubkey.is_none() {
+                            relay_notice.event = None;
+                        }
+                        ws_stream.send(make_notice_message(&Notice::signed(relay_notice))).await.ok();
+                    }
+                }
+            },
             Some(query_result) = query_rx.recv() => {
-                // database informed us of a query result we asked for
+                // database informed us of a query result we asked for.
+                // ignore results (including EOSE) from a subscription
+                // that has since been replaced or closed.
+                if !conn.is_current(&query_result.sub_id, query_result.generation) {
+                    trace!("dropping stale query result (cid: {}, sub: {:?})", cid, query_result.sub_id);
+                    continue;
+                }
                 let subesc = query_result.sub_id.replace('"', "");
-                if query_result.event == "EOSE" {
-                    let send_str = format!("[\"EOSE\",\"{subesc}\"]");
-                    ws_stream.send(Message::Text(send_str)).await.ok();
-                } else if allowed_to_send(&query_result.event, &conn, &settings) {
+                let mut end_of_stored = query_result.event == "EOSE";
+                let mut capped = false;
+                if !end_of_stored
+                    && allowed_to_send(&query_result.event, &conn, read_policy.as_ref())
+                    && stored_phase.stored(&query_result.sub_id, &query_result.event) {
                     metrics.sent_events.with_label_values(&["db"]).inc();
                     client_received_event_count += 1;
                     // send a result
-                    let send_str = format!("[\"EVENT\",\"{}\",{}]", subesc, &query_result.event);
+                    let send_str = match query_result.first_seen {
+                        Some(first_seen) => format!("[\"EVENT\",\"{}\",{},{}]", subesc, &query_result.event, first_seen),
+                        None => format!("[\"EVENT\",\"{}\",{}]", subesc, &query_result.event),
+                    };
+                    ws_stream.send(Message::Text(send_str)).await.ok();
+                    if let Some(p) = progress.as_mut() {
+                        p.sent(&query_result.sub_id, Instant::now());
+                    }
+                    if watermarks.is_tracked(&query_result.sub_id) {
+                        if let Some(created_at) = watermark::created_at(&query_result.event) {
+                            watermarks.delivered(&query_result.sub_id, created_at);
+                        }
+                    }
+                    if stored_phase.reached_quota(&query_result.sub_id) {
+                        // enough stored events; the rest of the query is ignored
+                        debug!("subscription reached its quota of stored events (cid: {}, sub: {:?})", cid, query_result.sub_id);
+                        if let Some(query) = running_queries.remove(&query_result.sub_id) {
+                            query.send(()).ok();
+                        }
+                        end_of_stored = true;
+                        capped = true;
+                    }
+                }
+                if end_of_stored {
+                    let end = stored_phase.end_of_stored(&query_result.sub_id);
+                    if end == EndOfStored::CaughtUp {
+                        // the client already has its EOSE
+                        continue;
+                    }
+                    // resuming clients get the server time to resume from next
+                    let resumable = conn.subscriptions().get(&query_result.sub_id).map_or(false, Subscription::is_resumable);
+                    let send_str = if resumable {
+                        format!("[\"EOSE\",\"{subesc}\",{}]", unix_time())
+                    } else {
+                        format!("[\"EOSE\",\"{subesc}\"]")
+                    };
                     ws_stream.send(Message::Text(send_str)).await.ok();
+                    if capped {
+                        let msg = format!("subscription {subesc} reached the limit of {} stored events; use narrower filters, or paginate with until", settings.limits.max_events_before_eose.unwrap_or_default());
+                        ws_stream.send(make_notice_message(&Notice::message(msg))).await.ok();
+                    }
+                    if query_result.timed_out {
+                        let msg = format!("subscription {subesc} timed out; stored results may be incomplete, use narrower filters");
+                        ws_stream.send(make_notice_message(&Notice::message(msg))).await.ok();
+                    }
+                    if let Some(p) = progress.as_mut() {
+                        p.finish(&query_result.sub_id);
+                    }
+                    let pending = watermarks.end_of_stored(&query_result.sub_id);
+                    save_watermarks(&repo, conn.auth_pubkey(), pending.into_iter().collect());
+                    match end {
+                        EndOfStored::Flush(live) => {
+                            // live events that arrived before EOSE, in order
+                            for (msg, created_at) in live {
+                                metrics.sent_events.with_label_values(&["realtime"]).inc();
+                                ws_stream.send(msg).await.ok();
+                                watermarks.delivered(&query_result.sub_id, created_at);
+                            }
+                        },
+                        EndOfStored::CatchUp(since) => {
+                            // too many live events to hold; query for them instead
+                            if let Some(s) = conn.subscriptions().get(&query_result.sub_id) {
+                                debug!("querying for live events missed before EOSE (cid: {}, sub: {:?})", cid, s.id);
+                                let mut catch_up = s.catch_up(since);
+                                catch_up.generation = query_result.generation;
+                                let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+                                if let Some(previous_query) = running_queries.insert(catch_up.id.clone(), abandon_query_tx) {
+                                    previous_query.send(()).ok();
+                                }
+                                repo.query_subscription(catch_up, cid.clone(), query_tx.clone(), abandon_query_rx).await.ok();
+                            }
+                        },
+                        EndOfStored::CaughtUp => {},
+                    }
                 }
             },
-            // TODO: consider logging the LaggedRecv error
-            Ok(global_event) = bcast_rx.recv() => {
+            bcast_msg = bcast_rx.recv() => {
+                let global_event = match bcast_msg {
+                    Ok(global_event) => global_event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // this client could not keep up, and missed
+                        // events; it must reconnect to query for them
+                        info!("closing connection that lagged behind broadcast (cid: {}, skipped: {})", cid, skipped);
+                        metrics.broadcast_dropped.with_label_values(&["lagged"]).inc_by(skipped);
+                        metrics.disconnects.with_label_values(&["lagged"]).inc();
+                        close_connection(&mut ws_stream, CloseReason::Overloaded).await;
+                        break;
+                    }
+                    // the relay is shutting down
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
                 // an event has been broadcast to all clients
                 // first check if there is a subscription for this event.
                 for (s, sub) in conn.subscriptions() {
@@ -1191,15 +3155,37 @@ async fn nostr_server(
                     }
                     // TODO: serialize at broadcast time, instead of
                     // once for each consumer.
-                    if let Ok(event_str) = serde_json::to_string(&global_event) {
-                        if allowed_to_send(&event_str, &conn, &settings) {
+                    if let Ok(event_str) = serde_json::to_string(&global_event.event) {
+                        if allowed_to_send(&event_str, &conn, read_policy.as_ref()) {
                             // create an event response and send it
                             trace!("sub match for client: {}, sub: {:?}, event: {:?}",
                                cid, s,
                                global_event.get_event_id_prefix());
                             let subesc = s.replace('"', "");
+                            let msg = if sub.wants_first_seen() {
+                                Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str},{}]", global_event.first_seen))
+                            } else {
+                                Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))
+                            };
+                            // held (or dropped as a duplicate) until EOSE
+                            let created_at = global_event.event.created_at;
+                            let Some((msg, _)) = stored_phase.live(s, &global_event.event.id, (msg, created_at)) else {
+                                continue;
+                            };
                             metrics.sent_events.with_label_values(&["realtime"]).inc();
-                            ws_stream.send(Message::Text(format!("[\"EVENT\",\"{subesc}\",{event_str}]"))).await.ok();
+                            metrics.broadcast_lag.observe(global_event.broadcast_at.elapsed().as_secs_f64());
+                            let sent = if let Some(flush_after) = broadcast_flush {
+                                // hold the write open until the flush deadline
+                                flush_hold.store(true, Ordering::Relaxed);
+                                flush_deadline.get_or_insert_with(|| tokio::time::Instant::now() + flush_after);
+                                ws_stream.feed(msg).await
+                            } else {
+                                ws_stream.send(msg).await
+                            };
+                            if sent.is_err() {
+                                metrics.broadcast_dropped.with_label_values(&["send_failed"]).inc();
+                            }
+                            watermarks.delivered(s, global_event.event.created_at);
                         }
                     } else {
                         warn!("could not serialize event: {:?}", global_event.get_event_id_prefix());
@@ -1212,11 +3198,35 @@ async fn nostr_server(
                 // Consume text messages from the client, parse into Nostr messages.
                 let nostr_msg = match ws_next {
                     Some(Ok(Message::Text(m))) => {
-                        convert_to_msg(&m,settings.limits.max_event_bytes)
+                        // reject pathological requests before parsing them
+                        if let Err(v) = check_req_limits(&m, &req_limits) {
+                            info!("client sent an oversized REQ (cid: {}, reason: {})", cid, v.reason);
+                            let notice = match v.sub_id {
+                                Some(sub_id) => Notice::closed(sub_id, RejectReason::Invalid(v.reason)),
+                                None => Notice::rejection(RejectReason::Invalid(v.reason)),
+                            };
+                            ws_stream.send(make_notice_message(&notice)).await.ok();
+                            continue;
+                        }
+                        let msg = convert_to_msg(&m);
+                        if let Ok(NostrMessage::EventMsg(ec)) = &msg {
+                            // the size limit depends on the kind
+                            if let Some(max) = settings.limits.max_event_bytes_for(ec.kind()) {
+                                if m.len() > max && enforce(shadow, &metrics, "event_size", ec.event_id(), &format!("event too large ({} > {max})", m.len())) {
+                                    info!("client sent an event larger ({} bytes) than max size for kind {} (cid: {})", m.len(), ec.kind(), cid);
+                                    let reason = RejectReason::Invalid(format!("event too large ({} bytes; the limit for kind {} is {max})", m.len(), ec.kind()));
+                                    let notice = Notice::rejected(ec.event_id().to_owned(), reason);
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                    continue;
+                                }
+                            }
+                        }
+                        msg
                     },
                     Some(Ok(Message::Binary(_))) => {
                         ws_stream.send(
-                            make_notice_message(&Notice::message("binary messages are not accepted".into()))).await.ok();
+                            make_notice_message(&Notice::rejection(RejectReason::Invalid("binary messages are not accepted".into())))).await.ok();
                         continue;
                     },
                     Some(Ok(Message::Ping(_) | Message::Pong(_))) => {
@@ -1225,9 +3235,10 @@ async fn nostr_server(
                         continue;
                     },
                     Some(Err(WsError::Capacity(MessageTooLong{size, max_size}))) => {
-                        ws_stream.send(
-                            make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await.ok();
-                        continue;
+                        info!("client sent a message too large ({} > {}) (cid: {}, ip: {:?})", size, max_size, cid, conn.ip());
+                        metrics.disconnects.with_label_values(&["error"]).inc();
+                        close_connection(&mut ws_stream, CloseReason::MessageTooBig).await;
+                        break;
                     },
                     None |
                     Some(Ok(Message::Close(_)) |
@@ -1249,6 +3260,9 @@ async fn nostr_server(
                         // default condition on error is to close the client connection
                         info!("unknown error (cid: {}, ip: {:?}): {:?} (closing conn)", cid, conn.ip(), x);
             metrics.disconnects.with_label_values(&["error"]).inc();
+                        if let Some(reason) = x.as_ref().and_then(|r| r.as_ref().err()).and_then(CloseReason::for_error) {
+                            close_connection(&mut ws_stream, reason).await;
+                        }
 
                         break;
                     }
@@ -1267,12 +3281,49 @@ async fn nostr_server(
                                 metrics.cmd_event.inc();
                                 let id_prefix:String = e.id.chars().take(8).collect();
                                 debug!("successfully parsed/validated event: {:?} (cid: {}, kind: {})", id_prefix, cid, e.kind);
-                                // check if event is expired
-                                if e.is_expired() {
-                                    let notice = Notice::invalid(e.id, "The event has already expired");
+                                if let Some(client) = client_info.identity.as_ref().and_then(|i| i.observe(&e)) {
+                                    info!("cid: {}, client: {:?}", cid, client);
+                                }
+                                if client_info.read_only.is_active() {
+                                    let notice = Notice::rejected(e.id, RejectReason::Error("relay is read-only".into()));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if listener.auth_required && conn.auth_pubkey().is_none() {
+                                    let notice = Notice::rejected(e.id, RejectReason::AuthRequired("authentication is required to publish".into()));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if !client_info.rate_limits.allow_event(conn.ip(), &e.pubkey).await {
+                                    info!("client: {} exceeded event rate limits", cid);
+                                    let notice = Notice::rejected(e.id, RejectReason::RateLimited("too many events, slow down".into()));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if e.is_expired() {
+                                    let notice = Notice::rejected(e.id, RejectReason::Invalid("event has already expired".into()));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if let Some(err) = e.validate_tag_limits(settings.limits.max_tag_value_bytes, settings.limits.max_event_tags).err()
+                                    .filter(|err| enforce(shadow, &metrics, "tag_limits", &e.id, &err.to_string())) {
+                                    info!("client: {} sent an event exceeding tag limits: {}", cid, err);
+                                    let notice = Notice::rejected(e.id, RejectReason::from(&err));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if let Some(err) = settings.limits.strict_tags.then(|| e.validate_tag_structure()).and_then(Result::err)
+                                    .filter(|err| enforce(shadow, &metrics, "strict_tags", &e.id, &err.to_string())) {
+                                    info!("client: {} sent an event with malformed tags", cid);
+                                    let notice = Notice::rejected(e.id, RejectReason::from(&err));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
+                                } else if let Some(err) = settings.limits.strict_hex.then(|| e.validate_hex_tags()).and_then(Result::err)
+                                    .filter(|err| enforce(shadow, &metrics, "strict_hex", &e.id, &err.to_string())) {
+                                    info!("client: {} sent an event with uppercase hex tags", cid);
+                                    let notice = Notice::rejected(e.id, RejectReason::from(&err));
+                                    client_info.rejections.observe(conn.ip(), &notice);
                                     ws_stream.send(make_notice_message(&notice)).await.ok();
                                     // check if the event is too far in the future.
-                                } else if e.is_valid_timestamp(settings.options.reject_future_seconds) {
+                                    // restricted kinds (gift wraps) have intentionally randomized timestamps.
+                                } else if settings.authorization.is_restricted_read_kind(e.kind)
+                                    || e.is_valid_timestamp(settings.options.reject_future_seconds)
+                                    || !enforce(shadow, &metrics, "future_timestamp", &e.id, "created_at too far in the future") {
                                     // Write this to the database.
                                     let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
                                     let submit_event = SubmittedEvent {
@@ -1281,21 +3332,23 @@ async fn nostr_server(
                                         source_ip: conn.ip().to_string(),
                                         origin: client_info.origin.clone(),
                                         user_agent: client_info.user_agent.clone(),
-                                        auth_pubkey };
+                                        auth_pubkey,
+                                        enforce_payment: listener.enforce_payment };
                                     event_tx.send(submit_event).await.ok();
                                     client_published_event_count += 1;
                                 } else {
                                     info!("client: {} sent a far future-dated event", cid);
                                     if let Some(fut_sec) = settings.options.reject_future_seconds {
                                         let msg = format!("The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay.");
-                                        let notice = Notice::invalid(e.id, &msg);
+                                        let notice = Notice::rejected(e.id, RejectReason::Invalid(msg));
+                                        client_info.rejections.observe(conn.ip(), &notice);
                                         ws_stream.send(make_notice_message(&notice)).await.ok();
                                     }
                                 }
                             },
                             Ok(WrappedAuth(event)) => {
                                 metrics.cmd_auth.inc();
-                                if settings.authorization.nip42_auth {
+                                if nip42_auth {
                                     let id_prefix:String = event.id.chars().take(8).collect();
                                     debug!("successfully parsed auth: {:?} (cid: {})", id_prefix, cid);
                                     match &settings.info.relay_url {
@@ -1303,6 +3356,14 @@ async fn nostr_server(
                                             error!("AUTH command received, but relay_url is not set in the config file (cid: {})", cid);
                                         },
                                         Some(relay) => {
+                                            let conn_allowed = auth_lim_opt.as_ref().map_or(true, |lim| lim.check().is_ok());
+                                            if !conn_allowed || !client_info.rate_limits.allow_auth(conn.ip()).await {
+                                                info!("too many AUTH attempts (cid: {})", cid);
+                                                let notice = Notice::rejected(event.id, RejectReason::RateLimited("too many AUTH attempts, slow down".into()));
+                                                ws_stream.send(make_notice_message(&notice)).await.ok();
+                                                continue;
+                                            }
+                                            let previous = conn.auth_pubkey().cloned();
                                             match conn.authenticate(&event, relay) {
                                                 Ok(_) => {
                                                     let pubkey = match conn.auth_pubkey() {
@@ -1310,29 +3371,124 @@ async fn nostr_server(
                                                         None => "<unspecified>".to_string(),
                                                     };
                                                     info!("client is authenticated: (cid: {}, pubkey: {:?})", cid, pubkey);
+                                                    let banned = conn.auth_pubkey().and_then(|k| client_info.quarantine.withheld_status(k)) == Some(PubkeyStatus::Banned);
+                                                    if banned {
+                                                        info!("closing connection of banned pubkey (cid: {})", cid);
+                                                        metrics.disconnects.with_label_values(&["banned"]).inc();
+                                                        close_connection(&mut ws_stream, CloseReason::Banned).await;
+                                                        break;
+                                                    }
+                                                    // switching identity: progress so far belongs to
+                                                    // the previous pubkey, and subscriptions it was
+                                                    // allowed to make may no longer be allowed.
+                                                    if previous.is_some() && previous.as_ref() != conn.auth_pubkey() {
+                                                        save_watermarks(&repo, previous.as_ref(), watermarks.close_all());
+                                                        let revoked: Vec<String> = if settings.info.can_see_first_seen(conn.auth_pubkey()) {
+                                                            vec![]
+                                                        } else {
+                                                            conn.subscriptions().values().filter(|s| s.wants_first_seen()).map(Subscription::get_id).collect()
+                                                        };
+                                                        for id in revoked {
+                                                            if let Some(tx) = running_queries.remove(&id) {
+                                                                tx.send(()).ok();
+                                                            }
+                                                            let open = conn.subscriptions().len();
+                                                            conn.unsubscribe(&Close { id: id.clone() });
+                                                            metrics
+                                                                .subscriptions
+                                                                .sub(open as i64 - conn.subscriptions().len() as i64);
+                                                            if let Some(p) = progress.as_mut() {
+                                                                p.finish(&id);
+                                                            }
+                                                            stored_phase.finish(&id);
+                                                            let notice = Notice::closed(id, RejectReason::Restricted("first-seen times are not available to this pubkey".into()));
+                                                            ws_stream.send(make_notice_message(&notice)).await.ok();
+                                                        }
+                                                    }
                                                 },
                                                 Err(e) => {
                                                     info!("authentication error: {} (cid: {})", e, cid);
-                                                    ws_stream.send(make_notice_message(&Notice::restricted(event.id, format!("authentication error: {e}").as_str()))).await.ok();
+                                                    let notice = Notice::rejected(event.id.clone(), RejectReason::Restricted(format!("authentication error: {e}")));
+                                                    client_info.rejections.observe(conn.ip(), &notice);
+                                                    if matches!(e, Error::AuthChallengeExpired) {
+                                                        // not a failure; answer with the new challenge
+                                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                                        if let Some(challenge) = conn.auth_challenge() {
+                                                            ws_stream.send(make_notice_message(&Notice::AuthChallenge(challenge.to_string()))).await.ok();
+                                                        }
+                                                        continue;
+                                                    }
+                                                    let pubkey_failures = client_info.rate_limits.auth_failed(&event.pubkey).await;
+                                                    let failures = pubkey_failures.max(u64::from(conn.auth_failures()));
+                                                    let delay = auth_failure_delay(auth_failure_base, failures);
+                                                    if conn.auth_failures() >= settings.authorization.auth_max_failures {
+                                                        // the connection is closing, so nothing else waits
+                                                        tokio::time::sleep(delay).await;
+                                                        ws_stream.send(make_notice_message(&notice)).await.ok();
+                                                        info!("closing connection after {} failed AUTH attempts (cid: {})", conn.auth_failures(), cid);
+                                                        metrics.disconnects.with_label_values(&["auth"]).inc();
+                                                        close_connection(&mut ws_stream, CloseReason::AuthFailures).await;
+                                                        break;
+                                                    }
+                                                    // only the answer waits, with the new challenge
+                                                    let mut deadline = tokio::time::Instant::now() + delay;
+                                                    if let Some((last, _)) = auth_replies.back() {
+                                                        deadline = deadline.max(*last);
+                                                    }
+                                                    auth_replies.push_back((deadline, notice));
                                                 },
                                             }
                                         }
                                     }
                                 } else {
-                                    let e = CommandUnknownError;
                                     info!("client sent an invalid event (cid: {})", cid);
-                                    ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+                                    let notice = Notice::rejected(evid, RejectReason::from(&CommandUnknownError));
+                                    client_info.rejections.observe(conn.ip(), &notice);
+                                    ws_stream.send(make_notice_message(&notice)).await.ok();
                                 }
                             },
                             Err(e) => {
                                 metrics.cmd_event.inc();
-                                info!("client sent an invalid event (cid: {})", cid);
-                                ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+                                info!("client sent an invalid event: {} (cid: {})", e, cid);
+                                let notice = Notice::rejected(evid, RejectReason::from(&e));
+                                client_info.rejections.observe(conn.ip(), &notice);
+                                ws_stream.send(make_notice_message(&notice)).await.ok();
                             }
                         }
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
+                    Ok(NostrMessage::SubMsg(mut s)) => {
                         debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
+                        if listener.auth_required && conn.auth_pubkey().is_none() {
+                            let notice = Notice::closed(s.id, RejectReason::AuthRequired("authentication is required to subscribe".into()));
+                            ws_stream.send(make_notice_message(&notice)).await.ok();
+                            continue;
+                        }
+                        // unauthenticated clients can never read restricted kinds
+                        if conn.auth_pubkey().is_none() {
+                            s.deny_kinds(&read_policy.restricted_kinds());
+                        }
+                        // first-seen times are only for authorized indexers
+                        if !settings.info.can_see_first_seen(conn.auth_pubkey()) {
+                            s.ignore_received_since();
+                        }
+                        // excluding kinds is an opt-in extension
+                        if !settings.options.exclude_kinds {
+                            s.ignore_exclude_kinds();
+                        }
+                        let limits = &settings.limits;
+                        if s.apply_limits(limits.default_limit, limits.max_limit) {
+                            let msg = format!(
+                                "limit of subscription {} reduced to {}",
+                                s.id,
+                                limits.max_limit.unwrap_or_default()
+                            );
+                            ws_stream.send(make_notice_message(&Notice::message(msg))).await.ok();
+                        }
+                        // filters without time bounds only cover a recent
+                        // window; older events need an explicit `since`.
+                        if let Some(window) = settings.limits.default_since_window() {
+                            s.apply_default_since(unix_time().saturating_sub(window));
+                        }
                         // subscription handling consists of:
                         // * check for rate limits
                         // * registering the subscription so future events can be matched
@@ -1346,21 +3502,65 @@ async fn nostr_server(
                             if let Some(ref lim) = sub_lim_opt {
                                 lim.until_ready_with_jitter(jitter).await;
                             }
+                            // an authenticated client repeating a REQ without
+                            // `since` resumes from the newest event it was sent.
+                            let mut resumed_since = None;
+                            let watermark_key = match conn.auth_pubkey() {
+                                Some(pubkey) if resume_subscriptions && !s.has_since() => {
+                                    let key = s.watermark_key();
+                                    if let Ok(Some(since)) = repo.get_watermark(pubkey, &key).await {
+                                        if s.fill_since(since) {
+                                            resumed_since = Some(since);
+                                        }
+                                    }
+                                    Some(key)
+                                }
+                                _ => None,
+                            };
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
-                            match conn.subscribe(s.clone()) {
-                                Ok(()) => {
+                            let open = conn.subscriptions().len();
+                            let subscribed = conn.subscribe(s.clone());
+                            metrics
+                                .subscriptions
+                                .add(conn.subscriptions().len() as i64 - open as i64);
+                            match subscribed {
+                                Ok(generation) => {
+                                    let pending = match watermark_key {
+                                        Some(key) => watermarks.track(&s.id, key),
+                                        None => watermarks.close(&s.id),
+                                    };
+                                    save_watermarks(&repo, conn.auth_pubkey(), pending.into_iter().collect());
+                                    if let Some(since) = resumed_since {
+                                        let msg = format!("subscription {} resumed with since={since}, the newest event previously sent", s.id);
+                                        ws_stream.send(make_notice_message(&Notice::message(msg))).await.ok();
+                                    }
+                                    // results are tagged with the generation, so
+                                    // stale ones from a replaced query are dropped.
+                                    s.generation = generation;
+                                    if let Some(p) = progress.as_mut() {
+                                        if s.needs_historical_events() && s.wants_progress(progress_default) {
+                                            p.start(&s.id, Instant::now());
+                                        } else {
+                                            p.finish(&s.id);
+                                        }
+                                    }
                                     // when we insert, if there was a previous query running with the same name, cancel it.
                                     if let Some(previous_query) = running_queries.insert(s.id.clone(), abandon_query_tx) {
                                         previous_query.send(()).ok();
                                     }
                                     if s.needs_historical_events() {
+                                        stored_phase.start(&s.id, unix_time());
                                         // start a database query.  this spawns a blocking database query on a worker thread.
                                         repo.query_subscription(s, cid.clone(), query_tx.clone(), abandon_query_rx).await.ok();
+                                    } else {
+                                        // no stored events to wait for
+                                        stored_phase.finish(&s.id);
+                                        watermarks.end_of_stored(&s.id);
                                     }
                                 },
                                 Err(e) => {
                                     info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    ws_stream.send(make_notice_message(&Notice::closed(s.id, RejectReason::from(&e)))).await.ok();
                                 }
                             }
                         }
@@ -1370,31 +3570,102 @@ async fn nostr_server(
                         let parsed : Result<Close> = Result::<Close>::from(cc);
                         if let Ok(c) = parsed {
                 metrics.cmd_close.inc();
-                            // check if a query is currently
-                            // running, and remove it if so.
-                            let stop_tx = running_queries.remove(&c.id);
-                            if let Some(tx) = stop_tx {
-                                tx.send(()).ok();
+                            let close_all = settings.options.close_all && c.id == CLOSE_ALL;
+                            let closing: Vec<Close> = if close_all {
+                                conn.subscriptions().keys().map(|id| Close { id: id.clone() }).collect()
+                            } else {
+                                vec![c]
+                            };
+                            for c in closing {
+                                // check if a query is currently
+                                // running, and remove it if so.
+                                let stop_tx = running_queries.remove(&c.id);
+                                if let Some(tx) = stop_tx {
+                                    tx.send(()).ok();
+                                }
+                                // stop checking new events against
+                                // the subscription
+                                let open = conn.subscriptions().len();
+                                conn.unsubscribe(&c);
+                                metrics
+                                    .subscriptions
+                                    .sub(open as i64 - conn.subscriptions().len() as i64);
+                                if let Some(p) = progress.as_mut() {
+                                    p.finish(&c.id);
+                                }
+                                stored_phase.finish(&c.id);
+                                let pending = watermarks.close(&c.id);
+                                save_watermarks(&repo, conn.auth_pubkey(), pending.into_iter().collect());
+                                if close_all {
+                                    ws_stream.send(make_notice_message(&Notice::ended(c.id))).await.ok();
+                                }
+                            }
+                        } else {
+                            info!("invalid command ignored");
+                            ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&Error::CloseParseFailed)))).await.ok();
+                        }
+                    },
+                    Ok(NostrMessage::ProofMsg(pc)) => {
+                        let Some(relay_keys) = client_info.relay_keys.as_ref() else {
+                            ws_stream.send(make_notice_message(&Notice::message("unsupported: relay has no identity key".into()))).await.ok();
+                            continue;
+                        };
+                        let Some(challenge) = pc.challenge() else {
+                            let msg = format!("challenge must be 1 to {MAX_CHALLENGE_LEN} characters");
+                            ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::Invalid(msg)))).await.ok();
+                            continue;
+                        };
+                        // signing shares the subscription creation limit
+                        if let Some(ref lim) = sub_lim_opt {
+                            lim.until_ready_with_jitter(jitter).await;
+                        }
+                        match proof_response(relay_keys, challenge, settings.info.relay_url.as_deref()) {
+                            Ok(proof) => {
+                                ws_stream.send(Message::text(proof.to_string())).await.ok();
+                            }
+                            Err(e) => {
+                                warn!("could not sign relay proof: {:?}", e);
+                                ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::Error("could not sign proof".into())))).await.ok();
                             }
-                            // stop checking new events against
-                            // the subscription
-                            conn.unsubscribe(&c);
+                        }
+                    },
+                    Ok(NostrMessage::SupportedMsg(sc)) => {
+                        if Result::<()>::from(sc).is_ok() {
+                            ws_stream.send(Message::text(supported_response(&settings).to_string())).await.ok();
                         } else {
                             info!("invalid command ignored");
-                            ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                            ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&Error::CommandUnknownError)))).await.ok();
                         }
                     },
                     Err(Error::ConnError) => {
                         debug!("got connection close/error, disconnecting cid: {}, ip: {:?}",cid, conn.ip());
                         break;
                     }
-                    Err(Error::EventMaxLengthError(s)) => {
-                        info!("client sent command larger ({} bytes) than max size (cid: {})", s, cid);
-                        ws_stream.send(make_notice_message(&Notice::message("event exceeded max size".into()))).await.ok();
+                    Err(Error::UnsupportedMessageType(name)) => {
+                        info!("client sent an unsupported message type {:?} (cid: {})", name, cid);
+                        match unknown_messages {
+                            UnknownMessages::Ignore => {},
+                            UnknownMessages::Notice => {
+                                ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&Error::UnsupportedMessageType(name))))).await.ok();
+                            },
+                            UnknownMessages::Close => {
+                                metrics.disconnects.with_label_values(&["error"]).inc();
+                                close_connection(&mut ws_stream, CloseReason::UnsupportedMessage).await;
+                                break;
+                            },
+                        }
                     },
                     Err(Error::ProtoParseError) => {
                         info!("client sent command that could not be parsed (cid: {})", cid);
-                        ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                        ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&Error::ProtoParseError)))).await.ok();
+                    },
+                    Err(e @ Error::MalformedJson(..)) => {
+                        info!("client sent {} (cid: {})", e, cid);
+                        ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&e)))).await.ok();
+                    },
+                    Err(e @ Error::EventMalformedField(..)) => {
+                        info!("client sent an event with a {} (cid: {})", e, cid);
+                        ws_stream.send(make_notice_message(&Notice::rejection(RejectReason::from(&e)))).await.ok();
                     },
                     Err(e) => {
                         info!("got non-fatal error from client (cid: {}, error: {:?}", cid, e);
@@ -1407,6 +3678,10 @@ async fn nostr_server(
     for (_, stop_tx) in running_queries {
         stop_tx.send(()).ok();
     }
+    save_watermarks(&repo, conn.auth_pubkey(), watermarks.close_all());
+    metrics.clients.dec();
+    metrics.subscriptions.sub(conn.subscriptions().len() as i64);
+    metrics.send_queue_depth.sub(queue_depth);
     info!(
         "stopping client connection (cid: {}, ip: {:?}, sent: {} events, recv: {} events, connected: {:?})",
         cid,
@@ -1425,10 +3700,34 @@ pub struct NostrMetrics {
     pub write_events: Histogram,     // response time of event writes
     pub sent_events: IntCounterVec,  // count of events sent to clients
     pub connections: IntCounter,     // count of websocket connections
+    pub clients: IntGauge,           // currently connected websocket clients
+    pub subscriptions: IntGauge,     // open subscriptions of connected clients
     pub disconnects: IntCounterVec,  // client disconnects
     pub query_aborts: IntCounterVec, // count of queries aborted by server
     pub cmd_req: IntCounter,         // count of REQ commands received
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter,       // count of CLOSE commands received
     pub cmd_auth: IntCounter,        // count of AUTH commands received
+    pub broadcast_lag: Histogram,    // delay between broadcast and delivery to a client
+    pub broadcast_dropped: IntCounterVec, // broadcast events dropped for lagging or closed clients
+    pub send_queue_depth: IntGauge,  // sampled count of messages queued for clients
+    pub shadow_rejections: IntCounterVec, // events that would have been rejected, in shadow mode
+    pub db_write_retries: IntCounterVec, // database writes retried or abandoned after transient errors
+    pub query_plan_seq_scans: IntCounterVec, // sampled slow queries planned with a sequential scan
+    pub tag_index_pending: IntGauge,     // stored events waiting for deferred tag indexing
+    pub tag_index_lag: Histogram,        // delay between storing an event and indexing its tags
+    pub stored_events: IntGauge,         // events stored, when max_stored_events is set
+    pub events_evicted: IntCounter,      // events deleted to stay under max_stored_events
+    pub pending_events: IntCounterVec,   // asynchronously admitted events, by outcome
+    pub read_only: IntGauge,             // 1 while the relay is read-only
+    pub rate_limit_store_errors: IntCounter, // rate limit store failures
+    pub connections_refused: IntCounter, // websocket connections refused by max_connections_per_ip
+    pub connections_geoblocked: IntCounter, // websocket connections refused by geoip filtering
+    pub http_compression_bytes: IntCounterVec, // gzipped HTTP response bytes, uncompressed and compressed
+    pub rejection_log_dropped: IntCounter,     // rejected events not written to the rejection log
+    pub archive_dropped: IntCounter,           // accepted events not written to the event archive
+    pub payment_funnel: IntCounterVec,         // pay-to-relay sign up and payment stages reached
+    pub invoices_unpaid: IntGauge,             // unpaid invoices that have not expired
+    pub sats_collected: IntGauge,              // total amount of paid invoices
+    pub invoice_paid_delay: Histogram,         // time from creating an invoice to seeing it paid
 }
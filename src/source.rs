@@ -0,0 +1,99 @@
+//! Where stored events came from
+//!
+//! Events published by clients are `local`.  Events copied in from
+//! other relays are recorded as `mirror:<url>` or `ingest:<url>`, so
+//! they can be told apart, and pruned sooner.  The source is stored
+//! beside each event, and is never sent to clients.
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::fmt;
+
+/// Source of events published to this relay by its clients
+pub const LOCAL: &str = "local";
+
+/// Origin of a stored event
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum EventSource {
+    /// Published by a client of this relay
+    #[default]
+    Local,
+    /// Copied continuously from another relay
+    Mirror(String),
+    /// Loaded in bulk, from another relay or an export
+    Ingest(String),
+}
+
+impl fmt::Display for EventSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventSource::Local => f.write_str(LOCAL),
+            EventSource::Mirror(url) => write!(f, "mirror:{url}"),
+            EventSource::Ingest(url) => write!(f, "ingest:{url}"),
+        }
+    }
+}
+
+impl std::str::FromStr for EventSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == LOCAL => Ok(EventSource::Local),
+            Some(("mirror", url)) if !url.is_empty() => Ok(EventSource::Mirror(url.to_owned())),
+            Some(("ingest", url)) if !url.is_empty() => Ok(EventSource::Ingest(url.to_owned())),
+            _ => Err(Error::CustomError(format!(
+                "unknown event source {s:?}, expected local, mirror:<url> or ingest:<url>"
+            ))),
+        }
+    }
+}
+
+/// Does a stored source match a pattern?  The pattern matches whole
+/// `:`-separated segments from the start of the source, so `mirror`
+/// matches events mirrored from any relay, and `mirror:<url>` those
+/// from one relay.
+#[must_use]
+pub fn source_matches(pattern: &str, source: &str) -> bool {
+    let mut segments = source.split(':');
+    pattern.split(':').all(|p| segments.next() == Some(p))
+}
+
+/// Events stored from one source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_round_trip() -> Result<()> {
+        for source in [
+            EventSource::Local,
+            EventSource::Mirror("wss://relay.example.com".to_owned()),
+            EventSource::Ingest("wss://archive.example.com/".to_owned()),
+        ] {
+            assert_eq!(source.to_string().parse::<EventSource>()?, source);
+        }
+        assert!("mirror".parse::<EventSource>().is_err());
+        assert!("copied:wss://x".parse::<EventSource>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_match_by_kind_or_url() {
+        let source = "mirror:wss://relay.example.com";
+        assert!(source_matches("mirror", source));
+        assert!(source_matches(source, source));
+        assert!(!source_matches("mirror:wss://other.example.com", source));
+        assert!(!source_matches("mirr", source));
+        assert!(!source_matches("mirror:ws", source));
+        assert!(!source_matches("mirror:wss://relay.example", source));
+        assert!(!source_matches("mirror:", source));
+        assert!(!source_matches("ingest", source));
+        assert!(source_matches("local", LOCAL));
+    }
+}
@@ -0,0 +1,151 @@
+//! Startup state, served while the database is migrated
+//!
+//! Listeners start before migrations run, so load balancers and
+//! clients get an answer instead of a refused connection.  Until the
+//! relay is ready, [`READY_PATH`] answers `503`, and [`HEALTH_PATH`]
+//! shows the migration running, with the progress of its progress bar.
+use indicatif::{ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+/// Path reporting whether the relay is up, and any migration running
+pub const HEALTH_PATH: &str = "/health";
+
+/// Path answering `200` once the relay serves clients, `503` before
+pub const READY_PATH: &str = "/ready";
+
+/// A migration being run, with its progress bar, if it draws one
+struct Running {
+    name: String,
+    bar: Option<ProgressBar>,
+}
+
+/// The migration being run, if any
+#[derive(Default)]
+pub struct MigrationProgress {
+    running: Mutex<Option<Running>>,
+}
+
+lazy_static! {
+    // migrations run once, before the relay serves clients, so their
+    // progress is kept here rather than passed through every one.
+    static ref MIGRATION: MigrationProgress = MigrationProgress::default();
+}
+
+impl MigrationProgress {
+    pub fn started(&self, name: impl Into<String>) {
+        *self.running.lock().unwrap() = Some(Running {
+            name: name.into(),
+            bar: None,
+        });
+    }
+
+    pub fn finished(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+
+    /// Follow the progress bar of the running migration.
+    pub fn follow(&self, bar: &ProgressBar) {
+        if let Some(running) = self.running.lock().unwrap().as_mut() {
+            running.bar = Some(bar.clone());
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> Option<MigrationStatus> {
+        let running = self.running.lock().unwrap();
+        let running = running.as_ref()?;
+        let bar = running.bar.as_ref().filter(|b| !b.is_finished());
+        Some(MigrationStatus {
+            migration: running.name.clone(),
+            step: bar.map(|b| b.message().to_string()),
+            percent: bar.and_then(|b| {
+                let len = b.length().filter(|len| *len > 0)?;
+                Some((b.position() * 100 / len).min(100))
+            }),
+        })
+    }
+}
+
+/// Record that a migration has started.
+pub fn migration_started(name: impl Into<String>) {
+    MIGRATION.started(name);
+}
+
+/// Record that no migration is running.
+pub fn migrations_finished() {
+    MIGRATION.finished();
+}
+
+/// A progress bar for a long migration step, drawn on the terminal,
+/// and reported at [`HEALTH_PATH`].
+#[must_use]
+pub fn progress_bar(len: u64, message: &'static str) -> ProgressBar {
+    let bar = ProgressBar::new(len).with_message(message);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.white/blue} {pos:>7}/{len:7} [{percent}%] {msg}",
+        )
+        .unwrap(),
+    );
+    MIGRATION.follow(&bar);
+    bar
+}
+
+/// Progress of the running migration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationStatus {
+    pub migration: String,
+    /// What the current step is doing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u64>,
+}
+
+/// Body of a [`HEALTH_PATH`] response
+#[must_use]
+pub fn health(ready: bool) -> Value {
+    health_with(ready, MIGRATION.status())
+}
+
+fn health_with(ready: bool, migration: Option<MigrationStatus>) -> Value {
+    match (ready, migration) {
+        (true, _) => json!({ "status": "ok" }),
+        (false, Some(migration)) => json!({ "status": "migrating", "migration": migration }),
+        (false, None) => json!({ "status": "starting" }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::ProgressDrawTarget;
+
+    #[test]
+    fn progress_is_reported_from_the_bar() {
+        let progress = MigrationProgress::default();
+        progress.started("sqlite v15 -> v16");
+        let status = progress.status().unwrap();
+        assert_eq!(status.migration, "sqlite v15 -> v16");
+        assert_eq!(status.percent, None);
+        let bar = ProgressBar::with_draw_target(Some(200), ProgressDrawTarget::hidden())
+            .with_message("rebuilding tags table");
+        progress.follow(&bar);
+        bar.inc(50);
+        let health = health_with(false, progress.status());
+        assert_eq!(health["status"], "migrating");
+        assert_eq!(health["migration"]["migration"], "sqlite v15 -> v16");
+        assert_eq!(health["migration"]["step"], "rebuilding tags table");
+        assert_eq!(health["migration"]["percent"], 25);
+        assert_eq!(
+            health_with(true, progress.status()),
+            json!({ "status": "ok" })
+        );
+        progress.finished();
+        assert_eq!(progress.status(), None);
+        assert_eq!(health_with(false, None), json!({ "status": "starting" }));
+    }
+}
@@ -0,0 +1,245 @@
+//! Daily write statistics by author and kind, for abuse analysis
+//!
+//! The event writer counts accepted events in memory, and the counts
+//! are added to the `event_stats` table once a minute.  A failed flush
+//! keeps the counts for the next one, and never holds up the writer.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::postgres::{self, PostgresPool};
+use crate::repo::sqlite::{self, build_pool};
+use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use chrono::NaiveDate;
+use rusqlite::OpenFlags;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// How often counts are added to the database
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Events written by one author, of one kind, on one (UTC) day
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventStat {
+    pub pubkey: String,
+    pub kind: u64,
+    pub day: NaiveDate,
+    pub count: u64,
+    /// Total size of the events, serialized
+    pub bytes: u64,
+}
+
+/// Events written by an author on one day (of one kind, or of all)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TopWriter {
+    pub pubkey: String,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for TopWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}\t{}", self.pubkey, self.count, self.bytes)
+    }
+}
+
+/// The UTC day of a unix time
+#[must_use]
+pub fn day_of(secs: u64) -> NaiveDate {
+    chrono::NaiveDateTime::from_timestamp_opt(secs as i64, 0)
+        .unwrap_or_default()
+        .date()
+}
+
+/// The current UTC day
+#[must_use]
+pub fn today() -> NaiveDate {
+    day_of(unix_time())
+}
+
+/// Parse a `YYYY-MM-DD` day.
+///
+/// # Errors
+///
+/// Will return `Err` if the day is not a valid date.
+pub fn parse_day(day: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|_| Error::CustomError(format!("invalid day {day:?}, expected YYYY-MM-DD")))
+}
+
+/// (pubkey, kind, day) of a count
+type StatKey = (String, u64, NaiveDate);
+
+/// Counts not yet added to the database.  Clones share state.
+#[derive(Debug, Clone, Default)]
+pub struct WriteStats {
+    pending: Arc<Mutex<HashMap<StatKey, (u64, u64)>>>,
+}
+
+impl WriteStats {
+    /// Count an event written today.
+    pub fn record(&self, pubkey: &str, kind: u64, bytes: usize) {
+        self.add((pubkey.to_owned(), kind, today()), 1, bytes as u64);
+    }
+
+    fn add(&self, key: StatKey, count: u64, bytes: u64) {
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        let entry = pending.entry(key).or_default();
+        entry.0 += count;
+        entry.1 += bytes;
+    }
+
+    /// Remove and return the counts recorded so far.
+    #[must_use]
+    pub fn take(&self) -> Vec<EventStat> {
+        // swap the map out, so the writer only waits on the swap
+        let pending = match self.pending.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => return vec![],
+        };
+        pending
+            .into_iter()
+            .map(|((pubkey, kind, day), (count, bytes))| EventStat {
+                pubkey,
+                kind,
+                day,
+                count,
+                bytes,
+            })
+            .collect()
+    }
+
+    /// Put back counts that could not be flushed.
+    pub fn restore(&self, stats: Vec<EventStat>) {
+        for s in stats {
+            self.add((s.pubkey, s.kind, s.day), s.count, s.bytes);
+        }
+    }
+
+    /// Add the counts recorded so far to the database.  On failure,
+    /// they are kept for the next flush.  Returns the rows flushed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the counts could not be written.
+    pub async fn flush(&self, repo: &dyn NostrRepo) -> Result<usize> {
+        let stats = self.take();
+        if stats.is_empty() {
+            return Ok(0);
+        }
+        match repo.add_event_stats(&stats).await {
+            Ok(()) => Ok(stats.len()),
+            Err(e) => {
+                self.restore(stats);
+                Err(e)
+            }
+        }
+    }
+
+    /// Flush counts every `FLUSH_INTERVAL`, and once more when the
+    /// relay shuts down.
+    pub async fn run(self, repo: Arc<dyn NostrRepo>, mut shutdown: broadcast::Receiver<()>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let stopping = tokio::select! {
+                _ = interval.tick() => false,
+                _ = shutdown.recv() => true,
+            };
+            match self.flush(repo.as_ref()).await {
+                Ok(rows) => debug!("flushed {} write statistics", rows),
+                Err(e) => warn!("could not flush write statistics (kept for retry): {:?}", e),
+            }
+            if stopping {
+                break;
+            }
+        }
+    }
+}
+
+/// The authors that wrote the most events on a day, of one kind or of
+/// all kinds, read from the configured database.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be read.
+pub fn run_stats_top(
+    settings: &Settings,
+    day: NaiveDate,
+    kind: Option<u64>,
+    limit: u64,
+) -> Result<Vec<TopWriter>> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "stats",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                1,
+                1,
+                false,
+            );
+            let conn = pool.get()?;
+            sqlite::top_writers(&conn, day, kind, limit)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = postgres::pool_options(settings)
+                    .max_connections(2)
+                    .connect(&settings.database.connection)
+                    .await?;
+                postgres::top_writers(&pool, day, kind, limit).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_accumulate_until_taken() {
+        let stats = WriteStats::default();
+        stats.record("aa", 1, 100);
+        stats.record("aa", 1, 50);
+        stats.record("aa", 7, 10);
+        stats.record("bb", 1, 20);
+        let mut taken = stats.take();
+        taken.sort_by(|a, b| (&a.pubkey, a.kind).cmp(&(&b.pubkey, b.kind)));
+        let counts: Vec<_> = taken
+            .iter()
+            .map(|s| (s.pubkey.as_str(), s.kind, s.count, s.bytes))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("aa", 1, 2, 150), ("aa", 7, 1, 10), ("bb", 1, 1, 20)]
+        );
+        assert!(stats.take().is_empty());
+        // counts that could not be flushed are merged with new ones
+        stats.record("bb", 1, 5);
+        stats.restore(taken);
+        let bb: Vec<_> = stats
+            .take()
+            .into_iter()
+            .filter(|s| s.pubkey == "bb")
+            .collect();
+        assert_eq!((bb[0].count, bb[0].bytes), (2, 25));
+    }
+
+    #[test]
+    fn days_are_utc_dates() -> Result<()> {
+        assert_eq!(day_of(0), parse_day("1970-01-01")?);
+        assert_eq!(day_of(86400 * 2 - 1), parse_day("1970-01-02")?);
+        assert!(parse_day("2024-13-01").is_err());
+        Ok(())
+    }
+}
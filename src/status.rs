@@ -0,0 +1,285 @@
+//! Relay status, as relay-signed events and a JSON summary
+use crate::config::Settings;
+use crate::error::Result;
+use crate::event::{BroadcastEvent, Event};
+use crate::relay_keys::RelayKeys;
+use crate::repo::NostrRepo;
+use crate::server::NostrMetrics;
+use crate::utils::unix_time;
+use futures::{SinkExt, StreamExt};
+use nostr::key::Keys;
+use nostr::prelude::{Kind, Tag, TagKind};
+use nostr::EventBuilder;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Receiver;
+use tokio::time::Interval;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+/// `d` tag identifying status events, so replaceable kinds keep only
+/// the latest one.
+pub const STATUS_IDENTIFIER: &str = "nostr-rs-relay/status";
+
+/// How long to wait on another relay when publishing a status event
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Content of a status event
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayStatus {
+    /// Relay software version
+    pub version: String,
+    /// Seconds since the relay started
+    pub uptime: u64,
+    /// Stored events, excluding deleted ones
+    pub events: u64,
+    /// Currently connected websocket clients
+    pub clients: u64,
+}
+
+/// Periodically publishes a status event signed with the relay key
+pub struct StatusPublisher {
+    /// Repository for saving events and counting stored events
+    repo: Arc<dyn NostrRepo>,
+    /// Status events are broadcast on this channel to subscribers
+    event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+    /// Settings
+    settings: Settings,
+    /// Metrics, for the connected client count
+    metrics: NostrMetrics,
+    /// Relay keys
+    keys: Keys,
+    /// Interval for publishing status events (first tick is immediate)
+    interval: Interval,
+    /// When the relay started
+    started: Instant,
+}
+
+impl StatusPublisher {
+    /// Create a status publisher, signing with the relay keys.
+    #[must_use]
+    pub fn new(
+        repo: Arc<dyn NostrRepo>,
+        event_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+        settings: Settings,
+        metrics: NostrMetrics,
+        relay_keys: &RelayKeys,
+    ) -> Self {
+        let interval =
+            tokio::time::interval(Duration::from_secs(settings.status_events.interval_secs));
+        StatusPublisher {
+            repo,
+            event_tx,
+            settings,
+            metrics,
+            keys: relay_keys.keys().clone(),
+            interval,
+            started: Instant::now(),
+        }
+    }
+
+    /// Publish status events until the relay shuts down
+    pub async fn run(&mut self) {
+        info!(
+            "publishing status events as {}",
+            self.keys.public_key().to_string()
+        );
+        loop {
+            self.interval.tick().await;
+            if let Err(e) = self.publish().await {
+                warn!("could not publish status event: {:?}", e);
+            }
+        }
+    }
+
+    /// Build, store and broadcast a status event, and send it to any
+    /// configured relays.
+    async fn publish(&self) -> Result<()> {
+        let status = RelayStatus {
+            version: crate::info::CARGO_PKG_VERSION
+                .unwrap_or("unknown")
+                .to_owned(),
+            uptime: self.started.elapsed().as_secs(),
+            events: self.repo.count_events().await?,
+            clients: self.metrics.clients.get().max(0) as u64,
+        };
+        let event = status_event(&self.keys, self.settings.status_events.kind, &status)?;
+        self.repo.write_event(&event).await?;
+        self.event_tx.send(event.clone().into()).ok();
+        debug!("published status event: {}", event.get_event_id_prefix());
+        for relay in &self.settings.status_events.relays {
+            match tokio::time::timeout(RELAY_TIMEOUT, send_to_relay(relay, &event)).await {
+                Ok(Ok(())) => debug!("sent status event to {}", relay),
+                Ok(Err(e)) => warn!("could not send status event to {}: {:?}", relay, e),
+                Err(_) => warn!("timed out sending status event to {}", relay),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build a status event, signed with the given keys.
+///
+/// # Errors
+///
+/// Will return `Err` if the event could not be signed.
+pub fn status_event(keys: &Keys, kind: u64, status: &RelayStatus) -> Result<Event> {
+    let tags = [Tag::Generic(TagKind::D, vec![STATUS_IDENTIFIER.to_owned()])];
+    let content = serde_json::to_string(status)?;
+    let nostr_event = EventBuilder::new(Kind::from(kind), content, &tags).to_event(keys)?;
+    let mut event = Event::from(nostr_event);
+    event.build_index();
+    Ok(event)
+}
+
+/// Stored events, as last counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EventCounts {
+    total: u64,
+    last_day: u64,
+}
+
+/// A JSON summary of the relay, for dashboards.  Stored events are
+/// counted periodically by [`RelaySummary::run`], so serving the
+/// summary never queries the database.
+#[derive(Debug, Clone)]
+pub struct RelaySummary {
+    started: Instant,
+    counts: Arc<Mutex<Option<EventCounts>>>,
+}
+
+/// Body of a summary response
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SummaryReport {
+    /// Relay software version
+    pub version: String,
+    /// Seconds since the relay started
+    pub uptime: u64,
+    /// Stored events, excluding deleted ones (null until first counted)
+    pub events: Option<u64>,
+    /// Stored events first seen in the last 24 hours
+    pub events_last_day: Option<u64>,
+    /// Currently connected websocket clients
+    pub clients: u64,
+    /// Open subscriptions of connected clients
+    pub subscriptions: u64,
+}
+
+impl Default for RelaySummary {
+    fn default() -> Self {
+        RelaySummary {
+            started: Instant::now(),
+            counts: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl RelaySummary {
+    /// Recount stored events every `refresh`, until the relay shuts
+    /// down.
+    pub async fn run(
+        self,
+        repo: Arc<dyn NostrRepo>,
+        refresh: Duration,
+        mut shutdown: Receiver<()>,
+    ) {
+        let mut interval = tokio::time::interval(refresh);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.recv() => break,
+            };
+            if let Err(e) = self.refresh(repo.as_ref()).await {
+                warn!("could not count events for the status page: {:?}", e);
+            }
+        }
+    }
+
+    async fn refresh(&self, repo: &dyn NostrRepo) -> Result<()> {
+        let total = repo.count_events().await?;
+        let last_day = repo
+            .count_events_since(unix_time().saturating_sub(24 * 60 * 60))
+            .await?;
+        *self.counts.lock().unwrap() = Some(EventCounts { total, last_day });
+        Ok(())
+    }
+
+    /// The summary, with the last event counts, and the current
+    /// clients and subscriptions.
+    #[must_use]
+    pub fn report(&self, metrics: &NostrMetrics) -> SummaryReport {
+        let counts = *self.counts.lock().unwrap();
+        SummaryReport {
+            version: crate::info::CARGO_PKG_VERSION
+                .unwrap_or("unknown")
+                .to_owned(),
+            uptime: self.started.elapsed().as_secs(),
+            events: counts.map(|c| c.total),
+            events_last_day: counts.map(|c| c.last_day),
+            clients: metrics.clients.get().max(0) as u64,
+            subscriptions: metrics.subscriptions.get().max(0) as u64,
+        }
+    }
+}
+
+/// Send an event to another relay, and wait for its response.
+async fn send_to_relay(relay: &str, event: &Event) -> Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(relay).await?;
+    let msg = serde_json::to_string(&("EVENT", event))?;
+    ws.send(Message::Text(msg)).await?;
+    // the first text message should be the OK for our event
+    while let Some(msg) = ws.next().await {
+        if let Message::Text(reply) = msg? {
+            debug!("status event response from {}: {}", relay, reply);
+            break;
+        }
+    }
+    ws.close(None).await.ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_event_is_signed() -> Result<()> {
+        let keys = Keys::generate();
+        let status = RelayStatus {
+            version: "0.0.0".to_owned(),
+            uptime: 60,
+            events: 10,
+            clients: 2,
+        };
+        let event = status_event(&keys, 30078, &status)?;
+        event.validate()?;
+        assert_eq!(event.pubkey, keys.public_key().to_string());
+        assert_eq!(event.kind, 30078);
+        assert_eq!(event.distinct_param(), Some(STATUS_IDENTIFIER.to_owned()));
+        let content: serde_json::Value = serde_json::from_str(&event.content)?;
+        assert_eq!(content["events"], 10);
+        assert_eq!(content["clients"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn summary_reports_last_counts() {
+        let (_, metrics) = crate::server::create_metrics();
+        metrics.clients.set(3);
+        metrics.subscriptions.set(7);
+        let summary = RelaySummary::default();
+        let report = summary.report(&metrics);
+        assert_eq!(report.events, None);
+        assert_eq!(report.clients, 3);
+        assert_eq!(report.subscriptions, 7);
+        *summary.counts.lock().unwrap() = Some(EventCounts {
+            total: 100,
+            last_day: 12,
+        });
+        let report = serde_json::to_value(summary.report(&metrics)).unwrap();
+        assert_eq!(report["events"], 100);
+        assert_eq!(report["events_last_day"], 12);
+    }
+}
@@ -1,20 +1,43 @@
 //! Subscription and filter parsing
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{normalize_tag_value, Event};
+use crate::utils::{is_hex, is_lower_hex};
+use bitcoin_hashes::{sha256, Hash};
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// Subscription identifier and set of request filters
-#[derive(Serialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Subscription {
     pub id: String,
     pub filters: Vec<ReqFilter>,
+    /// Per-connection counter, assigned when the subscription is
+    /// registered, that distinguishes it from earlier subscriptions
+    /// with the same id.
+    #[serde(skip)]
+    pub generation: u64,
+    /// Was `since` set only by the relay's default window, rather
+    /// than by the client?
+    #[serde(skip)]
+    pub since_defaulted: bool,
 }
 
+// Subscriptions are equal if they request the same thing, regardless
+// of when they were registered.
+impl PartialEq for Subscription {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.filters == other.filters
+    }
+}
+
+impl Eq for Subscription {}
+
 /// Filter for requests
 ///
 /// Corresponds to client-provided subscription request elements.  Any
@@ -26,6 +49,9 @@ pub struct ReqFilter {
     pub ids: Option<Vec<String>>,
     /// Event kinds
     pub kinds: Option<Vec<u64>>,
+    /// Event kinds that never match, even if listed in `kinds`
+    /// (non-standard `_excludeKinds`, if enabled)
+    pub exclude_kinds: Option<Vec<u64>>,
     /// Events published after this time
     pub since: Option<u64>,
     /// Events published before this time
@@ -36,6 +62,15 @@ pub struct ReqFilter {
     pub limit: Option<u64>,
     /// Set of tags
     pub tags: Option<HashMap<char, HashSet<String>>>,
+    /// Events first seen by the relay at or after this time
+    /// (non-standard `_receivedSince`, only for authorized clients)
+    pub received_since: Option<u64>,
+    /// Events first seen by the relay at or after this server time,
+    /// for resuming a subscription (non-standard `_resumeFrom`)
+    pub resume_from: Option<u64>,
+    /// Whether progress messages are wanted while stored events are
+    /// sent (non-standard `_progress`)
+    pub progress: Option<bool>,
     /// Force no matches due to malformed data
     // we can't represent it in the req filter, so we don't want to
     // erroneously match.  This basically indicates the req tried to
@@ -55,6 +90,9 @@ impl Serialize for ReqFilter {
         if let Some(kinds) = &self.kinds {
             map.serialize_entry("kinds", &kinds)?;
         }
+        if let Some(exclude_kinds) = &self.exclude_kinds {
+            map.serialize_entry("_excludeKinds", &exclude_kinds)?;
+        }
         if let Some(until) = &self.until {
             map.serialize_entry("until", until)?;
         }
@@ -67,6 +105,15 @@ impl Serialize for ReqFilter {
         if let Some(authors) = &self.authors {
             map.serialize_entry("authors", &authors)?;
         }
+        if let Some(received_since) = &self.received_since {
+            map.serialize_entry("_receivedSince", received_since)?;
+        }
+        if let Some(resume_from) = &self.resume_from {
+            map.serialize_entry("_resumeFrom", resume_from)?;
+        }
+        if let Some(progress) = &self.progress {
+            map.serialize_entry("_progress", progress)?;
+        }
         // serialize tags
         if let Some(tags) = &self.tags {
             for (k, v) in tags {
@@ -78,6 +125,21 @@ impl Serialize for ReqFilter {
     }
 }
 
+/// Lowercase a hex id, pubkey or prefix, since they are stored in
+/// lowercase.
+fn lowercase_hex(values: Vec<String>) -> Vec<String> {
+    values
+        .into_iter()
+        .map(|v| {
+            if is_hex(&v) {
+                v.to_ascii_lowercase()
+            } else {
+                v
+            }
+        })
+        .collect()
+}
+
 impl<'de> Deserialize<'de> for ReqFilter {
     fn deserialize<D>(deserializer: D) -> Result<ReqFilter, D::Error>
     where
@@ -93,11 +155,15 @@ impl<'de> Deserialize<'de> for ReqFilter {
         let mut rf = ReqFilter {
             ids: None,
             kinds: None,
+            exclude_kinds: None,
             since: None,
             until: None,
             authors: None,
             limit: None,
             tags: None,
+            received_since: None,
+            resume_from: None,
+            progress: None,
             force_no_match: false,
         };
         let empty_string = "".into();
@@ -115,15 +181,23 @@ impl<'de> Deserialize<'de> for ReqFilter {
                         ));
                     }
                 }
-                rf.ids = raw_ids;
+                rf.ids = raw_ids.map(lowercase_hex);
             } else if key == "kinds" {
                 rf.kinds = Deserialize::deserialize(val).ok();
+            } else if key == "_excludeKinds" {
+                rf.exclude_kinds = Deserialize::deserialize(val).ok();
             } else if key == "since" {
                 rf.since = Deserialize::deserialize(val).ok();
             } else if key == "until" {
                 rf.until = Deserialize::deserialize(val).ok();
             } else if key == "limit" {
                 rf.limit = Deserialize::deserialize(val).ok();
+            } else if key == "_receivedSince" {
+                rf.received_since = Deserialize::deserialize(val).ok();
+            } else if key == "_resumeFrom" {
+                rf.resume_from = Deserialize::deserialize(val).ok();
+            } else if key == "_progress" {
+                rf.progress = Deserialize::deserialize(val).ok();
             } else if key == "authors" {
                 let raw_authors: Option<Vec<String>> = Deserialize::deserialize(val).ok();
                 if let Some(a) = raw_authors.as_ref() {
@@ -134,7 +208,7 @@ impl<'de> Deserialize<'de> for ReqFilter {
                         ));
                     }
                 }
-                rf.authors = raw_authors;
+                rf.authors = raw_authors.map(lowercase_hex);
             } else if key.starts_with('#') && key.len() > 1 && val.is_array() {
                 if let Some(tag_search) = tag_search_char_from_filter(key) {
                     if ts.is_none() {
@@ -144,7 +218,11 @@ impl<'de> Deserialize<'de> for ReqFilter {
                     if let Some(m) = ts.as_mut() {
                         let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
                         if let Some(v) = tag_vals {
-                            let hs = v.into_iter().collect::<HashSet<_>>();
+                            let name = tag_search.to_string();
+                            let hs = v
+                                .iter()
+                                .map(|val| normalize_tag_value(&name, val).into_owned())
+                                .collect::<HashSet<_>>();
                             m.insert(tag_search.to_owned(), hs);
                         }
                     };
@@ -160,6 +238,257 @@ impl<'de> Deserialize<'de> for ReqFilter {
     }
 }
 
+/// Maximum nesting of arrays and objects in a REQ message.  Valid
+/// requests never nest deeper than 3 (message, filter, values).
+const MAX_REQ_DEPTH: usize = 8;
+
+/// Maximum number of unrecognized keys allowed in a single filter
+const MAX_UNKNOWN_FILTER_KEYS: usize = 8;
+
+/// Limits checked on REQ messages before they are deserialized.
+#[derive(Debug, Clone, Default)]
+pub struct ReqLimits {
+    /// Maximum size of the message in bytes
+    pub max_bytes: Option<usize>,
+    /// Maximum number of values in any array (ids, authors, kinds, tags)
+    pub max_values: Option<usize>,
+    /// Maximum number of ids in a filter with no other conditions,
+    /// which is answered by direct lookups and can be larger
+    pub max_ids: Option<usize>,
+}
+
+/// A REQ message that exceeded [`ReqLimits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReqLimitViolation {
+    /// Subscription id, if it could be read before the violation
+    pub sub_id: Option<String>,
+    pub reason: String,
+}
+
+/// Is a filter key one that we know how to handle?
+fn is_known_filter_key(key: &str) -> bool {
+    matches!(
+        key,
+        "ids"
+            | "authors"
+            | "kinds"
+            | "since"
+            | "until"
+            | "limit"
+            | "_receivedSince"
+            | "_resumeFrom"
+            | "_excludeKinds"
+            | "_progress"
+    ) || key.starts_with('#')
+}
+
+enum Container<'a> {
+    Array {
+        commas: usize,
+        /// Is this the `ids` array of a filter?
+        ids: bool,
+        /// Does it hold id prefixes, which need index scans?
+        prefixes: bool,
+    },
+    Object {
+        expecting_key: bool,
+        keys: HashSet<&'a str>,
+        last_key: Option<&'a str>,
+        unknown: usize,
+        /// Holds more ids than `max_values` allows
+        bulk_ids: bool,
+    },
+}
+
+/// Keys allowed in a filter whose ids exceed `max_values`
+fn is_direct_fetch_key(key: &str) -> bool {
+    matches!(key, "ids" | "limit" | "_progress")
+}
+
+/// Scan a REQ message for pathological structure, without allocating
+/// the values it contains.  Enforces the message size, nesting depth,
+/// number of array elements, duplicate filter keys, and number of
+/// unknown filter keys.  The `ids` of a filter may hold up to
+/// `max_ids` values, if they are all complete ids and the filter has
+/// no other conditions.  Other messages, and malformed JSON, pass
+/// through to normal parsing.
+pub fn check_req_limits(
+    msg: &str,
+    limits: &ReqLimits,
+) -> std::result::Result<(), ReqLimitViolation> {
+    let is_req = msg
+        .trim_start()
+        .strip_prefix('[')
+        .map_or(false, |rest| rest.trim_start().starts_with("\"REQ\""));
+    if !is_req {
+        return Ok(());
+    }
+    let mut sub_id: Option<String> = None;
+    let violation = |sub_id: &Option<String>, reason: String| ReqLimitViolation {
+        sub_id: sub_id.clone(),
+        reason,
+    };
+    if let Some(max_bytes) = limits.max_bytes {
+        if max_bytes > 0 && msg.len() > max_bytes {
+            return Err(violation(
+                &sub_id,
+                format!("message too large (max {max_bytes} bytes)"),
+            ));
+        }
+    }
+    let bytes = msg.as_bytes();
+    let mut stack: Vec<Container> = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                // find the end of the string, skipping escapes
+                let start = i + 1;
+                let mut end = start;
+                while end < bytes.len() && bytes[end] != b'"' {
+                    if bytes[end] == b'\\' {
+                        end += 1;
+                    }
+                    end += 1;
+                }
+                let end = end.min(bytes.len());
+                let text = &msg[start..end];
+                let depth = stack.len();
+                match stack.last_mut() {
+                    Some(Container::Object {
+                        expecting_key,
+                        keys,
+                        last_key,
+                        unknown,
+                        ..
+                    }) if *expecting_key => {
+                        *expecting_key = false;
+                        *last_key = Some(text);
+                        // keys of a filter object
+                        if depth == 2 {
+                            if !keys.insert(text) {
+                                return Err(violation(
+                                    &sub_id,
+                                    format!("duplicate filter key {text:?}"),
+                                ));
+                            }
+                            if !is_known_filter_key(text) {
+                                *unknown += 1;
+                                if *unknown > MAX_UNKNOWN_FILTER_KEYS {
+                                    return Err(violation(
+                                        &sub_id,
+                                        "too many unknown filter keys".to_owned(),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    Some(Container::Array { commas: 1, .. }) if depth == 1 && sub_id.is_none() => {
+                        sub_id = Some(text.to_owned());
+                    }
+                    Some(Container::Array {
+                        ids: true,
+                        prefixes,
+                        ..
+                    }) if text.len() != 64 => *prefixes = true,
+                    _ => {}
+                }
+                i = end;
+            }
+            b'[' | b'{' => {
+                if stack.len() >= MAX_REQ_DEPTH {
+                    return Err(violation(&sub_id, "message nested too deeply".to_owned()));
+                }
+                let ids = stack.len() == 2
+                    && matches!(
+                        stack.last(),
+                        Some(Container::Object {
+                            last_key: Some("ids"),
+                            ..
+                        })
+                    );
+                stack.push(if bytes[i] == b'[' {
+                    Container::Array {
+                        commas: 0,
+                        ids,
+                        prefixes: false,
+                    }
+                } else {
+                    Container::Object {
+                        expecting_key: true,
+                        keys: HashSet::new(),
+                        last_key: None,
+                        unknown: 0,
+                        bulk_ids: false,
+                    }
+                });
+            }
+            b']' | b'}' => match stack.pop() {
+                Some(Container::Object {
+                    keys,
+                    bulk_ids: true,
+                    ..
+                }) if !keys.iter().all(|k| is_direct_fetch_key(k)) => {
+                    return Err(violation(&sub_id, too_many_values(limits)));
+                }
+                // a prefix after the ids went over max_values
+                Some(Container::Array {
+                    commas,
+                    prefixes: true,
+                    ..
+                }) if limits.max_values.map_or(false, |max| commas >= max) => {
+                    return Err(violation(&sub_id, too_many_values(limits)));
+                }
+                _ => {}
+            },
+            b',' => match (stack.len(), stack.last_mut()) {
+                (
+                    depth,
+                    Some(Container::Array {
+                        commas,
+                        ids,
+                        prefixes,
+                    }),
+                ) => {
+                    *commas += 1;
+                    let (commas, ids) = (*commas, *ids && !*prefixes);
+                    // the message array itself holds the filters,
+                    // which are limited elsewhere.
+                    if let Some(max_values) = limits.max_values {
+                        if depth > 1 && commas >= max_values {
+                            let within_ids =
+                                ids && limits.max_ids.map_or(false, |max_ids| commas < max_ids);
+                            if !within_ids {
+                                return Err(violation(&sub_id, too_many_values(limits)));
+                            }
+                            if let Some(Container::Object { bulk_ids, .. }) =
+                                stack.get_mut(depth - 2)
+                            {
+                                *bulk_ids = true;
+                            }
+                        }
+                    }
+                }
+                (_, Some(Container::Object { expecting_key, .. })) => *expecting_key = true,
+                (_, None) => {}
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+fn too_many_values(limits: &ReqLimits) -> String {
+    match (limits.max_values, limits.max_ids) {
+        (Some(max_values), Some(max_ids)) if max_ids > max_values => format!(
+            "too many values in filter (max {max_values}, or {max_ids} complete ids in a filter with only ids)"
+        ),
+        (Some(max_values), _) => format!("too many values in filter (max {max_values})"),
+        (None, _) => "too many values in filter".to_owned(),
+    }
+}
+
 /// Attempt to form a single-char identifier from a tag search filter
 fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
     let tagname_nohash = &tagname[1..];
@@ -229,6 +558,8 @@ impl<'de> Deserialize<'de> for Subscription {
         Ok(Subscription {
             id: sub_id.to_owned(),
             filters,
+            generation: 0,
+            since_defaulted: false,
         })
     }
 }
@@ -247,6 +578,150 @@ impl Subscription {
         self.filters.iter().any(|f| f.limit != Some(0))
     }
 
+    /// Prevent filters that only request the given kinds from
+    /// matching anything.  Used to avoid querying for events the
+    /// client could never be allowed to read.
+    pub fn deny_kinds(&mut self, kinds: &[u64]) {
+        for f in &mut self.filters {
+            if f.only_kinds(kinds) {
+                f.force_no_match = true;
+            }
+        }
+    }
+
+    /// Drop any `_receivedSince` constraints, for clients that are not
+    /// allowed to query by first-seen time.
+    pub fn ignore_received_since(&mut self) {
+        for f in &mut self.filters {
+            f.received_since = None;
+        }
+    }
+
+    /// Drop any `_excludeKinds` constraints, when the extension is not
+    /// enabled.
+    pub fn ignore_exclude_kinds(&mut self) {
+        for f in &mut self.filters {
+            f.exclude_kinds = None;
+        }
+    }
+
+    /// Should progress messages be sent while stored events are sent?
+    /// Any filter may opt in (or out) with `_progress`; otherwise,
+    /// `default` applies.
+    #[must_use]
+    pub fn wants_progress(&self, default: bool) -> bool {
+        let mut choices = self.filters.iter().filter_map(|f| f.progress);
+        match choices.next() {
+            Some(first) => first || choices.any(|p| p),
+            None => default,
+        }
+    }
+
+    /// Should events be delivered with the time they were first seen?
+    #[must_use]
+    pub fn wants_first_seen(&self) -> bool {
+        self.filters.iter().any(|f| f.received_since.is_some())
+    }
+
+    /// Is this subscription resuming from a server time?  If so, the
+    /// client is sent a new resume point with EOSE.
+    #[must_use]
+    pub fn is_resumable(&self) -> bool {
+        self.filters.iter().any(|f| f.resume_from.is_some())
+    }
+
+    /// Apply the relay's limits: `default_limit` to filters without a
+    /// `limit` (except those asking for explicit ids, whose results
+    /// are already bounded), and `max_limit` as a ceiling on the rest.
+    /// Returns whether a limit the client asked for was reduced.
+    pub fn apply_limits(&mut self, default_limit: Option<u64>, max_limit: Option<u64>) -> bool {
+        let mut clamped = false;
+        for f in &mut self.filters {
+            match (f.limit, max_limit) {
+                (Some(lim), Some(max)) if lim > max => {
+                    f.limit = Some(max);
+                    clamped = true;
+                }
+                (None, _) if f.ids.is_none() => {
+                    f.limit = match (default_limit, max_limit) {
+                        (Some(default), Some(max)) => Some(default.min(max)),
+                        (default, _) => default,
+                    };
+                }
+                _ => {}
+            }
+        }
+        clamped
+    }
+
+    /// Start filters with no time bounds at `since`, so open-ended
+    /// subscriptions only cover a recent window.  Filters with `since`,
+    /// `until`, explicit ids, or a first-seen time are left alone.
+    /// Returns whether any filter was changed.
+    pub fn apply_default_since(&mut self, since: u64) -> bool {
+        let client_since = self.has_since();
+        let mut changed = false;
+        for f in &mut self.filters {
+            if f.since.is_none()
+                && f.until.is_none()
+                && f.ids.is_none()
+                && f.first_seen_since().is_none()
+            {
+                f.since = Some(since);
+                changed = true;
+            }
+        }
+        self.since_defaulted = changed && !client_since;
+        changed
+    }
+
+    /// Does any filter constrain results with a `since` set by the
+    /// client?
+    #[must_use]
+    pub fn has_since(&self) -> bool {
+        !self.since_defaulted && self.filters.iter().any(|f| f.since.is_some())
+    }
+
+    /// Start every filter at `since`, unless the client already set
+    /// `since` on any of them, which is never overridden.  A default
+    /// `since` is replaced.  Returns whether the filters were changed.
+    pub fn fill_since(&mut self, since: u64) -> bool {
+        if self.has_since() {
+            return false;
+        }
+        for f in &mut self.filters {
+            f.since = Some(since);
+        }
+        self.since_defaulted = false;
+        true
+    }
+
+    /// The same subscription, limited to events first seen at or after
+    /// `since`, with no `limit`.  Finds live events that arrived while
+    /// stored events were sent.
+    #[must_use]
+    pub fn catch_up(&self, since: u64) -> Subscription {
+        let mut s = self.clone();
+        for f in &mut s.filters {
+            f.resume_from = f.resume_from.max(Some(since));
+            f.limit = None;
+        }
+        s
+    }
+
+    /// Hash of the subscription id and filters, ignoring `since`, that
+    /// is the same for any REQ asking for the same events.  The order
+    /// of filters, and of values within them, does not matter.
+    #[must_use]
+    pub fn watermark_key(&self) -> String {
+        let mut filters: Vec<String> = self.filters.iter().map(ReqFilter::canonical_key).collect();
+        filters.sort();
+        filters.dedup();
+        let key = serde_json::to_string(&(&self.id, filters)).unwrap_or_default();
+        let digest: sha256::Hash = sha256::Hash::hash(key.as_bytes());
+        format!("{digest:x}")
+    }
+
     /// Determine if this subscription matches a given [`Event`].  Any
     /// individual filter match is sufficient.
     #[must_use]
@@ -271,6 +746,90 @@ fn prefix_match(prefixes: &[String], target: &str) -> bool {
 }
 
 impl ReqFilter {
+    /// Serialized form of everything but `since`, with values sorted,
+    /// so equivalent filters produce the same key.
+    fn canonical_key(&self) -> String {
+        fn sorted<T: Ord + Clone>(v: &Option<Vec<T>>) -> Option<Vec<T>> {
+            v.as_ref().map(|v| {
+                let mut v = v.clone();
+                v.sort();
+                v.dedup();
+                v
+            })
+        }
+        let tags: Option<BTreeMap<char, BTreeSet<&String>>> = self
+            .tags
+            .as_ref()
+            .map(|tags| tags.iter().map(|(k, v)| (*k, v.iter().collect())).collect());
+        let key = (
+            sorted(&self.ids),
+            sorted(&self.kinds),
+            sorted(&self.authors),
+            tags,
+            self.until,
+            self.limit,
+            self.received_since,
+            self.resume_from,
+            self.force_no_match,
+        );
+        match sorted(&self.exclude_kinds) {
+            // keys of filters without exclusions are unchanged, so
+            // stored watermarks still apply
+            None => serde_json::to_string(&key),
+            Some(excluded) => serde_json::to_string(&(key, excluded)),
+        }
+        .unwrap_or_default()
+    }
+
+    /// First-seen time requested, by either `_receivedSince` or
+    /// `_resumeFrom`.  With both, the later one applies, since events
+    /// must satisfy each.
+    #[must_use]
+    pub fn first_seen_since(&self) -> Option<u64> {
+        self.received_since.max(self.resume_from)
+    }
+
+    /// Is this a direct fetch: only complete event ids, with no other
+    /// conditions, and no limit that could cut the results short?
+    /// These are answered by primary key lookups.
+    #[must_use]
+    pub fn is_direct_fetch(&self) -> bool {
+        let ids = match &self.ids {
+            Some(ids) => ids,
+            None => return false,
+        };
+        !ids.is_empty()
+            && ids.iter().all(|id| id.len() == 64 && is_lower_hex(id))
+            && self.limit.map_or(true, |lim| lim >= ids.len() as u64)
+            && self.kinds.is_none()
+            && self.exclude_kinds.is_none()
+            && self.authors.is_none()
+            && self.tags.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.first_seen_since().is_none()
+            && !self.force_no_match
+    }
+
+    /// Split a direct fetch into filters of at most `max_ids` ids.
+    /// Other filters are returned unchanged.
+    #[must_use]
+    pub fn split_direct_fetch(&self, max_ids: usize) -> Vec<Cow<'_, ReqFilter>> {
+        match &self.ids {
+            Some(ids) if ids.len() > max_ids && max_ids > 0 && self.is_direct_fetch() => ids
+                .chunks(max_ids)
+                .map(|chunk| {
+                    Cow::Owned(ReqFilter {
+                        ids: Some(chunk.to_vec()),
+                        limit: None,
+                        ..self.clone()
+                    })
+                })
+                .collect(),
+            _ => vec![Cow::Borrowed(self)],
+        }
+    }
+
     fn ids_match(&self, event: &Event) -> bool {
         self.ids
             .as_ref()
@@ -312,6 +871,18 @@ impl ReqFilter {
     /// Check if this filter either matches, or does not care about the kind.
     fn kind_match(&self, kind: u64) -> bool {
         self.kinds.as_ref().map_or(true, |ks| ks.contains(&kind))
+            && !self
+                .exclude_kinds
+                .as_ref()
+                .map_or(false, |ks| ks.contains(&kind))
+    }
+
+    /// Check if this filter is restricted to a subset of the given kinds.
+    #[must_use]
+    pub fn only_kinds(&self, kinds: &[u64]) -> bool {
+        self.kinds
+            .as_ref()
+            .map_or(false, |ks| ks.iter().all(|k| kinds.contains(k)))
     }
 
     /// Determine if all populated fields in this filter match the provided event.
@@ -429,6 +1000,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn interest_mixed_case_hex() -> Result<()> {
+        let id = "ab".repeat(32);
+        let pubkey = "cd".repeat(32);
+        let referenced = "ef".repeat(32);
+        let raw_json = format!(
+            r##"["REQ","xyz",{{"ids":["{}"],"authors":["CD"],"#e":["{}"],"#t":["Nostr"]}}]"##,
+            id.to_uppercase(),
+            "eF".repeat(32)
+        );
+        let s: Subscription = serde_json::from_str(&raw_json)?;
+        let f = &s.filters[0];
+        assert_eq!(f.ids, Some(vec![id.clone()]));
+        assert_eq!(f.authors, Some(vec!["cd".to_owned()]));
+        let tags = f.tags.as_ref().unwrap();
+        assert!(tags[&'e'].contains(&referenced));
+        // only ids and pubkeys are lowercased
+        assert!(tags[&'t'].contains("Nostr"));
+        // an event tagged in uppercase matches too
+        let mut e = Event {
+            id,
+            pubkey,
+            delegated_by: None,
+            created_at: 0,
+            kind: 1,
+            tags: vec![
+                vec!["e".to_owned(), referenced.to_uppercase()],
+                vec!["t".to_owned(), "Nostr".to_owned()],
+            ],
+            content: "".to_owned(),
+            sig: "".to_owned(),
+            tagidx: None,
+        };
+        e.build_index();
+        assert!(s.interested_in_event(&e));
+        Ok(())
+    }
+
     #[test]
     fn interest_id_prefix_match() -> Result<()> {
         // subscription with a filter for ID
@@ -647,4 +1256,382 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn deny_restricted_kinds() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"kinds":[1059]},{"kinds":[4,1059]},{"kinds":[1,1059]},{"authors":["abc"]}]"#,
+        )?;
+        s.deny_kinds(&[4, 44, 1059]);
+        let denied: Vec<bool> = s.filters.iter().map(|f| f.force_no_match).collect();
+        // only filters requesting nothing but restricted kinds are denied
+        assert_eq!(denied, vec![true, true, false, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn received_since_filter() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"kinds":[1],"_receivedSince":1700000000},{"kinds":[7]}]"#,
+        )?;
+        assert_eq!(s.filters[0].received_since, Some(1_700_000_000));
+        assert_eq!(s.filters[1].received_since, None);
+        assert!(s.wants_first_seen());
+        // unauthorized clients get the filter as if the key was absent
+        s.ignore_received_since();
+        assert!(!s.wants_first_seen());
+        assert_eq!(s.filters[0].kinds, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn resume_from_filter() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"kinds":[1],"_resumeFrom":1700000000,"_receivedSince":1600000000}]"#,
+        )?;
+        assert!(s.is_resumable());
+        // the later of the two receive times applies
+        assert_eq!(s.filters[0].first_seen_since(), Some(1_700_000_000));
+        // resuming is open to any client, unlike first-seen times
+        s.ignore_received_since();
+        assert!(s.is_resumable());
+        assert!(!s.wants_first_seen());
+        assert_eq!(s.filters[0].first_seen_since(), Some(1_700_000_000));
+        let plain: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]}]"#)?;
+        assert!(!plain.is_resumable());
+        Ok(())
+    }
+
+    #[test]
+    fn exclude_kinds_filter() -> Result<()> {
+        let mut s: Subscription = serde_json::from_str(
+            r#"["REQ","xyz",{"kinds":[1,7],"authors":["abc"],"_excludeKinds":[7]}]"#,
+        )?;
+        let mut e = Event {
+            id: "abcde".to_owned(),
+            pubkey: "abc".to_owned(),
+            delegated_by: None,
+            created_at: 0,
+            kind: 1,
+            tags: Vec::new(),
+            content: "".to_owned(),
+            sig: "".to_owned(),
+            tagidx: None,
+        };
+        assert!(s.interested_in_event(&e));
+        // excluded, even though kinds includes it
+        e.kind = 7;
+        assert!(!s.interested_in_event(&e));
+        // other conditions still apply
+        e.kind = 1;
+        e.pubkey = "def".to_owned();
+        assert!(!s.interested_in_event(&e));
+        let json = serde_json::to_string(&s.filters[0])?;
+        assert!(json.contains(r#""_excludeKinds":[7]"#));
+        // without the extension, the exclusion is ignored
+        s.ignore_exclude_kinds();
+        e.pubkey = "abc".to_owned();
+        e.kind = 7;
+        assert!(s.interested_in_event(&e));
+        // an exclusion alone matches every other kind
+        let only: Subscription = serde_json::from_str(r#"["REQ","xyz",{"_excludeKinds":[7]}]"#)?;
+        assert!(!only.interested_in_event(&e));
+        e.kind = 30023;
+        assert!(only.interested_in_event(&e));
+        Ok(())
+    }
+
+    #[test]
+    fn progress_opt_in() -> Result<()> {
+        let asked: Subscription =
+            serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]},{"kinds":[7],"_progress":true}]"#)?;
+        assert!(asked.wants_progress(false));
+        let declined: Subscription =
+            serde_json::from_str(r#"["REQ","xyz",{"kinds":[1],"_progress":false}]"#)?;
+        assert!(!declined.wants_progress(true));
+        let plain: Subscription = serde_json::from_str(r#"["REQ","xyz",{"kinds":[1]}]"#)?;
+        assert!(!plain.wants_progress(false));
+        assert!(plain.wants_progress(true));
+        // the option is not a condition, so direct fetches still apply
+        let ids: Subscription = serde_json::from_str(&format!(
+            r#"["REQ","xyz",{{"ids":["{}"],"_progress":true}}]"#,
+            "ab".repeat(32)
+        ))?;
+        assert!(ids.filters[0].is_direct_fetch());
+        Ok(())
+    }
+
+    #[test]
+    fn req_limits_pass_normal_requests() {
+        let limits = ReqLimits {
+            max_bytes: Some(1024),
+            max_values: Some(3),
+            max_ids: None,
+        };
+        let raw =
+            r##"["REQ","sub",{"ids":["a","b","c"],"#e":["x"],"kinds":[1]},{"authors":["b"]}]"##;
+        assert_eq!(check_req_limits(raw, &limits), Ok(()));
+        // other message types are not checked
+        assert_eq!(
+            check_req_limits(r#"["EVENT",[[[[[[[[[[1]]]]]]]]]]]"#, &limits),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn req_limits_array_values() {
+        let limits = ReqLimits {
+            max_bytes: None,
+            max_values: Some(3),
+            max_ids: None,
+        };
+        let err =
+            check_req_limits(r#"["REQ","sub",{"ids":["a","b","c","d"]}]"#, &limits).unwrap_err();
+        assert_eq!(err.sub_id, Some("sub".to_owned()));
+        assert!(err.reason.contains("too many values"));
+    }
+
+    #[test]
+    fn req_limits_direct_fetch_ids() {
+        let limits = ReqLimits {
+            max_bytes: None,
+            max_values: Some(3),
+            max_ids: Some(5),
+        };
+        let ids = |n: usize| {
+            (0..n)
+                .map(|i| format!("\"{i:064x}\""))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let ok = format!(r#"["REQ","sub",{{"ids":[{}],"limit":5}}]"#, ids(5));
+        assert_eq!(check_req_limits(&ok, &limits), Ok(()));
+        let too_many = format!(r#"["REQ","sub",{{"ids":[{}]}}]"#, ids(6));
+        assert!(check_req_limits(&too_many, &limits).is_err());
+        // the larger cap only applies with no other conditions
+        let with_kinds = format!(r#"["REQ","sub",{{"ids":[{}],"kinds":[1]}}]"#, ids(4));
+        assert!(check_req_limits(&with_kinds, &limits)
+            .unwrap_err()
+            .reason
+            .contains("only ids"));
+        let kinds_first = format!(r#"["REQ","sub",{{"kinds":[1],"ids":[{}]}}]"#, ids(4));
+        assert!(check_req_limits(&kinds_first, &limits).is_err());
+        // and only to complete ids, as prefixes are not direct lookups
+        let prefixes = r#"["REQ","sub",{"ids":["a","b","c","d"]}]"#;
+        assert!(check_req_limits(prefixes, &limits).is_err());
+        let prefix_last = format!(r#"["REQ","sub",{{"ids":[{},"ab"]}}]"#, ids(4));
+        assert!(check_req_limits(&prefix_last, &limits).is_err());
+        let within_max_values = format!(r#"["REQ","sub",{{"ids":[{},"ab"]}}]"#, ids(2));
+        assert_eq!(check_req_limits(&within_max_values, &limits), Ok(()));
+        // and only to ids
+        let authors = r#"["REQ","sub",{"authors":["a","b","c","d"]}]"#;
+        assert!(check_req_limits(authors, &limits).is_err());
+    }
+
+    #[test]
+    fn direct_fetch_split() -> Result<()> {
+        let ids: Vec<String> = (0..5u8).map(|i| format!("{i:02x}").repeat(32)).collect();
+        let f: ReqFilter = serde_json::from_value(serde_json::json!({ "ids": ids }))?;
+        assert!(f.is_direct_fetch());
+        let chunks = f.split_direct_fetch(2);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].ids, Some(vec![ids[4].clone()]));
+        // prefixes, other conditions, and small limits are not direct
+        for other in [
+            serde_json::json!({ "ids": ["abcd"] }),
+            serde_json::json!({ "ids": ids, "kinds": [1] }),
+            serde_json::json!({ "ids": ids, "limit": 1 }),
+            serde_json::json!({ "ids": [] }),
+        ] {
+            let f: ReqFilter = serde_json::from_value(other)?;
+            assert!(!f.is_direct_fetch());
+            assert_eq!(f.split_direct_fetch(1).len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn req_limits_structure() {
+        let limits = ReqLimits::default();
+        let deep = format!(
+            r#"["REQ","sub",{{"ids":{}{}}}]"#,
+            "[".repeat(20),
+            "]".repeat(20)
+        );
+        assert!(check_req_limits(&deep, &limits).is_err());
+        let dup = r#"["REQ","sub",{"kinds":[1],"kinds":[2]}]"#;
+        assert!(check_req_limits(dup, &limits)
+            .unwrap_err()
+            .reason
+            .contains("duplicate"));
+        let unknown: Vec<String> = (0..20).map(|i| format!(r#""k{i}":1"#)).collect();
+        let unknown = format!(r#"["REQ","sub",{{{}}}]"#, unknown.join(","));
+        assert!(check_req_limits(&unknown, &limits).is_err());
+        // keys inside nested values are not filter keys
+        let nested = r#"["REQ","sub",{"ids":[{"a":1,"a":2}]}]"#;
+        assert_eq!(check_req_limits(nested, &limits), Ok(()));
+        let big = ReqLimits {
+            max_bytes: Some(10),
+            max_values: None,
+            max_ids: None,
+        };
+        assert!(check_req_limits(r#"["REQ","sub",{}]"#, &big).is_err());
+    }
+
+    #[test]
+    fn req_limits_fuzz() {
+        use rand::{Rng, SeedableRng};
+        use std::time::{Duration, Instant};
+        let limits = ReqLimits {
+            max_bytes: Some(1 << 20),
+            max_values: Some(1000),
+            max_ids: None,
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        // generate random JSON values, some of them very deep or wide
+        fn gen(rng: &mut rand::rngs::StdRng, depth: usize) -> Value {
+            match rng.gen_range(0..6) {
+                0 if depth < 40 => Value::Array(
+                    (0..rng.gen_range(0..4))
+                        .map(|_| gen(rng, depth + 1))
+                        .collect(),
+                ),
+                1 if depth < 40 => {
+                    let mut m = serde_json::Map::new();
+                    for _ in 0..rng.gen_range(0..4) {
+                        let key = ["ids", "#e", "kinds", "x\"y", "\\"][rng.gen_range(0..5)];
+                        m.insert(key.to_owned(), gen(rng, depth + 1));
+                    }
+                    Value::Object(m)
+                }
+                2 => Value::String("\"]}[{,\\".to_owned()),
+                3 => Value::Array(vec![Value::from(1); rng.gen_range(0..3000)]),
+                _ => Value::from(rng.gen::<u32>()),
+            }
+        }
+        let start = Instant::now();
+        for _ in 0..500 {
+            let filters: Vec<Value> = (0..rng.gen_range(1..4)).map(|_| gen(&mut rng, 2)).collect();
+            let mut msg = vec![Value::from("REQ"), Value::from("fuzz")];
+            msg.extend(filters);
+            let mut raw = Value::Array(msg).to_string();
+            // occasionally corrupt the message
+            if rng.gen_bool(0.2) {
+                raw.truncate(rng.gen_range(0..raw.len()));
+            }
+            if check_req_limits(&raw, &limits).is_ok() {
+                // anything that passes must be cheap to parse
+                let _ = serde_json::from_str::<Subscription>(&raw);
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn watermark_key_ignores_order_and_since() -> Result<()> {
+        let a: Subscription = serde_json::from_str(
+            r##"["REQ","feed",{"kinds":[1,6],"authors":["aa","bb"],"#t":["x","y"]},{"kinds":[0]}]"##,
+        )?;
+        let b: Subscription = serde_json::from_str(
+            r##"["REQ","feed",{"kinds":[0]},{"#t":["y","x"],"authors":["bb","aa"],"kinds":[6,1]}]"##,
+        )?;
+        assert_eq!(a.watermark_key(), b.watermark_key());
+        let mut since = a.clone();
+        assert!(since.fill_since(100));
+        assert_eq!(a.watermark_key(), since.watermark_key());
+        // a different id, limit, or set of values is a different request
+        let other_id: Subscription = serde_json::from_str(
+            r##"["REQ","other",{"kinds":[1,6],"authors":["aa","bb"],"#t":["x","y"]},{"kinds":[0]}]"##,
+        )?;
+        let other_limit: Subscription = serde_json::from_str(
+            r##"["REQ","feed",{"kinds":[1,6],"authors":["aa","bb"],"#t":["x","y"],"limit":10},{"kinds":[0]}]"##,
+        )?;
+        let other_tag: Subscription = serde_json::from_str(
+            r##"["REQ","feed",{"kinds":[1,6],"authors":["aa","bb"],"#t":["x"]},{"kinds":[0]}]"##,
+        )?;
+        for other in [other_id, other_limit, other_tag] {
+            assert_ne!(a.watermark_key(), other.watermark_key());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fill_since_never_overrides_client() -> Result<()> {
+        let mut s: Subscription =
+            serde_json::from_str(r#"["REQ","feed",{"kinds":[1]},{"kinds":[0]}]"#)?;
+        assert!(!s.has_since());
+        assert!(s.fill_since(500));
+        assert!(s.filters.iter().all(|f| f.since == Some(500)));
+        // a client-provided since, on any filter, is left alone
+        let mut s: Subscription =
+            serde_json::from_str(r#"["REQ","feed",{"kinds":[1],"since":10},{"kinds":[0]}]"#)?;
+        assert!(!s.fill_since(500));
+        assert_eq!(s.filters[0].since, Some(10));
+        assert_eq!(s.filters[1].since, None);
+        Ok(())
+    }
+
+    #[test]
+    fn catch_up_drops_limit() -> Result<()> {
+        let s: Subscription = serde_json::from_str(
+            r#"["REQ","feed",{"kinds":[1],"limit":10},{"kinds":[0],"_resumeFrom":900}]"#,
+        )?;
+        let c = s.catch_up(500);
+        assert_eq!(c.id, "feed");
+        assert_eq!(c.filters[0].limit, None);
+        assert_eq!(c.filters[0].first_seen_since(), Some(500));
+        // a later resume point is kept
+        assert_eq!(c.filters[1].first_seen_since(), Some(900));
+        assert_eq!(c.filters[0].kinds, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn limits_are_defaulted_and_clamped() -> Result<()> {
+        let raw = r#"["REQ","feed",{"kinds":[1]},{"kinds":[0],"limit":50},{"ids":["aa"]}]"#;
+        let mut s: Subscription = serde_json::from_str(raw)?;
+        assert!(!s.apply_limits(Some(100), Some(500)));
+        assert_eq!(s.filters[0].limit, Some(100));
+        assert_eq!(s.filters[1].limit, Some(50));
+        // explicit ids are exempt from the default
+        assert_eq!(s.filters[2].limit, None);
+        // client limits above the ceiling are reduced
+        let mut s: Subscription =
+            serde_json::from_str(r#"["REQ","feed",{"kinds":[1],"limit":1000},{"kinds":[0]}]"#)?;
+        assert!(s.apply_limits(Some(1000), Some(500)));
+        assert_eq!(s.filters[0].limit, Some(500));
+        assert_eq!(s.filters[1].limit, Some(500));
+        // with neither set, filters are unchanged
+        let mut s: Subscription = serde_json::from_str(raw)?;
+        assert!(!s.apply_limits(None, None));
+        assert_eq!(s.filters[0].limit, None);
+        Ok(())
+    }
+
+    #[test]
+    fn open_ended_filters_get_a_default_since() -> Result<()> {
+        let raw = r#"["REQ","feed",{"kinds":[1]},{"kinds":[1],"since":10},{"kinds":[1],"until":20},{"ids":["aa"]},{"_resumeFrom":30}]"#;
+        let mut s: Subscription = serde_json::from_str(raw)?;
+        assert!(s.apply_default_since(1000));
+        let since: Vec<Option<u64>> = s.filters.iter().map(|f| f.since).collect();
+        assert_eq!(since, vec![Some(1000), Some(10), None, None, None]);
+        // bounded filters are never changed
+        let mut s: Subscription =
+            serde_json::from_str(r#"["REQ","feed",{"kinds":[1],"since":10}]"#)?;
+        assert!(!s.apply_default_since(1000));
+        assert!(s.has_since());
+        Ok(())
+    }
+
+    #[test]
+    fn fill_since_replaces_a_default_since() -> Result<()> {
+        let mut s: Subscription =
+            serde_json::from_str(r#"["REQ","feed",{"kinds":[1]},{"kinds":[0]}]"#)?;
+        assert!(s.apply_default_since(1000));
+        assert!(!s.has_since());
+        assert!(s.fill_since(500));
+        assert!(s.filters.iter().all(|f| f.since == Some(500)));
+        assert!(s.has_since());
+        Ok(())
+    }
 }
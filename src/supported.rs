@@ -0,0 +1,111 @@
+//! Extension discovery with `SUPPORTED` messages
+//!
+//! Clients that do not fetch the relay information document (NIP-11)
+//! can ask which relay-specific extensions are enabled by sending
+//! `["SUPPORTED"]`.  The relay answers with the same capabilities it
+//! lists in NIP-11.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Version of the `SUPPORTED` response, increased if its format ever
+/// changes incompatibly.
+pub const SUPPORTED_VERSION: u32 = 1;
+
+/// Extension discovery request in network format
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct SupportedCmd {
+    /// Protocol command, expected to always be "SUPPORTED".
+    cmd: String,
+}
+
+impl From<SupportedCmd> for Result<()> {
+    fn from(sc: SupportedCmd) -> Result<()> {
+        // ensure command is correct
+        if sc.cmd == "SUPPORTED" {
+            Ok(())
+        } else {
+            Err(Error::CommandUnknownError)
+        }
+    }
+}
+
+/// Relay-specific protocol extensions enabled by this configuration
+#[must_use]
+pub fn capabilities(settings: &Settings) -> BTreeMap<String, bool> {
+    let enabled = [
+        ("close_all", settings.options.close_all),
+        ("exclude_kinds", settings.options.exclude_kinds),
+        ("newer_version", settings.options.send_newer_version),
+        ("progress", settings.options.req_progress_secs.is_some()),
+        ("received_since", settings.info.expose_first_seen),
+        (
+            "relay_proof",
+            settings.info.relay_secret_key.is_some()
+                || settings.info.relay_secret_key_file.is_some(),
+        ),
+        ("resume_from", true),
+        (
+            "resume_subscriptions",
+            settings.options.resume_subscriptions,
+        ),
+    ];
+    enabled
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, on)| (name.to_owned(), on))
+        .collect()
+}
+
+/// Response to a `SUPPORTED` request
+#[must_use]
+pub fn supported_response(settings: &Settings) -> Value {
+    json!([
+        "SUPPORTED",
+        {
+            "version": SUPPORTED_VERSION,
+            "capabilities": capabilities(settings),
+        }
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_supported_request() {
+        let sc: SupportedCmd = serde_json::from_str(r#"["SUPPORTED"]"#).unwrap();
+        assert!(Result::<()>::from(sc).is_ok());
+        let other: SupportedCmd = serde_json::from_str(r#"["CLOSE"]"#).unwrap();
+        assert!(Result::<()>::from(other).is_err());
+        assert!(serde_json::from_str::<SupportedCmd>(r#"["SUPPORTED", 1]"#).is_err());
+    }
+
+    #[test]
+    fn response_lists_enabled_extensions() {
+        let mut settings = Settings::default();
+        settings.options.req_progress_secs = None;
+        assert_eq!(
+            supported_response(&settings).to_string(),
+            r#"["SUPPORTED",{"version":1,"capabilities":{"resume_from":true}}]"#
+        );
+        settings.options.close_all = true;
+        settings.options.exclude_kinds = true;
+        settings.info.expose_first_seen = true;
+        let caps = capabilities(&settings);
+        assert_eq!(
+            caps.keys().collect::<Vec<_>>(),
+            vec![
+                "close_all",
+                "exclude_kinds",
+                "received_since",
+                "resume_from"
+            ]
+        );
+        settings.info.relay_secret_key_file = Some("relay.key".to_owned());
+        assert!(capabilities(&settings).contains_key("relay_proof"));
+    }
+}
@@ -0,0 +1,380 @@
+//! Seeded synthetic events, for benchmarks and tests
+//!
+//! `nostr-rs-relay gen-events` generates valid, signed events from a
+//! seeded RNG, and writes them as JSONL or into the configured
+//! database.  The same seed and [`GenProfile`] always produce the same
+//! events, so benchmark datasets can be rebuilt on any machine: ids are
+//! hashed from the canonical form that validation checks, and
+//! signatures are made without auxiliary randomness.  Generating events
+//! is only available in relays built with the `test-support` feature.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use std::fmt;
+use std::time::Duration;
+
+/// Kinds generated by default, with their relative weights
+pub const DEFAULT_KINDS: &str = "1:70,7:15,6:5,4:5,0:3,3:2";
+
+/// Shape of a generated dataset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenProfile {
+    pub count: u64,
+    pub seed: u64,
+    /// Kinds, with their relative weights
+    pub kinds: Vec<(u64, u32)>,
+    /// Distinct authors, chosen uniformly
+    pub pubkeys: usize,
+    /// Tags per event are chosen uniformly up to this many
+    pub max_tags: usize,
+    /// Content length is chosen uniformly up to this many bytes
+    pub max_content: usize,
+    /// Newest `created_at`; events are spread over `spread_secs` before it
+    pub until: u64,
+    pub spread_secs: u64,
+}
+
+impl Default for GenProfile {
+    fn default() -> Self {
+        GenProfile {
+            count: 100_000,
+            seed: 0,
+            kinds: parse_kind_weights(DEFAULT_KINDS).unwrap(),
+            pubkeys: 1000,
+            max_tags: 4,
+            max_content: 280,
+            until: 1_700_000_000,
+            spread_secs: 30 * 86400,
+        }
+    }
+}
+
+/// Parse kinds with relative weights, such as `1:70,7:30`.
+///
+/// # Errors
+///
+/// Will return `Err` if an entry is not `<kind>:<weight>`, or no kind
+/// has a positive weight.
+pub fn parse_kind_weights(kinds: &str) -> Result<Vec<(u64, u32)>> {
+    let mut weights = vec![];
+    for entry in kinds.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry
+            .split_once(':')
+            .and_then(|(k, w)| Some((k.trim().parse().ok()?, w.trim().parse().ok()?)));
+        match parsed {
+            Some(kind_weight) => weights.push(kind_weight),
+            None => {
+                return Err(Error::CustomError(format!(
+                    "expected <kind>:<weight>, found {entry:?}"
+                )))
+            }
+        }
+    }
+    if weights.iter().all(|(_, w)| *w == 0) {
+        return Err(Error::CustomError(
+            "at least one kind needs a positive weight".to_owned(),
+        ));
+    }
+    Ok(weights)
+}
+
+/// Where generated events are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenOutput {
+    /// A JSONL file, or stdout for `-`
+    Jsonl(String),
+    /// The configured database
+    Database,
+}
+
+/// Summary of a generated dataset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenReport {
+    pub events: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for GenReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "generated {} events in {:.1}s",
+            self.events,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Generate the events of a profile, and write them out.
+///
+/// # Errors
+///
+/// Will return `Err` if the relay was built without the `test-support`
+/// feature, or the events could not be written.
+#[cfg(not(feature = "test-support"))]
+pub fn run_gen_events(
+    _settings: &Settings,
+    _profile: &GenProfile,
+    _output: &GenOutput,
+) -> Result<GenReport> {
+    Err(Error::CustomError(
+        "gen-events needs a relay built with the test-support feature".to_owned(),
+    ))
+}
+
+#[cfg(feature = "test-support")]
+pub use enabled::{generate_events, run_gen_events, EventGenerator};
+
+#[cfg(feature = "test-support")]
+mod enabled {
+    use super::{Error, GenOutput, GenProfile, GenReport, Result, Settings};
+    use crate::db;
+    use crate::event::Event;
+    use crate::repo::NostrRepo;
+    use rand::distributions::{Alphanumeric, Distribution, WeightedIndex};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use secp256k1::{KeyPair, Message, Secp256k1, SecretKey, SignOnly, XOnlyPublicKey};
+    use std::io::{BufWriter, Write};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tracing::info;
+
+    /// Events written to the database concurrently
+    const DB_BATCH: usize = 500;
+
+    /// Distinct hashtags used in `t` tags
+    const TOPICS: u32 = 100;
+
+    /// Generates the events of a profile, in order
+    pub struct EventGenerator {
+        rng: StdRng,
+        secp: Secp256k1<SignOnly>,
+        authors: Vec<(KeyPair, String)>,
+        kinds: Vec<u64>,
+        kind_index: WeightedIndex<u32>,
+        /// Ids of earlier events, for `e` tags
+        ids: Vec<String>,
+        profile: GenProfile,
+        generated: u64,
+    }
+
+    impl EventGenerator {
+        /// Prepare to generate a profile.  The authors' keys are the
+        /// first values drawn from the seeded RNG.
+        ///
+        /// # Errors
+        ///
+        /// Will return `Err` if the profile has no authors or kinds.
+        pub fn new(profile: &GenProfile) -> Result<EventGenerator> {
+            if profile.pubkeys == 0 {
+                return Err(Error::CustomError(
+                    "at least one pubkey is needed".to_owned(),
+                ));
+            }
+            let kind_index = WeightedIndex::new(profile.kinds.iter().map(|(_, w)| *w))
+                .map_err(|e| Error::CustomError(format!("invalid kind weights: {e}")))?;
+            let mut rng = StdRng::seed_from_u64(profile.seed);
+            let secp = Secp256k1::signing_only();
+            let mut authors = Vec::with_capacity(profile.pubkeys);
+            while authors.len() < profile.pubkeys {
+                // out of range keys (vanishingly rare) are skipped
+                if let Ok(sk) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+                    let keypair = KeyPair::from_secret_key(&secp, sk);
+                    let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_string();
+                    authors.push((keypair, pubkey));
+                }
+            }
+            Ok(EventGenerator {
+                rng,
+                secp,
+                authors,
+                kinds: profile.kinds.iter().map(|(k, _)| *k).collect(),
+                kind_index,
+                ids: vec![],
+                profile: profile.clone(),
+                generated: 0,
+            })
+        }
+
+        fn tag(&mut self) -> Vec<String> {
+            match self.rng.gen_range(0..3) {
+                0 if !self.ids.is_empty() => {
+                    let id = &self.ids[self.rng.gen_range(0..self.ids.len())];
+                    vec!["e".to_owned(), id.clone()]
+                }
+                0 | 1 => {
+                    let author = self.rng.gen_range(0..self.authors.len());
+                    vec!["p".to_owned(), self.authors[author].1.clone()]
+                }
+                _ => {
+                    let topic = self.rng.gen_range(0..TOPICS);
+                    vec!["t".to_owned(), format!("topic{topic}")]
+                }
+            }
+        }
+
+        /// Generate and sign the next event.
+        fn generate(&mut self) -> Event {
+            let author = self.rng.gen_range(0..self.authors.len());
+            let kind = self.kinds[self.kind_index.sample(&mut self.rng)];
+            let age = self.rng.gen_range(0..=self.profile.spread_secs);
+            let tag_count = self.rng.gen_range(0..=self.profile.max_tags);
+            let tags = (0..tag_count).map(|_| self.tag()).collect();
+            let content_len = self.rng.gen_range(0..=self.profile.max_content);
+            let content = (&mut self.rng)
+                .sample_iter(Alphanumeric)
+                .take(content_len)
+                .map(char::from)
+                .collect();
+            let (keypair, pubkey) = &self.authors[author];
+            let mut event = Event {
+                id: String::new(),
+                pubkey: pubkey.clone(),
+                delegated_by: None,
+                created_at: self.profile.until.saturating_sub(age),
+                kind,
+                tags,
+                content,
+                sig: String::new(),
+                tagidx: None,
+            };
+            // canonical_digest only fails for unserializable values,
+            // which generated events never contain
+            let digest = event.canonical_digest().expect("canonical form");
+            let msg = Message::from_slice(digest.as_ref()).expect("32 byte digest");
+            event.id = format!("{digest:x}");
+            event.sig = self
+                .secp
+                .sign_schnorr_no_aux_rand(&msg, keypair)
+                .to_string();
+            event.build_index();
+            self.ids.push(event.id.clone());
+            event
+        }
+    }
+
+    impl Iterator for EventGenerator {
+        type Item = Event;
+
+        fn next(&mut self) -> Option<Event> {
+            if self.generated >= self.profile.count {
+                return None;
+            }
+            self.generated += 1;
+            Some(self.generate())
+        }
+    }
+
+    /// The events of a profile.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the profile has no authors or kinds.
+    pub fn generate_events(profile: &GenProfile) -> Result<EventGenerator> {
+        EventGenerator::new(profile)
+    }
+
+    async fn write_batch(repo: &Arc<dyn NostrRepo>, batch: &mut Vec<Event>) -> Result<()> {
+        futures::future::try_join_all(batch.iter().map(|e| repo.write_event(e))).await?;
+        batch.clear();
+        Ok(())
+    }
+
+    /// Generate the events of a profile, and write them out.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the profile is invalid, or the events could
+    /// not be written.
+    pub fn run_gen_events(
+        settings: &Settings,
+        profile: &GenProfile,
+        output: &GenOutput,
+    ) -> Result<GenReport> {
+        let start = Instant::now();
+        let events = generate_events(profile)?;
+        match output {
+            GenOutput::Jsonl(path) => {
+                let out: Box<dyn Write> = if path == "-" {
+                    Box::new(std::io::stdout().lock())
+                } else {
+                    Box::new(std::fs::File::create(path)?)
+                };
+                let mut out = BufWriter::new(out);
+                for event in events {
+                    serde_json::to_writer(&mut out, &event)?;
+                    out.write_all(b"\n")?;
+                }
+                out.flush()?;
+            }
+            GenOutput::Database => {
+                let rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(async {
+                    let repo = db::build_repo(settings, crate::server::create_metrics().1).await;
+                    let mut batch = Vec::with_capacity(DB_BATCH);
+                    for event in events {
+                        batch.push(event);
+                        if batch.len() >= DB_BATCH {
+                            write_batch(&repo, &mut batch).await?;
+                        }
+                    }
+                    write_batch(&repo, &mut batch).await
+                })?;
+            }
+        }
+        let report = GenReport {
+            events: profile.count,
+            elapsed: start.elapsed(),
+        };
+        info!("{}", report);
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn profile(seed: u64) -> GenProfile {
+            GenProfile {
+                count: 200,
+                seed,
+                pubkeys: 10,
+                ..GenProfile::default()
+            }
+        }
+
+        #[test]
+        fn events_are_valid_and_reproducible() -> Result<()> {
+            let events: Vec<Event> = generate_events(&profile(7))?.collect();
+            assert_eq!(events.len(), 200);
+            for event in &events {
+                event.validate()?;
+                assert!(event.created_at <= 1_700_000_000);
+                assert!(event.tags.len() <= 4);
+                assert!(event.content.len() <= 280);
+            }
+            let authors: std::collections::HashSet<&str> =
+                events.iter().map(|e| e.pubkey.as_str()).collect();
+            assert!(authors.len() <= 10);
+            // the same seed gives the same events, another seed does not
+            let again: Vec<Event> = generate_events(&profile(7))?.collect();
+            assert_eq!(events, again);
+            let other: Vec<Event> = generate_events(&profile(8))?.collect();
+            assert_ne!(events[0].id, other[0].id);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_weights_are_parsed() -> Result<()> {
+        assert_eq!(parse_kind_weights("1:70, 7:30")?, vec![(1, 70), (7, 30)]);
+        assert!(parse_kind_weights("1").is_err());
+        assert!(parse_kind_weights("1:0").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Restoring deleted events before they are purged
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::repo::postgres::{self, PostgresPool};
+use crate::repo::sqlite::{self, build_pool};
+use crate::utils::is_lower_hex;
+use rusqlite::OpenFlags;
+
+/// Restore an event hidden by a deletion or by retention, as long as
+/// it has not been purged.  Returns whether the event was restored.
+///
+/// Only events deleted while `retention.purge_delay_hours` was set can
+/// be restored.
+///
+/// # Errors
+///
+/// Will return `Err` if the id is not a 64-character hex string, or
+/// the database could not be updated.
+pub fn run_undelete(settings: &Settings, event_id: &str) -> Result<bool> {
+    if event_id.len() != 64 || !is_lower_hex(event_id) {
+        return Err(Error::CustomError(
+            "event id must be 64 lowercase hex characters".to_owned(),
+        ));
+    }
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "undelete",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_WRITE,
+                1,
+                1,
+                false,
+            );
+            let mut conn = pool.get()?;
+            sqlite::undelete_event(&mut conn, event_id)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = postgres::pool_options(settings)
+                    .max_connections(1)
+                    .connect(&settings.database.connection)
+                    .await?;
+                postgres::undelete_event(&pool, event_id).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
@@ -0,0 +1,323 @@
+//! Exporting and erasing everything stored about a pubkey
+//!
+//! `nostr-rs-relay user-export <pubkey>` writes a zip of JSONL files,
+//! one for each kind of record: the pubkey's events, NIP-05
+//! verification records, account, invoices and ledger entries.
+//! `user-erase <pubkey>` deletes the same records, except the account
+//! and ledger (which stay for reconciliation, with admission
+//! withdrawn), and prints a receipt (signed by the relay, if it has
+//! keys).  Deleted events stay recoverable for
+//! `retention.purge_delay_hours`, like other deletions, and the
+//! pubkey's older events can be refused for
+//! `retention.erasure_tombstone_days`, so they are not simply published
+//! again.  Records are streamed from the database, so the export of a
+//! prolific author is never held in memory.
+use crate::config::Settings;
+use crate::db;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::relay_keys::RelayKeys;
+use crate::repo::NostrRepo;
+use crate::utils::unix_time;
+use nostr::key::FromPkStr;
+use nostr::{EventBuilder, Keys, Kind};
+use serde::Serialize;
+use std::fmt;
+use std::io::{Seek, Write};
+use tokio::sync::mpsc;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Records buffered between the database and the zip file
+const EXPORT_BUFFER: usize = 1000;
+
+/// A kind of record stored about a pubkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserTable {
+    Events,
+    Verifications,
+    Account,
+    Invoices,
+    Ledger,
+}
+
+impl UserTable {
+    /// Every kind of record, in the order they are exported
+    pub const ALL: [UserTable; 5] = [
+        UserTable::Events,
+        UserTable::Verifications,
+        UserTable::Account,
+        UserTable::Invoices,
+        UserTable::Ledger,
+    ];
+
+    /// Name of the file holding these records in an export
+    #[must_use]
+    pub fn file_name(self) -> &'static str {
+        match self {
+            UserTable::Events => "events.jsonl",
+            UserTable::Verifications => "verifications.jsonl",
+            UserTable::Account => "account.jsonl",
+            UserTable::Invoices => "invoices.jsonl",
+            UserTable::Ledger => "ledger.jsonl",
+        }
+    }
+}
+
+/// One record stored about a pubkey, as JSON
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub table: UserTable,
+    pub json: String,
+}
+
+/// Records exported or erased, of each kind
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UserDataCounts {
+    pub events: u64,
+    pub verifications: u64,
+    pub account: u64,
+    pub invoices: u64,
+    pub ledger: u64,
+}
+
+impl UserDataCounts {
+    fn count(&mut self, table: UserTable) {
+        match table {
+            UserTable::Events => self.events += 1,
+            UserTable::Verifications => self.verifications += 1,
+            UserTable::Account => self.account += 1,
+            UserTable::Invoices => self.invoices += 1,
+            UserTable::Ledger => self.ledger += 1,
+        }
+    }
+}
+
+impl fmt::Display for UserDataCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} events, {} verification records, {} accounts, {} invoices, {} ledger entries",
+            self.events, self.verifications, self.account, self.invoices, self.ledger
+        )
+    }
+}
+
+/// What `user-erase` removed
+#[derive(Debug, Clone, Serialize)]
+pub struct ErasureReceipt {
+    pub pubkey: String,
+    pub erased_at: u64,
+    pub removed: UserDataCounts,
+    /// Deleted events can be restored until this time, when they are
+    /// purged
+    pub purge_after: Option<u64>,
+    /// Events the pubkey created before `erased_at` are refused until
+    /// this time
+    pub tombstone_until: Option<u64>,
+    /// The receipt as a text note signed by the relay, if it has keys
+    #[serde(skip)]
+    pub signed: Option<Event>,
+}
+
+impl fmt::Display for ErasureReceipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = match &self.signed {
+            Some(event) => serde_json::to_string_pretty(event),
+            None => serde_json::to_string_pretty(self),
+        };
+        write!(f, "{}", json.map_err(|_| fmt::Error)?)
+    }
+}
+
+fn zip_error(e: ZipError) -> Error {
+    Error::CustomError(format!("could not write export: {e}"))
+}
+
+/// Hex form of a pubkey given as hex or npub
+fn hex_pubkey(pubkey: &str) -> Result<String> {
+    Ok(Keys::from_pk_str(pubkey)?.public_key().to_string())
+}
+
+/// Refuse to go on while the pubkey has an unpaid invoice, unless
+/// forced: a payment arriving later would credit an account that is
+/// no longer there.
+async fn check_unpaid_invoice(repo: &dyn NostrRepo, pubkey: &str, force: bool) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    if let Some(invoice) = repo.get_unpaid_invoice(&Keys::from_pk_str(pubkey)?).await? {
+        return Err(Error::CustomError(format!(
+            "{pubkey} has an unpaid invoice ({}); use --force to go on anyway",
+            invoice.payment_hash
+        )));
+    }
+    Ok(())
+}
+
+/// Write records to a zip of JSONL files, one per kind of record.
+/// Records of each kind must arrive together.  Every file is written,
+/// even if it has no records.
+fn write_zip<W: Write + Seek>(
+    out: W,
+    mut rx: mpsc::Receiver<UserRecord>,
+) -> Result<UserDataCounts> {
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut zip = ZipWriter::new(out);
+    let mut written: Vec<UserTable> = vec![];
+    let mut counts = UserDataCounts::default();
+    while let Some(record) = rx.blocking_recv() {
+        if written.last() != Some(&record.table) {
+            if written.contains(&record.table) {
+                return Err(Error::CustomError(format!(
+                    "{} records were not exported together",
+                    record.table.file_name()
+                )));
+            }
+            zip.start_file(record.table.file_name(), options)
+                .map_err(zip_error)?;
+            written.push(record.table);
+        }
+        zip.write_all(record.json.as_bytes())?;
+        zip.write_all(b"\n")?;
+        counts.count(record.table);
+    }
+    for table in UserTable::ALL {
+        if !written.contains(&table) {
+            zip.start_file(table.file_name(), options)
+                .map_err(zip_error)?;
+        }
+    }
+    zip.finish().map_err(zip_error)?;
+    Ok(counts)
+}
+
+/// Export everything stored about a pubkey to a zip file at `output`.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey is not valid, has an unpaid invoice
+/// (unless `force` is set), or the records could not be read or
+/// written.
+pub fn run_user_export(
+    settings: &Settings,
+    pubkey: &str,
+    output: &str,
+    force: bool,
+) -> Result<UserDataCounts> {
+    let pubkey = hex_pubkey(pubkey)?;
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let repo = db::build_repo(settings, crate::server::create_metrics().1).await;
+        check_unpaid_invoice(repo.as_ref(), &pubkey, force).await?;
+        let file = std::fs::File::create(output)?;
+        let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+        let writer = tokio::task::spawn_blocking(move || write_zip(file, rx));
+        let sent = async {
+            repo.export_events_by(&pubkey, tx.clone()).await?;
+            repo.export_account_data(&pubkey, tx).await
+        }
+        .await;
+        // the writer finishes once every sender is dropped, and its
+        // error explains why sending failed.
+        let exported = writer.await?.and_then(|counts| sent.map(|()| counts));
+        if exported.is_err() {
+            // an incomplete export is not left behind
+            std::fs::remove_file(output).ok();
+        }
+        exported
+    })
+}
+
+/// Erase everything stored about a pubkey, and record a tombstone
+/// refusing its older events.
+///
+/// # Errors
+///
+/// Will return `Err` if the pubkey is not valid, has an unpaid invoice
+/// (unless `force` is set), the relay keys could not be loaded, or the
+/// records could not be deleted.
+pub fn run_user_erase(settings: &Settings, pubkey: &str, force: bool) -> Result<ErasureReceipt> {
+    let pubkey = hex_pubkey(pubkey)?;
+    let relay_keys = RelayKeys::load(&settings.info)?;
+    let rt = tokio::runtime::Runtime::new()?;
+    let mut receipt = rt.block_on(async {
+        let repo = db::build_repo(settings, crate::server::create_metrics().1).await;
+        check_unpaid_invoice(repo.as_ref(), &pubkey, force).await?;
+        let erased_at = unix_time();
+        let tombstone_until = (settings.retention.erasure_tombstone_days > 0)
+            .then(|| erased_at + settings.retention.erasure_tombstone_days * 86400);
+        // the tombstone is recorded first, so events published while
+        // erasing are refused; verification records go before the
+        // events they refer to.
+        let mut removed = repo
+            .erase_account_data(&pubkey, erased_at, tombstone_until)
+            .await?;
+        removed.events = repo.erase_events_by(&pubkey).await?;
+        let purge_delay = settings.retention.purge_delay_hours * 3600;
+        Ok::<_, Error>(ErasureReceipt {
+            pubkey: pubkey.clone(),
+            erased_at,
+            removed,
+            purge_after: (purge_delay > 0).then(|| erased_at + purge_delay),
+            tombstone_until,
+            signed: None,
+        })
+    })?;
+    if let Some(relay_keys) = relay_keys {
+        let content = serde_json::to_string(&receipt)?;
+        let event = EventBuilder::new(Kind::TextNote, &content, &[]).to_event(relay_keys.keys())?;
+        receipt.signed = Some(Event::from(event));
+    }
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn record(table: UserTable, json: &str) -> UserRecord {
+        UserRecord {
+            table,
+            json: json.to_owned(),
+        }
+    }
+
+    #[test]
+    fn records_are_zipped_by_kind() -> Result<()> {
+        let (tx, rx) = mpsc::channel(10);
+        tx.try_send(record(UserTable::Events, r#"{"id":"a"}"#))
+            .unwrap();
+        tx.try_send(record(UserTable::Events, r#"{"id":"b"}"#))
+            .unwrap();
+        tx.try_send(record(UserTable::Account, r#"{"balance":5}"#))
+            .unwrap();
+        drop(tx);
+        let mut out = Cursor::new(vec![]);
+        let counts = write_zip(&mut out, rx)?;
+        assert_eq!(counts.events, 2);
+        assert_eq!(counts.account, 1);
+        assert_eq!(counts.ledger, 0);
+        let mut zip = zip::ZipArchive::new(out).map_err(zip_error)?;
+        assert_eq!(zip.len(), UserTable::ALL.len());
+        let mut events = String::new();
+        zip.by_name("events.jsonl")
+            .map_err(zip_error)?
+            .read_to_string(&mut events)?;
+        assert_eq!(events, "{\"id\":\"a\"}\n{\"id\":\"b\"}\n");
+        assert_eq!(zip.by_name("ledger.jsonl").map_err(zip_error)?.size(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn records_of_a_kind_must_be_together() {
+        let (tx, rx) = mpsc::channel(10);
+        tx.try_send(record(UserTable::Events, "{}")).unwrap();
+        tx.try_send(record(UserTable::Account, "{}")).unwrap();
+        tx.try_send(record(UserTable::Events, "{}")).unwrap();
+        drop(tx);
+        assert!(write_zip(Cursor::new(vec![]), rx).is_err());
+    }
+}
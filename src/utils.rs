@@ -43,6 +43,62 @@ pub fn host_str(url: &str) -> Option<String> {
         .and_then(|u| u.host_str().map(|s| s.to_string()))
 }
 
+/// Check if a string is a well-formed http or https URL.
+#[must_use]
+pub fn is_http_url(url: &str) -> bool {
+    Url::parse(url)
+        .map(|u| (u.scheme() == "http" || u.scheme() == "https") && u.host_str().is_some())
+        .unwrap_or(false)
+}
+
+/// The HTTP URL of a relay's websocket URL: `wss` becomes `https`, and
+/// `ws` becomes `http`.  Only the scheme is changed.
+///
+/// # Errors
+///
+/// Will return `Err` if the URL could not be parsed.
+pub fn relay_http_url(relay_url: &str) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(relay_url)?;
+    let scheme = match url.scheme() {
+        "wss" => "https",
+        "ws" => "http",
+        _ => return Ok(url),
+    };
+    // all four are special schemes, so changing between them can not fail
+    let _res = url.set_scheme(scheme);
+    Ok(url)
+}
+
+/// The client address in a remote IP header.  A header like
+/// `X-Forwarded-For` can hold a comma-separated chain of addresses, of
+/// which only the last, added by the proxy in front of the relay, can be
+/// trusted; the rest are supplied by the client.
+#[must_use]
+pub fn forwarded_client_ip(header: &str) -> Option<&str> {
+    header
+        .rsplit(',')
+        .next()
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+}
+
+/// Escape text for inclusion in HTML content or attributes.
+#[must_use]
+pub fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -69,4 +125,52 @@ mod tests {
 
         assert_eq!(expected, got);
     }
+
+    #[test]
+    fn http_url() {
+        assert!(is_http_url("https://pay.example.com/join"));
+        assert!(is_http_url("http://localhost:8080/relay/join"));
+        assert!(!is_http_url("wss://relay.example.com"));
+        assert!(!is_http_url("not a url"));
+    }
+
+    #[test]
+    fn relay_url_scheme_becomes_http() {
+        let http = |url| relay_http_url(url).unwrap().to_string();
+        assert_eq!(
+            http("wss://relay.example.com/"),
+            "https://relay.example.com/"
+        );
+        assert_eq!(http("ws://localhost:8080/"), "http://localhost:8080/");
+        // only the scheme changes, not hosts or paths containing "ws"
+        assert_eq!(
+            http("wss://news.example.com/ws/"),
+            "https://news.example.com/ws/"
+        );
+        assert_eq!(
+            http("https://relay.example.com/"),
+            "https://relay.example.com/"
+        );
+        assert!(relay_http_url("not a url").is_err());
+    }
+
+    #[test]
+    fn forwarded_ip() {
+        assert_eq!(forwarded_client_ip("203.0.113.9"), Some("203.0.113.9"));
+        assert_eq!(
+            forwarded_client_ip("1.0.0.1, 10.0.0.2,203.0.113.9 "),
+            Some("203.0.113.9")
+        );
+        assert_eq!(forwarded_client_ip("2001:db8::1"), Some("2001:db8::1"));
+        assert_eq!(forwarded_client_ip("1.0.0.1, "), None);
+        assert_eq!(forwarded_client_ip(""), None);
+    }
+
+    #[test]
+    fn escape_html() {
+        assert_eq!(
+            html_escape("<b>\"Tom\" & 'Jerry'</b>"),
+            "&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;"
+        );
+    }
 }
@@ -0,0 +1,536 @@
+//! Integrity checks for the event store
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::repo::postgres::{pool_options, PostgresPool};
+use crate::repo::postgres_migration::m002;
+use crate::repo::sqlite::{build_pool, indexed_tags, write_tags, PooledConnection};
+use futures::StreamExt;
+use rusqlite::{params, OpenFlags};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use tracing::{debug, info};
+
+/// Options controlling an integrity check.
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// File to write offending event ids to
+    pub output: Option<String>,
+    /// Rebuild tag rows that do not match the event
+    pub repair_tags: bool,
+    /// Hide events whose id or signature does not verify
+    pub hide_invalid: bool,
+    /// Number of events to read from the database at once
+    pub batch_size: usize,
+    /// Maximum indexed tag value length (see `limits.max_indexed_tag_value_bytes`)
+    pub max_indexed_tag_value_bytes: Option<usize>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions {
+            output: None,
+            repair_tags: false,
+            hide_invalid: false,
+            batch_size: 1000,
+            max_indexed_tag_value_bytes: None,
+        }
+    }
+}
+
+/// Results of an integrity check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Events examined
+    pub events_checked: u64,
+    /// Events that could not be parsed
+    pub unparseable: u64,
+    /// Events whose id does not match the event hash
+    pub invalid_id: u64,
+    /// Events whose signature does not verify
+    pub invalid_sig: u64,
+    /// Events whose tag rows do not match the tags in the event
+    pub tag_mismatch: u64,
+    /// Replaceable events shadowed by a newer visible version
+    pub duplicate_replaceable: u64,
+    /// Events whose tag rows were rebuilt
+    pub tags_repaired: u64,
+    /// Events that were hidden
+    pub events_hidden: u64,
+}
+
+impl VerifyReport {
+    /// Were any problems found?
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.unparseable == 0
+            && self.invalid_id == 0
+            && self.invalid_sig == 0
+            && self.tag_mismatch == 0
+            && self.duplicate_replaceable == 0
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked: {}, unparseable: {}, invalid id: {}, invalid signature: {}, tag mismatch: {}, duplicate replaceable: {}, tags repaired: {}, hidden: {}",
+            self.events_checked,
+            self.unparseable,
+            self.invalid_id,
+            self.invalid_sig,
+            self.tag_mismatch,
+            self.duplicate_replaceable,
+            self.tags_repaired,
+            self.events_hidden
+        )
+    }
+}
+
+/// Record of problems found, optionally written to a file.
+struct Offenders {
+    out: Option<BufWriter<File>>,
+}
+
+impl Offenders {
+    fn record(&mut self, id: &str, reason: &str) -> Result<()> {
+        debug!("event {} failed check: {}", id, reason);
+        if let Some(out) = &mut self.out {
+            writeln!(out, "{id} {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run an integrity check against the configured database.
+///
+/// # Errors
+///
+/// Will return `Err` if the database engine is not SQLite, or the
+/// database could not be read.
+pub fn run_verify(settings: &Settings, opts: &VerifyOptions) -> Result<VerifyReport> {
+    if settings.database.engine != "sqlite" {
+        return Err(Error::CustomError(
+            "integrity checks are only supported for sqlite".to_owned(),
+        ));
+    }
+    let pool = build_pool(
+        "verify",
+        settings,
+        OpenFlags::SQLITE_OPEN_READ_WRITE,
+        1,
+        1,
+        false,
+    );
+    let mut conn = pool.get()?;
+    verify_db(&mut conn, opts)
+}
+
+/// Walk every visible event in the database, checking that ids and
+/// signatures verify, and that tag rows agree with the event tags.
+/// Replaceable events are checked to have a single visible version.
+///
+/// Events are read in batches to bound memory use, and repairs are
+/// made in one small transaction per event, so this is safe to run
+/// against a live relay.
+pub fn verify_db(conn: &mut PooledConnection, opts: &VerifyOptions) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut offenders = Offenders {
+        out: match &opts.output {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        },
+    };
+    let mut last_id: i64 = 0;
+    loop {
+        // read a batch of events
+        let batch: Vec<(i64, String, bool)> = {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, content, tags_indexed FROM event WHERE id > ? AND hidden != TRUE ORDER BY id LIMIT ?",
+            )?;
+            let rows = stmt.query_map(params![last_id, opts.batch_size], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        if batch.is_empty() {
+            break;
+        }
+        for (ev_id, content, tags_indexed) in batch {
+            last_id = ev_id;
+            report.events_checked += 1;
+            let event: Event = if let Ok(e) = serde_json::from_str(&content) {
+                e
+            } else {
+                report.unparseable += 1;
+                offenders.record(&format!("rowid:{ev_id}"), "unparseable")?;
+                continue;
+            };
+            // check the id and signature
+            let invalid = match event.validate() {
+                Ok(()) => None,
+                Err(Error::EventInvalidId | Error::EventMalformedField("id", _)) => {
+                    report.invalid_id += 1;
+                    Some("invalid-id")
+                }
+                Err(_) => {
+                    report.invalid_sig += 1;
+                    Some("invalid-signature")
+                }
+            };
+            if let Some(reason) = invalid {
+                offenders.record(&event.id, reason)?;
+                if opts.hide_invalid {
+                    conn.execute("UPDATE event SET hidden=TRUE WHERE id=?", params![ev_id])?;
+                    report.events_hidden += 1;
+                }
+                continue;
+            }
+            // check that the tag table agrees with the event, unless its
+            // tags are still waiting to be indexed
+            if tags_indexed && !tags_match(conn, ev_id, &event, opts.max_indexed_tag_value_bytes)? {
+                report.tag_mismatch += 1;
+                offenders.record(&event.id, "tag-mismatch")?;
+                if opts.repair_tags {
+                    let tx = conn.transaction()?;
+                    tx.execute("DELETE FROM tag WHERE event_id=?", params![ev_id])?;
+                    write_tags(&tx, ev_id, &event, opts.max_indexed_tag_value_bytes)?;
+                    tx.commit()?;
+                    report.tags_repaired += 1;
+                }
+            }
+        }
+        info!("verified {} events", report.events_checked);
+    }
+    // find replaceable events with more than one visible version
+    {
+        let mut stmt = conn.prepare(
+            "SELECT lower(hex(e.event_hash)) FROM event e WHERE e.hidden != TRUE \
+             AND (e.kind IN (0, 3, 41) OR (e.kind >= 10000 AND e.kind < 20000)) \
+             AND EXISTS (SELECT 1 FROM event n WHERE n.author=e.author AND n.kind=e.kind \
+             AND n.hidden != TRUE AND n.created_at > e.created_at)",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            report.duplicate_replaceable += 1;
+            offenders.record(&id, "duplicate-replaceable")?;
+        }
+    }
+    if let Some(out) = &mut offenders.out {
+        out.flush()?;
+    }
+    Ok(report)
+}
+
+/// Compare the tag rows for an event with the tags it should have.
+fn tags_match(
+    conn: &PooledConnection,
+    ev_id: i64,
+    event: &Event,
+    max_tag_bytes: Option<usize>,
+) -> Result<bool> {
+    Ok(expected_tags(event, max_tag_bytes) == stored_tags(conn, ev_id)?)
+}
+
+/// Tag (name, value) pairs that should be in the tag table for an event.
+fn expected_tags(event: &Event, max_tag_bytes: Option<usize>) -> HashSet<(String, String)> {
+    indexed_tags(event, max_tag_bytes)
+        .into_iter()
+        .map(|(n, v)| (n.clone(), v.into_owned()))
+        .collect()
+}
+
+/// Tag (name, value) pairs stored in the tag table for an event.
+fn stored_tags(conn: &PooledConnection, ev_id: i64) -> Result<HashSet<(String, String)>> {
+    let mut stmt =
+        conn.prepare_cached("SELECT name, value, value_hex FROM tag WHERE event_id=?")?;
+    let mut rows = stmt.query(params![ev_id])?;
+    let mut stored = HashSet::new();
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let value: Option<String> = row.get(1)?;
+        let value_hex: Option<Vec<u8>> = row.get(2)?;
+        // older databases stored hex values as blobs
+        let value = value
+            .or_else(|| value_hex.map(hex::encode))
+            .unwrap_or_default();
+        stored.insert((name, value));
+    }
+    Ok(stored)
+}
+
+/// Coverage of events by the tag index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagCoverageReport {
+    /// Events examined
+    pub events: u64,
+    /// Events with every searchable tag present in the tag table
+    pub covered: u64,
+    /// Ids of events with missing tag rows
+    pub missing: Vec<String>,
+}
+
+impl fmt::Display for TagCoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events: {}, fully indexed: {}, missing tag rows: {}",
+            self.events,
+            self.covered,
+            self.missing.len()
+        )
+    }
+}
+
+/// Compare the tag index against the configured database, without
+/// making any changes.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be read.
+pub fn run_tag_coverage(settings: &Settings) -> Result<TagCoverageReport> {
+    let max_tag_bytes = settings.limits.max_indexed_tag_value_bytes;
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "tag-coverage",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                1,
+                1,
+                false,
+            );
+            let mut conn = pool.get()?;
+            sqlite_tag_coverage(&mut conn, max_tag_bytes)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = pool_options(settings)
+                    .max_connections(2)
+                    .connect(&settings.database.connection)
+                    .await?;
+                m002::check_tags(&pool, max_tag_bytes).await
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+/// Find events whose searchable tags are not all in the tag table.
+pub fn sqlite_tag_coverage(
+    conn: &mut PooledConnection,
+    max_tag_bytes: Option<usize>,
+) -> Result<TagCoverageReport> {
+    let mut report = TagCoverageReport::default();
+    // events waiting for deferred tag indexing are not expected to be covered yet
+    let mut stmt =
+        conn.prepare("SELECT id, content FROM event WHERE tags_indexed=1 ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let ev_id: i64 = row.get(0)?;
+        let content: String = row.get(1)?;
+        let event: Event = serde_json::from_str(&content)?;
+        report.events += 1;
+        let stored = stored_tags(conn, ev_id)?;
+        if expected_tags(&event, max_tag_bytes).is_subset(&stored) {
+            report.covered += 1;
+        } else {
+            report.missing.push(event.id);
+        }
+    }
+    Ok(report)
+}
+
+/// Events whose id does not match their canonical NIP-01 form.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanonicalReport {
+    /// Events examined
+    pub events: u64,
+    /// Events that could not be parsed
+    pub unparseable: u64,
+    /// Ids of events that do not hash to their id
+    pub non_canonical: Vec<String>,
+}
+
+impl CanonicalReport {
+    fn check(&mut self, content: &[u8]) {
+        self.events += 1;
+        match serde_json::from_slice::<Event>(content) {
+            Ok(event) if !event.has_canonical_id() => self.non_canonical.push(event.id),
+            Ok(_) => (),
+            Err(_) => self.unparseable += 1,
+        }
+    }
+}
+
+impl fmt::Display for CanonicalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events: {}, unparseable: {}, non-canonical: {}",
+            self.events,
+            self.unparseable,
+            self.non_canonical.len()
+        )
+    }
+}
+
+/// Recompute the id of every stored event (including hidden ones)
+/// from its canonical form, without making any changes.
+///
+/// # Errors
+///
+/// Will return `Err` if the database could not be read.
+pub fn run_canonical_audit(settings: &Settings) -> Result<CanonicalReport> {
+    match settings.database.engine.as_str() {
+        "sqlite" => {
+            let pool = build_pool(
+                "canonical-audit",
+                settings,
+                OpenFlags::SQLITE_OPEN_READ_ONLY,
+                1,
+                1,
+                false,
+            );
+            let conn = pool.get()?;
+            sqlite_canonical_audit(&conn)
+        }
+        "postgres" => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async {
+                let pool: PostgresPool = pool_options(settings)
+                    .max_connections(1)
+                    .connect(&settings.database.connection)
+                    .await?;
+                let mut report = CanonicalReport::default();
+                let mut rows = sqlx::query("SELECT content FROM event ORDER BY id;").fetch(&pool);
+                while let Some(row) = rows.next().await {
+                    let content: Vec<u8> = row?.get(0);
+                    report.check(&content);
+                }
+                Ok(report)
+            })
+        }
+        _ => Err(Error::CustomError("Unknown database engine".to_owned())),
+    }
+}
+
+/// Find events whose id does not match their canonical form.
+pub fn sqlite_canonical_audit(conn: &PooledConnection) -> Result<CanonicalReport> {
+    let mut report = CanonicalReport::default();
+    let mut stmt = conn.prepare("SELECT content FROM event ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let content: String = row.get(0)?;
+        report.check(content.as_bytes());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::sqlite::{SqlitePool, SqliteRepo};
+    use crate::repo::sqlite_migration::upgrade_db;
+    use nostr::{EventBuilder, Keys, Tag};
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn test_conn() -> PooledConnection {
+        let manager = SqliteConnectionManager::memory();
+        let pool: SqlitePool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        conn
+    }
+
+    fn signed_note(keys: &Keys, content: &str) -> Event {
+        let tags = vec![Tag::parse(vec!["t", "nostr"]).unwrap()];
+        EventBuilder::new_text_note(content, &tags)
+            .to_event(keys)
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn clean_database() -> Result<()> {
+        let mut conn = test_conn();
+        let keys = Keys::generate();
+        SqliteRepo::persist_event(&mut conn, &signed_note(&keys, "one"), None)?;
+        let report = verify_db(&mut conn, &VerifyOptions::default())?;
+        assert_eq!(report.events_checked, 1);
+        assert!(report.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn detect_and_repair() -> Result<()> {
+        let mut conn = test_conn();
+        let keys = Keys::generate();
+        // an event whose content was altered after signing
+        let mut tampered = signed_note(&keys, "original");
+        tampered.content = "altered".to_owned();
+        SqliteRepo::persist_event(&mut conn, &tampered, None)?;
+        // an event whose tag rows went missing
+        let missing_tags = signed_note(&keys, "tags");
+        SqliteRepo::persist_event(&mut conn, &missing_tags, None)?;
+        conn.execute("DELETE FROM tag", [])?;
+        let opts = VerifyOptions {
+            repair_tags: true,
+            hide_invalid: true,
+            batch_size: 1,
+            ..Default::default()
+        };
+        let report = verify_db(&mut conn, &opts)?;
+        assert_eq!(report.events_checked, 2);
+        assert_eq!(report.invalid_id, 1);
+        assert_eq!(report.tag_mismatch, 1);
+        assert_eq!(report.tags_repaired, 1);
+        assert_eq!(report.events_hidden, 1);
+        // a second pass finds nothing wrong
+        let report = verify_db(&mut conn, &VerifyOptions::default())?;
+        assert_eq!(report.events_checked, 1);
+        assert!(report.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn tag_coverage() -> Result<()> {
+        let mut conn = test_conn();
+        let keys = Keys::generate();
+        let covered = signed_note(&keys, "covered");
+        SqliteRepo::persist_event(&mut conn, &covered, None)?;
+        let uncovered = signed_note(&keys, "uncovered");
+        SqliteRepo::persist_event(&mut conn, &uncovered, None)?;
+        conn.execute(
+            "DELETE FROM tag WHERE event_id=(SELECT max(id) FROM event)",
+            [],
+        )?;
+        let report = sqlite_tag_coverage(&mut conn, None)?;
+        assert_eq!(report.events, 2);
+        assert_eq!(report.covered, 1);
+        assert_eq!(report.missing, vec![uncovered.id]);
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_audit() -> Result<()> {
+        let mut conn = test_conn();
+        let keys = Keys::generate();
+        SqliteRepo::persist_event(&mut conn, &signed_note(&keys, "line\none"), None)?;
+        // stored with content that no longer hashes to the id
+        let mut altered = signed_note(&keys, "tab\there");
+        altered.content = "tab\\there".to_owned();
+        SqliteRepo::persist_event(&mut conn, &altered, None)?;
+        let report = sqlite_canonical_audit(&conn)?;
+        assert_eq!(report.events, 2);
+        assert_eq!(report.unparseable, 0);
+        assert_eq!(report.non_canonical, vec![altered.id]);
+        Ok(())
+    }
+}
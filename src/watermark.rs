@@ -0,0 +1,158 @@
+//! Newest events delivered to subscriptions, for resuming repeated REQs
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Watermark to store: (watermark key, created_at of the newest event)
+pub type Pending = (String, u64);
+
+#[derive(Debug)]
+struct Watermark {
+    /// Hash of the subscription id and filters
+    key: String,
+    /// Newest event sent before the end of stored events
+    stored: Option<u64>,
+    /// Have all stored events been sent?
+    complete: bool,
+    /// Newest event sent, once all stored events have been sent
+    newest: Option<u64>,
+    /// Last value handed out to be saved
+    saved: Option<u64>,
+}
+
+impl Watermark {
+    fn unsaved(&mut self) -> Option<Pending> {
+        let newest = self.newest?;
+        if self.saved.map_or(true, |saved| newest > saved) {
+            self.saved = Some(newest);
+            Some((self.key.clone(), newest))
+        } else {
+            None
+        }
+    }
+}
+
+/// Per-connection record of the newest event delivered to each
+/// subscription.
+///
+/// Stored events are sent newest first, and a query can be cut short,
+/// so nothing is reported until EOSE; only then is every event older
+/// than the watermark known to have been delivered.
+#[derive(Debug, Default)]
+pub struct Watermarks {
+    subs: HashMap<String, Watermark>,
+}
+
+#[derive(Deserialize)]
+struct CreatedAt {
+    created_at: u64,
+}
+
+/// Read `created_at` from a serialized event.
+#[must_use]
+pub fn created_at(event_json: &str) -> Option<u64> {
+    serde_json::from_str::<CreatedAt>(event_json)
+        .ok()
+        .map(|e| e.created_at)
+}
+
+impl Watermarks {
+    /// Start tracking a subscription.  Anything not yet saved for a
+    /// subscription it replaces is returned.
+    pub fn track(&mut self, sub_id: &str, key: String) -> Option<Pending> {
+        let wm = Watermark {
+            key,
+            stored: None,
+            complete: false,
+            newest: None,
+            saved: None,
+        };
+        self.subs
+            .insert(sub_id.to_owned(), wm)
+            .and_then(|mut old| old.unsaved())
+    }
+
+    /// Record an event sent to a subscription.
+    pub fn delivered(&mut self, sub_id: &str, created_at: u64) {
+        if let Some(wm) = self.subs.get_mut(sub_id) {
+            if wm.complete {
+                wm.newest = wm.newest.max(Some(created_at));
+            } else {
+                wm.stored = wm.stored.max(Some(created_at));
+            }
+        }
+    }
+
+    /// All stored events have been sent; returns the watermark to save.
+    pub fn end_of_stored(&mut self, sub_id: &str) -> Option<Pending> {
+        let wm = self.subs.get_mut(sub_id)?;
+        if !wm.complete {
+            wm.complete = true;
+            wm.newest = wm.stored.take();
+        }
+        wm.unsaved()
+    }
+
+    /// Stop tracking a subscription, returning anything not yet saved.
+    pub fn close(&mut self, sub_id: &str) -> Option<Pending> {
+        self.subs.remove(sub_id).and_then(|mut wm| wm.unsaved())
+    }
+
+    /// Stop tracking everything, returning anything not yet saved.
+    pub fn close_all(&mut self) -> Vec<Pending> {
+        self.subs
+            .drain()
+            .filter_map(|(_, mut wm)| wm.unsaved())
+            .collect()
+    }
+
+    /// Is this subscription being tracked?
+    #[must_use]
+    pub fn is_tracked(&self, sub_id: &str) -> bool {
+        self.subs.contains_key(sub_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_only_after_eose() {
+        let mut wm = Watermarks::default();
+        assert_eq!(wm.track("feed", "key".to_owned()), None);
+        // stored events arrive newest first, and may be cut short
+        wm.delivered("feed", 300);
+        wm.delivered("feed", 100);
+        assert_eq!(wm.close_all(), vec![]);
+
+        wm.track("feed", "key".to_owned());
+        wm.delivered("feed", 300);
+        wm.delivered("feed", 100);
+        assert_eq!(wm.end_of_stored("feed"), Some(("key".to_owned(), 300)));
+        // nothing new to save
+        assert_eq!(wm.end_of_stored("feed"), None);
+        wm.delivered("feed", 200);
+        assert_eq!(wm.close("feed"), None);
+
+        // no stored events, then a live one
+        wm.track("feed", "key".to_owned());
+        assert_eq!(wm.end_of_stored("feed"), None);
+        wm.delivered("feed", 400);
+        // replacing the subscription hands back the live watermark
+        assert_eq!(
+            wm.track("feed", "other".to_owned()),
+            Some(("key".to_owned(), 400))
+        );
+        wm.delivered("untracked", 500);
+        assert!(!wm.is_tracked("untracked"));
+    }
+
+    #[test]
+    fn created_at_from_json() {
+        assert_eq!(
+            created_at(r#"{"id":"aa","created_at":1700000000}"#),
+            Some(1_700_000_000)
+        );
+        assert_eq!(created_at("not json"), None);
+    }
+}
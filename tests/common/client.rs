@@ -0,0 +1,230 @@
+//! A websocket client for protocol-level tests
+use super::Relay;
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use nostr::{Event, EventBuilder, Keys, Kind, Tag, Url};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Longest wait for a message from the relay
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message from the relay
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayMessage {
+    Event {
+        sub_id: String,
+        event: Value,
+    },
+    Ok {
+        id: String,
+        accepted: bool,
+        message: String,
+    },
+    Eose(String),
+    Closed {
+        sub_id: String,
+        message: String,
+    },
+    Notice(String),
+    Auth(String),
+    Proof(Value),
+}
+
+impl RelayMessage {
+    /// Parse a message sent by the relay.
+    pub fn parse(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text)?;
+        let str_at = |i: usize| -> Result<String> {
+            value[i]
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("expected a string at {i} in {text}"))
+        };
+        match value[0].as_str() {
+            Some("EVENT") => Ok(RelayMessage::Event {
+                sub_id: str_at(1)?,
+                event: value[2].clone(),
+            }),
+            Some("OK") => Ok(RelayMessage::Ok {
+                id: str_at(1)?,
+                accepted: value[2]
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("expected a boolean in {text}"))?,
+                message: str_at(3)?,
+            }),
+            Some("EOSE") => Ok(RelayMessage::Eose(str_at(1)?)),
+            Some("CLOSED") => Ok(RelayMessage::Closed {
+                sub_id: str_at(1)?,
+                message: str_at(2)?,
+            }),
+            Some("NOTICE") => Ok(RelayMessage::Notice(str_at(1)?)),
+            Some("AUTH") => Ok(RelayMessage::Auth(str_at(1)?)),
+            Some("PROOF") => Ok(RelayMessage::Proof(value[1].clone())),
+            _ => Err(anyhow!("unexpected message from relay: {text}")),
+        }
+    }
+}
+
+/// A signed text note
+pub fn note(keys: &Keys, content: &str) -> Event {
+    signed(keys, 1, content)
+}
+
+/// A signed event of any kind, without tags
+pub fn signed(keys: &Keys, kind: u64, content: &str) -> Event {
+    EventBuilder::new(Kind::from(kind), content, &Vec::<Tag>::new())
+        .to_event(keys)
+        .expect("could not sign event")
+}
+
+/// A client connected to a relay by websocket
+pub struct TestClient {
+    ws: WsStream,
+}
+
+impl TestClient {
+    pub async fn connect(relay: &Relay) -> Result<Self> {
+        let (ws, _response) = connect_async(relay.url()).await?;
+        Ok(TestClient { ws })
+    }
+
+    /// Send a message as is.
+    pub async fn send_raw(&mut self, text: &str) -> Result<()> {
+        self.ws.send(Message::Text(text.to_owned())).await?;
+        Ok(())
+    }
+
+    /// Send an `EVENT`, without waiting for the relay's answer.
+    pub async fn send_event(&mut self, event: &Event) -> Result<()> {
+        self.send_raw(&json!(["EVENT", event]).to_string()).await
+    }
+
+    /// Send an `EVENT`, and wait for its `OK`.  Returns whether it was
+    /// accepted, and the message.
+    pub async fn publish(&mut self, event: &Event) -> Result<(bool, String)> {
+        self.send_event(event).await?;
+        self.expect_ok(&event.id.to_hex()).await
+    }
+
+    /// Open (or replace) a subscription.
+    pub async fn req(&mut self, sub_id: &str, filters: &[Value]) -> Result<()> {
+        let mut msg = vec![json!("REQ"), json!(sub_id)];
+        msg.extend(filters.iter().cloned());
+        self.send_raw(&Value::Array(msg).to_string()).await
+    }
+
+    /// Close a subscription.
+    pub async fn close(&mut self, sub_id: &str) -> Result<()> {
+        self.send_raw(&json!(["CLOSE", sub_id]).to_string()).await
+    }
+
+    /// Wait until the relay has handled the messages sent so far, by
+    /// running a query that matches nothing.
+    pub async fn sync(&mut self) -> Result<()> {
+        self.req("sync", &[json!({"ids": ["0".repeat(64)]})])
+            .await?;
+        self.stored_events("sync").await?;
+        self.close("sync").await
+    }
+
+    /// The next message from the relay, within `MESSAGE_TIMEOUT`.
+    pub async fn next_message(&mut self) -> Result<RelayMessage> {
+        self.next_message_within(MESSAGE_TIMEOUT)
+            .await?
+            .ok_or_else(|| anyhow!("no message from the relay within {MESSAGE_TIMEOUT:?}"))
+    }
+
+    /// The next message from the relay, if one arrives in time.
+    /// Pings are skipped.
+    pub async fn next_message_within(&mut self, wait: Duration) -> Result<Option<RelayMessage>> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            match tokio::time::timeout_at(deadline, self.ws.next()).await {
+                Err(_) => return Ok(None),
+                Ok(Some(Ok(Message::Text(text)))) => return RelayMessage::parse(&text).map(Some),
+                Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => continue,
+                Ok(other) => return Err(anyhow!("expected a text message, got {other:?}")),
+            }
+        }
+    }
+
+    /// Expect the `OK` for an event next.
+    pub async fn expect_ok(&mut self, id: &str) -> Result<(bool, String)> {
+        match self.next_message().await? {
+            RelayMessage::Ok {
+                id: ok_id,
+                accepted,
+                message,
+            } if ok_id == id => Ok((accepted, message)),
+            other => Err(anyhow!("expected OK for {id}, got {other:?}")),
+        }
+    }
+
+    /// Expect nothing from the relay for a while.
+    pub async fn expect_silence(&mut self, wait: Duration) -> Result<()> {
+        match self.next_message_within(wait).await? {
+            None => Ok(()),
+            Some(msg) => Err(anyhow!("expected no message, got {msg:?}")),
+        }
+    }
+
+    /// The stored events sent for a subscription, up to its `EOSE`.
+    pub async fn stored_events(&mut self, sub_id: &str) -> Result<Vec<Value>> {
+        let mut events = vec![];
+        loop {
+            match self.next_message().await? {
+                RelayMessage::Event { sub_id: s, event } if s == sub_id => events.push(event),
+                RelayMessage::Eose(s) if s == sub_id => return Ok(events),
+                other => return Err(anyhow!("expected events for {sub_id}, got {other:?}")),
+            }
+        }
+    }
+
+    /// Answer the relay's NIP-42 challenge, authenticating as `keys`.
+    /// The relay does not reply to a successful `AUTH`, so wait for a
+    /// query to finish, to know it has been handled.
+    pub async fn authenticate(&mut self, keys: &Keys, relay_url: &str) -> Result<()> {
+        let challenge = self.expect_challenge().await?;
+        self.send_auth(keys, &challenge, relay_url).await?;
+        self.sync().await
+    }
+
+    /// Expect an AUTH challenge next.
+    pub async fn expect_challenge(&mut self) -> Result<String> {
+        match self.next_message().await? {
+            RelayMessage::Auth(challenge) => Ok(challenge),
+            other => Err(anyhow!("expected an AUTH challenge, got {other:?}")),
+        }
+    }
+
+    /// Answer an AUTH challenge, without waiting for the result.
+    pub async fn send_auth(&mut self, keys: &Keys, challenge: &str, relay_url: &str) -> Result<()> {
+        let event =
+            EventBuilder::auth(challenge.to_owned(), Url::parse(relay_url)?).to_event(keys)?;
+        self.send_raw(&json!(["AUTH", event]).to_string()).await
+    }
+
+    /// Expect the relay to close the connection next, returning the
+    /// status code.
+    pub async fn expect_close(&mut self) -> Result<u16> {
+        loop {
+            match tokio::time::timeout(MESSAGE_TIMEOUT, self.ws.next()).await {
+                Ok(Some(Ok(Message::Close(Some(frame))))) => return Ok(frame.code.into()),
+                Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => continue,
+                other => return Err(anyhow!("expected a close frame, got {other:?}")),
+            }
+        }
+    }
+
+    /// Close the connection.
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+}
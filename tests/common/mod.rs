@@ -1,29 +1,84 @@
+// each test crate uses only part of the harness
+#![allow(dead_code)]
 use anyhow::{anyhow, Result};
 use nostr_rs_relay::config;
-use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::hooks::{LifecycleHooks, NoopHooks};
+use nostr_rs_relay::server::start_server_with_hooks;
 //use http::{Request, Response};
 use hyper::{Client, StatusCode, Uri};
 use std::net::TcpListener;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::mpsc as syncmpsc;
 use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 use tracing::{debug, info};
 
+pub mod client;
+pub mod payments;
+
 pub struct Relay {
     pub port: u16,
-    pub handle: JoinHandle<()>,
+    pub handle: JoinHandle<nostr_rs_relay::error::Result<()>>,
     pub shutdown_tx: MpscSender<()>,
 }
 
+impl Relay {
+    /// Websocket URL of the relay
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    /// URL of an HTTP route of the relay
+    pub fn http_url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{path}", self.port)
+    }
+
+    /// Stop the relay, and wait for it to exit.
+    pub fn shutdown(self) -> Result<()> {
+        // the relay may not be listening for shutdown yet
+        while self.shutdown_tx.send(()).is_err() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("relay thread panicked"))??;
+        Ok(())
+    }
+}
+
 pub fn start_relay() -> Result<Relay> {
+    start_relay_with(config::Settings::default())
+}
+
+/// Start a relay with the given settings (the network and database
+/// settings are replaced).
+pub fn start_relay_with(settings: config::Settings) -> Result<Relay> {
+    start_relay_with_hooks(settings, Arc::new(NoopHooks))
+}
+
+/// Start a relay with the given settings and lifecycle hooks, and wait
+/// until it serves requests.
+pub async fn start_healthy_relay(
+    settings: config::Settings,
+    hooks: Arc<dyn LifecycleHooks>,
+) -> Result<Relay> {
+    let relay = start_relay_with_hooks(settings, hooks)?;
+    wait_for_healthy_relay(&relay).await?;
+    Ok(relay)
+}
+
+/// Start a relay with the given settings (the network and database
+/// settings are replaced) and lifecycle hooks.
+pub fn start_relay_with_hooks(
+    mut settings: config::Settings,
+    hooks: Arc<dyn LifecycleHooks>,
+) -> Result<Relay> {
     // setup tracing
     let _trace_sub = tracing_subscriber::fmt::try_init();
     info!("Starting a new relay");
-    // replace default settings
-    let mut settings = config::Settings::default();
     // identify open port
     info!("Checking for address...");
     let port = get_available_port().unwrap();
@@ -36,10 +91,8 @@ pub fn start_relay() -> Result<Relay> {
     settings.database.min_conn = 4;
     settings.database.max_conn = 8;
     let (shutdown_tx, shutdown_rx): (MpscSender<()>, MpscReceiver<()>) = syncmpsc::channel();
-    let handle = thread::spawn(move || {
-        // server will block the thread it is run on.
-        let _ = start_server(&settings, shutdown_rx);
-    });
+    // server will block the thread it is run on.
+    let handle = thread::spawn(move || start_server_with_hooks(&settings, shutdown_rx, hooks));
     // how do we know the relay has finished starting up?
     Ok(Relay {
         port,
@@ -48,27 +101,39 @@ pub fn start_relay() -> Result<Relay> {
     })
 }
 
-// check if the server is healthy via HTTP request
-async fn server_ready(relay: &Relay) -> Result<bool> {
-    let uri: String = format!("http://127.0.0.1:{}/", relay.port);
+// check if the server is ready via HTTP request
+async fn server_ready(relay: &Relay) -> Result<StatusCode> {
+    let uri: String = relay.http_url("/ready");
     let client = Client::new();
     let uri: Uri = uri.parse().unwrap();
     let res = client.get(uri).await?;
-    Ok(res.status() == StatusCode::OK)
+    Ok(res.status())
 }
 
+/// Longest wait for a relay to start serving requests
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub async fn wait_for_healthy_relay(relay: &Relay) -> Result<()> {
-    // TODO: maximum time to wait for server to become healthy.
+    tokio::time::timeout(STARTUP_TIMEOUT, poll_healthy_relay(relay))
+        .await
+        .map_err(|_| anyhow!("relay did not start within {STARTUP_TIMEOUT:?}"))?
+}
+
+async fn poll_healthy_relay(relay: &Relay) -> Result<()> {
     // give it a little time to start up before we start polling
     tokio::time::sleep(Duration::from_millis(10)).await;
     loop {
         let server_check = server_ready(relay).await;
         match server_check {
-            Ok(true) => {
+            Ok(StatusCode::OK) => {
                 // server responded with 200-OK.
                 break;
             }
-            Ok(false) => {
+            Ok(StatusCode::SERVICE_UNAVAILABLE) => {
+                // server is still starting up (running migrations).
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok(_) => {
                 // server responded with an error, we're done.
                 return Err(anyhow!("Got non-200-OK from relay"));
             }
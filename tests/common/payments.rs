@@ -0,0 +1,60 @@
+//! A payment processor that never leaves the test process
+use async_trait::async_trait;
+use nostr::Keys;
+use nostr_rs_relay::config::Settings;
+use nostr_rs_relay::error::Error;
+use nostr_rs_relay::hooks::LifecycleHooks;
+use nostr_rs_relay::payment::{InvoiceInfo, InvoiceStatus, PaymentProcessor};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Creates made-up invoices, which are paid when a test says so.  Used
+/// as lifecycle hooks, it replaces the configured processor.
+#[derive(Clone, Default)]
+pub struct MockPayments {
+    created: Arc<AtomicU64>,
+    paid: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MockPayments {
+    /// Mark an invoice paid.
+    pub fn pay(&self, payment_hash: &str) {
+        self.paid.lock().unwrap().insert(payment_hash.to_owned());
+    }
+
+    /// Invoices created so far
+    pub fn invoices_created(&self) -> u64 {
+        self.created.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl PaymentProcessor for MockPayments {
+    async fn get_invoice(&self, keys: &Keys, amount: u64) -> Result<InvoiceInfo, Error> {
+        let n = self.created.fetch_add(1, Ordering::SeqCst);
+        Ok(InvoiceInfo {
+            pubkey: keys.public_key().to_string(),
+            payment_hash: format!("{n:064x}"),
+            bolt11: format!("lnbcrt{amount}mock{n}"),
+            amount,
+            status: InvoiceStatus::Unpaid,
+            memo: "mock admission invoice".to_owned(),
+            confirmed_at: None,
+        })
+    }
+
+    async fn check_invoice(&self, payment_hash: &str) -> Result<InvoiceStatus, Error> {
+        Ok(if self.paid.lock().unwrap().contains(payment_hash) {
+            InvoiceStatus::Paid
+        } else {
+            InvoiceStatus::Unpaid
+        })
+    }
+}
+
+impl LifecycleHooks for MockPayments {
+    fn payment_processor(&self, _settings: &Settings) -> Arc<dyn PaymentProcessor> {
+        Arc::new(self.clone())
+    }
+}
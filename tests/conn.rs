@@ -5,10 +5,13 @@ mod tests {
     use bitcoin_hashes::Hash;
     use secp256k1::rand;
     use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+    use std::time::{Duration, Instant};
 
+    use nostr_rs_relay::close::Close;
     use nostr_rs_relay::conn::ClientConn;
     use nostr_rs_relay::error::Error;
     use nostr_rs_relay::event::Event;
+    use nostr_rs_relay::subscription::Subscription;
     use nostr_rs_relay::utils::unix_time;
 
     const RELAY: &str = "wss://nostr.example.com/";
@@ -49,14 +52,15 @@ mod tests {
         assert_ne!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), None);
 
-        let challenge = client_conn.auth_challenge().unwrap();
-        let event = auth_event(challenge);
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&challenge);
 
         let result = client_conn.authenticate(&event, RELAY.into());
 
         assert!(matches!(result, Ok(())));
-        assert_eq!(client_conn.auth_challenge(), None);
+        assert_eq!(client_conn.auth_challenge(), Some(&challenge));
         assert_eq!(client_conn.auth_pubkey(), Some(&event.pubkey));
+        assert_eq!(client_conn.auth_failures(), 0);
     }
 
     #[test]
@@ -73,7 +77,7 @@ mod tests {
     }
 
     #[test]
-    fn test_authenticate_when_already_authenticated() {
+    fn test_reauthenticate_switches_identity() {
         let mut client_conn = ClientConn::new("127.0.0.1".into());
 
         assert_eq!(client_conn.auth_challenge(), None);
@@ -90,16 +94,100 @@ mod tests {
         let result = client_conn.authenticate(&event, RELAY.into());
 
         assert!(matches!(result, Ok(())));
-        assert_eq!(client_conn.auth_challenge(), None);
         assert_eq!(client_conn.auth_pubkey(), Some(&event.pubkey));
 
+        // another pubkey answering the same challenge replaces the first
         let event1 = auth_event(&challenge);
         let result1 = client_conn.authenticate(&event1, RELAY.into());
 
         assert!(matches!(result1, Ok(())));
-        assert_eq!(client_conn.auth_challenge(), None);
+        assert_eq!(client_conn.auth_pubkey(), Some(&event1.pubkey));
+        assert_ne!(client_conn.auth_pubkey(), Some(&event.pubkey));
+
+        // a failed attempt keeps the current identity
+        let event2 = auth_event(&"invalid challenge".into());
+        assert!(client_conn.authenticate(&event2, RELAY).is_err());
+        assert_eq!(client_conn.auth_pubkey(), Some(&event1.pubkey));
+    }
+
+    #[test]
+    fn test_fail_to_authenticate_with_replayed_challenge() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.generate_auth_challenge();
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+
+        // a failed attempt replaces the challenge
+        let bad = auth_event_with_relay(&challenge, &"wss://other.example.com/".into());
+        assert!(matches!(
+            client_conn.authenticate(&bad, RELAY),
+            Err(Error::AuthFailure)
+        ));
+        assert_eq!(client_conn.auth_failures(), 1);
+        assert_ne!(client_conn.auth_challenge(), Some(&challenge));
+
+        // so the old challenge can not be answered again
+        let replayed = auth_event(&challenge);
+        assert!(matches!(
+            client_conn.authenticate(&replayed, RELAY),
+            Err(Error::AuthFailure)
+        ));
+        assert_eq!(client_conn.auth_failures(), 2);
+        assert_eq!(client_conn.auth_pubkey(), None);
+
+        // the new one can
+        let current = client_conn.auth_challenge().unwrap().clone();
+        let event = auth_event(&current);
+        assert!(client_conn.authenticate(&event, RELAY).is_ok());
         assert_eq!(client_conn.auth_pubkey(), Some(&event.pubkey));
-        assert_ne!(client_conn.auth_pubkey(), Some(&event1.pubkey));
+    }
+
+    #[test]
+    fn test_fail_to_authenticate_with_expired_challenge() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_auth_window(Duration::ZERO, 600);
+        client_conn.generate_auth_challenge();
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+
+        let event = auth_event(&challenge);
+        let result = client_conn.authenticate(&event, RELAY);
+
+        assert!(matches!(result, Err(Error::AuthChallengeExpired)));
+        assert_eq!(client_conn.auth_pubkey(), None);
+        assert_ne!(client_conn.auth_challenge(), Some(&challenge));
+    }
+
+    #[test]
+    fn test_expired_challenge_is_not_a_failure() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_auth_window(Duration::from_secs(60), 600);
+        assert_eq!(client_conn.auth_challenge_expiry(), None);
+        client_conn.generate_auth_challenge();
+        let expiry = client_conn.auth_challenge_expiry().unwrap();
+        assert!(expiry > Instant::now() + Duration::from_secs(59));
+        client_conn.set_auth_window(Duration::ZERO, 600);
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+
+        let result = client_conn.authenticate(&auth_event(&challenge), RELAY);
+
+        assert!(matches!(result, Err(Error::AuthChallengeExpired)));
+        assert_eq!(client_conn.auth_failures(), 0);
+    }
+
+    #[test]
+    fn test_fail_to_authenticate_outside_skew() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_auth_window(Duration::from_secs(600), 60);
+        client_conn.generate_auth_challenge();
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+
+        let event = auth_event_with_created_at(&challenge, unix_time() - 120);
+        assert!(matches!(
+            client_conn.authenticate(&event, RELAY),
+            Err(Error::AuthFailure)
+        ));
+        let challenge = client_conn.auth_challenge().unwrap().clone();
+        let event = auth_event_with_created_at(&challenge, unix_time() - 30);
+        assert!(client_conn.authenticate(&event, RELAY).is_ok());
     }
 
     #[test]
@@ -280,6 +368,84 @@ mod tests {
         assert!(matches!(result, Err(Error::AuthFailure)));
     }
 
+    const RESTRICTED_KINDS: [u64; 3] = [4, 44, 1059];
+
+    fn gift_wrap(recipient: &str) -> Event {
+        Event {
+            id: "0".to_owned(),
+            pubkey: "a".repeat(64),
+            delegated_by: None,
+            created_at: unix_time(),
+            kind: 1059,
+            tags: vec![vec!["p".to_owned(), recipient.to_owned()]],
+            content: "".to_owned(),
+            sig: "0".to_owned(),
+            tagidx: None,
+        }
+    }
+
+    #[test]
+    fn test_unauthenticated_cannot_read_gift_wrap() {
+        let client_conn = ClientConn::new("127.0.0.1".into());
+        let event = gift_wrap(&"b".repeat(64));
+        assert!(!client_conn.can_read_event(&event, &RESTRICTED_KINDS));
+        // unrestricted kinds are readable by anybody
+        let mut note = event.clone();
+        note.kind = 1;
+        assert!(client_conn.can_read_event(&note, &RESTRICTED_KINDS));
+    }
+
+    #[test]
+    fn test_recipient_can_read_gift_wrap() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.generate_auth_challenge();
+        let challenge = client_conn.auth_challenge().unwrap();
+        let auth = auth_event(challenge);
+        assert!(client_conn.authenticate(&auth, RELAY).is_ok());
+
+        // addressed to the authenticated pubkey
+        let event = gift_wrap(&auth.pubkey);
+        assert!(client_conn.can_read_event(&event, &RESTRICTED_KINDS));
+        // addressed to someone else
+        let other = gift_wrap(&"b".repeat(64));
+        assert!(!client_conn.can_read_event(&other, &RESTRICTED_KINDS));
+    }
+
+    #[test]
+    fn test_req_close_churn_drops_stale_results() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        // results queued by every query started so far, which may
+        // arrive long after the subscription was replaced or closed.
+        let mut in_flight: Vec<u64> = vec![];
+        let mut current: Option<u64> = None;
+        for i in 0..1000u64 {
+            let req = format!(r#"["REQ","search",{{"kinds":[{i}]}}]"#);
+            let sub: Subscription = serde_json::from_str(&req).unwrap();
+            let generation = client_conn.subscribe(sub).unwrap();
+            assert!(current.map_or(true, |c| generation > c));
+            current = Some(generation);
+            in_flight.push(generation);
+            if i % 3 == 0 {
+                client_conn.unsubscribe(&Close {
+                    id: "search".to_owned(),
+                });
+                current = None;
+            }
+            // only results from the live subscription may be delivered
+            for g in &in_flight {
+                assert_eq!(
+                    client_conn.is_current("search", *g),
+                    current == Some(*g),
+                    "cycle {i}: result from generation {g} delivered"
+                );
+            }
+        }
+        assert_eq!(
+            client_conn.subscriptions().len(),
+            usize::from(current.is_some())
+        );
+    }
+
     fn auth_event(challenge: &String) -> Event {
         create_auth_event(Some(challenge), Some(&RELAY.into()), 22242, unix_time())
     }
@@ -1,12 +1,26 @@
 use anyhow::Result;
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
 use futures::SinkExt;
 use futures::StreamExt;
+use nostr_rs_relay::config::{Settings, UnknownMessages};
+use nostr_rs_relay::db;
+use nostr_rs_relay::error::Error;
+use nostr_rs_relay::hooks::LifecycleHooks;
+use nostr_rs_relay::repo::NostrRepo;
+use nostr_rs_relay::server::NostrMetrics;
+use std::io::Read;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use tokio_tungstenite::connect_async;
-use tracing::info;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
 mod common;
 
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
 #[tokio::test]
 async fn start_and_stop() -> Result<()> {
     // this will be the common pattern for acquiring a new relay:
@@ -38,6 +52,36 @@ async fn start_and_stop() -> Result<()> {
     Ok(())
 }
 
+/// Hooks whose repository is slow to start, like a long migration
+struct SlowRepo;
+
+#[async_trait]
+impl LifecycleHooks for SlowRepo {
+    async fn repo(&self, settings: &Settings, metrics: NostrMetrics) -> Arc<dyn NostrRepo> {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        db::build_repo(settings, metrics).await
+    }
+}
+
+#[tokio::test]
+async fn stop_while_starting_is_an_error() -> Result<()> {
+    let relay = common::start_relay_with_hooks(Settings::default(), Arc::new(SlowRepo))?;
+    // wait for the relay to answer with its startup state
+    let ready: hyper::Uri = relay.http_url("/ready").parse()?;
+    while hyper::Client::new().get(ready.clone()).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    relay.shutdown_tx.send(())?;
+    // the relay stops without waiting for its repository
+    let res = tokio::time::timeout(
+        Duration::from_secs(10),
+        tokio::task::spawn_blocking(move || relay.handle.join()),
+    )
+    .await??;
+    assert!(matches!(res, Ok(Err(Error::StoppedWhileStarting))));
+    Ok(())
+}
+
 #[tokio::test]
 async fn relay_home_page() -> Result<()> {
     // get a relay and wait for startup...
@@ -48,32 +92,118 @@ async fn relay_home_page() -> Result<()> {
     Ok(())
 }
 
-//#[tokio::test]
-// Still inwork
-async fn publish_test() -> Result<()> {
-    // get a relay and wait for startup
-    let relay = common::start_relay()?;
+/// Start a relay answering unsupported message types as configured,
+/// connect to it, and send it a made-up message type.
+async fn send_unknown_message(
+    unknown_messages: UnknownMessages,
+) -> Result<(common::Relay, WsStream)> {
+    let mut settings = Settings::default();
+    settings.options.unknown_messages = unknown_messages;
+    let relay = common::start_relay_with(settings)?;
     common::wait_for_healthy_relay(&relay).await?;
-    // open a non-secure websocket connection.
     let (mut ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
-    // send a simple pre-made message
-    let simple_event = r#"["EVENT", {"content": "hello world","created_at": 1691239763,
-      "id":"f3ce6798d70e358213ebbeba4886bbdfacf1ecfd4f65ee5323ef5f404de32b86",
-      "kind": 1,
-      "pubkey": "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
-      "sig": "30ca29e8581eeee75bf838171dec818af5e6de2b74f5337de940f5cc91186534c0b20d6cf7ad1043a2c51dbd60b979447720a471d346322103c83f6cb66e4e98",
-      "tags": []}]"#;
-    ws.send(simple_event.into()).await?;
-    // get response from server, confirm it is an array with first element "OK"
-    let event_confirm = ws.next().await;
-    ws.close(None).await?;
-    info!("event confirmed: {:?}", event_confirm);
-    // open a new connection, and wait for some time to get the event.
-    let (mut sub_ws, _res) = connect_async(format!("ws://localhost:{}", relay.port)).await?;
-    let event_sub = r#"["REQ", "simple", {}]"#;
-    sub_ws.send(event_sub.into()).await?;
-    // read from subscription
-    let _ws_next = sub_ws.next().await;
+    ws.send(r#"["FUTURE", "abc", {"x": 1}]"#.into()).await?;
+    Ok((relay, ws))
+}
+
+/// Read the next text message, within a few seconds
+async fn next_text(ws: &mut WsStream) -> Result<String> {
+    match tokio::time::timeout(Duration::from_secs(5), ws.next()).await? {
+        Some(Ok(Message::Text(text))) => Ok(text),
+        other => Err(anyhow::anyhow!("expected a text message, got {other:?}")),
+    }
+}
+
+#[tokio::test]
+async fn unknown_message_type_gets_notice() -> Result<()> {
+    let (relay, mut ws) = send_unknown_message(UnknownMessages::Notice).await?;
+    assert_eq!(
+        next_text(&mut ws).await?,
+        r#"["NOTICE","invalid: unsupported message type"]"#
+    );
+    // the connection is still usable
+    ws.send(r#"["REQ", "after", {"kinds": [1]}]"#.into())
+        .await?;
+    assert_eq!(next_text(&mut ws).await?, r#"["EOSE","after"]"#);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_message_type_ignored() -> Result<()> {
+    let (relay, mut ws) = send_unknown_message(UnknownMessages::Ignore).await?;
+    // nothing is sent for the unknown message
+    ws.send(r#"["REQ", "after", {"kinds": [1]}]"#.into())
+        .await?;
+    assert_eq!(next_text(&mut ws).await?, r#"["EOSE","after"]"#);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_message_type_closes() -> Result<()> {
+    let (relay, mut ws) = send_unknown_message(UnknownMessages::Close).await?;
+    match tokio::time::timeout(Duration::from_secs(5), ws.next()).await? {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(u16::from(frame.code), 1003);
+            assert_eq!(frame.reason, "unsupported message type");
+        }
+        other => panic!("expected a close frame, got {other:?}"),
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn websocket_compression_offer_is_declined() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    // permessage-deflate is not implemented, so the offer browsers make
+    // must be declined rather than accepted and then broken
+    let mut request = format!("ws://localhost:{}", relay.port).into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Extensions",
+        "permessage-deflate; client_max_window_bits".parse()?,
+    );
+    let (mut ws, response) = connect_async(request).await?;
+    assert!(!response.headers().contains_key("Sec-WebSocket-Extensions"));
+    ws.send(r#"["REQ", "plain", {"kinds": [1]}]"#.into())
+        .await?;
+    assert_eq!(next_text(&mut ws).await?, r#"["EOSE","plain"]"#);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Fetch the relay information document, accepting gzip or not
+async fn get_relay_info(port: u16, gzip: bool) -> Result<hyper::Response<hyper::Body>> {
+    let mut request = hyper::Request::get(format!("http://127.0.0.1:{port}/"))
+        .header("Accept", "application/nostr+json");
+    if gzip {
+        request = request.header("Accept-Encoding", "gzip, deflate");
+    }
+    Ok(hyper::Client::new()
+        .request(request.body(hyper::Body::empty())?)
+        .await?)
+}
+
+#[tokio::test]
+async fn relay_info_is_gzipped() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.network.http_compression = true;
+    let relay = common::start_relay_with(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let response = get_relay_info(relay.port, true).await?;
+    assert_eq!(response.headers()["Content-Encoding"], "gzip");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let mut info = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut info)?;
+    let info: serde_json::Value = serde_json::from_str(&info)?;
+    assert!(info["supported_nips"].is_array());
+    // clients that do not accept gzip get plain JSON
+    let response = get_relay_info(relay.port, false).await?;
+    assert!(!response.headers().contains_key("Content-Encoding"));
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    serde_json::from_slice::<serde_json::Value>(&body)?;
     let _res = relay.shutdown_tx.send(());
     Ok(())
 }
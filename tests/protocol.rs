@@ -0,0 +1,715 @@
+//! Protocol behavior of a running relay, as seen by websocket clients
+use anyhow::Result;
+use common::client::{note, RelayMessage, TestClient};
+use common::payments::MockPayments;
+use hyper::{Body, Client, Request, StatusCode};
+use nostr::{EventBuilder, Keys, Kind, Tag};
+use nostr_rs_relay::admin::{
+    run_broadcast_notice, run_quarantine_action, run_whitelist, BroadcastRequest,
+    BROADCAST_NOTICE_PATH,
+};
+use nostr_rs_relay::config::Settings;
+use nostr_rs_relay::event::Event;
+use nostr_rs_relay::hooks::NoopHooks;
+use nostr_rs_relay::nip98::sign_auth_header;
+use nostr_rs_relay::relay_proof::verify_proof;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+mod common;
+
+async fn start(settings: Settings) -> Result<common::Relay> {
+    common::start_healthy_relay(settings, Arc::new(NoopHooks)).await
+}
+
+fn ids(events: &[Value]) -> Vec<&str> {
+    events.iter().filter_map(|e| e["id"].as_str()).collect()
+}
+
+#[tokio::test]
+async fn published_events_reach_subscribers() -> Result<()> {
+    let relay = start(Settings::default()).await?;
+    let mut subscriber = TestClient::connect(&relay).await?;
+    subscriber.req("notes", &[json!({"kinds": [1]})]).await?;
+    assert!(subscriber.stored_events("notes").await?.is_empty());
+    let mut publisher = TestClient::connect(&relay).await?;
+    let event = note(&Keys::generate(), "hello world");
+    let id = event.id.to_hex();
+    assert_eq!(publisher.publish(&event).await?, (true, String::new()));
+    // live subscribers get the event
+    match subscriber.next_message().await? {
+        RelayMessage::Event { sub_id, event } => {
+            assert_eq!(sub_id, "notes");
+            assert_eq!(event["id"], id.as_str());
+            assert_eq!(event["content"], "hello world");
+        }
+        other => panic!("expected the published event, got {other:?}"),
+    }
+    // and later subscribers find it stored
+    let mut reader = TestClient::connect(&relay).await?;
+    reader.req("by-id", &[json!({"ids": [id]})]).await?;
+    assert_eq!(
+        ids(&reader.stored_events("by-id").await?),
+        vec![id.as_str()]
+    );
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn duplicate_events_are_acknowledged() -> Result<()> {
+    let relay = start(Settings::default()).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let event = note(&Keys::generate(), "only once");
+    assert_eq!(client.publish(&event).await?, (true, String::new()));
+    assert_eq!(
+        client.publish(&event).await?,
+        (true, "duplicate: already have this event".to_owned())
+    );
+    // stored once
+    client
+        .req("dup", &[json!({"ids": [event.id.to_hex()]})])
+        .await?;
+    assert_eq!(client.stored_events("dup").await?.len(), 1);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn stored_events_come_before_eose() -> Result<()> {
+    let relay = start(Settings::default()).await?;
+    let keys = Keys::generate();
+    let author = keys.public_key().to_string();
+    let mut client = TestClient::connect(&relay).await?;
+    let mut published = vec![];
+    for content in ["one", "two", "three"] {
+        let event = note(&keys, content);
+        assert!(client.publish(&event).await?.0);
+        published.push(event.id.to_hex());
+    }
+    client.req("all", &[json!({"authors": [author]})]).await?;
+    let mut stored: Vec<String> = ids(&client.stored_events("all").await?)
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    stored.sort();
+    published.sort();
+    assert_eq!(stored, published);
+    // events published after EOSE arrive live
+    let event = note(&keys, "four");
+    client.send_event(&event).await?;
+    let mut live = None;
+    for _ in 0..2 {
+        match client.next_message().await? {
+            RelayMessage::Event { sub_id, event } if sub_id == "all" => live = Some(event),
+            RelayMessage::Ok { accepted, .. } => assert!(accepted),
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+    assert_eq!(live.unwrap()["id"], event.id.to_hex().as_str());
+    // the limit applies to stored events only
+    client
+        .req("latest", &[json!({"authors": [author], "limit": 2})])
+        .await?;
+    assert_eq!(client.stored_events("latest").await?.len(), 2);
+    // closed subscriptions get nothing more
+    client.close("all").await?;
+    client.close("latest").await?;
+    client.sync().await?;
+    let mut publisher = TestClient::connect(&relay).await?;
+    assert!(publisher.publish(&note(&keys, "five")).await?.0);
+    client.expect_silence(Duration::from_millis(500)).await?;
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn subscriptions_end_early_at_their_quota() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.limits.max_events_before_eose = Some(3);
+    let relay = start(settings).await?;
+    let keys = Keys::generate();
+    let author = keys.public_key().to_string();
+    let mut client = TestClient::connect(&relay).await?;
+    for i in 0..5 {
+        assert!(client.publish(&note(&keys, &format!("note {i}"))).await?.0);
+    }
+    // the quota covers every filter of a REQ, whatever their limits
+    let filters = [
+        json!({"authors": [author], "limit": 100}),
+        json!({"kinds": [1], "limit": 100}),
+    ];
+    client.req("many", &filters).await?;
+    assert_eq!(client.stored_events("many").await?.len(), 3);
+    match client.next_message().await? {
+        RelayMessage::Notice(msg) => {
+            assert!(msg.contains("many"), "{msg}");
+            assert!(msg.contains("paginate"), "{msg}");
+        }
+        other => panic!("expected a notice, got {other:?}"),
+    }
+    // the rest of the query is not sent, but live events are
+    client.expect_silence(Duration::from_millis(200)).await?;
+    let event = note(&keys, "live");
+    let mut publisher = TestClient::connect(&relay).await?;
+    assert!(publisher.publish(&event).await?.0);
+    match client.next_message().await? {
+        RelayMessage::Event {
+            sub_id,
+            event: live,
+        } => {
+            assert_eq!(sub_id, "many");
+            assert_eq!(live["id"], event.id.to_hex().as_str());
+        }
+        other => panic!("expected the live event, got {other:?}"),
+    }
+    // subscriptions under the quota are not told anything
+    client
+        .req("few", &[json!({"authors": [author], "limit": 2})])
+        .await?;
+    assert_eq!(client.stored_events("few").await?.len(), 2);
+    client.expect_silence(Duration::from_millis(200)).await?;
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn malformed_json_is_explained() -> Result<()> {
+    let relay = start(Settings::default()).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    client.send_raw(r#"["REQ", "sub" {"kinds": [1]}]"#).await?;
+    assert_eq!(
+        client.next_message().await?,
+        RelayMessage::Notice(
+            "invalid: malformed JSON at position 15 (expected `,` or `]`)".to_owned()
+        )
+    );
+    // the connection stays open
+    client.req("sub", &[json!({"kinds": [1]})]).await?;
+    assert!(client.stored_events("sub").await?.is_empty());
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn oversized_events_are_rejected() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.limits.max_event_bytes = Some(1024);
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let keys = Keys::generate();
+    let (accepted, message) = client.publish(&note(&keys, &"x".repeat(2000))).await?;
+    assert!(!accepted);
+    assert!(message.starts_with("invalid: event too large"), "{message}");
+    assert!(client.publish(&note(&keys, "small")).await?.0);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn events_are_rate_limited_per_pubkey() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.limits.events_per_min_per_pubkey = Some(2);
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let keys = Keys::generate();
+    assert!(client.publish(&note(&keys, "one")).await?.0);
+    assert!(client.publish(&note(&keys, "two")).await?.0);
+    let (accepted, message) = client.publish(&note(&keys, "three")).await?;
+    assert!(!accepted);
+    assert_eq!(message, "rate-limited: too many events, slow down");
+    // other authors are not held back
+    assert!(client.publish(&note(&Keys::generate(), "hi")).await?.0);
+    relay.shutdown()
+}
+
+/// Ask the relay for an admission invoice
+async fn join(relay: &common::Relay, pubkey: &str) -> Result<Value> {
+    let request = hyper::Request::post(relay.http_url(&format!("/join/invoice?pubkey={pubkey}")))
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[tokio::test]
+async fn paid_admission_allows_publishing() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.pay_to_relay.enabled = true;
+    settings.pay_to_relay.sign_ups = true;
+    let payments = MockPayments::default();
+    let relay = common::start_healthy_relay(settings, Arc::new(payments.clone())).await?;
+    let keys = Keys::generate();
+    let pubkey = keys.public_key().to_string();
+    let mut client = TestClient::connect(&relay).await?;
+    let (accepted, message) = client.publish(&note(&keys, "free ride?")).await?;
+    assert!(!accepted);
+    assert_eq!(message, "blocked: pubkey not registered");
+    let invoice = join(&relay, &pubkey).await?;
+    assert_eq!(invoice["admitted"], false);
+    assert_eq!(payments.invoices_created(), 1);
+    payments.pay(invoice["payment_hash"].as_str().unwrap());
+    // the next request finds the invoice paid
+    assert_eq!(join(&relay, &pubkey).await?["admitted"], true);
+    assert!(client.publish(&note(&keys, "paid up")).await?.0);
+    relay.shutdown()
+}
+
+/// GET a JSON route of the relay
+async fn get_json(relay: &common::Relay, path: &str) -> Result<Value> {
+    let response = hyper::Client::new()
+        .get(relay.http_url(path).parse()?)
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[tokio::test]
+async fn lnurl_pays_admission() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.pay_to_relay.enabled = true;
+    settings.pay_to_relay.sign_ups = true;
+    settings.pay_to_relay.admission_cost = 21;
+    settings.info.relay_url = Some("wss://relay.example.com/".to_owned());
+    let payments = MockPayments::default();
+    let relay = common::start_healthy_relay(settings, Arc::new(payments.clone())).await?;
+    let keys = Keys::generate();
+    let pubkey = keys.public_key().to_string();
+    let pay_request = get_json(&relay, &format!("/.well-known/lnurlp/{pubkey}")).await?;
+    assert_eq!(pay_request["tag"], "payRequest");
+    assert_eq!(pay_request["minSendable"], 21000);
+    let callback = format!("/lnurlp/callback/{pubkey}");
+    assert_eq!(
+        pay_request["callback"],
+        format!("https://relay.example.com{callback}")
+    );
+    // only the admission fee is accepted
+    let wrong = get_json(&relay, &format!("{callback}?amount=1000")).await?;
+    assert_eq!(wrong["status"], "ERROR");
+    let invoice = get_json(&relay, &format!("{callback}?amount=21000")).await?;
+    let bolt11 = invoice["pr"].as_str().unwrap();
+    assert_eq!(payments.invoices_created(), 1);
+    // paying the invoice admits the pubkey
+    payments.pay(&format!("{:064x}", 0));
+    assert_eq!(join(&relay, &pubkey).await?["admitted"], true);
+    assert!(bolt11.starts_with("lnbcrt21"));
+    let mut client = TestClient::connect(&relay).await?;
+    assert!(client.publish(&note(&keys, "paid by lnurl")).await?.0);
+    relay.shutdown()
+}
+
+/// Fetch the status page
+async fn status_page(relay: &common::Relay) -> Result<Value> {
+    get_json(relay, "/stats").await
+}
+
+#[tokio::test]
+async fn status_page_summarizes_the_relay() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.status_page.enabled = true;
+    settings.status_page.refresh_secs = 1;
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    assert!(client.publish(&note(&Keys::generate(), "counted")).await?.0);
+    client.req("notes", &[json!({"kinds": [1]})]).await?;
+    client.stored_events("notes").await?;
+    // stored events are recounted periodically
+    let mut status = status_page(&relay).await?;
+    for _ in 0..30 {
+        if status["events"] == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        status = status_page(&relay).await?;
+    }
+    assert_eq!(status["events"], 1);
+    assert_eq!(status["events_last_day"], 1);
+    assert_eq!(status["clients"], 1);
+    assert_eq!(status["subscriptions"], 1);
+    client.close("notes").await?;
+    client.expect_silence(Duration::from_millis(200)).await?;
+    assert_eq!(status_page(&relay).await?["subscriptions"], 0);
+    relay.shutdown()
+}
+
+/// Broadcast an operator notice through the admin API
+async fn broadcast(
+    relay: &common::Relay,
+    settings: &Settings,
+    notice: BroadcastRequest,
+) -> Result<Value> {
+    let settings = settings.clone();
+    let url = relay.http_url("");
+    let body =
+        tokio::task::spawn_blocking(move || run_broadcast_notice(&settings, &notice, Some(&url)))
+            .await??;
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[tokio::test]
+async fn operator_notices_reach_their_targets() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let relay_keys = Keys::generate();
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    settings.authorization.nip42_auth = true;
+    let relay = start(settings.clone()).await?;
+    let (alice, bob) = (Keys::generate(), Keys::generate());
+    let mut to_alice = TestClient::connect(&relay).await?;
+    to_alice.authenticate(&alice, relay_url).await?;
+    let mut to_bob = TestClient::connect(&relay).await?;
+    to_bob.authenticate(&bob, relay_url).await?;
+    let mut anonymous = TestClient::connect(&relay).await?;
+    assert!(matches!(
+        anonymous.next_message().await?,
+        RelayMessage::Auth(_)
+    ));
+    // a client that leaves before the notice is sent
+    let leaving = TestClient::connect(&relay).await?;
+    leaving.disconnect().await?;
+    let sent = broadcast(
+        &relay,
+        &settings,
+        BroadcastRequest {
+            message: "just for alice".to_owned(),
+            signed: true,
+            target: None,
+            pubkeys: vec![alice.public_key().to_string()],
+        },
+    )
+    .await?;
+    assert_eq!(sent["target"], "pubkeys");
+    assert_eq!(
+        to_alice.next_message().await?,
+        RelayMessage::Notice("just for alice".to_owned())
+    );
+    to_bob.expect_silence(Duration::from_millis(200)).await?;
+    anonymous.expect_silence(Duration::from_millis(200)).await?;
+    let sent = broadcast(
+        &relay,
+        &settings,
+        BroadcastRequest {
+            message: "maintenance at noon".to_owned(),
+            signed: true,
+            target: Some("all".to_owned()),
+            pubkeys: vec![],
+        },
+    )
+    .await?;
+    assert_eq!(sent["target"], "all");
+    for client in [&mut to_alice, &mut to_bob, &mut anonymous] {
+        assert_eq!(
+            client.next_message().await?,
+            RelayMessage::Notice("maintenance at noon".to_owned())
+        );
+    }
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn malformed_broadcast_requests_are_rejected() -> Result<()> {
+    let relay_keys = Keys::generate();
+    let mut settings = Settings::default();
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let url = relay.http_url(BROADCAST_NOTICE_PATH);
+    let request = Request::post(&url)
+        .header(
+            "Authorization",
+            sign_auth_header(&relay_keys, &url, "POST")?,
+        )
+        .body(Body::from(
+            r#"{"message": "maintenance at noon", "target": "#,
+        ))?;
+    let response = Client::new().request(request).await?;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    client.expect_silence(Duration::from_millis(200)).await?;
+    relay.shutdown()
+}
+
+/// A membership list naming who may publish
+fn membership_list(admin: &Keys, members: &[&Keys]) -> Result<nostr::Event> {
+    let mut tags = vec![Tag::parse(vec!["d", "relay-members"])?];
+    for member in members {
+        tags.push(Tag::PubKey(member.public_key(), None));
+    }
+    Ok(EventBuilder::new(Kind::from(30000), "", &tags).to_event(admin)?)
+}
+
+#[tokio::test]
+async fn membership_list_in_force_is_reported() -> Result<()> {
+    let (relay_keys, admin) = (Keys::generate(), Keys::generate());
+    let (alice, bob) = (Keys::generate(), Keys::generate());
+    let mut settings = Settings::default();
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    settings.authorization.pubkey_whitelist = Some(vec![bob.public_key().to_string()]);
+    settings.authorization.membership_admin = Some(admin.public_key().to_string());
+    let relay = start(settings.clone()).await?;
+    let whitelist = || {
+        let settings = settings.clone();
+        let url = relay.http_url("");
+        async move {
+            let body =
+                tokio::task::spawn_blocking(move || run_whitelist(&settings, Some(&url))).await??;
+            Ok::<Value, anyhow::Error>(serde_json::from_str(&body)?)
+        }
+    };
+    assert_eq!(whitelist().await?["source"], "config");
+    let mut client = TestClient::connect(&relay).await?;
+    let list = membership_list(&admin, &[&alice, &bob])?;
+    assert!(client.publish(&list).await?.0);
+    let status = whitelist().await?;
+    assert_eq!(status["source"], "membership_list");
+    assert_eq!(status["event_id"], list.id.to_hex());
+    assert_eq!(status["count"], 2);
+    // a newer list replaces it, and removals apply immediately
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let list = membership_list(&admin, &[&alice])?;
+    assert!(client.publish(&list).await?.0);
+    assert_eq!(whitelist().await?["event_id"], list.id.to_hex());
+    assert!(!client.publish(&note(&bob, "still here?")).await?.0);
+    assert!(client.publish(&note(&alice, "hello")).await?.0);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn banned_pubkeys_are_closed_on_authentication() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let relay_keys = Keys::generate();
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    settings.authorization.nip42_auth = true;
+    settings.quarantine.enabled = true;
+    let relay = start(settings.clone()).await?;
+    let spammer = Keys::generate();
+    let pubkey = spammer.public_key().to_string();
+    let url = relay.http_url("");
+    tokio::task::spawn_blocking(move || {
+        run_quarantine_action(&settings, &pubkey, "ban", Some(&url))
+    })
+    .await??;
+    let mut client = TestClient::connect(&relay).await?;
+    let challenge = client.expect_challenge().await?;
+    client.send_auth(&spammer, &challenge, relay_url).await?;
+    assert_eq!(client.expect_close().await?, 1008);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn read_only_relay_rejects_events() -> Result<()> {
+    let mut settings = Settings::default();
+    settings.options.read_only = true;
+    settings.options.read_only_message = "back at noon".to_owned();
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    assert_eq!(
+        client.next_message().await?,
+        RelayMessage::Notice("back at noon; new events are not accepted".to_owned())
+    );
+    let event = note(&Keys::generate(), "while read-only");
+    let (accepted, message) = client.publish(&event).await?;
+    assert!(!accepted);
+    assert_eq!(message, "error: relay is read-only");
+    client.req("all", &[json!({})]).await?;
+    assert!(client.stored_events("all").await?.is_empty());
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn relay_proves_its_identity() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let relay_keys = Keys::generate();
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    client.send_raw(r#"["PROOF", "prove-it-123"]"#).await?;
+    let RelayMessage::Proof(proof) = client.next_message().await? else {
+        panic!("expected a proof");
+    };
+    let proof: Event = serde_json::from_value(proof)?;
+    let pubkey = relay_keys.public_key().to_string();
+    verify_proof(&proof, &pubkey, "prove-it-123", Some(relay_url), 60)?;
+    client.send_raw(r#"["PROOF", ""]"#).await?;
+    assert!(matches!(
+        client.next_message().await?,
+        RelayMessage::Notice(msg) if msg.starts_with("invalid:")
+    ));
+    relay.shutdown()?;
+    // a relay without keys has nothing to prove with
+    let relay = start(Settings::default()).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    client.send_raw(r#"["PROOF", "prove-it-123"]"#).await?;
+    assert_eq!(
+        client.next_message().await?,
+        RelayMessage::Notice("unsupported: relay has no identity key".to_owned())
+    );
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn failed_auth_replaces_the_challenge_until_the_client_is_closed() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.authorization.nip42_auth = true;
+    settings.authorization.auth_failure_delay_ms = 0;
+    settings.authorization.auth_max_failures = 2;
+    let relay = start(settings).await?;
+    let keys = Keys::generate();
+    let mut client = TestClient::connect(&relay).await?;
+    let challenge = client.expect_challenge().await?;
+    // answering for another relay fails, and replaces the challenge
+    client
+        .send_auth(&keys, &challenge, "wss://other.example.com/")
+        .await?;
+    assert!(matches!(
+        client.next_message().await?,
+        RelayMessage::Ok {
+            accepted: false,
+            ..
+        }
+    ));
+    let replacement = client.expect_challenge().await?;
+    assert_ne!(replacement, challenge);
+    // so replaying the first challenge fails too, which closes the connection
+    client.send_auth(&keys, &challenge, relay_url).await?;
+    assert!(matches!(
+        client.next_message().await?,
+        RelayMessage::Ok {
+            accepted: false,
+            ..
+        }
+    ));
+    assert_eq!(client.expect_close().await?, 1008);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn failed_auth_answer_waits_without_holding_up_the_connection() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.authorization.nip42_auth = true;
+    settings.authorization.auth_failure_delay_ms = 1000;
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let challenge = client.expect_challenge().await?;
+    client
+        .send_auth(&Keys::generate(), &challenge, "wss://other.example.com/")
+        .await?;
+    // other messages are answered while the AUTH answer waits
+    client.req("meanwhile", &[json!({"kinds": [1]})]).await?;
+    assert!(client.stored_events("meanwhile").await?.is_empty());
+    assert!(matches!(
+        client.next_message().await?,
+        RelayMessage::Ok {
+            accepted: false,
+            ..
+        }
+    ));
+    assert_ne!(client.expect_challenge().await?, challenge);
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn challenges_are_replaced_when_they_expire() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.authorization.nip42_auth = true;
+    settings.authorization.auth_challenge_ttl_secs = 1;
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let challenge = client.expect_challenge().await?;
+    let replacement = client.expect_challenge().await?;
+    assert_ne!(replacement, challenge);
+    client
+        .send_auth(&Keys::generate(), &replacement, relay_url)
+        .await?;
+    client.sync().await?;
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn switching_identity_closes_subscriptions_it_may_not_make() -> Result<()> {
+    let relay_url = "wss://relay.example.com/";
+    let (alice, bob) = (Keys::generate(), Keys::generate());
+    let mut settings = Settings::default();
+    settings.info.relay_url = Some(relay_url.to_owned());
+    settings.info.expose_first_seen = true;
+    settings.info.first_seen_pubkeys = vec![alice.public_key().to_string()];
+    settings.authorization.nip42_auth = true;
+    let relay = start(settings).await?;
+    let mut client = TestClient::connect(&relay).await?;
+    let challenge = client.expect_challenge().await?;
+    client.send_auth(&alice, &challenge, relay_url).await?;
+    client
+        .req("seen", &[json!({"kinds": [1], "_receivedSince": 0})])
+        .await?;
+    assert!(client.stored_events("seen").await?.is_empty());
+    // the same challenge authenticates bob in alice's place
+    client.send_auth(&bob, &challenge, relay_url).await?;
+    match client.next_message().await? {
+        RelayMessage::Closed { sub_id, message } => {
+            assert_eq!(sub_id, "seen");
+            assert!(message.starts_with("restricted:"), "{message}");
+        }
+        other => panic!("expected the subscription to be closed, got {other:?}"),
+    }
+    relay.shutdown()
+}
+
+#[tokio::test]
+async fn events_are_imported_over_http() -> Result<()> {
+    let relay_keys = Keys::generate();
+    let mut settings = Settings::default();
+    settings.info.relay_secret_key =
+        Some(relay_keys.secret_key()?.display_secret().to_string().into());
+    settings.import.enabled = true;
+    settings.import.batch_size = 2;
+    let relay = start(settings.clone()).await?;
+    let author = Keys::generate();
+    let (first, second) = (note(&author, "first"), note(&author, "second"));
+    let lines = [
+        serde_json::to_string(&first)?,
+        "not an event".to_owned(),
+        String::new(),
+        serde_json::to_string(&second)?,
+        // already imported
+        serde_json::to_string(&first)?,
+    ];
+    let file = std::env::temp_dir().join(format!("import-{}.jsonl", author.public_key()));
+    std::fs::write(&file, lines.join("\n"))?;
+    let url = relay.http_url("");
+    let path = file.to_string_lossy().into_owned();
+    let body = tokio::task::spawn_blocking(move || {
+        nostr_rs_relay::admin::run_import(&settings, &path, Some(&url))
+    })
+    .await?;
+    std::fs::remove_file(&file)?;
+    let summary: Value = serde_json::from_str(&body?)?;
+    assert_eq!(summary["accepted"], 3);
+    assert_eq!(summary["rejected"], 1);
+    let results = summary["results"].as_array().unwrap();
+    let lines: Vec<u64> = results.iter().filter_map(|r| r["line"].as_u64()).collect();
+    assert_eq!(lines, vec![1, 2, 4, 5]);
+    assert_eq!(results[1]["ok"], false);
+    assert!(results[3]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("duplicate:"));
+    let mut client = TestClient::connect(&relay).await?;
+    client
+        .req(
+            "imported",
+            &[json!({"authors": [author.public_key().to_string()]})],
+        )
+        .await?;
+    let stored = client.stored_events("imported").await?;
+    assert_eq!(stored.len(), 2);
+    relay.shutdown()
+}